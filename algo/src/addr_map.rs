@@ -0,0 +1,161 @@
+//! A fixed-size hash map from addresses to small values, for bookkeeping done inside the heap
+//! allocator, where nothing may allocate.
+//!
+//! It uses open addressing with linear probing, and removes by shifting later entries back
+//! rather than leaving tombstones, so a map that sees many inserts and removes stays as quick
+//! as a fresh one.
+
+/// Maps nonzero addresses to values of type `V`, holding at most `N` entries. `N` must be a
+/// power of two.
+pub struct AddrMap<V, const N: usize> {
+    /// Each entry's key, 0 when the slot is empty.
+    keys: [u64; N],
+    values: [V; N],
+    len: usize,
+}
+
+impl<V: Copy + Default, const N: usize> AddrMap<V, N> {
+    /// An empty map. `fill` is only there to initialise the values in a `const` context.
+    pub const fn new(fill: V) -> Self {
+        assert!(N.is_power_of_two());
+        AddrMap {
+            keys: [0; N],
+            values: [fill; N],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The slot `key` would be found from first.
+    fn home(key: u64) -> usize {
+        // Fibonacci hashing, as allocations share their low bits
+        (key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize & (N - 1)
+    }
+
+    /// The slot holding `key`, or the empty slot its probe ends on.
+    fn find(&self, key: u64) -> Option<usize> {
+        let mut slot = Self::home(key);
+        for _ in 0..N {
+            if self.keys[slot] == key || self.keys[slot] == 0 {
+                return Some(slot);
+            }
+            slot = (slot + 1) & (N - 1);
+        }
+        None
+    }
+
+    /// Sets `key`'s value. Returns `false`, changing nothing, if the map is full or `key` is 0.
+    #[must_use]
+    pub fn insert(&mut self, key: u64, value: V) -> bool {
+        if key == 0 {
+            return false;
+        }
+        let Some(slot) = self.find(key) else {
+            return false;
+        };
+        if self.keys[slot] == 0 {
+            // One slot stays empty so every probe ends
+            if self.len == N - 1 {
+                return false;
+            }
+            self.keys[slot] = key;
+            self.len += 1;
+        }
+        self.values[slot] = value;
+        true
+    }
+
+    pub fn get(&self, key: u64) -> Option<V> {
+        let slot = self.find(key)?;
+        (key != 0 && self.keys[slot] == key).then(|| self.values[slot])
+    }
+
+    /// Removes `key`, and returns its value if it was there.
+    pub fn remove(&mut self, key: u64) -> Option<V> {
+        let mut hole = self.find(key)?;
+        if key == 0 || self.keys[hole] != key {
+            return None;
+        }
+        let value = self.values[hole];
+        self.len -= 1;
+        // Moves back each later entry of the run that the hole now cuts off from its home
+        let mut slot = hole;
+        loop {
+            slot = (slot + 1) & (N - 1);
+            if self.keys[slot] == 0 {
+                break;
+            }
+            let home = Self::home(self.keys[slot]);
+            // Whether `home` lies cyclically in (hole, slot], where the entry can stay
+            let stays = if hole <= slot {
+                hole < home && home <= slot
+            } else {
+                hole < home || home <= slot
+            };
+            if !stays {
+                self.keys[hole] = self.keys[slot];
+                self.values[hole] = self.values[slot];
+                hole = slot;
+            }
+        }
+        self.keys[hole] = 0;
+        self.values[hole] = V::default();
+        Some(value)
+    }
+}
+
+#[test]
+fn test_addr_map_insert_and_remove() {
+    let mut map = AddrMap::<u16, 8>::new(0);
+    // Page-aligned keys, as allocations often are
+    for i in 1..=7 {
+        assert!(map.insert(i * 0x1000, i as u16));
+    }
+    assert!(!map.insert(8 * 0x1000, 8), "one slot stays empty");
+    assert!(!map.insert(0, 1));
+    assert_eq!(map.len(), 7);
+
+    assert_eq!(map.remove(3 * 0x1000), Some(3));
+    assert_eq!(map.remove(3 * 0x1000), None);
+    // Everything else can still be found after the shift
+    for i in (1..=7).filter(|&i| i != 3) {
+        assert_eq!(map.get(i * 0x1000), Some(i as u16));
+    }
+    assert!(map.insert(8 * 0x1000, 8));
+    assert!(map.insert(8 * 0x1000, 9));
+    assert_eq!(map.get(8 * 0x1000), Some(9));
+    assert_eq!(map.len(), 7);
+}
+
+#[test]
+fn test_addr_map_matches_a_btree() {
+    use alloc::collections::BTreeMap;
+
+    let mut map = AddrMap::<u32, 64>::new(0);
+    let mut model = BTreeMap::new();
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    for _ in 0..10_000 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        // A small key space, so removes often hit
+        let key = (state % 80 + 1) * 16;
+        if state & (1 << 40) != 0 && model.len() < 63 {
+            assert!(map.insert(key, state as u32));
+            model.insert(key, state as u32);
+        } else {
+            assert_eq!(map.remove(key), model.remove(&key));
+        }
+        assert_eq!(map.len(), model.len());
+    }
+    for (&key, &value) in &model {
+        assert_eq!(map.get(key), Some(value));
+    }
+}
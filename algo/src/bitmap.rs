@@ -0,0 +1,33 @@
+//! Searches over the bitmaps that track which frames are in use, one bit per frame.
+use core::ops::Range;
+
+use bitvec::prelude::*;
+
+/// The first free (0) bit in `range` at or after `from`, wrapping around to the start of
+/// `range`.
+pub fn first_free(bitmap: &BitSlice<u8, Lsb0>, range: Range<usize>, from: usize) -> Option<usize> {
+    let from = from.clamp(range.start, range.end);
+    bitmap[from..range.end]
+        .first_zero()
+        .map(|index| index + from)
+        .or_else(|| {
+            bitmap[range.start..from]
+                .first_zero()
+                .map(|index| index + range.start)
+        })
+}
+
+#[test]
+fn test_first_free_wraps_around() {
+    let mut bytes = [0xFFu8; 4];
+    let bits = bytes.view_bits_mut::<Lsb0>();
+    bits.set(3, false);
+    bits.set(20, false);
+
+    assert_eq!(first_free(bits, 0..32, 0), Some(3));
+    assert_eq!(first_free(bits, 0..32, 4), Some(20));
+    assert_eq!(first_free(bits, 0..32, 21), Some(3));
+    // Bits outside the range are never returned, even when free
+    assert_eq!(first_free(bits, 8..16, 0), None);
+    assert_eq!(first_free(bits, 16..24, 30), Some(20));
+}
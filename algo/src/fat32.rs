@@ -0,0 +1,237 @@
+//! The on-disk format of FAT32 volumes.
+//!
+//! A volume starts with a boot sector describing its layout, then reserved sectors, then one
+//! or more copies of the file allocation table (FAT), then the data area, divided into
+//! clusters numbered from 2. Each FAT entry holds the number of the next cluster of the file
+//! using that cluster, or marks it free, bad, or the end of a chain. Directories are files of
+//! 32-byte entries; the root directory starts at the cluster the boot sector names.
+use alloc::string::String;
+
+/// The only sector size handled.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The bits of a FAT entry that hold a cluster number; the top four are reserved.
+pub const ENTRY_MASK: u32 = 0x0FFF_FFFF;
+pub const FREE: u32 = 0;
+/// Marks a cluster that the media can't store data in.
+pub const BAD: u32 = 0x0FFF_FFF7;
+/// Entries from here up end a chain.
+pub const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// The number of the first cluster in the data area.
+pub const FIRST_CLUSTER: u32 = 2;
+
+pub const DIR_ENTRY_SIZE: usize = 32;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+/// The attribute combination that marks a long file name entry.
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+/// First name byte of a deleted entry.
+pub const DELETED: u8 = 0xE5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatError {
+    /// The boot sector doesn't describe a FAT32 volume.
+    NotFat32,
+    /// A FAT32 volume this code doesn't handle, e.g. with sectors other than 512 bytes.
+    Unsupported,
+}
+
+/// The layout of a volume, from its boot sector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootSector {
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_count: u32,
+    /// Sectors in each copy of the FAT.
+    pub fat_sectors: u32,
+    pub total_sectors: u32,
+    pub root_cluster: u32,
+}
+
+impl BootSector {
+    pub fn parse(sector: &[u8; SECTOR_SIZE]) -> Result<BootSector, FormatError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([sector[offset], sector[offset + 1]]);
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(sector[offset..offset + 4].try_into().expect("4 bytes"))
+        };
+        if sector[510..512] != [0x55, 0xAA] {
+            return Err(FormatError::NotFat32);
+        }
+        // FAT12 and FAT16 give the FAT size here, and have no root cluster
+        if u16_at(22) != 0 || u32_at(36) == 0 {
+            return Err(FormatError::NotFat32);
+        }
+        if u16_at(11) as usize != SECTOR_SIZE {
+            return Err(FormatError::Unsupported);
+        }
+        let boot = BootSector {
+            sectors_per_cluster: sector[13] as u32,
+            reserved_sectors: u16_at(14) as u32,
+            fat_count: sector[16] as u32,
+            fat_sectors: u32_at(36),
+            // Small volumes may give the count in the old 16-bit field instead
+            total_sectors: match u16_at(19) {
+                0 => u32_at(32),
+                small => small as u32,
+            },
+            root_cluster: u32_at(44),
+        };
+        if !boot.sectors_per_cluster.is_power_of_two()
+            || boot.reserved_sectors == 0
+            || boot.fat_count == 0
+            || boot.total_sectors <= boot.first_data_sector()
+        {
+            return Err(FormatError::NotFat32);
+        }
+        Ok(boot)
+    }
+
+    /// The sector cluster 2 starts at.
+    pub fn first_data_sector(&self) -> u32 {
+        self.reserved_sectors + self.fat_count * self.fat_sectors
+    }
+
+    /// Clusters in the data area, limited to what the FAT has entries for.
+    pub fn cluster_count(&self) -> u32 {
+        let in_data = (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster;
+        let in_fat = self.fat_sectors * (SECTOR_SIZE / 4) as u32 - FIRST_CLUSTER;
+        in_data.min(in_fat)
+    }
+
+    /// Whether `cluster` is a cluster of the data area.
+    pub fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.cluster_count()).contains(&cluster)
+    }
+
+    pub fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster as u64 * SECTOR_SIZE as u64
+    }
+
+    /// The first sector of `cluster`.
+    pub fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.first_data_sector() as u64
+            + (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster as u64
+    }
+
+    /// The first sector of FAT copy `index`.
+    pub fn fat_sector(&self, index: u32) -> u64 {
+        (self.reserved_sectors + index * self.fat_sectors) as u64
+    }
+}
+
+/// A short-name directory entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirEntry {
+    /// Eight name characters and three extension characters, space padded.
+    pub name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+impl DirEntry {
+    /// Parses the entry in `bytes`. Returns `None` for free entries, deleted ones and the
+    /// pieces of long file names.
+    pub fn parse(bytes: &[u8]) -> Option<DirEntry> {
+        if bytes[0] == 0 || bytes[0] == DELETED || bytes[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as u32;
+        Some(DirEntry {
+            name: bytes[..11].try_into().expect("11 bytes"),
+            attributes: bytes[11],
+            first_cluster: u16_at(20) << 16 | u16_at(26),
+            size: u32::from_le_bytes(bytes[28..32].try_into().expect("4 bytes")),
+        })
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_volume_label(&self) -> bool {
+        self.attributes & ATTR_VOLUME_ID != 0
+    }
+
+    /// Whether this is the `.` or `..` entry every directory but the root starts with.
+    pub fn is_dot(&self) -> bool {
+        self.name == *b".          " || self.name == *b"..         "
+    }
+
+    /// The name as `NAME.EXT`, or `NAME` without an extension.
+    pub fn display_name(&self) -> String {
+        let base = core::str::from_utf8(&self.name[..8])
+            .unwrap_or("?")
+            .trim_end();
+        let ext = core::str::from_utf8(&self.name[8..])
+            .unwrap_or("?")
+            .trim_end();
+        let mut name = String::from(base);
+        if !ext.is_empty() {
+            name.push('.');
+            name.push_str(ext);
+        }
+        name
+    }
+}
+
+/// Whether the directory entry bytes mark the end of the directory: nothing after them is in
+/// use.
+pub fn is_end_of_directory(bytes: &[u8]) -> bool {
+    bytes[0] == 0
+}
+
+#[test]
+fn test_dir_entry_names() {
+    let mut bytes = [0u8; DIR_ENTRY_SIZE];
+    bytes[..11].copy_from_slice(b"HELLO   TXT");
+    bytes[20..22].copy_from_slice(&1u16.to_le_bytes());
+    bytes[26..28].copy_from_slice(&2u16.to_le_bytes());
+    bytes[28..32].copy_from_slice(&700u32.to_le_bytes());
+    let entry = DirEntry::parse(&bytes).expect("entry is in use");
+    assert_eq!(entry.first_cluster, 0x1_0002);
+    assert_eq!(entry.size, 700);
+    assert!(!entry.is_directory());
+    assert!(!entry.is_dot());
+
+    bytes[0] = DELETED;
+    assert_eq!(DirEntry::parse(&bytes), None);
+    bytes[0] = b'A';
+    bytes[11] = ATTR_LONG_NAME;
+    assert_eq!(DirEntry::parse(&bytes), None);
+}
+
+#[test]
+fn test_boot_sector_layout() {
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    sector[13] = 8;
+    sector[14..16].copy_from_slice(&32u16.to_le_bytes());
+    sector[16] = 2;
+    sector[32..36].copy_from_slice(&131_072u32.to_le_bytes());
+    sector[36..40].copy_from_slice(&128u32.to_le_bytes());
+    sector[44..48].copy_from_slice(&2u32.to_le_bytes());
+    sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    let boot = BootSector::parse(&sector).expect("valid boot sector");
+    assert_eq!(boot.first_data_sector(), 32 + 2 * 128);
+    assert_eq!(boot.fat_sector(1), 32 + 128);
+    assert_eq!(boot.cluster_bytes(), 4096);
+    assert_eq!(boot.cluster_sector(3), 288 + 8);
+    // Here the data area, not the FAT, limits the clusters
+    assert_eq!(boot.cluster_count(), (131_072 - 288) / 8);
+    assert!(boot.is_data_cluster(FIRST_CLUSTER));
+    assert!(!boot.is_data_cluster(FIRST_CLUSTER + boot.cluster_count()));
+
+    let mut fat16 = sector;
+    fat16[22..24].copy_from_slice(&64u16.to_le_bytes());
+    assert_eq!(BootSector::parse(&fat16), Err(FormatError::NotFat32));
+    let mut big_sectors = sector;
+    big_sectors[11..13].copy_from_slice(&4096u16.to_le_bytes());
+    assert_eq!(
+        BootSector::parse(&big_sectors),
+        Err(FormatError::Unsupported)
+    );
+    sector[511] = 0;
+    assert_eq!(BootSector::parse(&sector), Err(FormatError::NotFat32));
+}
@@ -0,0 +1,17 @@
+//! Pure logic the kernel uses that doesn't touch hardware: bitmap searches, address range
+//! math, an allocation-free address map, heap size classes, the FAT32 on-disk format and
+//! character widths.
+//!
+//! Unlike the kernel, this crate builds for the host too, so its tests run under plain
+//! `cargo test -p kernel-algo`, and under Miri with `cargo miri test -p kernel-algo`, without
+//! booting QEMU.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+pub mod addr_map;
+pub mod bitmap;
+pub mod fat32;
+pub mod range;
+pub mod size_class;
+pub mod text;
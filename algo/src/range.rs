@@ -0,0 +1,159 @@
+//! Half-open address ranges, and sets of free ones.
+
+/// Whether `a_start..a_end` and `b_start..b_end` have an address in common. Both ranges must
+/// be non-empty.
+pub fn ranges_intersect(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> bool {
+    a_start < b_end && a_end > b_start
+}
+
+#[test]
+fn test_ranges_intersect() {
+    assert!(ranges_intersect(0x1000, 0x3000, 0x2000, 0x4000));
+    assert!(ranges_intersect(0x2000, 0x3000, 0x1000, 0x4000));
+    // Ranges that only touch don't overlap
+    assert!(!ranges_intersect(0x1000, 0x2000, 0x2000, 0x3000));
+    assert!(!ranges_intersect(0x2000, 0x3000, 0x1000, 0x2000));
+}
+
+/// Free address ranges, kept sorted and merged with their neighbours, in `N` fixed slots so
+/// tracking them never allocates.
+#[derive(Debug, Clone)]
+pub struct FreeRanges<const N: usize> {
+    ranges: [(u64, u64); N],
+    len: usize,
+}
+
+impl<const N: usize> FreeRanges<N> {
+    pub const fn new() -> Self {
+        FreeRanges {
+            ranges: [(0, 0); N],
+            len: 0,
+        }
+    }
+
+    /// The free ranges, lowest first.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges[..self.len].iter().copied()
+    }
+
+    /// Bytes free in all.
+    pub fn free(&self) -> u64 {
+        self.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// How many separate ranges are free.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes in the largest free range, the most that can be taken in one piece.
+    pub fn largest(&self) -> u64 {
+        self.iter().map(|(start, end)| end - start).max().unwrap_or(0)
+    }
+
+    /// Marks `start..end` free. Returns `false`, dropping the range, if it can't be merged
+    /// into a neighbour and every slot is in use. The range must not overlap a free one.
+    pub fn insert(&mut self, start: u64, end: u64) -> bool {
+        if start >= end {
+            return true;
+        }
+        let index = self.ranges[..self.len].partition_point(|&(other, _)| other < start);
+        debug_assert!(
+            (index == 0 || self.ranges[index - 1].1 <= start)
+                && (index == self.len || end <= self.ranges[index].0),
+            "free range overlaps another"
+        );
+        let joins_prev = index > 0 && self.ranges[index - 1].1 == start;
+        let joins_next = index < self.len && self.ranges[index].0 == end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.ranges[index - 1].1 = self.ranges[index].1;
+                self.ranges.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+            }
+            (true, false) => self.ranges[index - 1].1 = end,
+            (false, true) => self.ranges[index].0 = start,
+            (false, false) => {
+                if self.len == N {
+                    return false;
+                }
+                self.ranges.copy_within(index..self.len, index + 1);
+                self.ranges[index] = (start, end);
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    /// Takes `len` bytes from the start of the lowest range that has them, and returns where
+    /// they start.
+    pub fn take(&mut self, len: u64) -> Option<u64> {
+        let index = self.ranges[..self.len]
+            .iter()
+            .position(|&(start, end)| end - start >= len)?;
+        let start = self.ranges[index].0;
+        self.ranges[index].0 += len;
+        if self.ranges[index].0 == self.ranges[index].1 {
+            self.ranges.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+        Some(start)
+    }
+
+    /// Removes the highest range if it ends at `end`, and returns where it started.
+    pub fn take_last_ending_at(&mut self, end: u64) -> Option<u64> {
+        let (start, last_end) = *self.ranges[..self.len].last()?;
+        if last_end != end {
+            return None;
+        }
+        self.len -= 1;
+        Some(start)
+    }
+}
+
+impl<const N: usize> Default for FreeRanges<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_free_ranges_merge_and_reuse() {
+    let mut free = FreeRanges::<2>::new();
+    assert!(free.insert(0x1000, 0x2000));
+    assert!(free.insert(0x5000, 0x6000));
+    // Joins both neighbours into one range, freeing a slot
+    assert!(free.insert(0x2000, 0x5000));
+    assert_eq!(free.iter().collect::<Vec<_>>(), [(0x1000, 0x6000)]);
+    assert!(free.insert(0x8000, 0x9000));
+    // No slot left for a range that touches nothing
+    assert!(!free.insert(0xA000, 0xB000));
+    assert_eq!(free.free(), 0x6000);
+
+    // First fit, from the front of the range
+    assert_eq!(free.take(0x2000), Some(0x1000));
+    assert_eq!(free.take(0x4000), None);
+    assert_eq!(free.take(0x3000), Some(0x3000));
+    assert_eq!(free.iter().collect::<Vec<_>>(), [(0x8000, 0x9000)]);
+    assert_eq!(free.take(0x1000), Some(0x8000));
+    assert_eq!(free.free(), 0);
+}
+
+#[test]
+fn test_free_ranges_largest_and_last() {
+    let mut free = FreeRanges::<4>::new();
+    assert_eq!(free.largest(), 0);
+    free.insert(0x1000, 0x2000);
+    free.insert(0x4000, 0x7000);
+    free.insert(0x9000, 0xA000);
+    assert_eq!((free.len(), free.largest()), (3, 0x3000));
+
+    // Only the highest range, and only if it ends there
+    assert_eq!(free.take_last_ending_at(0x7000), None);
+    assert_eq!(free.take_last_ending_at(0xA000), Some(0x9000));
+    assert_eq!(free.iter().last(), Some((0x4000, 0x7000)));
+}
@@ -0,0 +1,26 @@
+//! The size classes of the kernel heap's fixed-size block allocator.
+use core::alloc::Layout;
+
+/// Block sizes, each serving layouts up to its size and alignment. Larger layouts get whole
+/// pages.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// The index in [`BLOCK_SIZES`] of the smallest block `layout` fits in, or `None` if it needs
+/// pages.
+pub fn index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+#[test]
+fn test_size_class_index() {
+    let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+    assert_eq!(index(&layout(1, 1)), Some(0));
+    assert_eq!(index(&layout(8, 8)), Some(0));
+    assert_eq!(index(&layout(9, 1)), Some(1));
+    // Alignment counts as much as size, since blocks are aligned to their size
+    assert_eq!(index(&layout(8, 256)), Some(5));
+    assert_eq!(index(&layout(2048, 8)), Some(8));
+    assert_eq!(index(&layout(2049, 8)), None);
+    assert_eq!(index(&layout(16, 4096)), None);
+}
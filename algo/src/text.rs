@@ -0,0 +1,72 @@
+//! How many terminal cells a character takes.
+
+/// Characters that take no cell of their own: combining marks, which draw over the character
+/// before them, zero-width spaces and joiners, and variation selectors.
+const ZERO_WIDTH: &[(u32, u32)] = &[
+    (0x0300, 0x036F),
+    (0x0483, 0x0489),
+    (0x0591, 0x05BD),
+    (0x0610, 0x061A),
+    (0x064B, 0x065F),
+    (0x1160, 0x11FF),
+    (0x1AB0, 0x1AFF),
+    (0x1DC0, 0x1DFF),
+    (0x200B, 0x200F),
+    (0x202A, 0x202E),
+    (0x2060, 0x2064),
+    (0x20D0, 0x20FF),
+    (0xFE00, 0xFE0F),
+    (0xFE20, 0xFE2F),
+    (0xFEFF, 0xFEFF),
+    (0xE0100, 0xE01EF),
+];
+
+/// Characters that take two cells: East Asian wide and fullwidth characters, and emoji.
+const WIDE: &[(u32, u32)] = &[
+    (0x1100, 0x115F),
+    (0x2E80, 0x303E),
+    (0x3041, 0x33FF),
+    (0x3400, 0x4DBF),
+    (0x4E00, 0x9FFF),
+    (0xA000, 0xA4CF),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE30, 0xFE4F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x1F300, 0x1F64F),
+    (0x1F900, 0x1F9FF),
+    (0x20000, 0x2FFFD),
+    (0x30000, 0x3FFFD),
+];
+
+fn in_ranges(ranges: &[(u32, u32)], c: char) -> bool {
+    let c = c as u32;
+    ranges.iter().any(|&(start, end)| (start..=end).contains(&c))
+}
+
+/// Cells `c` takes on a terminal: 0, 1 or 2. Control characters count as 0, since they aren't
+/// drawn; what they do to the cursor is up to the terminal.
+pub fn char_width(c: char) -> usize {
+    if c.is_control() || in_ranges(ZERO_WIDTH, c) {
+        0
+    } else if in_ranges(WIDE, c) {
+        2
+    } else {
+        1
+    }
+}
+
+#[test]
+fn test_char_width() {
+    assert_eq!(char_width('a'), 1);
+    assert_eq!(char_width('é'), 1);
+    assert_eq!(char_width('\u{301}'), 0);
+    assert_eq!(char_width('\u{200D}'), 0);
+    assert_eq!(char_width('\t'), 0);
+    assert_eq!(char_width('漢'), 2);
+    assert_eq!(char_width('あ'), 2);
+    assert_eq!(char_width('Ａ'), 2);
+    assert_eq!(char_width('🙂'), 2);
+    assert_eq!(char_width('\u{FFFD}'), 1);
+}
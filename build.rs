@@ -1,4 +1,7 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+#[path = "kernel/user_programs.rs"]
+mod user_programs;
 
 fn main() {
     let target_dir =
@@ -12,14 +15,18 @@ fn main() {
     let kernel_bin_name = format!("CARGO_BIN_FILE_{}_{}", "RUST_KERNEL", "rust-kernel");
     let kernel = PathBuf::from(std::env::var_os(kernel_bin_name).expect("Kernel binary not found"));
 
+    let initrd = build_initrd(&output_dir);
+
     // UEFI and BIOS disk iamges
     let uefi_path = output_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&initrd)
         .create_disk_image(&uefi_path)
         .expect("Failed to create UEFI disk image");
 
     let bios_path = output_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&initrd)
         .create_disk_image(&bios_path)
         .expect("Failed to create BIOS disk image");
 
@@ -28,3 +35,44 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+/// Packs the user programs into `initrd.cpio`, as `bin/<name>`, for the bootloader to load
+/// next to the kernel. Returns its path.
+fn build_initrd(output_dir: &Path) -> PathBuf {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=kernel/user");
+    println!("cargo:rerun-if-changed=kernel/user_programs.rs");
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let programs = user_programs::build(Path::new("kernel/user"), &out_dir.join("user"));
+
+    let mut archive = Vec::new();
+    for (ino, (name, elf)) in programs.iter().enumerate() {
+        let data = std::fs::read(elf).expect("failed to read user program");
+        let name = format!("bin/{name}");
+        write_cpio_entry(&mut archive, ino + 1, &name, 0o100755, &data);
+    }
+    write_cpio_entry(&mut archive, 0, "TRAILER!!!", 0, &[]);
+
+    let path = output_dir.join("initrd.cpio");
+    std::fs::write(&path, archive).expect("failed to write initrd");
+    path
+}
+
+/// Appends a file to a `newc` cpio archive, the format the kernel's initrd reader expects.
+fn write_cpio_entry(archive: &mut Vec<u8>, ino: usize, name: &str, mode: usize, data: &[u8]) {
+    let (size, name_size) = (data.len(), name.len() + 1);
+    // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor, rdevmajor, rdevminor,
+    // namesize (with the NUL), check
+    let fields = [ino, mode, 0, 0, 1, 0, size, 0, 0, 0, 0, name_size, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    // The name and the data each end on a 4-byte boundary
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
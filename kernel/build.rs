@@ -1,34 +1,44 @@
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+mod kconfig;
+mod user_programs;
+
 fn main() {
     println!("cargo:rerun-if-changed=src/smp/ap_trampoline.asm");
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     fs::create_dir_all(&out_dir).expect("Failed to create OUT_DIR");
 
+    // A flat binary at offset 0, relocated to its page by the kernel at boot
     let asm_src = PathBuf::from("src/smp/ap_trampoline.asm");
-    let asm_obj = out_dir.join("ap_trampoline.o");
     let bin_out = out_dir.join("ap_trampoline.bin");
 
     let status = Command::new("nasm")
-        .args(["-f", "elf64"])
+        .args(["-f", "bin"])
         .arg(&asm_src)
         .arg("-o")
-        .arg(&asm_obj)
+        .arg(&bin_out)
         .status()
         .expect("failed to run nasm");
     assert!(status.success(), "nasm failed");
 
-    let status = Command::new("llvm-objcopy")
-        .args(["-O", "binary"])
-        .arg(&asm_obj)
-        .arg(&bin_out)
-        .status()
-        .expect("failed to run objcopy");
-    assert!(status.success(), "objcopy failed");
-
     println!("cargo:rustc-env=AP_TRAMPOLINE_BIN={}", bin_out.display());
+
+    build_user_programs(&out_dir);
+    kconfig::generate(&out_dir);
+}
+
+/// Builds each `user/*.asm` into a static ELF executable `<name>.elf`, for the integration
+/// tests to load into user processes.
+fn build_user_programs(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=user");
+    println!("cargo:rerun-if-changed=user_programs.rs");
+
+    let user_out = out_dir.join("user");
+    user_programs::build(Path::new("user"), &user_out);
+
+    println!("cargo:rustc-env=USER_PROGRAMS_DIR={}", user_out.display());
 }
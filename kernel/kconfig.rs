@@ -0,0 +1,119 @@
+//! Turns `kernel.config` into Rust constants and `cfg`s for the kernel.
+//!
+//! The file holds `CONFIG_<NAME>=<value>` lines. Numbers and choices become constants in
+//! `$OUT_DIR/config.rs`, which `src/config.rs` includes; drivers set to `y` become
+//! `cfg(driver = "<name>")`, which gates their initcall.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+enum Kind {
+    /// A `usize` constant, written in decimal or `0x` hex, optionally with `_` separators.
+    Number,
+    /// A constant of the named enum in `src/config.rs`, from one of the listed lowercase values.
+    Choice(&'static str, &'static [&'static str]),
+    /// `y` or `n`: whether to build a driver.
+    Driver,
+}
+
+/// Every option, with its kind and the default when the file doesn't set it.
+const OPTIONS: &[(&str, Kind, &str)] = &[
+    ("HEAP_SIZE", Kind::Number, "0x4000_0000"),
+    ("HEAP_MAX_SIZE", Kind::Number, "0x4_0000_0000"),
+    ("BOOTSTRAP_HEAP_SIZE", Kind::Number, "0x1_0000"),
+    ("MAX_CPUS", Kind::Number, "8"),
+    ("AP_STACKS", Kind::Number, "4"),
+    ("AP_STACK_SIZE", Kind::Number, "32768"),
+    ("STACK_WARN_PERCENT", Kind::Number, "75"),
+    (
+        "CONSOLE",
+        Kind::Choice("ConsoleBackend", &["auto", "serial"]),
+        "auto",
+    ),
+    ("DRIVER_FW_CFG", Kind::Driver, "y"),
+    ("DRIVER_VIRTIO_CONSOLE", Kind::Driver, "y"),
+];
+
+pub fn generate(out_dir: &Path) {
+    println!("cargo:rerun-if-env-changed=KERNEL_CONFIG");
+    let path = env::var_os("KERNEL_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("kernel.config"));
+    println!("cargo:rerun-if-changed={}", path.display());
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+    let values = parse(&path, &text);
+
+    let drivers: Vec<String> = OPTIONS
+        .iter()
+        .filter_map(|(name, kind, _)| match kind {
+            Kind::Driver => Some(format!("{:?}", driver_name(name))),
+            _ => None,
+        })
+        .collect();
+    println!(
+        "cargo:rustc-check-cfg=cfg(driver, values({}))",
+        drivers.join(", ")
+    );
+
+    let mut out = String::from("// Generated by build.rs from kernel.config\n");
+    for (name, kind, default) in OPTIONS {
+        let value = values.get(name).map_or(*default, String::as_str);
+        match kind {
+            Kind::Number => {
+                let number = parse_number(value)
+                    .unwrap_or_else(|| panic!("CONFIG_{}: '{}' is not a number", name, value));
+                out += &format!("pub const {}: usize = {};\n", name, number);
+            }
+            Kind::Choice(ty, choices) => {
+                if !choices.contains(&value) {
+                    panic!("CONFIG_{}: '{}' is not one of {:?}", name, value, choices);
+                }
+                let variant = value[..1].to_uppercase() + &value[1..];
+                out += &format!("pub const {}: {} = {}::{};\n", name, ty, ty, variant);
+            }
+            Kind::Driver => match value {
+                "y" => println!("cargo:rustc-cfg=driver={:?}", driver_name(name)),
+                "n" => {}
+                _ => panic!("CONFIG_{}: expected y or n, not '{}'", name, value),
+            },
+        }
+    }
+    fs::write(out_dir.join("config.rs"), out).expect("failed to write config.rs");
+}
+
+/// Reads the `CONFIG_` lines of `text`, rejecting options that don't exist.
+fn parse(path: &Path, text: &str) -> BTreeMap<&'static str, String> {
+    let mut values = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{}:{}", path.display(), number + 1);
+        let (key, value) = line
+            .split_once('=')
+            .unwrap_or_else(|| panic!("{}: expected CONFIG_<NAME>=<value>", location));
+        let name = key.trim().strip_prefix("CONFIG_").unwrap_or(key.trim());
+        let Some((name, _, _)) = OPTIONS.iter().find(|(option, _, _)| *option == name) else {
+            panic!("{}: unknown option {}", location, key.trim());
+        };
+        values.insert(*name, value.trim().to_string());
+    }
+    values
+}
+
+fn parse_number(value: &str) -> Option<usize> {
+    let digits = value.replace('_', "");
+    match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => digits.parse().ok(),
+    }
+}
+
+/// `DRIVER_VIRTIO_CONSOLE` is `cfg(driver = "virtio_console")`.
+fn driver_name(option: &str) -> String {
+    option["DRIVER_".len()..].to_lowercase()
+}
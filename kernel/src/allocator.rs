@@ -7,8 +7,17 @@ use x86_64::structures::paging::{
 };
 
 pub mod alloc_info;
+#[cfg(feature = "alloc-sites")]
+pub mod alloc_sites;
+pub mod bootstrap;
+#[cfg(feature = "heap-compaction")]
+pub mod compact;
 pub mod fixed_size_block;
+pub mod oom;
 pub mod page_allocator;
+#[cfg(feature = "heap-sanitizer")]
+pub mod sanitizer;
+pub mod slab;
 
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
@@ -22,6 +31,13 @@ pub fn init_heap_experimental(
     unsafe {
         ALLOCATOR.lock().init(page_allocator);
     }
+    // Allocations go to the heap from here on
+    let used = bootstrap::BOOTSTRAP.retire();
+    crate::info!(
+        "Bootstrap allocator retired with {} of {} bytes in use",
+        used,
+        bootstrap::ARENA_SIZE
+    );
     Ok(())
 }
 pub struct Dummy;
@@ -51,4 +67,8 @@ impl<A> Locked<A> {
     pub fn lock(&'_ self) -> spin::MutexGuard<'_, A> {
         self.inner.lock()
     }
+
+    pub fn try_lock(&'_ self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
 }
@@ -17,14 +17,26 @@ pub struct AllocationInfo {
 
 pub static LARGE_ALLOCS: RwLock<[Option<(usize, AllocationInfo)>; 512]> = RwLock::new([None; 512]);
 
-/// Inserts a large allocation into the `LARGE_ALLOCS` map.
-pub fn large_alloc_insert(addr: usize, info: AllocationInfo) {
+/// Inserts a large allocation into the `LARGE_ALLOCS` map. Returns `false` if the map is full.
+#[must_use]
+pub fn large_alloc_insert(addr: usize, info: AllocationInfo) -> bool {
     let mut large_allocs = LARGE_ALLOCS.write();
     for slot in large_allocs.iter_mut() {
         if slot.is_none() {
             *slot = Some((addr, info));
-            return;
+            return true;
         }
     }
-    panic!("LARGE_ALLOCS is full!");
+    false
+}
+
+/// The number of large allocations and the pages they hold.
+pub fn large_alloc_stats() -> (usize, usize) {
+    LARGE_ALLOCS
+        .read()
+        .iter()
+        .flatten()
+        .fold((0, 0), |(count, pages), (_, info)| {
+            (count + 1, pages + info.num_pages)
+        })
 }
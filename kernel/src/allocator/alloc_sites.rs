@@ -0,0 +1,252 @@
+//! Attribution of heap memory to the code that allocated it, built with the `alloc-sites`
+//! feature.
+//!
+//! Every allocation made once the heap is up is charged to its site: the first
+//! [`SITE_FRAMES`] return addresses past the allocator, together with the size class it came
+//! from. A site counts the allocations it has live and the bytes they take up, so [`dump`]
+//! shows where the heap is going, and a site whose live bytes only ever grow is leaking.
+//!
+//! Allocations are found again when they are freed in an [`AddrMap`] of [`MAX_LIVE`] entries.
+//! Those made while it or the site table is full are counted as untracked and not charged.
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cmp::Reverse;
+use core::fmt::{self, Write};
+
+use kernel_algo::addr_map::AddrMap;
+use kernel_algo::size_class::{self, BLOCK_SIZES};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::backtrace;
+use crate::memory::PAGE_SIZE;
+use crate::{serial_print, serial_println};
+
+/// Return addresses that make up a site.
+pub const SITE_FRAMES: usize = 4;
+/// Frames of the allocator itself at the start of a walk from [`on_alloc`]: `on_alloc` and
+/// `GlobalAlloc::alloc`.
+const ALLOCATOR_FRAMES: usize = 2;
+const MAX_SITES: usize = 256;
+/// Live allocations that can be tracked at once.
+pub const MAX_LIVE: usize = 8192;
+
+/// An allocation site and what it holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    pub frames: [u64; SITE_FRAMES],
+    /// The index in [`BLOCK_SIZES`] of its blocks, or `None` if it gets whole pages.
+    pub class: Option<usize>,
+    pub live: usize,
+    /// Bytes of the heap its live allocations take up, counting whole blocks and pages.
+    pub live_bytes: usize,
+    /// Allocations ever made there.
+    pub total: u64,
+}
+
+struct Sites {
+    sites: [Option<Site>; MAX_SITES],
+    /// Each tracked allocation's index in `sites`.
+    live: AddrMap<u16, MAX_LIVE>,
+    untracked: u64,
+}
+
+static SITES: Mutex<Sites> = Mutex::new(Sites {
+    sites: [None; MAX_SITES],
+    live: AddrMap::new(0),
+    untracked: 0,
+});
+
+/// The report goes straight to the serial port, like the heap sanitizer's.
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+/// The size class `layout` is served from, and the bytes it takes up there.
+fn charge(layout: &Layout) -> (Option<usize>, usize) {
+    match size_class::index(layout) {
+        Some(class) => (Some(class), BLOCK_SIZES[class]),
+        None => {
+            let size = layout.size().max(layout.align());
+            (None, size.div_ceil(PAGE_SIZE as usize) * PAGE_SIZE as usize)
+        }
+    }
+}
+
+impl Sites {
+    /// The index of the site for `frames` and `class`, added if it is new.
+    fn find_or_add(&mut self, frames: [u64; SITE_FRAMES], class: Option<usize>) -> Option<u16> {
+        let mut empty = None;
+        for (index, slot) in self.sites.iter().enumerate() {
+            match slot {
+                Some(site) if site.frames == frames && site.class == class => {
+                    return Some(index as u16);
+                }
+                Some(_) => {}
+                None => {
+                    empty.get_or_insert(index);
+                }
+            }
+        }
+        let index = empty?;
+        self.sites[index] = Some(Site {
+            frames,
+            class,
+            live: 0,
+            live_bytes: 0,
+            total: 0,
+        });
+        Some(index as u16)
+    }
+
+    fn charge(&mut self, addr: u64, frames: [u64; SITE_FRAMES], layout: &Layout) {
+        let (class, bytes) = charge(layout);
+        let Some(index) = self.find_or_add(frames, class) else {
+            self.untracked += 1;
+            return;
+        };
+        if !self.live.insert(addr, index) {
+            self.untracked += 1;
+            return;
+        }
+        if let Some(site) = &mut self.sites[index as usize] {
+            site.live += 1;
+            site.live_bytes += bytes;
+            site.total += 1;
+        }
+    }
+
+    fn uncharge(&mut self, addr: u64, layout: &Layout) {
+        let Some(index) = self.live.remove(addr) else {
+            return;
+        };
+        if let Some(site) = &mut self.sites[index as usize] {
+            site.live -= 1;
+            site.live_bytes -= charge(layout).1;
+        }
+    }
+}
+
+/// Charges the allocation at `ptr` to the code that made it.
+#[inline(never)]
+pub fn on_alloc(ptr: *mut u8, layout: Layout) {
+    let mut frames = [0; SITE_FRAMES];
+    let mut index: usize = 0;
+    backtrace::walk(|return_addr| {
+        if let Some(frame) = index
+            .checked_sub(ALLOCATOR_FRAMES)
+            .and_then(|i| frames.get_mut(i))
+        {
+            *frame = return_addr;
+        }
+        index += 1;
+    });
+    interrupts::without_interrupts(|| SITES.lock().charge(ptr as u64, frames, &layout));
+}
+
+/// Takes the allocation at `ptr`, being freed with `layout`, off its site.
+pub fn on_dealloc(ptr: *mut u8, layout: Layout) {
+    interrupts::without_interrupts(|| SITES.lock().uncharge(ptr as u64, &layout));
+}
+
+/// Every site with allocations live, the most live bytes first, and how many allocations
+/// weren't tracked.
+pub fn sites() -> (Vec<Site>, u64) {
+    // Allocated up front, as the heap can't be used with the sites locked
+    let mut sites = Vec::with_capacity(MAX_SITES);
+    let untracked = interrupts::without_interrupts(|| {
+        let table = SITES.lock();
+        sites.extend(table.sites.iter().flatten().filter(|site| site.live > 0));
+        table.untracked
+    });
+    sites.sort_unstable_by_key(|site: &Site| Reverse(site.live_bytes));
+    (sites, untracked)
+}
+
+/// Prints the `top` sites holding the most live bytes over serial, each with its frames.
+pub fn dump(top: usize) {
+    let (sites, untracked) = sites();
+    let live_bytes: usize = sites.iter().map(|site| site.live_bytes).sum();
+    serial_println!(
+        "Allocation sites: {} KiB live at {} sites, {} allocations untracked",
+        live_bytes / 1024,
+        sites.len(),
+        untracked
+    );
+    for site in sites.iter().take(top) {
+        if let Some(class) = site.class {
+            serial_print!("{}-byte blocks", BLOCK_SIZES[class]);
+        } else {
+            serial_print!("pages");
+        }
+        serial_println!(
+            ": {} bytes in {} live, {} allocated in all",
+            site.live_bytes,
+            site.live,
+            site.total
+        );
+        let mut out = Serial;
+        for (index, &return_addr) in site
+            .frames
+            .iter()
+            .take_while(|&&addr| addr != 0)
+            .enumerate()
+        {
+            // Writing to the serial port can't fail
+            let _ = backtrace::write_frame(&mut out, index, return_addr);
+        }
+    }
+}
+
+#[test_case]
+fn test_sites_charge_and_uncharge() {
+    // Too large for the stack
+    static TABLE: Mutex<Sites> = Mutex::new(Sites {
+        sites: [None; MAX_SITES],
+        live: AddrMap::new(0),
+        untracked: 0,
+    });
+    let mut table = TABLE.lock();
+    let here = [0x1000, 0x2000, 0, 0];
+    let small = Layout::from_size_align(24, 8).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    table.charge(0xA000, here, &small);
+    table.charge(0xA020, here, &small);
+    table.charge(0xB000, here, &large);
+    let small_site = table
+        .sites
+        .iter()
+        .flatten()
+        .find(|site| site.class.is_some());
+    assert_eq!(
+        small_site.map(|site| (site.live, site.live_bytes)),
+        Some((2, 64))
+    );
+    let large_site = table
+        .sites
+        .iter()
+        .flatten()
+        .find(|site| site.class.is_none());
+    assert_eq!(
+        large_site.map(|site| site.live_bytes),
+        Some(2 * PAGE_SIZE as usize)
+    );
+
+    table.uncharge(0xA000, &small);
+    // Not tracked, so nothing to take off
+    table.uncharge(0xC000, &small);
+    let small_site = table
+        .sites
+        .iter()
+        .flatten()
+        .find(|site| site.class.is_some());
+    assert_eq!(
+        small_site.map(|site| (site.live, site.live_bytes, site.total)),
+        Some((1, 32, 2))
+    );
+}
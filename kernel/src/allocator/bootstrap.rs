@@ -0,0 +1,125 @@
+//! The heap before there is one.
+//!
+//! Until [`super::init_heap_experimental`] has set up the real heap, allocations are bumped
+//! out of [`BOOTSTRAP`], a static arena of `CONFIG_BOOTSTRAP_HEAP_SIZE` bytes, so init steps
+//! that need a little memory don't have to wait for the page allocator. The handoff retires
+//! the arena: it hands out nothing more, and what it already handed out stays valid for as
+//! long as its owners keep it. Freeing gives memory back only when it was the last allocation.
+use alloc::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const ARENA_SIZE: usize = crate::config::BOOTSTRAP_HEAP_SIZE;
+
+/// A bump allocator over `N` bytes of its own.
+#[repr(C, align(4096))]
+pub struct Arena<const N: usize> {
+    bytes: UnsafeCell<[u8; N]>,
+    /// Offset of the first byte not handed out.
+    next: AtomicUsize,
+    retired: AtomicBool,
+}
+
+// Each byte is handed out to one owner at most
+unsafe impl<const N: usize> Sync for Arena<N> {}
+
+impl<const N: usize> Arena<N> {
+    pub const fn new() -> Self {
+        Arena {
+            bytes: UnsafeCell::new([0; N]),
+            next: AtomicUsize::new(0),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    fn base(&self) -> usize {
+        self.bytes.get() as usize
+    }
+
+    /// Hands out memory for `layout`, or null once the arena is full or retired.
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.is_retired() {
+            return ptr::null_mut();
+        }
+        let base = self.base();
+        let mut start = 0;
+        let reserved = self
+            .next
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+                start = (base + next).checked_next_multiple_of(layout.align())? - base;
+                let end = start.checked_add(layout.size())?;
+                (end <= N).then_some(end)
+            });
+        match reserved {
+            Ok(_) => (base + start) as *mut u8,
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// Whether `ptr` points into the arena.
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        (self.base()..self.base() + N).contains(&(ptr as usize))
+    }
+
+    /// Gives back `ptr` if it was the last allocation; anything else stays allocated.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`Arena::alloc`] on this arena with `layout`, and not be used again.
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize - self.base();
+        let _ = self.next.compare_exchange(
+            start + layout.size(),
+            start,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Stops handing out memory, and returns how many bytes are still in use.
+    pub fn retire(&self) -> usize {
+        self.retired.store(true, Ordering::Release);
+        self.used()
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Acquire)
+    }
+
+    /// Bytes handed out, including padding for alignment.
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for Arena<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The arena behind the global allocator until the heap is ready.
+pub static BOOTSTRAP: Arena<ARENA_SIZE> = Arena::new();
+
+#[test_case]
+fn test_arena_bumps_and_retires() {
+    let arena: Arena<64> = Arena::new();
+    let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+    let a = arena.alloc(layout(3, 1));
+    let b = arena.alloc(layout(8, 8));
+    assert!(arena.contains(a) && arena.contains(b));
+    assert_eq!(b as usize % 8, 0);
+    assert_eq!(arena.used(), 16);
+    assert!(arena.alloc(layout(64, 1)).is_null());
+
+    // Only the last allocation is given back
+    unsafe { arena.dealloc(a, layout(3, 1)) };
+    assert_eq!(arena.used(), 16);
+    unsafe { arena.dealloc(b, layout(8, 8)) };
+    assert_eq!(arena.used(), 8);
+
+    assert_eq!(arena.retire(), 8);
+    assert!(arena.alloc(layout(1, 1)).is_null());
+    assert!(!arena.contains(ptr::null_mut()));
+}
@@ -0,0 +1,193 @@
+//! An experiment in compacting the heap's address space, built with the `heap-compaction`
+//! feature.
+//!
+//! Large allocations take whole pages of address space from the page allocator, which
+//! remembers freed ranges and hands them out again first fit. Over a long uptime the freed
+//! ranges can splinter into pieces too small for the next allocation, so the heap takes fresh
+//! address space instead and [`Fragmentation::percent`] climbs. [`compact`] tries the other way
+//! out: it moves allocations down into the lowest freed range they fit, by mapping their
+//! frames there rather than copying, so the space behind them joins up and, at the top, goes
+//! back to the bump pointer.
+//!
+//! An allocation can only move if nothing holds its address, so only those made through a
+//! [`Movable`] do. Their owners reach the memory through [`Movable::with`], and compaction
+//! takes the same lock before moving it.
+use core::cmp::Reverse;
+use core::slice;
+
+use spin::Mutex;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+
+use super::page_allocator::{self, Fragmentation, PAGE_ALLOCATOR};
+use crate::mem;
+use crate::memory::PAGE_SIZE;
+
+/// Movable allocations there can be at once.
+const MAX_MOVABLE: usize = 64;
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+#[derive(Debug)]
+pub enum MovableError {
+    /// Memory isn't set up yet.
+    NoAllocator,
+    Map(MapToError<Size4KiB>),
+    /// There are [`MAX_MOVABLE`] movable allocations already.
+    TooMany,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pages {
+    addr: usize,
+    num_pages: usize,
+}
+
+/// Pages of the heap that [`compact`] may move while nobody is using them.
+pub struct Movable {
+    pages: Mutex<Option<Pages>>,
+}
+
+static MOVABLES: Mutex<[Option<&'static Movable>; MAX_MOVABLE]> = Mutex::new([None; MAX_MOVABLE]);
+
+impl Movable {
+    pub const fn new() -> Self {
+        Movable {
+            pages: Mutex::new(None),
+        }
+    }
+
+    /// Allocates `num_pages` zeroed pages for it. Panics if it has pages already.
+    pub fn alloc(&'static self, num_pages: usize) -> Result<(), MovableError> {
+        assert!(self.pages.lock().is_none(), "movable allocated twice");
+        {
+            let mut movables = MOVABLES.lock();
+            let slot = movables
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(MovableError::TooMany)?;
+            *slot = Some(self);
+        }
+        let addr = PAGE_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or(MovableError::NoAllocator)
+            .and_then(|page_alloc| {
+                page_alloc
+                    .alloc(num_pages, FLAGS)
+                    .map_err(MovableError::Map)
+            });
+        let addr = match addr {
+            Ok(addr) => addr,
+            Err(e) => {
+                self.unregister();
+                return Err(e);
+            }
+        };
+        unsafe { mem::zero_pages(addr as *mut u8, num_pages) };
+        *self.pages.lock() = Some(Pages { addr, num_pages });
+        Ok(())
+    }
+
+    /// Runs `f` on the pages, which stay where they are until it returns. Returns `None` if
+    /// there are none.
+    pub fn with<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> Option<R> {
+        let pages = self.pages.lock();
+        let Pages { addr, num_pages } = (*pages)?;
+        let bytes =
+            unsafe { slice::from_raw_parts_mut(addr as *mut u8, num_pages * PAGE_SIZE as usize) };
+        Some(f(bytes))
+    }
+
+    /// Where the pages are now, which only means anything until the next [`compact`].
+    pub fn addr(&self) -> Option<usize> {
+        self.pages.lock().map(|pages| pages.addr)
+    }
+
+    /// Frees the pages.
+    pub fn free(&'static self) {
+        self.unregister();
+        let Some(Pages { addr, num_pages }) = self.pages.lock().take() else {
+            return;
+        };
+        if let Some(page_alloc) = PAGE_ALLOCATOR.lock().as_mut() {
+            page_alloc.dealloc(addr, num_pages).expect("dealloc failed");
+        }
+        page_allocator::flush_retired();
+    }
+
+    fn unregister(&'static self) {
+        let mut movables = MOVABLES.lock();
+        if let Some(slot) = movables
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|movable| core::ptr::eq(movable, self)))
+        {
+            *slot = None;
+        }
+    }
+}
+
+impl Default for Movable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What a [`compact`] pass did.
+#[derive(Debug, Clone, Copy)]
+pub struct Compaction {
+    pub moved: usize,
+    pub moved_bytes: usize,
+    /// Bytes of freed address space given back to the bump pointer.
+    pub trimmed: usize,
+    pub before: Fragmentation,
+    pub after: Fragmentation,
+}
+
+/// Moves every movable allocation down into the lowest freed range below it that fits, highest
+/// first so each can use the space the ones above it left. Returns `None` before memory is
+/// set up.
+pub fn compact() -> Option<Compaction> {
+    // So what was freed lately is there to move into
+    page_allocator::flush_retired();
+    let before = PAGE_ALLOCATOR.lock().as_ref()?.fragmentation();
+    let mut order = *MOVABLES.lock();
+    order.sort_unstable_by_key(|movable| Reverse(movable.and_then(Movable::addr)));
+
+    let mut moved = 0;
+    let mut moved_bytes = 0;
+    for movable in order.iter().flatten() {
+        let mut pages = movable.pages.lock();
+        let Some(current) = *pages else {
+            continue;
+        };
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut()?;
+        let Some(to) = page_alloc.take_free_below(current.num_pages, current.addr) else {
+            continue;
+        };
+        if page_alloc
+            .remap(current.addr, to, current.num_pages, FLAGS)
+            .is_ok()
+        {
+            *pages = Some(Pages {
+                addr: to,
+                ..current
+            });
+            moved += 1;
+            moved_bytes += current.num_pages * PAGE_SIZE as usize;
+        }
+    }
+
+    // Gives back the address space the allocations moved out of
+    page_allocator::flush_retired();
+    let mut guard = PAGE_ALLOCATOR.lock();
+    let page_alloc = guard.as_mut()?;
+    let trimmed = page_alloc.trim();
+    Some(Compaction {
+        moved,
+        moved_bytes,
+        trimmed,
+        before,
+        after: page_alloc.fragmentation(),
+    })
+}
@@ -1,22 +1,32 @@
 use super::Locked;
 use super::page_allocator::PAGE_ALLOCATOR;
-use super::page_allocator::PageAllocator;
+use super::page_allocator::{self, PageAllocator};
 use crate::allocator::alloc_info::AllocationInfo;
 use crate::allocator::alloc_info::LARGE_ALLOCS;
 use crate::allocator::alloc_info::large_alloc_insert;
+#[cfg(feature = "alloc-sites")]
+use crate::allocator::alloc_sites;
+use crate::allocator::bootstrap::BOOTSTRAP;
+use crate::allocator::oom;
+#[cfg(feature = "heap-sanitizer")]
+use crate::allocator::sanitizer;
+use crate::fault;
+use crate::fault::Site;
+use crate::log::Level;
+use crate::log_ratelimited;
 use crate::memory::PAGE_SIZE;
-use crate::println;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use core::mem;
 use core::ptr;
+use kernel_algo::size_class::{self, BLOCK_SIZES};
+use x86_64::VirtAddr;
 use x86_64::structures::paging::FrameAllocator;
 use x86_64::structures::paging::FrameDeallocator;
 use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::structures::paging::Size4KiB;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 const MAX_LIST_LENGTH: usize = 4096;
 
 struct ListNode {
@@ -26,6 +36,8 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     list_lengths: [usize; BLOCK_SIZES.len()],
+    #[cfg(feature = "heap-sanitizer")]
+    quarantine: sanitizer::Quarantine,
 }
 
 impl FixedSizeBlockAllocator {
@@ -34,6 +46,8 @@ impl FixedSizeBlockAllocator {
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             list_lengths: [0; BLOCK_SIZES.len()],
+            #[cfg(feature = "heap-sanitizer")]
+            quarantine: sanitizer::Quarantine::new(),
         }
     }
 
@@ -74,8 +88,11 @@ impl FixedSizeBlockAllocator {
                 num_pages,
                 PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
             ) {
-                large_alloc_insert(addr, AllocationInfo { num_pages });
-                return addr as *mut u8;
+                if large_alloc_insert(addr, AllocationInfo { num_pages }) {
+                    return addr as *mut u8;
+                }
+                // Without a record it could never be freed
+                page_alloc.dealloc(addr, num_pages).expect("dealloc failed");
             }
         }
         ptr::null_mut()
@@ -113,6 +130,162 @@ impl FixedSizeBlockAllocator {
 
         Some(user_block as *mut u8)
     }
+
+    /// How many blocks each free list holds.
+    pub fn free_blocks(&self) -> [usize; BLOCK_SIZES.len()] {
+        core::array::from_fn(|index| {
+            let mut count = 0;
+            let mut node = self.list_heads[index].as_deref();
+            while let Some(next) = node {
+                count += 1;
+                node = next.next.as_deref();
+            }
+            count
+        })
+    }
+
+    /// Hands out a block for `layout` from the free lists, or pages for a large one.
+    fn alloc_block(&mut self, layout: Layout) -> *mut u8 {
+        match size_class::index(&layout) {
+            Some(index) => {
+                match self.list_heads[index].take() {
+                    Some(node) => {
+                        self.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // If no block of the required size is available, "refill" the list
+                        match self.refill_free_list(index) {
+                            Some(block) => block, // get one for the user that requested it, and put the rest in the free list
+                            None => ptr::null_mut(), // Out of memory
+                        }
+                    }
+                }
+            }
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    /// Gives back memory [`alloc_block`](Self::alloc_block) handed out for `layout`.
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, layout: Layout) {
+        // figure out if it's small or large
+        if let Some(index) = size_class::index(&layout) {
+            // This is a small block
+            unsafe { self.free_small(index, ptr) };
+        } else {
+            // Large allocation => look up `ptr` in the map and deallocate
+            let mut map = LARGE_ALLOCS.write();
+            let start_addr = ptr as usize;
+            for slot in map.iter_mut() {
+                if slot.is_some() {
+                    let (addr, info) = slot.unwrap();
+                    if addr == start_addr {
+                        let num_pages = info.num_pages;
+                        let mut guard = PAGE_ALLOCATOR.lock();
+                        if let Some(ref mut page_alloc) = *guard {
+                            page_alloc
+                                .dealloc(start_addr, num_pages)
+                                .expect("dealloc failed");
+                            let start = VirtAddr::new(start_addr as u64);
+                            page_alloc
+                                .prune_page_tables(start..start + num_pages as u64 * PAGE_SIZE);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Puts `ptr`, a block of size class `index`, back on its free list.
+    unsafe fn free_small(&mut self, index: usize, ptr: *mut u8) {
+        if self.list_lengths[index] < MAX_LIST_LENGTH {
+            // push it onto the free list
+            let new_node = ListNode {
+                next: self.list_heads[index].take(),
+            };
+            assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+            assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+            let new_node_ptr = ptr as *mut ListNode;
+            unsafe { new_node_ptr.write(new_node) };
+            self.list_heads[index] = Some(unsafe { &mut *new_node_ptr });
+            self.list_lengths[index] += 1;
+        } else {
+            // a small block but the free list is at capacity
+            // If we're at capacity, just leak this block (for now)
+            log_ratelimited!(
+                Level::Warn,
+                "free list for block size {} is at capacity, leaking block ptr=0x{:x}",
+                BLOCK_SIZES[index],
+                ptr as usize
+            );
+        }
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    /// Allocates `layout` without reclaiming memory if that fails.
+    fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-sanitizer")]
+        if let Some((padded, class)) = sanitizer::padded(layout) {
+            let block = self.lock().alloc_block(padded);
+            if block.is_null() {
+                return block;
+            }
+            return unsafe { sanitizer::on_alloc(block, layout, class) };
+        }
+        self.lock().alloc_block(layout)
+    }
+
+    /// Checks a sanitized allocation as it is freed, and puts its block in quarantine. The
+    /// block that leaves quarantine to make room goes back on its free list.
+    #[cfg(feature = "heap-sanitizer")]
+    unsafe fn sanitized_dealloc(&self, ptr: *mut u8, layout: Layout, class: usize) {
+        if let Err(violation) = unsafe { sanitizer::check_free(ptr, layout, class) } {
+            violation.report();
+            if !violation.kind.block_usable() {
+                return;
+            }
+        }
+        unsafe { sanitizer::poison(ptr, class) };
+        let evicted = {
+            let mut allocator = self.lock();
+            allocator
+                .quarantine
+                .push(sanitizer::block_of(ptr), class)
+                .map(|(block, class)| {
+                    let checked = unsafe { sanitizer::check_quarantined(block, class) };
+                    unsafe { allocator.free_small(class, block) };
+                    checked
+                })
+        };
+        // Reported with the heap unlocked, since writing the report may allocate
+        if let Some(Err(violation)) = evicted {
+            violation.report();
+        }
+    }
+
+    /// Moves every block in quarantine back to its free list, checking it on the way, and
+    /// returns how many bytes that freed up.
+    #[cfg(feature = "heap-sanitizer")]
+    pub(super) fn drain_quarantine(&self) -> usize {
+        let mut freed = 0;
+        loop {
+            let checked = {
+                let mut allocator = self.lock();
+                let Some((block, class)) = allocator.quarantine.pop() else {
+                    return freed;
+                };
+                let checked = unsafe { sanitizer::check_quarantined(block, class) };
+                unsafe { allocator.free_small(class, block) };
+                freed += BLOCK_SIZES[class];
+                checked
+            };
+            if let Err(violation) = checked {
+                violation.report();
+            }
+        }
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
@@ -121,37 +294,37 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
     ///     ## Steps:
     ///     1. Lock the allocator for a mutable reference.
-    ///     2. Determine block size via `list_index`.
+    ///     2. Determine block size via `size_class::index`.
     ///        - If `None`, use `fallback_alloc`.
     ///     3. If a valid index exists:
     ///        - Pop the first node from `list_heads[index]` using `Option::take`.
     ///        - If a node is available, update the list head and return the node as a raw pointer.
     ///        - If empty, allocate a new block with `BLOCK_SIZES[index]` for size/alignment, create a `Layout`, and use `fallback_alloc`.
     ///     4. Allocations greater than the largest block size in BLOCK_SIZES will be handed to the PageAllocator.
+    ///     5. If that fails, run `oom::reclaim` and try once more before returning null.
+    ///     6. With the `alloc-sites` feature, charge the allocation to its caller.
+    ///
+    ///     Until `init_heap_experimental` has run, allocations come from the bootstrap arena instead.
 
     ///     ## Safety:
     ///     - Marked `unsafe` due to raw pointer manipulation, necessitates on correct allocator use.
     ///
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // If no block of the required size is available, "refill" the list
-                        match allocator.refill_free_list(index) {
-                            Some(block) => block, // get one for the user that requested it, and put the rest in the free list
-                            None => ptr::null_mut(), // Out of memory
-                        }
-                    }
-                }
-            }
-            None => allocator.fallback_alloc(layout),
+        if fault::should_fail(Site::Heap) {
+            return ptr::null_mut();
+        }
+        if !BOOTSTRAP.is_retired() {
+            return BOOTSTRAP.alloc(layout);
         }
+        let mut block = self.try_alloc(layout);
+        if block.is_null() && oom::reclaim() > 0 {
+            block = self.try_alloc(layout);
+        }
+        #[cfg(feature = "alloc-sites")]
+        if !block.is_null() {
+            alloc_sites::on_alloc(block, layout);
+        }
+        block
     }
 
     ///
@@ -159,7 +332,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
     ///     ## Steps:
     ///     1. Lock the allocator.
-    ///     2. Determine block size via `list_index`.
+    ///     2. Determine block size via `size_class::index`.
     ///        - If `None`, deallocate with `fallback_allocator` using a `NonNull` pointer.
     ///     3. If a valid index exists:
     ///        - Create a new `ListNode` pointing to `list_heads[index]`.
@@ -168,60 +341,26 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     ///     4. Aligns and sizes blocks.
 
     ///     - Blocks from `fallback_alloc` are returned to it, while segregated blocks grow their respective lists as needed.
+    ///     - Memory from the bootstrap arena goes back to it.
 
     ///     ## Safety:
     ///     - `unsafe` for raw pointer manipulation and memory management. Validates alignment and size before writes.
     ///
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-
-        // figure out if it's small or large
-        if let Some(index) = list_index(&layout) {
-            // This is a small block
-            if allocator.list_lengths[index] < MAX_LIST_LENGTH {
-                // push it onto the free list
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe { new_node_ptr.write(new_node) };
-                allocator.list_heads[index] = Some(unsafe { &mut *new_node_ptr });
-                allocator.list_lengths[index] += 1;
-            } else {
-                // a small block but the free list is at capacity
-                // If we're at capacity, just leak this block (for now)
-                println!(
-                    "Warning: free list for block size {} is at capacity, leaking block ptr=0x{:x}",
-                    BLOCK_SIZES[index], ptr as usize
-                );
-            }
-        } else {
-            // Large allocation => look up `ptr` in the map and deallocate
-            let mut map = LARGE_ALLOCS.write();
-            let start_addr = ptr as usize;
-            for slot in map.iter_mut() {
-                if slot.is_some() {
-                    let (addr, info) = slot.unwrap();
-                    if addr == start_addr {
-                        let num_pages = info.num_pages;
-                        let mut guard = PAGE_ALLOCATOR.lock();
-                        if let Some(ref mut page_alloc) = *guard {
-                            page_alloc
-                                .dealloc(start_addr, num_pages)
-                                .expect("dealloc failed");
-                        }
-                    }
-                }
-            }
+        if BOOTSTRAP.contains(ptr) {
+            unsafe { BOOTSTRAP.dealloc(ptr, layout) };
+            return;
         }
+        #[cfg(feature = "alloc-sites")]
+        alloc_sites::on_dealloc(ptr, layout);
+        #[cfg(feature = "heap-sanitizer")]
+        if let Some((_, class)) = sanitizer::padded(layout) {
+            unsafe { self.sanitized_dealloc(ptr, layout, class) };
+            return;
+        }
+        unsafe { self.lock().dealloc_block(ptr, layout) };
+        // Pages of a large allocation are only freed with the heap unlocked
+        page_allocator::try_flush_retired();
     }
 }
-
-fn list_index(layout: &Layout) -> Option<usize> {
-    let required_block_size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
-}
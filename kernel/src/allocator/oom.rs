@@ -0,0 +1,218 @@
+//! What the kernel does when the heap runs out.
+//!
+//! A heap allocation that fails first calls [`reclaim`], which gives back what the allocator
+//! itself holds onto and then asks each [`Reclaimer`] registered by other subsystems to drop
+//! what it can spare. If anything was freed the allocation is tried once more. Only if that
+//! fails too does the allocator return null; fallible callers such as `try_reserve` see the
+//! error, and infallible ones end up in [`handle_alloc_error`], which prints the state of the
+//! allocators before panicking.
+use alloc::alloc::Layout;
+use core::time::Duration;
+use spin::Mutex;
+
+use super::ALLOCATOR;
+use super::alloc_info::LARGE_ALLOCS;
+use super::page_allocator::{self, PAGE_ALLOCATOR};
+use crate::error::KernelError;
+use crate::memory::{PAGE_SIZE, vma};
+use crate::metrics::Counter;
+use crate::task::sched;
+use crate::{kernel_init, serial_println};
+use kernel_algo::size_class::BLOCK_SIZES;
+use x86_64::VirtAddr;
+
+/// Reclaimers that can be registered.
+const MAX_RECLAIMERS: usize = 16;
+
+/// Something that can free memory when the heap runs out, e.g. a cache.
+#[derive(Clone, Copy)]
+pub struct Reclaimer {
+    pub name: &'static str,
+    /// Frees what it can and returns roughly how many bytes that was. It runs from inside a
+    /// failed allocation, so it must not allocate or take locks held around allocations.
+    pub reclaim: fn() -> usize,
+}
+
+static RECLAIMERS: Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> = Mutex::new([None; MAX_RECLAIMERS]);
+
+static RECLAIMS: Counter = Counter::new("alloc.oom_reclaims");
+static RECLAIMED_BYTES: Counter = Counter::new("alloc.oom_reclaimed_bytes");
+
+/// Adds `reclaimer` to those [`reclaim`] runs. Panics if there are already
+/// [`MAX_RECLAIMERS`].
+pub fn register(reclaimer: Reclaimer) {
+    let mut reclaimers = RECLAIMERS.lock();
+    let slot = reclaimers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many OOM reclaimers");
+    *slot = Some(reclaimer);
+}
+
+/// Removes the reclaimer [`register`]ed as `name`, freeing its slot. Returns whether there
+/// was one.
+pub fn unregister(name: &str) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    let Some(slot) = reclaimers
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|reclaimer| reclaimer.name == name))
+    else {
+        return false;
+    };
+    *slot = None;
+    true
+}
+
+/// Frees whatever memory can be spared, and returns roughly how many bytes that was. Called
+/// by the heap when an allocation fails, with the heap unlocked.
+pub fn reclaim() -> usize {
+    RECLAIMS.inc();
+    let mut freed = trim();
+
+    // Copied out so a reclaimer may register another
+    let reclaimers = match RECLAIMERS.try_lock() {
+        Some(reclaimers) => *reclaimers,
+        None => [None; MAX_RECLAIMERS],
+    };
+    for reclaimer in reclaimers.iter().flatten() {
+        freed += (reclaimer.reclaim)();
+    }
+
+    RECLAIMED_BYTES.add(freed as u64);
+    freed
+}
+
+/// Gives back what the allocator itself holds onto but doesn't need, and returns roughly how
+/// many bytes that was. Unlike [`reclaim`] it leaves other subsystems' caches alone, so it also
+/// runs periodically.
+pub fn trim() -> usize {
+    let mut freed = 0;
+
+    #[cfg(feature = "heap-sanitizer")]
+    {
+        freed += ALLOCATOR.drain_quarantine();
+    }
+
+    // Large allocations prune the page tables they used, but pages freed straight to the
+    // page allocator don't
+    if let Some(mut guard) = PAGE_ALLOCATOR.try_lock()
+        && let Some(page_alloc) = guard.as_mut()
+    {
+        // Covers every window the heap has grown into
+        let heap = VirtAddr::new(vma::ARENA.start)..VirtAddr::new(vma::ARENA.end);
+        page_alloc.prune_page_tables(heap);
+    }
+    // With the page allocator unlocked again, and along with anything else freed lately
+    freed += page_allocator::try_flush_retired() * PAGE_SIZE as usize;
+    freed
+}
+
+/// How often the heap is trimmed when memory isn't short.
+const TRIM_EVERY: Duration = Duration::from_secs(60);
+
+kernel_init!(Late, "heap-trim", init_stage);
+
+fn init_stage() -> Result<(), KernelError> {
+    sched::every(TRIM_EVERY, "heap trim", || {
+        trim();
+    });
+    Ok(())
+}
+
+/// Prints the state of the heap and the page allocator over serial. Locks that are held are
+/// skipped, since this runs when things have already gone wrong.
+pub fn dump() {
+    match ALLOCATOR.try_lock() {
+        Some(allocator) => {
+            let free = allocator.free_blocks();
+            for (size, count) in BLOCK_SIZES.iter().zip(free) {
+                serial_println!("  {:>4}-byte blocks free: {}", size, count);
+            }
+        }
+        None => {
+            serial_println!("  heap locked");
+        }
+    }
+    match LARGE_ALLOCS.try_read() {
+        Some(large) => {
+            let (count, pages) = large
+                .iter()
+                .flatten()
+                .fold((0, 0), |(count, pages), (_, info)| {
+                    (count + 1, pages + info.num_pages)
+                });
+            serial_println!(
+                "  large allocations: {} of {}, {} pages",
+                count,
+                large.len(),
+                pages
+            );
+        }
+        None => {
+            serial_println!("  large allocations locked");
+        }
+    }
+    match PAGE_ALLOCATOR.try_lock() {
+        Some(guard) => match guard.as_ref() {
+            Some(page_alloc) => {
+                serial_println!(
+                    "  free frames: {}, page-table frames: {}",
+                    page_alloc.frame_allocator.free_frames(),
+                    page_alloc.table_frames()
+                );
+            }
+            None => {
+                serial_println!("  page allocator not initialized");
+            }
+        },
+        None => {
+            serial_println!("  page allocator locked");
+        }
+    }
+    serial_println!(
+        "  reclaims: {}, {} bytes reclaimed",
+        RECLAIMS.get(),
+        RECLAIMED_BYTES.get()
+    );
+}
+
+/// Reports an infallible allocation of `layout` that failed even after [`reclaim`], and
+/// panics.
+pub fn handle_alloc_error(layout: Layout) -> ! {
+    serial_println!(
+        "\nOut of memory allocating {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+    dump();
+    panic!(
+        "out of memory: allocation of {} bytes failed",
+        layout.size()
+    );
+}
+
+#[test_case]
+fn test_failed_allocation_reclaims() {
+    use super::page_allocator::KERNEL_HEAP_MAX_SIZE;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn count_call() -> usize {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+    register(Reclaimer {
+        name: "test",
+        reclaim: count_call,
+    });
+
+    // Larger than the heap can grow, so it can't succeed however much is reclaimed
+    let mut huge: Vec<u8> = Vec::new();
+    let reclaims = RECLAIMS.get();
+    assert!(huge.try_reserve_exact(KERNEL_HEAP_MAX_SIZE).is_err());
+    assert!(unregister("test"));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(RECLAIMS.get() - reclaims, 1);
+    assert!(!unregister("test"));
+}
@@ -1,31 +1,165 @@
 use core::arch::x86_64::_rdrand64_step;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel_algo::range::FreeRanges;
 use lazy_static::lazy_static;
-use spin::mutex::Mutex;
+use spin::mutex::{Mutex, MutexGuard};
 use x86_64::{
-    VirtAddr,
+    PhysAddr, VirtAddr,
     structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+        Size4KiB,
         mapper::{MapToError, UnmapError},
     },
 };
 
-use crate::{memory::BitmapFrameAllocator, serial_println};
+use crate::{
+    fault::{self, Site},
+    memory::{BitmapFrameAllocator, CountingFrameAllocator, prune_page_tables, vma},
+    metrics::Counter,
+    serial_println,
+};
 
 lazy_static! {
-    pub static ref PAGE_ALLOCATOR: Mutex<Option<PageAllocator<OffsetPageTable<'static>, BitmapFrameAllocator<'static>>>> =
-        Mutex::new(None);
+    pub static ref PAGE_ALLOCATOR: Mutex<Option<KernelPageAllocator>> = Mutex::new(None);
 }
 
 const PAGE_SIZE: usize = 4096;
+
+static PAGES_MAPPED: Counter = Counter::new("alloc.pages_mapped");
+static PAGES_UNMAPPED: Counter = Counter::new("alloc.pages_unmapped");
+static HEAP_WINDOWS: Counter = Counter::new("alloc.heap_windows");
+static PAGES_LEAKED: Counter = Counter::new("alloc.address_space_leaked_pages");
+/// The heap's first window, at the start of [`vma::ARENA`].
 pub const KERNEL_HEAP_START: usize = 0xFFFF_FF00_0000_0000;
-pub const KERNEL_HEAP_SIZE: usize = 0x4000_0000; // 1GB
+pub const KERNEL_HEAP_SIZE: usize = crate::config::HEAP_SIZE;
 pub const KERNEL_HEAP_END: usize = KERNEL_HEAP_START + KERNEL_HEAP_SIZE;
+/// Address space the heap may take in all, across the windows it grows into.
+pub const KERNEL_HEAP_MAX_SIZE: usize = crate::config::HEAP_MAX_SIZE;
+/// What the heap's windows are called in [`vma`].
+pub const HEAP_WINDOW: &str = "kernel heap";
+
+/// How full, in percent, the current window gets before the allocator tries to take the
+/// address space after it, so the heap keeps growing in one piece.
+const GROW_THRESHOLD_PERCENT: usize = 75;
+
+/// Freed ranges of address space remembered for reuse. Beyond this, freed ranges that don't
+/// join onto one already remembered are lost.
+const MAX_FREE_RANGES: usize = 64;
+/// Ranges [`PageAllocator::remap`] can leave for [`flush_retired`] to give back. Beyond this,
+/// their address space is lost.
+const MAX_RETIRED_UNMAPPED: usize = 16;
+/// Frames [`flush_retired`] frees per shootdown.
+const BATCH_FRAMES: usize = 64;
+
+/// Set while [`PAGE_ALLOCATOR`] has retired pages for [`flush_retired`] to free.
+static RETIRED: AtomicBool = AtomicBool::new(false);
+
+/// How the address space the allocator has given back is split up, as
+/// [`PageAllocator::fragmentation`] reports it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fragmentation {
+    /// Bytes freed and waiting to be reused, in how many separate ranges.
+    pub free_bytes: usize,
+    pub free_ranges: usize,
+    /// The largest allocation the freed ranges can take.
+    pub largest_free: usize,
+    /// Bytes left in the current window past the bump pointer.
+    pub unused_window: usize,
+}
+
+impl Fragmentation {
+    /// External fragmentation of the freed ranges, in percent: 0 when they are one range, and
+    /// close to 100 when the largest is a sliver of them.
+    pub fn percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free * 100 / self.free_bytes
+    }
+}
+
+/// The address space a [`PageAllocator`] hands out, as [`PageAllocator::set_window`] saves it.
+pub struct Window {
+    current_virt: usize,
+    end_virt: usize,
+    window_start: usize,
+    limit: usize,
+    free: FreeRanges<MAX_FREE_RANGES>,
+}
 
 pub struct PageAllocator<M, F> {
     pub frame_allocator: F,
     pub mapper: M,
     current_virt: usize,
     end_virt: usize,
+    /// Start of the window `current_virt` is in, including any windows joined onto it.
+    window_start: usize,
+    /// Address space taken so far, and how much may be taken.
+    reserved: usize,
+    limit: usize,
+    /// Address space given back by [`PageAllocator::dealloc`], and the ends of windows left
+    /// behind when the heap moved to a new one, handed out again before `current_virt` moves.
+    free: FreeRanges<MAX_FREE_RANGES>,
+    /// Page-table frames `map_to` has allocated on this allocator's behalf, less those
+    /// pruned since.
+    table_frames: usize,
+    retired: Retired,
+}
+
+/// Pages given back while other CPUs may still have them in their TLBs. Neither their frames
+/// nor their address space can be reused until a shootdown has reached every CPU, and that
+/// can't be waited for with [`PAGE_ALLOCATOR`] locked: a CPU spinning on it with interrupts
+/// disabled would never answer. So they wait here for [`flush_retired`].
+struct Retired {
+    /// Freed ranges, still mapped, as a list through a [`RetiredRange`] in the first page of
+    /// each. 0 when empty.
+    mapped: usize,
+    /// Address space [`PageAllocator::remap`] has unmapped, to give back.
+    unmapped: [(usize, usize); MAX_RETIRED_UNMAPPED],
+    unmapped_len: usize,
+    /// Where empty page tables are to be freed, once the ranges above are unmapped.
+    prune: Option<Range<VirtAddr>>,
+}
+
+#[repr(C)]
+struct RetiredRange {
+    next: usize,
+    num_pages: usize,
+}
+
+/// What one [`flush_retired`] pass frees once the TLBs are clear.
+struct Batch {
+    frames: [PhysFrame; BATCH_FRAMES],
+    len: usize,
+    /// Whether the frames held page tables.
+    tables: bool,
+    /// Address space to give back.
+    release: Option<(usize, usize)>,
+    /// What every CPU has to drop from its TLB first.
+    flush: Range<VirtAddr>,
+}
+
+impl Batch {
+    fn new(flush: Range<VirtAddr>) -> Self {
+        Batch {
+            frames: [PhysFrame::containing_address(PhysAddr::zero()); BATCH_FRAMES],
+            len: 0,
+            tables: false,
+            release: None,
+            flush,
+        }
+    }
+}
+
+/// Collects the page tables [`prune_page_tables`] frees into a [`Batch`].
+struct BatchCollector<'a>(&'a mut Batch);
+
+impl FrameDeallocator<Size4KiB> for BatchCollector<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.0.frames[self.0.len] = frame;
+        self.0.len += 1;
+    }
 }
 
 impl<M, F> PageAllocator<M, F>
@@ -39,39 +173,206 @@ where
             frame_allocator,
             current_virt: start_virt,
             end_virt,
+            window_start: start_virt,
+            reserved: end_virt - start_virt,
+            limit: end_virt - start_virt,
+            free: FreeRanges::new(),
+            table_frames: 0,
+            retired: Retired {
+                mapped: 0,
+                unmapped: [(0, 0); MAX_RETIRED_UNMAPPED],
+                unmapped_len: 0,
+                prune: None,
+            },
         }
     }
 
+    /// Lets the allocator take more windows of address space from [`vma`] as it fills up,
+    /// up to `limit` bytes in all. It only ever uses the range it was created with otherwise.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// Address space the allocator has taken, with the windows it grew into.
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    /// Has the allocator hand out only `start..end`, as if it had just been created there,
+    /// and returns its own window and freed ranges for [`restore_window`](Self::restore_window).
+    pub fn set_window(&mut self, start: usize, end: usize) -> Window {
+        let saved = Window {
+            current_virt: self.current_virt,
+            end_virt: self.end_virt,
+            window_start: self.window_start,
+            limit: self.limit,
+            free: core::mem::take(&mut self.free),
+        };
+        self.current_virt = start;
+        self.end_virt = end;
+        self.window_start = start;
+        // Growing would take windows after `end` that the caller doesn't know about
+        self.limit = 0;
+        saved
+    }
+
+    /// Undoes [`set_window`](Self::set_window). Everything allocated in the window in between
+    /// must have been freed and flushed.
+    pub fn restore_window(&mut self, window: Window) {
+        self.current_virt = window.current_virt;
+        self.end_virt = window.end_virt;
+        self.window_start = window.window_start;
+        self.limit = window.limit;
+        self.free = window.free;
+    }
+
+    /// Takes the `len` bytes of address space right after the current window, joining them
+    /// onto it. Fails if something else has them or it would go past the limit.
+    fn extend(&mut self, len: usize) -> bool {
+        if self.reserved + len > self.limit
+            || vma::reserve_at(HEAP_WINDOW, VirtAddr::new(self.end_virt as u64), len as u64)
+                .is_err()
+        {
+            return false;
+        }
+        self.end_virt += len;
+        self.reserved += len;
+        HEAP_WINDOWS.inc();
+        true
+    }
+
+    /// Takes another window with room for `bytes`: after the current one if it's free, or
+    /// wherever [`vma`] has room otherwise, leaving the end of the current window unused.
+    fn grow(&mut self, bytes: usize) -> bool {
+        let windows = |bytes: usize| bytes.div_ceil(KERNEL_HEAP_SIZE) * KERNEL_HEAP_SIZE;
+        if self.extend(windows(self.current_virt + bytes - self.end_virt)) {
+            return true;
+        }
+        let len = windows(bytes);
+        if self.reserved + len > self.limit {
+            return false;
+        }
+        let Ok(window) = vma::reserve(HEAP_WINDOW, len as u64) else {
+            return false;
+        };
+        self.release(
+            self.current_virt,
+            self.end_virt.saturating_sub(self.current_virt),
+        );
+        self.window_start = window.start.as_u64() as usize;
+        self.current_virt = self.window_start;
+        self.end_virt = window.end.as_u64() as usize;
+        self.reserved += len;
+        HEAP_WINDOWS.inc();
+        true
+    }
+
     pub fn alloc(
         &mut self,
         num_pages: usize,
         flags: PageTableFlags,
     ) -> Result<usize, MapToError<Size4KiB>> {
         let bytes_needed = num_pages * PAGE_SIZE;
-        if self.current_virt + bytes_needed > self.end_virt {
+        let reused = self
+            .free
+            .take(bytes_needed as u64)
+            .map(|start| start as usize);
+        if reused.is_none()
+            && self.current_virt + bytes_needed > self.end_virt
+            && !self.grow(bytes_needed)
+        {
             return Err(MapToError::FrameAllocationFailed); // Out of memory
         }
 
-        let start_addr = self.current_virt;
+        let start_addr = reused.unwrap_or(self.current_virt);
 
         for i in 0..num_pages {
             let page_virt = (start_addr + i * PAGE_SIZE) as u64;
             let page = Page::containing_address(VirtAddr::new(page_virt));
-            let frame = self
-                .frame_allocator
-                .allocate_frame()
-                .ok_or(MapToError::FrameAllocationFailed)?;
-            unsafe {
-                self.mapper
-                    .map_to(page, frame, flags, &mut self.frame_allocator)?
-                    .flush();
+            if let Err(e) = self.map_new_frame(page, flags) {
+                self.unwind(
+                    Page::containing_address(VirtAddr::new(start_addr as u64)),
+                    i,
+                );
+                if reused.is_some() {
+                    self.release(start_addr, bytes_needed);
+                }
+                return Err(e);
             }
-
-            self.current_virt += bytes_needed;
+        }
+        PAGES_MAPPED.add(num_pages as u64);
+        if reused.is_some() {
+            return Ok(start_addr);
+        }
+        self.current_virt += bytes_needed;
+        let used = self.current_virt - self.window_start;
+        if used * 100 >= (self.end_virt - self.window_start) * GROW_THRESHOLD_PERCENT {
+            // Not needed yet, so it doesn't matter if the space is taken
+            self.extend(KERNEL_HEAP_SIZE);
         }
         Ok(start_addr)
     }
 
+    /// Maps `num_pages` freshly allocated frames at the fixed address `start`, e.g. for user
+    /// programs that are linked to run at a particular address.
+    pub fn map_at(
+        &mut self,
+        start: VirtAddr,
+        num_pages: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let first = Page::containing_address(start);
+        for (i, page) in Page::range(first, first + num_pages as u64).enumerate() {
+            if let Err(e) = self.map_new_frame(page, flags) {
+                self.unwind(first, i);
+                return Err(e);
+            }
+        }
+        PAGES_MAPPED.add(num_pages as u64);
+        Ok(())
+    }
+
+    /// Maps `page` to a newly allocated frame. Nothing stays allocated if that fails, apart
+    /// from page tables created on the way.
+    fn map_new_frame(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        if fault::should_fail(Site::MapTo) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let mut tables = CountingFrameAllocator::new(&mut self.frame_allocator);
+        let mapped = unsafe { self.mapper.map_to(page, frame, flags, &mut tables) };
+        self.table_frames += tables.count;
+        match mapped {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(e) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Unmaps the first `num_pages` pages from `first` of a mapping that failed part way,
+    /// and frees their frames. The pages were never handed out, so no other CPU can have
+    /// them cached and flushing them here is enough.
+    fn unwind(&mut self, first: Page, num_pages: usize) {
+        for page in Page::range(first, first + num_pages as u64) {
+            if let Ok((frame, flush)) = self.mapper.unmap(page) {
+                flush.flush();
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    }
+
     pub fn init_start_aslr(&mut self) {
         let mut rng = 0u64;
         unsafe {
@@ -80,26 +381,292 @@ where
         self.current_virt = KERNEL_HEAP_START + (rng as usize % KERNEL_HEAP_SIZE);
     }
 
+    /// Page-table frames currently in use for mappings made by this allocator.
+    pub fn table_frames(&self) -> usize {
+        self.table_frames
+    }
+
+    /// Frees the `num_pages` writable pages at `addr`. They stay mapped, and neither their
+    /// frames nor their address space are reused, until [`flush_retired`] has run, which the
+    /// caller does once it has unlocked [`PAGE_ALLOCATOR`].
     pub fn dealloc(&mut self, addr: usize, num_pages: usize) -> Result<(), UnmapError> {
+        if num_pages == 0 {
+            return Ok(());
+        }
+        let start = VirtAddr::new(addr as u64);
         for i in 0..num_pages {
-            let page_virt = (addr + i * PAGE_SIZE) as u64;
-            let page = Page::containing_address(VirtAddr::new(page_virt));
-            let (mapped_frame, flush) = self.mapper.unmap(page)?;
-            flush.flush();
-            //Safety: if this function is being called, you must be sure you are not deallocating a frame that is still in use
-            unsafe {
-                self.frame_allocator.deallocate_frame(mapped_frame);
+            let page = Page::<Size4KiB>::containing_address(start + (i * PAGE_SIZE) as u64);
+            self.mapper
+                .translate_page(page)
+                .map_err(|_| UnmapError::PageNotMapped)?;
+        }
+        // The pages are the allocator's again, so the first can hold the list entry
+        unsafe {
+            (addr as *mut RetiredRange).write(RetiredRange {
+                next: self.retired.mapped,
+                num_pages,
+            });
+        }
+        self.retired.mapped = addr;
+        RETIRED.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Remembers `len` bytes of unmapped address space at `start` for reuse.
+    fn release(&mut self, start: usize, len: usize) {
+        if !self.free.insert(start as u64, (start + len) as u64) {
+            PAGES_LEAKED.add((len / PAGE_SIZE) as u64);
+        }
+    }
+
+    /// Bytes of address space freed and waiting to be reused.
+    pub fn free_bytes(&self) -> usize {
+        self.free.free() as usize
+    }
+
+    pub fn fragmentation(&self) -> Fragmentation {
+        Fragmentation {
+            free_bytes: self.free.free() as usize,
+            free_ranges: self.free.len(),
+            largest_free: self.free.largest() as usize,
+            unused_window: self.end_virt - self.current_virt,
+        }
+    }
+
+    /// Takes `num_pages` pages of freed address space that end at or below `limit`, for an
+    /// allocation to move down into.
+    #[cfg(feature = "heap-compaction")]
+    pub fn take_free_below(&mut self, num_pages: usize, limit: usize) -> Option<usize> {
+        let len = (num_pages * PAGE_SIZE) as u64;
+        let start = self.free.take(len)?;
+        // The lowest range that fits, so nothing lower does either
+        if start + len > limit as u64 {
+            self.free.insert(start, start + len);
+            return None;
+        }
+        Some(start as usize)
+    }
+
+    /// Moves the `num_pages` pages mapped at `from` to `to`, address space taken with
+    /// [`take_free_below`](Self::take_free_below), by mapping their frames there and unmapping
+    /// them at `from`. Nothing is copied. `to` is given back if that fails.
+    #[cfg(feature = "heap-compaction")]
+    pub fn remap(
+        &mut self,
+        from: usize,
+        to: usize,
+        num_pages: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let page_at = |base: usize, i: usize| {
+            Page::<Size4KiB>::containing_address(VirtAddr::new((base + i * PAGE_SIZE) as u64))
+        };
+        for i in 0..num_pages {
+            let frame = self
+                .mapper
+                .translate_page(page_at(from, i))
+                .expect("moving pages that aren't mapped");
+            let mut tables = CountingFrameAllocator::new(&mut self.frame_allocator);
+            let mapped = unsafe {
+                self.mapper
+                    .map_to(page_at(to, i), frame, flags, &mut tables)
+            };
+            self.table_frames += tables.count;
+            if let Err(e) = mapped.map(|flush| flush.flush()) {
+                // The frames stay where they were
+                for page in 0..i {
+                    if let Ok((_, flush)) = self.mapper.unmap(page_at(to, page)) {
+                        flush.flush();
+                    }
+                }
+                self.release(to, num_pages * PAGE_SIZE);
+                return Err(e);
             }
         }
+        for i in 0..num_pages {
+            if let Ok((_, flush)) = self.mapper.unmap(page_at(from, i)) {
+                flush.ignore();
+            }
+        }
+        // Given back by `flush_retired`, once no CPU can still reach the frames through it
+        let retired = &mut self.retired;
+        match retired.unmapped.get_mut(retired.unmapped_len) {
+            Some(slot) => {
+                *slot = (from, num_pages * PAGE_SIZE);
+                retired.unmapped_len += 1;
+                RETIRED.store(true, Ordering::Release);
+            }
+            None => PAGES_LEAKED.add(num_pages as u64),
+        }
         Ok(())
     }
+
+    /// Gives the highest freed range back to the bump pointer if it ends there, so it can be
+    /// taken in one piece again. Returns how many bytes that was.
+    #[cfg(feature = "heap-compaction")]
+    pub fn trim(&mut self) -> usize {
+        let Some(start) = self.free.take_last_ending_at(self.current_virt as u64) else {
+            return 0;
+        };
+        let start = start as usize;
+        // Ranges left behind in an earlier window stay in the free set
+        if start < self.window_start {
+            self.free.insert(start as u64, self.window_start as u64);
+        }
+        let trimmed = self.current_virt - start.max(self.window_start);
+        self.current_virt = start.max(self.window_start);
+        trimmed
+    }
+}
+
+impl<F> PageAllocator<OffsetPageTable<'static>, F>
+where
+    F: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    /// Has [`flush_retired`] free the page tables in `range` that no longer map anything, e.g.
+    /// after a large [`PageAllocator::dealloc`]. Level 3 tables are shared by every address
+    /// space, so they stay.
+    pub fn prune_page_tables(&mut self, range: Range<VirtAddr>) {
+        let prune = &mut self.retired.prune;
+        *prune = Some(match prune.take() {
+            Some(pending) => pending.start.min(range.start)..pending.end.max(range.end),
+            None => range,
+        });
+        RETIRED.store(true, Ordering::Release);
+    }
+
+    /// Unmaps the next lot of retired pages, or unlinks the next lot of empty page tables,
+    /// for [`flush_retired`] to free after a shootdown. Returns `None` once there is nothing
+    /// left.
+    fn next_batch(&mut self) -> Option<Batch> {
+        let retired = &mut self.retired;
+        if retired.mapped != 0 {
+            let addr = retired.mapped;
+            let RetiredRange { next, num_pages } = unsafe { (addr as *const RetiredRange).read() };
+            let taken = num_pages.min(BATCH_FRAMES);
+            retired.mapped = next;
+            if taken < num_pages {
+                let rest = addr + taken * PAGE_SIZE;
+                unsafe {
+                    (rest as *mut RetiredRange).write(RetiredRange {
+                        next,
+                        num_pages: num_pages - taken,
+                    });
+                }
+                retired.mapped = rest;
+            }
+            let start = VirtAddr::new(addr as u64);
+            let mut batch = Batch::new(start..start + (taken * PAGE_SIZE) as u64);
+            for i in 0..taken {
+                let page = Page::containing_address(start + (i * PAGE_SIZE) as u64);
+                if let Ok((frame, flush)) = self.mapper.unmap(page) {
+                    flush.ignore();
+                    batch.frames[batch.len] = frame;
+                    batch.len += 1;
+                }
+            }
+            batch.release = Some((addr, taken * PAGE_SIZE));
+            return Some(batch);
+        }
+        if retired.unmapped_len > 0 {
+            retired.unmapped_len -= 1;
+            let (addr, len) = retired.unmapped[retired.unmapped_len];
+            let start = VirtAddr::new(addr as u64);
+            let mut batch = Batch::new(start..start + len as u64);
+            batch.release = Some((addr, len));
+            return Some(batch);
+        }
+        if let Some(range) = retired.prune.take() {
+            // Invalidating any page drops the cached paging structures along with it
+            let mut batch = Batch::new(range.start..range.start + 1u64);
+            batch.tables = true;
+            let offset = self.mapper.phys_offset().as_u64();
+            let freed = unsafe {
+                prune_page_tables(
+                    self.mapper.level_4_table_mut(),
+                    range.clone(),
+                    true,
+                    offset,
+                    &mut BatchCollector(&mut batch),
+                    BATCH_FRAMES,
+                )
+            };
+            if freed == BATCH_FRAMES {
+                // There may be more
+                self.retired.prune = Some(range);
+            }
+            if freed > 0 {
+                return Some(batch);
+            }
+        }
+        RETIRED.store(false, Ordering::Release);
+        None
+    }
+
+    /// Frees what [`next_batch`](Self::next_batch) took, now that no CPU has it cached.
+    fn free_batch(&mut self, batch: &Batch) {
+        for &frame in &batch.frames[..batch.len] {
+            unsafe { self.frame_allocator.deallocate_frame(frame) };
+        }
+        if batch.tables {
+            self.table_frames = self.table_frames.saturating_sub(batch.len);
+        } else {
+            PAGES_UNMAPPED.add(batch.len as u64);
+        }
+        if let Some((start, len)) = batch.release {
+            self.release(start, len);
+        }
+    }
+}
+
+pub type KernelPageAllocator =
+    PageAllocator<OffsetPageTable<'static>, BitmapFrameAllocator<'static>>;
+
+/// Frees the pages [`PAGE_ALLOCATOR`] has retired, after a TLB shootdown, and returns how
+/// many frames that was. Call it with [`PAGE_ALLOCATOR`] unlocked, and no lock held that
+/// another CPU could be spinning on with interrupts disabled.
+pub fn flush_retired() -> usize {
+    flush_with(|| Some(PAGE_ALLOCATOR.lock()))
+}
+
+/// Like [`flush_retired`], but leaves the pages for later rather than wait for
+/// [`PAGE_ALLOCATOR`], which the caller might hold.
+pub fn try_flush_retired() -> usize {
+    flush_with(|| PAGE_ALLOCATOR.try_lock())
+}
+
+fn flush_with<'a>(lock: impl Fn() -> Option<MutexGuard<'a, Option<KernelPageAllocator>>>) -> usize {
+    let mut freed = 0;
+    while RETIRED.load(Ordering::Acquire) {
+        let Some(mut guard) = lock() else {
+            break;
+        };
+        let Some(batch) = guard.as_mut().and_then(PageAllocator::next_batch) else {
+            break;
+        };
+        drop(guard);
+        crate::smp::shootdown(batch.flush.clone());
+        if let Some(page_alloc) = PAGE_ALLOCATOR.lock().as_mut() {
+            page_alloc.free_batch(&batch);
+        }
+        freed += batch.len;
+    }
+    freed
 }
 
 pub fn init_page_allocator(
     mapper: OffsetPageTable<'static>,
     frame_alloc: BitmapFrameAllocator<'static>,
 ) {
-    let page_alloc = PageAllocator::new(mapper, frame_alloc, KERNEL_HEAP_START, KERNEL_HEAP_END);
+    vma::reserve_at(
+        HEAP_WINDOW,
+        VirtAddr::new(KERNEL_HEAP_START as u64),
+        KERNEL_HEAP_SIZE as u64,
+    )
+    .expect("kernel heap window taken");
+    let mut page_alloc =
+        PageAllocator::new(mapper, frame_alloc, KERNEL_HEAP_START, KERNEL_HEAP_END);
+    page_alloc.set_limit(KERNEL_HEAP_MAX_SIZE);
     serial_println!("Page allocator initialized");
     crate::allocator::page_allocator::PAGE_ALLOCATOR
         .lock()
@@ -0,0 +1,347 @@
+//! A lightweight heap sanitizer for drivers under development, built with the `heap-sanitizer`
+//! feature.
+//!
+//! Each small allocation gets a [`Header`] in front of it and a redzone filling the rest of its
+//! block. The header records where the allocation was made, and is checked along with the
+//! redzone when the allocation is freed. Freed blocks are filled with a poison byte and wait in
+//! a [`Quarantine`] of [`QUARANTINE_LEN`] blocks before they can be handed out again. A block
+//! whose poison has changed by then was written after it was freed.
+//!
+//! A violation is written to the serial port with the allocation site and a backtrace of
+//! where it was found, and counted; the kernel carries on. Allocations too large or too
+//! aligned for a padded block go to the page allocator unchecked.
+use alloc::alloc::Layout;
+use core::fmt::{self, Write};
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kernel_algo::size_class::{self, BLOCK_SIZES};
+
+use crate::backtrace;
+use crate::metrics::Counter;
+
+/// Return addresses kept for the allocation site.
+const SITE_FRAMES: usize = 5;
+/// Frames of the allocator itself at the start of a walk from [`on_alloc`]: `on_alloc`, the
+/// heap's `try_alloc` and `GlobalAlloc::alloc`.
+const ALLOCATOR_FRAMES: usize = 3;
+
+const LIVE: u32 = 0xA11C_A7ED;
+const FREED: u32 = 0xF4EE_D0FF;
+/// Fills the end of a block past the allocation.
+const REDZONE_BYTE: u8 = 0xFC;
+/// Fills a freed block while it is in quarantine.
+const FREED_BYTE: u8 = 0xFD;
+
+/// Freed blocks held back from reuse.
+pub const QUARANTINE_LEN: usize = 512;
+
+/// Sits in front of each allocation. The magic comes last, so the first bytes written before
+/// the allocation change it.
+#[repr(C)]
+struct Header {
+    sites: [u64; SITE_FRAMES],
+    size: u32,
+    magic: u32,
+}
+
+/// Bytes in front of each allocation.
+pub const FRONT: usize = mem::size_of::<Header>();
+
+/// Largest alignment a sanitized allocation can have. Padded blocks are at least 64 bytes and
+/// aligned to their size, so the allocation after the header is 16-byte aligned.
+const MAX_ALIGN: usize = 16;
+
+const _: () = assert!(FRONT.is_multiple_of(MAX_ALIGN));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The redzone after the allocation was written.
+    Overflow,
+    /// The header before the allocation was written, or the pointer freed isn't one the heap
+    /// handed out with that layout.
+    Underflow,
+    /// A block in quarantine was written after it was freed.
+    UseAfterFree,
+    /// An allocation was freed twice.
+    DoubleFree,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 4] = [
+        Kind::Overflow,
+        Kind::Underflow,
+        Kind::UseAfterFree,
+        Kind::DoubleFree,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Overflow => "heap-buffer-overflow",
+            Kind::Underflow => "heap-buffer-underflow or invalid free",
+            Kind::UseAfterFree => "use-after-free",
+            Kind::DoubleFree => "double free",
+        }
+    }
+
+    /// Whether the block can still be put in quarantine. After the others the header can't be
+    /// trusted, or the block is already there, so it is left alone.
+    pub fn block_usable(self) -> bool {
+        matches!(self, Kind::Overflow | Kind::UseAfterFree)
+    }
+}
+
+/// A violation found when an allocation was freed or left quarantine.
+#[derive(Debug, Clone, Copy)]
+pub struct Violation {
+    pub kind: Kind,
+    /// The allocation, as the heap handed it out.
+    pub addr: usize,
+    /// Its size in bytes.
+    pub size: usize,
+    /// Offset from `addr` of the first byte found changed, if the header wasn't it.
+    pub offset: Option<usize>,
+    sites: [u64; SITE_FRAMES],
+}
+
+static VIOLATIONS: [AtomicU64; Kind::ALL.len()] = [const { AtomicU64::new(0) }; Kind::ALL.len()];
+static REPORTED: Counter = Counter::new("heap.sanitizer_violations");
+
+/// How many violations of `kind` have been reported.
+pub fn violations(kind: Kind) -> u64 {
+    VIOLATIONS[kind as usize].load(Ordering::Relaxed)
+}
+
+/// Reports go straight to the serial port, since the heap may be in no state to format them.
+struct Serial;
+
+impl Write for Serial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+impl Violation {
+    /// Writes the violation to the serial port and counts it. Call it without the heap locked.
+    pub fn report(&self) {
+        VIOLATIONS[self.kind as usize].fetch_add(1, Ordering::Relaxed);
+        REPORTED.inc();
+        // Writing to the serial port can't fail
+        let _ = self.write(&mut Serial);
+    }
+
+    fn write(&self, out: &mut dyn Write) -> fmt::Result {
+        write!(out, "\nheap-sanitizer: {} ", self.kind.name())?;
+        match self.offset {
+            Some(offset) => write!(out, "at {:#x}, byte {} of", self.addr + offset, offset)?,
+            None => write!(out, "in the header of")?,
+        }
+        writeln!(
+            out,
+            " the {}-byte allocation at {:#x}",
+            self.size, self.addr
+        )?;
+        writeln!(out, "Allocated at:")?;
+        let frames = self.sites.iter().take_while(|&&addr| addr != 0);
+        for (index, &return_addr) in frames.enumerate() {
+            backtrace::write_frame(out, index, return_addr)?;
+        }
+        writeln!(out, "Found at:")?;
+        backtrace::write(out)
+    }
+}
+
+/// The layout of the block that holds `layout` with its header and redzone, and its size
+/// class, or `None` if it isn't sanitized.
+pub fn padded(layout: Layout) -> Option<(Layout, usize)> {
+    if layout.align() > MAX_ALIGN {
+        return None;
+    }
+    // At least a word of redzone
+    let size = FRONT.checked_add(layout.size())?.checked_add(8)?;
+    let padded = Layout::from_size_align(size, layout.align()).ok()?;
+    size_class::index(&padded).map(|class| (padded, class))
+}
+
+/// Sets up `block` of size class `class` for an allocation of `layout`, and returns the
+/// allocation.
+///
+/// # Safety
+/// `block` must be a free block of the class [`padded`] gave for `layout`.
+#[inline(never)]
+pub unsafe fn on_alloc(block: *mut u8, layout: Layout, class: usize) -> *mut u8 {
+    let mut sites = [0; SITE_FRAMES];
+    let mut index: usize = 0;
+    backtrace::walk(|return_addr| {
+        if let Some(site) = index
+            .checked_sub(ALLOCATOR_FRAMES)
+            .and_then(|i| sites.get_mut(i))
+        {
+            *site = return_addr;
+        }
+        index += 1;
+    });
+    unsafe {
+        block.cast::<Header>().write(Header {
+            sites,
+            size: layout.size() as u32,
+            magic: LIVE,
+        });
+        let tail = FRONT + layout.size();
+        block
+            .add(tail)
+            .write_bytes(REDZONE_BYTE, BLOCK_SIZES[class] - tail);
+        block.add(FRONT)
+    }
+}
+
+/// The block an allocation handed out by [`on_alloc`] lives in.
+pub fn block_of(ptr: *mut u8) -> *mut u8 {
+    ptr.wrapping_sub(FRONT)
+}
+
+/// `start` plus the index of the first byte in `bytes` that isn't `expected`.
+fn first_changed(bytes: &[u8], start: usize, expected: u8) -> Option<usize> {
+    bytes
+        .iter()
+        .position(|&byte| byte != expected)
+        .map(|i| start + i)
+}
+
+/// Checks the header and redzone of the allocation at `ptr` as it is freed with `layout`.
+///
+/// # Safety
+/// `ptr` must have come from the heap with a layout [`padded`] gives class `class` for.
+pub unsafe fn check_free(ptr: *mut u8, layout: Layout, class: usize) -> Result<(), Violation> {
+    let block = block_of(ptr);
+    let header = unsafe { &*block.cast::<Header>() };
+    let mut violation = Violation {
+        kind: Kind::Underflow,
+        addr: ptr as usize,
+        size: layout.size(),
+        offset: None,
+        sites: header.sites,
+    };
+    if header.magic == FREED {
+        violation.kind = Kind::DoubleFree;
+        return Err(violation);
+    }
+    if header.magic != LIVE || header.size as usize != layout.size() {
+        return Err(violation);
+    }
+    let tail = FRONT + layout.size();
+    let redzone =
+        unsafe { core::slice::from_raw_parts(block.add(tail), BLOCK_SIZES[class] - tail) };
+    if let Some(offset) = first_changed(redzone, layout.size(), REDZONE_BYTE) {
+        violation.kind = Kind::Overflow;
+        violation.offset = Some(offset);
+        return Err(violation);
+    }
+    Ok(())
+}
+
+/// Marks the allocation at `ptr` freed and poisons it, keeping the allocation site.
+///
+/// # Safety
+/// `ptr` must be an allocation of class `class` that [`check_free`] has just looked at.
+pub unsafe fn poison(ptr: *mut u8, class: usize) {
+    let block = block_of(ptr);
+    unsafe {
+        (*block.cast::<Header>()).magic = FREED;
+        ptr.write_bytes(FREED_BYTE, BLOCK_SIZES[class] - FRONT);
+    }
+}
+
+/// Checks that `block`, leaving quarantine, hasn't been written since it was poisoned.
+///
+/// # Safety
+/// `block` must be a block of class `class` that [`poison`] poisoned.
+pub unsafe fn check_quarantined(block: *mut u8, class: usize) -> Result<(), Violation> {
+    let header = unsafe { &*block.cast::<Header>() };
+    let ptr = unsafe { block.add(FRONT) };
+    let contents = unsafe { core::slice::from_raw_parts(ptr, BLOCK_SIZES[class] - FRONT) };
+    let offset = first_changed(contents, 0, FREED_BYTE);
+    if header.magic == FREED && offset.is_none() {
+        return Ok(());
+    }
+    Err(Violation {
+        kind: Kind::UseAfterFree,
+        addr: ptr as usize,
+        size: header.size as usize,
+        offset,
+        sites: header.sites,
+    })
+}
+
+/// Freed blocks waiting to be reused, oldest first.
+pub struct Quarantine {
+    /// Each block with its size class.
+    blocks: [(usize, usize); QUARANTINE_LEN],
+    /// Where the next block goes, which once full is the oldest.
+    next: usize,
+    len: usize,
+}
+
+impl Quarantine {
+    pub const fn new() -> Self {
+        Quarantine {
+            blocks: [(0, 0); QUARANTINE_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Adds `block` of size class `class`, and returns the oldest block and its class if that
+    /// made room for it.
+    pub fn push(&mut self, block: *mut u8, class: usize) -> Option<(*mut u8, usize)> {
+        let evicted = mem::replace(&mut self.blocks[self.next], (block as usize, class));
+        self.next = (self.next + 1) % QUARANTINE_LEN;
+        if self.len < QUARANTINE_LEN {
+            self.len += 1;
+            return None;
+        }
+        Some((evicted.0 as *mut u8, evicted.1))
+    }
+
+    /// Takes out the oldest block and its class.
+    pub fn pop(&mut self) -> Option<(*mut u8, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+        let (block, class) = self.blocks[(self.next + QUARANTINE_LEN - self.len) % QUARANTINE_LEN];
+        self.len -= 1;
+        Some((block as *mut u8, class))
+    }
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_padded_layout() {
+    let (layout, class) = padded(Layout::from_size_align(24, 8).unwrap()).unwrap();
+    assert_eq!(layout.size(), FRONT + 24 + 8);
+    assert!(BLOCK_SIZES[class] >= layout.size());
+    // Too aligned for the header, or too large for a block
+    assert!(padded(Layout::from_size_align(64, 64).unwrap()).is_none());
+    assert!(padded(Layout::from_size_align(4096, 8).unwrap()).is_none());
+}
+
+#[test_case]
+fn test_quarantine_evicts_oldest() {
+    let mut quarantine = Quarantine::new();
+    let block = |i: usize| (0x1000 * (i + 1)) as *mut u8;
+    for i in 0..QUARANTINE_LEN {
+        assert!(quarantine.push(block(i), 2).is_none());
+    }
+    assert_eq!(
+        quarantine.push(block(QUARANTINE_LEN), 3),
+        Some((block(0), 2))
+    );
+    assert_eq!(quarantine.push(block(0), 3), Some((block(1), 2)));
+    assert_eq!(quarantine.pop(), Some((block(2), 2)));
+}
@@ -0,0 +1,260 @@
+//! Typed slab caches for kernel objects that are allocated and freed often.
+//!
+//! A [`SlabCache<T>`] carves slabs of pages from the page allocator into slots sized for `T`,
+//! so objects of one type sit together instead of spread over the heap's size classes. Slabs
+//! are never given back; a freed slot is reused by the next allocation from the same cache.
+//!
+//! Each CPU keeps a small magazine of free slots and only takes the cache's lock to move a
+//! batch of them, so allocating and freeing on one CPU rarely contends with other CPUs.
+use core::any::type_name;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::page_allocator::PAGE_ALLOCATOR;
+use crate::cpu::{MAX_CPUS, PerCpu};
+use crate::memory::PAGE_SIZE;
+
+/// Free slots each CPU holds for a cache.
+const MAGAZINE_SIZE: usize = 16;
+/// Slots moved between a magazine and the shared free list at once.
+const MAGAZINE_BATCH: usize = MAGAZINE_SIZE / 2;
+/// Slabs are made big enough for at least this many objects.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+struct Magazine {
+    slots: [*mut FreeSlot; MAGAZINE_SIZE],
+    len: usize,
+}
+
+// The slots are only ever used by whoever holds the magazine's lock
+unsafe impl Send for Magazine {}
+
+impl Magazine {
+    const fn new() -> Self {
+        Magazine {
+            slots: [ptr::null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+}
+
+/// An unused slot, linked into the shared free list through its first word.
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+struct Depot {
+    free: *mut FreeSlot,
+    slabs: usize,
+}
+
+unsafe impl Send for Depot {}
+
+impl Depot {
+    fn pop(&mut self) -> Option<*mut FreeSlot> {
+        let slot = NonNull::new(self.free)?.as_ptr();
+        self.free = unsafe { (*slot).next };
+        Some(slot)
+    }
+
+    fn push(&mut self, slot: *mut FreeSlot) {
+        unsafe { (*slot).next = self.free };
+        self.free = slot;
+    }
+}
+
+/// Usage of one cache, as reported by [`SlabCache::stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub slab_bytes: usize,
+    /// Objects currently allocated.
+    pub allocated: usize,
+}
+
+pub struct SlabCache<T> {
+    depot: Mutex<Depot>,
+    magazines: PerCpu<Mutex<Magazine>>,
+    allocated: AtomicUsize,
+    _marker: PhantomData<T>,
+}
+
+// Slots are handed out to one owner at a time, so sharing the cache is fine if `T` may move
+// between CPUs
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(align_of::<T>(), align_of::<FreeSlot>());
+    const SLOT_SIZE: usize =
+        max(size_of::<T>(), size_of::<FreeSlot>()).next_multiple_of(Self::SLOT_ALIGN);
+    const SLAB_BYTES: usize =
+        (Self::SLOT_SIZE * MIN_OBJECTS_PER_SLAB).next_multiple_of(PAGE_SIZE as usize);
+
+    pub const fn new() -> Self {
+        assert!(
+            Self::SLOT_ALIGN <= PAGE_SIZE as usize,
+            "slab objects can be at most page aligned"
+        );
+        SlabCache {
+            depot: Mutex::new(Depot {
+                free: ptr::null_mut(),
+                slabs: 0,
+            }),
+            magazines: PerCpu::new([const { Mutex::new(Magazine::new()) }; MAX_CPUS]),
+            allocated: AtomicUsize::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into a slot from this cache. Returns `None` if a new slab was needed and
+    /// memory ran out.
+    pub fn alloc(&self, value: T) -> Option<SlabBox<'_, T>> {
+        let ptr = self.alloc_uninit()?;
+        unsafe { ptr.write(value) };
+        Some(SlabBox { cache: self, ptr })
+    }
+
+    /// Drops the object at `ptr` and returns its slot to the cache.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`SlabCache::alloc_uninit`] on this cache, hold an initialised `T`,
+    /// and not be used again.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        unsafe {
+            ptr.drop_in_place();
+            self.free_uninit(ptr);
+        }
+    }
+
+    /// Takes a slot for a `T` without initialising it.
+    pub fn alloc_uninit(&self) -> Option<NonNull<T>> {
+        let slot = interrupts::without_interrupts(|| {
+            let mut magazine = self.magazines.get().lock();
+            if magazine.len == 0 {
+                self.refill(&mut magazine);
+            }
+            magazine.len = magazine.len.checked_sub(1)?;
+            Some(magazine.slots[magazine.len])
+        })?;
+        self.allocated.fetch_add(1, Ordering::Relaxed);
+        NonNull::new(slot.cast())
+    }
+
+    /// Returns a slot to the cache without dropping what's in it.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`SlabCache::alloc_uninit`] on this cache and not be used again.
+    pub unsafe fn free_uninit(&self, ptr: NonNull<T>) {
+        self.allocated.fetch_sub(1, Ordering::Relaxed);
+        interrupts::without_interrupts(|| {
+            let mut magazine = self.magazines.get().lock();
+            if magazine.len == MAGAZINE_SIZE {
+                let mut depot = self.depot.lock();
+                for _ in 0..MAGAZINE_BATCH {
+                    magazine.len -= 1;
+                    depot.push(magazine.slots[magazine.len]);
+                }
+            }
+            let len = magazine.len;
+            magazine.slots[len] = ptr.as_ptr().cast();
+            magazine.len += 1;
+        })
+    }
+
+    /// Moves a batch of free slots into `magazine`, carving a new slab if there are none.
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        if depot.free.is_null() {
+            self.grow(&mut depot);
+        }
+        while magazine.len < MAGAZINE_BATCH {
+            let Some(slot) = depot.pop() else {
+                break;
+            };
+            magazine.slots[magazine.len] = slot;
+            magazine.len += 1;
+        }
+    }
+
+    fn grow(&self, depot: &mut Depot) {
+        let pages = Self::SLAB_BYTES / PAGE_SIZE as usize;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let Some(start) = PAGE_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|page_alloc| page_alloc.alloc(pages, flags).ok())
+        else {
+            return;
+        };
+        // Push in reverse so slots are handed out in address order
+        for index in (0..Self::SLAB_BYTES / Self::SLOT_SIZE).rev() {
+            depot.push((start + index * Self::SLOT_SIZE) as *mut FreeSlot);
+        }
+        depot.slabs += 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.depot.lock().slabs;
+        SlabStats {
+            name: type_name::<T>(),
+            object_size: Self::SLOT_SIZE,
+            slabs,
+            slab_bytes: slabs * Self::SLAB_BYTES,
+            allocated: self.allocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        SlabCache::new()
+    }
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+/// An object in a [`SlabCache`], returned to the cache when dropped.
+pub struct SlabBox<'a, T> {
+    cache: &'a SlabCache<T>,
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<'_, T> {}
+unsafe impl<T: Sync> Sync for SlabBox<'_, T> {}
+
+impl<T> SlabBox<'_, T> {
+    pub fn as_ptr(this: &Self) -> *const T {
+        this.ptr.as_ptr()
+    }
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe { self.cache.free(self.ptr) };
+    }
+}
@@ -0,0 +1,97 @@
+//! Kernel backtraces, by unwinding with `.eh_frame` or walking the chain of saved frame
+//! pointers.
+//!
+//! The kernel is built with frame pointers (`force-frame-pointers` in `.cargo/config.toml`), so
+//! every frame starts with the caller's RBP followed by the return address into the caller.
+//! Following that chain up the stack gives the calls in progress, and [`crate::ksyms`] turns
+//! the return addresses into function names. Each frame is looked up in the page tables before
+//! it is read, so a corrupted chain ends the walk instead of faulting.
+//!
+//! Once [`eh_frame::init`] has indexed the kernel's unwind tables, [`walk`] unwinds with those
+//! instead. They don't depend on frame pointers, so backtraces keep working in a build without
+//! them, and they show the instruction an interrupt or exception stopped at rather than
+//! skipping over it. Both walks report return addresses, which [`write_frame`] symbolizes.
+pub mod eh_frame;
+
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::VirtAddr;
+
+use crate::ksyms::{self, Demangle};
+use crate::process::address_space::translate_active;
+
+/// Frames a walk goes through before giving up.
+pub const MAX_FRAMES: usize = 32;
+
+/// Calls `f` with the return address of each frame on the current stack, innermost first,
+/// starting with the address `walk` returns to.
+#[inline(never)]
+pub fn walk(f: impl FnMut(u64)) {
+    if eh_frame::is_loaded() {
+        eh_frame::walk_from(eh_frame::Registers::current(), f);
+        return;
+    }
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    walk_from(rbp, f);
+}
+
+/// Like [`walk`], starting from the frame `rbp` points to, e.g. the RBP in a
+/// [`TrapFrame`](crate::trap::TrapFrame).
+pub fn walk_from(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_readable(rbp, 16) {
+            return;
+        }
+        let (next, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_addr == 0 {
+            return;
+        }
+        f(return_addr);
+        // The caller's frame is further up the stack; anything else is garbage
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+/// Whether the `len` bytes at `addr`, at most a page, are mapped. They may straddle two pages.
+fn is_readable(addr: u64, len: u64) -> bool {
+    let Some(last) = addr.checked_add(len - 1) else {
+        return false;
+    };
+    [addr, last]
+        .into_iter()
+        .all(|addr| VirtAddr::try_new(addr).is_ok_and(|addr| translate_active(addr).is_some()))
+}
+
+/// Writes frame `index` of a backtrace, with the function the return address is in.
+pub fn write_frame(out: &mut dyn fmt::Write, index: usize, return_addr: u64) -> fmt::Result {
+    write!(out, "  #{:<2} {:#018x}", index, return_addr)?;
+    // The call is the instruction before the return address, which may be in another function
+    // if the call was the last thing in it
+    match ksyms::resolve(return_addr - 1) {
+        Some(symbol) => writeln!(
+            out,
+            " {}+{:#x}",
+            Demangle(symbol.name),
+            return_addr - symbol.start
+        ),
+        None => writeln!(out),
+    }
+}
+
+/// Writes a backtrace of the current stack to `out`, one frame per line.
+pub fn write(out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut index = 0;
+    let mut result = Ok(());
+    walk(|return_addr| {
+        if result.is_ok() {
+            result = write_frame(out, index, return_addr);
+        }
+        index += 1;
+    });
+    result
+}
@@ -0,0 +1,733 @@
+//! Unwinding with the DWARF call frame information in `.eh_frame`.
+//!
+//! The kernel is built with unwind tables (`force-unwind-tables` in `.cargo/config.toml`), so
+//! `.eh_frame` has a frame description entry (FDE) for every function, saying for each of its
+//! instructions where the caller's registers and the return address are. That holds whether or
+//! not the function keeps a frame pointer. The trap stubs describe themselves as signal frames
+//! whose caller is the interrupted code, so a walk carries on through interrupts and
+//! exceptions. [`init`] indexes the section of the kernel image; until then nothing unwinds.
+//!
+//! Only what the compiler emits for x86_64 is understood: CFA rules of a register plus an
+//! offset, and registers saved at an offset from the CFA. A frame whose CFA is a DWARF
+//! expression ends the walk.
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ops::Range;
+
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+
+use super::{MAX_FRAMES, is_readable};
+use crate::ksyms;
+
+/// Registers unwinding tracks: the 16 general-purpose ones and the return address, which are
+/// DWARF registers 0 to 16.
+const REGS: usize = 17;
+const RBP: usize = 6;
+const RSP: usize = 7;
+/// The return address column.
+const RA: usize = 16;
+/// How deep `DW_CFA_remember_state` can nest.
+const STATE_DEPTH: usize = 4;
+
+const DW_EH_PE_OMIT: u8 = 0xFF;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0A;
+const DW_EH_PE_SDATA4: u8 = 0x0B;
+const DW_EH_PE_SDATA8: u8 = 0x0C;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+/// The kernel's `.eh_frame`, indexed by [`init`].
+static EH_FRAME: OnceCell<EhFrame> = OnceCell::uninit();
+
+/// An `.eh_frame` section and its FDEs sorted by address.
+struct EhFrame {
+    data: &'static [u8],
+    /// The run-time address of `data`.
+    addr: u64,
+    /// What the image was moved by, for absolute addresses.
+    load_offset: u64,
+    fdes: Vec<FdeEntry>,
+}
+
+/// The code an FDE covers and where it starts in the section.
+#[derive(Debug, Clone)]
+struct FdeEntry {
+    code: Range<u64>,
+    offset: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    ra_register: u64,
+    /// How FDE addresses are encoded.
+    encoding: u8,
+    /// Whether FDEs have augmentation data, which is skipped.
+    augmented: bool,
+    /// Whether the frames it describes are interrupted code rather than calls, so the address
+    /// they return to is the next instruction to run, not one after a call.
+    signal: bool,
+    instructions: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct Fde {
+    cie: Cie,
+    code: Range<u64>,
+    instructions: Range<usize>,
+}
+
+/// Where a register of the caller is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    Undefined,
+    /// In the same register.
+    SameValue,
+    /// Saved at this offset from the CFA.
+    Offset(i64),
+    /// It is the CFA plus this offset.
+    ValOffset(i64),
+    /// In this other register.
+    Register(u16),
+}
+
+/// How to find the caller's registers at one instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Row {
+    cfa_register: u16,
+    cfa_offset: i64,
+    rules: [Rule; REGS],
+}
+
+impl Row {
+    /// Registers are where the callee found them, except the stack pointer, which is the CFA.
+    fn new() -> Self {
+        let mut rules = [Rule::SameValue; REGS];
+        rules[RSP] = Rule::ValOffset(0);
+        rules[RA] = Rule::Undefined;
+        Row {
+            cfa_register: RSP as u16,
+            cfa_offset: 0,
+            rules,
+        }
+    }
+
+    fn set(&mut self, register: u64, rule: Rule) {
+        if let Some(slot) = self.rules.get_mut(register as usize) {
+            *slot = rule;
+        }
+    }
+
+    /// Puts `register`'s rule back to what it is in `initial`.
+    fn restore(&mut self, register: u64, initial: &Row) {
+        if let Some(&rule) = initial.rules.get(register as usize) {
+            self.set(register, rule);
+        }
+    }
+}
+
+/// Register values by DWARF number: RAX, RDX, RCX, RBX, RSI, RDI, RBP, RSP, R8 to R15, and the
+/// address the frame is at. `None` where unwinding lost track of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers([Option<u64>; REGS]);
+
+impl Registers {
+    /// The callee-saved registers and the stack pointer where this is inlined, at an address
+    /// within the function it is inlined into.
+    #[inline(always)]
+    pub fn current() -> Self {
+        let (rip, rsp, rbp, rbx, r12, r13, r14, r15): (u64, u64, u64, u64, u64, u64, u64, u64);
+        unsafe {
+            asm!(
+                "lea {rip}, [rip]",
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                "mov {rbx}, rbx",
+                "mov {r12}, r12",
+                "mov {r13}, r13",
+                "mov {r14}, r14",
+                "mov {r15}, r15",
+                rip = out(reg) rip, rsp = out(reg) rsp, rbp = out(reg) rbp,
+                rbx = out(reg) rbx, r12 = out(reg) r12, r13 = out(reg) r13,
+                r14 = out(reg) r14, r15 = out(reg) r15,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        let mut registers = [None; REGS];
+        for (register, value) in [
+            (3, rbx),
+            (RBP, rbp),
+            (RSP, rsp),
+            (12, r12),
+            (13, r13),
+            (14, r14),
+            (15, r15),
+            (RA, rip),
+        ] {
+            registers[register] = Some(value);
+        }
+        Registers(registers)
+    }
+
+    fn get(&self, register: u16) -> Option<u64> {
+        *self.0.get(register as usize)?
+    }
+}
+
+/// Reads a little-endian `.eh_frame` field by field.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.end - self.pos < N {
+            return None;
+        }
+        let bytes = self.data.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|[b]| b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7F) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    /// Skips a NUL-terminated string and returns it.
+    fn cstr(&mut self) -> Option<&'a [u8]> {
+        let start = self.pos;
+        while self.u8()? != 0 {}
+        self.data.get(start..self.pos - 1)
+    }
+}
+
+impl EhFrame {
+    /// Indexes the FDEs of `data`, which is at `addr` at run time.
+    fn new(data: &'static [u8], addr: u64, load_offset: u64) -> Self {
+        let mut eh_frame = EhFrame {
+            data,
+            addr,
+            load_offset,
+            fdes: Vec::new(),
+        };
+        let mut offset = 0;
+        while let Some((body, next)) = eh_frame.record(offset) {
+            // A CIE pointer of 0 marks a CIE
+            let is_fde = eh_frame.reader(body).u32().is_some_and(|id| id != 0);
+            if is_fde && let Some(fde) = eh_frame.fde(offset) {
+                eh_frame.fdes.push(FdeEntry {
+                    code: fde.code,
+                    offset,
+                });
+            }
+            offset = next;
+        }
+        eh_frame.fdes.sort_unstable_by_key(|fde| fde.code.start);
+        eh_frame
+    }
+
+    fn reader(&self, range: Range<usize>) -> Reader<'static> {
+        Reader {
+            data: self.data,
+            pos: range.start,
+            end: range.end.min(self.data.len()),
+        }
+    }
+
+    /// The body of the CIE or FDE at `offset`, after its length, and where the next record
+    /// starts. `None` at the terminator or the end of the section.
+    fn record(&self, offset: usize) -> Option<(Range<usize>, usize)> {
+        let mut reader = self.reader(offset..self.data.len());
+        let length = reader.u32()?;
+        // Zero terminates the section; the 64-bit format isn't used on x86_64
+        if length == 0 || length == u32::MAX {
+            return None;
+        }
+        let end = reader.pos.checked_add(length as usize)?;
+        (end <= self.data.len()).then_some((reader.pos..end, end))
+    }
+
+    /// Reads a value in the format of the low bits of a pointer encoding, without applying it.
+    fn value(reader: &mut Reader, format: u8) -> Option<u64> {
+        let value = match format & 0x0F {
+            DW_EH_PE_ABSPTR | DW_EH_PE_UDATA8 => reader.u64()?,
+            DW_EH_PE_ULEB128 => reader.uleb()?,
+            DW_EH_PE_UDATA2 => u64::from(reader.u16()?),
+            DW_EH_PE_UDATA4 => u64::from(reader.u32()?),
+            DW_EH_PE_SLEB128 => reader.sleb()? as u64,
+            DW_EH_PE_SDATA2 => reader.u16()? as i16 as u64,
+            DW_EH_PE_SDATA4 => reader.u32()? as i32 as u64,
+            DW_EH_PE_SDATA8 => reader.u64()?,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Reads an address encoded as `encoding`.
+    fn pointer(&self, reader: &mut Reader, encoding: u8) -> Option<u64> {
+        let field = self.addr.wrapping_add(reader.pos as u64);
+        let value = Self::value(reader, encoding)?;
+        match encoding & 0x70 {
+            0 if encoding & 0x0F == DW_EH_PE_ABSPTR => Some(value.wrapping_add(self.load_offset)),
+            0 => Some(value),
+            DW_EH_PE_PCREL => Some(field.wrapping_add(value)),
+            _ => None,
+        }
+    }
+
+    fn cie(&self, offset: usize) -> Option<Cie> {
+        let (body, _) = self.record(offset)?;
+        let mut reader = self.reader(body.clone());
+        if reader.u32()? != 0 {
+            return None;
+        }
+        let version = reader.u8()?;
+        let augmentation = reader.cstr()?;
+        let code_align = reader.uleb()?;
+        let data_align = reader.sleb()?;
+        let ra_register = match version {
+            1 => u64::from(reader.u8()?),
+            _ => reader.uleb()?,
+        };
+        let mut cie = Cie {
+            code_align,
+            data_align,
+            ra_register,
+            encoding: DW_EH_PE_ABSPTR,
+            augmented: augmentation.first() == Some(&b'z'),
+            signal: false,
+            instructions: 0..0,
+        };
+        if let Some(letters) = augmentation.strip_prefix(b"z") {
+            let length = reader.uleb()? as usize;
+            let data_end = reader.pos.checked_add(length)?;
+            for &letter in letters {
+                match letter {
+                    b'R' => cie.encoding = reader.u8()?,
+                    b'P' => {
+                        let encoding = reader.u8()?;
+                        self.pointer(&mut reader, encoding)?;
+                    }
+                    b'L' => {
+                        reader.u8()?;
+                    }
+                    b'S' => cie.signal = true,
+                    _ => break,
+                }
+            }
+            reader.pos = data_end;
+        } else if !augmentation.is_empty() {
+            return None;
+        }
+        cie.instructions = reader.pos..body.end;
+        Some(cie)
+    }
+
+    fn fde(&self, offset: usize) -> Option<Fde> {
+        let (body, _) = self.record(offset)?;
+        let mut reader = self.reader(body.clone());
+        // The CIE is this far back from the field
+        let cie_pointer = reader.u32()? as usize;
+        let cie = self.cie(body.start.checked_sub(cie_pointer)?)?;
+        if cie.encoding == DW_EH_PE_OMIT {
+            return None;
+        }
+        let start = self.pointer(&mut reader, cie.encoding)?;
+        let length = Self::value(&mut reader, cie.encoding)?;
+        if cie.augmented {
+            let length = reader.uleb()? as usize;
+            reader.pos = reader.pos.checked_add(length)?;
+        }
+        Some(Fde {
+            cie,
+            code: start..start.checked_add(length)?,
+            instructions: reader.pos..body.end,
+        })
+    }
+
+    /// The FDE covering `pc`.
+    fn find(&self, pc: u64) -> Option<Fde> {
+        let index = self
+            .fdes
+            .partition_point(|fde| fde.code.start <= pc)
+            .checked_sub(1)?;
+        let entry = &self.fdes[index];
+        if !entry.code.contains(&pc) {
+            return None;
+        }
+        self.fde(entry.offset)
+    }
+
+    /// The row of `fde`'s table for `pc`.
+    fn row_at(&self, fde: &Fde, pc: u64) -> Option<Row> {
+        let cie = &fde.cie;
+        let mut row = Row::new();
+        // The CIE's initial instructions apply from the start of the function
+        let initial = row;
+        self.execute(
+            cie,
+            cie.instructions.clone(),
+            &mut row,
+            &initial,
+            0,
+            u64::MAX,
+        )?;
+        let initial = row;
+        self.execute(
+            cie,
+            fde.instructions.clone(),
+            &mut row,
+            &initial,
+            fde.code.start,
+            pc,
+        )?;
+        Some(row)
+    }
+
+    /// Runs call frame instructions from address `loc` until they move past `pc`. `initial` is
+    /// the row `DW_CFA_restore` goes back to.
+    fn execute(
+        &self,
+        cie: &Cie,
+        instructions: Range<usize>,
+        row: &mut Row,
+        initial: &Row,
+        mut loc: u64,
+        pc: u64,
+    ) -> Option<()> {
+        let mut reader = self.reader(instructions);
+        let mut saved = [*row; STATE_DEPTH];
+        let mut depth = 0;
+        let factored = |offset: u64| (offset as i64).checked_mul(cie.data_align);
+        let factored_signed = |offset: i64| offset.checked_mul(cie.data_align);
+        while reader.pos < reader.end {
+            let opcode = reader.u8()?;
+            let advance = match (opcode >> 6, opcode & 0x3F) {
+                // DW_CFA_advance_loc
+                (1, delta) => Some(u64::from(delta)),
+                // DW_CFA_offset
+                (2, register) => {
+                    row.set(u64::from(register), Rule::Offset(factored(reader.uleb()?)?));
+                    None
+                }
+                // DW_CFA_restore
+                (3, register) => {
+                    row.restore(u64::from(register), initial);
+                    None
+                }
+                (_, 0x00) => None,
+                // DW_CFA_set_loc
+                (_, 0x01) => {
+                    loc = self.pointer(&mut reader, cie.encoding)?;
+                    if loc > pc {
+                        return Some(());
+                    }
+                    None
+                }
+                (_, 0x02) => Some(u64::from(reader.u8()?)),
+                (_, 0x03) => Some(u64::from(reader.u16()?)),
+                (_, 0x04) => Some(u64::from(reader.u32()?)),
+                // DW_CFA_offset_extended
+                (_, 0x05) => {
+                    let register = reader.uleb()?;
+                    row.set(register, Rule::Offset(factored(reader.uleb()?)?));
+                    None
+                }
+                // DW_CFA_restore_extended
+                (_, 0x06) => {
+                    row.restore(reader.uleb()?, initial);
+                    None
+                }
+                // DW_CFA_undefined
+                (_, 0x07) => {
+                    row.set(reader.uleb()?, Rule::Undefined);
+                    None
+                }
+                // DW_CFA_same_value
+                (_, 0x08) => {
+                    row.set(reader.uleb()?, Rule::SameValue);
+                    None
+                }
+                // DW_CFA_register
+                (_, 0x09) => {
+                    let register = reader.uleb()?;
+                    row.set(register, Rule::Register(reader.uleb()? as u16));
+                    None
+                }
+                // DW_CFA_remember_state
+                (_, 0x0A) => {
+                    *saved.get_mut(depth)? = *row;
+                    depth += 1;
+                    None
+                }
+                // DW_CFA_restore_state, which keeps the CFA
+                (_, 0x0B) => {
+                    depth = depth.checked_sub(1)?;
+                    row.rules = saved[depth].rules;
+                    None
+                }
+                // DW_CFA_def_cfa
+                (_, 0x0C) => {
+                    row.cfa_register = reader.uleb()? as u16;
+                    row.cfa_offset = reader.uleb()? as i64;
+                    None
+                }
+                // DW_CFA_def_cfa_register
+                (_, 0x0D) => {
+                    row.cfa_register = reader.uleb()? as u16;
+                    None
+                }
+                // DW_CFA_def_cfa_offset
+                (_, 0x0E) => {
+                    row.cfa_offset = reader.uleb()? as i64;
+                    None
+                }
+                // DW_CFA_expression and DW_CFA_val_expression: a register this can't recover
+                (_, 0x10 | 0x16) => {
+                    let register = reader.uleb()?;
+                    let length = reader.uleb()? as usize;
+                    reader.pos = reader.pos.checked_add(length)?;
+                    row.set(register, Rule::Undefined);
+                    None
+                }
+                // DW_CFA_offset_extended_sf
+                (_, 0x11) => {
+                    let register = reader.uleb()?;
+                    row.set(register, Rule::Offset(factored_signed(reader.sleb()?)?));
+                    None
+                }
+                // DW_CFA_def_cfa_sf
+                (_, 0x12) => {
+                    row.cfa_register = reader.uleb()? as u16;
+                    row.cfa_offset = factored_signed(reader.sleb()?)?;
+                    None
+                }
+                // DW_CFA_def_cfa_offset_sf
+                (_, 0x13) => {
+                    row.cfa_offset = factored_signed(reader.sleb()?)?;
+                    None
+                }
+                // DW_CFA_val_offset
+                (_, 0x14) => {
+                    let register = reader.uleb()?;
+                    row.set(register, Rule::ValOffset(factored(reader.uleb()?)?));
+                    None
+                }
+                // DW_CFA_val_offset_sf
+                (_, 0x15) => {
+                    let register = reader.uleb()?;
+                    row.set(register, Rule::ValOffset(factored_signed(reader.sleb()?)?));
+                    None
+                }
+                // DW_CFA_GNU_args_size
+                (_, 0x2E) => {
+                    reader.uleb()?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                (_, 0x2F) => {
+                    let register = reader.uleb()?;
+                    row.set(
+                        register,
+                        Rule::Offset(factored(reader.uleb()?)?.checked_neg()?),
+                    );
+                    None
+                }
+                // DW_CFA_def_cfa_expression, or something unknown
+                _ => return None,
+            };
+            if let Some(delta) = advance {
+                loc = loc.checked_add(delta.checked_mul(cie.code_align)?)?;
+                if loc > pc {
+                    return Some(());
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Unwinds the frame `registers` are in, at `pc`, to its caller. Also says whether the
+    /// frame was a signal frame.
+    fn step(&self, registers: &Registers, pc: u64) -> Option<(Registers, bool)> {
+        let fde = self.find(pc)?;
+        let row = self.row_at(&fde, pc)?;
+        let cfa = registers
+            .get(row.cfa_register)?
+            .checked_add_signed(row.cfa_offset)?;
+        let mut caller = Registers([None; REGS]);
+        for (register, rule) in row.rules.iter().enumerate() {
+            caller.0[register] = match *rule {
+                Rule::Undefined => None,
+                Rule::SameValue => registers.0[register],
+                Rule::Offset(offset) => read(cfa.checked_add_signed(offset)?),
+                Rule::ValOffset(offset) => cfa.checked_add_signed(offset),
+                Rule::Register(other) => registers.get(other),
+            };
+        }
+        // The CIE may name another column for the return address
+        if fde.cie.ra_register != RA as u64 {
+            caller.0[RA] = caller.get(fde.cie.ra_register as u16);
+        }
+        Some((caller, fde.cie.signal))
+    }
+
+    fn walk(&self, mut registers: Registers, mut f: impl FnMut(u64)) {
+        // The first frame is stopped at `pc` itself, not after a call
+        let mut exact = true;
+        for _ in 0..MAX_FRAMES {
+            let Some(pc) = registers.get(RA as u16) else {
+                return;
+            };
+            let lookup = if exact { pc } else { pc.wrapping_sub(1) };
+            let Some((caller, signal)) = self.step(&registers, lookup) else {
+                return;
+            };
+            match caller.get(RA as u16) {
+                Some(0) | None => return,
+                Some(return_addr) => f(return_addr),
+            }
+            registers = caller;
+            exact = signal;
+        }
+    }
+}
+
+/// Reads the word at `addr`, if it is mapped.
+fn read(addr: u64) -> Option<u64> {
+    (addr.is_multiple_of(8) && is_readable(addr, 8)).then(|| unsafe { *(addr as *const u64) })
+}
+
+/// Indexes the kernel image's `.eh_frame`, and returns how many functions it describes. Needs
+/// the heap and the physical memory offset.
+pub fn init(boot_info: &BootInfo) -> usize {
+    let Some((addr, data)) =
+        ksyms::kernel_image(boot_info).and_then(|image| ksyms::find_section(image, ".eh_frame"))
+    else {
+        return 0;
+    };
+    let load_offset = boot_info.kernel_image_offset;
+    let eh_frame = EhFrame::new(data, addr.wrapping_add(load_offset), load_offset);
+    let count = eh_frame.fdes.len();
+    let _ = EH_FRAME.try_init_once(|| eh_frame);
+    count
+}
+
+/// Whether [`init`] has found unwind information to walk with.
+pub fn is_loaded() -> bool {
+    EH_FRAME
+        .get()
+        .is_some_and(|eh_frame| !eh_frame.fdes.is_empty())
+}
+
+/// Calls `f` with the return address of each frame above the one `registers` are in,
+/// innermost first. Through an interrupt, the address is where the interrupted code was.
+pub fn walk_from(registers: Registers, f: impl FnMut(u64)) {
+    if let Some(eh_frame) = EH_FRAME.get() {
+        eh_frame.walk(registers, f);
+    }
+}
+
+/// A CIE for the usual x86_64 entry state, followed by the FDE of a function at 0x2000 that
+/// pushes RBP and sets it up as the frame pointer, for a section at 0x1000.
+#[cfg(test)]
+#[rustfmt::skip]
+static TEST_SECTION: [u8; 56] = [
+    // CIE: length, id, version 1, "zR", code and data alignment, return address column
+    20, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16,
+    // Augmentation data: FDE addresses are 4-byte PC-relative
+    1, 0x1B,
+    // CFA is RSP + 8 and the return address is at CFA - 8; padding
+    0x0C, 7, 8, 0x90, 1, 0, 0,
+    // FDE: length, back 28 bytes to the CIE, starts 0xFE0 past its field at 0x1020, 16 bytes
+    24, 0, 0, 0, 28, 0, 0, 0, 0xE0, 0x0F, 0, 0, 16, 0, 0, 0, 0,
+    // After `push rbp`: CFA is RSP + 16 and RBP is at CFA - 16
+    0x41, 0x0E, 16, 0x86, 2,
+    // After `mov rbp, rsp`: CFA is RBP + 16; padding
+    0x43, 0x0D, 6, 0, 0, 0,
+    // Terminator
+    0, 0, 0, 0,
+];
+
+#[test_case]
+fn test_unwind_frame_pointer_function() {
+    let eh_frame = EhFrame::new(&TEST_SECTION, 0x1000, 0);
+    assert_eq!(eh_frame.fdes.len(), 1);
+    assert_eq!(eh_frame.fdes[0].code, 0x2000..0x2010);
+    assert!(eh_frame.find(0x2010).is_none());
+
+    let fde = eh_frame.find(0x2000).unwrap();
+    let row = eh_frame.row_at(&fde, 0x2000).unwrap();
+    assert_eq!((row.cfa_register, row.cfa_offset), (RSP as u16, 8));
+    assert_eq!(row.rules[RA], Rule::Offset(-8));
+    let row = eh_frame.row_at(&fde, 0x2003).unwrap();
+    assert_eq!((row.cfa_register, row.cfa_offset), (RSP as u16, 16));
+    assert_eq!(row.rules[RBP], Rule::Offset(-16));
+    let row = eh_frame.row_at(&fde, 0x2004).unwrap();
+    assert_eq!((row.cfa_register, row.cfa_offset), (RBP as u16, 16));
+
+    // Saved RBP and the return address, as the function's frame holds them
+    let stack: [u64; 2] = [0xAAAA_0000, 0xFFFF_8000_0000_1234];
+    let frame = stack.as_ptr() as u64;
+    let mut registers = Registers([None; REGS]);
+    registers.0[RBP] = Some(frame);
+    registers.0[RSP] = Some(frame - 32);
+    let (caller, signal) = eh_frame.step(&registers, 0x2008).unwrap();
+    assert!(!signal);
+    assert_eq!(caller.get(RA as u16), Some(stack[1]));
+    assert_eq!(caller.get(RBP as u16), Some(stack[0]));
+    assert_eq!(caller.get(RSP as u16), Some(frame + 16));
+}
@@ -0,0 +1,224 @@
+//! Checks that a [`BlockDevice`] behaves the way file systems rely on: requests stay within
+//! the sectors they name, requests at and past the end of the device succeed and fail as they
+//! should, requests issued together all complete correctly, and flushes complete.
+//!
+//! Every driver is run through [`check`] in the integration tests, against [`RamDisk`] as the
+//! reference. It overwrites the device, so only run it on scratch disks.
+//!
+//! [`RamDisk`]: super::RamDisk
+use alloc::vec::Vec;
+
+use futures_util::future::join_all;
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+
+/// Sectors the checks need the device to have.
+pub const MIN_SECTORS: u64 = 16;
+
+/// Requests issued at once by the concurrency checks.
+const CONCURRENT_REQUESTS: u64 = 8;
+
+/// A check the device failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failure {
+    pub check: &'static str,
+    /// The error the device returned, if that is why the check failed.
+    pub error: Option<BlockError>,
+}
+
+type Sector = [u8; SECTOR_SIZE];
+
+/// Contents that differ for every sector and `seed`.
+fn pattern(sector: u64, seed: u8) -> Sector {
+    core::array::from_fn(|i| (sector as usize * 31 + i) as u8 ^ seed.wrapping_mul(0x5B))
+}
+
+/// Turns an error from the device into a failure of `check`.
+fn failed(check: &'static str) -> impl FnOnce(BlockError) -> Failure {
+    move |error| Failure {
+        check,
+        error: Some(error),
+    }
+}
+
+fn ensure(check: &'static str, condition: bool) -> Result<(), Failure> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Failure { check, error: None })
+    }
+}
+
+async fn write(
+    device: &dyn BlockDevice,
+    check: &'static str,
+    sector: u64,
+    buf: &[u8],
+) -> Result<(), Failure> {
+    device.write(sector, buf).await.map_err(failed(check))
+}
+
+async fn read(
+    device: &dyn BlockDevice,
+    check: &'static str,
+    sector: u64,
+    sectors: usize,
+) -> Result<Vec<u8>, Failure> {
+    let mut buf = alloc::vec![0; sectors * SECTOR_SIZE];
+    device.read(sector, &mut buf).await.map_err(failed(check))?;
+    Ok(buf)
+}
+
+/// Expects a request to fail with `expected`.
+fn rejected(
+    check: &'static str,
+    result: Result<(), BlockError>,
+    expected: BlockError,
+) -> Result<(), Failure> {
+    match result {
+        Err(error) if error == expected => Ok(()),
+        Err(error) => Err(Failure {
+            check,
+            error: Some(error),
+        }),
+        Ok(()) => Err(Failure { check, error: None }),
+    }
+}
+
+/// Runs every check on `device`, which must be writable and have at least [`MIN_SECTORS`]
+/// sectors, and returns the first that fails.
+pub async fn check(device: &dyn BlockDevice) -> Result<(), Failure> {
+    ensure("device size", device.sector_count() >= MIN_SECTORS)?;
+    round_trip(device).await?;
+    sector_boundaries(device).await?;
+    end_of_device(device).await?;
+    bad_buffers(device).await?;
+    concurrent_requests(device).await?;
+    flush(device).await
+}
+
+async fn round_trip(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "round trip";
+    let data = pattern(0, 1);
+    write(device, CHECK, 0, &data).await?;
+    ensure(CHECK, read(device, CHECK, 0, 1).await? == data)?;
+
+    // One request for several sectors, read back one at a time and all together
+    let data: Vec<u8> = (2..6).flat_map(|sector| pattern(sector, 2)).collect();
+    write(device, CHECK, 2, &data).await?;
+    for sector in 2..6 {
+        ensure(
+            CHECK,
+            read(device, CHECK, sector, 1).await? == pattern(sector, 2),
+        )?;
+    }
+    ensure(CHECK, read(device, CHECK, 2, 4).await? == data)
+}
+
+async fn sector_boundaries(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "sector boundaries";
+    for sector in 6..9 {
+        write(device, CHECK, sector, &pattern(sector, 3)).await?;
+    }
+    // Rewriting the middle sector must leave its neighbours alone
+    write(device, CHECK, 7, &pattern(7, 4)).await?;
+    ensure(CHECK, read(device, CHECK, 6, 1).await? == pattern(6, 3))?;
+    ensure(CHECK, read(device, CHECK, 7, 1).await? == pattern(7, 4))?;
+    ensure(CHECK, read(device, CHECK, 8, 1).await? == pattern(8, 3))
+}
+
+async fn end_of_device(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "end of device";
+    let last = device.sector_count() - 1;
+    write(device, CHECK, last, &pattern(last, 5)).await?;
+    ensure(
+        CHECK,
+        read(device, CHECK, last, 1).await? == pattern(last, 5),
+    )?;
+
+    let mut buf = [0; 2 * SECTOR_SIZE];
+    rejected(
+        CHECK,
+        device.read(last + 1, &mut buf[..SECTOR_SIZE]).await,
+        BlockError::OutOfRange,
+    )?;
+    rejected(
+        CHECK,
+        device.read(last, &mut buf).await,
+        BlockError::OutOfRange,
+    )?;
+    rejected(
+        CHECK,
+        device.read(u64::MAX, &mut buf[..SECTOR_SIZE]).await,
+        BlockError::OutOfRange,
+    )?;
+    // A write that doesn't fit must not happen in part
+    rejected(
+        CHECK,
+        device.write(last, &[0xEE; 2 * SECTOR_SIZE]).await,
+        BlockError::OutOfRange,
+    )?;
+    ensure(
+        CHECK,
+        read(device, CHECK, last, 1).await? == pattern(last, 5),
+    )
+}
+
+async fn bad_buffers(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "partial sectors";
+    let mut buf = [0; SECTOR_SIZE + 1];
+    rejected(
+        CHECK,
+        device.read(0, &mut buf[..100]).await,
+        BlockError::Misaligned,
+    )?;
+    rejected(CHECK, device.write(0, &buf).await, BlockError::Misaligned)?;
+    ensure(CHECK, read(device, CHECK, 0, 1).await? == pattern(0, 1))?;
+    // Nothing to transfer is not an error
+    device.read(0, &mut []).await.map_err(failed(CHECK))
+}
+
+async fn concurrent_requests(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "concurrent requests";
+    let base = device.sector_count() - 1 - CONCURRENT_REQUESTS;
+    let sectors: Vec<u64> = (base..base + CONCURRENT_REQUESTS).collect();
+    let data: Vec<Sector> = sectors.iter().map(|&sector| pattern(sector, 6)).collect();
+    let writes = sectors
+        .iter()
+        .zip(&data)
+        .map(|(&sector, data)| device.write(sector, data));
+    for result in join_all(writes).await {
+        result.map_err(failed(CHECK))?;
+    }
+
+    let mut bufs: Vec<Sector> = alloc::vec![[0; SECTOR_SIZE]; sectors.len()];
+    // Issued in the opposite order from the writes
+    let reads = sectors
+        .iter()
+        .zip(bufs.iter_mut())
+        .rev()
+        .map(|(&sector, buf)| device.read(sector, buf));
+    for result in join_all(reads).await {
+        result.map_err(failed(CHECK))?;
+    }
+    ensure(CHECK, bufs == data)
+}
+
+async fn flush(device: &dyn BlockDevice) -> Result<(), Failure> {
+    const CHECK: &str = "flush";
+    let flushed = |result: Result<(), BlockError>| result.map_err(failed(CHECK));
+    flushed(device.flush().await)?;
+    // Nothing written since
+    flushed(device.flush().await)?;
+
+    write(device, CHECK, 1, &pattern(1, 7)).await?;
+    flushed(device.flush().await)?;
+    ensure(CHECK, read(device, CHECK, 1, 1).await? == pattern(1, 7))?;
+
+    // A flush issued alongside writes completes with them
+    let data = pattern(9, 8);
+    let (written, flush) = futures_util::future::join(device.write(9, &data), device.flush()).await;
+    flushed(written)?;
+    flushed(flush)?;
+    ensure(CHECK, read(device, CHECK, 9, 1).await? == data)
+}
@@ -0,0 +1,128 @@
+//! Block devices: disks addressed in fixed-size sectors.
+//!
+//! Every disk driver implements [`BlockDevice`] and registers its disks here. Requests are
+//! asynchronous, so a driver can complete them from its interrupt handler while the executor
+//! runs other tasks. [`RamDisk`] is the reference implementation, and [`conformance`] checks
+//! that a device behaves the way file systems expect.
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::{Pin, pin};
+use core::task::{Context, Poll, Waker};
+
+use spin::RwLock;
+
+pub mod conformance;
+pub mod ramdisk;
+
+pub use ramdisk::RamDisk;
+
+/// Bytes in a sector. Devices with larger native sectors emulate this size.
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last sector.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    Misaligned,
+    /// The device can't be written to.
+    ReadOnly,
+    /// The device reported an error.
+    Io,
+}
+
+/// A request in flight. It borrows the device and the buffer until it completes.
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+pub trait BlockDevice: Send + Sync {
+    /// A short name for messages, e.g. `ram0`.
+    fn name(&self) -> &str;
+
+    /// Size of the device in sectors.
+    fn sector_count(&self) -> u64;
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector` into `buf`.
+    fn read<'a>(&'a self, sector: u64, buf: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Writes `buf` to the sectors starting at `sector`. The data may sit in a cache on the
+    /// device until the next [`flush`](BlockDevice::flush).
+    fn write<'a>(&'a self, sector: u64, buf: &'a [u8]) -> BlockFuture<'a>;
+
+    /// Completes once every write that completed before it was issued is on stable storage.
+    fn flush(&self) -> BlockFuture<'_>;
+}
+
+/// Checks a request of `len` bytes at `sector` against a device of `sector_count` sectors,
+/// before any of it is carried out.
+pub fn check_request(sector_count: u64, sector: u64, len: usize) -> Result<(), BlockError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::Misaligned);
+    }
+    let end = sector
+        .checked_add((len / SECTOR_SIZE) as u64)
+        .ok_or(BlockError::OutOfRange)?;
+    if end > sector_count {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+static DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+
+/// Makes `device` available to [`devices`] and [`find`]. Drivers call this for each disk they
+/// find.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.write().push(device);
+}
+
+/// Every registered device, in the order they were registered.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Like [`find`], but gives up rather than wait for a device being registered. For the panic
+/// path, where the lock may never be released.
+pub fn try_find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .try_read()?
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// Runs `request` to completion without the executor, polling it until it is done. For
+/// callers that can't await it, like monitor commands.
+pub fn wait<T>(request: impl Future<Output = T>) -> T {
+    let mut request = pin!(request);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(result) = request.as_mut().poll(&mut context) {
+            return result;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_check_request() {
+    assert_eq!(check_request(8, 0, 8 * SECTOR_SIZE), Ok(()));
+    assert_eq!(check_request(8, 7, SECTOR_SIZE), Ok(()));
+    assert_eq!(check_request(8, 8, 0), Ok(()));
+    assert_eq!(
+        check_request(8, 7, 2 * SECTOR_SIZE),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        check_request(8, u64::MAX, SECTOR_SIZE),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(check_request(8, 0, 100), Err(BlockError::Misaligned));
+}
@@ -0,0 +1,148 @@
+//! A block device in memory, the reference for how drivers should behave.
+//!
+//! Like a disk with a volatile write cache, written sectors are held apart from the "media"
+//! until a flush. [`RamDisk::lose_power`] throws the cache away, so tests can check that file
+//! systems flush where they need to. Every request yields to the executor once before it
+//! completes, as one waiting for an interrupt would, so requests issued together overlap.
+use alloc::collections::BTreeMap;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use spin::Mutex;
+
+use super::{BlockDevice, BlockError, BlockFuture, SECTOR_SIZE, check_request};
+use crate::mem;
+
+pub struct RamDisk {
+    name: String,
+    sectors: u64,
+    read_only: bool,
+    /// Contents as of the last flush.
+    media: Mutex<Vec<u8>>,
+    /// Sectors written since the last flush, by sector number.
+    cache: Mutex<BTreeMap<u64, [u8; SECTOR_SIZE]>>,
+}
+
+impl RamDisk {
+    /// A zeroed, writable disk of `sectors` sectors.
+    pub fn new(name: &str, sectors: u64) -> RamDisk {
+        RamDisk::with_contents(name, alloc::vec![0; sectors as usize * SECTOR_SIZE])
+    }
+
+    /// A writable disk holding `image`, which is padded with zeroes to a whole sector.
+    pub fn with_contents(name: &str, mut image: Vec<u8>) -> RamDisk {
+        image.resize(image.len().next_multiple_of(SECTOR_SIZE), 0);
+        RamDisk {
+            name: String::from(name),
+            sectors: (image.len() / SECTOR_SIZE) as u64,
+            read_only: false,
+            media: Mutex::new(image),
+            cache: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Makes writes fail with [`BlockError::ReadOnly`].
+    pub fn read_only(mut self) -> RamDisk {
+        self.read_only = true;
+        self
+    }
+
+    /// Drops every write since the last flush, as if the machine had lost power.
+    pub fn lose_power(&self) {
+        self.cache.lock().clear();
+    }
+
+    /// Sectors written but not yet flushed.
+    pub fn dirty_sectors(&self) -> usize {
+        self.cache.lock().len()
+    }
+
+    fn read_now(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, sector, buf.len())?;
+        let media = self.media.lock();
+        let cache = self.cache.lock();
+        for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+            match cache.get(&sector) {
+                Some(cached) => mem::copy(chunk, cached),
+                None => {
+                    let offset = sector as usize * SECTOR_SIZE;
+                    mem::copy(chunk, &media[offset..offset + SECTOR_SIZE]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_now(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self.sectors, sector, buf.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut cache = self.cache.lock();
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            let mut data = [0; SECTOR_SIZE];
+            mem::copy(&mut data, chunk);
+            cache.insert(sector + i as u64, data);
+        }
+        Ok(())
+    }
+
+    fn flush_now(&self) {
+        let mut media = self.media.lock();
+        for (sector, data) in core::mem::take(&mut *self.cache.lock()) {
+            let offset = sector as usize * SECTOR_SIZE;
+            mem::copy(&mut media[offset..offset + SECTOR_SIZE], &data);
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read<'a>(&'a self, sector: u64, buf: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.read_now(sector, buf)
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buf: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.write_now(sector, buf)
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async move {
+            YieldOnce(false).await;
+            self.flush_now();
+            Ok(())
+        })
+    }
+}
+
+/// Pending the first time it is polled, ready the second.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,189 @@
+//! The CMOS: 128 bytes of battery-backed RAM behind ports 0x70 and 0x71, shared by the
+//! real-time clock and the firmware's settings.
+//!
+//! A byte is read or written by selecting it in the index port and then using the data port.
+//! Bit 7 of the index port also masks NMIs, so every access masks them while it runs and then
+//! puts the mask back the way [`set_nmi_masked`] left it. The pair of port accesses runs under
+//! a lock with interrupts off, so accesses from different CPUs or interrupt handlers don't
+//! select each other's bytes.
+//!
+//! Bytes `0x10..=0x2D` hold the firmware's configuration and are covered by the checksum in
+//! `0x2E`/`0x2F`; [`write`] keeps it correct. [`standard`] reads the fields PC firmware
+//! defines there.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::ioport::{self, IoRegion};
+
+const INDEX_PORT: u16 = 0x70;
+const PORT_COUNT: u16 = 2;
+/// The data port, as an offset from the index port.
+const DATA: u16 = 1;
+const NMI_DISABLE: u8 = 0x80;
+
+/// Bytes of CMOS RAM; the index port's last bit is the NMI mask.
+pub const SIZE: usize = 0x80;
+
+pub const RTC_STATUS_A: u8 = 0x0A;
+pub const RTC_STATUS_B: u8 = 0x0B;
+/// Which RTC interrupts are pending. Reading it acknowledges them.
+pub const RTC_STATUS_C: u8 = 0x0C;
+/// Left selected between accesses, as reading it has no side effects.
+const RTC_STATUS_D: u8 = 0x0D;
+pub const DIAGNOSTIC_STATUS: u8 = 0x0E;
+pub const SHUTDOWN_STATUS: u8 = 0x0F;
+pub const FLOPPY_TYPES: u8 = 0x10;
+pub const EQUIPMENT: u8 = 0x14;
+/// Low bytes of little-endian words.
+pub const BASE_MEMORY_KIB: u8 = 0x15;
+pub const EXTENDED_MEMORY_KIB: u8 = 0x17;
+/// The bytes the checksum covers, and the checksum, big-endian.
+pub const CHECKSUMMED: core::ops::RangeInclusive<u8> = 0x10..=0x2D;
+pub const CHECKSUM: u8 = 0x2E;
+
+static PORTS: Once<Mutex<IoRegion>> = Once::new();
+static NMI_MASKED: AtomicBool = AtomicBool::new(false);
+
+fn ports() -> &'static Mutex<IoRegion> {
+    PORTS.call_once(|| {
+        let region = unsafe { ioport::claim("cmos", INDEX_PORT, PORT_COUNT) };
+        Mutex::new(region.expect("CMOS ports taken"))
+    })
+}
+
+/// Runs `f` with the CMOS ports to itself, then puts the NMI mask back.
+fn access<T>(f: impl FnOnce(&IoRegion) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let ports = ports().lock();
+        let result = f(&ports);
+        ports.write(0, nmi_bit() | RTC_STATUS_D);
+        result
+    })
+}
+
+fn nmi_bit() -> u8 {
+    if NMI_MASKED.load(Ordering::Relaxed) {
+        NMI_DISABLE
+    } else {
+        0
+    }
+}
+
+/// Selects `register` with NMIs masked.
+fn select(ports: &IoRegion, register: u8) {
+    assert!((register as usize) < SIZE, "CMOS register {:#x}", register);
+    ports.write(0, NMI_DISABLE | register);
+}
+
+fn read_locked(ports: &IoRegion, register: u8) -> u8 {
+    select(ports, register);
+    ports.read(DATA)
+}
+
+fn write_locked(ports: &IoRegion, register: u8, value: u8) {
+    select(ports, register);
+    ports.write(DATA, value);
+}
+
+/// Masks or unmasks NMIs at the index port. They stay as set across CMOS accesses.
+pub fn set_nmi_masked(masked: bool) {
+    NMI_MASKED.store(masked, Ordering::Relaxed);
+    access(|_| ());
+}
+
+pub fn read(register: u8) -> u8 {
+    access(|ports| read_locked(ports, register))
+}
+
+/// Writes `value` at `register`, updating the checksum if the byte is covered by it.
+pub fn write(register: u8, value: u8) {
+    access(|ports| {
+        let old = read_locked(ports, register);
+        write_locked(ports, register, value);
+        if CHECKSUMMED.contains(&register) {
+            let stored = [
+                read_locked(ports, CHECKSUM),
+                read_locked(ports, CHECKSUM + 1),
+            ];
+            let [high, low] = u16::from_be_bytes(stored)
+                .wrapping_sub(old as u16)
+                .wrapping_add(value as u16)
+                .to_be_bytes();
+            write_locked(ports, CHECKSUM, high);
+            write_locked(ports, CHECKSUM + 1, low);
+        }
+    })
+}
+
+/// Reads all of CMOS RAM.
+pub fn read_all() -> [u8; SIZE] {
+    access(|ports| core::array::from_fn(|register| read_locked(ports, register as u8)))
+}
+
+/// The sum of the checksummed bytes in `bytes`, as firmware computes it.
+fn checksum(bytes: &[u8; SIZE]) -> u16 {
+    bytes[*CHECKSUMMED.start() as usize..=*CHECKSUMMED.end() as usize]
+        .iter()
+        .map(|&byte| byte as u16)
+        .sum()
+}
+
+/// The fields PC firmware keeps in CMOS RAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Standard {
+    /// POST's diagnostic flags; nonzero bits mean a test failed.
+    pub diagnostic_status: u8,
+    /// Why the CPU was last reset, as firmware recorded it.
+    pub shutdown_status: u8,
+    /// Floppy drive types, drive 0 in the high nibble.
+    pub floppy_types: u8,
+    pub equipment: u8,
+    pub base_memory_kib: u16,
+    pub extended_memory_kib: u16,
+    /// Whether the checksum matches, so the configuration bytes can be trusted.
+    pub checksum_valid: bool,
+}
+
+impl Standard {
+    pub fn parse(bytes: &[u8; SIZE]) -> Self {
+        let word = |register: u8| {
+            u16::from_le_bytes([bytes[register as usize], bytes[register as usize + 1]])
+        };
+        let stored = u16::from_be_bytes([bytes[CHECKSUM as usize], bytes[CHECKSUM as usize + 1]]);
+        Standard {
+            diagnostic_status: bytes[DIAGNOSTIC_STATUS as usize],
+            shutdown_status: bytes[SHUTDOWN_STATUS as usize],
+            floppy_types: bytes[FLOPPY_TYPES as usize],
+            equipment: bytes[EQUIPMENT as usize],
+            base_memory_kib: word(BASE_MEMORY_KIB),
+            extended_memory_kib: word(EXTENDED_MEMORY_KIB),
+            checksum_valid: checksum(bytes) == stored,
+        }
+    }
+}
+
+/// Reads the standard fields from CMOS RAM.
+pub fn standard() -> Standard {
+    Standard::parse(&read_all())
+}
+
+#[test_case]
+fn test_parse_standard_fields() {
+    let mut bytes = [0; SIZE];
+    bytes[FLOPPY_TYPES as usize] = 0x40;
+    bytes[BASE_MEMORY_KIB as usize..][..2].copy_from_slice(&640u16.to_le_bytes());
+    bytes[EXTENDED_MEMORY_KIB as usize..][..2].copy_from_slice(&0xFC00u16.to_le_bytes());
+    // 0x40 + 0x80 + 0x02 + 0xFC
+    bytes[CHECKSUM as usize..][..2].copy_from_slice(&0x01BEu16.to_be_bytes());
+
+    let standard = Standard::parse(&bytes);
+    assert_eq!(standard.floppy_types, 0x40);
+    assert_eq!(standard.base_memory_kib, 640);
+    assert_eq!(standard.extended_memory_kib, 0xFC00);
+    assert!(standard.checksum_valid);
+
+    bytes[EQUIPMENT as usize] = 1;
+    assert!(!Standard::parse(&bytes).checksum_valid);
+}
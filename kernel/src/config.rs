@@ -0,0 +1,22 @@
+//! Build-time configuration.
+//!
+//! `build.rs` generates the constants below from `kernel.config` (see `kconfig.rs` for the
+//! options and their defaults), so sizes and limits are set in one place rather than in the
+//! modules that use them. Drivers switched off there are left out with `cfg(driver = ...)`.
+
+/// Where console output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleBackend {
+    /// The framebuffer, or the VGA text buffer when the bootloader gave no framebuffer.
+    Auto,
+    /// Serial only; the screen is left alone.
+    Serial,
+}
+
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
+
+// Every AP with a stack needs a per-CPU slot besides the BSP's: AP_STACKS + 1 <= MAX_CPUS
+const _: () = assert!(
+    AP_STACKS < MAX_CPUS,
+    "CONFIG_AP_STACKS must be below CONFIG_MAX_CPUS"
+);
@@ -0,0 +1,41 @@
+//! The console used from the first instruction of `kernel_main` until the framebuffer console
+//! is up.
+//!
+//! It needs neither the heap nor any init stage: output goes straight to the serial port, and
+//! also to the terminal, which is shown on the VGA text buffer when the bootloader left the
+//! machine in text mode, i.e. gave us no framebuffer. Text printed before the framebuffer comes
+//! up appears once it does. If it never comes up the early console simply stays in charge, so a
+//! panic in GDT or memory setup still reaches both serial and the screen.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bootloader_api::BootInfo;
+use bootloader_api::info::Optional;
+
+use crate::config::{self, ConsoleBackend};
+use crate::vga_buffer;
+
+/// Set until [`handoff`].
+static ACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Shows the console on the VGA text buffer if the machine booted without a framebuffer, unless
+/// the build configuration keeps the console on serial. Safe
+/// to call before anything else, as it only reads `boot_info`.
+pub fn init(boot_info: &BootInfo) {
+    let Optional::Some(offset) = boot_info.physical_memory_offset else {
+        return;
+    };
+    if boot_info.framebuffer.as_ref().is_none() && config::CONSOLE == ConsoleBackend::Auto {
+        // Low memory isn't identity mapped, so the buffer is only reachable through the offset
+        unsafe { super::attach_text(offset + vga_buffer::BUFFER_PHYS_ADDR) };
+    }
+}
+
+/// Hands the screen over to the framebuffer console. From then on `print!` output no longer
+/// goes to serial as well.
+pub fn handoff() {
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
@@ -0,0 +1,770 @@
+//! Text consoles for `print!` output.
+//!
+//! What is on screen is kept as text in a [`Terminal`]: a grid of [`Cell`]s with a cursor, the
+//! current colors, and the rows that scrolled off the top. The terminal draws through a
+//! [`Console`] backend picked at boot, the bootloader's framebuffer or, when the machine booted
+//! in text mode, the VGA text buffer. Colors and scrollback are handled by the terminal, so they
+//! work the same on either backend.
+//!
+//! Once the executor runs, printing only queues the text and [`render`] draws it from a task.
+//!
+//! Characters are laid out by [`char_width`]: wide ones, such as CJK, take two cells, and
+//! combining marks and other zero-width characters are left out. Tabs move to the next multiple
+//! of [`TAB_WIDTH`] columns.
+//!
+//! A blinking cursor marks where the next character goes. Typed input is written with [`echo`]
+//! rather than printed, so the terminal knows which text on the current line is input being
+//! edited: other output is written above it, and the input is written again below.
+pub mod early;
+pub mod render;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use bootloader_api::info::FrameBufferInfo;
+use kernel_algo::text::char_width;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+
+use crate::framebuffer::FrameBufferWriter;
+use crate::task::sleep::sleep_ticks;
+use crate::vga_buffer;
+
+/// The 16 colors of the VGA palette, numbered as in text mode attributes.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Color {
+    #[default]
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+impl Color {
+    /// The color as `0xRRGGBB`, as the VGA palette shows it.
+    pub const fn rgb(self) -> u32 {
+        match self {
+            Color::Black => 0x000000,
+            Color::Blue => 0x0000AA,
+            Color::Green => 0x00AA00,
+            Color::Cyan => 0x00AAAA,
+            Color::Red => 0xAA0000,
+            Color::Magenta => 0xAA00AA,
+            Color::Brown => 0xAA5500,
+            Color::LightGray => 0xAAAAAA,
+            Color::DarkGray => 0x555555,
+            Color::LightBlue => 0x5555FF,
+            Color::LightGreen => 0x55FF55,
+            Color::LightCyan => 0x55FFFF,
+            Color::LightRed => 0xFF5555,
+            Color::Pink => 0xFF55FF,
+            Color::Yellow => 0xFFFF55,
+            Color::White => 0xFFFFFF,
+        }
+    }
+}
+
+pub const DEFAULT_FOREGROUND: Color = Color::Yellow;
+pub const DEFAULT_BACKGROUND: Color = Color::Black;
+
+/// One character position on screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cell {
+    /// `'\0'` for a position nothing was written to, drawn as a blank. The second cell of a
+    /// wide character is blank too.
+    pub ch: char,
+    pub fg: Color,
+    pub bg: Color,
+}
+
+impl Cell {
+    const EMPTY: Cell = Cell {
+        ch: '\0',
+        fg: Color::Black,
+        bg: Color::Black,
+    };
+
+    const fn blank(fg: Color, bg: Color) -> Cell {
+        Cell { ch: '\0', fg, bg }
+    }
+}
+
+/// A screen the terminal can draw text cells on.
+pub trait Console: Send {
+    /// Columns and rows of cells that fit on screen.
+    fn size(&self) -> (usize, usize);
+
+    /// Draws `cell` at column `col` of row `row`.
+    fn draw(&mut self, col: usize, row: usize, cell: Cell);
+
+    /// Moves every row up by one and blanks the bottom row in `bg`.
+    fn scroll_up(&mut self, bg: Color);
+
+    /// Blanks the whole screen in `bg`.
+    fn clear(&mut self, bg: Color);
+}
+
+/// The largest screen the terminal keeps text for; a bigger backend only uses this much of it.
+pub const MAX_COLUMNS: usize = 200;
+pub const MAX_ROWS: usize = 60;
+/// Rows kept after they scroll off the top.
+pub const SCROLLBACK_ROWS: usize = 100;
+const HISTORY_ROWS: usize = MAX_ROWS + SCROLLBACK_ROWS;
+
+/// Columns between tab stops.
+pub const TAB_WIDTH: usize = 8;
+
+/// The size assumed until a backend is attached, the VGA text mode one.
+const DEFAULT_SIZE: (usize, usize) = (80, 25);
+
+/// Timer ticks the cursor stays shown or hidden for: half a second.
+const CURSOR_BLINK_TICKS: u64 = crate::interrupts::APIC_TIMER_HZ as u64 / 2;
+
+enum Backend {
+    /// Nothing on screen yet; text is only kept in the history.
+    None,
+    Text(vga_buffer::Writer),
+    Framebuffer(FrameBufferWriter),
+}
+
+impl Backend {
+    fn console(&mut self) -> Option<&mut dyn Console> {
+        match self {
+            Backend::None => None,
+            Backend::Text(writer) => Some(writer),
+            Backend::Framebuffer(writer) => Some(writer),
+        }
+    }
+}
+
+struct Terminal {
+    backend: Backend,
+    /// A ring of rows. Screen row 0 is at `top`; the `scrolled` rows before it are scrollback.
+    history: [[Cell; MAX_COLUMNS]; HISTORY_ROWS],
+    top: usize,
+    scrolled: usize,
+    /// How many rows back from the bottom the screen shows, 0 when following output.
+    view: usize,
+    columns: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: Color,
+    bg: Color,
+    /// Whether the cursor is in the shown half of its blink.
+    cursor_on: bool,
+    /// The screen position the cursor is drawn at, if it is drawn.
+    cursor_at: Option<(usize, usize)>,
+    /// Input echoed since the last newline, which ends the text on screen.
+    echo: String,
+}
+
+static TERMINAL: Mutex<Terminal> = Mutex::new(Terminal::new());
+
+impl Terminal {
+    const fn new() -> Self {
+        Terminal {
+            backend: Backend::None,
+            history: [[Cell::EMPTY; MAX_COLUMNS]; HISTORY_ROWS],
+            top: 0,
+            scrolled: 0,
+            view: 0,
+            columns: DEFAULT_SIZE.0,
+            rows: DEFAULT_SIZE.1,
+            col: 0,
+            row: 0,
+            fg: DEFAULT_FOREGROUND,
+            bg: DEFAULT_BACKGROUND,
+            cursor_on: true,
+            cursor_at: None,
+            echo: String::new(),
+        }
+    }
+
+    /// Where screen row `row` is kept in the history, with the view at the bottom.
+    fn line(&self, row: usize) -> usize {
+        (self.top + row) % HISTORY_ROWS
+    }
+
+    /// Switches to `backend` and redraws the screen on it.
+    fn attach(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.resize();
+    }
+
+    /// Fits the screen to the backend's size, e.g. after a mode change, and redraws it. When it
+    /// got shorter the top rows go to the scrollback, so the cursor stays on screen.
+    fn resize(&mut self) {
+        let (columns, rows) = self.backend.console().map_or(DEFAULT_SIZE, |c| c.size());
+        let columns = columns.clamp(1, MAX_COLUMNS);
+        let rows = rows.clamp(1, MAX_ROWS);
+        if self.row >= rows {
+            let shift = self.row + 1 - rows;
+            self.top = (self.top + shift) % HISTORY_ROWS;
+            self.scrolled += shift;
+            self.row -= shift;
+        }
+        // Rows the screen grew into held the oldest scrollback
+        for row in self.rows..rows {
+            let line = self.line(row);
+            self.history[line] = [Cell::blank(self.fg, self.bg); MAX_COLUMNS];
+        }
+        self.columns = columns;
+        self.rows = rows;
+        self.scrolled = self.scrolled.min(HISTORY_ROWS - rows);
+        self.col = self.col.min(columns);
+        self.view = 0;
+        self.redraw();
+    }
+
+    /// Draws every cell of the screen, as seen from `view`.
+    fn redraw(&mut self) {
+        let first = (self.top + HISTORY_ROWS - self.view) % HISTORY_ROWS;
+        let bg = self.bg;
+        self.cursor_at = None;
+        let Some(console) = self.backend.console() else {
+            return;
+        };
+        console.clear(bg);
+        for row in 0..self.rows {
+            let line = &self.history[(first + row) % HISTORY_ROWS];
+            for (col, &cell) in line[..self.columns].iter().enumerate() {
+                if cell != Cell::blank(cell.fg, bg) {
+                    console.draw(col, row, cell);
+                }
+            }
+        }
+        self.show_cursor();
+    }
+
+    /// Draws the cursor over the cell the next character goes in, in the current colors
+    /// swapped, unless it is in the hidden half of its blink or the view is in the scrollback.
+    fn show_cursor(&mut self) {
+        if !self.cursor_on || self.view != 0 || self.cursor_at.is_some() {
+            return;
+        }
+        // Past the last column until the next character wraps
+        let (col, row) = (self.col.min(self.columns - 1), self.row);
+        let cell = Cell {
+            ch: self.history[self.line(row)][col].ch,
+            fg: self.bg,
+            bg: self.fg,
+        };
+        if let Some(console) = self.backend.console() {
+            console.draw(col, row, cell);
+            self.cursor_at = Some((col, row));
+        }
+    }
+
+    /// Draws the cell under the cursor again, before anything else changes the screen.
+    fn hide_cursor(&mut self) {
+        let Some((col, row)) = self.cursor_at.take() else {
+            return;
+        };
+        let cell = self.history[self.line(row)][col];
+        if let Some(console) = self.backend.console() {
+            console.draw(col, row, cell);
+        }
+    }
+
+    fn blink(&mut self) {
+        self.cursor_on = !self.cursor_on;
+        self.hide_cursor();
+        self.show_cursor();
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+        self.top = (self.top + 1) % HISTORY_ROWS;
+        self.scrolled = (self.scrolled + 1).min(HISTORY_ROWS - self.rows);
+        let bottom = self.line(self.rows - 1);
+        self.history[bottom] = [Cell::blank(self.fg, self.bg); MAX_COLUMNS];
+        let bg = self.bg;
+        if let Some(console) = self.backend.console() {
+            console.scroll_up(bg);
+        }
+    }
+
+    fn put_char(&mut self, c: char) {
+        self.hide_cursor();
+        if self.view != 0 {
+            self.view = 0;
+            self.redraw();
+            self.hide_cursor();
+        }
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.col = 0,
+            // Moves back without erasing, so erasing is "\x08 \x08" as on a terminal
+            '\x08' => self.col = self.col.saturating_sub(1),
+            '\t' => {
+                if self.col >= self.columns {
+                    self.newline();
+                }
+                let stop = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < stop.min(self.columns) {
+                    self.put_cell(' ');
+                }
+            }
+            c => {
+                let width = char_width(c);
+                if width == 0 {
+                    return;
+                }
+                // A wide character doesn't split across rows
+                if self.col + width > self.columns {
+                    self.newline();
+                }
+                self.put_cell(c);
+                if width == 2 {
+                    self.put_cell('\0');
+                }
+            }
+        }
+    }
+
+    /// Writes `c` in the cell at the cursor and moves past it. The cursor must be on screen.
+    fn put_cell(&mut self, c: char) {
+        let (col, row) = (self.col, self.row);
+        let cell = Cell {
+            ch: c,
+            fg: self.fg,
+            bg: self.bg,
+        };
+        let line = self.line(row);
+        self.history[line][col] = cell;
+        if let Some(console) = self.backend.console() {
+            console.draw(col, row, cell);
+        }
+        self.col += 1;
+    }
+
+    /// Blanks the cell before the cursor and moves back onto it, to the end of the row above
+    /// if the cursor is at the start of one.
+    fn erase_back(&mut self) {
+        self.hide_cursor();
+        if self.view != 0 {
+            self.view = 0;
+            self.redraw();
+            self.hide_cursor();
+        }
+        if self.col > 0 {
+            self.col -= 1;
+        } else if self.row > 0 {
+            self.row -= 1;
+            self.col = self.columns - 1;
+        } else {
+            return;
+        }
+        let (col, row) = (self.col.min(self.columns - 1), self.row);
+        let cell = Cell::blank(self.fg, self.bg);
+        let line = self.line(row);
+        self.history[line][col] = cell;
+        if let Some(console) = self.backend.console() {
+            console.draw(col, row, cell);
+        }
+    }
+
+    /// Writes typed input. A backspace erases the last character echoed since the last
+    /// newline, if there is one.
+    fn echo(&mut self, text: &str) {
+        for c in text.chars() {
+            match c {
+                '\n' => {
+                    self.echo.clear();
+                    self.put_char('\n');
+                }
+                '\x08' => {
+                    if let Some(c) = self.echo.pop() {
+                        for _ in 0..char_width(c) {
+                            self.erase_back();
+                        }
+                    }
+                }
+                // Echoed as one column, which is how much a backspace takes back
+                '\t' => {
+                    self.echo.push(' ');
+                    self.put_char(' ');
+                }
+                c => {
+                    self.echo.push(c);
+                    self.put_char(c);
+                }
+            }
+        }
+        self.show_cursor();
+    }
+
+    /// Writes `s` as output. Input being edited is erased first and written again on a line
+    /// of its own after it.
+    fn write_output(&mut self, s: &str) {
+        let echo = core::mem::take(&mut self.echo);
+        for _ in 0..echo.chars().map(char_width).sum() {
+            self.erase_back();
+        }
+        for c in s.chars() {
+            self.put_char(c);
+        }
+        if !echo.is_empty() {
+            if self.col != 0 {
+                self.newline();
+            }
+            for c in echo.chars() {
+                self.put_char(c);
+            }
+        }
+        self.echo = echo;
+        self.show_cursor();
+    }
+
+    /// Moves the view `rows` further back into the scrollback, or forward for negative `rows`.
+    fn scroll_view(&mut self, rows: isize) {
+        let view = self.view.saturating_add_signed(rows).min(self.scrolled);
+        if view != self.view {
+            self.view = view;
+            self.redraw();
+        }
+    }
+
+    /// Blanks the screen. Input being edited is forgotten along with it.
+    fn clear(&mut self) {
+        self.echo.clear();
+        for row in 0..self.rows {
+            let line = self.line(row);
+            self.history[line] = [Cell::blank(self.fg, self.bg); MAX_COLUMNS];
+        }
+        self.col = 0;
+        self.row = 0;
+        self.view = 0;
+        self.redraw();
+    }
+}
+
+impl fmt::Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_output(s);
+        Ok(())
+    }
+}
+
+fn with_terminal<R>(f: impl FnOnce(&mut Terminal) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TERMINAL.lock()))
+}
+
+/// Shows the terminal on the VGA text buffer mapped at `addr`.
+///
+/// # Safety
+///
+/// `addr` must be where the text buffer at [`vga_buffer::BUFFER_PHYS_ADDR`] is mapped.
+pub unsafe fn attach_text(addr: u64) {
+    let writer = unsafe { vga_buffer::Writer::new(addr) };
+    with_terminal(|terminal| terminal.attach(Backend::Text(writer)));
+}
+
+/// Shows the terminal on the framebuffer, including everything printed before.
+pub fn attach_framebuffer(writer: FrameBufferWriter) {
+    with_terminal(|terminal| terminal.attach(Backend::Framebuffer(writer)));
+}
+
+/// Runs `f` on the framebuffer backend, if that is the one in use, then fits the terminal to its
+/// new geometry. Nothing is drawn while `f` runs, so `f` must not print.
+pub fn with_framebuffer<R>(f: impl FnOnce(&mut FrameBufferWriter) -> R) -> Option<R> {
+    with_terminal(|terminal| {
+        let Backend::Framebuffer(ref mut writer) = terminal.backend else {
+            return None;
+        };
+        let result = f(writer);
+        terminal.resize();
+        Some(result)
+    })
+}
+
+/// The framebuffer's geometry and the address it is mapped at, if it is the backend in use.
+pub fn framebuffer() -> Option<(FrameBufferInfo, VirtAddr)> {
+    with_terminal(|terminal| match terminal.backend {
+        Backend::Framebuffer(ref writer) => Some((writer.info(), writer.buffer_start())),
+        _ => None,
+    })
+}
+
+/// Columns and rows of the screen.
+pub fn size() -> (usize, usize) {
+    with_terminal(|terminal| (terminal.columns, terminal.rows))
+}
+
+/// Sets the colors for text printed from now on.
+pub fn set_colors(fg: Color, bg: Color) {
+    // Text still queued was printed in the old colors
+    render::flush();
+    with_terminal(|terminal| {
+        terminal.fg = fg;
+        terminal.bg = bg;
+    });
+}
+
+pub fn reset_colors() {
+    set_colors(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+}
+
+/// Shows `rows` older rows from the scrollback. Printing anything goes back to the bottom.
+pub fn scroll_back(rows: usize) {
+    with_terminal(|terminal| terminal.scroll_view(rows as isize));
+}
+
+pub fn scroll_forward(rows: usize) {
+    with_terminal(|terminal| terminal.scroll_view(-(rows as isize)));
+}
+
+/// Blanks the screen and puts the cursor at the top left. The scrollback is kept.
+pub fn clear() {
+    render::flush();
+    with_terminal(Terminal::clear);
+}
+
+/// Writes typed input at the end of the screen, as the echo of a terminal. What was echoed
+/// since the last newline stays below other output, and `'\x08'` erases the last character of
+/// it, so input can be edited while the kernel logs. Output queued before is drawn first.
+pub fn echo(text: &str) {
+    if early::is_active() {
+        crate::serial::write_port(format_args!("{}", text));
+    }
+    render::flush();
+    with_terminal(|terminal| terminal.echo(text));
+    crate::virtio::console::mirror(format_args!("{}", text));
+}
+
+/// Blinks the cursor, as a task.
+pub async fn blink_cursor() {
+    loop {
+        sleep_ticks(CURSOR_BLINK_TICKS).await;
+        with_terminal(Terminal::blink);
+    }
+}
+
+/// The text on screen at the time of a [`capture`].
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub columns: usize,
+    pub rows: usize,
+    /// Where the next character goes, as `(column, row)`.
+    pub cursor: (usize, usize),
+    cells: Vec<Cell>,
+}
+
+impl Capture {
+    pub fn cell(&self, col: usize, row: usize) -> Cell {
+        assert!(col < self.columns && row < self.rows);
+        self.cells[row * self.columns + col]
+    }
+
+    /// Row `row` as text, without trailing blanks.
+    pub fn line(&self, row: usize) -> String {
+        let cells = &self.cells[row * self.columns..(row + 1) * self.columns];
+        let line: String = cells
+            .iter()
+            .map(|cell| if cell.ch == '\0' { ' ' } else { cell.ch })
+            .collect();
+        String::from(line.trim_end())
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.rows).map(|row| self.line(row))
+    }
+
+    /// Whether `text` appears within one row.
+    pub fn contains(&self, text: &str) -> bool {
+        self.lines().any(|line| line.contains(text))
+    }
+}
+
+/// Copies the text on screen, after drawing anything still queued. The copy is of the live
+/// screen even while the user is looking back through the scrollback, and is taken from the
+/// terminal's own cells, so it works the same with no backend at all.
+pub fn capture() -> Capture {
+    render::flush();
+    with_terminal(|terminal| {
+        let mut cells = Vec::with_capacity(terminal.columns * terminal.rows);
+        for row in 0..terminal.rows {
+            let line = &terminal.history[terminal.line(row)];
+            cells.extend_from_slice(&line[..terminal.columns]);
+        }
+        Capture {
+            columns: terminal.columns,
+            rows: terminal.rows,
+            cursor: (terminal.col, terminal.row),
+            cells,
+        }
+    })
+}
+
+/// Writes to the screen only, without copying to serial or the virtio console.
+pub(crate) fn write(args: fmt::Arguments) {
+    if render::push(args) {
+        return;
+    }
+    with_terminal(|terminal| {
+        terminal
+            .write_fmt(args)
+            .expect("Writing to the console failed!")
+    });
+}
+
+/// Internal print function used by the print macros. Until the framebuffer is up the early
+/// console also copies it to serial.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if early::is_active() {
+        crate::serial::write_port(args);
+    }
+    write(args);
+    crate::virtio::console::mirror(args);
+}
+
+/// Spins this many times for a lock in [`panic_flush`] before breaking it.
+const PANIC_LOCK_SPINS: usize = 1_000_000;
+
+/// Waits a little for `mutex` to be free, then forcibly unlocks it: the panicking code may be
+/// the one holding it, and it is never going to let go.
+///
+/// # Safety
+///
+/// Only for the panic path, where a holder that is still running is the lesser problem.
+unsafe fn break_lock<T>(mutex: &Mutex<T>) {
+    for _ in 0..PANIC_LOCK_SPINS {
+        if !mutex.is_locked() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { mutex.force_unlock() };
+}
+
+/// Draws whatever output is still queued and makes later output draw directly, for the panic
+/// handler, as the render task won't run again.
+pub fn panic_flush() {
+    render::stop();
+    unsafe {
+        break_lock(&render::RENDERER);
+        break_lock(&TERMINAL);
+    }
+    render::flush();
+}
+
+#[test_case]
+fn test_println_output() {
+    /*
+        The terminal must be locked while testing, with interrupts off, so that nothing else
+        prints in between. An interrupt handler may have printed before, so the test string
+        starts on a new line.
+    */
+    let s = "Some test string that we can fit on one line";
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        writeln!(terminal, "\n{}", s).expect("writeln failed!");
+        let line = terminal.line(terminal.row - 1);
+        for (i, c) in s.chars().enumerate() {
+            assert_eq!(terminal.history[line][i].ch, c);
+        }
+    });
+}
+
+#[test_case]
+fn test_colors_stick_to_cells() {
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        terminal.write_str("\n").unwrap();
+        terminal.fg = Color::LightRed;
+        terminal.bg = Color::Blue;
+        terminal.write_str("!").unwrap();
+        terminal.fg = DEFAULT_FOREGROUND;
+        terminal.bg = DEFAULT_BACKGROUND;
+        let line = terminal.line(terminal.row);
+        let cell = terminal.history[line][0];
+        assert_eq!(
+            (cell.ch, cell.fg, cell.bg),
+            ('!', Color::LightRed, Color::Blue)
+        );
+    });
+}
+
+#[test_case]
+fn test_scroll_view_is_bounded_by_scrollback() {
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        for _ in 0..terminal.rows + 3 {
+            terminal.write_str("\n").unwrap();
+        }
+        terminal.scroll_view(isize::MAX);
+        assert_eq!(terminal.view, terminal.scrolled);
+        terminal.write_str("x").unwrap();
+        assert_eq!(terminal.view, 0);
+        terminal.scroll_view(-1);
+        assert_eq!(terminal.view, 0);
+    });
+}
+
+#[test_case]
+fn test_output_goes_above_echoed_input() {
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        terminal.write_str("\n").unwrap();
+        terminal.echo("> lx\x08s");
+        terminal.write_str("logged\n").unwrap();
+        let row = terminal.row;
+        let text = |terminal: &Terminal, row: usize, len: usize| -> String {
+            terminal.history[terminal.line(row)][..len]
+                .iter()
+                .map(|cell| cell.ch)
+                .collect()
+        };
+        assert_eq!(text(&terminal, row - 1, 6), "logged");
+        assert_eq!(text(&terminal, row, 4), "> ls");
+        assert_eq!(terminal.col, 4);
+
+        // Backspaces erase no more than was echoed
+        terminal.echo("\x08\x08\x08\x08\x08");
+        assert_eq!(text(&terminal, row, 4), "\0\0\0\0");
+        assert_eq!((terminal.col, terminal.row), (0, row));
+        terminal.echo("\n");
+        assert!(terminal.echo.is_empty());
+    });
+}
+
+#[test_case]
+fn test_wide_and_zero_width_characters() {
+    interrupts::without_interrupts(|| {
+        let mut terminal = TERMINAL.lock();
+        terminal.write_str("\na\u{301}漢\tb").unwrap();
+        let (col, row) = (terminal.col, terminal.row);
+        let line = terminal.history[terminal.line(row)];
+        let text: String = line[..col].iter().map(|cell| cell.ch).collect();
+        assert_eq!(text, "a漢\0     b");
+
+        // A wide character that doesn't fit goes on the next row
+        terminal.col = terminal.columns - 1;
+        terminal.write_str("漢").unwrap();
+        assert_eq!(terminal.col, 2);
+        let line = terminal.line(terminal.row);
+        assert_eq!(terminal.history[line][0].ch, '漢');
+    });
+}
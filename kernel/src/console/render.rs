@@ -0,0 +1,237 @@
+//! Deferred rendering of console output.
+//!
+//! Once the render task ([`run`]) is running, `print!` no longer draws anything itself: the
+//! formatted text goes into a lock-free byte ring and the task draws it from the executor. This
+//! keeps framebuffer writes out of interrupt handlers, and an interrupt handler that prints can
+//! no longer deadlock on the terminal lock held by the code it interrupted. Until the task
+//! starts, and again after a panic (see [`super::panic_flush`]), output is drawn directly.
+use core::fmt::{self, Write};
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use core::task::Poll;
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{TERMINAL, Terminal};
+use crate::metrics::Counter;
+
+/// Bytes of output that can wait for the render task.
+pub const RING_SIZE: usize = 16 * 1024;
+/// Bytes drawn per hold of the terminal lock, so interrupts aren't held off for long.
+const BATCH: usize = 256;
+
+/// Set in a slot that holds a byte not yet taken by the consumer.
+const FULL: u16 = 0x100;
+
+/// A byte queue for any number of producers and one consumer. A producer reserves room for a
+/// whole string and then fills it in; the consumer stops at the first slot not filled yet.
+struct ByteRing<const N: usize> {
+    slots: [AtomicU16; N],
+    /// Positions, not indices: both only go up, and `head - tail` bytes are reserved.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    const fn new() -> Self {
+        ByteRing {
+            slots: [const { AtomicU16::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends all of `bytes`, or nothing if they don't fit.
+    fn push(&self, bytes: &[u8]) -> bool {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            if head - tail + bytes.len() > N {
+                return false;
+            }
+            match self.head.compare_exchange_weak(
+                head,
+                head + bytes.len(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.slots[(head + i) % N].store(FULL | byte as u16, Ordering::Release);
+        }
+        true
+    }
+
+    /// Takes the next byte, if its producer has written it. Only one consumer may call this.
+    fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let slot = &self.slots[tail % N];
+        let value = slot.load(Ordering::Acquire);
+        if value & FULL == 0 {
+            return None;
+        }
+        // Emptied before the position is released, so the next producer's write comes after
+        slot.store(0, Ordering::Relaxed);
+        self.tail.store(tail + 1, Ordering::Release);
+        Some(value as u8)
+    }
+
+    fn is_empty(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.slots[tail % N].load(Ordering::Acquire) & FULL == 0
+    }
+}
+
+static RING: ByteRing<RING_SIZE> = ByteRing::new();
+static DEFERRED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED: Counter = Counter::new("console.dropped_bytes");
+
+/// The consumer's state. Holding the lock is what makes its holder the ring's one consumer.
+pub(super) struct Renderer {
+    /// The start of a UTF-8 sequence whose other bytes weren't in the ring yet.
+    partial: [u8; 4],
+    partial_len: usize,
+}
+
+pub(super) static RENDERER: Mutex<Renderer> = Mutex::new(Renderer {
+    partial: [0; 4],
+    partial_len: 0,
+});
+
+struct Queue;
+
+impl fmt::Write for Queue {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !RING.push(s.as_bytes()) {
+            DROPPED.add(s.len() as u64);
+        }
+        Ok(())
+    }
+}
+
+/// Queues `args` for the render task. Returns `false`, queueing nothing, while output is drawn
+/// directly instead. Output that doesn't fit in the ring is dropped and counted in the
+/// `console.dropped_bytes` metric.
+pub(super) fn push(args: fmt::Arguments) -> bool {
+    if !DEFERRED.load(Ordering::Acquire) {
+        return false;
+    }
+    let _ = Queue.write_fmt(args);
+    WAKER.wake();
+    true
+}
+
+pub fn is_deferred() -> bool {
+    DEFERRED.load(Ordering::Relaxed)
+}
+
+/// Bytes queued and not drawn yet.
+pub fn queued() -> usize {
+    // The tail first: it never passes the head, so the difference can't go negative
+    let tail = RING.tail.load(Ordering::Relaxed);
+    RING.head.load(Ordering::Relaxed) - tail
+}
+
+/// Goes back to drawing output directly. Whatever is queued still needs a [`flush`].
+pub fn stop() {
+    DEFERRED.store(false, Ordering::Release);
+}
+
+/// Draws up to [`BATCH`] queued bytes. Returns whether more are waiting.
+fn render_batch(renderer: &mut Renderer, terminal: &mut Terminal) -> bool {
+    let mut buf = [0; BATCH + 4];
+    let mut len = renderer.partial_len;
+    buf[..len].copy_from_slice(&renderer.partial[..len]);
+    while len < buf.len() {
+        let Some(byte) = RING.pop() else {
+            break;
+        };
+        buf[len] = byte;
+        len += 1;
+    }
+
+    let mut bytes = &buf[..len];
+    while !bytes.is_empty() {
+        match core::str::from_utf8(bytes) {
+            Ok(s) => {
+                let _ = terminal.write_str(s);
+                bytes = &[];
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                // SAFETY: `from_utf8` checked everything up to `valid_up_to`
+                let _ = terminal.write_str(unsafe { core::str::from_utf8_unchecked(valid) });
+                match e.error_len() {
+                    Some(invalid) => {
+                        let _ = terminal.write_char(char::REPLACEMENT_CHARACTER);
+                        bytes = &rest[invalid..];
+                    }
+                    // Cut off by the end of the batch, or not written yet; finish it next time
+                    None => {
+                        bytes = rest;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    renderer.partial_len = bytes.len();
+    renderer.partial[..bytes.len()].copy_from_slice(bytes);
+    !RING.is_empty()
+}
+
+/// Draws everything queued so far, with interrupts enabled between batches.
+pub fn flush() {
+    while interrupts::without_interrupts(|| {
+        let mut renderer = RENDERER.lock();
+        let mut terminal = TERMINAL.lock();
+        render_batch(&mut renderer, &mut terminal)
+    }) {}
+}
+
+/// The render task. Output is queued from the time it first runs.
+pub async fn run() {
+    DEFERRED.store(true, Ordering::Release);
+    loop {
+        poll_fn(|context| {
+            WAKER.register(context.waker());
+            if RING.is_empty() {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+        flush();
+    }
+}
+
+#[test_case]
+fn test_ring_wraps_around() {
+    static TEST_RING: ByteRing<8> = ByteRing::new();
+    for round in 0..5u8 {
+        assert!(TEST_RING.push(&[round, round + 1, round + 2]));
+        assert_eq!(TEST_RING.pop(), Some(round));
+        assert_eq!(TEST_RING.pop(), Some(round + 1));
+        assert_eq!(TEST_RING.pop(), Some(round + 2));
+        assert!(TEST_RING.is_empty());
+    }
+}
+
+#[test_case]
+fn test_ring_rejects_what_does_not_fit() {
+    static TEST_RING: ByteRing<4> = ByteRing::new();
+    assert!(TEST_RING.push(b"abc"));
+    assert!(!TEST_RING.push(b"de"));
+    assert!(TEST_RING.push(b"d"));
+    assert_eq!(TEST_RING.pop(), Some(b'a'));
+    assert!(TEST_RING.push(b"e"));
+    assert!(!TEST_RING.push(b"f"));
+    assert!(!TEST_RING.push(b"too long for the ring"));
+}
@@ -0,0 +1,281 @@
+//! x87, SSE and AVX register state.
+//!
+//! [`init`] turns on SSE and, on CPUs with XSAVE, the extended state AVX adds. The kernel is
+//! built without SSE, so outside a [`KernelFpu`] guard these registers only ever hold a user
+//! process's values.
+//!
+//! Processes get them lazily. Switching to a process sets CR0.TS unless its registers are
+//! already the ones loaded on this CPU, and its first FPU or SIMD instruction then traps with
+//! #NM. The handler saves the registers into the [`FpuState`] of the process that had them and
+//! loads the current process's, so a process that never touches them costs nothing to switch to.
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::XCr0;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{MAX_CPUS, PerCpu};
+
+const CPUID_1_ECX_XSAVE: u32 = 1 << 26;
+const CPUID_1_ECX_AVX: u32 = 1 << 28;
+/// Processor extended state enumeration: EDX:EAX are the state components XCR0 may enable,
+/// EBX the save area size for those enabled now.
+const CPUID_XSTATE_LEAF: u32 = 0xD;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// Bytes an [`FpuState`] holds. The legacy area, the XSAVE header and the upper halves of the
+/// AVX registers take 832.
+pub const STATE_SIZE: usize = 1024;
+
+/// Control word and MXCSR after `fninit`: every exception masked, round to nearest.
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+const MXCSR_OFFSET: usize = 24;
+
+/// Whether the CPUs save state with XSAVE rather than FXSAVE.
+static XSAVE: AtomicBool = AtomicBool::new(false);
+/// The state components enabled in XCR0.
+static FEATURES: AtomicU64 = AtomicU64::new(XCR0_X87 | XCR0_SSE);
+
+/// PID of the process whose registers are loaded on each CPU, or 0 when they belong to no one.
+static OWNER: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// The state components to enable, given CPUID leaf 1's ECX and the components the CPU
+/// supports.
+fn xcr0_for(ecx: u32, supported: u64) -> u64 {
+    let mut xcr0 = XCR0_X87 | XCR0_SSE;
+    if ecx & CPUID_1_ECX_AVX != 0 {
+        xcr0 |= XCR0_AVX;
+    }
+    xcr0 & supported
+}
+
+/// Enables SSE, and AVX where there is XSAVE to save it with, on the executing CPU. Every CPU
+/// has to run this, and they all end up with the same features.
+pub fn init() {
+    unsafe {
+        Cr0::update(|cr0| {
+            cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+    let ecx = __cpuid(1).ecx;
+    if ecx & CPUID_1_ECX_XSAVE != 0 {
+        unsafe { Cr4::update(|cr4| cr4.insert(Cr4Flags::OSXSAVE)) };
+        let leaf = __cpuid_count(CPUID_XSTATE_LEAF, 0);
+        let mut xcr0 = xcr0_for(ecx, (leaf.edx as u64) << 32 | leaf.eax as u64);
+        unsafe { XCr0::write_raw(xcr0) };
+        if __cpuid_count(CPUID_XSTATE_LEAF, 0).ebx as usize > STATE_SIZE {
+            xcr0 &= !XCR0_AVX;
+            unsafe { XCr0::write_raw(xcr0) };
+        }
+        XSAVE.store(true, Ordering::Relaxed);
+        FEATURES.store(xcr0, Ordering::Relaxed);
+    }
+    unsafe { reset() };
+    OWNER.get().store(0, Ordering::Relaxed);
+    if super::is_bsp() {
+        crate::info!(
+            "fpu: {} with state components {:#x}",
+            if XSAVE.load(Ordering::Relaxed) {
+                "xsave"
+            } else {
+                "fxsave"
+            },
+            FEATURES.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Resets the x87 unit and MXCSR, so the kernel computes with default rounding and masked
+/// exceptions whatever a process left set. CR0.TS must be clear.
+unsafe fn reset() {
+    let mxcsr = MXCSR_DEFAULT;
+    unsafe {
+        asm!("fninit", options(nostack, nomem));
+        asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, readonly));
+    }
+}
+
+fn clts() {
+    unsafe { asm!("clts", options(nostack, nomem)) };
+}
+
+fn set_ts() {
+    unsafe { Cr0::update(|cr0| cr0.insert(Cr0Flags::TASK_SWITCHED)) };
+}
+
+/// A saved copy of the x87, SSE and AVX registers, in the layout XSAVE or FXSAVE stores.
+#[derive(Clone)]
+#[repr(C, align(64))]
+pub struct FpuState([u8; STATE_SIZE]);
+
+impl FpuState {
+    /// The state a program starts with: zeroed registers, every exception masked.
+    pub fn new() -> Self {
+        let mut bytes = [0; STATE_SIZE];
+        bytes[..2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        bytes[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        FpuState(bytes)
+    }
+
+    /// Stores the executing CPU's registers here. CR0.TS must be clear.
+    pub(crate) unsafe fn save(&mut self) {
+        let ptr = self.0.as_mut_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) ptr, options(nostack));
+            }
+        }
+    }
+
+    /// Loads the executing CPU's registers from here. CR0.TS must be clear.
+    pub(crate) unsafe fn restore(&self) {
+        let ptr = self.0.as_ptr();
+        unsafe {
+            if XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) ptr, in("eax") u32::MAX, in("edx") u32::MAX,
+                    options(nostack, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) ptr, options(nostack, readonly));
+            }
+        }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Lets the kernel use the x87, SSE and AVX registers until dropped, which is
+/// `kernel_fpu_end`. Whatever they held is saved by [`kernel_fpu_begin`] and put back on drop,
+/// so guards nest and interrupt handlers may take them. Code using the registers has to be
+/// compiled for them, e.g. with `#[target_feature(enable = "sse2")]`, and must not return to
+/// user mode while holding a guard.
+pub struct KernelFpu {
+    saved: FpuState,
+    /// Whether CR0.TS was set, i.e. the registers belonged to no process running here.
+    was_ts: bool,
+}
+
+/// Saves the registers and hands them to the kernel with default control settings.
+pub fn kernel_fpu_begin() -> KernelFpu {
+    let was_ts = Cr0::read().contains(Cr0Flags::TASK_SWITCHED);
+    clts();
+    let mut saved = FpuState::new();
+    unsafe {
+        saved.save();
+        reset();
+    }
+    KernelFpu { saved, was_ts }
+}
+
+/// Gives the registers back to whoever had them before `guard` was taken.
+pub fn kernel_fpu_end(guard: KernelFpu) {
+    drop(guard);
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        unsafe { self.saved.restore() };
+        if self.was_ts {
+            set_ts();
+        }
+    }
+}
+
+/// PID of the process whose registers are loaded on this CPU, or 0.
+pub(crate) fn owner() -> u64 {
+    OWNER.get().load(Ordering::Relaxed)
+}
+
+/// Records that the registers loaded on this CPU now belong to `pid`.
+pub(crate) fn set_owner(pid: u64) {
+    OWNER.get().store(pid, Ordering::Relaxed);
+}
+
+/// Called when this CPU switches to `pid`: its next FPU or SIMD instruction traps unless the
+/// registers are already its own.
+pub(crate) fn switch_to(pid: u64) {
+    if owner() == pid {
+        clts();
+    } else {
+        set_ts();
+    }
+}
+
+/// Copies `pid`'s registers into `state` if they are the ones loaded on this CPU, as they are
+/// newer than its saved copy.
+pub(crate) fn save_if_owner(pid: u64, state: &mut FpuState) {
+    if owner() == pid {
+        clts();
+        unsafe { state.save() };
+    }
+}
+
+/// Drops `pid`'s claim to the registers loaded on this CPU, e.g. when it execs a new program,
+/// so its next FPU or SIMD instruction loads its saved state again.
+pub(crate) fn forget(pid: u64) {
+    if owner() == pid {
+        set_owner(0);
+        set_ts();
+    }
+}
+
+/// #NM: a process used the registers while CR0.TS was set, so they may hold another's.
+pub(crate) extern "x86-interrupt" fn device_not_available_handler(frame: InterruptStackFrame) {
+    clts();
+    if frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring0 {
+        panic!(
+            "EXCEPTION: DEVICE NOT AVAILABLE, FPU used by the kernel without kernel_fpu_begin\n{:#?}",
+            frame
+        );
+    }
+    crate::process::scheduler::load_fpu();
+}
+
+#[test_case]
+fn test_xcr0_for() {
+    let all = XCR0_X87 | XCR0_SSE | XCR0_AVX | 0xE0;
+    assert_eq!(
+        xcr0_for(CPUID_1_ECX_AVX, all),
+        XCR0_X87 | XCR0_SSE | XCR0_AVX
+    );
+    assert_eq!(xcr0_for(0, all), XCR0_X87 | XCR0_SSE);
+    // Claiming AVX in CPUID doesn't help if XSAVE can't hold it
+    assert_eq!(
+        xcr0_for(CPUID_1_ECX_AVX, XCR0_X87 | XCR0_SSE),
+        XCR0_X87 | XCR0_SSE
+    );
+}
+
+#[test_case]
+fn test_kernel_fpu_guards_nest() {
+    let read_xmm0 = || {
+        let value: u64;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nostack, nomem)) };
+        value
+    };
+    let write_xmm0 = |value: u64| unsafe {
+        asm!("movq xmm0, {}", in(reg) value, options(nostack, nomem));
+    };
+
+    let outer = kernel_fpu_begin();
+    write_xmm0(0x1234);
+    let inner = kernel_fpu_begin();
+    write_xmm0(0x5678);
+    kernel_fpu_end(inner);
+    assert_eq!(read_xmm0(), 0x1234);
+    kernel_fpu_end(outer);
+}
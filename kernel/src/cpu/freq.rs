@@ -0,0 +1,135 @@
+//! Processor frequency reporting.
+//!
+//! Base and maximum frequency come from CPUID leaf 0x16 when the CPU reports it, with the TSC
+//! rate as a fallback for the base frequency. Under a hypervisor that reports the TSC rate it
+//! is taken from there; otherwise it is calibrated against the HPET, whose timing an emulator
+//! doesn't keep faithfully enough to measure against. The effective current
+//! frequency is derived from the APERF/MPERF ratio sampled on every timer tick:
+//! `current = base * ΔAPERF / ΔMPERF`.
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+
+use super::{MAX_CPUS, PerCpu, hypervisor, rdtsc};
+use crate::timer::uptime_us;
+
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+const CPUID_6_ECX_APERFMPERF: u32 = 1 << 0;
+
+/// How long to count TSC ticks against the HPET when calibrating.
+const CALIBRATION_US: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreqInfo {
+    /// Base (nominal) frequency in MHz.
+    pub base_mhz: u32,
+    /// Maximum turbo frequency in MHz, if reported.
+    pub max_mhz: Option<u32>,
+    /// Bus/reference frequency in MHz, if reported.
+    pub bus_mhz: Option<u32>,
+    /// TSC frequency in MHz, as the hypervisor reports it or measured against the HPET.
+    pub tsc_mhz: Option<u32>,
+    pub has_aperf_mperf: bool,
+}
+
+static INFO: Once<FreqInfo> = Once::new();
+
+struct Sample {
+    aperf: AtomicU64,
+    mperf: AtomicU64,
+    current_mhz: AtomicU64,
+}
+
+impl Sample {
+    const fn new() -> Self {
+        Sample {
+            aperf: AtomicU64::new(0),
+            mperf: AtomicU64::new(0),
+            current_mhz: AtomicU64::new(0),
+        }
+    }
+}
+
+static SAMPLES: PerCpu<Sample> = PerCpu::new([const { Sample::new() }; MAX_CPUS]);
+
+/// Reads the frequency CPUID leaves and calibrates the TSC. Needs the HPET for calibration,
+/// so call it after `init_hpet`.
+pub fn init() -> FreqInfo {
+    *INFO.call_once(|| {
+        let max_leaf = __cpuid(0).eax;
+        let (mut base_mhz, mut max_mhz, mut bus_mhz) = (0, None, None);
+        if max_leaf >= 0x16 {
+            let leaf = __cpuid(0x16);
+            base_mhz = leaf.eax & 0xFFFF;
+            max_mhz = Some(leaf.ebx & 0xFFFF).filter(|&m| m != 0);
+            bus_mhz = Some(leaf.ecx & 0xFFFF).filter(|&m| m != 0);
+        }
+        let has_aperf_mperf = max_leaf >= 6 && __cpuid(6).ecx & CPUID_6_ECX_APERFMPERF != 0;
+
+        let tsc_mhz = reported_tsc_mhz().or_else(calibrate_tsc_mhz);
+        if base_mhz == 0 {
+            base_mhz = tsc_mhz.unwrap_or(0);
+        }
+
+        FreqInfo {
+            base_mhz,
+            max_mhz,
+            bus_mhz,
+            tsc_mhz,
+            has_aperf_mperf,
+        }
+    })
+}
+
+pub fn info() -> Option<FreqInfo> {
+    INFO.get().copied()
+}
+
+/// The TSC rate the hypervisor scales its clock by, or reports in its timing leaf.
+fn reported_tsc_mhz() -> Option<u32> {
+    let khz = crate::pvclock::tsc_khz().or_else(|| hypervisor::tsc_khz().map(u64::from))?;
+    u32::try_from(khz / 1000).ok().filter(|&mhz| mhz != 0)
+}
+
+/// Counts TSC ticks over a short HPET-timed window.
+fn calibrate_tsc_mhz() -> Option<u32> {
+    let start_us = uptime_us()?;
+    let start_tsc = rdtsc();
+    let mut now_us = start_us;
+    while now_us - start_us < CALIBRATION_US {
+        core::hint::spin_loop();
+        now_us = uptime_us()?;
+    }
+    let cycles = rdtsc().wrapping_sub(start_tsc);
+    Some((cycles / (now_us - start_us)) as u32)
+}
+
+/// Samples APERF/MPERF on the executing CPU. Called from the timer interrupt.
+pub fn on_tick() {
+    let Some(info) = INFO.get() else {
+        return;
+    };
+    if !info.has_aperf_mperf || info.base_mhz == 0 {
+        return;
+    }
+    let (aperf, mperf) = unsafe { (Msr::new(IA32_APERF).read(), Msr::new(IA32_MPERF).read()) };
+
+    let sample = SAMPLES.get();
+    let delta_aperf = aperf.wrapping_sub(sample.aperf.swap(aperf, Ordering::Relaxed));
+    let delta_mperf = mperf.wrapping_sub(sample.mperf.swap(mperf, Ordering::Relaxed));
+    if delta_mperf != 0 {
+        let mhz = (info.base_mhz as u128 * delta_aperf as u128 / delta_mperf as u128) as u64;
+        sample.current_mhz.store(mhz, Ordering::Relaxed);
+    }
+}
+
+/// Returns the effective frequency of CPU `index` over its last timer tick, in MHz.
+pub fn current_mhz(index: usize) -> Option<u64> {
+    match SAMPLES.get_for(index)?.current_mhz.load(Ordering::Relaxed) {
+        0 => None,
+        mhz => Some(mhz),
+    }
+}
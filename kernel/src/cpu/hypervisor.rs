@@ -0,0 +1,120 @@
+//! Detection of the hypervisor the kernel runs under, if any.
+//!
+//! A hypervisor sets the "hypervisor present" bit of CPUID leaf 1 and identifies itself with a
+//! 12-byte vendor signature in leaf 0x4000_0000. Under one, some hardware is emulated with
+//! different timing than real machines (under TCG the TSC isn't even tied to wall time), so
+//! timekeeping prefers what the hypervisor reports over calibrating against emulated timers.
+use core::arch::x86_64::__cpuid;
+
+use spin::Once;
+
+const CPUID_1_ECX_HYPERVISOR: u32 = 1 << 31;
+const LEAF_BASE: u32 = 0x4000_0000;
+/// The VMware-defined timing leaf, which KVM and others also provide: TSC and bus kHz.
+const LEAF_TIMING: u32 = 0x4000_0010;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    HyperV,
+    Vmware,
+    Xen,
+    VirtualBox,
+    /// QEMU without KVM, emulating the CPU in software.
+    Tcg,
+    /// Anything else, by its vendor signature.
+    Other([u8; 12]),
+}
+
+impl Hypervisor {
+    fn from_signature(signature: [u8; 12]) -> Self {
+        match &signature {
+            b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+            b"Microsoft Hv" => Hypervisor::HyperV,
+            b"VMwareVMware" => Hypervisor::Vmware,
+            b"XenVMMXenVMM" => Hypervisor::Xen,
+            b"VBoxVBoxVBox" => Hypervisor::VirtualBox,
+            b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+            _ => Hypervisor::Other(signature),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Hypervisor::Kvm => "KVM",
+            Hypervisor::HyperV => "Hyper-V",
+            Hypervisor::Vmware => "VMware",
+            Hypervisor::Xen => "Xen",
+            Hypervisor::VirtualBox => "VirtualBox",
+            Hypervisor::Tcg => "QEMU TCG",
+            Hypervisor::Other(signature) => {
+                let len = signature.iter().position(|&b| b == 0).unwrap_or(12);
+                core::str::from_utf8(&signature[..len]).unwrap_or("unknown")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Detected {
+    hypervisor: Hypervisor,
+    max_leaf: u32,
+}
+
+static DETECTED: Once<Option<Detected>> = Once::new();
+
+fn detected() -> Option<Detected> {
+    *DETECTED.call_once(|| {
+        if __cpuid(1).ecx & CPUID_1_ECX_HYPERVISOR == 0 {
+            return None;
+        }
+        let leaf = __cpuid(LEAF_BASE);
+        let mut signature = [0u8; 12];
+        signature[..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+        signature[8..].copy_from_slice(&leaf.edx.to_le_bytes());
+        Some(Detected {
+            hypervisor: Hypervisor::from_signature(signature),
+            // Some hypervisors leave this 0, meaning leaves up to 0x4000_0001
+            max_leaf: leaf.eax.max(LEAF_BASE + 1),
+        })
+    })
+}
+
+/// The hypervisor the kernel runs under, or `None` on bare metal.
+pub fn get() -> Option<Hypervisor> {
+    detected().map(|detected| detected.hypervisor)
+}
+
+/// Reads hypervisor CPUID leaf `leaf` (0x4000_0000 and up), if the hypervisor provides it.
+pub fn cpuid(leaf: u32) -> Option<[u32; 4]> {
+    let detected = detected()?;
+    if !(LEAF_BASE..=detected.max_leaf).contains(&leaf) {
+        return None;
+    }
+    let regs = __cpuid(leaf);
+    Some([regs.eax, regs.ebx, regs.ecx, regs.edx])
+}
+
+/// The TSC frequency in kHz as the hypervisor's timing leaf reports it.
+pub fn tsc_khz() -> Option<u32> {
+    cpuid(LEAF_TIMING)
+        .map(|[eax, ..]| eax)
+        .filter(|&khz| khz != 0)
+}
+
+#[test_case]
+fn test_signatures() {
+    assert_eq!(
+        Hypervisor::from_signature(*b"KVMKVMKVM\0\0\0"),
+        Hypervisor::Kvm
+    );
+    assert_eq!(
+        Hypervisor::from_signature(*b"Microsoft Hv"),
+        Hypervisor::HyperV
+    );
+    assert_eq!(
+        Hypervisor::from_signature(*b"bhyve bhyve ").name(),
+        "bhyve bhyve "
+    );
+}
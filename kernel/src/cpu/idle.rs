@@ -0,0 +1,148 @@
+//! The idle path: MONITOR/MWAIT with a C-state hint when the CPU supports it, `hlt` otherwise.
+//!
+//! Each CPU monitors its own wake word, so another CPU can end an MWAIT early by writing to it
+//! with [`kick`], in addition to the usual wake-up on interrupts. Time spent idle is measured
+//! with the TSC and kept per CPU.
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+use super::{MAX_CPUS, PerCpu, rdtsc};
+
+const CPUID_ECX_MONITOR: u32 = 1 << 3;
+
+#[repr(align(64))]
+struct IdleState {
+    /// The cache line watched by MONITOR. Kept alone in its line so unrelated writes don't
+    /// cause spurious wake-ups.
+    wake: AtomicU64,
+    entries: AtomicU64,
+    mwait_entries: AtomicU64,
+    idle_cycles: AtomicU64,
+}
+
+impl IdleState {
+    const fn new() -> Self {
+        IdleState {
+            wake: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            mwait_entries: AtomicU64::new(0),
+            idle_cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+static IDLE: PerCpu<IdleState> = PerCpu::new([const { IdleState::new() }; MAX_CPUS]);
+
+#[derive(Debug, Clone, Copy)]
+struct MwaitSupport {
+    /// Deepest C-state (1-based, as in C1..C7) with at least one sub-state.
+    deepest_cstate: u8,
+}
+
+static MWAIT: Once<Option<MwaitSupport>> = Once::new();
+
+/// Upper limit on the C-state requested from MWAIT; 0 disables MWAIT entirely.
+static MAX_CSTATE: AtomicU8 = AtomicU8::new(u8::MAX);
+
+fn mwait_support() -> Option<MwaitSupport> {
+    *MWAIT.call_once(|| {
+        if __cpuid(1).ecx & CPUID_ECX_MONITOR == 0 || __cpuid(0).eax < 5 {
+            return None;
+        }
+        // CPUID.05H:EDX holds the number of MWAIT sub-states for C0..C7 in 4-bit fields
+        let substates = __cpuid(5).edx;
+        let deepest_cstate = (1..8u8)
+            .rev()
+            .find(|&c| (substates >> (c as u32 * 4)) & 0xF != 0)
+            .unwrap_or(1);
+        Some(MwaitSupport { deepest_cstate })
+    })
+}
+
+/// Limits how deep a C-state the idle loop asks for. `0` forces `hlt`.
+pub fn set_max_cstate(cstate: u8) {
+    MAX_CSTATE.store(cstate, Ordering::Relaxed);
+}
+
+/// Returns the C-state MWAIT is currently asked for, or `None` if the idle loop uses `hlt`.
+pub fn cstate() -> Option<u8> {
+    let support = mwait_support()?;
+    match support
+        .deepest_cstate
+        .min(MAX_CSTATE.load(Ordering::Relaxed))
+    {
+        0 => None,
+        cstate => Some(cstate),
+    }
+}
+
+/// Enables interrupts and waits for the next one, in a single step so an interrupt arriving in
+/// between can't be missed. Meant to be called with interrupts disabled, like `enable_and_hlt`.
+pub fn enable_and_idle() {
+    let state = IDLE.get();
+    measure(state, || match cstate() {
+        Some(cstate) => {
+            state.mwait_entries.fetch_add(1, Ordering::Relaxed);
+            let hint = ((cstate as u32) - 1) << 4;
+            unsafe {
+                asm!(
+                    "monitor",
+                    in("rax") state.wake.as_ptr(),
+                    in("ecx") 0,
+                    in("edx") 0,
+                    options(nostack, preserves_flags)
+                );
+                // `sti` only takes effect after the next instruction, so an interrupt can't
+                // sneak in between it and `mwait`
+                asm!("sti; mwait", in("eax") hint, in("ecx") 0, options(nomem, nostack));
+            }
+        }
+        None => interrupts::enable_and_hlt(),
+    });
+}
+
+/// Idles once. With interrupts disabled this is a plain `hlt`, since only an NMI could wake
+/// the CPU anyway.
+pub fn idle() {
+    if interrupts::are_enabled() {
+        interrupts::disable();
+        enable_and_idle();
+    } else {
+        measure(IDLE.get(), x86_64::instructions::hlt);
+    }
+}
+
+fn measure(state: &IdleState, wait: impl FnOnce()) {
+    state.entries.fetch_add(1, Ordering::Relaxed);
+    let start = rdtsc();
+    wait();
+    let elapsed = rdtsc().wrapping_sub(start);
+    state.idle_cycles.fetch_add(elapsed, Ordering::Relaxed);
+}
+
+/// Wakes CPU `index` out of MWAIT by writing to the line it is monitoring.
+pub fn kick(index: usize) {
+    if let Some(state) = IDLE.get_for(index) {
+        state.wake.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Idle residency counters for one CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct IdleStats {
+    pub entries: u64,
+    pub mwait_entries: u64,
+    pub idle_cycles: u64,
+}
+
+pub fn stats(index: usize) -> Option<IdleStats> {
+    IDLE.get_for(index).map(|state| IdleStats {
+        entries: state.entries.load(Ordering::Relaxed),
+        mwait_entries: state.mwait_entries.load(Ordering::Relaxed),
+        idle_cycles: state.idle_cycles.load(Ordering::Relaxed),
+    })
+}
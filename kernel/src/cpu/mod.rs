@@ -0,0 +1,141 @@
+//! Per-CPU identification and storage.
+//!
+//! CPUs are numbered densely in the order they first call [`current_index`], so per-CPU data
+//! can live in fixed-size arrays indexed by that number rather than by (possibly sparse) APIC ID.
+use core::arch::x86_64::{__cpuid, __rdtscp, _rdtsc};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+pub mod fpu;
+pub mod freq;
+pub mod hypervisor;
+pub mod idle;
+pub mod pat;
+
+/// Upper bound on the number of CPUs the kernel keeps per-CPU state for.
+pub const MAX_CPUS: usize = crate::config::MAX_CPUS;
+
+const UNUSED: u32 = u32::MAX;
+
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(UNUSED) }; MAX_CPUS];
+
+/// Holds each CPU's index plus one once it has registered, for RDTSCP to read back. It is 0
+/// from reset.
+const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// Whether the CPUs have RDTSCP, as CPUID reports it; checked on first use.
+static RDTSCP: AtomicU8 = AtomicU8::new(UNKNOWN);
+const UNKNOWN: u8 = 0;
+const ABSENT: u8 = 1;
+const PRESENT: u8 = 2;
+
+fn has_rdtscp() -> bool {
+    match RDTSCP.load(Ordering::Relaxed) {
+        UNKNOWN => {
+            let present = __cpuid(0x8000_0000).eax >= 0x8000_0001
+                && __cpuid(0x8000_0001).edx & (1 << 27) != 0;
+            RDTSCP.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+        state => state == PRESENT,
+    }
+}
+
+/// Returns the initial APIC ID of the executing CPU, as reported by CPUID.
+pub fn apic_id() -> u32 {
+    __cpuid(1).ebx >> 24
+}
+
+/// Returns the processor brand string from CPUID leaves 0x8000_0002..=0x8000_0004, with
+/// padding trimmed.
+pub fn brand_string(buf: &mut [u8; 48]) -> Option<&str> {
+    if __cpuid(0x8000_0000).eax < 0x8000_0004 {
+        return None;
+    }
+    for (i, leaf) in (0x8000_0002..=0x8000_0004u32).enumerate() {
+        let regs = __cpuid(leaf);
+        for (j, reg) in [regs.eax, regs.ebx, regs.ecx, regs.edx].iter().enumerate() {
+            let offset = i * 16 + j * 4;
+            buf[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+        }
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).ok().map(str::trim)
+}
+
+/// Reads the time-stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns the dense index of the executing CPU, registering it on first use. After that the
+/// index comes from IA32_TSC_AUX where the CPU has RDTSCP, rather than a search by APIC ID.
+pub fn current_index() -> usize {
+    if !has_rdtscp() {
+        return register();
+    }
+    let mut aux = 0;
+    unsafe { __rdtscp(&mut aux) };
+    if aux != 0 {
+        return aux as usize - 1;
+    }
+    let index = register();
+    unsafe { Msr::new(IA32_TSC_AUX).write(index as u64 + 1) };
+    index
+}
+
+/// Finds the slot of the executing CPU's APIC ID in [`APIC_IDS`], taking a free one the first
+/// time. SMP bring-up starts no more CPUs than there are slots.
+fn register() -> usize {
+    let id = apic_id();
+    for (index, slot) in APIC_IDS.iter().enumerate() {
+        let current = slot.load(Ordering::Acquire);
+        if current == id {
+            return index;
+        }
+        if current == UNUSED
+            && slot
+                .compare_exchange(UNUSED, id, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            return index;
+        }
+    }
+    panic!("CPU with APIC ID {} exceeds MAX_CPUS ({})", id, MAX_CPUS);
+}
+
+/// Whether the executing CPU is the bootstrap processor, which registers before any AP runs.
+pub fn is_bsp() -> bool {
+    current_index() == 0
+}
+
+/// Iterates over the CPUs that have registered so far, as `(index, apic_id)` pairs.
+pub fn online() -> impl Iterator<Item = (usize, u32)> {
+    APIC_IDS
+        .iter()
+        .map(|slot| slot.load(Ordering::Acquire))
+        .enumerate()
+        .filter(|&(_, id)| id != UNUSED)
+}
+
+/// A value with one instance per CPU. Use atomics or other interior mutability inside `T` to
+/// update it.
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// Returns the executing CPU's instance.
+    pub fn get(&self) -> &T {
+        &self.slots[current_index()]
+    }
+
+    /// Returns the instance belonging to CPU `index`.
+    pub fn get_for(&self, index: usize) -> Option<&T> {
+        self.slots.get(index)
+    }
+}
@@ -0,0 +1,61 @@
+//! The page attribute table (PAT), which picks the memory type for each combination of a page's
+//! PAT, PCD and PWT bits.
+//!
+//! Out of reset, entry 1, the one PWT alone selects, is write-through. [`init`] makes it
+//! write-combining instead, as Linux does, so device memory such as a framebuffer can be mapped
+//! for fast streaming writes. Nothing else the kernel maps sets PWT without PCD.
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+
+const IA32_PAT: u32 = 0x277;
+const CPUID_1_EDX_PAT: u32 = 1 << 16;
+
+// Memory type encodings, named as in the SDM
+const UC: u8 = 0x00;
+const WC: u8 = 0x01;
+const WT: u8 = 0x04;
+const WP: u8 = 0x05;
+const WB: u8 = 0x06;
+/// Uncacheable unless the MTRRs say write-combining.
+const UC_MINUS: u8 = 0x07;
+
+/// Entries 0 to 7. The first four differ from the reset values only in entry 1; write-through
+/// stays available in entry 7.
+const ENTRIES: [u8; 8] = [WB, WC, UC_MINUS, UC, WB, WP, UC_MINUS, WT];
+
+/// Page flags that map a page write-combining once [`init`] has run, or write-through on CPUs
+/// without a PAT.
+pub const WRITE_COMBINING: PageTableFlags = PageTableFlags::WRITE_THROUGH;
+
+/// The value of the `IA32_PAT` MSR holding [`ENTRIES`].
+const fn msr_value() -> u64 {
+    let mut value = 0;
+    let mut i = 0;
+    while i < ENTRIES.len() {
+        value |= (ENTRIES[i] as u64) << (8 * i);
+        i += 1;
+    }
+    value
+}
+
+/// Programs the PAT of the executing CPU. Every CPU has to run this, since the memory types
+/// must agree across CPUs.
+pub fn init() {
+    if __cpuid(1).edx & CPUID_1_EDX_PAT == 0 {
+        return;
+    }
+    unsafe {
+        // Nothing may stay in the caches under a type that is about to change
+        asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(msr_value());
+    }
+    x86_64::instructions::tlb::flush_all();
+}
+
+#[test_case]
+fn test_pat_layout() {
+    assert_eq!(msr_value(), 0x0407_0506_0007_0106);
+}
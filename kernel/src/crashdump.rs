@@ -0,0 +1,314 @@
+//! Crash dumps: the state of the machine when the kernel panicked, kept for after the reboot.
+//!
+//! `crashdump=` on the kernel command line says where dumps go, and can be given twice:
+//!
+//! - `serial` streams the dump over the serial port, base64-encoded between [`BEGIN_MARKER`]
+//!   and [`END_MARKER`] lines, so it survives terminals and loggers that mangle control bytes,
+//! - anything else names a block device set aside for dumps, such as a reserved partition. The
+//!   dump is written after a header sector and flushed, and [`load`] reads it back.
+//!
+//! A dump is text: the panic message, the registers, a backtrace, the task list and the end of
+//! the kernel log. It is put together in a static buffer rather than on the heap, which may be
+//! what broke, and parts whose lock is held are left out, since the panicking code may hold it.
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{backtrace, cpu, log, println, serial_println, task, timer, trap, warn};
+
+/// Largest dump; the end of a longer one is cut off.
+pub const DUMP_BYTES: usize = 32 * 1024;
+
+pub const BEGIN_MARKER: &str = "-----BEGIN KERNEL CRASH DUMP-----";
+pub const END_MARKER: &str = "-----END KERNEL CRASH DUMP-----";
+
+/// Starts the header sector of a dump on disk. The dump's length follows, as a little-endian
+/// `u32`.
+const MAGIC: [u8; 8] = *b"RKCRASH1";
+
+/// Longest block device name `crashdump=` takes.
+const MAX_DEVICE_NAME: usize = 32;
+
+/// Dump bytes per line of base64, which makes lines of 76 characters as in MIME.
+const BASE64_LINE_BYTES: usize = 57;
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// A block device name, kept inline so the panic path can read it without allocating.
+struct DeviceName {
+    bytes: [u8; MAX_DEVICE_NAME],
+    len: usize,
+}
+
+impl DeviceName {
+    fn new(name: &str) -> Option<Self> {
+        let mut bytes = [0; MAX_DEVICE_NAME];
+        bytes
+            .get_mut(..name.len())?
+            .copy_from_slice(name.as_bytes());
+        Some(DeviceName {
+            bytes,
+            len: name.len(),
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        // Copied whole from a `&str`
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+}
+
+static TO_SERIAL: AtomicBool = AtomicBool::new(false);
+/// The block device dumps are saved to.
+static DEVICE: Mutex<Option<DeviceName>> = Mutex::new(None);
+/// Set by the first panic to write a dump, so a panic while dumping doesn't start over.
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+/// Where a dump is put together, so the panic path doesn't allocate.
+struct Buffer {
+    bytes: [u8; DUMP_BYTES],
+    len: usize,
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(DUMP_BYTES - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    bytes: [0; DUMP_BYTES],
+    len: 0,
+});
+
+/// Applies the `crashdump=` options in `cmdline`.
+pub fn configure(cmdline: &str) {
+    for value in cmdline
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix("crashdump="))
+    {
+        match value {
+            "" => warn!("crashdump: no target given"),
+            "serial" => TO_SERIAL.store(true, Ordering::Relaxed),
+            device => match DeviceName::new(device) {
+                Some(name) => *DEVICE.lock() = Some(name),
+                None => warn!("crashdump: device name '{}' is too long", device),
+            },
+        }
+    }
+}
+
+/// Writes a dump of the panic `info` describes to the configured targets. Called by the panic
+/// handler once the message is out.
+pub fn on_panic(info: &PanicInfo) {
+    let to_serial = TO_SERIAL.load(Ordering::Relaxed);
+    let device = DEVICE.try_lock();
+    let device = device.as_ref().and_then(|device| device.as_ref());
+    if !to_serial && device.is_none() {
+        return;
+    }
+    if DUMPING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let Some(mut buffer) = BUFFER.try_lock() else {
+        return;
+    };
+    buffer.len = 0;
+    let _ = compose(&mut *buffer, info);
+    let dump = &buffer.bytes[..buffer.len];
+
+    if to_serial {
+        stream(dump);
+    }
+    if let Some(name) = device.map(DeviceName::as_str) {
+        match block::try_find(name) {
+            Some(device) => match block::wait(save(&*device, dump)) {
+                Ok(()) => println!("Crash dump saved to {}", name),
+                Err(e) => println!("Crash dump not saved to {}: {:?}", name, e),
+            },
+            None => println!("Crash dump not saved: no block device '{}'", name),
+        }
+    }
+}
+
+/// Writes a dump of a panic with `message` to `out`, as [`on_panic`] sends to its targets.
+pub fn compose(out: &mut dyn fmt::Write, message: &dyn fmt::Display) -> fmt::Result {
+    write!(
+        out,
+        "Kernel panic on CPU {} (APIC ID {})",
+        cpu::current_index(),
+        cpu::apic_id()
+    )?;
+    match timer::uptime_us() {
+        Some(us) => writeln!(
+            out,
+            ", {}.{:06}s after boot",
+            us / 1_000_000,
+            us % 1_000_000
+        )?,
+        None => writeln!(out)?,
+    }
+    writeln!(out, "{}", message)?;
+
+    let (rsp, rbp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rsp", "mov {}, rbp", out(reg) rsp, out(reg) rbp,
+            options(nomem, nostack, preserves_flags));
+    }
+    writeln!(out, "\nRegisters:")?;
+    // An exception's registers say more than the panic handler's own
+    match trap::fault_frame() {
+        Some(frame) => writeln!(out, "{}", frame)?,
+        None => writeln!(
+            out,
+            "  rsp {:#018x}  rbp {:#018x}  rflags {:#x}",
+            rsp,
+            rbp,
+            rflags::read_raw()
+        )?,
+    }
+    writeln!(
+        out,
+        "  cr0 {:#018x}  cr2 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw()
+    )?;
+    writeln!(
+        out,
+        "  cr3 {:#018x}  cr4 {:#018x}",
+        Cr3::read_raw().0.start_address().as_u64(),
+        Cr4::read_raw()
+    )?;
+
+    writeln!(out, "\nBacktrace:")?;
+    backtrace::write(out)?;
+
+    writeln!(out, "\nTasks:")?;
+    let mut result = Ok(());
+    let listed = task::try_for_each(|task| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "  {:>3} {:<12} {:<8} cpu{} {:>8} polls",
+                task.id,
+                task.name,
+                task.state.name(),
+                task.cpu,
+                task.polls
+            );
+        }
+    });
+    result?;
+    if !listed {
+        writeln!(out, "  (locked)")?;
+    }
+
+    writeln!(out, "\nLog:")?;
+    let written = log::with_history(|older, newer| {
+        for chunk in older.utf8_chunks().chain(newer.utf8_chunks()) {
+            out.write_str(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                out.write_char(char::REPLACEMENT_CHARACTER)?;
+            }
+        }
+        Ok(())
+    });
+    written.unwrap_or_else(|| writeln!(out, "  (locked)"))
+}
+
+/// Sends `dump` over the serial port, base64-encoded between the markers.
+fn stream(dump: &[u8]) {
+    serial_println!("{}", BEGIN_MARKER);
+    let mut line = [0; BASE64_LINE_BYTES / 3 * 4];
+    for chunk in dump.chunks(BASE64_LINE_BYTES) {
+        let len = encode_base64(chunk, &mut line);
+        serial_println!("{}", core::str::from_utf8(&line[..len]).unwrap_or(""));
+    }
+    serial_println!("{}", END_MARKER);
+}
+
+/// Encodes `input` as padded base64 into `out`, which must have room for it, and returns the
+/// length of the encoding.
+fn encode_base64(input: &[u8], out: &mut [u8]) -> usize {
+    let mut len = 0;
+    for group in input.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+            bits | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            out[len + i] = if i <= group.len() {
+                BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize]
+            } else {
+                b'='
+            };
+        }
+        len += 4;
+    }
+    len
+}
+
+/// Writes `dump`, cut to [`DUMP_BYTES`], to the start of `device` so [`load`] can find it, and
+/// flushes it.
+pub async fn save(device: &dyn BlockDevice, dump: &[u8]) -> Result<(), BlockError> {
+    let dump = &dump[..dump.len().min(DUMP_BYTES)];
+    let whole = dump.len() / SECTOR_SIZE * SECTOR_SIZE;
+    device.write(1, &dump[..whole]).await?;
+    if whole < dump.len() {
+        let mut last = [0; SECTOR_SIZE];
+        last[..dump.len() - whole].copy_from_slice(&dump[whole..]);
+        device
+            .write(1 + (whole / SECTOR_SIZE) as u64, &last)
+            .await?;
+    }
+    // The header goes last, so a dump cut short by another crash isn't taken for a whole one
+    device.flush().await?;
+    let mut header = [0; SECTOR_SIZE];
+    header[..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&(dump.len() as u32).to_le_bytes());
+    device.write(0, &header).await?;
+    device.flush().await
+}
+
+/// Reads the dump [`save`] wrote to `device`, or returns `None` if there isn't one.
+pub async fn load(device: &dyn BlockDevice) -> Result<Option<Vec<u8>>, BlockError> {
+    let mut header = [0; SECTOR_SIZE];
+    device.read(0, &mut header).await?;
+    if header[..8] != MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[8..12].try_into().expect("4 bytes")) as usize;
+    let len = len.min(DUMP_BYTES);
+    let mut dump = alloc::vec![0; len.next_multiple_of(SECTOR_SIZE)];
+    device.read(1, &mut dump).await?;
+    dump.truncate(len);
+    Ok(Some(dump))
+}
+
+/// Removes the dump from `device`, so [`load`] finds none until the next one is saved.
+pub async fn clear(device: &dyn BlockDevice) -> Result<(), BlockError> {
+    device.write(0, &[0; SECTOR_SIZE]).await?;
+    device.flush().await
+}
+
+#[test_case]
+fn test_encode_base64() {
+    let mut out = [0; 12];
+    let len = encode_base64(b"Man", &mut out);
+    assert_eq!(&out[..len], b"TWFu");
+    let len = encode_base64(b"Ma", &mut out);
+    assert_eq!(&out[..len], b"TWE=");
+    let len = encode_base64(b"M", &mut out);
+    assert_eq!(&out[..len], b"TQ==");
+    let len = encode_base64(b"panic!", &mut out);
+    assert_eq!(&out[..len], b"cGFuaWMh");
+}
@@ -0,0 +1,119 @@
+//! Errors that stop a kernel subsystem from coming up, reported by the init pipeline in
+//! [`crate::init::Boot`] instead of panicking where they happen.
+use core::fmt;
+
+use acpi::AcpiError;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+
+use crate::fw_cfg::FwCfgError;
+use crate::memory::PhysMapError;
+use crate::virtio::VirtioError;
+
+#[derive(Debug)]
+pub enum KernelError {
+    /// The bootloader did not pass a framebuffer.
+    NoFramebuffer,
+    /// The bootloader did not map physical memory, which the kernel relies on everywhere.
+    NoPhysicalMemoryOffset,
+    HeapInit(MapToError<Size4KiB>),
+    /// A stage ran before the page allocator it needs was installed.
+    PageAllocatorUninitialized,
+    /// The bootloader did not find an RSDP.
+    RsdpMissing,
+    AcpiTables(AcpiError),
+    PlatformInfo(AcpiError),
+    /// A stage that reads ACPI tables ran on a machine without them.
+    NoAcpi,
+    /// The firmware describes an interrupt model other than the APIC.
+    NotApic,
+    HpetMissing(AcpiError),
+    /// The HPET reports a zero counter period, so it can't be used to keep time.
+    HpetPeriodZero,
+    /// The HPET's registers couldn't be mapped.
+    HpetMap(PhysMapError),
+    /// SMP bring-up needs the APIC, the HPET and the firmware's processor list.
+    SmpUnavailable(&'static str),
+    /// No RAM page below 1 MiB was free for the AP trampoline.
+    NoTrampolinePage,
+    /// The trampoline page read back differently from what was copied to it.
+    TrampolineCorrupt(PhysAddr),
+    TrampolineUnmap(UnmapError),
+    TrampolineMap(MapToError<Size4KiB>),
+    FwCfg(FwCfgError),
+    VirtioConsole(VirtioError),
+    /// Turned off by a flag on the kernel command line, or in the build configuration.
+    Disabled {
+        subsystem: &'static str,
+        flag: &'static str,
+    },
+}
+
+impl KernelError {
+    /// The subsystem the error belongs to.
+    pub fn subsystem(&self) -> &'static str {
+        match self {
+            KernelError::NoFramebuffer => "framebuffer",
+            KernelError::NoPhysicalMemoryOffset
+            | KernelError::HeapInit(_)
+            | KernelError::PageAllocatorUninitialized => "memory",
+            KernelError::RsdpMissing
+            | KernelError::AcpiTables(_)
+            | KernelError::PlatformInfo(_)
+            | KernelError::NoAcpi => "ACPI",
+            KernelError::NotApic => "APIC",
+            KernelError::HpetMissing(_) | KernelError::HpetPeriodZero | KernelError::HpetMap(_) => {
+                "HPET"
+            }
+            KernelError::SmpUnavailable(_)
+            | KernelError::NoTrampolinePage
+            | KernelError::TrampolineCorrupt(_)
+            | KernelError::TrampolineUnmap(_)
+            | KernelError::TrampolineMap(_) => "SMP",
+            KernelError::FwCfg(_) => "fw_cfg",
+            KernelError::VirtioConsole(_) => "virtio-console",
+            KernelError::Disabled { subsystem, .. } => subsystem,
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.subsystem())?;
+        match self {
+            KernelError::NoFramebuffer => write!(f, "no framebuffer from the bootloader"),
+            KernelError::NoPhysicalMemoryOffset => {
+                write!(f, "physical memory offset not provided by the bootloader")
+            }
+            KernelError::HeapInit(e) => write!(f, "heap initialization failed: {:?}", e),
+            KernelError::PageAllocatorUninitialized => write!(f, "page allocator not set up"),
+            KernelError::RsdpMissing => write!(f, "RSDP missing"),
+            KernelError::AcpiTables(e) => write!(f, "failed to parse tables: {:?}", e),
+            KernelError::PlatformInfo(e) => write!(f, "no platform information: {:?}", e),
+            KernelError::NoAcpi => write!(f, "tables unavailable"),
+            KernelError::NotApic => write!(f, "interrupt model is not APIC"),
+            KernelError::HpetMissing(e) => write!(f, "not described by ACPI: {:?}", e),
+            KernelError::HpetPeriodZero => write!(f, "counter period is zero"),
+            KernelError::HpetMap(e) => write!(f, "registers unmapped: {}", e),
+            KernelError::SmpUnavailable(why) => write!(f, "unavailable without {}", why),
+            KernelError::NoTrampolinePage => {
+                write!(f, "no free page below 1 MiB for the trampoline")
+            }
+            KernelError::TrampolineCorrupt(page) => {
+                write!(
+                    f,
+                    "trampoline page {:#x} doesn't hold what was copied",
+                    page.as_u64()
+                )
+            }
+            KernelError::TrampolineUnmap(e) => {
+                write!(f, "failed to unmap trampoline page: {:?}", e)
+            }
+            KernelError::TrampolineMap(e) => write!(f, "failed to map trampoline page: {:?}", e),
+            KernelError::FwCfg(e) => write!(f, "unavailable: {:?}", e),
+            KernelError::VirtioConsole(e) => write!(f, "unavailable: {:?}", e),
+            KernelError::Disabled { flag, .. } => write!(f, "disabled by `{}`", flag),
+        }
+    }
+}
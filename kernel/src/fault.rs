@@ -0,0 +1,141 @@
+//! Fault injection: makes frame allocation, page mapping or heap allocation fail on demand, so
+//! tests can check that the kernel hands the error back instead of panicking.
+//!
+//! Each [`Site`] is checked with [`should_fail`] just before the kernel would go ahead, and fails
+//! as the [`Fault`] set for it with [`set`] says. Without the `fault-injection` feature nothing
+//! ever fails and the checks compile away.
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Site {
+    /// `FrameAllocator::allocate_frame` on the kernel's frame allocator, which also provides
+    /// the frames for new page tables.
+    FrameAlloc,
+    /// Mapping a new page into the kernel heap or a user address space.
+    MapTo,
+    /// Allocating from the kernel heap.
+    Heap,
+}
+
+impl Site {
+    pub const ALL: [Site; 3] = [Site::FrameAlloc, Site::MapTo, Site::Heap];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Nothing fails.
+    Off,
+    /// The `n`th call from now fails, counting from 1, and no other.
+    Nth(u64),
+    /// Each call fails with a chance of one in `n`.
+    OneIn(u64),
+}
+
+const OFF: u8 = 0;
+const NTH: u8 = 1;
+const ONE_IN: u8 = 2;
+
+struct Injector {
+    kind: AtomicU8,
+    /// Calls left until the failing one for [`Fault::Nth`], or `n` for [`Fault::OneIn`].
+    arg: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Injector {
+    const fn new() -> Self {
+        Injector {
+            kind: AtomicU8::new(OFF),
+            arg: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    fn check(&self) -> bool {
+        let fail = match self.kind.load(Ordering::Acquire) {
+            NTH => {
+                let left = self
+                    .arg
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| {
+                        left.checked_sub(1)
+                    });
+                left == Ok(1)
+            }
+            ONE_IN => {
+                let n = self.arg.load(Ordering::Relaxed);
+                n != 0 && random().is_multiple_of(n)
+            }
+            _ => false,
+        };
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+}
+
+static INJECTORS: [Injector; Site::ALL.len()] = [const { Injector::new() }; Site::ALL.len()];
+/// State of the xorshift generator behind [`Fault::OneIn`]; never zero.
+static RNG: AtomicU64 = AtomicU64::new(0x2545_F491_4F6C_DD1D);
+
+fn random() -> u64 {
+    let step = |mut x: u64| {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x
+    };
+    // The closure never fails, so this is always `Ok`
+    let state = RNG
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x)))
+        .unwrap_or_else(|x| x);
+    step(state)
+}
+
+/// Makes `site` fail as `fault` says from now on. Has no effect without the `fault-injection`
+/// feature.
+pub fn set(site: Site, fault: Fault) {
+    let injector = &INJECTORS[site as usize];
+    injector.kind.store(OFF, Ordering::Release);
+    let (kind, arg) = match fault {
+        Fault::Off => return,
+        Fault::Nth(n) => (NTH, n),
+        Fault::OneIn(n) => (ONE_IN, n),
+    };
+    injector.arg.store(arg, Ordering::Relaxed);
+    injector.kind.store(kind, Ordering::Release);
+}
+
+/// Stops injecting faults anywhere.
+pub fn clear() {
+    for site in Site::ALL {
+        set(site, Fault::Off);
+    }
+}
+
+/// Restarts the sequence [`Fault::OneIn`] draws from, so a run can be repeated.
+pub fn seed(seed: u64) {
+    RNG.store(seed.max(1), Ordering::Relaxed);
+}
+
+/// How many times `site` has been made to fail.
+pub fn injected(site: Site) -> u64 {
+    INJECTORS[site as usize].injected.load(Ordering::Relaxed)
+}
+
+/// Whether the operation at `site` should fail now. Callers fail it the way they would if
+/// the resource had run out.
+#[inline]
+pub fn should_fail(site: Site) -> bool {
+    cfg!(feature = "fault-injection") && INJECTORS[site as usize].check()
+}
+
+#[test_case]
+fn test_nth_call_fails_once() {
+    let injector = Injector::new();
+    injector.kind.store(NTH, Ordering::Relaxed);
+    injector.arg.store(3, Ordering::Relaxed);
+    let calls: [bool; 5] = core::array::from_fn(|_| injector.check());
+    assert_eq!(calls, [false, false, true, false, false]);
+    assert_eq!(injector.injected.load(Ordering::Relaxed), 1);
+}
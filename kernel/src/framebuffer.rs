@@ -1,30 +1,24 @@
-use core::{fmt, ptr};
+//! The bootloader's linear framebuffer as a [`Console`] backend, drawing text with a bitmap font.
+//!
+//! Characters the font has no glyph for are drawn as [`font_constants::INVALID_CHAR`], or `?`
+//! if the font lacks that too.
+use core::ptr;
 
 use bootloader_api::info::{FrameBufferInfo, PixelFormat};
 use font_constants::INVALID_CHAR;
 use noto_sans_mono_bitmap::{RasterizedChar, get_raster};
-use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::console::{Cell, Color, Console};
+use crate::mem;
 
 const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 const BORDER_PADDING: usize = 1;
 
-pub static FRAMEBUFFER_WRITER: Mutex<Option<FrameBufferWriter>> = Mutex::new(None);
-
-pub fn init_framebuffer_writer(framebuffer: &'static mut [u8], info: FrameBufferInfo) {
-    let writer = FrameBufferWriter::new(framebuffer, info);
-    *FRAMEBUFFER_WRITER.lock() = Some(writer);
-}
-
-/// Internal print function used by the framebuffer print macros.
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    if let Some(ref mut writer) = *FRAMEBUFFER_WRITER.lock() {
-        writer
-            .write_fmt(args)
-            .expect("Writing to framebuffer failed!");
-    }
-}
+/// Pixels taken by one text cell.
+const CELL_WIDTH: usize = font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING;
+const CELL_HEIGHT: usize = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
 
 mod font_constants {
     use noto_sans_mono_bitmap::{FontWeight, RasterHeight, get_raster_width};
@@ -38,7 +32,7 @@ mod font_constants {
     pub const FONT_WEIGHT: FontWeight = FontWeight::Regular;
 }
 
-fn get_char_raster(c: char) -> RasterizedChar {
+fn get_char_raster(c: char) -> Option<RasterizedChar> {
     fn get(c: char) -> Option<RasterizedChar> {
         get_raster(
             c,
@@ -46,45 +40,37 @@ fn get_char_raster(c: char) -> RasterizedChar {
             font_constants::CHAR_RASTER_HEIGHT,
         )
     }
-    get(c).unwrap_or_else(|| get(INVALID_CHAR).expect("ERROR: Failed to get invalid char."))
+    get(c).or_else(|| get(INVALID_CHAR)).or_else(|| get('?'))
 }
 
 pub struct FrameBufferWriter {
     framebuffer: &'static mut [u8],
     info: FrameBufferInfo,
-    x_pos: usize,
-    y_pos: usize,
 }
 
 impl FrameBufferWriter {
-    /// Create a new logger using a given FrameBufferInfo
+    /// Create a new writer using a given FrameBufferInfo
     pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
-        let mut logger = Self {
-            framebuffer,
-            info,
-            x_pos: 0,
-            y_pos: 0,
-        };
-        logger.clear();
-        logger
+        let mut writer = Self { framebuffer, info };
+        writer.fill_rows(0, writer.height(), Color::Black);
+        writer
     }
 
-    /// Prints a newline based on character raster height and line spacing
-    fn newline(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
-        self.carriage_return();
+    /// Switches to a different buffer or geometry, e.g. after a mode change, and clears it.
+    pub fn replace_buffer(&mut self, framebuffer: &'static mut [u8], info: FrameBufferInfo) {
+        self.framebuffer = framebuffer;
+        self.info = info;
+        self.fill_rows(0, self.height(), Color::Black);
     }
 
-    /// Increments the x position by `BORDER_PADDING` to simulate a carriage return
-    fn carriage_return(&mut self) {
-        self.x_pos = BORDER_PADDING;
+    /// Returns the current geometry and pixel format
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
     }
 
-    /// Clears the framebuffer and resets `x_pos` and `y_pos`
-    pub fn clear(&mut self) {
-        self.x_pos = BORDER_PADDING;
-        self.y_pos = BORDER_PADDING;
-        self.framebuffer.fill(0);
+    /// Returns the address the framebuffer is mapped at
+    pub fn buffer_start(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.framebuffer.as_ptr())
     }
 
     /// Returns the width of the framebuffer
@@ -97,62 +83,101 @@ impl FrameBufferWriter {
         self.info.height
     }
 
-    /// Writes a character to the framebuffer
-    fn write_char(&mut self, c: char) {
-        match c {
-            '\n' => self.newline(),
-            '\r' => self.carriage_return(),
-            c => {
-                let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
-                if new_xpos > self.width() {
-                    self.newline();
-                }
-                let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
-                if new_ypos > self.height() {
-                    self.clear();
-                }
-                self.write_rendered_char(get_char_raster(c));
-            }
-        }
-    }
-
-    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+    /// Encodes `rgb` (`0xRRGGBB`) in the framebuffer's pixel format. Supports RGB, BGR, and U8.
+    fn encode(&mut self, rgb: u32) -> [u8; 4] {
+        let [_, r, g, b] = rgb.to_be_bytes();
+        match self.info.pixel_format {
+            PixelFormat::Rgb => [r, g, b, 0],
+            PixelFormat::Bgr => [b, g, r, 0],
+            PixelFormat::U8 => [
+                ((r as u16 * 3 + g as u16 * 6 + b as u16) / 10) as u8,
+                0,
+                0,
+                0,
+            ],
+            other => {
+                self.info.pixel_format = PixelFormat::Rgb;
+                panic!("Unsupported pixel format: {:?}", other);
             }
         }
-        self.x_pos += rendered_char.width() + LETTER_SPACING;
     }
 
-    /// Write a given pixel to the framebuffer. Supports RGB, BGR, and U8 pixel formats.
-    fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+    /// Write a given pixel to the framebuffer.
+    fn write_pixel(&mut self, x: usize, y: usize, color: [u8; 4]) {
         let pixel_offset = y * self.info.stride + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
-            other => {
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("Unsupported pixel format: {:?}", other);
-            }
-        };
         let bpp = self.info.bytes_per_pixel;
         let byte_offset = pixel_offset * bpp;
         self.framebuffer[byte_offset..(byte_offset + bpp)].copy_from_slice(&color[..bpp]);
         let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
     }
+
+    /// Fills pixel lines `start..end` with `bg`.
+    fn fill_rows(&mut self, start: usize, end: usize, bg: Color) {
+        let color = self.encode(bg.rgb());
+        let bpp = self.info.bytes_per_pixel;
+        let line = self.info.stride * bpp;
+        let end = (end * line).min(self.framebuffer.len());
+        mem::stream_fill(&mut self.framebuffer[start * line..end], &color[..bpp]);
+    }
 }
 
-unsafe impl Send for FrameBufferWriter {}
-unsafe impl Sync for FrameBufferWriter {}
+/// Mixes `fg` over `bg` by the glyph coverage `intensity`.
+fn blend(fg: Color, bg: Color, intensity: u8) -> u32 {
+    let (fg, bg) = (fg.rgb().to_be_bytes(), bg.rgb().to_be_bytes());
+    let mix = |i: usize| {
+        (fg[i] as u32 * intensity as u32 + bg[i] as u32 * (255 - intensity as u32)) / 255
+    };
+    (mix(1) << 16) | (mix(2) << 8) | mix(3)
+}
+
+impl Console for FrameBufferWriter {
+    fn size(&self) -> (usize, usize) {
+        (
+            self.width().saturating_sub(2 * BORDER_PADDING) / CELL_WIDTH,
+            self.height().saturating_sub(2 * BORDER_PADDING) / CELL_HEIGHT,
+        )
+    }
 
-impl fmt::Write for FrameBufferWriter {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            self.write_char(c);
+    fn draw(&mut self, col: usize, row: usize, cell: Cell) {
+        let x0 = BORDER_PADDING + col * CELL_WIDTH;
+        let y0 = BORDER_PADDING + row * CELL_HEIGHT;
+        let raster = match cell.ch {
+            '\0' | ' ' => None,
+            c => get_char_raster(c),
+        };
+        let raster = raster.as_ref().map(RasterizedChar::raster);
+        for y in 0..CELL_HEIGHT {
+            for x in 0..CELL_WIDTH {
+                let intensity = raster
+                    .and_then(|raster| raster.get(y)?.get(x).copied())
+                    .unwrap_or(0);
+                let color = self.encode(blend(cell.fg, cell.bg, intensity));
+                self.write_pixel(x0 + x, y0 + y, color);
+            }
+        }
+    }
+
+    fn scroll_up(&mut self, bg: Color) {
+        let (_, rows) = self.size();
+        if rows == 0 {
+            return;
         }
-        Ok(())
+        let line = self.info.stride * self.info.bytes_per_pixel;
+        let top = BORDER_PADDING * line;
+        let row_bytes = CELL_HEIGHT * line;
+        mem::stream_within(
+            self.framebuffer,
+            top + row_bytes..top + rows * row_bytes,
+            top,
+        );
+        let last = BORDER_PADDING + (rows - 1) * CELL_HEIGHT;
+        self.fill_rows(last, last + CELL_HEIGHT, bg);
+    }
+
+    fn clear(&mut self, bg: Color) {
+        self.fill_rows(0, self.height(), bg);
     }
 }
+
+unsafe impl Send for FrameBufferWriter {}
+unsafe impl Sync for FrameBufferWriter {}
@@ -0,0 +1,249 @@
+//! Device nodes: the console, the serial port, `null`, `zero`, and the virtio console and the
+//! framebuffer if there are any.
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{DeviceMemory, FileSystem, FsError, Node};
+use crate::memory::PAGE_SIZE;
+use crate::process::address_space::translate_phys_active;
+use crate::virtio::console as hvc;
+use crate::{console, print, serial_print, tty};
+
+/// `ioctl` request on `/dev/fb0` for the framebuffer's geometry, as a [`FbInfo`].
+pub const FBIOGET_INFO: u64 = 0x4600;
+
+/// The terminal. Reads get keyboard input through the line discipline in [`crate::tty`], and
+/// writes go to the screen.
+struct Console;
+
+impl Node for Console {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        tty::read(buf)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, request: u64, arg: u64, answer: &mut [u8]) -> Result<usize, FsError> {
+        tty::ioctl(request, arg, answer)
+    }
+}
+
+/// COM1. Reads return whatever bytes the UART has already received, without waiting.
+struct Serial;
+
+impl Node for Serial {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut len = 0;
+        while len < buf.len()
+            && let Some(byte) = crate::serial::try_read_byte()
+        {
+            buf[len] = byte;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        serial_print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// Port 0 of the virtio console. Like the serial port, reads do not wait for input.
+struct Hvc;
+
+impl Node for Hvc {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(hvc::read(buf))
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        hvc::write(buf);
+        Ok(buf.len())
+    }
+}
+
+/// What [`FBIOGET_INFO`] answers with. Pixels are `bytes_per_pixel` bytes each, and lines
+/// `stride` pixels apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    pub stride: u32,
+    pub bytes_per_pixel: u32,
+    pub format: u32,
+    /// Bytes in the framebuffer, and so the most that can be mapped.
+    pub len: u64,
+}
+
+/// [`FbInfo::format`] values, giving the order of the colour bytes.
+pub const FB_FORMAT_RGB: u32 = 0;
+pub const FB_FORMAT_BGR: u32 = 1;
+/// One byte of grey.
+pub const FB_FORMAT_GREY: u32 = 2;
+pub const FB_FORMAT_UNKNOWN: u32 = 3;
+
+impl FbInfo {
+    fn new(info: &FrameBufferInfo) -> Self {
+        FbInfo {
+            width: info.width as u32,
+            height: info.height as u32,
+            stride: info.stride as u32,
+            bytes_per_pixel: info.bytes_per_pixel as u32,
+            format: match info.pixel_format {
+                PixelFormat::Rgb => FB_FORMAT_RGB,
+                PixelFormat::Bgr => FB_FORMAT_BGR,
+                PixelFormat::U8 => FB_FORMAT_GREY,
+                _ => FB_FORMAT_UNKNOWN,
+            },
+            len: info.byte_len as u64,
+        }
+    }
+
+    /// The fields in order, little-endian, with four bytes of padding before `len`.
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        let fields = [
+            self.width,
+            self.height,
+            self.stride,
+            self.bytes_per_pixel,
+            self.format,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes[24..].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+}
+
+/// The framebuffer as raw pixels, which can also be mapped into a process to draw directly.
+/// The console keeps drawing to it too, so its output lands on top of whatever a program draws.
+struct Fb;
+
+impl Fb {
+    /// The current geometry, which changes with the display mode, and where the pixels are.
+    fn buffer() -> Result<(FrameBufferInfo, VirtAddr), FsError> {
+        console::framebuffer().ok_or(FsError::NotFound)
+    }
+}
+
+impl Node for Fb {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let (info, start) = Fb::buffer()?;
+        let offset = (offset as usize).min(info.byte_len);
+        let len = buf.len().min(info.byte_len - offset);
+        let pixels = (start + offset as u64).as_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(pixels, buf.as_mut_ptr(), len) };
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let (info, start) = Fb::buffer()?;
+        let offset = (offset as usize).min(info.byte_len);
+        let len = buf.len().min(info.byte_len - offset);
+        let pixels = (start + offset as u64).as_mut_ptr::<u8>();
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr(), pixels, len) };
+        Ok(len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Fb::buffer().ok().map(|(info, _)| info.byte_len as u64)
+    }
+
+    fn ioctl(&self, request: u64, _arg: u64, answer: &mut [u8]) -> Result<usize, FsError> {
+        if request != FBIOGET_INFO {
+            return Err(FsError::NotSupported);
+        }
+        let bytes = FbInfo::new(&Fb::buffer()?.0).to_bytes();
+        answer[..bytes.len()].copy_from_slice(&bytes);
+        Ok(bytes.len())
+    }
+
+    fn device_memory(&self) -> Option<DeviceMemory> {
+        let (info, start) = Fb::buffer().ok()?;
+        // The bootloader maps the framebuffer in one piece, from contiguous memory
+        let phys: PhysAddr = translate_phys_active(start)?;
+        phys.is_aligned(PAGE_SIZE).then_some(DeviceMemory {
+            start: phys,
+            len: info.byte_len as u64,
+            write_combining: true,
+        })
+    }
+}
+
+struct Null;
+
+impl Node for Null {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+struct Zero;
+
+impl Node for Zero {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+pub struct DevFs {
+    devices: Vec<(&'static str, Arc<dyn Node>)>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        let mut devices = alloc::vec![
+            ("console", Arc::new(Console) as Arc<dyn Node>),
+            ("serial", Arc::new(Serial)),
+            ("null", Arc::new(Null)),
+            ("zero", Arc::new(Zero)),
+        ];
+        if hvc::is_present() {
+            devices.push(("hvc0", Arc::new(Hvc)));
+        }
+        if console::framebuffer().is_some() {
+            devices.push(("fb0", Arc::new(Fb)));
+        }
+        DevFs { devices }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for DevFs {
+    fn lookup(&self, path: &str) -> Result<Arc<dyn Node>, FsError> {
+        self.devices
+            .iter()
+            .find(|(name, _)| *name == path)
+            .map(|(_, node)| node.clone())
+            .ok_or(FsError::NotFound)
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .map(|(name, _)| String::from(*name))
+            .collect()
+    }
+}
@@ -0,0 +1,285 @@
+//! A consistency check for FAT32 volumes, in the spirit of `fsck.fat -n`: it reads the whole
+//! volume and reports problems without repairing anything.
+//!
+//! Starting at the root directory, it follows the FAT chain of every file and directory,
+//! recording which path owns each cluster. A cluster claimed twice is cross-linked; a chain
+//! that runs into a free, bad or nonexistent cluster is broken; and a file's size has to fit
+//! its chain. Allocated clusters that nothing owns afterwards are lost. The FAT copies are
+//! also compared with each other.
+use alloc::{string::String, vec::Vec};
+
+use super::{
+    BAD, BootSector, DIR_ENTRY_SIZE, DirEntry, END_OF_CHAIN, ENTRY_MASK, FIRST_CLUSTER, FREE,
+    FatError, is_end_of_directory, read_boot_sector,
+};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// FAT copy `copy` differs from the first starting at `sector` of the FAT.
+    FatsDiffer { copy: u32, sector: u32 },
+    /// `path`'s chain reaches `cluster`, which isn't in the data area.
+    OutOfRange { path: String, cluster: u32 },
+    /// `path` uses `cluster`, which the FAT marks free.
+    FreeInChain { path: String, cluster: u32 },
+    /// `path` uses `cluster`, which the FAT marks bad.
+    BadInChain { path: String, cluster: u32 },
+    /// `path`'s chain comes back to `cluster`, which it already went through.
+    Loop { path: String, cluster: u32 },
+    /// `cluster` is in the chains of both `first` and `second`.
+    CrossLinked {
+        cluster: u32,
+        first: String,
+        second: String,
+    },
+    /// `path` is `size` bytes long, but its chain has `clusters` clusters.
+    SizeMismatch {
+        path: String,
+        size: u32,
+        clusters: u32,
+    },
+    /// `count` clusters are allocated in the FAT but no file uses them, the lowest numbered
+    /// being `first`.
+    LostClusters { first: u32, count: u32 },
+}
+
+/// What [`check`] found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub files: usize,
+    /// Directories, including the root.
+    pub directories: usize,
+    /// Clusters in the chains of files and directories.
+    pub used_clusters: u32,
+    pub free_clusters: u32,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A file or directory waiting to be checked.
+struct Pending {
+    path: String,
+    first_cluster: u32,
+    directory: bool,
+    size: u32,
+}
+
+struct Checker<'a> {
+    device: &'a dyn BlockDevice,
+    boot: BootSector,
+    fat: Vec<u32>,
+    /// Index into `paths` of the owner of each cluster, by cluster number.
+    owners: Vec<Option<usize>>,
+    paths: Vec<String>,
+    report: Report,
+}
+
+/// Checks the FAT32 volume on `device`. Only fails if the volume can't be read, or isn't FAT32.
+pub async fn check(device: &dyn BlockDevice) -> Result<Report, FatError> {
+    let boot = read_boot_sector(device).await?;
+    let mut checker = Checker {
+        device,
+        boot,
+        fat: Vec::new(),
+        owners: alloc::vec![None; (FIRST_CLUSTER + boot.cluster_count()) as usize],
+        paths: Vec::new(),
+        report: Report::default(),
+    };
+    checker.read_fats().await?;
+
+    let mut pending = alloc::vec![Pending {
+        path: String::from("/"),
+        first_cluster: boot.root_cluster,
+        directory: true,
+        size: 0,
+    }];
+    while let Some(next) = pending.pop() {
+        let clusters = checker.follow(&next);
+        if next.directory {
+            checker.report.directories += 1;
+            checker
+                .read_directory(&next.path, &clusters, &mut pending)
+                .await?;
+        } else {
+            checker.report.files += 1;
+        }
+    }
+    checker.find_lost();
+    Ok(checker.report)
+}
+
+impl Checker<'_> {
+    /// Reads the first FAT, and the others to compare with it.
+    async fn read_fats(&mut self) -> Result<(), FatError> {
+        let entries = (FIRST_CLUSTER + self.boot.cluster_count()) as usize;
+        let mut first = Vec::new();
+        let mut sector = [0; SECTOR_SIZE];
+        for index in 0..self.boot.fat_sectors {
+            self.device
+                .read(self.boot.fat_sector(0) + index as u64, &mut sector)
+                .await?;
+            first.extend_from_slice(&sector);
+        }
+        for copy in 1..self.boot.fat_count {
+            for index in 0..self.boot.fat_sectors {
+                self.device
+                    .read(self.boot.fat_sector(copy) + index as u64, &mut sector)
+                    .await?;
+                let offset = index as usize * SECTOR_SIZE;
+                if sector[..] != first[offset..offset + SECTOR_SIZE] {
+                    self.report.problems.push(Problem::FatsDiffer {
+                        copy,
+                        sector: index,
+                    });
+                    break;
+                }
+            }
+        }
+        self.fat = first
+            .chunks_exact(4)
+            .take(entries)
+            .map(|entry| u32::from_le_bytes(entry.try_into().expect("4 bytes")) & ENTRY_MASK)
+            .collect();
+        Ok(())
+    }
+
+    /// Claims the clusters in `file`'s chain and returns them, stopping at the first problem.
+    fn follow(&mut self, file: &Pending) -> Vec<u32> {
+        let owner = self.paths.len();
+        self.paths.push(file.path.clone());
+        let mut clusters = Vec::new();
+        let mut cluster = file.first_cluster;
+        // Empty files have no chain; the root directory always has one
+        if cluster == FREE && !file.directory {
+            return clusters;
+        }
+        loop {
+            if !self.boot.is_data_cluster(cluster) {
+                self.report.problems.push(Problem::OutOfRange {
+                    path: file.path.clone(),
+                    cluster,
+                });
+                break;
+            }
+            match self.owners[cluster as usize] {
+                Some(previous) if previous == owner => {
+                    self.report.problems.push(Problem::Loop {
+                        path: file.path.clone(),
+                        cluster,
+                    });
+                    break;
+                }
+                Some(previous) => {
+                    self.report.problems.push(Problem::CrossLinked {
+                        cluster,
+                        first: self.paths[previous].clone(),
+                        second: file.path.clone(),
+                    });
+                    break;
+                }
+                None => self.owners[cluster as usize] = Some(owner),
+            }
+            clusters.push(cluster);
+            self.report.used_clusters += 1;
+
+            let next = self.fat[cluster as usize];
+            if next >= END_OF_CHAIN {
+                break;
+            }
+            if next == FREE {
+                self.report.problems.push(Problem::FreeInChain {
+                    path: file.path.clone(),
+                    cluster,
+                });
+                break;
+            }
+            if next == BAD {
+                self.report.problems.push(Problem::BadInChain {
+                    path: file.path.clone(),
+                    cluster,
+                });
+                break;
+            }
+            cluster = next;
+        }
+
+        if !file.directory {
+            let expected = (file.size as u64).div_ceil(self.boot.cluster_bytes());
+            if expected != clusters.len() as u64 {
+                self.report.problems.push(Problem::SizeMismatch {
+                    path: file.path.clone(),
+                    size: file.size,
+                    clusters: clusters.len() as u32,
+                });
+            }
+        }
+        clusters
+    }
+
+    /// Reads the entries of the directory at `path`, which occupies `clusters`, and queues
+    /// them to be checked.
+    async fn read_directory(
+        &mut self,
+        path: &str,
+        clusters: &[u32],
+        pending: &mut Vec<Pending>,
+    ) -> Result<(), FatError> {
+        let mut sector = [0; SECTOR_SIZE];
+        for &cluster in clusters {
+            let first = self.boot.cluster_sector(cluster);
+            for index in 0..self.boot.sectors_per_cluster as u64 {
+                self.device.read(first + index, &mut sector).await?;
+                for bytes in sector.chunks_exact(DIR_ENTRY_SIZE) {
+                    if is_end_of_directory(bytes) {
+                        return Ok(());
+                    }
+                    let Some(entry) = DirEntry::parse(bytes) else {
+                        continue;
+                    };
+                    if entry.is_dot() || entry.is_volume_label() {
+                        continue;
+                    }
+                    let mut child = String::from(path);
+                    if !child.ends_with('/') {
+                        child.push('/');
+                    }
+                    child.push_str(&entry.display_name());
+                    pending.push(Pending {
+                        path: child,
+                        first_cluster: entry.first_cluster,
+                        directory: entry.is_directory(),
+                        size: entry.size,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts free clusters, and reports allocated ones that no chain reached.
+    fn find_lost(&mut self) {
+        let mut lost = 0;
+        let mut first_lost = None;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + self.boot.cluster_count() {
+            match self.fat[cluster as usize] {
+                FREE => self.report.free_clusters += 1,
+                BAD => {}
+                _ if self.owners[cluster as usize].is_none() => {
+                    lost += 1;
+                    first_lost.get_or_insert(cluster);
+                }
+                _ => {}
+            }
+        }
+        if let Some(first) = first_lost {
+            self.report
+                .problems
+                .push(Problem::LostClusters { first, count: lost });
+        }
+    }
+}
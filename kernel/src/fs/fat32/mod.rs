@@ -0,0 +1,39 @@
+//! Reading FAT32 volumes from block devices, in the format [`kernel_algo::fat32`] describes.
+use crate::block::{self, BlockDevice, BlockError};
+
+pub use kernel_algo::fat32::*;
+
+pub mod fsck;
+
+const _: () = assert!(SECTOR_SIZE == block::SECTOR_SIZE);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Block(BlockError),
+    /// The boot sector doesn't describe a FAT32 volume.
+    NotFat32,
+    /// A FAT32 volume this code doesn't handle, e.g. with sectors other than 512 bytes.
+    Unsupported,
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        FatError::Block(error)
+    }
+}
+
+impl From<FormatError> for FatError {
+    fn from(error: FormatError) -> Self {
+        match error {
+            FormatError::NotFat32 => FatError::NotFat32,
+            FormatError::Unsupported => FatError::Unsupported,
+        }
+    }
+}
+
+/// Reads the boot sector of the volume on `device`.
+pub async fn read_boot_sector(device: &dyn BlockDevice) -> Result<BootSector, FatError> {
+    let mut sector = [0; SECTOR_SIZE];
+    device.read(0, &mut sector).await?;
+    Ok(BootSector::parse(&sector)?)
+}
@@ -0,0 +1,37 @@
+//! The files QEMU passes through fw_cfg, read from the device on demand.
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use super::{FileSystem, FsError, Node};
+use crate::fw_cfg::{self, FwCfgError, FwCfgFile};
+
+struct FwCfgNode(FwCfgFile);
+
+impl Node for FwCfgNode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        fw_cfg::read_at(&self.0, offset, buf).map_err(|e| match e {
+            FwCfgError::NotFound => FsError::NotFound,
+            FwCfgError::NotPresent | FwCfgError::Dma => FsError::NotSupported,
+        })
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.0.size as u64)
+    }
+}
+
+pub struct FwCfgFs;
+
+impl FileSystem for FwCfgFs {
+    fn lookup(&self, path: &str) -> Result<Arc<dyn Node>, FsError> {
+        let file = fw_cfg::find(path).ok_or(FsError::NotFound)?;
+        Ok(Arc::new(FwCfgNode(file)))
+    }
+
+    fn list(&self) -> Vec<String> {
+        fw_cfg::files().into_iter().map(|file| file.name).collect()
+    }
+}
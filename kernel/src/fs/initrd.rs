@@ -0,0 +1,177 @@
+//! The initrd: a `newc` cpio archive, the format Linux uses, that the bootloader loads next to
+//! the kernel. [`super::init`] unpacks the regular files in it into the root file system, which
+//! is how the shell and the other user programs get onto the machine.
+use bootloader_api::BootInfo;
+use spin::Once;
+
+use super::ramfs::RamFs;
+
+const MAGIC: &[u8; 6] = b"070701";
+/// The magic followed by 13 fields of 8 hex digits.
+const HEADER_LEN: usize = 110;
+/// Name of the entry that ends the archive.
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of a mode, and the type of a regular file.
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// An entry doesn't start with the `newc` magic.
+    BadMagic,
+    /// A header field isn't hex.
+    BadHeader,
+    /// An entry runs past the end of the archive.
+    Truncated,
+    /// A name isn't UTF-8 or isn't NUL-terminated.
+    BadName,
+}
+
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+/// The entries of an archive, up to its trailer. Stops after the first malformed one.
+pub struct Entries<'a> {
+    rest: &'a [u8],
+}
+
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries { rest: archive }
+}
+
+impl<'a> Entries<'a> {
+    /// Parses the entry at the start of `rest`, or returns `None` at the trailer.
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, InitrdError> {
+        let header = self.rest.get(..HEADER_LEN).ok_or(InitrdError::Truncated)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(InitrdError::BadMagic);
+        }
+        let field = |index: usize| {
+            let start = MAGIC.len() + 8 * index;
+            hex(&header[start..start + 8])
+        };
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        // The name, with its NUL, and the data each start on a 4-byte boundary
+        let name_end = HEADER_LEN + name_size;
+        let data_start = name_end.next_multiple_of(4);
+        let data_end = data_start + size;
+        if name_size == 0 || data_end > self.rest.len() {
+            return Err(InitrdError::Truncated);
+        }
+        let name = match self.rest[HEADER_LEN..name_end].split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| InitrdError::BadName)?,
+            _ => return Err(InitrdError::BadName),
+        };
+        if name == TRAILER {
+            return Ok(None);
+        }
+        let data = &self.rest[data_start..data_end];
+        // The padding after the last entry may be missing
+        self.rest = &self.rest[data_end.next_multiple_of(4).min(self.rest.len())..];
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, InitrdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let entry = self.parse().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.rest = &[];
+        }
+        entry
+    }
+}
+
+fn hex(digits: &[u8]) -> Result<u32, InitrdError> {
+    let digits = core::str::from_utf8(digits).map_err(|_| InitrdError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| InitrdError::BadHeader)
+}
+
+static ARCHIVE: Once<&'static [u8]> = Once::new();
+
+/// Records where the bootloader put the initrd, if it loaded one.
+pub fn init(boot_info: &BootInfo) {
+    if let Some(&addr) = boot_info.ramdisk_addr.as_ref() {
+        // The bootloader maps the initrd and reports its memory as in use, so it stays put
+        let archive = unsafe {
+            core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize)
+        };
+        ARCHIVE.call_once(|| archive);
+    }
+}
+
+/// The initrd the bootloader loaded, if any.
+pub fn archive() -> Option<&'static [u8]> {
+    ARCHIVE.get().copied()
+}
+
+/// Copies the regular files in `archive` into `root`, read-only, and returns how many there
+/// were. Files before a malformed entry are still copied.
+pub fn unpack(archive: &[u8], root: &RamFs) -> Result<usize, InitrdError> {
+    let mut files = 0;
+    for entry in entries(archive) {
+        let entry = entry?;
+        if entry.is_file() {
+            root.insert(entry.name.trim_start_matches("./"), entry.data, true);
+            files += 1;
+        }
+    }
+    Ok(files)
+}
+
+#[test_case]
+fn test_parse_newc_archive() {
+    // Each header is the magic, ino, mode, uid, gid, nlink and mtime, then filesize, devmajor,
+    // devminor, rdevmajor, rdevminor, namesize and check
+    const ARCHIVE: &str = concat!(
+        "07070100000001000081ED00000000000000000000000100000000",
+        "00000003000000000000000000000000000000000000000700000000",
+        "bin/sh\0\0\0\0",
+        "hi!\0",
+        "07070100000002000041ED00000000000000000000000100000000",
+        "00000000000000000000000000000000000000000000000400000000",
+        "bin\0\0\0",
+        "070701000000000000000000000000000000000000000100000000",
+        "00000000000000000000000000000000000000000000000B00000000",
+        "TRAILER!!!\0\0\0\0",
+    );
+    let archive = ARCHIVE.as_bytes();
+
+    let mut parsed = entries(archive);
+    let file = parsed.next().unwrap().unwrap();
+    assert_eq!(
+        (file.name, file.data, file.is_file()),
+        ("bin/sh", &b"hi!"[..], true)
+    );
+    let dir = parsed.next().unwrap().unwrap();
+    assert_eq!((dir.name, dir.is_file()), ("bin", false));
+    assert!(parsed.next().is_none());
+
+    // Parsing stops at the first malformed entry
+    let mut parsed = entries(&archive[..200]);
+    assert!(parsed.next().unwrap().is_ok());
+    assert_eq!(parsed.next().unwrap().err(), Some(InitrdError::Truncated));
+    assert!(parsed.next().is_none());
+    assert_eq!(
+        entries(&archive[1..]).next().unwrap().err(),
+        Some(InitrdError::BadMagic)
+    );
+}
@@ -0,0 +1,191 @@
+//! A minimal virtual file system.
+//!
+//! File systems are mounted at path prefixes and resolve the rest of a path to a [`Node`]. There
+//! are no directories as such: each file system maps whole relative paths to nodes.
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::cmp::Reverse;
+
+use spin::RwLock;
+use x86_64::PhysAddr;
+
+use crate::error::KernelError;
+use crate::{info, kernel_init, warn};
+
+pub mod devfs;
+pub mod fat32;
+pub mod fwcfgfs;
+pub mod initrd;
+pub mod pipe;
+pub mod ramfs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    /// The path is not absolute or has an empty component.
+    InvalidPath,
+    ReadOnly,
+    /// The node doesn't support the operation, e.g. seeking on a device.
+    NotSupported,
+    /// The operation can't make progress yet; retry once the given wait channel is woken.
+    WouldBlock(usize),
+    /// Writing to a pipe whose read end is closed.
+    BrokenPipe,
+    /// An argument to a device request is out of range.
+    InvalidArgument,
+    /// A write would take the file past the largest size its file system allows.
+    FileTooBig,
+}
+
+/// Physical memory behind a device node that processes can map, such as a framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMemory {
+    /// Page-aligned.
+    pub start: PhysAddr,
+    pub len: u64,
+    /// Whether to map it write-combining rather than uncached.
+    pub write_combining: bool,
+}
+
+/// Something that can be read and written: a file, a device, or one end of a pipe.
+pub trait Node: Send + Sync {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError>;
+
+    /// Size in bytes for seekable files, `None` for streams such as devices and pipes, which
+    /// ignore the offset.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Discards the contents of the file.
+    fn truncate(&self) -> Result<(), FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Carries out the device-specific `request`. Requests that take a value get it in `arg`;
+    /// those that answer write the answer to the start of `answer` and return its length, and
+    /// the system call stores it at `arg`.
+    fn ioctl(&self, _request: u64, _arg: u64, _answer: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    /// The memory a process can map from this node, for devices that have some.
+    fn device_memory(&self) -> Option<DeviceMemory> {
+        None
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Finds the node at `path`, relative to the mount point.
+    fn lookup(&self, path: &str) -> Result<Arc<dyn Node>, FsError>;
+
+    /// Creates an empty file at `path`, relative to the mount point.
+    fn create(&self, _path: &str) -> Result<Arc<dyn Node>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Paths of all nodes, relative to the mount point.
+    fn list(&self) -> Vec<String>;
+}
+
+struct Mount {
+    prefix: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+/// Mounts `fs` at `prefix`, e.g. `/dev`. Later lookups pick the longest matching prefix.
+pub fn mount(prefix: &str, fs: Arc<dyn FileSystem>) {
+    let prefix = String::from(prefix.trim_end_matches('/'));
+    let mut mounts = MOUNTS.write();
+    mounts.retain(|m| m.prefix != prefix);
+    mounts.push(Mount { prefix, fs });
+    // Longest prefix first, so `/dev` wins over `/`
+    mounts.sort_by_key(|m| Reverse(m.prefix.len()));
+}
+
+/// Finds the file system responsible for `path` and the path relative to it.
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String), FsError> {
+    if !path.starts_with('/') || path.split('/').skip(1).any(str::is_empty) {
+        return Err(FsError::InvalidPath);
+    }
+    let mounts = MOUNTS.read();
+    for mount in mounts.iter() {
+        if let Some(rest) = path.strip_prefix(mount.prefix.as_str())
+            && let Some(relative) = rest.strip_prefix('/')
+        {
+            return Ok((mount.fs.clone(), String::from(relative)));
+        }
+    }
+    Err(FsError::NotFound)
+}
+
+/// Opens the node at absolute `path`, creating an empty file there first if `create` is set and
+/// nothing exists yet.
+pub fn open(path: &str, create: bool) -> Result<Arc<dyn Node>, FsError> {
+    let (fs, relative) = resolve(path)?;
+    match fs.lookup(&relative) {
+        Err(FsError::NotFound) if create => fs.create(&relative),
+        result => result,
+    }
+}
+
+/// Reads the whole file at `path`.
+pub fn read_all(path: &str) -> Result<Vec<u8>, FsError> {
+    let node = open(path, false)?;
+    let size = node.size().ok_or(FsError::NotSupported)?;
+    let mut data = alloc::vec![0u8; size as usize];
+    let mut done = 0;
+    while done < data.len() {
+        match node.read_at(done as u64, &mut data[done..])? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+/// Absolute paths of every node in every mounted file system.
+pub fn list() -> Vec<String> {
+    let mounts = MOUNTS.read();
+    let mut paths = Vec::new();
+    for mount in mounts.iter() {
+        for path in mount.fs.list() {
+            paths.push(alloc::format!("{}/{}", mount.prefix, path));
+        }
+    }
+    paths.sort();
+    paths
+}
+
+// The `/fw_cfg` mount depends on whether fw_cfg found the device
+kernel_init!(Early, "fs", after = ["fw_cfg"], init_stage);
+
+fn init_stage() -> Result<(), KernelError> {
+    init();
+    Ok(())
+}
+
+/// Mounts a RAM file system at `/`, holding the built-in programs under `/bin` and the contents
+/// of the initrd, the device file system at `/dev`, and the host's fw_cfg files at `/fw_cfg`
+/// when there are any.
+pub fn init() {
+    let root = ramfs::RamFs::new();
+    for (name, image) in crate::process::builtin::PROGRAMS {
+        root.insert(&alloc::format!("bin/{}", name), image(), true);
+    }
+    if let Some(archive) = initrd::archive() {
+        match initrd::unpack(archive, &root) {
+            Ok(files) => info!("initrd: {} files", files),
+            Err(e) => warn!("initrd: {:?}", e),
+        }
+    }
+    mount("/", Arc::new(root));
+    mount("/dev", Arc::new(devfs::DevFs::new()));
+    if crate::fw_cfg::is_present() {
+        mount("/fw_cfg", Arc::new(fwcfgfs::FwCfgFs));
+    }
+}
@@ -0,0 +1,108 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte queue with a read end and a write end, each a [`Node`] that any
+//! number of descriptors may share. Reading an empty pipe or writing a full one fails with
+//! [`FsError::WouldBlock`], naming a wait channel that is woken whenever the other end makes
+//! progress or goes away.
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{FsError, Node};
+use crate::process::scheduler;
+
+/// Bytes a pipe buffers before writers have to wait.
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct Pipe {
+    buffer: Mutex<VecDeque<u8>>,
+    reader_open: AtomicBool,
+    writer_open: AtomicBool,
+}
+
+impl Pipe {
+    fn channel(&self) -> usize {
+        self as *const Pipe as usize
+    }
+}
+
+/// The read end of a pipe. Reads return 0 once the buffer is empty and the write end is gone.
+pub struct PipeReader(Arc<Pipe>);
+
+/// The write end of a pipe. Writes fail with [`FsError::BrokenPipe`] once the read end is gone.
+pub struct PipeWriter(Arc<Pipe>);
+
+/// Creates a pipe and returns its two ends.
+pub fn pipe() -> (Arc<PipeReader>, Arc<PipeWriter>) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(VecDeque::new()),
+        reader_open: AtomicBool::new(true),
+        writer_open: AtomicBool::new(true),
+    });
+    (
+        Arc::new(PipeReader(pipe.clone())),
+        Arc::new(PipeWriter(pipe)),
+    )
+}
+
+impl Node for PipeReader {
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let pipe = &self.0;
+        let mut buffer = pipe.buffer.lock();
+        if buffer.is_empty() {
+            if pipe.writer_open.load(Ordering::Acquire) {
+                return Err(FsError::WouldBlock(pipe.channel()));
+            }
+            return Ok(0);
+        }
+        let len = buf.len().min(buffer.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.drain(..len)) {
+            *dst = src;
+        }
+        drop(buffer);
+        scheduler::wake(pipe.channel());
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+impl Node for PipeWriter {
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let pipe = &self.0;
+        if !pipe.reader_open.load(Ordering::Acquire) {
+            return Err(FsError::BrokenPipe);
+        }
+        let mut buffer = pipe.buffer.lock();
+        let space = PIPE_CAPACITY - buffer.len();
+        if space == 0 {
+            return Err(FsError::WouldBlock(pipe.channel()));
+        }
+        let len = buf.len().min(space);
+        buffer.extend(&buf[..len]);
+        drop(buffer);
+        scheduler::wake(pipe.channel());
+        Ok(len)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.reader_open.store(false, Ordering::Release);
+        scheduler::wake(self.0.channel());
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.writer_open.store(false, Ordering::Release);
+        scheduler::wake(self.0.channel());
+    }
+}
@@ -0,0 +1,107 @@
+//! A file system that keeps its files in memory.
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+
+use spin::RwLock;
+
+use super::{FileSystem, FsError, Node};
+
+/// Largest a file may grow, so that a write far past the end can't ask for more memory than
+/// there is.
+pub const MAX_FILE_SIZE: u64 = 16 << 20;
+
+pub struct RamFile {
+    data: RwLock<Vec<u8>>,
+    read_only: bool,
+}
+
+impl Node for RamFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.data.read();
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .ok_or(FsError::FileTooBig)?;
+        let (offset, end) = (offset as usize, end as usize);
+        let mut data = self.data.write();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.data.read().len() as u64)
+    }
+
+    fn truncate(&self) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.data.write().clear();
+        Ok(())
+    }
+}
+
+pub struct RamFs {
+    files: RwLock<BTreeMap<String, Arc<RamFile>>>,
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        RamFs {
+            files: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Adds a file with the given contents, replacing any existing one.
+    pub fn insert(&self, path: &str, contents: &[u8], read_only: bool) {
+        let file = Arc::new(RamFile {
+            data: RwLock::new(Vec::from(contents)),
+            read_only,
+        });
+        self.files.write().insert(String::from(path), file);
+    }
+}
+
+impl Default for RamFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for RamFs {
+    fn lookup(&self, path: &str) -> Result<Arc<dyn Node>, FsError> {
+        match self.files.read().get(path) {
+            Some(file) => Ok(file.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, path: &str) -> Result<Arc<dyn Node>, FsError> {
+        let mut files = self.files.write();
+        if files.contains_key(path) {
+            return Err(FsError::AlreadyExists);
+        }
+        let file = Arc::new(RamFile {
+            data: RwLock::new(Vec::new()),
+            read_only: false,
+        });
+        files.insert(String::from(path), file.clone());
+        Ok(file)
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.files.read().keys().cloned().collect()
+    }
+}
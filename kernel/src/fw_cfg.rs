@@ -0,0 +1,307 @@
+//! QEMU's firmware configuration device, through which the host passes named blobs to the
+//! guest, e.g. with `-fw_cfg name=opt/rust-kernel/cmdline,string=...`.
+//!
+//! Items are read through the DMA interface when the device offers it and one byte at a time
+//! through the data port otherwise. The file directory is read once by [`init`].
+use alloc::{string::String, vec, vec::Vec};
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::FrameAllocator;
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::info;
+use crate::memory::phys_to_virt;
+#[cfg(driver = "fw_cfg")]
+use crate::{error::KernelError, kernel_init};
+
+const PORT_SELECTOR: u16 = 0x510;
+const PORT_DATA: u16 = 0x511;
+/// The DMA address register, written as two big-endian halves; writing the low half starts
+/// the transfer.
+const PORT_DMA_HIGH: u16 = 0x514;
+const PORT_DMA_LOW: u16 = 0x518;
+
+const SELECT_SIGNATURE: u16 = 0x0000;
+const SELECT_ID: u16 = 0x0001;
+const SELECT_FILE_DIR: u16 = 0x0019;
+
+const SIGNATURE: &[u8; 4] = b"QEMU";
+const ID_DMA: u32 = 1 << 1;
+
+const DMA_ERROR: u32 = 1 << 0;
+const DMA_READ: u32 = 1 << 1;
+const DMA_SKIP: u32 = 1 << 2;
+const DMA_SELECT: u32 = 1 << 3;
+
+/// Size of a directory entry: size, select, reserved, then a NUL-padded name.
+const DIR_ENTRY_LEN: usize = 64;
+const NAME_LEN: usize = 56;
+
+/// Where the kernel looks for its command line.
+pub const CMDLINE_FILE: &str = "opt/rust-kernel/cmdline";
+
+const PAGE_SIZE: usize = 4096;
+/// The DMA descriptor sits at the start of the bounce page and data follows it.
+const BOUNCE_OFFSET: usize = 64;
+const BOUNCE_LEN: usize = PAGE_SIZE - BOUNCE_OFFSET;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FwCfgError {
+    NotPresent,
+    NotFound,
+    /// The device flagged a DMA transfer as failed.
+    Dma,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FwCfgFile {
+    pub name: String,
+    pub size: u32,
+    pub select: u16,
+}
+
+#[repr(C)]
+struct DmaAccess {
+    control: u32,
+    length: u32,
+    address: u64,
+}
+
+struct FwCfg {
+    /// Physical address of the bounce page, if DMA is in use.
+    dma_page: Option<u64>,
+    files: Vec<FwCfgFile>,
+}
+
+static FW_CFG: Mutex<Option<FwCfg>> = Mutex::new(None);
+
+#[cfg(driver = "fw_cfg")]
+kernel_init!(Early, "fw_cfg", init_stage);
+
+/// [`init`] as an initcall, where a machine without the device isn't a failure.
+#[cfg(driver = "fw_cfg")]
+fn init_stage() -> Result<(), KernelError> {
+    match init() {
+        Ok(()) | Err(FwCfgError::NotPresent) => Ok(()),
+        Err(e) => Err(KernelError::FwCfg(e)),
+    }
+}
+
+/// Looks for the device and reads its file directory. Must run after memory initialisation.
+pub fn init() -> Result<(), FwCfgError> {
+    let mut signature = [0u8; 4];
+    select(SELECT_SIGNATURE);
+    read_port(&mut signature);
+    if &signature != SIGNATURE {
+        return Err(FwCfgError::NotPresent);
+    }
+    let mut id = [0u8; 4];
+    select(SELECT_ID);
+    read_port(&mut id);
+    let dma_page = if u32::from_le_bytes(id) & ID_DMA != 0 {
+        PAGE_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|allocator| allocator.frame_allocator.allocate_frame())
+            .map(|frame| frame.start_address().as_u64())
+    } else {
+        None
+    };
+    let mut fw_cfg = FwCfg {
+        dma_page,
+        files: Vec::new(),
+    };
+    fw_cfg.files = fw_cfg.read_directory()?;
+    info!(
+        "fw_cfg: {} files, {}",
+        fw_cfg.files.len(),
+        if dma_page.is_some() {
+            "DMA"
+        } else {
+            "port I/O"
+        }
+    );
+    *FW_CFG.lock() = Some(fw_cfg);
+    Ok(())
+}
+
+pub fn is_present() -> bool {
+    FW_CFG.lock().is_some()
+}
+
+/// Every file the host provided.
+pub fn files() -> Vec<FwCfgFile> {
+    FW_CFG
+        .lock()
+        .as_ref()
+        .map_or_else(Vec::new, |fw_cfg| fw_cfg.files.clone())
+}
+
+pub fn find(name: &str) -> Option<FwCfgFile> {
+    FW_CFG
+        .lock()
+        .as_ref()?
+        .files
+        .iter()
+        .find(|file| file.name == name)
+        .cloned()
+}
+
+/// Reads part of `file` starting at `offset`, returning how many bytes were read.
+pub fn read_at(file: &FwCfgFile, offset: u64, buf: &mut [u8]) -> Result<usize, FwCfgError> {
+    let size = file.size as u64;
+    if offset >= size {
+        return Ok(0);
+    }
+    let len = buf.len().min((size - offset) as usize);
+    let mut guard = FW_CFG.lock();
+    let fw_cfg = guard.as_mut().ok_or(FwCfgError::NotPresent)?;
+    fw_cfg.read(file.select, offset as u32, &mut buf[..len])?;
+    Ok(len)
+}
+
+/// Reads the whole of the file called `name`.
+pub fn read_file(name: &str) -> Result<Vec<u8>, FwCfgError> {
+    let file = find(name).ok_or(FwCfgError::NotFound)?;
+    let mut data = vec![0; file.size as usize];
+    read_at(&file, 0, &mut data)?;
+    Ok(data)
+}
+
+/// Reads as much of the file called `name` as fits into `buf` through the data port, returning
+/// how many bytes were read. Needs neither the heap nor [`init`], for code that runs before
+/// memory is set up, such as the test runner.
+pub fn read_early(name: &str, buf: &mut [u8]) -> Option<usize> {
+    // Keeps the ports to itself
+    let _guard = FW_CFG.lock();
+    let mut signature = [0u8; 4];
+    select(SELECT_SIGNATURE);
+    read_port(&mut signature);
+    if &signature != SIGNATURE {
+        return None;
+    }
+    let mut count = [0u8; 4];
+    select(SELECT_FILE_DIR);
+    read_port(&mut count);
+    // The data port moves through the directory one entry after another
+    let mut entry = [0u8; DIR_ENTRY_LEN];
+    for _ in 0..u32::from_be_bytes(count) {
+        read_port(&mut entry);
+        let entry_name = &entry[8..8 + NAME_LEN];
+        let end = entry_name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        if &entry_name[..end] != name.as_bytes() {
+            continue;
+        }
+        let size = u32::from_be_bytes(entry[0..4].try_into().unwrap()) as usize;
+        let len = buf.len().min(size);
+        select(u16::from_be_bytes(entry[4..6].try_into().unwrap()));
+        read_port(&mut buf[..len]);
+        return Some(len);
+    }
+    None
+}
+
+/// The kernel command line passed in [`CMDLINE_FILE`], without trailing NULs or newlines.
+pub fn cmdline() -> Option<String> {
+    let data = read_file(CMDLINE_FILE).ok()?;
+    let text = String::from_utf8_lossy(&data);
+    Some(String::from(text.trim_end_matches(['\0', '\n'])))
+}
+
+impl FwCfg {
+    fn read_directory(&mut self) -> Result<Vec<FwCfgFile>, FwCfgError> {
+        let mut count = [0u8; 4];
+        self.read(SELECT_FILE_DIR, 0, &mut count)?;
+        let count = u32::from_be_bytes(count) as usize;
+        let mut entries = vec![0u8; count * DIR_ENTRY_LEN];
+        self.read(SELECT_FILE_DIR, 4, &mut entries)?;
+        Ok(entries
+            .chunks_exact(DIR_ENTRY_LEN)
+            .map(|entry| {
+                let name = &entry[8..8 + NAME_LEN];
+                let end = name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+                FwCfgFile {
+                    name: String::from_utf8_lossy(&name[..end]).into_owned(),
+                    size: u32::from_be_bytes(entry[0..4].try_into().unwrap()),
+                    select: u16::from_be_bytes(entry[4..6].try_into().unwrap()),
+                }
+            })
+            .collect())
+    }
+
+    /// Reads `buf.len()` bytes of item `selector` starting `offset` bytes in.
+    fn read(&mut self, selector: u16, offset: u32, buf: &mut [u8]) -> Result<(), FwCfgError> {
+        let Some(page) = self.dma_page else {
+            select(selector);
+            let mut data: Port<u8> = Port::new(PORT_DATA);
+            for _ in 0..offset {
+                unsafe { data.read() };
+            }
+            read_port(buf);
+            return Ok(());
+        };
+        let bounce = page_ptr::<u8>(page);
+        let control = (selector as u32) << 16 | DMA_SELECT;
+        dma(page, control | DMA_SKIP, offset)?;
+        for chunk in buf.chunks_mut(BOUNCE_LEN) {
+            dma(page, DMA_READ, chunk.len() as u32)?;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    bounce.add(BOUNCE_OFFSET),
+                    chunk.as_mut_ptr(),
+                    chunk.len(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The DMA page at `page`, through the physical memory mapping.
+fn page_ptr<T>(page: u64) -> *mut T {
+    phys_to_virt(PhysAddr::new(page))
+        .expect("fw_cfg DMA page outside the physical memory mapping")
+        .as_mut_ptr()
+}
+
+/// Runs one DMA command with the descriptor at the start of `page`, pointing it at the rest
+/// of the page, and waits for the device to finish.
+fn dma(page: u64, control: u32, length: u32) -> Result<(), FwCfgError> {
+    let access = page_ptr::<DmaAccess>(page);
+    unsafe {
+        ptr::write_volatile(
+            access,
+            DmaAccess {
+                control: control.to_be(),
+                length: length.to_be(),
+                address: (page + BOUNCE_OFFSET as u64).to_be(),
+            },
+        );
+        Port::<u32>::new(PORT_DMA_HIGH).write(((page >> 32) as u32).to_be());
+        Port::<u32>::new(PORT_DMA_LOW).write((page as u32).to_be());
+    }
+    loop {
+        let control = u32::from_be(unsafe { ptr::read_volatile(&raw const (*access).control) });
+        if control & DMA_ERROR != 0 {
+            return Err(FwCfgError::Dma);
+        }
+        if control == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn select(selector: u16) {
+    unsafe { Port::<u16>::new(PORT_SELECTOR).write(selector) };
+}
+
+fn read_port(buf: &mut [u8]) {
+    let mut data: Port<u8> = Port::new(PORT_DATA);
+    for byte in buf {
+        *byte = unsafe { data.read() };
+    }
+}
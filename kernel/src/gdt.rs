@@ -1,60 +1,226 @@
-use lazy_static::lazy_static;
+//! Per-CPU descriptor tables.
+//!
+//! Every CPU gets a GDT and TSS of its own: the TSS holds the stacks the CPU switches to on
+//! interrupts, and loading a TSS marks its descriptor busy, so two CPUs can't share one. The
+//! boot CPU loads its tables before memory management is up and uses static stacks; the APs
+//! allocate theirs from the page allocator.
+use alloc::boxed::Box;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError};
 use x86_64::structures::tss::TaskStateSegment;
 
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::cpu::{MAX_CPUS, PerCpu};
+use crate::memory::PAGE_SIZE;
+use crate::stack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const STACK_SIZE: usize = 4096 * 5;
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE.try_into().unwrap();
-            stack_end
-        };
-        tss
-    };
+/// RFLAGS for freshly entered user code: interrupts enabled, plus the always-set reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    // Written after it has been loaded, whenever the kernel stack used on entry from ring 3
+    // changes
+    tss: TaskStateSegment,
+    selectors: Selectors,
+}
+
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+static mut BOOT_TABLES: CpuTables = CpuTables::new();
+static mut BOOT_DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+static mut BOOT_KERNEL_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+/// The tables each CPU has loaded.
+static TABLES: PerCpu<AtomicPtr<CpuTables>> =
+    PerCpu::new([const { AtomicPtr::new(null_mut()) }; MAX_CPUS]);
+
+impl CpuTables {
+    const fn new() -> Self {
+        CpuTables {
+            gdt: GlobalDescriptorTable::new(),
+            tss: TaskStateSegment::new(),
+            selectors: Selectors {
+                code_selector: SegmentSelector(0),
+                data_selector: SegmentSelector(0),
+                user_code_selector: SegmentSelector(0),
+                user_data_selector: SegmentSelector(0),
+                tss_selector: SegmentSelector(0),
+            },
+        }
+    }
+
+    /// Points the TSS at the given stacks and builds a GDT around it. The tables must not move
+    /// afterwards, since the GDT refers to the TSS by address.
+    fn build(&mut self, double_fault_stack: VirtAddr, kernel_stack: VirtAddr) {
+        self.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        self.tss.privilege_stack_table[0] = kernel_stack;
+
+        // User data comes before user code: SYSRET derives both selectors from one base, in
+        // that order.
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-            },
-        )
-    };
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
+        // The tables are static or leaked, so the TSS outlives the descriptor
+        let tss_selector =
+            gdt.append(unsafe { Descriptor::tss_segment_unchecked(&raw const self.tss) });
+        self.gdt = gdt;
+        self.selectors = Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        };
+    }
+}
+
+/// Loads `tables` on the executing CPU and records them as its own.
+///
+/// # Safety
+/// `tables` must have been built and stay valid and in place forever.
+unsafe fn load(tables: *mut CpuTables) {
+    use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    let tables: &'static CpuTables = unsafe { &*tables };
+    let selectors = &tables.selectors;
+    tables.gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code_selector);
+        DS::set_reg(selectors.data_selector);
+        ES::set_reg(selectors.data_selector);
+        FS::set_reg(selectors.data_selector);
+        GS::set_reg(selectors.data_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
+    }
+    TABLES.get().store(
+        tables as *const CpuTables as *mut CpuTables,
+        Ordering::Release,
+    );
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+fn stack_top(stack: *const [u8; STACK_SIZE]) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + STACK_SIZE as u64
 }
 
+/// Loads the boot CPU's tables. Runs before memory management is initialized, so the stacks
+/// are static.
 pub fn init() {
-    use x86_64::instructions::segmentation::{CS, DS, ES, FS, GS, SS, Segment};
-    use x86_64::instructions::tables::load_tss;
+    // Rebuilding loaded tables would mark the loaded TSS descriptor available again
+    if !TABLES.get().load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let tables = &raw mut BOOT_TABLES;
+    let double_fault_stack = stack_top(&raw const BOOT_DOUBLE_FAULT_STACK);
+    let kernel_stack = stack_top(&raw const BOOT_KERNEL_STACK);
+    track_stacks(double_fault_stack, kernel_stack);
+    unsafe {
+        (*tables).build(double_fault_stack, kernel_stack);
+        load(tables);
+    }
+}
+
+/// Loads a fresh set of tables on an application processor, with stacks from the page
+/// allocator.
+pub fn init_ap() -> Result<(), MapToError<Size4KiB>> {
+    let double_fault_stack = alloc_stack()?;
+    let kernel_stack = alloc_stack()?;
+    track_stacks(double_fault_stack, kernel_stack);
+    let tables = Box::leak(Box::new(CpuTables::new()));
+    tables.build(double_fault_stack, kernel_stack);
+    unsafe { load(tables) };
+    Ok(())
+}
+
+/// Paints the executing CPU's new double fault and entry stacks, given their tops, and
+/// registers them for [`crate::stack`]'s usage reports.
+fn track_stacks(double_fault_stack: VirtAddr, kernel_stack: VirtAddr) {
+    let cpu = crate::cpu::current_index();
+    for (name, top) in [
+        ("double fault", double_fault_stack),
+        ("entry", kernel_stack),
+    ] {
+        let range = top.as_u64() - STACK_SIZE as u64..top.as_u64();
+        unsafe { stack::paint(range.clone()) };
+        stack::register(name, cpu, range);
+    }
+}
+
+/// Allocates a kernel stack and returns its top.
+fn alloc_stack() -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let mut guard = PAGE_ALLOCATOR.lock();
+    let allocator = guard.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = allocator.alloc(STACK_SIZE / PAGE_SIZE as usize, flags)?;
+    Ok(VirtAddr::new((start + STACK_SIZE) as u64))
+}
+
+fn current() -> *mut CpuTables {
+    let tables = TABLES.get().load(Ordering::Acquire);
+    assert!(!tables.is_null(), "GDT not loaded on this CPU");
+    tables
+}
+
+/// The segment selectors, which are the same on every CPU.
+pub fn selectors() -> &'static Selectors {
+    unsafe { &(*current()).selectors }
+}
+
+/// Sets the stack the executing CPU switches to when an interrupt or exception arrives in
+/// ring 3 (RSP0). Called on every switch to a different user thread.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*current()).tss.privilege_stack_table[0] = top };
+    // SYSCALL doesn't switch stacks in hardware, so the entry stub keeps its own copy
+    crate::syscall::set_kernel_stack(top);
+}
+
+pub fn kernel_stack() -> VirtAddr {
+    unsafe { (*current()).tss.privilege_stack_table[0] }
+}
 
-    GDT.0.load();
+/// Drops to ring 3 at `entry` with the stack pointer set to `stack`, by building an interrupt
+/// return frame and executing `iretq`.
+///
+/// # Safety
+/// `entry` and `stack` must be mapped user-accessible in the active address space, and the TSS
+/// must hold a valid kernel stack for the next entry back into ring 0.
+pub unsafe fn enter_usermode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    let code = selectors().user_code_selector.0 as u64;
+    let data = selectors().user_data_selector.0 as u64;
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        FS::set_reg(GDT.1.data_selector);
-        GS::set_reg(GDT.1.data_selector);
-        SS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        core::arch::asm!(
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",
+            "push {stack}",
+            "push {rflags}",
+            "push {code}",
+            "push {entry}",
+            "iretq",
+            data = in(reg) data,
+            stack = in(reg) stack.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            code = in(reg) code,
+            entry = in(reg) entry.as_u64(),
+            options(noreturn)
+        );
     }
 }
@@ -1,29 +1,29 @@
-use crate::kernel_acpi::KernelAcpiHandler;
+use crate::error::KernelError;
+use crate::kernel_acpi::{self, AcpiRegistry, KernelAcpiHandler};
 use crate::println;
-use acpi::{AcpiTables, platform::PlatformInfo};
+use acpi::AcpiTables;
 use bootloader_api::BootInfo;
 use bootloader_api::info::Optional;
 
+/// Parses the ACPI tables and fills in [`kernel_acpi::registry`].
+///
+/// The tables are returned for the few stages that still read them directly; they must be
+/// dropped before ACPI memory is reclaimed, while the registry stays valid.
 pub fn init_acpi(
     boot_info: &BootInfo,
-) -> (
-    AcpiTables<KernelAcpiHandler>,
-    acpi::PlatformInfo<'_, alloc::alloc::Global>,
-) {
+) -> Result<(AcpiTables<KernelAcpiHandler>, &'static AcpiRegistry), KernelError> {
     let rsdp_addr = match boot_info.rsdp_addr {
         Optional::Some(a) => a,
-        Optional::None => panic!("RSDP address not provided by bootloader"),
+        Optional::None => return Err(KernelError::RsdpMissing),
     };
     println!("RSDP located at {:#x}", rsdp_addr);
 
     let acpi_handler = KernelAcpiHandler {};
     println!("ACPI handler created.");
 
-    let tables = unsafe {
-        AcpiTables::from_rsdp(acpi_handler, rsdp_addr.try_into().unwrap())
-            .expect("Failed to parse ACPI tables")
-    };
-    let platform_info = PlatformInfo::new(&tables).unwrap();
+    let tables = unsafe { AcpiTables::from_rsdp(acpi_handler, rsdp_addr as usize) }
+        .map_err(KernelError::AcpiTables)?;
+    let registry = kernel_acpi::init_registry(&tables).map_err(KernelError::PlatformInfo)?;
 
-    (tables, platform_info)
+    Ok((tables, registry))
 }
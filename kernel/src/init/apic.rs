@@ -1,25 +1,27 @@
 use acpi::PlatformInfo;
-use acpi::platform::interrupt::{InterruptModel, Polarity, TriggerMode};
+use acpi::platform::interrupt::InterruptModel;
 
 use crate::apic_ptr::{APIC_BASE, u32_to_apic_ptr};
+use crate::error::KernelError;
 use crate::interrupts::{
-    TIMER_VEC, disable_pic, enable_local_apic, init_apic_timer, map_apic_registers,
-    set_ioapic_redirect,
+    APIC_TIMER_HZ, TIMER_VEC, calibrate_apic_timer, disable_pic, enable_local_apic,
+    init_apic_timer, map_apic_registers,
 };
-use crate::println;
+use crate::{info, println, ps2, warn};
 
-pub fn init_apic(platform_info: &PlatformInfo<'_, alloc::alloc::Global>) {
+/// Sets up the APICs described by `platform_info`. Fails on other interrupt models, in which
+/// case the caller falls back to the PICs with [`crate::interrupts::init_pic_mode`].
+pub fn init_apic(
+    platform_info: &PlatformInfo<'_, alloc::alloc::Global>,
+) -> Result<(), KernelError> {
     match &platform_info.interrupt_model {
         InterruptModel::Apic(apic_info) => {
             // 1) Map local APIC
-            let mapped_ptr = map_apic_registers(apic_info.local_apic_address as u64);
+            let mapped_ptr = map_apic_registers(crate::platform::get().local_apic.as_u64());
             unsafe { APIC_BASE = Some(u32_to_apic_ptr(mapped_ptr)) };
             let local_apic_base = unsafe { &APIC_BASE.unwrap() };
 
-            println!(
-                "[INFO] APIC registers mapped to {:#?}",
-                local_apic_base.as_ptr()
-            );
+            info!("APIC registers mapped to {:#?}", local_apic_base.as_ptr());
             if apic_info.also_has_legacy_pics {
                 disable_pic();
                 println!("PIC Disabled.");
@@ -27,32 +29,56 @@ pub fn init_apic(platform_info: &PlatformInfo<'_, alloc::alloc::Global>) {
 
             // 2) Enable local APIC and set up timer
             let apic_mmio = local_apic_base.as_ptr();
-            println!("[INFO] APIC MMIO at {:?}", apic_mmio);
+            info!("APIC MMIO at {:?}", apic_mmio);
             unsafe {
                 enable_local_apic(apic_mmio);
                 init_apic_timer(apic_mmio, TIMER_VEC);
             }
 
-            // 3) Map I/O APIC(s) and set up keyboard redirect
-            for io_apic in apic_info.io_apics.iter() {
+            // 3) Route the keyboard through whichever I/O APIC handles its GSI
+            for io_apic in crate::platform::get().io_apics.iter() {
                 println!(
                     "  IO APIC id={}, address={:#x}, GSI base={}",
-                    io_apic.id, io_apic.address, io_apic.global_system_interrupt_base
+                    io_apic.id,
+                    io_apic.address.as_u64(),
+                    io_apic.gsi_base
                 );
-                unsafe {
-                    // GSI=1 => keyboard IRQ on IOAPIC with base=0
-                    set_ioapic_redirect(
-                        1,
-                        0,
-                        0x2F, // KEYBOARD_VEC
-                        TriggerMode::Edge,
-                        Polarity::ActiveHigh,
-                    );
-                }
+            }
+            match ps2::init_irq() {
+                Ok(irq) => info!("Keyboard on vector {:#x}", irq.vector()),
+                Err(e) => warn!("Keyboard interrupt not routed: {:?}", e),
             }
 
-            // 4) Handle overrides, NMIs, etc.
+            // 4) Handle NMIs, etc.
+            Ok(())
         }
-        _ => panic!("Non-APIC model!"),
+        _ => Err(KernelError::NotApic),
+    }
+}
+
+/// Calibrates the BSP's local APIC timer against the HPET, which isn't set up yet when
+/// [`init_apic`] runs. The APs start their timers with the result.
+pub fn calibrate_timer() {
+    let Some(apic) = (unsafe { APIC_BASE }) else {
+        return;
+    };
+    match unsafe { calibrate_apic_timer(apic.as_ptr(), TIMER_VEC) } {
+        Some(count) => info!(
+            "APIC timer: initial count {} for {} Hz",
+            count, APIC_TIMER_HZ
+        ),
+        None => warn!("APIC timer not calibrated: no HPET to measure it against"),
+    }
+}
+
+/// Enables the local APIC of the AP this runs on and starts its timer, so it takes its own
+/// ticks. Does nothing if the BSP is using the PICs.
+pub fn init_ap() {
+    let Some(apic) = (unsafe { APIC_BASE }) else {
+        return;
+    };
+    unsafe {
+        enable_local_apic(apic.as_ptr());
+        init_apic_timer(apic.as_ptr(), TIMER_VEC);
     }
 }
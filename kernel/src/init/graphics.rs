@@ -1,13 +1,24 @@
 use bootloader_api::BootInfo;
 use bootloader_api::info::Optional;
 
-pub fn init_framebuffer(boot_info: &mut BootInfo) {
-    if let Optional::Some(ref mut fb) = boot_info.framebuffer {
-        let info = fb.info();
-        // Convert the mutable slice to have a 'static lifetime.
-        let buffer: &'static mut [u8] = unsafe { core::mem::transmute(fb.buffer_mut()) };
-        crate::framebuffer::init_framebuffer_writer(buffer, info);
-    } else {
-        panic!("No framebuffer available in BootInfo");
+use crate::config::{self, ConsoleBackend};
+use crate::error::KernelError;
+use crate::framebuffer::FrameBufferWriter;
+
+pub fn init_framebuffer(boot_info: &mut BootInfo) -> Result<(), KernelError> {
+    if config::CONSOLE == ConsoleBackend::Serial {
+        return Err(KernelError::Disabled {
+            subsystem: "framebuffer",
+            flag: "CONFIG_CONSOLE=serial",
+        });
     }
+    let Optional::Some(ref mut fb) = boot_info.framebuffer else {
+        return Err(KernelError::NoFramebuffer);
+    };
+    let info = fb.info();
+    // Convert the mutable slice to have a 'static lifetime.
+    let buffer: &'static mut [u8] = unsafe { core::mem::transmute(fb.buffer_mut()) };
+    crate::console::attach_framebuffer(FrameBufferWriter::new(buffer, info));
+    crate::console::early::handoff();
+    Ok(())
 }
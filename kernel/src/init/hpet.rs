@@ -1,38 +1,147 @@
 use acpi::HpetInfo;
+use spin::Once;
 
-use crate::{init::memory_init::get_offset_u64, println};
+use x86_64::PhysAddr;
 
-pub static mut HPET_BASE: *mut u64 = core::ptr::null_mut();
+use crate::{error::KernelError, memory, println, timer::Clock};
 
 //HPET registers, in bytes
 const HPET_CAPS_OFFSET: usize = 0x0;
 const HPET_CONFIG_OFFSET: usize = 0x10;
 const HPET_COUNTER_OFFSET: usize = 0xF0;
+const HPET_TIMER0_OFFSET: usize = 0x100;
+/// Each comparator has a configuration, a value and an FSB route register.
+const HPET_TIMER_STRIDE: usize = 0x20;
+/// The register block, with room for all 32 comparators.
+const HPET_MMIO_SIZE: u64 = 0x400;
 
-pub fn init_hpet(hpet_info: &HpetInfo) {
-    let virt_addr = hpet_info.base_address + get_offset_u64() as usize;
-    unsafe {
-        HPET_BASE = virt_addr as *mut u64;
-        let caps = core::ptr::read_volatile(HPET_BASE.add(HPET_CAPS_OFFSET / 8));
-        println!("HPET capabilities: {:#x}", caps);
-        println!("HPET clock tick unit: {} fs", hpet_info.clock_tick_unit);
+const CONFIG_ENABLE: u64 = 1 << 0;
 
-        // Enable the HPET by writing to the config register
-        let config_ptr = HPET_BASE.add(HPET_CONFIG_OFFSET / 8);
-        core::ptr::write_volatile(config_ptr, 1); // set the enable bit
-        let config = core::ptr::read_volatile(config_ptr);
-        println!("HPET config register: {:#x}", config);
+static HPET: Once<Hpet> = Once::new();
 
-        // Optionally, check the main counter once
-        let main_counter = core::ptr::read_volatile(HPET_BASE.add(HPET_COUNTER_OFFSET / 8));
-        println!("Initial HPET main counter: {}", main_counter);
+/// The memory-mapped HPET registers.
+pub struct Hpet {
+    base: *mut u64,
+    period_fs: u64,
+}
+
+// The registers are MMIO, and every access is a single volatile read or write
+unsafe impl Send for Hpet {}
+unsafe impl Sync for Hpet {}
+
+/// One of the HPET's comparators (timers).
+pub struct Comparator<'a> {
+    hpet: &'a Hpet,
+    offset: usize,
+}
+
+impl Hpet {
+    /// Wraps the registers mapped at `base`.
+    ///
+    /// ## Safety
+    ///
+    /// `base` must point at the HPET's register block, mapped for as long as the `Hpet` lives.
+    pub unsafe fn new(base: *mut u64) -> Result<Hpet, KernelError> {
+        let caps = unsafe { core::ptr::read_volatile(base.add(HPET_CAPS_OFFSET / 8)) };
+        // Bits 32-63 are the counter period, which all timekeeping divides by
+        let period_fs = caps >> 32;
+        if period_fs == 0 {
+            return Err(KernelError::HpetPeriodZero);
+        }
+        Ok(Hpet { base, period_fs })
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { core::ptr::read_volatile(self.base.add(offset / 8)) }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { core::ptr::write_volatile(self.base.add(offset / 8), value) }
+    }
+
+    pub fn capabilities(&self) -> u64 {
+        self.read(HPET_CAPS_OFFSET)
+    }
+
+    /// The main counter.
+    pub fn counter(&self) -> u64 {
+        self.read(HPET_COUNTER_OFFSET)
+    }
+
+    /// Femtoseconds per counter tick.
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    /// Starts the main counter.
+    pub fn enable(&self) {
+        let config = self.read(HPET_CONFIG_OFFSET);
+        self.write(HPET_CONFIG_OFFSET, config | CONFIG_ENABLE);
+    }
+
+    pub fn config(&self) -> u64 {
+        self.read(HPET_CONFIG_OFFSET)
+    }
+
+    /// How many comparators the HPET implements.
+    pub fn comparator_count(&self) -> usize {
+        ((self.capabilities() >> 8) & 0x1F) as usize + 1
     }
+
+    /// Comparator `n`, if it exists.
+    pub fn comparator(&self, n: usize) -> Option<Comparator<'_>> {
+        (n < self.comparator_count()).then_some(Comparator {
+            hpet: self,
+            offset: HPET_TIMER0_OFFSET + n * HPET_TIMER_STRIDE,
+        })
+    }
+}
+
+impl Comparator<'_> {
+    pub fn config(&self) -> u64 {
+        self.hpet.read(self.offset)
+    }
+
+    pub fn set_config(&self, config: u64) {
+        self.hpet.write(self.offset, config);
+    }
+
+    /// The counter value the comparator fires at.
+    pub fn value(&self) -> u64 {
+        self.hpet.read(self.offset + 8)
+    }
+
+    pub fn set_value(&self, value: u64) {
+        self.hpet.write(self.offset + 8, value);
+    }
+}
+
+impl Clock for Hpet {
+    fn counter(&self) -> u64 {
+        Hpet::counter(self)
+    }
+
+    fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+}
+
+/// The HPET, once [`init_hpet`] has set it up.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
 }
 
-/// Reads the clock tick unit from the HPET capabilities register as a fallback.
-pub unsafe fn get_clock_tick_unit_fallback(hpet_base: *const u64) -> u32 {
-    // Read the capabilities register (offset 0)
-    let caps = unsafe { core::ptr::read_volatile(hpet_base) };
-    // Bits 32-63 contain the tick period in femtoseconds.
-    (caps >> 32) as u32
+pub fn init_hpet(hpet_info: &HpetInfo) -> Result<(), KernelError> {
+    let virt_addr = memory::map_mmio(PhysAddr::new(hpet_info.base_address as u64), HPET_MMIO_SIZE)
+        .map_err(KernelError::HpetMap)?;
+    let hpet = unsafe { Hpet::new(virt_addr.as_mut_ptr::<u64>())? };
+    println!("HPET capabilities: {:#x}", hpet.capabilities());
+    println!("HPET clock tick unit: {} fs", hpet.period_fs());
+
+    hpet.enable();
+    println!("HPET config register: {:#x}", hpet.config());
+    println!("Initial HPET main counter: {}", hpet.counter());
+
+    HPET.call_once(|| hpet);
+    Ok(())
 }
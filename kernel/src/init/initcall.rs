@@ -0,0 +1,116 @@
+//! Init functions that register themselves at link time.
+//!
+//! [`kernel_init!`](crate::kernel_init) places an [`Initcall`] in the `kernel_initcalls` link
+//! section, and the linker gathers every one in the kernel into a single array between
+//! `__start_kernel_initcalls` and `__stop_kernel_initcalls`. `kernel_main` only says when each
+//! [`Stage`] runs, through [`Boot::initcalls`](super::Boot::initcalls), so a new driver
+//! registers itself next to its own code instead of being called from `kernel_main`.
+//!
+//! Within a stage, an initcall runs after the ones it names in `after`, and otherwise in order
+//! of name so that boots don't depend on link order.
+use alloc::vec::Vec;
+
+use crate::error::KernelError;
+use crate::warn;
+
+/// When in boot an initcall runs. Stages run in the order they are declared here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// The heap and page allocator are up; interrupts are still off.
+    Early,
+    /// ACPI, the interrupt controller and timers are set up and interrupts are on.
+    Device,
+    /// Everything else is done and the executor is about to start.
+    Late,
+}
+
+pub struct Initcall {
+    pub name: &'static str,
+    pub stage: Stage,
+    /// Initcalls in the same stage that have to run first.
+    pub after: &'static [&'static str],
+    pub run: fn() -> Result<(), KernelError>,
+}
+
+/// Registers an init function to run during boot.
+///
+/// ```ignore
+/// kernel_init!(Early, "fw_cfg", init_stage);
+/// kernel_init!(Early, "fs", after = ["fw_cfg"], init_stage);
+/// ```
+#[macro_export]
+macro_rules! kernel_init {
+    ($stage:ident, $name:literal, $run:path) => {
+        $crate::kernel_init!($stage, $name, after = [], $run);
+    };
+    ($stage:ident, $name:literal, after = [$($after:literal),* $(,)?], $run:path) => {
+        const _: () = {
+            #[used]
+            #[unsafe(link_section = "kernel_initcalls")]
+            static INITCALL: $crate::init::initcall::Initcall = $crate::init::initcall::Initcall {
+                name: $name,
+                stage: $crate::init::initcall::Stage::$stage,
+                after: &[$($after),*],
+                run: $run,
+            };
+        };
+    };
+}
+
+// Defined by the linker; only their addresses mean anything
+unsafe extern "C" {
+    static __start_kernel_initcalls: u8;
+    static __stop_kernel_initcalls: u8;
+}
+
+/// Every registered initcall, in link order.
+pub fn all() -> &'static [Initcall] {
+    unsafe {
+        let start = (&raw const __start_kernel_initcalls).cast::<Initcall>();
+        let stop = (&raw const __stop_kernel_initcalls).cast::<Initcall>();
+        core::slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// The initcalls of `stage` from `initcalls`, each after the ones it depends on.
+pub fn ordered(initcalls: &'static [Initcall], stage: Stage) -> Vec<&'static Initcall> {
+    let mut pending: Vec<&Initcall> = initcalls
+        .iter()
+        .filter(|initcall| initcall.stage == stage)
+        .collect();
+    pending.sort_by_key(|initcall| initcall.name);
+
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        // Dependencies that aren't in this stage have run already, or never will
+        let ready = pending.iter().position(|initcall| {
+            initcall
+                .after
+                .iter()
+                .all(|name| !pending.iter().any(|other| other.name == *name))
+        });
+        match ready {
+            Some(index) => ordered.push(pending.remove(index)),
+            None => {
+                warn!(
+                    "init: initcalls {:?} depend on each other, running them in name order",
+                    pending
+                        .iter()
+                        .map(|initcall| initcall.name)
+                        .collect::<Vec<_>>()
+                );
+                ordered.append(&mut pending);
+            }
+        }
+    }
+    ordered
+}
+
+#[test_case]
+fn test_initcalls_are_registered() {
+    assert!(
+        all()
+            .iter()
+            .any(|initcall| initcall.name == "fs" && initcall.stage == Stage::Early)
+    );
+}
@@ -3,33 +3,74 @@ use crate::{
         self,
         page_allocator::{PAGE_ALLOCATOR, init_page_allocator},
     },
+    error::KernelError,
+    info,
     interrupts::PHYSICAL_MEMORY_OFFSET,
-    memory::{self, BitmapFrameAllocator},
+    memory::{self, BitmapFrameAllocator, RegionType},
+    serial_println,
 };
 use bootloader_api::BootInfo;
 use bootloader_api::info::Optional;
+use core::sync::atomic::{AtomicBool, Ordering};
 use x86_64::VirtAddr;
 
-pub fn init_memory(boot_info: &BootInfo) {
+/// Set once the ACPI tables' memory has been handed to the frame allocator.
+static ACPI_RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+pub fn init_memory(boot_info: &BootInfo) -> Result<(), KernelError> {
     // 1) Get the physical memory offset
     let offset = match boot_info.physical_memory_offset {
         Optional::Some(o) => init_offset(VirtAddr::new(o)),
-        Optional::None => panic!("Physical memory offset not provided by bootloader"),
+        Optional::None => return Err(KernelError::NoPhysicalMemoryOffset),
     };
+    memory::phys::init(&boot_info.memory_regions);
 
     // 2) Create a local mapper + frame-allocator
     let mapper = unsafe { memory::init(VirtAddr::new(offset)) };
-    let allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, offset) };
+    let allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions) };
+    memory::report::init(&boot_info.memory_regions);
 
     // 3) Install them as the global mapper & allocator
     init_page_allocator(mapper, allocator);
 
     // 4) Init your heap, etc.
-    {
-        let mut guard = PAGE_ALLOCATOR.lock();
-        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
-        allocator::init_heap_experimental(page_alloc).expect("heap initialization failed");
+    let mut guard = PAGE_ALLOCATOR.lock();
+    let page_alloc = guard
+        .as_mut()
+        .ok_or(KernelError::PageAllocatorUninitialized)?;
+    allocator::init_heap_experimental(page_alloc).map_err(KernelError::HeapInit)?;
+    drop(guard);
+
+    // 5) Show what memory there is and what the kernel took of it
+    if let Some(report) = memory::report::Report::current() {
+        serial_println!("{}", report);
     }
+    Ok(())
+}
+
+/// Hands the memory holding the ACPI tables to the frame allocator. Call this once the
+/// tables have been read; ACPI NVS memory is never touched.
+pub fn reclaim_acpi_memory(boot_info: &BootInfo) -> Result<usize, KernelError> {
+    let map = memory::PhysMemoryMap::new(&boot_info.memory_regions);
+    for region in map.regions_of(RegionType::AcpiNvs) {
+        info!(
+            "ACPI NVS at {:#x}..{:#x} left in place",
+            region.start, region.end
+        );
+    }
+    let guard = PAGE_ALLOCATOR.lock();
+    let page_alloc = guard
+        .as_ref()
+        .ok_or(KernelError::PageAllocatorUninitialized)?;
+    ACPI_RECLAIMED.store(true, Ordering::Relaxed);
+    Ok(page_alloc
+        .frame_allocator
+        .reclaim_acpi(&boot_info.memory_regions))
+}
+
+/// Whether [`reclaim_acpi_memory`] has run, so the ACPI tables may have been overwritten.
+pub fn acpi_reclaimed() -> bool {
+    ACPI_RECLAIMED.load(Ordering::Relaxed)
 }
 
 /// Initializes a write-once constant with the bootloader physical offset.
@@ -38,7 +79,8 @@ pub fn init_offset(offset: VirtAddr) -> u64 {
     offset.as_u64()
 }
 
-/// Returns the physical memory offset of the kernel.
+/// Returns the physical memory offset of the kernel. Turn physical addresses into pointers with
+/// [`memory::phys_to_virt`] rather than by adding this, as not all of physical memory is mapped.
 pub fn get_offset() -> VirtAddr {
     *PHYSICAL_MEMORY_OFFSET.wait()
 }
@@ -1,6 +1,81 @@
+//! Boot-time initialisation, run as a sequence of named stages.
+//!
+//! Each stage returns a [`KernelError`] instead of panicking. [`Boot`] logs how every stage
+//! went and keeps the failures, so the kernel can carry on without optional subsystems and
+//! say exactly what is missing, while failures it can't continue from still end in a panic
+//! that names the stage and the cause.
+//!
+//! Drivers that only need to be told when to start register themselves with
+//! [`kernel_init!`](crate::kernel_init) instead, and run with the rest of their [`Stage`].
+use alloc::vec::Vec;
+
+use crate::error::KernelError;
+use crate::{info, warn};
+
+pub use initcall::Stage;
+
 pub mod acpi;
 pub mod apic;
 pub mod graphics;
 pub mod hpet;
+pub mod initcall;
 pub mod memory_init;
 pub mod multicore;
+
+/// Records the outcome of each init stage.
+#[derive(Default)]
+pub struct Boot {
+    failures: Vec<(&'static str, KernelError)>,
+}
+
+impl Boot {
+    pub fn new() -> Self {
+        Boot::default()
+    }
+
+    /// Runs an optional stage, returning its result or recording why it failed.
+    pub fn stage<T>(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce() -> Result<T, KernelError>,
+    ) -> Option<T> {
+        match f() {
+            Ok(value) => {
+                info!("init: {} done", name);
+                Some(value)
+            }
+            Err(e) => {
+                warn!("init: {} skipped ({})", name, e);
+                self.failures.push((name, e));
+                None
+            }
+        }
+    }
+
+    /// Runs a stage the kernel can't do without, panicking with the cause if it fails.
+    pub fn require<T>(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce() -> Result<T, KernelError>,
+    ) -> T {
+        match f() {
+            Ok(value) => {
+                info!("init: {} done", name);
+                value
+            }
+            Err(e) => panic!("init: {} failed ({})", name, e),
+        }
+    }
+
+    /// Runs the registered initcalls of `stage`, each as an optional stage.
+    pub fn initcalls(&mut self, stage: Stage) {
+        for initcall in initcall::ordered(initcall::all(), stage) {
+            self.stage(initcall.name, initcall.run);
+        }
+    }
+
+    /// Stages that failed, with the reasons.
+    pub fn failures(&self) -> &[(&'static str, KernelError)] {
+        &self.failures
+    }
+}
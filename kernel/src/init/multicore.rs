@@ -1,133 +1,245 @@
 use acpi::platform::{ProcessorInfo, ProcessorState};
 use x86_64::{
-    PhysAddr, VirtAddr,
+    VirtAddr,
     structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
 };
 
+/// The MP specification's waits: after INIT before the first SIPI, and after each SIPI.
+const INIT_DELAY_US: u64 = 10_000;
+const SIPI_DELAY_US: u64 = 200;
+/// How long an AP may take to run the trampoline after its last SIPI, and then to load its
+/// stack.
+const AP_ALIVE_TIMEOUT_US: u64 = 100_000;
+const AP_STACK_TIMEOUT_US: u64 = 100_000;
+/// Further INIT-SIPI-SIPI sequences tried on an AP that didn't respond.
+const AP_START_RETRIES: usize = 2;
+/// How long the APs that started may take to report in from [`ap_startup`].
+const AP_READY_TIMEOUT_US: u64 = 1_000_000;
+
+/// APs that have finished [`ap_startup`]'s setup.
+static AP_READY: AtomicUsize = AtomicUsize::new(0);
+
+/// Why an AP didn't start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApStartError {
+    /// The local APIC still showed an IPI pending at the deadline.
+    IpiNotAccepted,
+    /// The AP never ran the trampoline.
+    NoResponse,
+    /// The AP ran the trampoline but never got as far as its stack.
+    StuckInTrampoline,
+}
+
+/// Starts the APs the firmware lists as waiting for a SIPI, one at a time, then waits until
+/// all that started have reported in.
 pub unsafe fn init_smp(
     lapic_base: *mut u32,
     processor_info: &ProcessorInfo<'_, alloc::alloc::Global>,
-) {
-    let trampoline_vector = 0x8; // since 0x8000/0x1000 = 8
+) -> Result<(), KernelError> {
+    let platform = crate::platform::get();
+    let trampoline_vector = platform.trampoline_vector();
 
     // Patch and load the trampoline into low memory.
     unsafe {
-        load_ap_trampoline();
+        load_ap_trampoline()?;
         patch_trampoline();
     }
 
-    // For each AP (skipping the BSP), send INIT/SIPI.
+    let mut expected = 0;
+    let mut started = 0;
+    // CPUs that have or may yet take a per-CPU slot, starting with the BSP
+    let mut cpus = 1;
     for ap in processor_info.application_processors.iter() {
-        if ap.state == ProcessorState::WaitingForSipi {
-            unsafe {
-                send_init_ipi(lapic_base, ap.local_apic_id);
-                delay_ms(HPET_BASE, 10);
-                send_startup_ipi(lapic_base, ap.local_apic_id, trampoline_vector);
-                delay_us(HPET_BASE, 200);
-                send_startup_ipi(lapic_base, ap.local_apic_id, trampoline_vector);
-                delay_us(HPET_BASE, 100);
+        if ap.state != ProcessorState::WaitingForSipi {
+            continue;
+        }
+        expected += 1;
+        if cpus == MAX_CPUS {
+            warn!(
+                "AP {}: only {} CPUs supported, not started",
+                ap.local_apic_id, MAX_CPUS
+            );
+            continue;
+        }
+        let Some(stack_top) = (unsafe { allocate_ap_stack() }) else {
+            warn!("AP {}: no stack left, not started", ap.local_apic_id);
+            continue;
+        };
+        match unsafe { start_ap(lapic_base, ap.local_apic_id, trampoline_vector, stack_top) } {
+            Ok(()) => {
+                started += 1;
+                cpus += 1;
+            }
+            Err(e) => {
+                warn!("AP {} did not start: {:?}", ap.local_apic_id, e);
+                // It got as far as its stack, so it might carry on
+                if e == ApStartError::StuckInTrampoline {
+                    cpus += 1;
+                }
             }
+        }
+    }
 
-            // Compute pointer to the trampoline's communication word.
-            let tramp_comm_ptr = (get_offset_u64() as usize
-                + crate::smp::trampoline::TRAMPOLINE_BASE
-                + crate::smp::trampoline::COMMWORD_OFFSET)
-                as *const u32;
-
-            // Poll for the AP to signal readiness.
-            if unsafe { wait_for_ap(HPET_BASE, tramp_comm_ptr, 100_000) } {
-                serial_println!("AP {} started.", ap.local_apic_id);
-            } else {
-                serial_println!("AP {} did not start in time.", ap.local_apic_id);
-                // Optionally, send another SIPI here.
+    let all_ready = Deadline::after_us(AP_READY_TIMEOUT_US)
+        .spin_until(|| AP_READY.load(Ordering::Acquire) >= started);
+    let ready = AP_READY.load(Ordering::Acquire);
+    if !all_ready {
+        warn!("{} of {} started APs reported in", ready, started);
+    }
+    info!("{} of {} APs up", ready, expected);
+    Ok(())
+}
+
+/// Starts the AP with local APIC ID `apic_id` on the stack at `stack_top` with the MP
+/// specification's INIT-SIPI-SIPI sequence, trying again if it doesn't respond. Returns once
+/// the AP is on its stack, so the trampoline can be patched for the next one.
+///
+/// # Safety
+/// `lapic_base` must point to the BSP's mapped local APIC registers, the trampoline must be
+/// loaded and patched, and `stack_top` must be the top of an AP stack nothing else uses.
+/// `apic_id` must be a CPU that is waiting for a SIPI, not one that is running.
+unsafe fn start_ap(
+    lapic_base: *mut u32,
+    apic_id: u32,
+    vector: u8,
+    stack_top: u64,
+) -> Result<(), ApStartError> {
+    for attempt in 0..=AP_START_RETRIES {
+        if attempt > 0 {
+            warn!("AP {} did not respond, retrying", apic_id);
+        }
+        unsafe { prepare_ap(stack_top) };
+        let alive = || commword() & COMM_ALIVE != 0;
+        let result = unsafe { send_init_ipi(lapic_base, apic_id) }.and_then(|()| {
+            delay_us(INIT_DELAY_US);
+            // The second SIPI is only for CPUs that missed the first
+            for _ in 0..2 {
+                unsafe { send_startup_ipi(lapic_base, apic_id, vector) }?;
+                delay_us(SIPI_DELAY_US);
+                if alive() {
+                    break;
+                }
             }
+            if !Deadline::after_us(AP_ALIVE_TIMEOUT_US).spin_until(alive) {
+                return Err(ApStartError::NoResponse);
+            }
+            let on_stack = || commword() & COMM_ON_STACK != 0;
+            if !Deadline::after_us(AP_STACK_TIMEOUT_US).spin_until(on_stack) {
+                return Err(ApStartError::StuckInTrampoline);
+            }
+            Ok(())
+        });
+        // An AP stuck past the trampoline can't be restarted safely with the same stack
+        if result != Err(ApStartError::NoResponse) {
+            return result;
         }
     }
+    Err(ApStartError::NoResponse)
+}
+
+/// Sends the IPI with ICR low dword `low` to the AP with `apic_id`.
+///
+/// # Safety
+/// As for [`interrupts::send_icr`].
+unsafe fn send_icr(lapic_base: *mut u32, apic_id: u32, low: u32) -> Result<(), ApStartError> {
+    unsafe { interrupts::send_icr(lapic_base, apic_id, low) }
+        .then_some(())
+        .ok_or(ApStartError::IpiNotAccepted)
 }
 
-/// Sends an INIT IPI to the target AP.
-pub unsafe fn send_init_ipi(lapic_base: *mut u32, apic_id: u32) {
+/// Sends an INIT IPI to the target AP, asserted and then deasserted as older CPUs need.
+///
+/// # Safety
+/// `lapic_base` must point to the BSP's mapped local APIC registers. INIT resets the target,
+/// so `apic_id` must not be a CPU that is running the kernel.
+unsafe fn send_init_ipi(lapic_base: *mut u32, apic_id: u32) -> Result<(), ApStartError> {
     unsafe {
-        // Clear APIC errors (@ offset 0x280)
-        core::ptr::write_volatile(lapic_base.add(0x280 / 4), 0);
-        // Set the target APIC ID in the ICR high register (offset 0x310)
-        let icr_high = lapic_base.add(0x310 / 4);
-        let id8 = (apic_id & 0xff) as u32;
-        let high = core::ptr::read_volatile(icr_high) & 0x00FF_FFFF;
-        core::ptr::write_volatile(icr_high, high | (id8 << 24));
-
-        // Send INIT IPI by writing to ICR low (offset 0x300)
-        let icr_low = lapic_base.add(0x300 / 4);
-        core::ptr::write_volatile(icr_low, 0x0000_4500);
-
-        // maybe wait until delivery status is cleared?
+        send_icr(
+            lapic_base,
+            apic_id,
+            APIC_ICR_INIT | APIC_ICR_ASSERT | APIC_ICR_LEVEL_TRIGGERED,
+        )?;
+        send_icr(
+            lapic_base,
+            apic_id,
+            APIC_ICR_INIT | APIC_ICR_LEVEL_TRIGGERED,
+        )
     }
 }
 
 /// Sends a Startup IPI (SIPI) to the target AP.
 /// `vector` is the wherever the asm "trampoline" physical page is (if trampoline is at 0x8000, then vector = 0x8).
-pub unsafe fn send_startup_ipi(lapic_base: *mut u32, apic_id: u32, vector: u8) {
+///
+/// # Safety
+/// `lapic_base` must point to the BSP's mapped local APIC registers, the target must have
+/// just been sent INIT, and the trampoline page `vector` names must hold the loaded trampoline.
+unsafe fn send_startup_ipi(
+    lapic_base: *mut u32,
+    apic_id: u32,
+    vector: u8,
+) -> Result<(), ApStartError> {
     unsafe {
-        // Clear APIC errors
-        core::ptr::write_volatile(lapic_base.add(0x280 / 4), 0);
-        // Set target APIC ID
-        let icr_high = lapic_base.add(0x310 / 4);
-        let id8 = (apic_id & 0xff) as u32;
-        let high = core::ptr::read_volatile(icr_high) & 0x00FF_FFFF;
-        core::ptr::write_volatile(icr_high, high | (id8 << 24));
-        // Send SIPI: vector (in lower 8 bits) ORed with 0x600
-        let icr_low = lapic_base.add(0x300 / 4);
-        core::ptr::write_volatile(icr_low, (vector as u32) | 0x0000_4600);
+        send_icr(
+            lapic_base,
+            apic_id,
+            APIC_ICR_STARTUP | APIC_ICR_ASSERT | vector as u32,
+        )
     }
 }
 
-pub unsafe fn wait_for_ap(hpet_base: *const u64, comm_ptr: *const u32, timeout_us: u64) -> bool {
-    let start = unsafe { get_current_time_us(hpet_base) };
-    loop {
-        if unsafe { core::ptr::read_volatile(comm_ptr) == 1 } {
-            return true;
-        }
-        if unsafe { get_current_time_us(hpet_base) } - start >= timeout_us {
-            return false;
-        }
-        core::hint::spin_loop();
-    }
-}
-
-use core::{arch::x86_64::_mm_pause, sync::atomic::AtomicUsize};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{
     allocator::page_allocator::PAGE_ALLOCATOR,
-    init::memory_init::get_offset_u64,
+    config,
+    cpu::MAX_CPUS,
+    error,
+    error::KernelError,
+    info,
+    interrupts::{
+        self, APIC_ICR_ASSERT, APIC_ICR_INIT, APIC_ICR_LEVEL_TRIGGERED, APIC_ICR_STARTUP,
+    },
     serial_println,
-    smp::trampoline::{TRAMPOLINE_BASE, load_ap_trampoline, patch_trampoline},
-    timer::{delay_ms, delay_us, get_current_time_us},
+    smp::trampoline::{
+        COMM_ALIVE, COMM_ON_STACK, allocate_ap_stack, commword, load_ap_trampoline,
+        patch_trampoline, prepare_ap,
+    },
+    timer::{Deadline, delay_us},
+    warn,
 };
 
-use super::hpet::HPET_BASE;
-
 use x86_64::structures::paging::mapper::{MapperFlush, UnmapError};
 
-pub unsafe fn remap_trampoline_uncacheable() {
-    let va = VirtAddr::new(TRAMPOLINE_BASE as u64);
+/// Maps the trampoline page identity-mapped and uncacheable, so the BSP's writes to it reach
+/// the APs that run it from real mode.
+///
+/// # Safety
+/// The trampoline page's virtual address equals its physical one, so nothing else may be
+/// mapped there, and no AP may be running the trampoline while it is remapped.
+pub unsafe fn remap_trampoline_uncacheable() -> Result<(), KernelError> {
+    let trampoline = crate::platform::get().trampoline;
+    if !crate::platform::get().trampoline_usable {
+        return Err(KernelError::NoTrampolinePage);
+    }
+    let va = VirtAddr::new(trampoline.as_u64());
     let page: Page<Size4KiB> = Page::containing_address(va);
     let mut lock = PAGE_ALLOCATOR.lock();
-    let allocator = lock.as_mut().expect("PageAlloc uninit");
+    let allocator = lock
+        .as_mut()
+        .ok_or(KernelError::PageAllocatorUninitialized)?;
 
     // attempt to unmap, but if it was already unmapped, fabricate your own frame+flush
-    let (frame, flush) = allocator
-        .mapper
-        .unmap(page)
-        .unwrap_or_else(|err| match err {
-            UnmapError::PageNotMapped => {
-                // page was already unmapped → use the physical frame we know
-                let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_BASE as u64));
-                // create a no-op “flush promise” for this page
-                let flush = MapperFlush::new(page);
-                (frame, flush)
-            }
-            other => panic!("failed to unmap trampoline page: {:?}", other),
-        });
+    let (frame, flush) = match allocator.mapper.unmap(page) {
+        Ok(unmapped) => unmapped,
+        Err(UnmapError::PageNotMapped) => {
+            // page was already unmapped → use the physical frame we know
+            let frame = PhysFrame::containing_address(trampoline);
+            // create a no-op “flush promise” for this page
+            let flush = MapperFlush::new(page);
+            (frame, flush)
+        }
+        Err(other) => return Err(KernelError::TrampolineUnmap(other)),
+    };
 
     flush.flush();
 
@@ -137,42 +249,63 @@ pub unsafe fn remap_trampoline_uncacheable() {
         allocator
             .mapper
             .map_to(page, frame, flags, &mut allocator.frame_allocator)
-            .expect("mapping trampoline failed")
+            .map_err(KernelError::TrampolineMap)?
             .flush()
     };
+    Ok(())
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn ap_startup(_apic_id: i32) -> ! {
+pub extern "C" fn ap_startup(apic_id: i32) -> ! {
     // This function is called on each Application Processor (AP).
     // Perform per-core initialization here.
-    // For now, we just loop
     serial_println!("hello");
 
-    //initalize GDT
-    crate::gdt::init();
+    // Descriptor tables and interrupt stacks of its own, then the shared IDT
+    if let Err(e) = crate::gdt::init_ap() {
+        error!("AP {}: failed to allocate stacks: {:?}", apic_id, e);
+        crate::halt();
+    }
+    track_ap_stack();
+    crate::interrupts::init_idt();
+    crate::syscall::init();
+    crate::cpu::pat::init();
+    crate::cpu::fpu::init();
+    // Its own timer, so it ticks, samples and preempts independently of the BSP
+    crate::init::apic::init_ap();
+    AP_READY.fetch_add(1, Ordering::Release);
+    x86_64::instructions::interrupts::enable();
     loop {
-        unsafe {
-            _mm_pause();
+        crate::cpu::idle::idle();
+    }
+}
+
+/// Registers the AP stack the executing CPU runs on, which `allocate_ap_stack` painted, for
+/// [`crate::stack`]'s usage reports.
+fn track_ap_stack() {
+    let rsp = crate::stack::current_rsp();
+    let stacks = (&raw const AP_STACKS).cast::<Stack>();
+    for index in 0..NUM_AP_STACKS {
+        let bottom = stacks.wrapping_add(index) as u64;
+        let range = bottom..bottom + config::AP_STACK_SIZE as u64;
+        if range.contains(&rsp) {
+            crate::stack::register("boot", crate::cpu::current_index(), range);
+            return;
         }
     }
 }
 
 /// Allocate a block of memory for AP stacks.
-/// Here we assume a maximum of 4 APs, each with a 32KB stack.
+/// One stack per AP, as many as the build configuration allows for.
 #[repr(align(16))]
-pub struct Stack([u8; 32768]);
+pub struct Stack([u8; config::AP_STACK_SIZE]);
 
 #[unsafe(no_mangle)]
-pub static mut AP_STACKS: [Stack; 4] = [
-    Stack([0; 32768]),
-    Stack([0; 32768]),
-    Stack([0; 32768]),
-    Stack([0; 32768]),
-];
+pub static mut AP_STACKS: [Stack; NUM_AP_STACKS] =
+    [const { Stack([0; config::AP_STACK_SIZE]) }; NUM_AP_STACKS];
 
 pub static AP_STACK_INDEX: AtomicUsize = AtomicUsize::new(0);
-pub const NUM_AP_STACKS: usize = 4;
+pub const NUM_AP_STACKS: usize = config::AP_STACKS;
 
 impl Stack {
     pub fn as_ptr(&self) -> *const u8 {
@@ -1,18 +1,30 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use core::{panic, usize};
 
 use crate::apic_ptr::APIC_BASE;
-use crate::init::memory_init::get_offset_u64;
-use crate::memory::PAGE_SIZE;
-use crate::{gdt, print, println, serial_println};
+use crate::cpu::{self, MAX_CPUS, PerCpu};
+use crate::log::Level;
+use crate::memory::{self, PAGE_SIZE};
+use crate::process::signal;
+use crate::timer::Deadline;
+use crate::trap::{TrapFrame, trap_stub};
+use crate::{debug, gdt, log_ratelimited, print, println, serial_print, serial_println, warn};
 use acpi::platform::interrupt::{Polarity, TriggerMode};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::{self, Once};
-use x86_64::VirtAddr;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::{PhysAddr, VirtAddr};
 
 pub const TIMER_VEC: u8 = 0x2E;
-pub const KEYBOARD_VEC: u8 = 0x2F;
+/// Rate every CPU's local APIC timer is programmed for once it has been calibrated.
+pub const APIC_TIMER_HZ: u32 = 100;
+/// Runs functions posted by other CPUs, see [`crate::smp::call`].
+pub const CALL_VEC: u8 = 0xFB;
+/// Parks and unparks APs, see [`crate::smp::park`].
+pub const PARK_VEC: u8 = 0xFC;
+pub const THERMAL_VEC: u8 = 0xFD;
+pub const APIC_ERROR_VEC: u8 = 0xFE;
 pub const SPURIOUS_VEC: u8 = 0xFF;
 pub static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
 
@@ -20,16 +32,48 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.device_not_available
+            .set_handler_fn(crate::cpu::fpu::device_not_available_handler);
+        // Fatal in the kernel, so the panic shows every register
         unsafe {
+            idt.divide_error
+                .set_handler_addr(VirtAddr::new(divide_error_entry as *const () as u64));
+            idt.invalid_opcode
+                .set_handler_addr(VirtAddr::new(invalid_opcode_entry as *const () as u64));
+            idt.invalid_tss
+                .set_handler_addr(VirtAddr::new(invalid_tss_entry as *const () as u64));
+            idt.segment_not_present
+                .set_handler_addr(VirtAddr::new(segment_not_present_entry as *const () as u64));
+            idt.stack_segment_fault
+                .set_handler_addr(VirtAddr::new(stack_segment_entry as *const () as u64));
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::new(general_protection_entry as *const () as u64));
             idt.double_fault
-                .set_handler_fn(double_fault_handler)
+                .set_handler_addr(VirtAddr::new(double_fault_entry as *const () as u64))
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[TIMER_VEC].set_handler_fn(apic_timer_interrupt_handler);
-        idt[KEYBOARD_VEC].set_handler_fn(apic_keyboard_interrupt_handler);
+        // The timer may preempt a user process, which needs the full register state
+        unsafe {
+            idt[TIMER_VEC].set_handler_addr(VirtAddr::new(timer_entry as *const () as u64));
+        }
+        crate::irq::install_stubs(&mut idt);
+        // The same handlers serve the legacy PIC vectors when there is no APIC
+        unsafe {
+            idt[InterruptIndex::Timer as u8]
+                .set_handler_addr(VirtAddr::new(timer_entry as *const () as u64));
+        }
+        idt[InterruptIndex::Keyboard as u8].set_handler_fn(pic_keyboard_interrupt_handler);
+        idt[CALL_VEC].set_handler_fn(crate::smp::call::call_interrupt_handler);
+        idt[PARK_VEC].set_handler_fn(crate::smp::park::park_interrupt_handler);
+        idt[THERMAL_VEC].set_handler_fn(thermal_interrupt_handler);
+        idt[APIC_ERROR_VEC].set_handler_fn(apic_error_interrupt_handler);
         idt[SPURIOUS_VEC].set_handler_fn(spurious_interrupt_handler);
 
-        idt.page_fault.set_handler_fn(apic_page_fault_handler);
+        // User faults end the faulting process, which means switching to another one
+        unsafe {
+            idt.page_fault
+                .set_handler_addr(VirtAddr::new(page_fault_entry as *const () as u64));
+        }
 
         idt
     };
@@ -47,11 +91,84 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+trap_stub!(divide_error_entry => divide_error_handler);
+trap_stub!(invalid_opcode_entry => invalid_opcode_handler);
+trap_stub!(invalid_tss_entry => invalid_tss_handler, error_code);
+trap_stub!(segment_not_present_entry => segment_not_present_handler, error_code);
+trap_stub!(stack_segment_entry => stack_segment_handler, error_code);
+trap_stub!(general_protection_entry => general_protection_handler, error_code);
+trap_stub!(double_fault_entry => double_fault_handler, error_code);
+
+/// Ends the current process with `signal` if it raised exception `name`, or panics with every
+/// register if the kernel did.
+fn fatal_exception(frame: &mut TrapFrame, name: &str, error_code: Option<u64>, signal: u32) {
+    use crate::process::{self, scheduler};
+
+    if frame.from_user() {
+        log_ratelimited!(
+            Level::Warn,
+            "pid {:?} killed by {} at rip {:#x}",
+            process::current_pid().map(|pid| pid.0),
+            name,
+            frame.rip
+        );
+        scheduler::force_signal(frame, signal);
+        return;
+    }
+    crate::trap::set_fault_frame(frame);
+    match error_code {
+        Some(code) => panic!("EXCEPTION: {} (error code {:#x})\n{}", name, code, frame),
+        None => panic!("EXCEPTION: {}\n{}", name, frame),
+    }
+}
+
+extern "C" fn divide_error_handler(frame: &mut TrapFrame) {
+    fatal_exception(frame, "DIVIDE ERROR", None, signal::SIGFPE);
+}
+
+extern "C" fn invalid_opcode_handler(frame: &mut TrapFrame) {
+    fatal_exception(frame, "INVALID OPCODE", None, signal::SIGILL);
+}
+
+extern "C" fn invalid_tss_handler(frame: &mut TrapFrame, error_code: u64) {
+    fatal_exception(frame, "INVALID TSS", Some(error_code), signal::SIGSEGV);
+}
+
+extern "C" fn segment_not_present_handler(frame: &mut TrapFrame, error_code: u64) {
+    fatal_exception(
+        frame,
+        "SEGMENT NOT PRESENT",
+        Some(error_code),
+        signal::SIGSEGV,
+    );
+}
+
+extern "C" fn stack_segment_handler(frame: &mut TrapFrame, error_code: u64) {
+    fatal_exception(
+        frame,
+        "STACK SEGMENT FAULT",
+        Some(error_code),
+        signal::SIGSEGV,
+    );
+}
+
+extern "C" fn general_protection_handler(frame: &mut TrapFrame, error_code: u64) {
+    fatal_exception(
+        frame,
+        "GENERAL PROTECTION FAULT",
+        Some(error_code),
+        signal::SIGSEGV,
+    );
+}
+
+extern "C" fn double_fault_handler(frame: &mut TrapFrame, _error_code: u64) {
+    // Often a kernel stack overflow, which the panic handler may not survive
+    crate::serial::force_unlock_and_print(format_args!(
+        "EXCEPTION: DOUBLE FAULT at {:#x}, stack pointer {:#x}\n",
+        frame.rip, frame.rsp
+    ));
+    crate::trap::set_fault_frame(frame);
+    panic!("EXCEPTION: DOUBLE FAULT\n{}", frame);
 }
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -75,45 +192,204 @@ impl InterruptIndex {
     }
 }
 
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const PIT_CHANNEL0_PERIODIC: u8 = 0b0011_0100;
+/// Tick rate of the PIT when it stands in for the APIC timer.
+pub const PIT_TICK_HZ: u32 = 100;
+
+/// Interrupt setup for machines without a usable APIC: the 8259 PICs deliver the PIT's timer
+/// and the keyboard on [`InterruptIndex`] vectors, with everything else masked.
+pub fn init_pic_mode() {
+    use x86_64::instructions::port::Port;
+
+    let divisor = (PIT_FREQUENCY_HZ / PIT_TICK_HZ) as u16;
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // IRQ 0 (PIT) and 1 (keyboard) only
+        pics.write_masks(!0b11, 0xFF);
+
+        Port::<u8>::new(PIT_COMMAND).write(PIT_CHANNEL0_PERIODIC);
+        let mut data = Port::<u8>::new(PIT_CHANNEL0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+/// Acknowledges an interrupt to whichever controller delivered it: the local APIC once it is
+/// set up, the PICs otherwise.
+pub fn end_of_interrupt(vector: u8) {
+    match unsafe { APIC_BASE } {
+        Some(apic_mmio) => write_apic_reg(apic_mmio.as_ptr(), APIC_REG_EOI, 0),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(vector) },
+    }
+}
+
 // APIC Interrupt Handlers
 
 extern "x86-interrupt" fn spurious_interrupt_handler(_frame: InterruptStackFrame) {
-    println!("[NOTE] Spurious interrupt handler triggered.");
+    debug!("Spurious interrupt handler triggered.");
     let apic_mmio = unsafe { &APIC_BASE.expect("[ERROR] APIC_BASE unset!") };
     write_apic_reg(apic_mmio.as_ptr(), APIC_REG_EOI, 0);
 }
 
-extern "x86-interrupt" fn apic_timer_interrupt_handler(_frame: InterruptStackFrame) {
-    print!(".");
-    let apic_mmio = unsafe { &APIC_BASE.expect("[ERROR] APIC_BASE unset!") };
-    write_apic_reg(apic_mmio.as_ptr(), APIC_REG_EOI, 0);
+/// Local APIC error and thermal interrupts taken by one CPU.
+struct ApicEventCounts {
+    errors: AtomicU64,
+    /// Every ESR bit seen so far.
+    esr_bits: AtomicU32,
+    thermal: AtomicU64,
 }
 
-extern "x86-interrupt" fn apic_keyboard_interrupt_handler(_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+static APIC_EVENTS: PerCpu<ApicEventCounts> = PerCpu::new(
+    [const {
+        ApicEventCounts {
+            errors: AtomicU64::new(0),
+            esr_bits: AtomicU32::new(0),
+            thermal: AtomicU64::new(0),
+        }
+    }; MAX_CPUS],
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicEventStats {
+    pub errors: u64,
+    /// Every ESR bit seen so far; see [`esr_errors`].
+    pub esr_bits: u32,
+    pub thermal: u64,
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+/// Returns the APIC error and thermal interrupt counts of CPU `index`.
+pub fn apic_event_stats(index: usize) -> Option<ApicEventStats> {
+    let counts = APIC_EVENTS.get_for(index)?;
+    Some(ApicEventStats {
+        errors: counts.errors.load(Ordering::Relaxed),
+        esr_bits: counts.esr_bits.load(Ordering::Relaxed),
+        thermal: counts.thermal.load(Ordering::Relaxed),
+    })
+}
 
-    crate::task::keyboard::add_scancode(scancode);
+/// What each bit of the error status register means, from bit 0 up.
+const ESR_ERRORS: [&str; 8] = [
+    "send checksum",
+    "receive checksum",
+    "send accept",
+    "receive accept",
+    "redirectable IPI",
+    "send illegal vector",
+    "receive illegal vector",
+    "illegal register address",
+];
+
+/// Names the errors set in an error status register value.
+pub fn esr_errors(esr: u32) -> impl Iterator<Item = &'static str> {
+    ESR_ERRORS
+        .iter()
+        .enumerate()
+        .filter(move |&(bit, _)| esr & 1 << bit != 0)
+        .map(|(_, &name)| name)
+}
 
-    let apic_mmio = unsafe { &APIC_BASE.expect("[ERROR] APIC_BASE unset!") };
-    write_apic_reg(apic_mmio.as_ptr(), APIC_REG_EOI, 0);
+extern "x86-interrupt" fn apic_error_interrupt_handler(_frame: InterruptStackFrame) {
+    let apic_mmio = unsafe { APIC_BASE.expect("[ERROR] APIC_BASE unset!") }.as_ptr();
+    // The ESR only latches new errors when it is written
+    write_apic_reg(apic_mmio, APIC_REG_ESR, 0);
+    let esr = read_apic_reg(apic_mmio, APIC_REG_ESR);
+
+    let counts = APIC_EVENTS.get();
+    counts.errors.fetch_add(1, Ordering::Relaxed);
+    counts.esr_bits.fetch_or(esr, Ordering::Relaxed);
+    serial_print!(
+        "[WARN] APIC error on cpu{}: ESR {:#x}",
+        cpu::current_index(),
+        esr
+    );
+    for error in esr_errors(esr) {
+        serial_print!(", {}", error);
+    }
+    serial_println!();
+
+    write_apic_reg(apic_mmio, APIC_REG_EOI, 0);
 }
 
-extern "x86-interrupt" fn apic_page_fault_handler(
-    frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+extern "x86-interrupt" fn thermal_interrupt_handler(_frame: InterruptStackFrame) {
+    let apic_mmio = unsafe { APIC_BASE.expect("[ERROR] APIC_BASE unset!") }.as_ptr();
+    let thermal = APIC_EVENTS.get().thermal.fetch_add(1, Ordering::Relaxed) + 1;
+    log_ratelimited!(
+        Level::Warn,
+        "Thermal event on cpu{} ({} so far)",
+        cpu::current_index(),
+        thermal
+    );
+    write_apic_reg(apic_mmio, APIC_REG_EOI, 0);
+}
+
+trap_stub!(timer_entry => apic_timer_interrupt_handler);
+
+/// Taken on every CPU whose local APIC timer runs. Each one samples and preempts on its own
+/// ticks; [`crate::timer::on_tick`] leaves the global tick count to the BSP.
+extern "C" fn apic_timer_interrupt_handler(frame: &mut TrapFrame) {
+    if cpu::is_bsp() {
+        print!(".");
+    }
+    crate::profile::sample(frame);
+    crate::timer::on_tick();
+    end_of_interrupt(InterruptIndex::Timer as u8);
+    crate::process::scheduler::tick(frame);
+}
+
+/// The keyboard when the PICs deliver it; with the APIC it gets a vector from [`crate::irq`].
+extern "x86-interrupt" fn pic_keyboard_interrupt_handler(_frame: InterruptStackFrame) {
+    // IRQ 1 isn't shared on the PICs, so there is no one else to claim it
+    crate::ps2::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Keyboard as u8);
+}
+
+trap_stub!(page_fault_entry => page_fault_handler, error_code);
+
+extern "C" fn page_fault_handler(frame: &mut TrapFrame, error_code: u64) {
+    use crate::process::{self, scheduler};
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error code: {:#?}", error_code);
-    println!("{:#?}", frame);
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && let Ok(addr) = Cr2::read()
+        && process::address_space::handle_cow_fault(addr)
+    {
+        return;
+    }
 
-    let apic_mmio = unsafe { &APIC_BASE.expect("[ERROR] APIC_BASE unset!") };
-    write_apic_reg(apic_mmio.as_ptr(), APIC_REG_EOI, 0);
+    if frame.from_user() {
+        log_ratelimited!(
+            Level::Warn,
+            "pid {:?} segfault at {:?}, rip {:#x}, error {:?}",
+            process::current_pid().map(|pid| pid.0),
+            Cr2::read(),
+            frame.rip,
+            error_code
+        );
+        scheduler::force_signal(frame, signal::SIGSEGV);
+        return;
+    }
+
+    // The kernel faulted on a user address it was allowed to
+    if let Some(fixup) = crate::syscall::uaccess::fixup(frame.rip) {
+        frame.rip = fixup;
+        return;
+    }
+
+    // Returning would only fault again on the same instruction
+    crate::trap::set_fault_frame(frame);
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:?} (error code {:?})\n{}",
+        Cr2::read(),
+        error_code,
+        frame
+    );
 }
 
 /// Maps the APIC registers to physical memory.
@@ -135,10 +411,10 @@ extern "x86-interrupt" fn apic_page_fault_handler(
 pub fn map_apic_registers(apic_base: u64) -> *mut u32 {
     let page_aligned_base: u64 = apic_base & !((PAGE_SIZE) - 1);
     let internal_page_offset = apic_base - page_aligned_base;
-    // Use the bootloader's offset rather than KERNEL_HEAP_START.
-    let virt_base = get_offset_u64() as usize + (page_aligned_base as usize);
-    let apic_ptr = (virt_base + internal_page_offset as usize) as *mut u32;
-    apic_ptr
+    // At the bootloader's offset rather than in the heap, mapped if the bootloader left it out
+    let virt_base = memory::map_mmio(PhysAddr::new(page_aligned_base), PAGE_SIZE)
+        .expect("failed to map the local APIC registers");
+    (virt_base + internal_page_offset).as_mut_ptr()
 }
 /// Read the value of a given APIC register
 ///
@@ -182,24 +458,148 @@ fn write_apic_reg(apic_mmio: *mut u32, reg_offset: u32, value: u32) {
 }
 
 const APIC_REG_ID: u32 = 0x20; // Local APIC ID Register
+const APIC_REG_VERSION: u32 = 0x30;
 const APIC_REG_TPR: u32 = 0x80; // Task Priority
 const APIC_REG_EOI: u32 = 0xB0; // End of Interrupt
 const APIC_REG_SVR: u32 = 0xF0; // SIV
 const APIC_SVR_ENABLE: u32 = 1 << 8; // Bit storing 'APIC Software Enable' in SVR
+const APIC_REG_ESR: u32 = 0x280; // Error Status
+const APIC_REG_ICR_LOW: u32 = 0x300; // Interrupt Command
+const APIC_REG_ICR_HIGH: u32 = 0x310;
+/// Set in the low ICR dword until the last IPI sent has been accepted.
+const APIC_ICR_SEND_PENDING: u32 = 1 << 12;
+/// Delivery modes and level bits for the low ICR dword, beside the vector.
+pub const APIC_ICR_INIT: u32 = 0x500;
+pub const APIC_ICR_STARTUP: u32 = 0x600;
+pub const APIC_ICR_ASSERT: u32 = 1 << 14;
+pub const APIC_ICR_LEVEL_TRIGGERED: u32 = 1 << 15;
+/// How long the local APIC may take to accept an IPI.
+const APIC_ICR_TIMEOUT_US: u64 = 10_000;
 const APIC_REG_LVT_TIMER: u32 = 0x320; // Local Vector Table Timer
+const APIC_REG_LVT_THERMAL: u32 = 0x330;
+const APIC_REG_LVT_ERROR: u32 = 0x370;
 const APIC_REG_TIMER_INITIAL_COUNT: u32 = 0x380;
-//const APIC_REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+/// The thermal LVT entry exists when the version register reports at least this many entries
+/// past the first.
+const APIC_MAX_LVT_WITH_THERMAL: u32 = 5;
+const APIC_REG_TIMER_CURRENT_COUNT: u32 = 0x390;
 const APIC_REG_TIMER_DIV: u32 = 0x3E0;
-
+/// Divide configuration value for dividing the bus clock by 16.
+const APIC_TIMER_DIV_16: u32 = 0x3;
+const APIC_LVT_MASKED: u32 = 1 << 16;
+const APIC_LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Initial count the timer runs with until [`calibrate_apic_timer`] has measured it.
+const APIC_TIMER_UNCALIBRATED_COUNT: u32 = 20_000_000;
+/// How long calibration lets the timer count down against the HPET.
+const APIC_CALIBRATION_US: u64 = 10_000;
+
+/// Initial count that makes the timer fire at [`APIC_TIMER_HZ`], measured on the BSP. Every
+/// core's timer runs off the same bus clock, so the APs start theirs with it too.
+static APIC_TIMER_COUNT: AtomicU32 = AtomicU32::new(APIC_TIMER_UNCALIBRATED_COUNT);
+
+/// Starts this CPU's local APIC timer in periodic mode on `vector`, with the calibrated
+/// count if there is one.
 pub unsafe fn init_apic_timer(apic_mmio: *mut u32, vector: u8) {
-    //In this case, the "value" we write to the APIC register is the divide value. 0x3 is 16 (???).
-    write_apic_reg(apic_mmio, APIC_REG_TIMER_DIV, 0x3);
+    write_apic_reg(apic_mmio, APIC_REG_TIMER_DIV, APIC_TIMER_DIV_16);
+    write_apic_reg(
+        apic_mmio,
+        APIC_REG_LVT_TIMER,
+        vector as u32 | APIC_LVT_TIMER_PERIODIC,
+    );
+    let initial_count = APIC_TIMER_COUNT.load(Ordering::Relaxed);
+    write_apic_reg(apic_mmio, APIC_REG_TIMER_INITIAL_COUNT, initial_count);
+}
 
-    let lvt_timer_value = vector as u32 | 0x20000; // bit 17 is the mask bit
-    write_apic_reg(apic_mmio, APIC_REG_LVT_TIMER, lvt_timer_value);
+/// Stops this CPU's local APIC timer, or restarts it with the calibrated count.
+pub fn set_apic_timer_masked(masked: bool) {
+    let Some(apic) = (unsafe { APIC_BASE }) else {
+        return;
+    };
+    if masked {
+        write_apic_reg(
+            apic.as_ptr(),
+            APIC_REG_LVT_TIMER,
+            TIMER_VEC as u32 | APIC_LVT_MASKED,
+        );
+    } else {
+        unsafe { init_apic_timer(apic.as_ptr(), TIMER_VEC) };
+    }
+}
+
+/// Measures how fast this CPU's local APIC timer counts down against the HPET and restarts it
+/// at [`APIC_TIMER_HZ`]. Returns the initial count, which later [`init_apic_timer`] calls
+/// reuse, or `None` if there is no HPET to measure against. Interrupts should be disabled, as
+/// anything taken during the measurement makes the timer look faster than it is.
+///
+/// # Safety
+/// `apic_mmio` must point to the executing CPU's mapped local APIC registers, and `vector`
+/// must have a handler installed, as the timer is left running.
+pub unsafe fn calibrate_apic_timer(apic_mmio: *mut u32, vector: u8) -> Option<u32> {
+    write_apic_reg(
+        apic_mmio,
+        APIC_REG_LVT_TIMER,
+        vector as u32 | APIC_LVT_MASKED,
+    );
+    write_apic_reg(apic_mmio, APIC_REG_TIMER_DIV, APIC_TIMER_DIV_16);
+    write_apic_reg(apic_mmio, APIC_REG_TIMER_INITIAL_COUNT, u32::MAX);
+    let measured = crate::timer::delay_us(APIC_CALIBRATION_US);
+    let elapsed = u32::MAX - read_apic_reg(apic_mmio, APIC_REG_TIMER_CURRENT_COUNT);
+
+    let count = measured
+        .then(|| count_per_tick(elapsed, APIC_CALIBRATION_US, APIC_TIMER_HZ))
+        .filter(|&count| count > 0);
+    if let Some(count) = count {
+        APIC_TIMER_COUNT.store(count, Ordering::Relaxed);
+    }
+    unsafe { init_apic_timer(apic_mmio, vector) };
+    count
+}
 
-    let inital_count = 20_000_000; // placeholder
-    write_apic_reg(apic_mmio, APIC_REG_TIMER_INITIAL_COUNT, inital_count);
+/// The initial count for `hz` interrupts a second from a timer that counted `elapsed` in
+/// `window_us` microseconds.
+fn count_per_tick(elapsed: u32, window_us: u64, hz: u32) -> u32 {
+    let per_second = elapsed as u64 * 1_000_000 / window_us;
+    (per_second / hz as u64).min(u32::MAX as u64) as u32
+}
+
+#[test_case]
+fn test_apic_timer_count_per_tick() {
+    // A 1 GHz bus divided by 16 counts 625_000 in 10 ms
+    assert_eq!(count_per_tick(625_000, 10_000, 100), 625_000);
+    assert_eq!(count_per_tick(625_000, 10_000, 1000), 62_500);
+    assert_eq!(count_per_tick(0, 10_000, 100), 0);
+}
+
+/// Sends an IPI to the CPU with local APIC ID `apic_id` by writing `low`, the vector with
+/// any `APIC_ICR_*` bits, to the ICR of the local APIC at `apic_mmio`. Waits for the IPI
+/// before it to be accepted first, and for this one after. Returns `false` if the local APIC
+/// was still busy with either at the deadline.
+///
+/// # Safety
+/// `apic_mmio` must point to the executing CPU's mapped local APIC registers, and `low` must
+/// describe an IPI the target can take: an INIT or SIPI only for a CPU being started.
+pub unsafe fn send_icr(apic_mmio: *mut u32, apic_id: u32, low: u32) -> bool {
+    let accepted = || read_apic_reg(apic_mmio, APIC_REG_ICR_LOW) & APIC_ICR_SEND_PENDING == 0;
+    // The ICR is written in two halves, which an IPI sent from an interrupt mustn't split
+    let sent = x86_64::instructions::interrupts::without_interrupts(|| {
+        if !Deadline::after_us(APIC_ICR_TIMEOUT_US).spin_until(accepted) {
+            return false;
+        }
+        let high = read_apic_reg(apic_mmio, APIC_REG_ICR_HIGH) & 0x00FF_FFFF;
+        write_apic_reg(apic_mmio, APIC_REG_ICR_HIGH, high | (apic_id & 0xFF) << 24);
+        write_apic_reg(apic_mmio, APIC_REG_ICR_LOW, low);
+        true
+    });
+    sent && Deadline::after_us(APIC_ICR_TIMEOUT_US).spin_until(accepted)
+}
+
+/// Sends a fixed interrupt on `vector` to the CPU with local APIC ID `apic_id`. Returns
+/// `false` if there is no local APIC to send it with, or it didn't accept the IPI.
+pub fn send_ipi(apic_id: u32, vector: u8) -> bool {
+    let Some(apic) = (unsafe { APIC_BASE }) else {
+        return false;
+    };
+    unsafe { send_icr(apic.as_ptr(), apic_id, vector as u32) }
 }
 
 pub unsafe fn enable_local_apic(apic_mmio: *mut u32) {
@@ -211,18 +611,35 @@ pub unsafe fn enable_local_apic(apic_mmio: *mut u32) {
     // Clear the TPR by setting priority to 0 so all interrupts come in
     write_apic_reg(apic_mmio, APIC_REG_TPR, 0);
 
+    // Report APIC errors instead of silently dropping the interrupts they affect. Writing the
+    // ESR twice discards whatever was latched before the handler was in place.
+    write_apic_reg(apic_mmio, APIC_REG_LVT_ERROR, APIC_ERROR_VEC as u32);
+    write_apic_reg(apic_mmio, APIC_REG_ESR, 0);
+    write_apic_reg(apic_mmio, APIC_REG_ESR, 0);
+
+    // Touching a missing LVT entry would itself be an illegal register access
+    let max_lvt = (read_apic_reg(apic_mmio, APIC_REG_VERSION) >> 16) & 0xFF;
+    if max_lvt >= APIC_MAX_LVT_WITH_THERMAL {
+        write_apic_reg(apic_mmio, APIC_REG_LVT_THERMAL, THERMAL_VEC as u32);
+    }
+
     let lapic_id = read_apic_reg(apic_mmio, APIC_REG_ID) >> 24;
     println!("Enabled local APIC with ID={}", lapic_id);
 }
 
-/// Returns a pointer to the I/O APIC register window.
-pub fn map_io_apic() -> *mut u8 {
-    let ptr = get_offset_u64() + 0xfec00000;
-    ptr as *mut u8
+/// Returns a pointer to the register window of the I/O APIC at `address`.
+pub fn map_io_apic(address: PhysAddr) -> *mut u8 {
+    memory::map_mmio(address, IOAPIC_MMIO_SIZE)
+        .expect("failed to map the I/O APIC registers")
+        .as_mut_ptr()
 }
 
 const IOREGSEL: u32 = 0x00;
 const IOWIN: u32 = 0x10;
+/// IOREGSEL and IOWIN are all there is to the register window.
+const IOAPIC_MMIO_SIZE: u64 = 0x20;
+/// Mask bit in the low dword of a redirection entry.
+const IOAPIC_MASKED: u32 = 1 << 16;
 
 /// Write a 32-bit register in the I/O APIC.
 unsafe fn ioapic_write(ioapic_mmio: *mut u8, reg_index: u32, value: u32) {
@@ -235,7 +652,11 @@ unsafe fn ioapic_write(ioapic_mmio: *mut u8, reg_index: u32, value: u32) {
 }
 
 /// Read a 32-bit register in the I/O APIC.
-unsafe fn _ioapic_read(ioapic_mmio: *mut u8, reg_index: u32) -> u32 {
+///
+/// # Safety
+/// `ioapic_mmio` must point to a mapped I/O APIC, and nothing else may use its IOREGSEL and
+/// IOWIN pair until the read is done.
+unsafe fn ioapic_read(ioapic_mmio: *mut u8, reg_index: u32) -> u32 {
     unsafe {
         // Write the index
         core::ptr::write_volatile(ioapic_mmio.add(IOREGSEL as usize).cast::<u32>(), reg_index);
@@ -251,13 +672,17 @@ pub unsafe fn set_ioapic_redirect(
     trigger: TriggerMode,
     polarity: Polarity,
 ) {
-    // Map  the I/O APIC to read/write the regs
-    let ioapic_mmio = map_io_apic();
+    // Find the I/O APIC that handles this GSI and map it to read/write the regs
+    let Some((io_apic, pin)) = crate::platform::get().io_apic_for_gsi(gsi) else {
+        warn!("No I/O APIC handles GSI {}", gsi);
+        return;
+    };
+    let ioapic_mmio = map_io_apic(io_apic.address);
 
-    // Each GSI has 2 regs: low dword and high dword
-    // base index for GSI is 0x10 + 2*gsi
+    // Each pin has 2 regs: low dword and high dword
+    // base index for a pin is 0x10 + 2*pin
 
-    let redtbl_index_low = 0x10 + 2 * gsi;
+    let redtbl_index_low = 0x10 + 2 * pin;
     let redtbl_index_high = redtbl_index_low + 1;
 
     //build the low dword:
@@ -308,6 +733,24 @@ pub unsafe fn set_ioapic_redirect(
     //maybe unmap here?
 }
 
+/// Masks or unmasks the redirection entry for `gsi`, leaving the rest of it as programmed.
+///
+/// # Safety
+/// Nothing else may be programming the I/O APIC that handles `gsi` meanwhile, and unmasking
+/// it needs a handler installed for the vector the entry delivers to.
+pub unsafe fn set_ioapic_mask(gsi: u32, masked: bool) {
+    let Some((io_apic, pin)) = crate::platform::get().io_apic_for_gsi(gsi) else {
+        return;
+    };
+    let ioapic_mmio = map_io_apic(io_apic.address);
+    let redtbl_index_low = 0x10 + 2 * pin;
+    unsafe {
+        let low_dword = ioapic_read(ioapic_mmio, redtbl_index_low) & !IOAPIC_MASKED;
+        let mask_bit = if masked { IOAPIC_MASKED } else { 0 };
+        ioapic_write(ioapic_mmio, redtbl_index_low, low_dword | mask_bit);
+    }
+}
+
 pub fn disable_pic() {
     use x86_64::instructions::port::Port;
 
@@ -323,3 +766,11 @@ pub fn disable_pic() {
         pic2_data.write(0xFFu8);
     }
 }
+
+#[test_case]
+fn test_esr_errors() {
+    let mut errors = esr_errors(1 << 7 | 1 << 2);
+    assert_eq!(errors.next(), Some("send accept"));
+    assert_eq!(errors.next(), Some("illegal register address"));
+    assert_eq!(errors.next(), None);
+}
@@ -0,0 +1,216 @@
+//! I/O port ranges and the drivers that own them.
+//!
+//! A driver [`claim`]s the ports of its device once and reaches them only through the
+//! [`IoRegion`] it gets back, whose accessors are bounds-checked and typed by width. A claim
+//! that overlaps another is a bug in one of the two drivers: debug builds panic on it, and
+//! release builds log it and fail the claim, so two drivers can't quietly fight over a device.
+use core::fmt;
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+use crate::warn;
+
+/// Claims that can be held at once.
+const MAX_CLAIMS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Claim {
+    owner: &'static str,
+    start: u16,
+    end: u16,
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPortError {
+    /// Some of the ports belong to `owner`, which claimed `start..end`.
+    Conflict {
+        owner: &'static str,
+        start: u16,
+        end: u16,
+    },
+    /// The range is empty or reaches port 0xFFFF.
+    BadRange,
+    /// [`MAX_CLAIMS`] ranges are already claimed.
+    TooManyClaims,
+}
+
+impl fmt::Display for IoPortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoPortError::Conflict { owner, start, end } => {
+                write!(f, "ports {:#x}..{:#x} belong to {}", start, end, owner)
+            }
+            IoPortError::BadRange => write!(f, "bad port range"),
+            IoPortError::TooManyClaims => write!(f, "too many port claims"),
+        }
+    }
+}
+
+/// A range of ports owned by one driver, given back when dropped.
+#[derive(Debug)]
+pub struct IoRegion {
+    owner: &'static str,
+    start: u16,
+    len: u16,
+}
+
+impl IoRegion {
+    pub fn base(&self) -> u16 {
+        self.start
+    }
+
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
+    /// The port at `offset` into the region, for `T`-sized accesses. Panics if the access
+    /// would reach past the region.
+    pub fn port<T>(&self, offset: u16) -> Port<T> {
+        let width = size_of::<T>() as u16;
+        assert!(
+            offset.checked_add(width).is_some_and(|end| end <= self.len),
+            "{}: port offset {:#x} outside its {} ports",
+            self.owner,
+            offset,
+            self.len
+        );
+        Port::new(self.start + offset)
+    }
+
+    /// Reads the `T`-sized port at `offset`.
+    pub fn read<T: PortRead>(&self, offset: u16) -> T {
+        // The claim made the region this driver's to use
+        unsafe { self.port::<T>(offset).read() }
+    }
+
+    /// Writes `value` to the `T`-sized port at `offset`.
+    pub fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { self.port::<T>(offset).write(value) }
+    }
+}
+
+impl Drop for IoRegion {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut claims = CLAIMS.lock();
+            let claim = claims
+                .iter_mut()
+                .find(|claim| claim.is_some_and(|claim| claim.start == self.start));
+            if let Some(claim) = claim {
+                *claim = None;
+            }
+        });
+    }
+}
+
+/// Claims the `len` ports from `start` for `owner`.
+///
+/// # Safety
+/// The ports must belong to the device the caller drives, since the region lets it read and
+/// write them without further checks.
+pub unsafe fn claim(owner: &'static str, start: u16, len: u16) -> Result<IoRegion, IoPortError> {
+    let end = start
+        .checked_add(len)
+        .filter(|_| len > 0)
+        .ok_or(IoPortError::BadRange)?;
+    let result = interrupts::without_interrupts(|| {
+        let mut claims = CLAIMS.lock();
+        if let Some(other) = overlapping(&*claims, start..end) {
+            return Err(IoPortError::Conflict {
+                owner: other.owner,
+                start: other.start,
+                end: other.end,
+            });
+        }
+        let slot = claims
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IoPortError::TooManyClaims)?;
+        *slot = Some(Claim { owner, start, end });
+        Ok(IoRegion { owner, start, len })
+    });
+    if let Err(error @ IoPortError::Conflict { .. }) = &result {
+        if cfg!(debug_assertions) {
+            panic!(
+                "{} claiming ports {:#x}..{:#x}: {}",
+                owner, start, end, error
+            );
+        }
+        warn!(
+            "{} claiming ports {:#x}..{:#x}: {}",
+            owner, start, end, error
+        );
+    }
+    result
+}
+
+/// The first claim in `claims` that shares a port with `ports`.
+fn overlapping(claims: &[Option<Claim>], ports: Range<u16>) -> Option<Claim> {
+    claims
+        .iter()
+        .flatten()
+        .find(|claim| ports.start < claim.end && claim.start < ports.end)
+        .copied()
+}
+
+/// The driver that owns `port`, if any.
+pub fn owner(port: u16) -> Option<&'static str> {
+    interrupts::without_interrupts(|| {
+        CLAIMS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|claim| (claim.start..claim.end).contains(&port))
+            .map(|claim| claim.owner)
+    })
+}
+
+#[test_case]
+fn test_overlapping_claims() {
+    let uart = Claim {
+        owner: "uart",
+        start: 0x3F8,
+        end: 0x400,
+    };
+    let claims = [None, Some(uart)];
+    assert_eq!(overlapping(&claims, 0x3F0..0x3F8), None);
+    assert_eq!(overlapping(&claims, 0x3F0..0x3F9), Some(uart));
+    assert_eq!(overlapping(&claims, 0x3FD..0x3FE), Some(uart));
+    assert_eq!(overlapping(&claims, 0x400..0x408), None);
+}
+
+#[test_case]
+fn test_claims_last_until_dropped() {
+    // Ports no driver uses
+    let region = unsafe { claim("test", 0xF000, 8) }.unwrap();
+    assert_eq!(owner(0xF007), Some("test"));
+    assert_eq!(owner(0xF008), None);
+    assert_eq!(
+        unsafe { claim("test", 0xF000, 0) }.err(),
+        Some(IoPortError::BadRange)
+    );
+    assert_eq!(
+        unsafe { claim("test", 0xFFFF, 2) }.err(),
+        Some(IoPortError::BadRange)
+    );
+
+    // A 32-bit access at the last four ports fits
+    let _ = region.port::<u32>(4);
+    drop(region);
+    assert_eq!(owner(0xF000), None);
+    let again = unsafe { claim("test", 0xF004, 8) }.unwrap();
+    assert_eq!(again.base(), 0xF004);
+}
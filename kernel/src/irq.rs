@@ -0,0 +1,324 @@
+//! Interrupt vectors for devices routed through the I/O APIC.
+//!
+//! A driver asks for its interrupt with [`register_isa`] or [`register_gsi`], which picks a
+//! free vector from [`DEVICE_VECTORS`], installs the handler and programs the I/O APIC to
+//! deliver the line to the boot CPU. Every vector in the range has a fixed IDT stub that calls
+//! the handlers registered on it and acknowledges the interrupt.
+//!
+//! Lines can be shared, as legacy PCI INTx lines often are: registering a GSI that already has
+//! a vector adds the handler to that vector. Every unmasked handler is called on each interrupt
+//! and reports whether its device raised it. A line that keeps interrupting without any handler
+//! claiming it is masked at the I/O APIC, so a stuck level-triggered device can't hang the CPU.
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use acpi::platform::interrupt::{Polarity, TriggerMode};
+use spin::Mutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::interrupts::{end_of_interrupt, set_ioapic_mask, set_ioapic_redirect};
+use crate::metrics::Counter;
+use crate::{cpu, platform, warn};
+
+/// Vectors handed out to devices.
+pub const DEVICE_VECTORS: Range<u8> = 0x30..0x40;
+const VECTOR_COUNT: usize = (DEVICE_VECTORS.end - DEVICE_VECTORS.start) as usize;
+/// Handlers that can share one vector.
+pub const MAX_SHARED: usize = 4;
+/// Unclaimed interrupts in a row after which a line is masked.
+const STORM_LIMIT: u32 = 1000;
+
+/// A device's interrupt handler. Returns `true` if its device raised the interrupt.
+pub type Handler = fn() -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Every vector in [`DEVICE_VECTORS`] is taken.
+    NoFreeVector,
+    /// The GSI already has [`MAX_SHARED`] handlers.
+    LineFull(u32),
+    /// No I/O APIC handles this GSI.
+    NoIoApic(u32),
+}
+
+/// The line a vector is routed from and the names of the handlers on it.
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    gsi: u32,
+    handlers: [Option<&'static str>; MAX_SHARED],
+}
+
+/// State read by the interrupt stubs, which never take a lock.
+struct Vector {
+    /// Each handler as a `fn() -> bool` address, 0 for an empty slot.
+    handlers: [AtomicUsize; MAX_SHARED],
+    /// One bit per handler slot that is skipped when the interrupt arrives.
+    masked: AtomicU8,
+    gsi: AtomicU32,
+    count: AtomicU64,
+    unclaimed: AtomicU64,
+    /// Unclaimed interrupts since one was last claimed.
+    unclaimed_run: AtomicU32,
+    /// Whether the line is masked at the I/O APIC.
+    line_masked: AtomicBool,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Vector {
+            handlers: [const { AtomicUsize::new(0) }; MAX_SHARED],
+            masked: AtomicU8::new(0),
+            gsi: AtomicU32::new(0),
+            count: AtomicU64::new(0),
+            unclaimed: AtomicU64::new(0),
+            unclaimed_run: AtomicU32::new(0),
+            line_masked: AtomicBool::new(false),
+        }
+    }
+
+    fn set_line_masked(&self, masked: bool) {
+        if self.line_masked.swap(masked, Ordering::AcqRel) != masked {
+            unsafe { set_ioapic_mask(self.gsi.load(Ordering::Relaxed), masked) };
+        }
+    }
+}
+
+static DEVICE_INTERRUPTS: Counter = Counter::new("irq.device");
+static UNCLAIMED_INTERRUPTS: Counter = Counter::new("irq.unclaimed");
+
+static VECTORS: [Vector; VECTOR_COUNT] = [const { Vector::new() }; VECTOR_COUNT];
+static LINES: Mutex<[Option<Line>; VECTOR_COUNT]> = Mutex::new([None; VECTOR_COUNT]);
+
+/// One handler's registration, used to mask and unmask it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: u8,
+    slot: u8,
+}
+
+impl IrqHandle {
+    pub fn vector(self) -> u8 {
+        self.vector
+    }
+
+    /// Stops calling this handler. The line itself is masked once none of its handlers is
+    /// left unmasked.
+    pub fn mask(self) {
+        let vector = &VECTORS[(self.vector - DEVICE_VECTORS.start) as usize];
+        let masked = vector.masked.fetch_or(1 << self.slot, Ordering::AcqRel) | 1 << self.slot;
+        if vector
+            .handlers
+            .iter()
+            .enumerate()
+            .all(|(slot, handler)| masked & 1 << slot != 0 || handler.load(Ordering::Relaxed) == 0)
+        {
+            vector.set_line_masked(true);
+        }
+    }
+
+    /// Calls this handler again, unmasking the line if it was masked, including after an
+    /// interrupt storm.
+    pub fn unmask(self) {
+        let vector = &VECTORS[(self.vector - DEVICE_VECTORS.start) as usize];
+        vector.masked.fetch_and(!(1 << self.slot), Ordering::AcqRel);
+        vector.unclaimed_run.store(0, Ordering::Relaxed);
+        vector.set_line_masked(false);
+    }
+}
+
+/// Routes ISA IRQ `irq` to `handler`, following the firmware's interrupt source overrides.
+pub fn register_isa(irq: u8, name: &'static str, handler: Handler) -> Result<IrqHandle, IrqError> {
+    let route = platform::get().isa_irq(irq);
+    register_gsi(route.gsi, route.trigger, route.polarity, name, handler)
+}
+
+/// Routes global system interrupt `gsi` to `handler`. If the GSI already has a vector, the
+/// handler shares it and `trigger` and `polarity` are left as the first registration set them.
+pub fn register_gsi(
+    gsi: u32,
+    trigger: TriggerMode,
+    polarity: Polarity,
+    name: &'static str,
+    handler: Handler,
+) -> Result<IrqHandle, IrqError> {
+    if platform::get().io_apic_for_gsi(gsi).is_none() {
+        return Err(IrqError::NoIoApic(gsi));
+    }
+    let mut lines = LINES.lock();
+    let shared = lines
+        .iter()
+        .position(|line| line.is_some_and(|line| line.gsi == gsi));
+    let (index, new_line) = match shared {
+        Some(index) => (index, false),
+        None => {
+            let index = lines
+                .iter()
+                .position(Option::is_none)
+                .ok_or(IrqError::NoFreeVector)?;
+            (index, true)
+        }
+    };
+    let line = lines[index].get_or_insert(Line {
+        gsi,
+        handlers: [None; MAX_SHARED],
+    });
+    let Some(slot) = line.handlers.iter().position(Option::is_none) else {
+        return Err(IrqError::LineFull(gsi));
+    };
+    line.handlers[slot] = Some(name);
+
+    let vector = &VECTORS[index];
+    vector.gsi.store(gsi, Ordering::Relaxed);
+    vector.masked.fetch_and(!(1 << slot), Ordering::AcqRel);
+    vector.handlers[slot].store(handler as usize, Ordering::Release);
+
+    let handle = IrqHandle {
+        vector: DEVICE_VECTORS.start + index as u8,
+        slot: slot as u8,
+    };
+    if new_line {
+        unsafe { set_ioapic_redirect(gsi, cpu::apic_id(), handle.vector, trigger, polarity) };
+    } else {
+        handle.unmask();
+    }
+    Ok(handle)
+}
+
+#[derive(Debug, Clone)]
+pub struct IrqInfo {
+    pub vector: u8,
+    pub gsi: u32,
+    /// Handler names, with whether each is masked.
+    pub handlers: Vec<(&'static str, bool)>,
+    /// Interrupts taken on the vector so far.
+    pub count: u64,
+    /// Interrupts no handler claimed.
+    pub unclaimed: u64,
+    pub line_masked: bool,
+}
+
+/// Lists the registered device interrupts.
+pub fn list() -> Vec<IrqInfo> {
+    let lines = *LINES.lock();
+    lines
+        .iter()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.as_ref()?;
+            let vector = &VECTORS[index];
+            let masked = vector.masked.load(Ordering::Relaxed);
+            let handlers = line
+                .handlers
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, name)| Some(((*name)?, masked & 1 << slot != 0)))
+                .collect();
+            Some(IrqInfo {
+                vector: DEVICE_VECTORS.start + index as u8,
+                gsi: line.gsi,
+                handlers,
+                count: vector.count.load(Ordering::Relaxed),
+                unclaimed: vector.unclaimed.load(Ordering::Relaxed),
+                line_masked: vector.line_masked.load(Ordering::Relaxed),
+            })
+        })
+        .collect()
+}
+
+/// Device interrupts taken since boot.
+pub fn delivered() -> u64 {
+    DEVICE_INTERRUPTS.get()
+}
+
+/// Whether each line was masked before [`mask_lines_except`], for [`restore_lines`].
+pub struct LineMasks([bool; VECTOR_COUNT]);
+
+/// Masks every device line at the I/O APIC but those of the GSIs in `keep`, e.g. to leave only
+/// wake-up sources on while suspended.
+pub fn mask_lines_except(keep: &[u32]) -> LineMasks {
+    let lines = LINES.lock();
+    let mut masks = [false; VECTOR_COUNT];
+    for (index, line) in lines.iter().enumerate() {
+        let Some(line) = line else {
+            continue;
+        };
+        let vector = &VECTORS[index];
+        masks[index] = vector.line_masked.load(Ordering::Relaxed);
+        if !keep.contains(&line.gsi) {
+            vector.set_line_masked(true);
+        }
+    }
+    LineMasks(masks)
+}
+
+/// Masks or unmasks every line the way it was before [`mask_lines_except`].
+pub fn restore_lines(masks: LineMasks) {
+    let lines = LINES.lock();
+    for (index, line) in lines.iter().enumerate() {
+        if line.is_some() {
+            VECTORS[index].set_line_masked(masks.0[index]);
+        }
+    }
+}
+
+fn dispatch(number: u8) {
+    let vector = &VECTORS[(number - DEVICE_VECTORS.start) as usize];
+    vector.count.fetch_add(1, Ordering::Relaxed);
+    DEVICE_INTERRUPTS.inc();
+    let masked = vector.masked.load(Ordering::Acquire);
+    let mut claimed = false;
+    // Every handler runs, since more than one device on the line may be asking for service
+    for (slot, handler) in vector.handlers.iter().enumerate() {
+        let handler = handler.load(Ordering::Acquire);
+        if handler == 0 || masked & 1 << slot != 0 {
+            continue;
+        }
+        let handler: Handler = unsafe { core::mem::transmute(handler) };
+        claimed |= handler();
+    }
+    if claimed {
+        vector.unclaimed_run.store(0, Ordering::Relaxed);
+    } else {
+        unclaimed(number, vector);
+    }
+    end_of_interrupt(number);
+}
+
+/// Counts an interrupt nobody claimed and masks the line once they come in a storm.
+fn unclaimed(number: u8, vector: &Vector) {
+    let total = vector.unclaimed.fetch_add(1, Ordering::Relaxed) + 1;
+    UNCLAIMED_INTERRUPTS.inc();
+    // Warn on the 1st, 2nd, 4th, 8th... so a slow trickle stays visible without flooding
+    if total.is_power_of_two() {
+        warn!(
+            "{} unclaimed interrupt(s) on vector {:#x} (GSI {})",
+            total,
+            number,
+            vector.gsi.load(Ordering::Relaxed)
+        );
+    }
+    if vector.unclaimed_run.fetch_add(1, Ordering::Relaxed) + 1 == STORM_LIMIT {
+        vector.set_line_masked(true);
+        warn!(
+            "Masked GSI {} after {} unclaimed interrupts in a row",
+            vector.gsi.load(Ordering::Relaxed),
+            STORM_LIMIT
+        );
+    }
+}
+
+extern "x86-interrupt" fn device_interrupt<const VECTOR: u8>(_frame: InterruptStackFrame) {
+    dispatch(VECTOR);
+}
+
+/// Points every vector in [`DEVICE_VECTORS`] at its stub.
+pub(crate) fn install_stubs(idt: &mut InterruptDescriptorTable) {
+    macro_rules! stubs {
+        ($($vector:literal)*) => {
+            $(idt[$vector].set_handler_fn(device_interrupt::<$vector>);)*
+            const _: () = assert!([$($vector),*].len() == VECTOR_COUNT);
+        };
+    }
+    stubs!(0x30 0x31 0x32 0x33 0x34 0x35 0x36 0x37 0x38 0x39 0x3A 0x3B 0x3C 0x3D 0x3E 0x3F);
+}
@@ -1,17 +1,17 @@
+use alloc::alloc::Global;
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
 use core::ptr::NonNull;
 
-use acpi::{AcpiHandler, PhysicalMapping};
-use x86_64::{
-    VirtAddr,
-    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+use acpi::mcfg::Mcfg;
+use acpi::{
+    AcpiError, AcpiHandler, AcpiResult, AcpiTables, GenericAddress, HpetInfo, PhysicalMapping,
+    PlatformInfo, fadt::Fadt,
 };
+use spin::Once;
+use x86_64::{PhysAddr, structures::paging::PageTableFlags};
 
-use crate::{
-    allocator::page_allocator::{KERNEL_HEAP_START, PAGE_ALLOCATOR},
-    init::memory_init::get_offset_u64,
-    memory::PAGE_SIZE,
-    serial_println,
-};
+use crate::memory::{self, PAGE_SIZE};
 
 #[derive(Clone, Copy)]
 /// An implementation of the `AcpiHandler` trait that can be used to map ACPI tables.
@@ -27,8 +27,19 @@ impl AcpiHandler for KernelAcpiHandler {
         let phys_base_page = physical_address & !(PAGE_SIZE as usize - 1);
         let offset_in_page = physical_address - phys_base_page;
         let mapped_size = offset_in_page + size;
-        let virt_base = get_offset_u64() as usize + phys_base_page;
-        let t_virtual = (virt_base + offset_in_page) as *mut T;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let virt_base = memory::map_physical(
+            PhysAddr::new(phys_base_page as u64),
+            mapped_size as u64,
+            flags,
+        )
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to map ACPI region at {:#x}: {}",
+                physical_address, e
+            )
+        });
+        let t_virtual = (virt_base + offset_in_page as u64).as_mut_ptr::<T>();
 
         let mapping = unsafe {
             PhysicalMapping::new(
@@ -43,41 +54,122 @@ impl AcpiHandler for KernelAcpiHandler {
         mapping
     }
 
-    // Because the bootloader mapping is permanent, unmapping is a no-op.
+    // The physical memory mapping is permanent, including pages added to it, so unmapping is a
+    // no-op.
     fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {
         //serial_println!("unmap_physical_region: No operation performed (bootloader mapping)");
     }
 }
 
-pub fn map_physical(phys_addr: usize, num_pages: usize) -> usize {
-    let mut pa_guard = PAGE_ALLOCATOR.lock();
-    let page_alloc = pa_guard.as_mut().expect("PAGE_ALLOCATOR uninitialized");
-    let virt_base = KERNEL_HEAP_START + phys_addr;
-
-    // 2) for each page in [0..num_pages], map it to the existing physical address
-    for i in 0..num_pages {
-        let pa = phys_addr + i * (PAGE_SIZE as usize);
-        let va = virt_base + i * (PAGE_SIZE as usize);
-        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(va as u64));
-
-        let phys_frame = PhysFrame::containing_address(x86_64::PhysAddr::new(pa as u64));
-        unsafe {
-            let page_flush = match page_alloc.mapper.map_to(
-                page,
-                phys_frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                &mut page_alloc.frame_allocator,
-            ) {
-                Ok(flush) => flush,
-                Err(e) => {
-                    serial_println!("map_to failed: {:?}", e);
-                    panic!("map_to failed: {:?}", e);
-                }
-            };
-
-            page_flush.flush();
+/// What the kernel keeps from the ACPI tables once they have been parsed at boot.
+///
+/// Everything here is copied out of the tables, so it stays valid after the memory they live
+/// in has been reclaimed. Subsystems that come up late query it through [`registry`] instead
+/// of being handed the tables by `kernel_main`.
+pub struct AcpiRegistry {
+    platform: PlatformInfo<'static, Global>,
+    hpet: AcpiResult<HpetInfo>,
+    fadt: AcpiResult<FadtInfo>,
+    mcfg: Vec<McfgRegion>,
+}
+
+/// The parts of the FADT the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct FadtInfo {
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// CMOS index of the RTC century register, or 0 if there is none.
+    pub century: u8,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    pub pm_timer: Option<GenericAddress>,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub has_8042: bool,
+    pub has_cmos_rtc: bool,
+}
+
+impl FadtInfo {
+    fn new(fadt: &Fadt) -> Self {
+        let boot_arch = fadt.iapc_boot_arch;
+        FadtInfo {
+            sci_interrupt: fadt.sci_interrupt,
+            smi_command: fadt.smi_cmd_port,
+            acpi_enable: fadt.acpi_enable,
+            acpi_disable: fadt.acpi_disable,
+            century: fadt.century,
+            pm1a_control: fadt.pm1a_control_block().ok(),
+            pm1b_control: fadt.pm1b_control_block().ok().flatten(),
+            pm_timer: fadt.pm_timer_block().ok().flatten(),
+            reset_register: fadt.reset_register().ok(),
+            reset_value: fadt.reset_value,
+            has_8042: boot_arch.motherboard_implements_8042(),
+            has_cmos_rtc: !boot_arch.cmos_rtc_not_present(),
         }
     }
+}
+
+/// One MCFG entry: where the PCI Express configuration space of a range of buses is mapped.
+#[derive(Debug, Clone)]
+pub struct McfgRegion {
+    pub segment: u16,
+    pub buses: RangeInclusive<u8>,
+    /// Address of bus 0's configuration space, even if `buses` starts later.
+    pub base: PhysAddr,
+}
+
+impl AcpiRegistry {
+    pub fn platform(&self) -> &PlatformInfo<'static, Global> {
+        &self.platform
+    }
+
+    pub fn hpet(&self) -> AcpiResult<&HpetInfo> {
+        self.hpet.as_ref().map_err(|e| *e)
+    }
+
+    pub fn fadt(&self) -> AcpiResult<&FadtInfo> {
+        self.fadt.as_ref().map_err(|e| *e)
+    }
+
+    /// The PCI Express configuration regions, empty if the firmware has no MCFG.
+    pub fn mcfg(&self) -> &[McfgRegion] {
+        &self.mcfg
+    }
+}
+
+static REGISTRY: Once<AcpiRegistry> = Once::new();
+
+/// Parses the tables the kernel needs into the registry. Only the first call has any effect.
+pub fn init_registry(
+    tables: &AcpiTables<KernelAcpiHandler>,
+) -> Result<&'static AcpiRegistry, AcpiError> {
+    if let Some(registry) = REGISTRY.get() {
+        return Ok(registry);
+    }
+    let platform = PlatformInfo::new(tables)?;
+    let mcfg = match tables.find_table::<Mcfg>() {
+        Ok(mcfg) => mcfg
+            .entries()
+            .iter()
+            .map(|&entry| McfgRegion {
+                segment: entry.pci_segment_group,
+                buses: entry.bus_number_start..=entry.bus_number_end,
+                base: PhysAddr::new(entry.base_address),
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    Ok(REGISTRY.call_once(|| AcpiRegistry {
+        platform,
+        hpet: HpetInfo::new(tables),
+        fadt: tables.find_table::<Fadt>().map(|fadt| FadtInfo::new(&fadt)),
+        mcfg,
+    }))
+}
 
-    virt_base
+/// Returns the registry, or `None` if the machine has no usable ACPI tables.
+pub fn registry() -> Option<&'static AcpiRegistry> {
+    REGISTRY.get()
 }
@@ -0,0 +1,464 @@
+//! Starting another kernel without going back through the firmware, like Linux's kexec.
+//!
+//! [`load`] copies a kernel image into memory set aside for it and builds the page tables and
+//! boot info the bootloader would have given it; [`execute`] stops the other CPUs and the
+//! devices and jumps to it. On real hardware, where a firmware reboot takes many seconds, this
+//! makes trying out a new build much quicker.
+//!
+//! The new kernel is handed:
+//!
+//! - its `PT_LOAD` segments at their linked addresses, in page tables of their own,
+//! - the bootloader's mappings of physical memory, the framebuffer and the ramdisk, at the
+//!   addresses this kernel got them, and a fresh stack,
+//! - a copy of this kernel's [`BootInfo`], with the kernel file and TLS template replaced by
+//!   the new image's, and everything set aside for it marked in use in the memory map.
+//!
+//! Everything else this kernel allocated is free memory to the new one. Only non-PIE kernels
+//! (`ET_EXEC`, as `relocation-model=static` links them) can be loaded, since nothing applies
+//! relocations. Once this kernel has reclaimed the memory holding the ACPI tables they may have
+//! been overwritten, so the new kernel then gets no RSDP and boots as if there were no ACPI.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::{asm, naked_asm};
+use core::convert::Infallible;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bootloader_api::BootInfo;
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions, Optional, TlsTemplate};
+use x86_64::registers::control::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::fs::{self, FsError};
+use crate::fw_cfg::{self, FwCfgError};
+use crate::init::memory_init::{self, get_offset_u64};
+use crate::memory::{PAGE_SIZE, phys_to_virt};
+use crate::process::address_space::{self, translate_phys_active};
+use crate::process::elf::{self, ElfError};
+use crate::smp::park::{self, ParkError};
+use crate::{cpu, info, interrupts, mem, virtio};
+
+/// Pages of stack the new kernel starts on: 80 KiB, the bootloader's default.
+const STACK_PAGES: usize = 20;
+const ENTRIES: usize = 512;
+
+#[derive(Debug)]
+pub enum KexecError {
+    Fs(FsError),
+    FwCfg(FwCfgError),
+    Elf(ElfError),
+    /// [`init`] hasn't been given the boot info to pass on.
+    NoBootInfo,
+    /// The image is linked where the new kernel is handed other mappings, or there is no free
+    /// level 4 slot to jump through.
+    AddressInUse,
+    /// No run of free frames is large enough for the image and its page tables.
+    OutOfMemory,
+    /// Only the BSP can stop the other CPUs.
+    NotBsp,
+    Park(ParkError),
+}
+
+/// The boot info this kernel was started with.
+static BOOT_INFO: AtomicPtr<BootInfo> = AtomicPtr::new(ptr::null_mut());
+
+/// Keeps `boot_info`, which lives in memory the bootloader set aside for good, as the base of
+/// the boot info for kernels started later.
+pub fn init(boot_info: &BootInfo) {
+    BOOT_INFO.store(ptr::from_ref(boot_info).cast_mut(), Ordering::Release);
+}
+
+/// Reads a kernel image from `source`: an absolute path in the VFS, or else the name of a
+/// fw_cfg file, e.g. `opt/rust-kernel/next`.
+pub fn read_image(source: &str) -> Result<Vec<u8>, KexecError> {
+    if source.starts_with('/') {
+        fs::read_all(source).map_err(KexecError::Fs)
+    } else {
+        fw_cfg::read_file(source).map_err(KexecError::FwCfg)
+    }
+}
+
+/// A kernel in memory, ready for [`execute`]. Dropping it frees the memory again.
+pub struct Loaded {
+    /// Only held, so the memory stays set aside until the jump.
+    _area: Area,
+    entry: u64,
+    pml4: PhysAddr,
+    stack_top: u64,
+    /// The new kernel's address for its boot info.
+    boot_info: u64,
+    /// The level 4 slot both page tables map the trampoline in, and the table under it.
+    trampoline_slot: usize,
+    trampoline_table: PhysAddr,
+}
+
+impl Loaded {
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+}
+
+/// The frames set aside for a new kernel, handed out in order.
+struct Area {
+    start: PhysAddr,
+    pages: usize,
+    used: usize,
+}
+
+impl Area {
+    /// Takes `pages` zeroed pages.
+    fn take(&mut self, pages: usize) -> Result<PhysAddr, KexecError> {
+        if self.used + pages > self.pages {
+            return Err(KexecError::OutOfMemory);
+        }
+        let addr = self.start + self.used as u64 * PAGE_SIZE;
+        self.used += pages;
+        unsafe { mem::zero_pages(virt(addr), pages) };
+        Ok(addr)
+    }
+
+    fn range(&self) -> Range<u64> {
+        self.start.as_u64()..self.start.as_u64() + self.pages as u64 * PAGE_SIZE
+    }
+}
+
+impl Drop for Area {
+    fn drop(&mut self) {
+        if let Some(page_alloc) = PAGE_ALLOCATOR.lock().as_ref() {
+            for page in 0..self.pages as u64 {
+                let frame = PhysFrame::containing_address(self.start + page * PAGE_SIZE);
+                page_alloc.frame_allocator.deallocate(frame);
+            }
+        }
+    }
+}
+
+fn virt(addr: PhysAddr) -> *mut u8 {
+    phys_to_virt(addr)
+        .expect("kexec frame outside the physical memory mapping")
+        .as_mut_ptr()
+}
+
+fn table(addr: PhysAddr) -> &'static mut PageTable {
+    unsafe { &mut *virt(addr).cast::<PageTable>() }
+}
+
+/// The level 4 slot `addr` is in.
+fn slot(addr: u64) -> usize {
+    (addr >> 39) as usize % ENTRIES
+}
+
+/// Marks the level 4 slots of the `len` bytes at `start`.
+fn mark_slots(slots: &mut [bool; ENTRIES], start: u64, len: u64) {
+    if len > 0 {
+        slots[slot(start)..=slot(start + (len - 1))].fill(true);
+    }
+}
+
+/// Maps `page` to `frame` under the level 4 table at `pml4`, taking the tables it needs on the
+/// way from `area`.
+fn map(
+    area: &mut Area,
+    pml4: PhysAddr,
+    page: u64,
+    frame: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), KexecError> {
+    let mut table_addr = pml4;
+    for shift in [39, 30, 21] {
+        let entry = &mut table(table_addr)[(page >> shift) as usize % ENTRIES];
+        if entry.is_unused() {
+            entry.set_addr(
+                area.take(1)?,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            );
+        }
+        table_addr = entry.addr();
+    }
+    table(table_addr)[(page >> 12) as usize % ENTRIES]
+        .set_addr(frame, flags | PageTableFlags::PRESENT);
+    Ok(())
+}
+
+/// Copies the memory map `regions` into `out` with the usable memory in `reserved` marked as
+/// the bootloader's, splitting regions where needed, and returns how many regions that made.
+/// `out` needs room for two more regions than `regions` has.
+fn reserve_regions(
+    regions: &[MemoryRegion],
+    reserved: Range<u64>,
+    out: &mut [MemoryRegion],
+) -> usize {
+    let mut count = 0;
+    let mut push = |start: u64, end: u64, kind: MemoryRegionKind| {
+        if start < end {
+            out[count] = MemoryRegion { start, end, kind };
+            count += 1;
+        }
+    };
+    for region in regions {
+        if region.kind != MemoryRegionKind::Usable
+            || region.end <= reserved.start
+            || region.start >= reserved.end
+        {
+            push(region.start, region.end, region.kind);
+            continue;
+        }
+        let start = region.start.max(reserved.start);
+        let end = region.end.min(reserved.end);
+        push(region.start, start, MemoryRegionKind::Usable);
+        push(start, end, MemoryRegionKind::Bootloader);
+        push(end, region.end, MemoryRegionKind::Usable);
+    }
+    count
+}
+
+/// Loads the kernel in `image` for [`execute`].
+pub fn load(image: &[u8]) -> Result<Loaded, KexecError> {
+    let boot_info =
+        unsafe { BOOT_INFO.load(Ordering::Acquire).as_ref() }.ok_or(KexecError::NoBootInfo)?;
+    let elf = elf::parse_executable(image).map_err(KexecError::Elf)?;
+    let offset = get_offset_u64();
+
+    // The slots of the mappings the new kernel inherits
+    let mut inherited = [false; ENTRIES];
+    let memory_end = boot_info.memory_regions.iter().map(|r| r.end).max();
+    mark_slots(&mut inherited, offset, memory_end.unwrap_or(0));
+    if let Some(framebuffer) = boot_info.framebuffer.as_ref() {
+        let buffer = framebuffer.buffer();
+        mark_slots(&mut inherited, buffer.as_ptr() as u64, buffer.len() as u64);
+    }
+    if let Some(&ramdisk) = boot_info.ramdisk_addr.as_ref() {
+        mark_slots(&mut inherited, ramdisk, boot_info.ramdisk_len);
+    }
+
+    // Every page of the new kernel, and whether it is writable and executable
+    let mut pages: BTreeMap<u64, (bool, bool)> = BTreeMap::new();
+    let mut kernel_slots = [false; ENTRIES];
+    for segment in elf.segments.iter().filter(|segment| segment.mem_size > 0) {
+        let end = segment
+            .vaddr
+            .checked_add(segment.mem_size)
+            .ok_or(KexecError::Elf(ElfError::BadAddress))?;
+        let start = segment.vaddr & !(PAGE_SIZE - 1);
+        for page in (start..end).step_by(PAGE_SIZE as usize) {
+            if inherited[slot(page)] {
+                return Err(KexecError::AddressInUse);
+            }
+            kernel_slots[slot(page)] = true;
+            let (writable, executable) = pages.entry(page).or_default();
+            *writable |= segment.writable;
+            *executable |= segment.executable;
+        }
+    }
+
+    // The trampoline goes in a slot that is free in this kernel's tables too
+    let current = table(address_space::kernel_pml4().start_address());
+    let trampoline_slot = (ENTRIES / 2..ENTRIES)
+        .find(|&index| current[index].is_unused() && !inherited[index] && !kernel_slots[index])
+        .ok_or(KexecError::AddressInUse)?;
+
+    let regions = boot_info.memory_regions.len() + 2;
+    let info_bytes = size_of::<BootInfo>().next_multiple_of(8);
+    let info_pages =
+        (info_bytes + regions * size_of::<MemoryRegion>()).div_ceil(PAGE_SIZE as usize);
+    // A level 3, 2 and 1 table at most for each 2 MiB of the kernel and for the trampoline
+    let mut huge_pages: Vec<u64> = pages.keys().map(|page| page >> 21).collect();
+    huge_pages.dedup();
+    let tables = 1 + 3 * (huge_pages.len() + 1);
+    let total =
+        image.len().div_ceil(PAGE_SIZE as usize) + pages.len() + STACK_PAGES + info_pages + tables;
+    let start = PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|page_alloc| page_alloc.frame_allocator.allocate_contiguous(total))
+        .ok_or(KexecError::OutOfMemory)?;
+    let mut area = Area {
+        start: start.start_address(),
+        pages: total,
+        used: 0,
+    };
+
+    // The new kernel reads its symbols from its file, as this one does
+    let file = area.take(image.len().div_ceil(PAGE_SIZE as usize))?;
+    unsafe { ptr::copy_nonoverlapping(image.as_ptr(), virt(file), image.len()) };
+
+    let pml4 = area.take(1)?;
+    let no_execute = Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE);
+    for (&page, &(writable, executable)) in &pages {
+        let frame = area.take(1)?;
+        for segment in &elf.segments {
+            // The part of the segment's file contents in this page
+            let start = segment.vaddr.max(page);
+            let end = (segment.vaddr + segment.file_size).min(page + PAGE_SIZE);
+            if start < end {
+                let from = (segment.offset + (start - segment.vaddr)) as usize;
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        image[from..].as_ptr(),
+                        virt(frame).add((start - page) as usize),
+                        (end - start) as usize,
+                    );
+                }
+            }
+        }
+        let mut flags = PageTableFlags::empty();
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !executable && no_execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        map(&mut area, pml4, page, frame, flags)?;
+    }
+    for index in (0..ENTRIES).filter(|&index| inherited[index]) {
+        table(pml4)[index] = current[index].clone();
+    }
+
+    // The pages of this kernel's code holding the trampoline, which may straddle two
+    let code_page = trampoline as *const () as u64 & !(PAGE_SIZE - 1);
+    let trampoline_base = VirtAddr::new_truncate((trampoline_slot as u64) << 39).as_u64();
+    for page in 0..2 {
+        if let Some(frame) = translate_phys_active(VirtAddr::new(code_page + page * PAGE_SIZE)) {
+            map(
+                &mut area,
+                pml4,
+                trampoline_base + page * PAGE_SIZE,
+                frame,
+                PageTableFlags::empty(),
+            )?;
+        }
+    }
+    let trampoline_table = table(pml4)[trampoline_slot].addr();
+
+    let stack = area.take(STACK_PAGES)?;
+    let stack_top = offset + stack.as_u64() + STACK_PAGES as u64 * PAGE_SIZE;
+
+    let info_addr = area.take(info_pages)?;
+    let regions_ptr = unsafe { virt(info_addr).add(info_bytes).cast::<MemoryRegion>() };
+    let count = reserve_regions(&boot_info.memory_regions, area.range(), unsafe {
+        core::slice::from_raw_parts_mut(regions_ptr, regions)
+    });
+    // A bitwise copy: the new kernel takes over the framebuffer and the rest from this one
+    let mut info = unsafe { ptr::read(boot_info) };
+    info.memory_regions =
+        MemoryRegions::from(unsafe { core::slice::from_raw_parts_mut(regions_ptr, count) });
+    info.kernel_addr = file.as_u64();
+    info.kernel_len = image.len() as u64;
+    info.kernel_image_offset = 0;
+    info.tls_template = elf
+        .tls
+        .map(|tls| TlsTemplate {
+            start_addr: tls.vaddr,
+            file_size: tls.file_size,
+            mem_size: tls.mem_size,
+        })
+        .into();
+    if memory_init::acpi_reclaimed() {
+        info.rsdp_addr = Optional::None;
+    }
+    unsafe { ptr::write(virt(info_addr).cast::<BootInfo>(), info) };
+
+    Ok(Loaded {
+        _area: area,
+        entry: elf.entry,
+        pml4,
+        stack_top,
+        boot_info: offset + info_addr.as_u64(),
+        trampoline_slot,
+        trampoline_table,
+    })
+}
+
+/// Stops the other CPUs and the devices and starts `kernel`. Only returns if that can't be
+/// done, with the other CPUs running again.
+pub fn execute(kernel: Loaded) -> Result<Infallible, KexecError> {
+    if !cpu::is_bsp() {
+        return Err(KexecError::NotBsp);
+    }
+    let current = table(address_space::kernel_pml4().start_address());
+    if !current[kernel.trampoline_slot].is_unused() {
+        return Err(KexecError::AddressInUse);
+    }
+    let mut parked = Vec::new();
+    for (index, _) in cpu::online().filter(|&(index, _)| index != cpu::current_index()) {
+        match park::park(index) {
+            Ok(()) => parked.push(index),
+            Err(ParkError::AlreadyParked(_)) => {}
+            Err(e) => {
+                for &index in &parked {
+                    let _ = park::unpark(index);
+                }
+                return Err(KexecError::Park(e));
+            }
+        }
+    }
+
+    info!("kexec: starting the new kernel at {:#x}", kernel.entry);
+    x86_64::instructions::interrupts::disable();
+    interrupts::set_apic_timer_masked(true);
+    interrupts::disable_pic();
+    virtio::console::shutdown();
+    address_space::activate_kernel();
+    current[kernel.trampoline_slot].set_addr(
+        kernel.trampoline_table,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    );
+    let trampoline_base = VirtAddr::new_truncate((kernel.trampoline_slot as u64) << 39);
+    let jump = trampoline_base.as_u64() + (trampoline as *const () as u64 & (PAGE_SIZE - 1));
+    unsafe {
+        asm!(
+            "jmp {}",
+            in(reg) jump,
+            in("rdi") kernel.boot_info,
+            in("rsi") kernel.pml4.as_u64(),
+            in("rdx") kernel.entry,
+            in("rcx") kernel.stack_top,
+            options(noreturn),
+        );
+    }
+}
+
+/// Switches to the new kernel's page tables and stack and enters it the way the bootloader
+/// does, with the boot info in RDI. Runs from a mapping both page tables have; RSI holds the
+/// new level 4 table, RDX the entry point and RCX the top of the stack.
+#[unsafe(naked)]
+unsafe extern "C" fn trampoline() {
+    naked_asm!(
+        "mov cr3, rsi",
+        // Global pages survive the switch, and this kernel's may be where the new one is
+        "mov rax, cr4",
+        "mov r8, rax",
+        "btr rax, 7",
+        "mov cr4, rax",
+        "mov cr4, r8",
+        "mov rsp, rcx",
+        "xor ebp, ebp",
+        // As if called, with a return address on an aligned stack
+        "push 0",
+        "jmp rdx",
+    );
+}
+
+#[test_case]
+fn test_reserve_regions() {
+    let region = |start, end, kind| MemoryRegion { start, end, kind };
+    let regions = [
+        region(0, 0x1000, MemoryRegionKind::Bootloader),
+        region(0x1000, 0x9000, MemoryRegionKind::Usable),
+        region(0x9000, 0xA000, MemoryRegionKind::UnknownBios(2)),
+    ];
+    let mut out = [MemoryRegion::empty(); 5];
+    assert_eq!(reserve_regions(&regions, 0x3000..0x5000, &mut out), 5);
+    assert_eq!(out[1], region(0x1000, 0x3000, MemoryRegionKind::Usable));
+    assert_eq!(out[2], region(0x3000, 0x5000, MemoryRegionKind::Bootloader));
+    assert_eq!(out[3], region(0x5000, 0x9000, MemoryRegionKind::Usable));
+    assert_eq!(out[4], regions[2]);
+
+    // Reserving the start of a region leaves no empty region behind
+    assert_eq!(reserve_regions(&regions, 0x1000..0x2000, &mut out), 4);
+    assert_eq!(out[1], region(0x1000, 0x2000, MemoryRegionKind::Bootloader));
+    assert_eq!(out[2], region(0x2000, 0x9000, MemoryRegionKind::Usable));
+}
@@ -0,0 +1,264 @@
+//! The kernel's own function symbols, read from the ELF file the bootloader loaded it from.
+//!
+//! [`init`] copies the function symbols out of the image's `.symtab` into a table sorted by
+//! address, so code addresses (sampled instruction pointers, return addresses) can be turned
+//! back into function names. A stripped kernel has no symbols and nothing resolves. Other
+//! sections of the image, such as the unwind tables, are found with [`find_section`].
+use alloc::vec::Vec;
+use core::fmt;
+
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_range_to_virt;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+/// A function in the kernel image, at its run-time address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub start: u64,
+    pub size: u64,
+    /// The mangled name; format it with [`Demangle`].
+    pub name: &'static str,
+}
+
+static SYMBOLS: OnceCell<Vec<Symbol>> = OnceCell::uninit();
+
+/// The ELF file the kernel was loaded from. Needs the physical memory offset.
+pub(crate) fn kernel_image(boot_info: &BootInfo) -> Option<&'static [u8]> {
+    let start =
+        phys_range_to_virt(PhysAddr::new(boot_info.kernel_addr), boot_info.kernel_len).ok()?;
+    Some(unsafe {
+        core::slice::from_raw_parts(start.as_ptr::<u8>(), boot_info.kernel_len as usize)
+    })
+}
+
+/// Reads the function symbols from the kernel image. Returns how many were found. Needs the
+/// heap and the physical memory offset.
+pub fn init(boot_info: &BootInfo) -> usize {
+    let Some(image) = kernel_image(boot_info) else {
+        return 0;
+    };
+    let mut symbols = read_symbols(image, boot_info.kernel_image_offset).unwrap_or_default();
+    symbols.sort_unstable_by_key(|symbol| symbol.start);
+    let count = symbols.len();
+    let _ = SYMBOLS.try_init_once(|| symbols);
+    count
+}
+
+/// Finds the function containing `addr`.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    let symbols = SYMBOLS.get()?;
+    let index = symbols
+        .partition_point(|symbol| symbol.start <= addr)
+        .checked_sub(1)?;
+    let symbol = symbols[index];
+    // Zero-sized symbols come from assembly; assume they run up to the next one
+    let contains = symbol.size == 0 || addr - symbol.start < symbol.size;
+    contains.then_some(symbol)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Section header `index` of the ELF file `image`.
+fn section_header(image: &[u8], index: usize) -> Option<&[u8]> {
+    let section_table = read_u64(image, 0x28)? as usize;
+    image
+        .get(section_table + index * SHDR_SIZE..)?
+        .get(..SHDR_SIZE)
+}
+
+/// The section headers of the ELF file `image`.
+fn section_headers(image: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let count = read_u16(image, 0x3C).unwrap_or(0) as usize;
+    (0..count).filter_map(|index| section_header(image, index))
+}
+
+/// The contents of the section `header` describes.
+fn section_data<'a>(image: &'a [u8], header: &[u8]) -> Option<&'a [u8]> {
+    let data = image.get(read_u64(header, 24)? as usize..)?;
+    data.get(..read_u64(header, 32)? as usize)
+}
+
+/// The link-time address and the contents of the section called `name` in the ELF file
+/// `image`.
+pub(crate) fn find_section<'a>(image: &'a [u8], name: &str) -> Option<(u64, &'a [u8])> {
+    let names = section_header(image, read_u16(image, 0x3E)? as usize)?;
+    let names = section_data(image, names)?;
+    let header = section_headers(image).find(|header| {
+        read_u32(header, 0)
+            .and_then(|offset| names.get(offset as usize..))
+            .is_some_and(|s| {
+                s.strip_prefix(name.as_bytes())
+                    .is_some_and(|rest| rest.first() == Some(&0))
+            })
+    })?;
+    Some((read_u64(header, 16)?, section_data(image, header)?))
+}
+
+/// Collects the `STT_FUNC` symbols of the ELF file `image`, moved by `load_offset`.
+fn read_symbols(image: &'static [u8], load_offset: u64) -> Option<Vec<Symbol>> {
+    let symtab = section_headers(image).find(|header| read_u32(header, 4) == Some(SHT_SYMTAB))?;
+    let strtab = section_header(image, read_u32(symtab, 40)? as usize)?;
+    let strings = section_data(image, strtab)?;
+    let table = section_data(image, symtab)?;
+
+    let mut symbols = Vec::new();
+    for entry in table.chunks_exact(SYM_SIZE) {
+        let value = read_u64(entry, 8)?;
+        if entry[4] & 0xF != STT_FUNC || value == 0 {
+            continue;
+        }
+        let name = strings.get(read_u32(entry, 0)? as usize..)?;
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        let Ok(name) = core::str::from_utf8(name) else {
+            continue;
+        };
+        symbols.push(Symbol {
+            start: value.wrapping_add(load_offset),
+            size: read_u64(entry, 16)?,
+            name,
+        });
+    }
+    Some(symbols)
+}
+
+/// Formats a symbol name with Rust's legacy mangling (`_ZN...E`) undone and the hash dropped.
+/// Other names are shown as they are.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+        let mut first = true;
+        while let Some(len_digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&n| n > 0) {
+            let Ok(len) = rest[..len_digits].parse::<usize>() else {
+                break;
+            };
+            let Some(part) = rest.get(len_digits..len_digits + len) else {
+                break;
+            };
+            rest = &rest[len_digits + len..];
+            let is_hash = part.len() == 17
+                && part.starts_with('h')
+                && part[1..].bytes().all(|b| b.is_ascii_hexdigit());
+            if is_hash && rest == "E" {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_unescaped(f, part)?;
+        }
+        Ok(())
+    }
+}
+
+/// Undoes the `$...$` and `..` escapes of a legacy-mangled path component.
+fn write_unescaped(f: &mut fmt::Formatter, mut part: &str) -> fmt::Result {
+    // Components that would start with a digit or `$` get a leading underscore
+    if part.starts_with("_$") {
+        part = &part[1..];
+    }
+    while !part.is_empty() {
+        if let Some(rest) = part.strip_prefix("..") {
+            f.write_str("::")?;
+            part = rest;
+        } else if let Some(rest) = part.strip_prefix('$')
+            && let Some(end) = rest.find('$')
+        {
+            let escape = &rest[..end];
+            match escape {
+                "SP" => f.write_str("@")?,
+                "BP" => f.write_str("*")?,
+                "RF" => f.write_str("&")?,
+                "LT" => f.write_str("<")?,
+                "GT" => f.write_str(">")?,
+                "LP" => f.write_str("(")?,
+                "RP" => f.write_str(")")?,
+                "C" => f.write_str(",")?,
+                _ => match escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => write!(f, "{}", c)?,
+                    None => write!(f, "${}$", escape)?,
+                },
+            }
+            part = &rest[end + 1..];
+        } else {
+            let end = part[1..]
+                .find(['$', '.'])
+                .map_or(part.len(), |index| index + 1);
+            f.write_str(&part[..end])?;
+            part = &part[end..];
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+struct Expect<'a> {
+    expected: &'a str,
+    matched: usize,
+    ok: bool,
+}
+
+#[cfg(test)]
+impl fmt::Write for Expect<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.ok &= self.expected[self.matched..].starts_with(s);
+        self.matched = (self.matched + s.len()).min(self.expected.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn demangles_to(mangled: &str, expected: &str) -> bool {
+    use fmt::Write;
+    let mut out = Expect {
+        expected,
+        matched: 0,
+        ok: true,
+    };
+    write!(out, "{}", Demangle(mangled)).unwrap();
+    out.ok && out.matched == expected.len()
+}
+
+#[test_case]
+fn test_demangle_legacy() {
+    assert!(demangles_to(
+        "_ZN11rust_kernel5timer7on_tick17h0123456789abcdefE",
+        "rust_kernel::timer::on_tick"
+    ));
+    assert!(demangles_to(
+        "_ZN70_$LT$alloc..vec..Vec$LT$T$C$A$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop17h0123456789abcdefE",
+        "<alloc::vec::Vec<T,A> as core::ops::drop::Drop>::drop"
+    ));
+    assert!(demangles_to("memcpy", "memcpy"));
+}
@@ -5,33 +5,94 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 #![feature(allocator_api)]
+#![feature(alloc_error_handler)]
 
+#[cfg(test)]
+use bootloader_api::config::{BootloaderConfig, Mapping};
 #[cfg(test)]
 use bootloader_api::{BootInfo, entry_point};
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+#[cfg(test)]
+entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
 
+use core::fmt;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 pub mod allocator;
 pub mod apic_ptr;
+pub mod backtrace;
+pub mod block;
+pub mod cmos;
+pub mod config;
+pub mod console;
+pub mod cpu;
+pub mod crashdump;
+pub mod error;
+pub mod fault;
 pub mod framebuffer;
+pub mod fs;
+pub mod fw_cfg;
 pub mod gdt;
 pub mod init;
 pub mod interrupts;
+pub mod ioport;
+pub mod irq;
 pub mod kernel_acpi;
+pub mod kexec;
+pub mod ksyms;
+pub mod log;
+pub mod mem;
 pub mod memory;
+pub mod metrics;
+pub mod panic_policy;
+pub mod pci;
+pub mod platform;
+pub mod power;
+pub mod process;
+pub mod profile;
+pub mod ps2;
+pub mod pvclock;
 pub mod serial;
 pub mod smp;
+pub mod speaker;
+pub mod stack;
+pub mod syscall;
 pub mod task;
+pub mod time;
 pub mod timer;
+pub mod trap;
+pub mod tty;
+pub mod vbe;
+pub mod vdso;
 pub mod vga_buffer;
+pub mod virtio;
+pub mod watchdog;
 
 extern crate alloc;
 
+/// Tests [`test_runner`] can shuffle. A binary with more runs them in order.
+const MAX_SHUFFLED_TESTS: usize = 256;
+/// Bytes of the command line the test runner reads.
+const TEST_CMDLINE_LEN: usize = 256;
+
+/// Tests passed and filtered out so far in the run, for the summary a failure prints.
+static TESTS_PASSED: AtomicUsize = AtomicUsize::new(0);
+static TESTS_FILTERED: AtomicUsize = AtomicUsize::new(0);
+/// Set while [`test_runner`] is running tests.
+static TESTS_RUNNING: AtomicBool = AtomicBool::new(false);
+
 pub trait Testable {
     fn run(&self) -> ();
+    /// The test's path, which `test.filter=` matches against.
+    fn name(&self) -> &'static str;
 }
 
 impl<T> Testable for T
@@ -39,25 +100,160 @@ where
     T: Fn(),
 {
     fn run(&self) {
-        serial_print!("{}.........", core::any::type_name::<T>());
+        serial_print!("{}.........", self.name());
+        let start = Stopwatch::start();
         self();
-        serial_println!("[OK]");
+        serial_println!("[OK] {}", start.elapsed());
+    }
+
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+}
+
+/// Times tests by the uptime clock, or in TSC cycles where there is none yet.
+#[derive(Debug, Clone, Copy)]
+struct Stopwatch {
+    ns: Option<u64>,
+    tsc: u64,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        Stopwatch {
+            ns: timer::uptime_ns(),
+            tsc: cpu::rdtsc(),
+        }
+    }
+
+    fn elapsed(&self) -> Elapsed {
+        match (self.ns, timer::uptime_ns()) {
+            (Some(start), Some(now)) => Elapsed::Us(now.saturating_sub(start) / 1000),
+            _ => Elapsed::Cycles(cpu::rdtsc().wrapping_sub(self.tsc)),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+enum Elapsed {
+    Us(u64),
+    Cycles(u64),
+}
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Elapsed::Us(us) => write!(f, "{}.{:03}ms", us / 1000, us % 1000),
+            Elapsed::Cycles(cycles) => write!(f, "{} cycles", cycles),
+        }
+    }
+}
+
+/// How [`test_runner`] runs the tests, from the kernel command line in fw_cfg (see
+/// [`fw_cfg::CMDLINE_FILE`]). `test.filter=TEXT` runs only the tests whose name contains
+/// `TEXT`. `test.shuffle` runs them in a random order and prints the seed, and `test.seed=N`
+/// repeats the order of seed `N`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct TestOptions<'a> {
+    filter: Option<&'a str>,
+    seed: Option<u64>,
+}
+
+impl<'a> TestOptions<'a> {
+    fn parse(cmdline: &'a str) -> Self {
+        let mut options = TestOptions::default();
+        for word in cmdline.split_whitespace() {
+            if let Some(filter) = word.strip_prefix("test.filter=") {
+                options.filter = Some(filter);
+            } else if let Some(seed) = word.strip_prefix("test.seed=") {
+                options.seed = seed.parse().ok();
+            } else if word == "test.shuffle" {
+                options.seed.get_or_insert_with(cpu::rdtsc);
+            }
+        }
+        options
+    }
+}
+
+/// Puts `order` in the order seed `seed` gives, by a Fisher-Yates shuffle driven by xorshift.
+fn shuffle(order: &mut [u16], seed: u64) {
+    // Xorshift never leaves zero
+    let mut state = if seed == 0 {
+        0x2545_F491_4F6C_DD1D
+    } else {
+        seed
+    };
+    for i in (1..order.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        order.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Runs `tests` as the command line's `test.` options say, timing each, and ends with a
+/// summary line in libtest's format for scripts to parse.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
-    for test in tests {
-        test.run();
+    let mut cmdline = [0u8; TEST_CMDLINE_LEN];
+    let len = fw_cfg::read_early(fw_cfg::CMDLINE_FILE, &mut cmdline).unwrap_or(0);
+    let cmdline = core::str::from_utf8(&cmdline[..len]).unwrap_or("");
+    let options = TestOptions::parse(cmdline.trim_end_matches(['\0', '\n']));
+
+    let mut order: [u16; MAX_SHUFFLED_TESTS] = core::array::from_fn(|i| i as u16);
+    let shuffled = match options.seed {
+        Some(seed) if tests.len() <= MAX_SHUFFLED_TESTS => {
+            serial_println!("Shuffling with test.seed={}", seed);
+            shuffle(&mut order[..tests.len()], seed);
+            true
+        }
+        Some(_) => {
+            serial_println!("Not shuffling more than {} tests", MAX_SHUFFLED_TESTS);
+            false
+        }
+        None => false,
+    };
+    let selected = |test: &&dyn Testable| options.filter.is_none_or(|f| test.name().contains(f));
+    let filtered = tests.iter().filter(|test| !selected(test)).count();
+    serial_println!(
+        "Running {} tests, {} filtered out",
+        tests.len() - filtered,
+        filtered
+    );
+
+    TESTS_FILTERED.store(filtered, Ordering::Relaxed);
+    TESTS_RUNNING.store(true, Ordering::Relaxed);
+    let start = Stopwatch::start();
+    for i in 0..tests.len() {
+        let test = tests[if shuffled { order[i] as usize } else { i }];
+        if selected(&test) {
+            test.run();
+            TESTS_PASSED.fetch_add(1, Ordering::Relaxed);
+        }
     }
+    TESTS_RUNNING.store(false, Ordering::Relaxed);
+    serial_println!(
+        "test result: ok. {} passed; 0 failed; 0 ignored; 0 measured; {} filtered out; finished in {}",
+        TESTS_PASSED.load(Ordering::Relaxed),
+        filtered,
+        start.elapsed()
+    );
     exit_qemu(QemuExitCode::Success);
+    // Only reached without the isa-debug-exit device, e.g. on real hardware
+    power::shutdown();
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    serial::force_unlock_and_print(format_args!("[failed]\n\nError: {}\n\n", info));
+    if TESTS_RUNNING.load(Ordering::Relaxed) {
+        serial::force_unlock_and_print(format_args!(
+            "test result: FAILED. {} passed; 1 failed; 0 ignored; 0 measured; {} filtered out\n",
+            TESTS_PASSED.load(Ordering::Relaxed),
+            TESTS_FILTERED.load(Ordering::Relaxed)
+        ));
+    }
+    speaker::alert();
     exit_qemu(QemuExitCode::Failed);
-    hlt_loop();
+    halt();
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -79,8 +275,10 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static mut BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
     init_gdt_idt();
+    // Tests allocate, so they run on the real heap rather than the bootstrap arena
+    init::memory_init::init_memory(boot_info).expect("memory initialization failed");
     test_main();
     hlt_loop();
 }
@@ -91,22 +289,67 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+/// Heap allocations that can't fail end up here once the heap has tried reclaiming memory.
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    allocator::oom::handle_alloc_error(layout)
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+#[test_case]
+fn test_runner_options_and_shuffle() {
+    let options = TestOptions::parse("quiet test.filter=allocator test.seed=42");
+    assert_eq!(options.filter, Some("allocator"));
+    assert_eq!(options.seed, Some(42));
+    assert_eq!(TestOptions::parse("quiet"), TestOptions::default());
+    // An explicit seed wins over a random one
+    assert_eq!(TestOptions::parse("test.shuffle test.seed=7").seed, Some(7));
+
+    let mut first: [u16; 10] = core::array::from_fn(|i| i as u16);
+    let mut again = first;
+    shuffle(&mut first, 42);
+    shuffle(&mut again, 42);
+    assert_eq!(first, again);
+    let mut sorted = first;
+    sorted.sort_unstable();
+    assert!(
+        sorted
+            .iter()
+            .enumerate()
+            .all(|(i, &index)| index as usize == i)
+    );
+}
+
 /// This function does several things. Firstly, it sets up the GDT (Global Descriptor Table).
 /// After that, it initializes the IDT (Interrupt Descriptor Table). Then, it initializes the PICs (Programmable Interrupt Controllers).
 /// Finally, it intializes the PIC and enables interrupts.
+/// It also enables the SYSCALL instruction, which depends on the GDT layout, and programs the
+/// page attribute table.
 pub fn init_gdt_idt() {
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
+    cpu::pat::init();
+    cpu::fpu::init();
 }
 
 // A wrapper for the `hlt` instruction that loops until an interrupt is received
 // This is used to halt the CPU until the next interrupt is fired. If this wasn't done, the CPU would be running at 100% utilization, all the time.
+// Uses MWAIT instead of `hlt` when the CPU supports it, see `cpu::idle`.
 pub fn hlt_loop() -> ! {
+    loop {
+        cpu::idle::idle();
+    }
+}
+
+/// Halts the CPU for good with plain `hlt`. Unlike [`hlt_loop`] it touches no per-CPU state,
+/// so the panic and shutdown paths can end in it even on a CPU that isn't set up, or whose
+/// per-CPU state is what panicked.
+pub fn halt() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
@@ -115,7 +358,7 @@ pub fn hlt_loop() -> ! {
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
-        $crate::framebuffer::_print(format_args!($($arg)*))
+        $crate::console::_print(format_args!($($arg)*))
     };
 }
 
@@ -0,0 +1,346 @@
+//! Leveled kernel log messages, fanned out to the serial port and the screen.
+//!
+//! Each sink has its own level filter, so chatty diagnostics can go to serial only while the
+//! screen shows warnings and errors. The filters default to [`DEFAULT_SERIAL_LEVEL`] and
+//! [`DEFAULT_SCREEN_LEVEL`] and can be set on the kernel command line with
+//! `log.serial=LEVEL` and `log.screen=LEVEL`, where `LEVEL` is a [`Level`] name or `off`.
+//!
+//! Log with [`error!`](crate::error), [`warn!`](crate::warn), [`info!`](crate::info),
+//! [`debug!`](crate::debug) and [`trace!`](crate::trace). Code that can log at interrupt rates
+//! uses [`log_ratelimited!`](crate::log_ratelimited) instead, which drops messages from a call
+//! site beyond a burst and counts them into the next one it lets through.
+//!
+//! The last [`HISTORY_BYTES`] of logged messages are also kept in memory, for the `dmesg`
+//! monitor command and for crash dumps.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "[ERROR]",
+            Level::Warn => "[WARN]",
+            Level::Info => "[INFO]",
+            Level::Debug => "[DEBUG]",
+            Level::Trace => "[TRACE]",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Serial,
+    Screen,
+}
+
+pub const DEFAULT_SERIAL_LEVEL: Level = Level::Info;
+pub const DEFAULT_SCREEN_LEVEL: Level = Level::Warn;
+
+/// The most verbose level each sink takes, as a `Level` value, or 0 for none.
+static SERIAL_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_SERIAL_LEVEL as u8);
+static SCREEN_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_SCREEN_LEVEL as u8);
+
+fn filter(sink: Sink) -> &'static AtomicU8 {
+    match sink {
+        Sink::Serial => &SERIAL_LEVEL,
+        Sink::Screen => &SCREEN_LEVEL,
+    }
+}
+
+/// Makes `sink` take messages up to `level`, or none at all for `None`.
+pub fn set_level(sink: Sink, level: Option<Level>) {
+    filter(sink).store(level.map_or(0, |level| level as u8), Ordering::Relaxed);
+}
+
+/// The most verbose level `sink` takes, or `None` if it is off.
+pub fn level(sink: Sink) -> Option<Level> {
+    let value = filter(sink).load(Ordering::Relaxed);
+    Level::ALL.into_iter().find(|&level| level as u8 == value)
+}
+
+pub fn enabled(sink: Sink, level: Level) -> bool {
+    level as u8 <= filter(sink).load(Ordering::Relaxed)
+}
+
+/// Parses a level name, or `off`. Returns `None` for anything else.
+pub fn parse_level(name: &str) -> Option<Option<Level>> {
+    if name == "off" {
+        return Some(None);
+    }
+    Level::ALL
+        .into_iter()
+        .find(|level| level.name() == name)
+        .map(Some)
+}
+
+/// Applies the `log.serial=` and `log.screen=` options in `cmdline`.
+pub fn configure(cmdline: &str) {
+    for word in cmdline.split_whitespace() {
+        let (sink, value) = if let Some(value) = word.strip_prefix("log.serial=") {
+            (Sink::Serial, value)
+        } else if let Some(value) = word.strip_prefix("log.screen=") {
+            (Sink::Screen, value)
+        } else {
+            continue;
+        };
+        match parse_level(value) {
+            Some(level) => set_level(sink, level),
+            None => crate::warn!("log: unknown level '{}' in '{}'", value, word),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    let serial = enabled(Sink::Serial, level);
+    let screen = enabled(Sink::Screen, level);
+    if !serial && !screen {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if serial {
+            crate::serial::write_port(format_args!("{} {}\n", level.tag(), args));
+        }
+        if screen {
+            crate::console::write(format_args!("{} {}\n", level.tag(), args));
+        }
+        crate::virtio::console::mirror(format_args!("{} {}\n", level.tag(), args));
+        // Skipped while the history is being read, which may log, e.g. from the allocator
+        if let Some(mut history) = HISTORY.try_lock() {
+            let _ = writeln!(history, "{} {}", level.tag(), args);
+        }
+    });
+}
+
+/// Bytes of recent messages kept by [`with_history`], whichever sinks they went to.
+pub const HISTORY_BYTES: usize = 8192;
+
+/// A ring of the most recent log output.
+struct History {
+    buf: [u8; HISTORY_BYTES],
+    /// Bytes ever written; the next goes at `written % HISTORY_BYTES`.
+    written: usize,
+}
+
+impl fmt::Write for History {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[self.written % HISTORY_BYTES] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    buf: [0; HISTORY_BYTES],
+    written: 0,
+});
+
+/// Calls `f` with the kept log output, oldest first, in two pieces because the history wraps
+/// around. The first starts at a whole message. Returns `None` without calling `f` if the
+/// history is locked, as it stays if a CPU panicked while logging.
+pub fn with_history<R>(f: impl FnOnce(&[u8], &[u8]) -> R) -> Option<R> {
+    let history = HISTORY.try_lock()?;
+    if history.written <= HISTORY_BYTES {
+        return Some(f(&history.buf[..history.written], &[]));
+    }
+    let end = history.written % HISTORY_BYTES;
+    // The oldest message was partly overwritten
+    let older = &history.buf[end..];
+    let start = older
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(older.len(), |i| i + 1);
+    Some(f(&older[start..], &history.buf[..end]))
+}
+
+/// Messages a [`log_ratelimited!`](crate::log_ratelimited) call site logs before it is limited.
+pub const RATELIMIT_BURST: u32 = 10;
+/// Timer ticks after which a limited call site may log one more message: half a second.
+pub const RATELIMIT_INTERVAL_TICKS: u64 = 50;
+
+/// A token bucket for one call site: it starts with `burst` tokens, each message takes one,
+/// and one comes back every `interval_ticks` timer ticks, up to `burst`.
+pub struct RateLimit {
+    burst: u32,
+    interval_ticks: u64,
+    tokens: AtomicU32,
+    /// The tick the tokens were last topped up at.
+    refilled: AtomicU64,
+    /// Messages dropped since the last one logged.
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32, interval_ticks: u64) -> Self {
+        RateLimit {
+            burst,
+            interval_ticks,
+            tokens: AtomicU32::new(burst),
+            refilled: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token if there is one at tick `now`. Doesn't lock, so it works in interrupt
+    /// handlers.
+    fn allow(&self, now: u64) -> bool {
+        let refilled = self.refilled.load(Ordering::Relaxed);
+        let earned = now.saturating_sub(refilled) / self.interval_ticks;
+        // Whoever moves `refilled` on hands out the tokens earned meanwhile
+        if earned > 0
+            && self
+                .refilled
+                .compare_exchange(
+                    refilled,
+                    refilled + earned * self.interval_ticks,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            let _ = self
+                .tokens
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                    Some((tokens as u64 + earned).min(self.burst as u64) as u32)
+                });
+        }
+        self.tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                tokens.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+#[doc(hidden)]
+pub fn _log_ratelimited(limit: &RateLimit, level: Level, args: fmt::Arguments) {
+    if !enabled(Sink::Serial, level) && !enabled(Sink::Screen, level) {
+        return;
+    }
+    if !limit.allow(crate::timer::ticks()) {
+        limit.suppressed.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    match limit.suppressed.swap(0, Ordering::Relaxed) {
+        0 => _log(level, args),
+        suppressed => _log(
+            level,
+            format_args!("{} ({} more suppressed)", args, suppressed),
+        ),
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::_log($level, format_args!($($arg)*))
+    };
+}
+
+/// Like [`log!`](crate::log), but each call site logs at most
+/// [`RATELIMIT_BURST`](crate::log::RATELIMIT_BURST) messages in a row and then one per
+/// [`RATELIMIT_INTERVAL_TICKS`](crate::log::RATELIMIT_INTERVAL_TICKS). The next message logged
+/// says how many were dropped.
+#[macro_export]
+macro_rules! log_ratelimited {
+    ($level:expr, $($arg:tt)*) => {{
+        static LIMIT: $crate::log::RateLimit = $crate::log::RateLimit::new(
+            $crate::log::RATELIMIT_BURST,
+            $crate::log::RATELIMIT_INTERVAL_TICKS,
+        );
+        $crate::log::_log_ratelimited(&LIMIT, $level, format_args!($($arg)*))
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[test_case]
+fn test_parse_level() {
+    assert_eq!(parse_level("trace"), Some(Some(Level::Trace)));
+    assert_eq!(parse_level("off"), Some(None));
+    assert_eq!(parse_level("loud"), None);
+}
+
+#[test_case]
+fn test_rate_limit_refills_up_to_the_burst() {
+    let limit = RateLimit::new(3, 10);
+    assert!((0..3).all(|_| limit.allow(5)));
+    assert!(!limit.allow(9));
+    // Two intervals earn two tokens, and the third interval is only half over
+    assert!(limit.allow(25));
+    assert!(limit.allow(25));
+    assert!(!limit.allow(25));
+    // A long quiet spell earns no more than the burst
+    assert!((0..3).all(|_| limit.allow(1_000)));
+    assert!(!limit.allow(1_000));
+}
+
+#[test_case]
+fn test_configure_sets_each_sink() {
+    configure("quiet log.serial=trace log.screen=off");
+    assert_eq!(level(Sink::Serial), Some(Level::Trace));
+    assert_eq!(level(Sink::Screen), None);
+    assert!(enabled(Sink::Serial, Level::Trace));
+    assert!(!enabled(Sink::Screen, Level::Error));
+
+    set_level(Sink::Serial, Some(DEFAULT_SERIAL_LEVEL));
+    set_level(Sink::Screen, Some(DEFAULT_SCREEN_LEVEL));
+    assert!(!enabled(Sink::Serial, Level::Debug));
+    assert!(enabled(Sink::Screen, Level::Warn));
+}
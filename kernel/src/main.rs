@@ -4,18 +4,26 @@
 #![test_runner(rust_kernel::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use acpi::HpetInfo;
 use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{BootInfo, entry_point};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 use rust_kernel::apic_ptr::APIC_BASE;
+use rust_kernel::error::KernelError;
+use rust_kernel::fw_cfg;
 use rust_kernel::init::hpet::init_hpet;
 use rust_kernel::init::multicore::{init_smp, init_stack_top, remap_trampoline_uncacheable};
-use rust_kernel::init::{self, graphics, memory_init};
-use rust_kernel::smp::trampoline;
+use rust_kernel::init::{self, Boot, Stage, graphics, memory_init};
+use rust_kernel::interrupts::{PIT_TICK_HZ, init_pic_mode};
+use rust_kernel::panic_policy::Policy;
 use rust_kernel::task::executor::Executor;
-use rust_kernel::task::{Task, keyboard};
-use rust_kernel::{println, serial_println};
+use rust_kernel::task::{Task, keyboard, monitor, sched};
+use rust_kernel::{
+    QemuExitCode, backtrace, console, cpu, crashdump, exit_qemu, fs, kexec, ksyms, memory, metrics,
+    panic_policy, pci, platform, power, process, profile, pvclock, smp, stack, time, tty, vdso,
+    watchdog,
+};
+use rust_kernel::{info, log, println, serial_println, warn};
 extern crate alloc;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
@@ -26,50 +34,220 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
+/// Set by `test` on the command line, as `cargo run -- test` passes it: the kernel exits QEMU
+/// once initialisation is done, or with a failure if it panics first.
+static BOOT_TEST: AtomicBool = AtomicBool::new(false);
+
+/// Uses the PIC and PIT even when there is an APIC, so the legacy path can be exercised on
+/// any machine.
+const FLAG_NO_APIC: &str = "nolapic";
+/// Samples where time goes during initialisation and prints a profile over serial at the end.
+const FLAG_PROFILE: &str = "profile";
+/// Leaves the watchdog off, e.g. while stepping through the kernel in a debugger.
+const FLAG_NO_WATCHDOG: &str = "nowatchdog";
+/// Functions listed in the boot profile.
+const BOOT_PROFILE_TOP: usize = 30;
+/// The first process, unless `init=` on the command line names another program.
+const DEFAULT_INIT: &str = "/bin/init";
+
 #[unsafe(no_mangle)]
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    console::early::init(boot_info);
     rust_kernel::init_gdt_idt();
 
-    graphics::init_framebuffer(boot_info);
+    let mut boot = Boot::new();
+
+    boot.stage("framebuffer", || graphics::init_framebuffer(boot_info));
+
+    boot.require("memory", || memory_init::init_memory(boot_info));
+    stack::adopt_current(BOOTLOADER_CONFIG.kernel_stack_size);
+    kexec::init(boot_info);
+    fs::initrd::init(boot_info);
 
-    memory_init::init_memory(boot_info);
+    boot.initcalls(Stage::Early);
+
+    let cmdline = fw_cfg::cmdline().unwrap_or_default();
+    if !cmdline.is_empty() {
+        info!("Command line: {}", cmdline);
+    }
+    log::configure(&cmdline);
+    let has_flag = |flag: &str| cmdline.split_whitespace().any(|word| word == flag);
+    BOOT_TEST.store(has_flag("test"), Ordering::Relaxed);
+    panic_policy::configure(&cmdline);
+    crashdump::configure(&cmdline);
+    if has_flag("test") {
+        panic_policy::set(Policy::ExitQemu);
+    }
+
+    let symbols = ksyms::init(boot_info);
+    info!("{} kernel symbols", symbols);
+    let unwind_entries = backtrace::eh_frame::init(boot_info);
+    info!("{} functions with unwind information", unwind_entries);
+    // Samples start once a timer is ticking, so this covers the rest of initialisation
+    let profile_boot = has_flag(FLAG_PROFILE);
+    if profile_boot {
+        profile::start();
+    }
 
     serial_println!(
         "Physical memory offset: {:#?}",
         boot_info.physical_memory_offset
     );
 
-    let (tables, platform_info) = init::acpi::init_acpi(boot_info);
+    // Subsystems the machine can't support are skipped and reported rather than fatal
+    let acpi = boot.stage("ACPI", || init::acpi::init_acpi(boot_info));
+    let tables = acpi.as_ref().map(|(tables, _)| tables);
+    let registry = acpi.as_ref().map(|&(_, registry)| registry);
+    let platform_info = registry.map(|registry| registry.platform());
 
-    init::apic::init_apic(&platform_info);
+    if let Some(tables) = tables {
+        power::init(tables);
+    }
+
+    platform::init(platform_info);
+
+    let ecam_ranges = pci::init_ecam();
+    if ecam_ranges > 0 {
+        info!("PCI: using ECAM for {} bus ranges", ecam_ranges);
+    }
+
+    let apic = boot.stage("APIC", || {
+        if has_flag(FLAG_NO_APIC) {
+            return Err(KernelError::Disabled {
+                subsystem: "APIC",
+                flag: FLAG_NO_APIC,
+            });
+        }
+        let platform_info = platform_info.ok_or(KernelError::NoAcpi)?;
+        init::apic::init_apic(platform_info)
+    });
+    if apic.is_none() {
+        warn!("Using the PIC and PIT at {} Hz", PIT_TICK_HZ);
+        init_pic_mode();
+    }
+
+    let hpet = boot.stage("HPET", || {
+        let registry = registry.ok_or(KernelError::NoAcpi)?;
+        let hpet_info = registry.hpet().map_err(KernelError::HpetMissing)?;
+        init_hpet(hpet_info)
+    });
+    if let Some(hypervisor) = cpu::hypervisor::get() {
+        info!("Running under {}", hypervisor.name());
+    }
+    let pvclock = pvclock::init();
+    if let Some(source) = pvclock {
+        info!("Timekeeping on {}", source.name());
+    }
+    if hpet.is_some() || pvclock.is_some() {
+        time::init();
+    }
+    if apic.is_some() && hpet.is_some() {
+        init::apic::calibrate_timer();
+    }
 
-    if let Ok(hpet_info) = HpetInfo::new(&tables) {
-        init_hpet(&hpet_info);
+    let freq = cpu::freq::init();
+    println!(
+        "CPU frequency: base {} MHz, max {:?} MHz, TSC {:?} MHz",
+        freq.base_mhz, freq.max_mhz, freq.tsc_mhz
+    );
+    vdso::init();
+    if vdso::is_active() {
+        info!("vDSO: clock_gettime reads the TSC in user space");
     }
 
     x86_64::instructions::interrupts::enable();
 
-    unsafe {
-        //unmapped - sort out mapping?
-        remap_trampoline_uncacheable();
-        trampoline::load_ap_trampoline();
-        init_stack_top();
-        if let Some(i) = platform_info.processor_info {
-            init_smp(APIC_BASE.expect("BSP APIC uninitalized!").as_ptr(), &i);
+    boot.initcalls(Stage::Device);
+
+    // Starting the APs needs their APIC IDs, IPIs, and the HPET to time the INIT/SIPI sequence
+    let aps = boot.stage("SMP", || {
+        let processor_info = platform_info
+            .and_then(|info| info.processor_info.as_ref())
+            .ok_or(KernelError::SmpUnavailable("an ACPI processor list"))?;
+        let apic_base = unsafe { APIC_BASE }.ok_or(KernelError::SmpUnavailable("the APIC"))?;
+        hpet.ok_or(KernelError::SmpUnavailable("the HPET"))?;
+        unsafe {
+            //unmapped - sort out mapping?
+            remap_trampoline_uncacheable()?;
+            init_stack_top();
+            init_smp(apic_base.as_ptr(), processor_info)
         }
+    });
+    if aps.is_some() {
+        smp::tsc::measure();
     }
 
-    println!("All initialization steps completed successfully!");
+    // Everything needed from the ACPI tables has been copied out by now, and what other
+    // subsystems need later is in the registry
+    if let Some(frames) = boot.stage("ACPI reclaim", || {
+        memory_init::reclaim_acpi_memory(boot_info)
+    }) {
+        info!("Reclaimed {} KiB of ACPI memory", frames * 4);
+    }
+
+    boot.initcalls(Stage::Late);
+
+    if boot.failures().is_empty() {
+        println!("All initialization steps completed successfully!");
+    } else {
+        warn!("Initialization completed with some subsystems disabled:");
+        for (stage, e) in boot.failures() {
+            warn!("  {} ({})", stage, e);
+        }
+    }
+
+    if profile_boot {
+        profile::stop();
+        profile::dump(BOOT_PROFILE_TOP);
+    }
+
+    if BOOT_TEST.load(Ordering::Relaxed) {
+        metrics::dump_serial();
+        exit_qemu(QemuExitCode::Success);
+    }
 
     #[cfg(test)]
     test_main();
 
+    panic_policy::boot_succeeded();
+    if !has_flag(FLAG_NO_WATCHDOG) {
+        watchdog::start();
+    }
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::named("console", console::render::run()));
+    executor.spawn(Task::named("cursor", console::blink_cursor()));
+    executor.spawn(Task::named("example", example_task()));
+    executor.spawn(Task::named("keyboard", keyboard::dispatch_keypresses()));
+    executor.spawn(Task::named("monitor", monitor::run()));
+    executor.spawn(Task::named("keypresses", keyboard::print_keypresses()));
+    executor.spawn(Task::named("tty", tty::run()));
+    executor.spawn(Task::named("scrub", memory::zeroed::scrub()));
+    executor.spawn(Task::named("sched", sched::run()));
+    start_init(&cmdline);
+    executor.spawn(Task::named("processes", process::scheduler::serve()));
     executor.run();
 }
 
+/// Starts the program `init=` names, or [`DEFAULT_INIT`], as the first process, with the
+/// console for its standard input and output.
+fn start_init(cmdline: &str) {
+    let path = cmdline
+        .split_whitespace()
+        .find_map(|word| word.strip_prefix("init="))
+        .unwrap_or(DEFAULT_INIT);
+    let image = match fs::read_all(path) {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("init: can't read {}: {:?}", path, e);
+            return;
+        }
+    };
+    match process::spawn("init", &image) {
+        Ok(pid) => info!("Started {} as PID {}", path, pid.0),
+        Err(e) => warn!("init: can't start {}: {:?}", path, e),
+    }
+}
+
 async fn async_number() -> u32 {
     42
 }
@@ -82,8 +260,11 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    console::panic_flush();
     println!("{}", info);
-    rust_kernel::hlt_loop();
+    rust_kernel::serial::force_unlock_and_print(format_args!("{}\n", info));
+    crashdump::on_panic(info);
+    panic_policy::finish();
 }
 
 #[cfg(test)]
@@ -0,0 +1,225 @@
+//! Copying and filling memory in bulk, with the string instructions the CPU does best.
+//!
+//! The kernel is built without SSE, so `copy_from_slice` and friends end up in the byte-wise
+//! `memcpy` of `compiler_builtins`. [`copy`] and [`fill`] use `rep movsb` and `rep stosb`
+//! instead on CPUs with enhanced REP MOVSB/STOSB (ERMS), and the quadword forms otherwise.
+//! [`zero_pages`], [`stream`], [`stream_within`] and [`stream_fill`] write with `movnti`, which
+//! bypasses the caches: for whole pages that are handed out zeroed and for the framebuffer,
+//! nothing is gained by evicting everything else to keep them cached. `movnti` works on
+//! general-purpose registers, so none of this needs [`crate::cpu::fpu::kernel_fpu_begin`].
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ops::Range;
+
+use spin::Once;
+
+use crate::memory::PAGE_SIZE;
+
+const CPUID_7_EBX_ERMS: u32 = 1 << 9;
+
+static ERMS: Once<bool> = Once::new();
+
+/// Whether the CPU has enhanced REP MOVSB/STOSB, which makes the byte forms the fastest.
+fn erms() -> bool {
+    *ERMS.call_once(|| __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & CPUID_7_EBX_ERMS != 0)
+}
+
+/// Copies `len` bytes from `src` to `dst`.
+///
+/// # Safety
+///
+/// `src` must be valid for `len` bytes of reads and `dst` for `len` bytes of writes, and the
+/// two must not overlap.
+pub unsafe fn copy_raw(dst: *mut u8, src: *const u8, len: usize) {
+    unsafe {
+        if erms() {
+            asm!("rep movsb", inout("rcx") len => _, inout("rdi") dst => _,
+                inout("rsi") src => _, options(nostack, preserves_flags));
+        } else {
+            asm!("rep movsq", "mov ecx, {tail:e}", "rep movsb", tail = in(reg) len % 8,
+                inout("rcx") len / 8 => _, inout("rdi") dst => _, inout("rsi") src => _,
+                options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Copies `src` into `dst`, which must be the same length.
+pub fn copy(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(
+        dst.len(),
+        src.len(),
+        "copy between slices of different lengths"
+    );
+    unsafe { copy_raw(dst.as_mut_ptr(), src.as_ptr(), dst.len()) };
+}
+
+/// Sets every byte of `dst` to `byte`.
+pub fn fill(dst: &mut [u8], byte: u8) {
+    let (ptr, len) = (dst.as_mut_ptr(), dst.len());
+    unsafe {
+        if erms() {
+            asm!("rep stosb", inout("rcx") len => _, inout("rdi") ptr => _, in("al") byte,
+                options(nostack, preserves_flags));
+        } else {
+            let word = u64::from_ne_bytes([byte; 8]);
+            asm!("rep stosq", "mov ecx, {tail:e}", "rep stosb", tail = in(reg) len % 8,
+                inout("rcx") len / 8 => _, inout("rdi") ptr => _, in("rax") word,
+                options(nostack, preserves_flags));
+        }
+    }
+}
+
+/// Stores `word` at `dst` past the caches. Callers finish with [`store_fence`].
+unsafe fn store_nt(dst: *mut u64, word: u64) {
+    unsafe {
+        asm!("movnti [{}], {}", in(reg) dst, in(reg) word, options(nostack, preserves_flags))
+    };
+}
+
+/// Orders the non-temporal stores before it ahead of any later store, since they aren't
+/// ordered like normal ones.
+fn store_fence() {
+    unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Zeroes `pages` whole pages at `page`, without pulling them into the caches.
+///
+/// # Safety
+///
+/// `page` must be page aligned and valid for writes of `pages` pages.
+pub unsafe fn zero_pages(page: *mut u8, pages: usize) {
+    debug_assert!((page as u64).is_multiple_of(PAGE_SIZE));
+    let words = page.cast::<u64>();
+    for i in 0..pages * PAGE_SIZE as usize / 8 {
+        unsafe { store_nt(words.add(i), 0) };
+    }
+    store_fence();
+}
+
+/// Copies `len` bytes from `src` to `dst` front to back, storing past the caches. `dst` may
+/// overlap `src` if it is below it.
+unsafe fn stream_raw(dst: *mut u8, src: *const u8, len: usize) {
+    let head = dst.align_offset(8).min(len);
+    unsafe {
+        for i in 0..head {
+            dst.add(i).write(src.add(i).read());
+        }
+        let words = (len - head) / 8;
+        for i in 0..words {
+            let at = head + i * 8;
+            store_nt(
+                dst.add(at).cast(),
+                src.add(at).cast::<u64>().read_unaligned(),
+            );
+        }
+        for i in head + words * 8..len {
+            dst.add(i).write(src.add(i).read());
+        }
+    }
+    store_fence();
+}
+
+/// Copies `src` into `dst`, which must be the same length, storing past the caches, e.g. to
+/// put an image on the framebuffer.
+pub fn stream(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(
+        dst.len(),
+        src.len(),
+        "copy between slices of different lengths"
+    );
+    unsafe { stream_raw(dst.as_mut_ptr(), src.as_ptr(), dst.len()) };
+}
+
+/// Moves the bytes in `src` down to `dest` within `buf`, storing past the caches, e.g. to
+/// scroll the framebuffer.
+pub fn stream_within(buf: &mut [u8], src: Range<usize>, dest: usize) {
+    assert!(
+        src.start <= src.end && src.end <= buf.len(),
+        "source out of bounds"
+    );
+    assert!(dest <= src.start, "stream_within only moves bytes down");
+    let ptr = buf.as_mut_ptr();
+    unsafe { stream_raw(ptr.add(dest), ptr.add(src.start), src.len()) };
+}
+
+/// Fills `dst` with `pattern` repeated, from its first byte, storing past the caches, e.g. to
+/// clear the framebuffer to a color. `pattern` may be at most 8 bytes long.
+pub fn stream_fill(dst: &mut [u8], pattern: &[u8]) {
+    assert!(
+        (1..=8).contains(&pattern.len()),
+        "fill patterns are 1 to 8 bytes"
+    );
+    let byte_at = |i: usize| pattern[i % pattern.len()];
+    let head = dst.as_ptr().align_offset(8).min(dst.len());
+    for (i, byte) in dst[..head].iter_mut().enumerate() {
+        *byte = byte_at(i);
+    }
+    // Aligned words repeat every `pattern.len()` words at most
+    let mut words = [0u64; 8];
+    for (k, word) in words.iter_mut().enumerate() {
+        *word = u64::from_ne_bytes(core::array::from_fn(|i| byte_at(head + k * 8 + i)));
+    }
+    let body = (dst.len() - head) / 8;
+    let ptr = dst[head..].as_mut_ptr().cast::<u64>();
+    for i in 0..body {
+        unsafe { store_nt(ptr.add(i), words[i % pattern.len()]) };
+    }
+    store_fence();
+    let tail = head + body * 8;
+    for (i, byte) in dst[tail..].iter_mut().enumerate() {
+        *byte = byte_at(tail + i);
+    }
+}
+
+#[test_case]
+fn test_copy_and_fill() {
+    let src: [u8; 37] = core::array::from_fn(|i| i as u8);
+    // Every misalignment of the destination, and lengths with and without a tail
+    for offset in 0..8 {
+        for len in [0, 5, 8, 29] {
+            let mut dst = [0xAAu8; 40];
+            copy(&mut dst[offset..offset + len], &src[..len]);
+            assert_eq!(&dst[offset..offset + len], &src[..len]);
+            assert!(dst[offset + len..].iter().all(|&b| b == 0xAA));
+
+            let mut dst = [0xAAu8; 40];
+            stream(&mut dst[offset..offset + len], &src[..len]);
+            assert_eq!(&dst[offset..offset + len], &src[..len]);
+
+            fill(&mut dst[offset..offset + len], 0x5C);
+            assert!(dst[offset..offset + len].iter().all(|&b| b == 0x5C));
+            assert!(dst[..offset].iter().all(|&b| b == 0xAA));
+        }
+    }
+}
+
+#[test_case]
+fn test_stream_fill_and_within() {
+    let mut buf = [0u8; 53];
+    stream_fill(&mut buf[1..], &[1, 2, 3]);
+    assert_eq!(buf[0], 0);
+    for (i, &byte) in buf[1..].iter().enumerate() {
+        assert_eq!(byte, [1, 2, 3][i % 3]);
+    }
+
+    let mut buf: [u8; 53] = core::array::from_fn(|i| i as u8);
+    stream_within(&mut buf, 3..53, 0);
+    assert!(buf[..50].iter().enumerate().all(|(i, &b)| b == i as u8 + 3));
+}
+
+#[test_case]
+fn test_zero_pages() {
+    use alloc::alloc::{Layout, alloc, dealloc};
+
+    let len = 2 * PAGE_SIZE as usize;
+    let layout = Layout::from_size_align(len, PAGE_SIZE as usize).unwrap();
+    unsafe {
+        let pages = alloc(layout);
+        assert!(!pages.is_null());
+        let bytes = core::slice::from_raw_parts_mut(pages, len);
+        fill(bytes, 0xFF);
+        zero_pages(pages, 2);
+        assert!(bytes.iter().all(|&b| b == 0));
+        dealloc(pages, layout);
+    }
+}
@@ -1,17 +1,33 @@
+pub mod phys;
+pub mod report;
+pub mod vma;
+pub mod zeroed;
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::u64;
 
 use x86_64::{
     VirtAddr,
-    structures::paging::{FrameDeallocator, OffsetPageTable, PageTable},
+    instructions::interrupts,
+    structures::paging::{
+        FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, page_table::PageTableEntry,
+    },
 };
 
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
 
 use bitvec::prelude::*;
+use kernel_algo::{bitmap::first_free, range::ranges_intersect};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, Once};
 
+use crate::cpu::{MAX_CPUS, PerCpu};
+use crate::fault::{self, Site};
 use crate::serial_println;
+use crate::smp::trampoline::{TRAMPOLINE_BASE, TRAMPOLINE_LIMIT};
+
+pub use phys::{PhysMapError, map_mmio, map_physical, phys_range_to_virt, phys_to_virt};
 
 pub const PAGE_SIZE: u64 = 4096;
 
@@ -23,184 +39,611 @@ lazy_static! {
     pub static ref MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
 }
 
-pub struct BitmapFrameAllocator<'a> {
-    base_addr: u64,
-    frame_count: usize,
-    bitmap: Mutex<&'a mut BitSlice<u8, Lsb0>>,
+/// Physical memory from 1 MiB to 16 MiB has always been kept out of the frame allocator.
+const LOW_RESERVED: AddressRange = AddressRange {
+    start: 0x100000,
+    end: 0x1000000,
+};
+
+/// The first page holds the real-mode interrupt vector table (0x0..0x400) and the BIOS data
+/// area (0x400..0x500), which real-mode code expects to find intact.
+const REAL_MODE_DATA: AddressRange = AddressRange {
+    start: 0x0,
+    end: 0x1000,
+};
+
+/// The pages below 1 MiB that [`BitmapFrameAllocator::init`] keeps out of the allocator
+/// before it hands out anything, for real-mode code and the firmware data it relies on.
+#[derive(Debug, Clone, Copy)]
+pub struct LowMemory {
+    /// The page holding the interrupt vector table and the BIOS data area.
+    pub real_mode_data: PhysFrame,
+    /// The page the AP trampoline runs from, or `None` if no free page was found for it.
+    pub trampoline: Option<PhysAddr>,
 }
 
-impl<'a> BitmapFrameAllocator<'a> {
-    pub unsafe fn init(memory_map: &MemoryRegions, offset: u64) -> Self {
-        // 1) Print out the memory map for debugging
-        for region in memory_map.iter() {
-            serial_println!(
-                "Region: start={:#x}, end={:#x}, type={:?}",
-                region.start,
-                region.end,
-                region.kind
-            );
-        }
+static LOW_MEMORY: Once<LowMemory> = Once::new();
 
-        // 2) Find the maximum physical address in all "Usable" regions
+/// The low-memory reservations, once the frame allocator has been built.
+pub fn low_memory() -> Option<&'static LowMemory> {
+    LOW_MEMORY.get()
+}
 
-        let mut max_addr = 0;
-        for region in memory_map.iter() {
-            if region.kind == MemoryRegionKind::Usable {
-                if region.end > max_addr {
-                    max_addr = region.end;
-                }
-            }
-        }
+static FRAME_BITMAP: Once<Range<u64>> = Once::new();
 
-        // 3) Convert max_addr -> max_frame, figure out how many frames we have in total
-        let max_frame = (max_addr + PAGE_SIZE - 1) / PAGE_SIZE;
-        let frame_count = max_frame as usize;
+/// The physical memory holding the frame allocator's bitmap, once it has been built.
+pub fn frame_bitmap() -> Option<Range<u64>> {
+    FRAME_BITMAP.get().cloned()
+}
 
-        // 4) Compute how many bytes our bitmap needs (1 bit per frame)
-        let bytes_needed = (frame_count + 7) / 8;
+/// What a region of the memory map holds. The bootloader passes the firmware's E820 or UEFI
+/// memory type through for regions that aren't usable and that it didn't use itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionType {
+    Usable,
+    /// In use by the bootloader: page tables, the boot info and the kernel itself.
+    Bootloader,
+    /// ACPI tables, which are ordinary RAM once the kernel has read them.
+    AcpiReclaimable,
+    /// Firmware state that must be left alone, including across sleep states.
+    AcpiNvs,
+    /// Memory the firmware found to be faulty.
+    Unusable,
+    /// Device memory, firmware code and data, and anything else the firmware keeps.
+    Reserved,
+}
 
-        const MAX_ILLEGAL: usize = 64;
-        static mut ILLEGAL_REGIONS: [AddressRange; MAX_ILLEGAL] =
-            [AddressRange { start: 0, end: 0 }; MAX_ILLEGAL];
+impl RegionType {
+    pub const ALL: [RegionType; 6] = [
+        RegionType::Usable,
+        RegionType::Bootloader,
+        RegionType::AcpiReclaimable,
+        RegionType::AcpiNvs,
+        RegionType::Unusable,
+        RegionType::Reserved,
+    ];
 
-        let mut count = 0;
-        unsafe {
-            ILLEGAL_REGIONS[count] = AddressRange {
-                start: 0x100000,
-                end: 0x1000000,
-            };
-            count += 1;
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionType::Usable => "usable",
+            RegionType::Bootloader => "bootloader",
+            RegionType::AcpiReclaimable => "ACPI reclaimable",
+            RegionType::AcpiNvs => "ACPI NVS",
+            RegionType::Unusable => "unusable",
+            RegionType::Reserved => "reserved",
         }
-        for region in memory_map.iter() {
-            if region.kind != MemoryRegionKind::Usable && count < MAX_ILLEGAL {
-                unsafe {
-                    ILLEGAL_REGIONS[count] = AddressRange {
-                        start: region.start,
-                        end: region.end,
-                    };
-                }
-                count += 1;
+    }
+
+    pub fn of(kind: MemoryRegionKind) -> Self {
+        match kind {
+            MemoryRegionKind::Usable => RegionType::Usable,
+            MemoryRegionKind::Bootloader => RegionType::Bootloader,
+            MemoryRegionKind::UnknownBios(3) | MemoryRegionKind::UnknownUefi(9) => {
+                RegionType::AcpiReclaimable
             }
+            MemoryRegionKind::UnknownBios(4) | MemoryRegionKind::UnknownUefi(10) => {
+                RegionType::AcpiNvs
+            }
+            MemoryRegionKind::UnknownBios(5) | MemoryRegionKind::UnknownUefi(8) => {
+                RegionType::Unusable
+            }
+            _ => RegionType::Reserved,
         }
+    }
+}
 
-        // 6) Find a single "Usable" region large enough to hold the bitmap without overlapping any "illegal" region
-        let mut region_base = None;
+/// How many ranges [`PhysMemoryMap::reserve`] can hold.
+const MAX_RESERVED: usize = 16;
 
-        'outer: for region in memory_map.iter() {
-            if region.kind == MemoryRegionKind::Usable {
-                //skip regions below 1MB
-                if region.end <= 0x100000 {
-                    continue;
-                }
+/// The physical frames a [`BitmapFrameAllocator`] may hand out: whole frames inside the
+/// usable regions of the bootloader's memory map, except where another region overlaps them
+/// or the kernel has reserved the range.
+pub struct PhysMemoryMap<'a> {
+    regions: &'a [MemoryRegion],
+    reserved: [AddressRange; MAX_RESERVED],
+    reserved_count: usize,
+}
 
-                let start = core::cmp::max(region.start, 0x100000);
-                let end = region.end;
-                let size = end - start;
+impl<'a> PhysMemoryMap<'a> {
+    pub fn new(regions: &'a [MemoryRegion]) -> Self {
+        PhysMemoryMap {
+            regions,
+            reserved: [AddressRange { start: 0, end: 0 }; MAX_RESERVED],
+            reserved_count: 0,
+        }
+    }
 
-                // If region can't fit the bitmap, skip it
-                if size < bytes_needed as u64 {
-                    continue;
-                }
+    /// Keeps `start..end` out of the allocator even where the map says it's usable.
+    pub fn reserve(mut self, start: u64, end: u64) -> Self {
+        assert!(
+            self.reserved_count < MAX_RESERVED,
+            "too many reserved physical ranges"
+        );
+        self.reserved[self.reserved_count] = AddressRange { start, end };
+        self.reserved_count += 1;
+        self
+    }
 
-                // Check if it intersects any illegal region
-                let local_illegal_regions = unsafe { ILLEGAL_REGIONS };
-                for off in &local_illegal_regions {
-                    if ranges_intersect(start, end, off.start, off.end) {
-                        // Overlaps something non-usable, skip
-                        continue 'outer;
-                    }
+    fn usable(&self) -> impl Iterator<Item = AddressRange> + '_ {
+        self.regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| AddressRange {
+                start: align_up(r.start),
+                end: align_down(r.end),
+            })
+            .filter(|r| r.start < r.end)
+    }
+
+    /// Ranges no frame may overlap: every region that isn't usable, and the reservations.
+    fn unavailable(&self) -> impl Iterator<Item = AddressRange> + '_ {
+        self.regions
+            .iter()
+            .filter(|r| r.kind != MemoryRegionKind::Usable)
+            .map(|r| AddressRange {
+                start: r.start,
+                end: r.end,
+            })
+            .chain(self.reserved[..self.reserved_count].iter().copied())
+    }
+
+    /// A whole page in `range` that is usable and not reserved: `preferred` if it is,
+    /// otherwise the lowest one.
+    pub fn free_page_in(&self, preferred: u64, range: Range<u64>) -> Option<u64> {
+        let is_free = |page: u64| {
+            let end = page + PAGE_SIZE;
+            range.start <= page
+                && end <= range.end
+                && self.usable().any(|r| r.start <= page && end <= r.end)
+                && !self
+                    .unavailable()
+                    .any(|off| ranges_intersect(page, end, off.start, off.end))
+        };
+        core::iter::once(align_down(preferred))
+            .chain((align_up(range.start)..range.end).step_by(PAGE_SIZE as usize))
+            .find(|&page| is_free(page))
+    }
+
+    /// The regions of type `ty`.
+    pub fn regions_of(&self, ty: RegionType) -> impl Iterator<Item = &MemoryRegion> + '_ {
+        self.regions
+            .iter()
+            .filter(move |r| RegionType::of(r.kind) == ty)
+    }
+
+    /// Whole frames in ACPI-reclaimable regions that nothing else claims, i.e. that may be
+    /// used once the ACPI tables have been read.
+    pub fn acpi_reclaimable(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.regions_of(RegionType::AcpiReclaimable)
+            .flat_map(|r| (align_up(r.start)..align_down(r.end)).step_by(PAGE_SIZE as usize))
+            .filter(|&addr| {
+                let claimed = self
+                    .regions
+                    .iter()
+                    .filter(|r| {
+                        !matches!(
+                            RegionType::of(r.kind),
+                            RegionType::Usable | RegionType::AcpiReclaimable
+                        )
+                    })
+                    .map(|r| AddressRange {
+                        start: r.start,
+                        end: r.end,
+                    })
+                    .chain(self.reserved[..self.reserved_count].iter().copied())
+                    .any(|off| ranges_intersect(addr, addr + PAGE_SIZE, off.start, off.end));
+                !claimed
+            })
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Address of the lowest usable frame, which is bit 0 of the bitmap.
+    pub fn base_addr(&self) -> u64 {
+        self.usable().map(|r| r.start).min().unwrap_or(0)
+    }
+
+    /// One past the highest usable address.
+    pub fn end_addr(&self) -> u64 {
+        self.usable().map(|r| r.end).max().unwrap_or(0)
+    }
+
+    /// Frames between [`Self::base_addr`] and [`Self::end_addr`], usable or not.
+    pub fn frame_count(&self) -> usize {
+        ((self.end_addr() - self.base_addr()) / PAGE_SIZE) as usize
+    }
+
+    /// Size of a bitmap with one bit per frame.
+    pub fn bitmap_bytes(&self) -> usize {
+        self.frame_count().div_ceil(8)
+    }
+
+    /// Finds the lowest page-aligned room for the bitmap in usable memory above 1 MiB that
+    /// doesn't overlap anything unavailable.
+    pub fn bitmap_location(&self) -> Option<u64> {
+        let bytes = self.bitmap_bytes() as u64;
+        self.usable().find_map(|r| {
+            let mut start = core::cmp::max(r.start, 0x100000);
+            // Move past anything in the way until the bitmap fits or the region runs out
+            while start < r.end && r.end - start >= bytes {
+                match self
+                    .unavailable()
+                    .find(|off| ranges_intersect(start, start + bytes, off.start, off.end))
+                {
+                    Some(off) => start = align_up(off.end),
+                    None => return Some(start),
                 }
+            }
+            None
+        })
+    }
 
-                // If we got here, this region is big enough and doesn't overlap the illegal ranges
-                region_base = Some(start);
-                break;
+    /// Sets `bitmap` to mark exactly the free frames with a 0 bit and returns how many there
+    /// are. Bits past [`Self::frame_count`] are left marked used.
+    pub fn fill(&self, bitmap: &mut BitSlice<u8, Lsb0>) -> usize {
+        let base = self.base_addr();
+        let frames = core::cmp::min(self.frame_count(), bitmap.len());
+        let index = |addr: u64| core::cmp::min(((addr - base) / PAGE_SIZE) as usize, frames);
+
+        bitmap.fill(true);
+        for r in self.usable() {
+            bitmap[index(r.start)..index(r.end)].fill(false);
+        }
+        for off in self.unavailable() {
+            let (start, end) = (align_down(off.start), align_up(off.end));
+            if end <= base || start >= end {
+                continue;
             }
+            bitmap[index(start.max(base))..index(end)].fill(true);
         }
+        bitmap[..frames].count_zeros()
+    }
+}
 
-        if region_base.is_none() {
-            panic!("Could not find a suitable region to place the bitmap!");
+/// A range of physical memory named after the hardware that can't address beyond it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16 MiB, reachable by ISA DMA and real-mode code such as the AP trampoline.
+    Dma,
+    /// Below 4 GiB, for devices limited to 32-bit DMA addresses.
+    Dma32,
+    /// Everything above 4 GiB.
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// The physical addresses in the zone.
+    pub fn range(self) -> Range<u64> {
+        match self {
+            Zone::Dma => 0..0x100_0000,
+            Zone::Dma32 => 0x100_0000..0x1_0000_0000,
+            Zone::Normal => 0x1_0000_0000..u64::MAX,
         }
-        let bitmap_phys_addr = region_base.unwrap();
+    }
 
-        // 7) Convert that physical address into a virtual address
-        let bitmap_virt_addr = phys_to_virt(bitmap_phys_addr, offset);
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma => "DMA",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "Normal",
+        }
+    }
 
-        // 8) Create a slice that references that memory
-        use core::slice;
-        let bitmap_slice =
-            unsafe { slice::from_raw_parts_mut(bitmap_virt_addr as *mut u8, bytes_needed) };
+    /// `self` and the zones below it, highest first: the order an allocation restricted to
+    /// `self` tries them in, so low memory is only used once higher memory runs out.
+    fn fallbacks(self) -> impl Iterator<Item = Zone> {
+        Zone::ALL
+            .into_iter()
+            .rev()
+            .filter(move |&zone| zone <= self)
+    }
+}
+
+/// How many freed frames each CPU keeps in front of the bitmap.
+const FRAME_CACHE_SIZE: usize = 32;
+/// How many frames move between a CPU's cache and the bitmap at once.
+const FRAME_CACHE_BATCH: usize = FRAME_CACHE_SIZE / 2;
 
-        // 9) Convert that slice into a BitSlice
-        use bitvec::prelude::*;
-        let bitmap_bits: &mut BitSlice<u8, Lsb0> = BitSlice::from_slice_mut(bitmap_slice);
+/// Bitmap indices of frames that are marked used in the bitmap but free to hand out on the
+/// CPU that owns the cache.
+struct FrameCache {
+    frames: [usize; FRAME_CACHE_SIZE],
+    len: usize,
+}
 
-        // 10) Initialize everything to "used" (true)
-        for i in 0..bitmap_bits.len() {
-            bitmap_bits.set(i, true);
+impl FrameCache {
+    const fn new() -> Self {
+        FrameCache {
+            frames: [0; FRAME_CACHE_SIZE],
+            len: 0,
         }
+    }
 
-        // 11) Mark the bitmap's own frames as used
-        let start_frame = bitmap_phys_addr / PAGE_SIZE;
-        let end_frame = (bitmap_phys_addr + bytes_needed as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
-        for frame_num in start_frame..end_frame {
-            if frame_num < max_frame {
-                bitmap_bits.set(frame_num as usize, true);
-            }
+    fn pop(&mut self) -> Option<usize> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.frames[self.len])
+    }
+
+    fn push(&mut self, index: usize) {
+        self.frames[self.len] = index;
+        self.len += 1;
+    }
+}
+
+/// Hands out frames from a bitmap with one bit per frame, set when the frame is in use.
+///
+/// Each CPU allocates from and frees into its own small [`FrameCache`] first and only takes
+/// the bitmap lock to move a batch of frames, so CPUs allocating single frames at the same
+/// time rarely contend on it.
+pub struct BitmapFrameAllocator<'a> {
+    base_addr: u64,
+    frame_count: usize,
+    bitmap: Mutex<&'a mut BitSlice<u8, Lsb0>>,
+    /// Where the next search of each zone starts, just past the last frame it handed out.
+    next: [AtomicUsize; Zone::ALL.len()],
+    caches: PerCpu<Mutex<FrameCache>>,
+    /// The ranges reserved in the map the allocator was built from, kept out of anything
+    /// reclaimed later.
+    reserved: [AddressRange; MAX_RESERVED],
+    reserved_count: usize,
+    acpi_reclaimed: AtomicBool,
+}
+
+impl<'a> BitmapFrameAllocator<'a> {
+    /// Builds the kernel's frame allocator from the bootloader's memory map, keeping its
+    /// bitmap in usable RAM reached through the physical memory mapping.
+    ///
+    /// # Safety
+    /// `memory_map` must be the bootloader's, so the frames it calls usable really are free,
+    /// and the physical memory mapping must be set up. Call it once: a second allocator would
+    /// hand out the same frames.
+    pub unsafe fn init(memory_map: &MemoryRegions) -> Self {
+        // 1) Reserve what real-mode code needs below 1 MiB before anything can be allocated
+        let mut map = PhysMemoryMap::new(memory_map)
+            .reserve(LOW_RESERVED.start, LOW_RESERVED.end)
+            .reserve(REAL_MODE_DATA.start, REAL_MODE_DATA.end);
+        let trampoline = map.free_page_in(TRAMPOLINE_BASE as u64, 0..TRAMPOLINE_LIMIT as u64);
+        if let Some(page) = trampoline {
+            map = map.reserve(page, page + PAGE_SIZE);
         }
+        LOW_MEMORY.call_once(|| LowMemory {
+            real_mode_data: PhysFrame::containing_address(PhysAddr::new(REAL_MODE_DATA.start)),
+            trampoline: trampoline.map(PhysAddr::new),
+        });
 
-        // 12) Now mark all truly free frames (in "Usable" ranges) as false
-        for region in memory_map.iter() {
-            if region.kind == MemoryRegionKind::Usable {
-                let start_frame = region.start / PAGE_SIZE;
-                let end_frame = (region.end + PAGE_SIZE - 1) / PAGE_SIZE;
+        // 2) Find a usable region large enough to hold the bitmap, and keep it out of the
+        //    frames the bitmap hands out
+        let bytes_needed = map.bitmap_bytes();
+        let bitmap_phys_addr = map
+            .bitmap_location()
+            .expect("Could not find a suitable region to place the bitmap!");
+        let map = map.reserve(bitmap_phys_addr, bitmap_phys_addr + bytes_needed as u64);
+        FRAME_BITMAP.call_once(|| bitmap_phys_addr..bitmap_phys_addr + bytes_needed as u64);
 
-                for frame in start_frame..end_frame {
-                    if frame >= max_frame {
-                        break;
-                    }
-                    let frame_addr = frame * PAGE_SIZE;
-                    let frame_end = frame_addr + PAGE_SIZE;
+        // 3) Reach it through the physical memory mapping
+        let bitmap_virt_addr =
+            phys_range_to_virt(PhysAddr::new(bitmap_phys_addr), bytes_needed as u64)
+                .expect("frame bitmap outside the physical memory mapping");
+        let bitmap =
+            unsafe { core::slice::from_raw_parts_mut(bitmap_virt_addr.as_mut_ptr(), bytes_needed) };
 
-                    // Skip if it intersects the bitmap storage
-                    let bitmap_end = bitmap_phys_addr + bytes_needed as u64;
-                    if ranges_intersect(frame_addr, frame_end, bitmap_phys_addr, bitmap_end) {
-                        continue;
-                    }
+        let allocator = BitmapFrameAllocator::new(&map, bitmap);
+        serial_println!(
+            "Total free frames: {} from {:#x}",
+            allocator.free_frames(),
+            allocator.base_addr
+        );
+        for zone in Zone::ALL {
+            serial_println!(
+                "  {}: {} free frames",
+                zone.name(),
+                allocator.free_frames_in(zone)
+            );
+        }
+        allocator
+    }
 
-                    // Skip if it intersects any illegal region
-                    let mut intersects_illegal = false;
-                    let local_illegal_regions = unsafe { ILLEGAL_REGIONS };
-                    for off in &local_illegal_regions {
-                        if ranges_intersect(frame_addr, frame_end, off.start, off.end) {
-                            intersects_illegal = true;
-                            break;
-                        }
-                    }
-                    if intersects_illegal {
-                        continue;
-                    }
+    /// Builds an allocator over `map`, keeping its bitmap in `bitmap`, which must be at
+    /// least [`PhysMemoryMap::bitmap_bytes`] long.
+    pub fn new(map: &PhysMemoryMap, bitmap: &'a mut [u8]) -> Self {
+        assert!(bitmap.len() >= map.bitmap_bytes(), "frame bitmap too small");
+        let bitmap_bits = BitSlice::from_slice_mut(bitmap);
+        map.fill(bitmap_bits);
+        BitmapFrameAllocator {
+            base_addr: map.base_addr(),
+            frame_count: map.frame_count(),
+            bitmap: Mutex::new(bitmap_bits),
+            next: [const { AtomicUsize::new(0) }; Zone::ALL.len()],
+            caches: PerCpu::new([const { Mutex::new(FrameCache::new()) }; MAX_CPUS]),
+            reserved: map.reserved,
+            reserved_count: map.reserved_count,
+            acpi_reclaimed: AtomicBool::new(false),
+        }
+    }
 
-                    // If we get here, the frame is truly free
-                    bitmap_bits.set(frame as usize, false);
-                }
+    /// Frees the frames of the ACPI-reclaimable regions in `memory_map`, which must be the
+    /// map the allocator was built from, and returns how many there were. Only call this once
+    /// nothing refers to the ACPI tables any more. Later calls free nothing.
+    pub fn reclaim_acpi(&self, memory_map: &[MemoryRegion]) -> usize {
+        if self.acpi_reclaimed.swap(true, Ordering::AcqRel) {
+            return 0;
+        }
+        let map = PhysMemoryMap {
+            regions: memory_map,
+            reserved: self.reserved,
+            reserved_count: self.reserved_count,
+        };
+        let mut bitmap = self.bitmap.lock();
+        let mut freed = 0;
+        for frame in map.acpi_reclaimable() {
+            // Frames past the highest usable address aren't in the bitmap
+            if let Some(index) = self.frame_as_index(frame)
+                && bitmap[index]
+            {
+                bitmap.set(index, false);
+                freed += 1;
             }
         }
+        freed
+    }
+
+    /// Frames that are currently free, including those in the per-CPU caches.
+    pub fn free_frames(&self) -> usize {
+        let cached: usize = (0..MAX_CPUS)
+            .filter_map(|cpu| self.caches.get_for(cpu))
+            .map(|cache| cache.lock().len)
+            .sum();
+        self.bitmap.lock()[..self.frame_count].count_zeros() + cached
+    }
+
+    /// Free frames in `zone`, including those in the per-CPU caches.
+    pub fn free_frames_in(&self, zone: Zone) -> usize {
+        let range = self.zone_indices(zone);
+        let cached: usize = (0..MAX_CPUS)
+            .filter_map(|cpu| self.caches.get_for(cpu))
+            .map(|cache| {
+                let cache = cache.lock();
+                cache.frames[..cache.len]
+                    .iter()
+                    .filter(|index| range.contains(index))
+                    .count()
+            })
+            .sum();
+        self.bitmap.lock()[range].count_zeros() + cached
+    }
+
+    /// The bitmap indices of the frames in `zone`.
+    fn zone_indices(&self, zone: Zone) -> Range<usize> {
+        let index = |addr: u64| {
+            let frames = addr.saturating_sub(self.base_addr) / PAGE_SIZE;
+            core::cmp::min(frames, self.frame_count as u64) as usize
+        };
+        let range = zone.range();
+        index(range.start)..index(range.end)
+    }
+
+    /// Marks and returns a free frame from the highest zone `zone` allows.
+    fn take_free(&self, bitmap: &mut BitSlice<u8, Lsb0>, zone: Zone) -> Option<usize> {
+        zone.fallbacks().find_map(|zone| {
+            let range = self.zone_indices(zone);
+            let next = &self.next[zone as usize];
+            let index = first_free(bitmap, range, next.load(Ordering::Relaxed))?;
+            bitmap.set(index, true);
+            next.store(index + 1, Ordering::Relaxed);
+            Some(index)
+        })
+    }
+
+    /// Allocates a frame that lies in `zone` or below it, preferring the highest. Only
+    /// allocations from [`Zone::Normal`] go through the per-CPU caches.
+    pub fn allocate_in(&self, zone: Zone) -> Option<PhysFrame> {
+        if zone == Zone::Normal {
+            return self.allocate();
+        }
+        let index = self.take_free(&mut self.bitmap.lock(), zone)?;
+        Some(self.index_as_frame(index))
+    }
 
-        let mut free_count = 0;
-        for i in 0..bitmap_bits.len() {
-            if !bitmap_bits[i] {
-                free_count += 1;
+    /// Takes a frame from the executing CPU's cache, refilling the cache from the bitmap
+    /// when it's empty.
+    pub fn allocate(&self) -> Option<PhysFrame> {
+        interrupts::without_interrupts(|| {
+            let mut cache = self.caches.get().lock();
+            if cache.len == 0 {
+                self.refill(&mut cache);
             }
+            cache.pop().map(|index| self.index_as_frame(index))
+        })
+    }
+
+    /// Returns a frame to the executing CPU's cache, moving half the cache back to the bitmap
+    /// when it's full.
+    pub fn deallocate(&self, frame: PhysFrame) {
+        let Some(index) = self.frame_as_index(frame) else {
+            // We will panic for now, but eventually handle this more gracefully.
+            todo!("Attempted to deallocate frame that was not allocated by the allocator");
+        };
+        interrupts::without_interrupts(|| {
+            let mut cache = self.caches.get().lock();
+            if cache.len == FRAME_CACHE_SIZE {
+                let mut bitmap = self.bitmap.lock();
+                for _ in 0..FRAME_CACHE_BATCH {
+                    if let Some(cached) = cache.pop() {
+                        bitmap.set(cached, false);
+                    }
+                }
+            }
+            cache.push(index);
+        })
+    }
+
+    /// Allocates the first free frame at or after `addr`, wrapping around to the start of
+    /// memory if there is none, e.g. to keep a buffer's frames close together. Bypasses the
+    /// per-CPU caches.
+    pub fn allocate_frame_near(&self, addr: PhysAddr) -> Option<PhysFrame> {
+        let from = (addr.as_u64().saturating_sub(self.base_addr) / PAGE_SIZE) as usize;
+        let mut bitmap = self.bitmap.lock();
+        let index = first_free(&bitmap, 0..self.frame_count, from)?;
+        bitmap.set(index, true);
+        Some(self.index_as_frame(index))
+    }
+
+    /// Allocates the lowest free frame that lies entirely within `range`, e.g. for code that
+    /// has to run from below 1 MiB. Frames sitting in the per-CPU caches are not considered.
+    pub fn allocate_in_range(&self, range: Range<u64>) -> Option<PhysFrame> {
+        let index = |addr: u64, round_up: bool| {
+            let offset = addr.saturating_sub(self.base_addr);
+            let frames = if round_up {
+                offset.div_ceil(PAGE_SIZE)
+            } else {
+                offset / PAGE_SIZE
+            };
+            core::cmp::min(frames, self.frame_count as u64) as usize
+        };
+        let (start, end) = (index(range.start, true), index(range.end, false));
+        if start >= end {
+            return None;
         }
-        serial_println!("Total free frames: {}", free_count);
+        let mut bitmap = self.bitmap.lock();
+        let index = bitmap[start..end].first_zero()? + start;
+        bitmap.set(index, true);
+        Some(self.index_as_frame(index))
+    }
 
-        BitmapFrameAllocator {
-            base_addr: 0,
-            frame_count,
-            bitmap: Mutex::new(bitmap_bits),
+    /// Moves a batch of frames from the bitmap into `cache`, first taken on top.
+    fn refill(&self, cache: &mut FrameCache) {
+        let mut bitmap = self.bitmap.lock();
+        let mut taken = [0; FRAME_CACHE_BATCH];
+        let mut count = 0;
+        while count < FRAME_CACHE_BATCH {
+            let Some(index) = self.take_free(&mut bitmap, Zone::Normal) else {
+                break;
+            };
+            taken[count] = index;
+            count += 1;
+        }
+        for &index in taken[..count].iter().rev() {
+            cache.push(index);
         }
     }
 
+    /// Returns every CPU's cached frames to the bitmap.
+    pub fn drain_caches(&self) {
+        interrupts::without_interrupts(|| {
+            for cpu in 0..MAX_CPUS {
+                let Some(cache) = self.caches.get_for(cpu) else {
+                    continue;
+                };
+                let mut cache = cache.lock();
+                let mut bitmap = self.bitmap.lock();
+                while let Some(index) = cache.pop() {
+                    bitmap.set(index, false);
+                }
+            }
+        })
+    }
+
     fn frame_as_index(&self, frame: PhysFrame) -> Option<usize> {
         let frame_addr = frame.start_address().as_u64();
         if frame_addr < self.base_addr {
@@ -219,36 +662,60 @@ impl<'a> BitmapFrameAllocator<'a> {
         let addr = self.base_addr + (index as u64) * PAGE_SIZE;
         PhysFrame::containing_address(PhysAddr::new(addr))
     }
-}
 
-unsafe impl<'a> FrameAllocator<Size4KiB> for BitmapFrameAllocator<'a> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        // Find the first free frame (a 'false' bit in the bitvec).
-        let mut bitmap_guard = self.bitmap.lock();
+    /// Allocates `count` physically contiguous frames and returns the first, e.g. for device
+    /// memory that is addressed by physical address. Frames sitting in the per-CPU caches are
+    /// returned to the bitmap first if that's what it takes to find a run.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_in(count, Zone::Normal)
+    }
 
-        // Split the iteration and bit setting into two steps to avoid borrowing issues.
-        let free_index = {
-            let mut bit_iter = bitmap_guard.iter().enumerate();
-            bit_iter.find(|(_, bit)| !**bit).map(|(idx, _)| idx)
+    /// Like [`Self::allocate_contiguous`], with every frame in `zone` or below it.
+    pub fn allocate_contiguous_in(&mut self, count: usize, zone: Zone) -> Option<PhysFrame> {
+        let find = |this: &Self| {
+            zone.fallbacks()
+                .find_map(|zone| this.find_contiguous(count, this.zone_indices(zone)))
         };
+        find(self).or_else(|| {
+            self.drain_caches();
+            find(self)
+        })
+    }
 
-        if let Some(idx) = free_index {
-            bitmap_guard.set(idx, true);
-            Some(self.index_as_frame(idx))
-        } else {
-            None
+    fn find_contiguous(&self, count: usize, range: Range<usize>) -> Option<PhysFrame> {
+        let mut bitmap_guard = self.bitmap.lock();
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for idx in range {
+            if bitmap_guard[idx] {
+                run_len = 0;
+                continue;
+            }
+            if run_len == 0 {
+                run_start = idx;
+            }
+            run_len += 1;
+            if run_len == count {
+                bitmap_guard[run_start..run_start + count].fill(true);
+                return Some(self.index_as_frame(run_start));
+            }
         }
+        None
+    }
+}
+
+unsafe impl<'a> FrameAllocator<Size4KiB> for BitmapFrameAllocator<'a> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if fault::should_fail(Site::FrameAlloc) {
+            return None;
+        }
+        self.allocate()
     }
 }
 
 impl<'a> FrameDeallocator<Size4KiB> for BitmapFrameAllocator<'a> {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        if let Some(idx) = self.frame_as_index(frame) {
-            self.bitmap.lock().set(idx, false);
-        } else {
-            // We will panic for now, but eventually handle this more gracefully.
-            todo!("Attempted to deallocate frame that was not allocated by the allocator");
-        }
+        self.deallocate(frame);
     }
 }
 
@@ -258,16 +725,12 @@ struct AddressRange {
     end: u64,
 }
 
-fn ranges_intersect(a_start: u64, a_end: u64, b_start: u64, b_end: u64) -> bool {
-    // True if the intervals (a_start..a_end) and (b_start..b_end) overlap
-    // Overlap means they have some common address:
-    //   a_start < b_end && a_end > b_start
-    //   (assuming a_start < a_end and b_start < b_end)
-    a_start < b_end && a_end > b_start
+fn align_up(addr: u64) -> u64 {
+    addr.div_ceil(PAGE_SIZE) * PAGE_SIZE
 }
 
-fn phys_to_virt(phys: u64, offset: u64) -> u64 {
-    phys + offset
+fn align_down(addr: u64) -> u64 {
+    addr / PAGE_SIZE * PAGE_SIZE
 }
 
 /// Initializes an instance of OffsetPageTable.
@@ -294,14 +757,14 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
 use x86_64::{
     PhysAddr,
-    structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, Mapper, Page, PhysFrame, Size4KiB, mapper::MapToError},
 };
 
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+) -> Result<(), MapToError<Size4KiB>> {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
@@ -311,7 +774,111 @@ pub fn create_example_mapping(
         //do not do this
         mapper.map_to(page, frame, flags, frame_allocator)
     };
-    map_result.expect("map_to failed").flush();
+    map_result?.flush();
+    Ok(())
+}
+
+/// Hands allocations through to another allocator and counts them. `map_to` only allocates
+/// for the page tables it has to create, so passing it one of these counts new tables.
+pub struct CountingFrameAllocator<'a, A> {
+    inner: &'a mut A,
+    pub count: usize,
+}
+
+impl<'a, A> CountingFrameAllocator<'a, A> {
+    pub fn new(inner: &'a mut A) -> Self {
+        CountingFrameAllocator { inner, count: 0 }
+    }
+}
+
+unsafe impl<'a, A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB>
+    for CountingFrameAllocator<'a, A>
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.inner.allocate_frame()?;
+        self.count += 1;
+        Some(frame)
+    }
+}
+
+/// Frees the level 1, 2 and 3 tables under `pml4` that map nothing, among those covering
+/// `range`, and returns how many were freed. Level 3 tables are kept if `keep_p3` is set, as
+/// the kernel's must be: their level 4 entries are copied into every address space. At most
+/// `max` are freed, and the rest are left for another call.
+///
+/// Nothing is flushed from the TLB; the caller should flush if `pml4` is active.
+///
+/// # Safety
+/// `pml4` must be a level 4 table reachable through the physical memory offset `offset`, and
+/// its tables in `range` must have been allocated from `frame_allocator`.
+pub unsafe fn prune_page_tables(
+    pml4: &mut PageTable,
+    range: Range<VirtAddr>,
+    keep_p3: bool,
+    offset: u64,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    max: usize,
+) -> usize {
+    if range.start >= range.end {
+        return 0;
+    }
+    let last = range.end - 1u64;
+    // Each table level covers 9 more address bits than the one below it
+    let indices = |shift: u32, base: u64| {
+        let span = 1u64 << (shift + 9);
+        let first = range.start.as_u64().max(base);
+        let end = last.as_u64().min(base + (span - 1));
+        let index = move |addr: u64| ((addr >> shift) & 0x1FF) as usize;
+        index(first)..=index(end)
+    };
+    let table = |entry: &PageTableEntry| {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        let ptr = (offset + entry.addr().as_u64()) as *mut PageTable;
+        Some(unsafe { &mut *ptr })
+    };
+    let mut freed = 0;
+    let mut free = |entry: &mut PageTableEntry| {
+        if freed == max {
+            return;
+        }
+        if let Ok(frame) = entry.frame() {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            entry.set_unused();
+            freed += 1;
+        }
+    };
+
+    let p4_base = range.start.as_u64() & !((1 << 48) - 1);
+    for i4 in indices(39, p4_base) {
+        let base4 = p4_base | (i4 as u64) << 39;
+        let Some(p3) = table(&pml4[i4]) else {
+            continue;
+        };
+        for i3 in indices(30, base4) {
+            let base3 = base4 | (i3 as u64) << 30;
+            let Some(p2) = table(&p3[i3]) else {
+                continue;
+            };
+            for i2 in indices(21, base3) {
+                let Some(p1) = table(&p2[i2]) else {
+                    continue;
+                };
+                if p1.iter().all(PageTableEntry::is_unused) {
+                    free(&mut p2[i2]);
+                }
+            }
+            if p2.iter().all(PageTableEntry::is_unused) {
+                free(&mut p3[i3]);
+            }
+        }
+        if !keep_p3 && p3.iter().all(PageTableEntry::is_unused) {
+            free(&mut pml4[i4]);
+        }
+    }
+    freed
 }
 
 //Legacy Frame Allocator
@@ -359,3 +926,201 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+#[cfg(test)]
+fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+    MemoryRegion { start, end, kind }
+}
+
+#[test_case]
+fn test_phys_memory_map_counts() {
+    let regions = [
+        region(0x0, 0x9F000, MemoryRegionKind::Usable),
+        region(0x9F000, 0x100000, MemoryRegionKind::UnknownBios(2)),
+        region(0x100000, 0x200000, MemoryRegionKind::Bootloader),
+        region(0x200000, 0x400000, MemoryRegionKind::Usable),
+    ];
+    let map = PhysMemoryMap::new(&regions);
+    assert_eq!(map.base_addr(), 0);
+    assert_eq!(map.end_addr(), 0x400000);
+    assert_eq!(map.frame_count(), 1024);
+    assert_eq!(map.bitmap_bytes(), 128);
+
+    let mut bitmap = [0u8; 128];
+    let free = map.fill(BitSlice::from_slice_mut(&mut bitmap));
+    assert_eq!(free, 0x9F + 0x200);
+
+    let map = map.reserve(0x300000, 0x300001);
+    let free = map.fill(BitSlice::from_slice_mut(&mut bitmap));
+    assert_eq!(free, 0x9F + 0x200 - 1);
+}
+
+#[test_case]
+fn test_phys_memory_map_partial_and_overlapping() {
+    let regions = [
+        // Only the whole frames 0x2000..0x5000 are usable
+        region(0x1800, 0x5800, MemoryRegionKind::Usable),
+        // Firmware claims part of a frame the usable region also covers
+        region(0x4100, 0x4200, MemoryRegionKind::UnknownUefi(0)),
+    ];
+    let map = PhysMemoryMap::new(&regions);
+    assert_eq!(map.base_addr(), 0x2000);
+    assert_eq!(map.frame_count(), 3);
+
+    let mut bitmap = [0u8; 1];
+    let bits = BitSlice::from_slice_mut(&mut bitmap);
+    assert_eq!(map.fill(bits), 2);
+    assert!(!bits[0] && !bits[1] && bits[2]);
+    assert!(bits[3..].all());
+}
+
+#[test_case]
+fn test_bitmap_frame_allocator_base_addr() {
+    let regions = [region(0x100000, 0x108000, MemoryRegionKind::Usable)];
+    let map = PhysMemoryMap::new(&regions).reserve(0x100000, 0x101000);
+    let mut bitmap = [0u8; 1];
+    let mut allocator = BitmapFrameAllocator::new(&map, &mut bitmap);
+    assert_eq!(allocator.free_frames(), 7);
+
+    let frame = allocator.allocate_frame().expect("no free frame");
+    assert_eq!(frame.start_address().as_u64(), 0x101000);
+    assert_eq!(allocator.free_frames(), 6);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), 7);
+
+    let run = allocator.allocate_contiguous(7).expect("no free run");
+    assert_eq!(run.start_address().as_u64(), 0x101000);
+    assert!(allocator.allocate_frame().is_none());
+}
+
+#[test_case]
+fn test_phys_memory_map_bitmap_location() {
+    let regions = [
+        region(0x0, 0x9F000, MemoryRegionKind::Usable),
+        region(0x100000, 0x101000, MemoryRegionKind::Usable),
+        region(0x101000, 0x102000, MemoryRegionKind::Bootloader),
+        region(0x102000, 0x10000000, MemoryRegionKind::Usable),
+    ];
+    let map = PhysMemoryMap::new(&regions);
+    // Below 1 MiB is never used, and the bitmap doesn't fit in the single frame at 1 MiB
+    assert_eq!(map.bitmap_location(), Some(0x102000));
+    let map = map.reserve(0x100000, 0x1000000);
+    assert_eq!(map.bitmap_location(), Some(0x1000000));
+}
+
+#[test_case]
+fn test_bitmap_frame_allocator_near_and_caches() {
+    let regions = [region(0x0, 0x40000, MemoryRegionKind::Usable)];
+    let map = PhysMemoryMap::new(&regions);
+    let mut bitmap = [0u8; 8];
+    let mut allocator = BitmapFrameAllocator::new(&map, &mut bitmap);
+    assert_eq!(allocator.free_frames(), 64);
+
+    let near = allocator.allocate_frame_near(PhysAddr::new(0x20800));
+    assert_eq!(near.map(|f| f.start_address().as_u64()), Some(0x20000));
+    // Past the end it wraps around to the lowest free frame
+    let wrapped = allocator.allocate_frame_near(PhysAddr::new(0x100000));
+    assert_eq!(wrapped.map(|f| f.start_address().as_u64()), Some(0x0));
+
+    // A single allocation moves a whole batch out of the bitmap into this CPU's cache
+    let frame = allocator.allocate_frame().expect("no free frame");
+    assert_eq!(frame.start_address().as_u64(), 0x1000);
+    assert_eq!(allocator.free_frames(), 61);
+    assert_eq!(
+        allocator.bitmap.lock()[..64].count_zeros(),
+        62 - FRAME_CACHE_BATCH
+    );
+
+    // Frames freed beyond the cache's capacity go back to the bitmap
+    let frames: [PhysFrame; FRAME_CACHE_SIZE] =
+        core::array::from_fn(|_| allocator.allocate_frame().expect("no free frame"));
+    for frame in frames {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), 62);
+
+    allocator.drain_caches();
+    assert_eq!(allocator.bitmap.lock()[..64].count_zeros(), 62);
+}
+
+#[test_case]
+fn test_phys_memory_map_free_page_in() {
+    let regions = [
+        region(0x0, 0x9F000, MemoryRegionKind::Usable),
+        region(0x9F000, 0x100000, MemoryRegionKind::UnknownBios(2)),
+    ];
+    let map = PhysMemoryMap::new(&regions).reserve(0x0, 0x1000);
+    assert_eq!(map.free_page_in(0x8000, 0..0x100000), Some(0x8000));
+    let map = map.reserve(0x8000, 0x9000);
+    assert_eq!(map.free_page_in(0x8000, 0..0x100000), Some(0x1000));
+    // Neither the preferred page nor anything else may leave the range
+    assert_eq!(map.free_page_in(0x8000, 0x9E000..0x100000), Some(0x9E000));
+    assert_eq!(map.free_page_in(0x9F000, 0x9F000..0x100000), None);
+}
+
+#[test_case]
+fn test_bitmap_frame_allocator_in_range() {
+    let regions = [region(0x0, 0x40000, MemoryRegionKind::Usable)];
+    let map = PhysMemoryMap::new(&regions).reserve(0x8000, 0x9000);
+    let mut bitmap = [0u8; 8];
+    let allocator = BitmapFrameAllocator::new(&map, &mut bitmap);
+
+    assert!(allocator.allocate_in_range(0x8000..0x9000).is_none());
+    // Only whole frames inside the range count
+    let frame = allocator.allocate_in_range(0x7800..0xA800);
+    assert_eq!(frame.map(|f| f.start_address().as_u64()), Some(0x9000));
+    let frame = allocator.allocate_in_range(0x7800..0xA800);
+    assert!(frame.is_none());
+    assert!(allocator.allocate_in_range(0x40000..0x100000).is_none());
+    assert_eq!(allocator.free_frames(), 62);
+}
+
+#[test_case]
+fn test_bitmap_frame_allocator_zones() {
+    let regions = [region(0xF00000, 0x1100000, MemoryRegionKind::Usable)];
+    let map = PhysMemoryMap::new(&regions);
+    let mut bitmap = [0u8; 64];
+    let mut allocator = BitmapFrameAllocator::new(&map, &mut bitmap);
+    assert_eq!(allocator.free_frames_in(Zone::Dma), 256);
+    assert_eq!(allocator.free_frames_in(Zone::Dma32), 256);
+    assert_eq!(allocator.free_frames_in(Zone::Normal), 0);
+
+    // General allocations come from the highest zone with memory
+    let frame = allocator.allocate_frame().expect("no free frame");
+    assert_eq!(frame.start_address().as_u64(), 0x1000000);
+    let dma = allocator.allocate_in(Zone::Dma).expect("no DMA frame");
+    assert_eq!(dma.start_address().as_u64(), 0xF00000);
+    assert_eq!(allocator.free_frames_in(Zone::Dma), 255);
+    assert_eq!(allocator.free_frames_in(Zone::Dma32), 255);
+
+    // A run can't cross into a zone the caller didn't allow
+    assert!(allocator.allocate_contiguous_in(256, Zone::Dma).is_none());
+    let run = allocator.allocate_contiguous_in(255, Zone::Dma);
+    assert_eq!(run.map(|f| f.start_address().as_u64()), Some(0xF01000));
+    assert!(allocator.allocate_in(Zone::Dma).is_none());
+}
+
+#[test_case]
+fn test_reclaim_acpi() {
+    let regions = [
+        region(0x0, 0x8000, MemoryRegionKind::Usable),
+        region(0x8000, 0xC000, MemoryRegionKind::UnknownBios(3)),
+        // NVS overlapping the last reclaimable frame keeps it out
+        region(0xB800, 0xD000, MemoryRegionKind::UnknownBios(4)),
+        region(0xD000, 0x10000, MemoryRegionKind::Usable),
+        // Above the highest usable address, so not tracked at all
+        region(0x10000, 0x12000, MemoryRegionKind::UnknownUefi(9)),
+    ];
+    let map = PhysMemoryMap::new(&regions).reserve(0x8000, 0x9000);
+    assert_eq!(map.regions_of(RegionType::AcpiNvs).count(), 1);
+    assert_eq!(map.acpi_reclaimable().count(), 4);
+
+    let mut bitmap = [0u8; 2];
+    let allocator = BitmapFrameAllocator::new(&map, &mut bitmap);
+    assert_eq!(allocator.free_frames(), 8 + 3);
+    // 0x9000 and 0xA000: 0x8000 is reserved and 0xB000 overlaps NVS
+    assert_eq!(allocator.reclaim_acpi(&regions), 2);
+    assert_eq!(allocator.free_frames(), 8 + 3 + 2);
+    assert_eq!(allocator.reclaim_acpi(&regions), 0);
+}
@@ -0,0 +1,172 @@
+//! Reaching physical memory through the bootloader's physical memory mapping.
+//!
+//! The bootloader maps physical memory at a fixed offset, but only as far as the highest
+//! address in the memory map: RAM, and whatever device memory the firmware listed. On UEFI
+//! machines device memory is often left out, so `offset + phys` can land on nothing, or on
+//! whatever else the kernel has mapped there. [`phys_to_virt`] and [`phys_range_to_virt`]
+//! check the address is covered before handing out a pointer; code that may be pointed at
+//! memory outside RAM uses [`map_physical`] or [`map_mmio`] instead, which map what isn't
+//! covered at the place the bootloader mapping would have put it, so every physical address
+//! keeps a single virtual one.
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader_api::info::MemoryRegion;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page_table::PageTableLevel;
+use x86_64::structures::paging::{Mapper, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::PAGE_SIZE;
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::fault::{self, Site};
+use crate::interrupts::PHYSICAL_MEMORY_OFFSET;
+
+/// One past the highest address the bootloader is known to have mapped, from the memory map.
+/// Addresses above it are looked up in the page tables.
+static MAPPED_END: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum PhysMapError {
+    /// The physical memory offset isn't known yet.
+    NoOffset,
+    /// The page at this address isn't covered by the physical memory mapping.
+    NotMapped(PhysAddr),
+    /// The range wraps around the address space.
+    BadRange,
+    /// Mapping needs the page allocator, which isn't installed yet.
+    NoPageAllocator,
+    Map(MapToError<Size4KiB>),
+}
+
+impl fmt::Display for PhysMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PhysMapError::NoOffset => write!(f, "physical memory offset not known yet"),
+            PhysMapError::NotMapped(addr) => {
+                write!(f, "{:#x} not in the physical memory mapping", addr)
+            }
+            PhysMapError::BadRange => write!(f, "physical range wraps around"),
+            PhysMapError::NoPageAllocator => write!(f, "page allocator not installed"),
+            PhysMapError::Map(e) => write!(f, "mapping failed: {:?}", e),
+        }
+    }
+}
+
+/// Records how much of physical memory the bootloader mapped, from the memory map it was
+/// given. Call once the offset is known.
+pub fn init(regions: &[MemoryRegion]) {
+    let end = regions.iter().map(|r| r.end).max().unwrap_or(0);
+    MAPPED_END.store(end, Ordering::Relaxed);
+}
+
+fn offset() -> Result<u64, PhysMapError> {
+    PHYSICAL_MEMORY_OFFSET
+        .get()
+        .map(|offset| offset.as_u64())
+        .ok_or(PhysMapError::NoOffset)
+}
+
+/// Where `phys` is in the physical memory mapping. Fails if it isn't mapped there.
+pub fn phys_to_virt(phys: PhysAddr) -> Result<VirtAddr, PhysMapError> {
+    phys_range_to_virt(phys, 1)
+}
+
+/// Where the `len` bytes at `phys` are in the physical memory mapping. Fails if any of them
+/// isn't mapped there.
+pub fn phys_range_to_virt(phys: PhysAddr, len: u64) -> Result<VirtAddr, PhysMapError> {
+    let offset = offset()?;
+    let end = phys
+        .as_u64()
+        .checked_add(len.max(1))
+        .ok_or(PhysMapError::BadRange)?;
+    // Past the known end, check each page: something may have mapped it since
+    if end > MAPPED_END.load(Ordering::Relaxed) {
+        let first = phys.align_down(PAGE_SIZE).as_u64();
+        for page in (first..end).step_by(PAGE_SIZE as usize) {
+            let virt = VirtAddr::try_new(offset + page).ok();
+            if virt.and_then(|virt| translate(offset, virt)) != Some(PhysAddr::new(page)) {
+                return Err(PhysMapError::NotMapped(PhysAddr::new(page)));
+            }
+        }
+    }
+    Ok(VirtAddr::new(offset + phys.as_u64()))
+}
+
+/// Looks `virt` up in the active page tables, reading them through the physical memory
+/// mapping. Page tables are in RAM, which it always covers.
+fn translate(offset: u64, virt: VirtAddr) -> Option<PhysAddr> {
+    let mut table = Cr3::read().0.start_address();
+    let mut level = PageTableLevel::Four;
+    loop {
+        let entries = unsafe { &*((offset + table.as_u64()) as *const PageTable) };
+        let entry = &entries[virt.page_table_index(level)];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let huge =
+            level != PageTableLevel::Four && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        match level.next_lower_level() {
+            Some(lower) if !huge => {
+                table = entry.addr();
+                level = lower;
+            }
+            // The entry maps a page of the size this level covers
+            _ => {
+                let page_mask = level.entry_address_space_alignment() - 1;
+                return Some(entry.addr() + (virt.as_u64() & page_mask));
+            }
+        }
+    }
+}
+
+/// Makes the `len` bytes at `phys` reachable through the physical memory mapping with
+/// `flags`, mapping the pages the bootloader didn't, and returns where they are. Pages
+/// already mapped are left as they are. Pages mapped before a failure stay mapped.
+pub fn map_physical(
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, PhysMapError> {
+    if let Ok(virt) = phys_range_to_virt(phys, len) {
+        return Ok(virt);
+    }
+    let offset = offset()?;
+    let end = phys
+        .as_u64()
+        .checked_add(len.max(1))
+        .ok_or(PhysMapError::BadRange)?;
+    let mut guard = PAGE_ALLOCATOR.lock();
+    let allocator = guard.as_mut().ok_or(PhysMapError::NoPageAllocator)?;
+    let first = phys.align_down(PAGE_SIZE).as_u64();
+    for addr in (first..end).step_by(PAGE_SIZE as usize) {
+        let virt = VirtAddr::try_new(offset + addr)
+            .map_err(|_| PhysMapError::NotMapped(PhysAddr::new(addr)))?;
+        let page = Page::<Size4KiB>::containing_address(virt);
+        if translate(offset, page.start_address()).is_some() {
+            continue;
+        }
+        if fault::should_fail(Site::MapTo) {
+            return Err(PhysMapError::Map(MapToError::FrameAllocationFailed));
+        }
+        let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+        unsafe {
+            allocator
+                .mapper
+                .map_to(page, frame, flags, &mut allocator.frame_allocator)
+                .map_err(PhysMapError::Map)?
+                .flush();
+        }
+    }
+    Ok(VirtAddr::new(offset + phys.as_u64()))
+}
+
+/// Like [`map_physical`], for device registers: pages it maps are uncached and not executable.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, PhysMapError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    map_physical(phys, len, flags)
+}
@@ -0,0 +1,198 @@
+//! A readable dump of the boot memory map and of what the kernel set aside on top of it.
+//!
+//! The map is recorded by [`init`] and printed to serial once the heap is up,
+//! and again on demand by the monitor's `memmap` command.
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use bootloader_api::info::MemoryRegion;
+use spin::Once;
+
+use super::{LOW_RESERVED, RegionType, frame_bitmap, low_memory, vma};
+use crate::allocator::bootstrap::{ARENA_SIZE, BOOTSTRAP};
+use crate::init::multicore::AP_STACKS;
+
+static MEMORY_MAP: Once<&'static [MemoryRegion]> = Once::new();
+
+/// Keeps `regions` for later reports.
+pub fn init(regions: &[MemoryRegion]) {
+    // The bootloader keeps the memory map in memory it never gives back
+    let regions = unsafe { core::slice::from_raw_parts(regions.as_ptr(), regions.len()) };
+    MEMORY_MAP.call_once(|| regions);
+}
+
+/// The memory map [`init`] recorded.
+pub fn memory_map() -> Option<&'static [MemoryRegion]> {
+    MEMORY_MAP.get().copied()
+}
+
+/// Memory the kernel keeps for itself.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub name: &'static str,
+    pub range: Range<u64>,
+    /// Whether `range` is virtual, for memory in the kernel image or the heap's address space,
+    /// rather than physical.
+    pub virt: bool,
+}
+
+/// What the kernel has reserved so far.
+pub fn reservations() -> Vec<Reservation> {
+    let phys = |name, range| Reservation {
+        name,
+        range,
+        virt: false,
+    };
+    let virt = |name, start: u64, len: u64| Reservation {
+        name,
+        range: start..start + len,
+        virt: true,
+    };
+    let mut reserved = Vec::new();
+    if let Some(low) = low_memory() {
+        let start = low.real_mode_data.start_address().as_u64();
+        reserved.push(phys("real-mode data", start..start + super::PAGE_SIZE));
+        if let Some(trampoline) = low.trampoline {
+            let start = trampoline.as_u64();
+            reserved.push(phys("AP trampoline", start..start + super::PAGE_SIZE));
+        }
+    }
+    reserved.push(phys("low memory", LOW_RESERVED.start..LOW_RESERVED.end));
+    if let Some(bitmap) = frame_bitmap() {
+        reserved.push(phys("frame bitmap", bitmap));
+    }
+    let stacks = &raw const AP_STACKS;
+    reserved.push(virt(
+        "AP stacks",
+        stacks as u64,
+        size_of_val(unsafe { &*stacks }) as u64,
+    ));
+    reserved.push(virt(
+        "bootstrap heap",
+        &BOOTSTRAP as *const _ as u64,
+        ARENA_SIZE as u64,
+    ));
+    for window in vma::windows() {
+        reserved.push(virt(
+            window.name,
+            window.range.start,
+            window.range.end - window.range.start,
+        ));
+    }
+    reserved
+}
+
+/// Bytes and regions of each type in `regions`, in [`RegionType::ALL`] order.
+pub fn totals(regions: &[MemoryRegion]) -> [(RegionType, u64, usize); RegionType::ALL.len()] {
+    RegionType::ALL.map(|ty| {
+        regions
+            .iter()
+            .filter(|r| RegionType::of(r.kind) == ty)
+            .fold((ty, 0, 0), |(ty, bytes, count), r| {
+                (ty, bytes + (r.end - r.start), count + 1)
+            })
+    })
+}
+
+/// A byte count in the largest unit it has at least one of.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            bytes if bytes >= 1 << 30 => write!(f, "{} GiB", bytes >> 30),
+            bytes if bytes >= 1 << 20 => write!(f, "{} MiB", bytes >> 20),
+            bytes if bytes >= 1 << 10 => write!(f, "{} KiB", bytes >> 10),
+            bytes => write!(f, "{} B", bytes),
+        }
+    }
+}
+
+/// The memory map, its totals per type and the kernel's reservations, printable.
+pub struct Report<'a> {
+    pub regions: &'a [MemoryRegion],
+    pub reservations: Vec<Reservation>,
+}
+
+impl Report<'static> {
+    /// A report on the recorded memory map, or `None` before [`init`].
+    pub fn current() -> Option<Self> {
+        Some(Report {
+            regions: memory_map()?,
+            reservations: reservations(),
+        })
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "memory map, {} regions:", self.regions.len())?;
+        for region in self.regions {
+            writeln!(
+                f,
+                "  {:#012x}..{:#012x} {:>8}  {:<16} {:?}",
+                region.start,
+                region.end,
+                Size(region.end - region.start),
+                RegionType::of(region.kind).name(),
+                region.kind
+            )?;
+        }
+        writeln!(f, "totals:")?;
+        for (ty, bytes, count) in totals(self.regions) {
+            if count > 0 {
+                writeln!(
+                    f,
+                    "  {:<16} {:>8} in {} regions",
+                    ty.name(),
+                    Size(bytes),
+                    count
+                )?;
+            }
+        }
+        writeln!(f, "reserved by the kernel:")?;
+        for reservation in &self.reservations {
+            writeln!(
+                f,
+                "  {:<16} {:#018x}..{:#018x} {:>8} {}",
+                reservation.name,
+                reservation.range.start,
+                reservation.range.end,
+                Size(reservation.range.end - reservation.range.start),
+                if reservation.virt { "virt" } else { "phys" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_totals_by_type() {
+    use bootloader_api::info::MemoryRegionKind;
+
+    let region = |start, end, kind| MemoryRegion { start, end, kind };
+    let regions = [
+        region(0x0, 0x9F000, MemoryRegionKind::Usable),
+        region(0x9F000, 0x100000, MemoryRegionKind::UnknownBios(2)),
+        region(0x100000, 0x200000, MemoryRegionKind::Bootloader),
+        region(0x200000, 0x400000, MemoryRegionKind::Usable),
+        region(0x400000, 0x401000, MemoryRegionKind::UnknownBios(3)),
+    ];
+    let totals = totals(&regions);
+    assert_eq!(totals[0], (RegionType::Usable, 0x29F000, 2));
+    assert_eq!(totals[1], (RegionType::Bootloader, 0x100000, 1));
+    assert_eq!(totals[2], (RegionType::AcpiReclaimable, 0x1000, 1));
+    assert_eq!(totals[5], (RegionType::Reserved, 0x61000, 1));
+
+    let report = alloc::format!(
+        "{}",
+        Report {
+            regions: &regions,
+            reservations: Vec::new(),
+        }
+    );
+    assert!(report.contains("memory map, 5 regions:"));
+    assert!(report.contains("usable"));
+    assert!(report.contains("2 MiB"));
+}
@@ -0,0 +1,188 @@
+//! Windows of kernel virtual address space that the kernel maps pages into itself, such as the
+//! heap.
+//!
+//! Every window is carved out of [`ARENA`] and no two overlap, so the heap can take another
+//! window when it fills up without landing on pages mapped for something else, such as device
+//! registers. Windows live in a fixed table rather than on the heap, since the heap takes them
+//! from inside an allocation.
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use super::PAGE_SIZE;
+
+/// The level 4 entry below the top one. The bootloader maps the kernel image and its own
+/// mappings elsewhere.
+pub const ARENA: Range<u64> = 0xFFFF_FF00_0000_0000..0xFFFF_FF80_0000_0000;
+
+/// Windows that can be reserved at once.
+const MAX_WINDOWS: usize = 32;
+
+/// A reserved range of kernel virtual address space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    pub name: &'static str,
+    pub range: Range<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// The range isn't page aligned, is empty or isn't inside [`ARENA`].
+    BadRange,
+    /// The range overlaps the named window.
+    Overlaps(&'static str),
+    /// There is no gap in [`ARENA`] large enough.
+    NoRoom,
+    /// Every slot in the window table is in use.
+    TableFull,
+}
+
+impl fmt::Display for VmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmaError::BadRange => write!(f, "range unaligned or outside the window arena"),
+            VmaError::Overlaps(name) => write!(f, "range overlaps the {} window", name),
+            VmaError::NoRoom => write!(f, "no gap large enough"),
+            VmaError::TableFull => write!(f, "window table full"),
+        }
+    }
+}
+
+/// The reserved windows, in no particular order.
+struct Windows {
+    slots: [Option<Window>; MAX_WINDOWS],
+}
+
+impl Windows {
+    const fn new() -> Self {
+        Windows {
+            slots: [const { None }; MAX_WINDOWS],
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Window> {
+        self.slots.iter().flatten()
+    }
+
+    /// Reserves exactly `range`.
+    fn reserve_at(&mut self, name: &'static str, range: Range<u64>) -> Result<(), VmaError> {
+        if range.is_empty()
+            || range.start < ARENA.start
+            || range.end > ARENA.end
+            || !range.start.is_multiple_of(PAGE_SIZE)
+            || !range.end.is_multiple_of(PAGE_SIZE)
+        {
+            return Err(VmaError::BadRange);
+        }
+        if let Some(other) = self
+            .iter()
+            .find(|window| window.range.start < range.end && range.start < window.range.end)
+        {
+            return Err(VmaError::Overlaps(other.name));
+        }
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmaError::TableFull)?;
+        *slot = Some(Window { name, range });
+        Ok(())
+    }
+
+    /// Reserves `len` bytes, rounded up to whole pages, in the lowest gap that fits them.
+    fn reserve(&mut self, name: &'static str, len: u64) -> Result<Range<u64>, VmaError> {
+        let len = len.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        // A gap starts at the arena's start or at the end of a window
+        let start = core::iter::once(ARENA.start)
+            .chain(self.iter().map(|window| window.range.end))
+            .filter(|&start| {
+                start.checked_add(len).is_some_and(|end| end <= ARENA.end)
+                    && !self
+                        .iter()
+                        .any(|window| window.range.start < start + len && start < window.range.end)
+            })
+            .min()
+            .ok_or(VmaError::NoRoom)?;
+        self.reserve_at(name, start..start + len)?;
+        Ok(start..start + len)
+    }
+
+    /// Gives back the window starting at `start`.
+    fn release(&mut self, start: u64) -> Option<Window> {
+        self.slots
+            .iter_mut()
+            .find(|slot| {
+                slot.as_ref()
+                    .is_some_and(|window| window.range.start == start)
+            })?
+            .take()
+    }
+}
+
+static WINDOWS: Mutex<Windows> = Mutex::new(Windows::new());
+
+/// Reserves exactly `len` bytes at `start` as `name`.
+pub fn reserve_at(name: &'static str, start: VirtAddr, len: u64) -> Result<(), VmaError> {
+    let start = start.as_u64();
+    let end = start.checked_add(len).ok_or(VmaError::BadRange)?;
+    WINDOWS.lock().reserve_at(name, start..end)
+}
+
+/// Reserves `len` bytes as `name` wherever there is room, and returns where.
+pub fn reserve(name: &'static str, len: u64) -> Result<Range<VirtAddr>, VmaError> {
+    let range = WINDOWS.lock().reserve(name, len)?;
+    Ok(VirtAddr::new(range.start)..VirtAddr::new(range.end))
+}
+
+/// Gives back the window starting at `start`. Its pages must have been unmapped already.
+pub fn release(start: VirtAddr) -> Option<Window> {
+    WINDOWS.lock().release(start.as_u64())
+}
+
+/// The reserved windows, lowest first.
+pub fn windows() -> Vec<Window> {
+    let mut windows: Vec<Window> = WINDOWS.lock().iter().cloned().collect();
+    windows.sort_by_key(|window| window.range.start);
+    windows
+}
+
+#[test_case]
+fn test_windows_never_overlap() {
+    let mut windows = Windows::new();
+    let heap = ARENA.start..ARENA.start + 0x4000_0000;
+    windows.reserve_at("heap", heap.clone()).unwrap();
+    assert_eq!(
+        windows.reserve_at("mmio", heap.end - PAGE_SIZE..heap.end + PAGE_SIZE),
+        Err(VmaError::Overlaps("heap"))
+    );
+    assert_eq!(
+        windows.reserve_at("mmio", heap.end + 1..heap.end + PAGE_SIZE),
+        Err(VmaError::BadRange)
+    );
+    assert_eq!(
+        windows.reserve("mmio", 0x1800),
+        Ok(heap.end..heap.end + 0x2000)
+    );
+    // Growing the heap in place now collides, so it goes after the device window
+    assert_eq!(
+        windows.reserve_at("heap", heap.end..heap.end + 0x4000_0000),
+        Err(VmaError::Overlaps("mmio"))
+    );
+    assert_eq!(
+        windows.reserve("heap", 0x4000_0000),
+        Ok(heap.end + 0x2000..heap.end + 0x2000 + 0x4000_0000)
+    );
+    // The gap left by a released window is reused
+    assert!(windows.release(heap.end).is_some());
+    assert_eq!(
+        windows.reserve("mmio", 0x1000),
+        Ok(heap.end..heap.end + 0x1000)
+    );
+    assert_eq!(
+        windows.reserve("huge", ARENA.end - ARENA.start),
+        Err(VmaError::NoRoom)
+    );
+}
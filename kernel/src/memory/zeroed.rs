@@ -0,0 +1,157 @@
+//! Frames zeroed ahead of time, for memory handed to user processes.
+//!
+//! A frame a process gets must not show it what the kernel or another process left there, so
+//! it has to be zeroed first. [`scrub`] runs as a kernel task and keeps up to [`POOL_SIZE`]
+//! frames zeroed in advance, a batch per timer tick so other tasks don't wait behind it, and
+//! [`take`] hands them out without a memset on the way. Callers zero a frame themselves when
+//! the pool has run dry.
+use core::future::poll_fn;
+use core::task::Poll;
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+
+use super::{PAGE_SIZE, phys_to_virt};
+use crate::allocator::oom::{self, Reclaimer};
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::mem;
+use crate::metrics::Gauge;
+use crate::task::sleep::sleep_ticks;
+
+/// Frames the pool holds when full, 256 KiB.
+pub const POOL_SIZE: usize = 64;
+/// [`scrub`] starts refilling once the pool is down to this many frames.
+const REFILL_BELOW: usize = POOL_SIZE / 2;
+/// Frames [`scrub`] zeroes before letting other tasks run.
+const BATCH: usize = 8;
+/// Ticks [`scrub`] waits before trying again when there were no free frames.
+const OOM_RETRY_TICKS: u64 = 100;
+
+static POOLED: Gauge = Gauge::new("mem.zeroed_frames");
+
+/// Zeroed frames, as a stack of physical addresses.
+struct Pool {
+    frames: [u64; POOL_SIZE],
+    len: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    frames: [0; POOL_SIZE],
+    len: 0,
+});
+
+/// Woken by [`take`] when the pool runs low.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Takes a zeroed frame from the pool, or returns `None` if it is empty.
+pub fn take() -> Option<PhysFrame> {
+    let (frame, left) = interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        if pool.len == 0 {
+            return (None, 0);
+        }
+        pool.len -= 1;
+        (Some(pool.frames[pool.len]), pool.len)
+    });
+    if left < REFILL_BELOW {
+        WAKER.wake();
+    }
+    let frame = frame?;
+    POOLED.dec();
+    Some(PhysFrame::containing_address(PhysAddr::new(frame)))
+}
+
+/// Zeroed frames in the pool now.
+pub fn len() -> usize {
+    interrupts::without_interrupts(|| POOL.lock().len)
+}
+
+/// Adds a zeroed frame to the pool, or gives it back if the pool is full.
+fn put(frame: PhysFrame) -> Result<(), PhysFrame> {
+    interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        if pool.len == POOL_SIZE {
+            return Err(frame);
+        }
+        let len = pool.len;
+        pool.frames[len] = frame.start_address().as_u64();
+        pool.len += 1;
+        POOLED.inc();
+        Ok(())
+    })
+}
+
+/// Allocates a frame and zeroes it, or returns `None` if memory is out.
+fn zeroed_frame() -> Option<PhysFrame> {
+    let frame = PAGE_ALLOCATOR.lock().as_ref()?.frame_allocator.allocate()?;
+    let virt = phys_to_virt(frame.start_address()).expect("RAM outside the physical mapping");
+    unsafe { mem::zero_pages(virt.as_mut_ptr(), 1) };
+    Some(frame)
+}
+
+/// Hands the pooled frames back to the frame allocator when the heap runs out.
+fn reclaim() -> usize {
+    let Some(mut guard) = PAGE_ALLOCATOR.try_lock() else {
+        return 0;
+    };
+    let Some(page_alloc) = guard.as_mut() else {
+        return 0;
+    };
+    let Some(mut pool) = POOL.try_lock() else {
+        return 0;
+    };
+    let freed = pool.len;
+    for &frame in &pool.frames[..freed] {
+        let frame = PhysFrame::containing_address(PhysAddr::new(frame));
+        page_alloc.frame_allocator.deallocate(frame);
+    }
+    pool.len = 0;
+    POOLED.set(0);
+    freed * PAGE_SIZE as usize
+}
+
+/// Zeroes a batch of frames into the pool, stopping early once it is full. Returns `false` if
+/// memory ran out. [`scrub`] calls this; anything else only needs it to fill the pool without
+/// the executor.
+pub fn refill_batch() -> bool {
+    for _ in 0..BATCH {
+        let Some(frame) = zeroed_frame() else {
+            return false;
+        };
+        if let Err(frame) = put(frame) {
+            if let Some(page_alloc) = PAGE_ALLOCATOR.lock().as_ref() {
+                page_alloc.frame_allocator.deallocate(frame);
+            }
+            break;
+        }
+    }
+    true
+}
+
+/// Keeps the pool topped up, forever. Spawn it on the executor.
+pub async fn scrub() {
+    oom::register(Reclaimer {
+        name: "zeroed frames",
+        reclaim,
+    });
+    POOLED.register();
+    loop {
+        while len() < POOL_SIZE {
+            let ticks = if refill_batch() { 1 } else { OOM_RETRY_TICKS };
+            sleep_ticks(ticks).await;
+        }
+        // Until `take` finds the pool running low
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            if len() < REFILL_BELOW {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+}
@@ -0,0 +1,205 @@
+//! Named event counters and gauges that any subsystem can bump cheaply.
+//!
+//! A metric is a `static` [`Counter`] or [`Gauge`] declared next to the code that updates it.
+//! Updating one is a single atomic operation; the first update also adds it to a lock-free
+//! registry, so metrics can be bumped from interrupt handlers and show up in [`list`] once
+//! they have been used. Call [`Counter::register`] or [`Gauge::register`] to list a metric
+//! before that.
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::serial_println;
+
+/// Metrics the registry can hold; later ones are counted but not listed.
+const MAX_METRICS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Only ever goes up.
+    Counter,
+    /// Goes up and down, e.g. something currently in use.
+    Gauge,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+struct Metric {
+    name: &'static str,
+    kind: Kind,
+    value: AtomicI64,
+    registered: AtomicBool,
+}
+
+impl Metric {
+    const fn new(name: &'static str, kind: Kind) -> Self {
+        Metric {
+            name,
+            kind,
+            value: AtomicI64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    fn register(&'static self) {
+        if self.registered.load(Ordering::Relaxed) || self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let index = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        match REGISTRY.get(index) {
+            Some(slot) => slot.store(ptr::from_ref(self).cast_mut(), Ordering::Release),
+            None => {
+                UNLISTED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn add(&'static self, delta: i64) {
+        self.value.fetch_add(delta, Ordering::Relaxed);
+        self.register();
+    }
+}
+
+static REGISTRY: [AtomicPtr<Metric>; MAX_METRICS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_METRICS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+/// Metrics that didn't fit in the registry.
+static UNLISTED: AtomicU64 = AtomicU64::new(0);
+
+/// A count of events, such as allocations or context switches.
+pub struct Counter(Metric);
+
+impl Counter {
+    pub const fn new(name: &'static str) -> Self {
+        Counter(Metric::new(name, Kind::Counter))
+    }
+
+    pub fn inc(&'static self) {
+        self.0.add(1);
+    }
+
+    pub fn add(&'static self, count: u64) {
+        self.0.add(count as i64);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.value.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn register(&'static self) {
+        self.0.register();
+    }
+}
+
+/// A level that goes up and down, such as objects currently allocated.
+pub struct Gauge(Metric);
+
+impl Gauge {
+    pub const fn new(name: &'static str) -> Self {
+        Gauge(Metric::new(name, Kind::Gauge))
+    }
+
+    pub fn add(&'static self, delta: i64) {
+        self.0.add(delta);
+    }
+
+    pub fn inc(&'static self) {
+        self.0.add(1);
+    }
+
+    pub fn dec(&'static self) {
+        self.0.add(-1);
+    }
+
+    pub fn set(&'static self, value: i64) {
+        self.0.value.store(value, Ordering::Relaxed);
+        self.0.register();
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.value.load(Ordering::Relaxed)
+    }
+
+    pub fn register(&'static self) {
+        self.0.register();
+    }
+}
+
+/// One metric's value when [`list`] was called.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub value: i64,
+}
+
+/// The registered metrics, sorted by name.
+pub fn list() -> Vec<Sample> {
+    let mut samples: Vec<Sample> = REGISTRY
+        .iter()
+        .filter_map(|slot| {
+            // A slot is claimed before its pointer is stored, so it may still be null
+            let metric = unsafe { slot.load(Ordering::Acquire).as_ref() }?;
+            Some(Sample {
+                name: metric.name,
+                kind: metric.kind,
+                value: metric.value.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    samples.sort_unstable_by_key(|sample| sample.name);
+    samples
+}
+
+/// How many metrics were used but didn't fit in the registry.
+pub fn unlisted() -> u64 {
+    UNLISTED.load(Ordering::Relaxed)
+}
+
+/// Prints every metric over serial, one `metric <name> <kind> <value>` line each between
+/// `metrics begin` and `metrics end`, for the test runner to collect.
+pub fn dump_serial() {
+    serial_println!("metrics begin");
+    for sample in list() {
+        serial_println!(
+            "metric {} {} {}",
+            sample.name,
+            sample.kind.name(),
+            sample.value
+        );
+    }
+    serial_println!("metrics end");
+}
+
+#[test_case]
+fn test_counter_registers_on_first_use() {
+    static TEST_COUNTER: Counter = Counter::new("test.counter");
+    assert!(!TEST_COUNTER.0.registered.load(Ordering::Relaxed));
+    TEST_COUNTER.inc();
+    TEST_COUNTER.add(2);
+    assert_eq!(TEST_COUNTER.get(), 3);
+    assert!(TEST_COUNTER.0.registered.load(Ordering::Relaxed));
+    let registered = REGISTRY
+        .iter()
+        .filter(|slot| ptr::eq(slot.load(Ordering::Relaxed), &TEST_COUNTER.0))
+        .count();
+    assert_eq!(registered, 1);
+}
+
+#[test_case]
+fn test_gauge_goes_both_ways() {
+    static TEST_GAUGE: Gauge = Gauge::new("test.gauge");
+    TEST_GAUGE.inc();
+    TEST_GAUGE.dec();
+    TEST_GAUGE.dec();
+    assert_eq!(TEST_GAUGE.get(), -1);
+    TEST_GAUGE.set(5);
+    assert_eq!(TEST_GAUGE.get(), 5);
+}
@@ -0,0 +1,163 @@
+//! What the kernel does once a panic has been reported.
+//!
+//! The policy comes from `panic=` on the kernel command line:
+//!
+//! - `halt`, the default, stops with the message on screen,
+//! - `reboot`, or a number of seconds to wait first, resets the machine,
+//! - `exit` leaves QEMU through isa-debug-exit with a failure code, as test boots do.
+//!
+//! A machine that panics during boot would reboot forever, so every panic reboot bumps a count
+//! kept in a CMOS byte, and a boot that gets as far as starting the monitor clears it. After
+//! [`MAX_PANIC_REBOOTS`] reboots in a row the panic handler stops rebooting and runs the
+//! monitor instead, so someone at the machine can look around.
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+use crate::{QemuExitCode, cmos, exit_qemu, power, println, speaker, timer, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Halt,
+    Reboot { delay_secs: u32 },
+    ExitQemu,
+}
+
+const KIND_HALT: u8 = 0;
+const KIND_REBOOT: u8 = 1;
+const KIND_EXIT_QEMU: u8 = 2;
+
+static KIND: AtomicU8 = AtomicU8::new(KIND_HALT);
+static REBOOT_DELAY_SECS: AtomicU32 = AtomicU32::new(0);
+
+/// Panic reboots in a row after which the next panic runs the monitor instead.
+pub const MAX_PANIC_REBOOTS: u8 = 3;
+
+/// The CMOS byte holding the panic reboot count. It is past the registers PC firmware and QEMU
+/// define; since other firmware may still use it, the count is tagged so that a byte someone
+/// else wrote reads as 0.
+const COUNT_REGISTER: u8 = 0x7E;
+const COUNT_TAG: u8 = 0xA0;
+const COUNT_MASK: u8 = 0x0F;
+
+pub fn set(policy: Policy) {
+    let kind = match policy {
+        Policy::Halt => KIND_HALT,
+        Policy::Reboot { delay_secs } => {
+            REBOOT_DELAY_SECS.store(delay_secs, Ordering::Relaxed);
+            KIND_REBOOT
+        }
+        Policy::ExitQemu => KIND_EXIT_QEMU,
+    };
+    KIND.store(kind, Ordering::Relaxed);
+}
+
+pub fn get() -> Policy {
+    match KIND.load(Ordering::Relaxed) {
+        KIND_REBOOT => Policy::Reboot {
+            delay_secs: REBOOT_DELAY_SECS.load(Ordering::Relaxed),
+        },
+        KIND_EXIT_QEMU => Policy::ExitQemu,
+        _ => Policy::Halt,
+    }
+}
+
+/// Parses the value of a `panic=` option.
+pub fn parse(value: &str) -> Option<Policy> {
+    match value {
+        "halt" => Some(Policy::Halt),
+        "reboot" => Some(Policy::Reboot { delay_secs: 0 }),
+        "exit" => Some(Policy::ExitQemu),
+        secs => secs
+            .parse()
+            .ok()
+            .map(|delay_secs| Policy::Reboot { delay_secs }),
+    }
+}
+
+/// Applies the `panic=` option in `cmdline`, if there is one.
+pub fn configure(cmdline: &str) {
+    for value in cmdline
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix("panic="))
+    {
+        match parse(value) {
+            Some(policy) => set(policy),
+            None => warn!("panic: unknown policy '{}'", value),
+        }
+    }
+}
+
+/// Panic reboots since the last boot that reached the monitor.
+pub fn panic_reboots() -> u8 {
+    let value = cmos::read(COUNT_REGISTER);
+    if value & !COUNT_MASK == COUNT_TAG {
+        value & COUNT_MASK
+    } else {
+        0
+    }
+}
+
+fn set_panic_reboots(count: u8) {
+    cmos::write(COUNT_REGISTER, COUNT_TAG | count.min(COUNT_MASK));
+}
+
+/// Records that this boot came up, so earlier panic reboots no longer count as a loop.
+pub fn boot_succeeded() {
+    if panic_reboots() != 0 {
+        set_panic_reboots(0);
+    }
+}
+
+/// Carries out the policy. Called by the panic handler once the message is out.
+pub fn finish() -> ! {
+    match get() {
+        Policy::Halt => {}
+        Policy::ExitQemu => exit_qemu(QemuExitCode::Failed),
+        Policy::Reboot { delay_secs } => {
+            let reboots = panic_reboots();
+            if reboots >= MAX_PANIC_REBOOTS {
+                println!(
+                    "Panicked {} boots in a row; not rebooting again. Running the monitor.",
+                    reboots + 1
+                );
+                speaker::alert();
+                crate::task::monitor::run_polled();
+            }
+            set_panic_reboots(reboots + 1);
+            if delay_secs > 0 {
+                println!("Rebooting in {} seconds", delay_secs);
+                wait_secs(delay_secs);
+            }
+            power::reboot();
+        }
+    }
+    // Only reached without the isa-debug-exit device for `ExitQemu`
+    speaker::alert();
+    crate::halt();
+}
+
+/// Spins for `secs` seconds, or not at all without a clock.
+fn wait_secs(secs: u32) {
+    let Some(start) = timer::uptime_us() else {
+        return;
+    };
+    while timer::uptime_us().is_some_and(|now| now - start < secs as u64 * 1_000_000) {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_parse_policy() {
+    assert_eq!(parse("halt"), Some(Policy::Halt));
+    assert_eq!(parse("reboot"), Some(Policy::Reboot { delay_secs: 0 }));
+    assert_eq!(parse("10"), Some(Policy::Reboot { delay_secs: 10 }));
+    assert_eq!(parse("exit"), Some(Policy::ExitQemu));
+    assert_eq!(parse("-1"), None);
+}
+
+#[test_case]
+fn test_set_round_trips() {
+    set(Policy::Reboot { delay_secs: 5 });
+    assert_eq!(get(), Policy::Reboot { delay_secs: 5 });
+    set(Policy::Halt);
+    assert_eq!(get(), Policy::Halt);
+}
@@ -0,0 +1,327 @@
+//! PCI configuration space access.
+//!
+//! Buses covered by the ACPI MCFG table are reached through their memory-mapped configuration
+//! space (PCI Express ECAM) once [`init_ecam`] has run, which also exposes the extended 4 KiB of
+//! each function. Everything else goes through the legacy I/O port mechanism (0xCF8/0xCFC),
+//! which only reaches the first 256 bytes.
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::ioport::{self, IoRegion};
+use crate::{kernel_acpi, memory, warn};
+
+/// The configuration mechanism's ports: the address register, then the data register.
+const CONFIG_PORTS: u16 = 0xCF8;
+const CONFIG_PORT_COUNT: u16 = 8;
+const CONFIG_ADDRESS: u16 = 0;
+const CONFIG_DATA: u16 = 4;
+
+const REG_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0C;
+const REG_BAR0: u8 = 0x10;
+const REG_SUBSYSTEM: u8 = 0x2C;
+const REG_INTERRUPT: u8 = 0x3C;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+/// Extended capabilities start right after the legacy configuration space.
+const EXTENDED_CAPABILITIES: u16 = 0x100;
+const CONFIG_SPACE_SIZE: u16 = 0x1000;
+
+/// A range of buses whose configuration space is memory mapped.
+struct Ecam {
+    buses: RangeInclusive<u8>,
+    /// Bus 0's configuration space, through the physical memory mapping.
+    base: u64,
+}
+
+/// ECAM regions for PCI segment 0, the only segment [`PciAddress`] can name.
+static ECAM: Once<Vec<Ecam>> = Once::new();
+static CONFIG: Once<IoRegion> = Once::new();
+
+fn config_ports() -> &'static IoRegion {
+    CONFIG.call_once(|| {
+        unsafe { ioport::claim("pci", CONFIG_PORTS, CONFIG_PORT_COUNT) }
+            .expect("PCI configuration ports taken")
+    })
+}
+
+/// Switches configuration access to memory-mapped ECAM for the buses in the ACPI MCFG table,
+/// and returns how many bus ranges it covers. Without an MCFG everything keeps using the I/O
+/// ports. Only the first call has any effect.
+pub fn init_ecam() -> usize {
+    let regions = ECAM.call_once(|| {
+        let Some(registry) = kernel_acpi::registry() else {
+            return Vec::new();
+        };
+        registry
+            .mcfg()
+            .iter()
+            .filter(|region| region.segment == 0)
+            .filter_map(|region| {
+                // `base` is where bus 0 would be; only the region's own buses are there
+                let first = region.base.as_u64() + ((*region.buses.start() as u64) << 20);
+                let len = (region.buses.clone().count() as u64) << 20;
+                match memory::map_mmio(PhysAddr::new(first), len) {
+                    Ok(virt) => Some(Ecam {
+                        buses: region.buses.clone(),
+                        base: virt.as_u64() - (first - region.base.as_u64()),
+                    }),
+                    Err(e) => {
+                        warn!("ECAM for buses {:?} unusable: {}", region.buses, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    });
+    regions.len()
+}
+
+/// Offset of a function's configuration register from the start of bus 0's ECAM space.
+fn ecam_offset(address: PciAddress, offset: u16) -> u64 {
+    (address.bus as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12
+        | (offset & 0xFFC) as u64
+}
+
+/// The location of a function on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub subsystem_id: u16,
+    /// Legacy interrupt line as routed by the firmware, if any.
+    pub interrupt_line: Option<u8>,
+}
+
+/// A decoded base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory { addr: u64, prefetchable: bool },
+}
+
+impl PciAddress {
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    /// The register's address in an ECAM region, if one covers this bus.
+    fn ecam_register(self, offset: u16) -> Option<*mut u32> {
+        let ecam = ECAM
+            .get()?
+            .iter()
+            .find(|ecam| ecam.buses.contains(&self.bus))?;
+        Some((ecam.base + ecam_offset(self, offset)) as *mut u32)
+    }
+
+    pub fn read_u32(self, offset: u8) -> u32 {
+        if let Some(register) = self.ecam_register(offset as u16) {
+            return unsafe { register.read_volatile() };
+        }
+        let ports = config_ports();
+        ports.write(CONFIG_ADDRESS, self.config_address(offset));
+        ports.read(CONFIG_DATA)
+    }
+
+    pub fn write_u32(self, offset: u8, value: u32) {
+        if let Some(register) = self.ecam_register(offset as u16) {
+            return unsafe { register.write_volatile(value) };
+        }
+        let ports = config_ports();
+        ports.write(CONFIG_ADDRESS, self.config_address(offset));
+        ports.write(CONFIG_DATA, value);
+    }
+
+    /// Reads anywhere in the 4 KiB configuration space. Returns `None` if the bus has no ECAM
+    /// region, as the I/O ports can't reach past the first 256 bytes.
+    pub fn read_extended_u32(self, offset: u16) -> Option<u32> {
+        assert!(
+            offset < CONFIG_SPACE_SIZE,
+            "config offset {:#x} out of range",
+            offset
+        );
+        let register = self.ecam_register(offset)?;
+        Some(unsafe { register.read_volatile() })
+    }
+
+    /// Writes anywhere in the 4 KiB configuration space. Returns `false` without writing if
+    /// the bus has no ECAM region.
+    pub fn write_extended_u32(self, offset: u16, value: u32) -> bool {
+        assert!(
+            offset < CONFIG_SPACE_SIZE,
+            "config offset {:#x} out of range",
+            offset
+        );
+        let Some(register) = self.ecam_register(offset) else {
+            return false;
+        };
+        unsafe { register.write_volatile(value) };
+        true
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let id = address.read_u32(REG_ID);
+        let vendor_id = id as u16;
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let class = address.read_u32(REG_CLASS);
+        let line = address.read_u8(REG_INTERRUPT);
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            subsystem_id: address.read_u16(REG_SUBSYSTEM + 2),
+            interrupt_line: (line != 0xFF).then_some(line),
+        })
+    }
+
+    /// Decodes BAR `index` (0 to 5). Returns `None` for unimplemented BARs and the upper half
+    /// of 64-bit ones.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index > 5 {
+            return None;
+        }
+        let offset = REG_BAR0 + index * 4;
+        let low = self.address.read_u32(offset);
+        if low & 1 == 1 {
+            let port = (low & !0x3) as u16;
+            return (port != 0).then_some(Bar::Io(port));
+        }
+        let addr = match (low >> 1) & 0x3 {
+            0 => (low & !0xF) as u64,
+            2 if index < 5 => {
+                let high = self.address.read_u32(offset + 4) as u64;
+                high << 32 | (low & !0xF) as u64
+            }
+            _ => return None,
+        };
+        (addr != 0).then_some(Bar::Memory {
+            addr,
+            prefetchable: low & 0x8 != 0,
+        })
+    }
+
+    /// Finds a PCI Express extended capability by ID and returns its offset in configuration
+    /// space. Always `None` without ECAM.
+    pub fn find_extended_capability(&self, id: u16) -> Option<u16> {
+        let mut offset = EXTENDED_CAPABILITIES;
+        // Each capability is at least a dword, which bounds the walk on a looping list
+        for _ in 0..(CONFIG_SPACE_SIZE - EXTENDED_CAPABILITIES) / 4 {
+            let header = self.address.read_extended_u32(offset)?;
+            if header == 0 || header == u32::MAX {
+                return None;
+            }
+            if header as u16 == id {
+                return Some(offset);
+            }
+            offset = (header >> 20) as u16 & 0xFFC;
+            if offset < EXTENDED_CAPABILITIES {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Turns on I/O and memory decoding, and DMA if `bus_master` is set.
+    pub fn enable(&self, bus_master: bool) {
+        let mut command = self.address.read_u16(REG_COMMAND) | COMMAND_IO | COMMAND_MEMORY;
+        if bus_master {
+            command |= COMMAND_BUS_MASTER;
+        }
+        self.address.write_u16(REG_COMMAND, command);
+    }
+}
+
+/// Enumerates every function on every bus by brute force.
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32u8 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(found) = PciDevice::probe(first) else {
+                continue;
+            };
+            devices.push(found);
+            if first.read_u8(REG_HEADER_TYPE + 2) & HEADER_MULTIFUNCTION == 0 {
+                continue;
+            }
+            for function in 1..8 {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                devices.extend(PciDevice::probe(address));
+            }
+        }
+    }
+    devices
+}
+
+/// Finds the first function with the given vendor and device ID.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    devices()
+        .into_iter()
+        .find(|d| d.vendor_id == vendor_id && d.device_id == device_id)
+}
+
+#[test_case]
+fn test_ecam_offset() {
+    let address = PciAddress {
+        bus: 3,
+        device: 31,
+        function: 7,
+    };
+    assert_eq!(ecam_offset(address, 0x100), 0x3F_F100);
+    // Registers are dword aligned
+    assert_eq!(ecam_offset(address, 0xFFF), 0x3F_FFFC);
+}
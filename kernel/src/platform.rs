@@ -0,0 +1,226 @@
+//! A description of the machine's fixed physical addresses: the interrupt controllers and the
+//! page the AP trampoline runs from.
+//!
+//! It is filled in from ACPI and the frame allocator's low-memory reservations when they are
+//! available, with the conventional PC addresses otherwise, so nothing else has to hardcode
+//! them.
+use alloc::vec::Vec;
+
+use acpi::PlatformInfo;
+use acpi::platform::interrupt::{InterruptModel, Polarity, TriggerMode};
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::smp::trampoline::TRAMPOLINE_BASE;
+use crate::{info, memory};
+
+/// Where the local APIC lives unless the firmware says otherwise.
+pub const DEFAULT_LOCAL_APIC: u64 = 0xFEE0_0000;
+/// Where the first I/O APIC lives unless the firmware says otherwise.
+pub const DEFAULT_IO_APIC: u64 = 0xFEC0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// How an ISA IRQ reaches the I/O APICs, after any MADT interrupt source override.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaRoute {
+    pub gsi: u32,
+    pub trigger: TriggerMode,
+    pub polarity: Polarity,
+}
+
+#[derive(Debug, Clone)]
+pub struct Platform {
+    pub local_apic: PhysAddr,
+    /// Sorted by `gsi_base`.
+    pub io_apics: Vec<IoApic>,
+    /// ISA IRQs the firmware has wired differently from the identity mapping, by IRQ number.
+    pub isa_overrides: Vec<(u8, IsaRoute)>,
+    /// Physical address of the page the AP trampoline is copied to and started at.
+    pub trampoline: PhysAddr,
+    /// Whether `trampoline` is a RAM page below 1 MiB that the frame allocator reserved for
+    /// the trampoline. Without one, the APs can't be started.
+    pub trampoline_usable: bool,
+}
+
+static PLATFORM: Once<Platform> = Once::new();
+
+/// Builds the platform description. Without ACPI (`platform_info` is `None`) the default PC
+/// addresses are assumed. The trampoline page is the one the frame allocator reserved.
+pub fn init(platform_info: Option<&PlatformInfo<'_, alloc::alloc::Global>>) -> &'static Platform {
+    PLATFORM.call_once(|| {
+        let mut platform = Platform::default();
+        if let Some(InterruptModel::Apic(apic)) = platform_info.map(|info| &info.interrupt_model) {
+            platform.local_apic = PhysAddr::new(apic.local_apic_address);
+            if !apic.io_apics.is_empty() {
+                platform.io_apics = apic
+                    .io_apics
+                    .iter()
+                    .map(|io_apic| IoApic {
+                        id: io_apic.id,
+                        address: PhysAddr::new(io_apic.address as u64),
+                        gsi_base: io_apic.global_system_interrupt_base,
+                    })
+                    .collect();
+                platform.io_apics.sort_by_key(|io_apic| io_apic.gsi_base);
+            }
+            platform.isa_overrides = apic
+                .interrupt_source_overrides
+                .iter()
+                .map(|o| {
+                    let route = IsaRoute {
+                        gsi: o.global_system_interrupt,
+                        trigger: o.trigger_mode,
+                        polarity: o.polarity,
+                    };
+                    (o.isa_source, route)
+                })
+                .collect();
+        }
+        if let Some(page) = memory::low_memory().and_then(|low| low.trampoline) {
+            platform.trampoline = page;
+            platform.trampoline_usable = true;
+        }
+        info!(
+            "platform: local APIC {:#x}, {} I/O APIC(s), trampoline {:#x}{}",
+            platform.local_apic.as_u64(),
+            platform.io_apics.len(),
+            platform.trampoline.as_u64(),
+            if platform.trampoline_usable {
+                ""
+            } else {
+                " (no free page below 1 MiB)"
+            }
+        );
+        platform
+    })
+}
+
+/// The platform description, or the defaults if [`init`] hasn't run.
+pub fn get() -> &'static Platform {
+    PLATFORM.call_once(Platform::default)
+}
+
+impl Default for Platform {
+    /// A PC with one I/O APIC at the usual addresses and no trampoline page reserved.
+    fn default() -> Self {
+        Platform {
+            local_apic: PhysAddr::new(DEFAULT_LOCAL_APIC),
+            io_apics: alloc::vec![IoApic {
+                id: 0,
+                address: PhysAddr::new(DEFAULT_IO_APIC),
+                gsi_base: 0,
+            }],
+            isa_overrides: Vec::new(),
+            trampoline: PhysAddr::new(TRAMPOLINE_BASE as u64),
+            trampoline_usable: false,
+        }
+    }
+}
+
+impl Platform {
+    /// The I/O APIC that handles `gsi`, and the pin it arrives on there.
+    pub fn io_apic_for_gsi(&self, gsi: u32) -> Option<(&IoApic, u32)> {
+        io_apic_for_gsi(&self.io_apics, gsi)
+    }
+
+    /// Where ISA IRQ `irq` arrives. ISA interrupts are edge triggered and active high unless
+    /// an override says otherwise.
+    pub fn isa_irq(&self, irq: u8) -> IsaRoute {
+        isa_route(&self.isa_overrides, irq)
+    }
+
+    /// The SIPI vector that starts an AP at the trampoline.
+    pub fn trampoline_vector(&self) -> u8 {
+        (self.trampoline.as_u64() >> 12) as u8
+    }
+}
+
+fn io_apic_for_gsi(io_apics: &[IoApic], gsi: u32) -> Option<(&IoApic, u32)> {
+    io_apics
+        .iter()
+        .rev()
+        .find(|io_apic| io_apic.gsi_base <= gsi)
+        .map(|io_apic| (io_apic, gsi - io_apic.gsi_base))
+}
+
+fn isa_route(overrides: &[(u8, IsaRoute)], irq: u8) -> IsaRoute {
+    let route = overrides
+        .iter()
+        .find(|&&(source, _)| source == irq)
+        .map(|&(_, route)| route);
+    let Some(route) = route else {
+        return IsaRoute {
+            gsi: irq as u32,
+            trigger: TriggerMode::Edge,
+            polarity: Polarity::ActiveHigh,
+        };
+    };
+    IsaRoute {
+        gsi: route.gsi,
+        trigger: match route.trigger {
+            TriggerMode::SameAsBus => TriggerMode::Edge,
+            trigger => trigger,
+        },
+        polarity: match route.polarity {
+            Polarity::SameAsBus => Polarity::ActiveHigh,
+            polarity => polarity,
+        },
+    }
+}
+
+#[test_case]
+fn test_io_apic_for_gsi() {
+    let io_apic = |id, gsi_base| IoApic {
+        id,
+        address: PhysAddr::new(DEFAULT_IO_APIC + id as u64 * 0x1000),
+        gsi_base,
+    };
+    let io_apics = [io_apic(0, 0), io_apic(1, 24)];
+    assert_eq!(
+        io_apic_for_gsi(&io_apics, 1).map(|(a, pin)| (a.id, pin)),
+        Some((0, 1))
+    );
+    assert_eq!(
+        io_apic_for_gsi(&io_apics, 30).map(|(a, pin)| (a.id, pin)),
+        Some((1, 6))
+    );
+    // Built by hand, as the defaults would need the heap
+    let platform = Platform {
+        local_apic: PhysAddr::new(DEFAULT_LOCAL_APIC),
+        io_apics: Vec::new(),
+        isa_overrides: Vec::new(),
+        trampoline: PhysAddr::new(TRAMPOLINE_BASE as u64),
+        trampoline_usable: false,
+    };
+    assert_eq!(platform.trampoline_vector(), 0x8);
+}
+
+#[test_case]
+fn test_isa_irq_overrides() {
+    let timer = IsaRoute {
+        gsi: 2,
+        trigger: TriggerMode::SameAsBus,
+        polarity: Polarity::SameAsBus,
+    };
+    let sci = IsaRoute {
+        gsi: 9,
+        trigger: TriggerMode::Level,
+        polarity: Polarity::ActiveHigh,
+    };
+    let overrides = [(0, timer), (9, sci)];
+    let edge_high = |gsi| IsaRoute {
+        gsi,
+        trigger: TriggerMode::Edge,
+        polarity: Polarity::ActiveHigh,
+    };
+    assert_eq!(isa_route(&overrides, 0), edge_high(2));
+    assert_eq!(isa_route(&overrides, 1), edge_high(1));
+    assert_eq!(isa_route(&overrides, 9), sci);
+}
@@ -0,0 +1,239 @@
+//! System shutdown, reboot and suspend to idle.
+//!
+//! Shutdown enters ACPI sleep state S5 by writing `SLP_TYPx | SLP_EN` to the PM1 control
+//! registers named in the FADT, with the sleep type values taken from the DSDT's `\_S5_` package.
+//! If ACPI is unavailable, the well-known QEMU/Bochs/VirtualBox power-off ports are tried.
+//! Reboot tries the FADT reset register, then the keyboard controller reset line, and finally
+//! forces a triple fault.
+//!
+//! [`suspend::suspend`] keeps everything powered and idles the machine until a key or the RTC
+//! alarm wakes it.
+pub mod suspend;
+
+use acpi::{AcpiTables, AddressSpace, GenericAddress};
+use spin::Once;
+use x86_64::PhysAddr;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::kernel_acpi::{self, KernelAcpiHandler};
+use crate::{error, info, memory, warn};
+
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+
+/// `SLP_TYP` value for S5 used by QEMU's generated DSDT, for when the real one can't be parsed.
+const QEMU_SLP_TYP_S5: u8 = 0;
+
+/// Power-off ports used by emulators: (port, value).
+const EMULATOR_SHUTDOWN_PORTS: &[(u16, u16)] = &[
+    (0x604, 0x2000),  // QEMU (i440fx and q35)
+    (0xB004, 0x2000), // Bochs and older QEMU
+    (0x4004, 0x3400), // VirtualBox
+];
+
+#[derive(Debug, Clone, Copy)]
+struct PowerInfo {
+    pm1a_control: Option<u16>,
+    pm1b_control: Option<u16>,
+    slp_typa: u8,
+    slp_typb: u8,
+    reset_register: Option<(u16, u8)>,
+    smi_command: u16,
+    acpi_enable: u8,
+}
+
+static POWER_INFO: Once<PowerInfo> = Once::new();
+
+/// Reads the FADT from the ACPI registry and the DSDT to discover how to power off and reset
+/// the machine.
+pub fn init(tables: &AcpiTables<KernelAcpiHandler>) {
+    let fadt = match kernel_acpi::registry().map(|registry| registry.fadt()) {
+        Some(Ok(fadt)) => fadt,
+        Some(Err(e)) => {
+            warn!("FADT unavailable ({:?}); power control uses fallbacks", e);
+            return;
+        }
+        None => return,
+    };
+
+    let (slp_typa, slp_typb) = match tables.dsdt() {
+        Ok(dsdt) => {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            match memory::map_physical(
+                PhysAddr::new(dsdt.address as u64),
+                dsdt.length as u64,
+                flags,
+            ) {
+                Ok(virt) => {
+                    let aml = unsafe {
+                        core::slice::from_raw_parts(virt.as_ptr::<u8>(), dsdt.length as usize)
+                    };
+                    find_s5(aml).unwrap_or((QEMU_SLP_TYP_S5, QEMU_SLP_TYP_S5))
+                }
+                Err(e) => {
+                    warn!("DSDT unreadable ({}); assuming QEMU's S5 sleep type", e);
+                    (QEMU_SLP_TYP_S5, QEMU_SLP_TYP_S5)
+                }
+            }
+        }
+        Err(_) => (QEMU_SLP_TYP_S5, QEMU_SLP_TYP_S5),
+    };
+
+    let info = PowerInfo {
+        pm1a_control: fadt.pm1a_control.and_then(io_port),
+        pm1b_control: fadt.pm1b_control.and_then(io_port),
+        slp_typa,
+        slp_typb,
+        reset_register: fadt
+            .reset_register
+            .and_then(io_port)
+            .map(|port| (port, fadt.reset_value)),
+        smi_command: fadt.smi_command as u16,
+        acpi_enable: fadt.acpi_enable,
+    };
+    info!(
+        "ACPI power: PM1a_CNT={:x?}, S5 SLP_TYP=({}, {}), reset={:x?}",
+        info.pm1a_control, info.slp_typa, info.slp_typb, info.reset_register
+    );
+    POWER_INFO.call_once(|| info);
+}
+
+/// Returns the port number of a generic address in system I/O space.
+fn io_port(address: GenericAddress) -> Option<u16> {
+    if address.address_space == AddressSpace::SystemIo
+        && address.address != 0
+        && address.address <= u16::MAX as u64
+    {
+        Some(address.address as u16)
+    } else {
+        None
+    }
+}
+
+/// Finds the `\_S5_` package in AML bytecode and returns its SLP_TYPa and SLP_TYPb values.
+///
+/// This is not a general AML interpreter: it looks for `NameOp "_S5_" PackageOp` and decodes
+/// the first two elements as integer constants, which is how every firmware we care about
+/// encodes it.
+pub fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    let name_op = match pos {
+        0 => return None,
+        1 => aml[0],
+        // Skip an optional root prefix
+        _ if aml[pos - 1] == b'\\' => aml[pos - 2],
+        _ => aml[pos - 1],
+    };
+    if name_op != 0x08 {
+        return None;
+    }
+
+    let mut i = pos + 4;
+    if *aml.get(i)? != 0x12 {
+        return None; // PackageOp
+    }
+    i += 1;
+    // PkgLength: the top two bits of the lead byte count the extra length bytes
+    i += ((*aml.get(i)? >> 6) & 0x3) as usize + 1;
+    i += 1; // NumElements
+
+    let (slp_typa, len) = aml_integer(aml.get(i..)?)?;
+    i += len;
+    let (slp_typb, _) = aml_integer(aml.get(i..)?)?;
+    Some((slp_typa, slp_typb))
+}
+
+/// Decodes a small AML integer constant, returning the value and encoded length.
+fn aml_integer(bytes: &[u8]) -> Option<(u8, usize)> {
+    match *bytes.first()? {
+        0x0A => Some((*bytes.get(1)?, 2)), // BytePrefix
+        0x00 => Some((0, 1)),              // ZeroOp
+        0x01 => Some((1, 1)),              // OneOp
+        _ => None,
+    }
+}
+
+/// Makes sure the chipset is in ACPI mode so PM1 control writes take effect.
+unsafe fn enable_acpi(info: &PowerInfo, pm1a_control: u16) {
+    let mut control: Port<u16> = Port::new(pm1a_control);
+    if unsafe { control.read() } & SCI_EN != 0 || info.smi_command == 0 || info.acpi_enable == 0 {
+        return;
+    }
+    unsafe { Port::<u8>::new(info.smi_command).write(info.acpi_enable) };
+    for _ in 0..1_000_000 {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Powers the machine off.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(info) = POWER_INFO.get()
+        && let Some(pm1a_control) = info.pm1a_control
+    {
+        unsafe {
+            enable_acpi(info, pm1a_control);
+            Port::<u16>::new(pm1a_control).write(((info.slp_typa as u16) << 10) | SLP_EN);
+            if let Some(pm1b_control) = info.pm1b_control {
+                Port::<u16>::new(pm1b_control).write(((info.slp_typb as u16) << 10) | SLP_EN);
+            }
+        }
+    }
+
+    for &(port, value) in EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+
+    error!("shutdown failed; halting");
+    crate::halt();
+}
+
+/// Resets the machine.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    if let Some(&(port, value)) = POWER_INFO
+        .get()
+        .and_then(|info| info.reset_register.as_ref())
+    {
+        unsafe { Port::<u8>::new(port).write(value) };
+    }
+
+    // Pulse the CPU reset line through the keyboard controller
+    crate::ps2::pulse_reset();
+
+    // Load an empty IDT and raise an exception: the resulting triple fault resets the CPU
+    unsafe {
+        use x86_64::structures::DescriptorTablePointer;
+        let empty = DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::new(0),
+        };
+        x86_64::instructions::tables::lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::halt();
+}
+
+#[test_case]
+fn test_find_s5() {
+    // Name(\_S5_, Package(0x04) { 0x05, 0x05, Zero, Zero })
+    let aml = [
+        0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x0A, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00,
+        0x00,
+    ];
+    assert_eq!(find_s5(&aml[1..]), Some((5, 5)));
+
+    // QEMU: Name(_S5_, Package(0x04) { Zero, Zero, Zero, Zero })
+    let qemu = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(find_s5(&qemu), Some((0, 0)));
+
+    assert_eq!(find_s5(b"no sleep states here"), None);
+}
@@ -0,0 +1,227 @@
+//! Suspend to idle: nothing is powered off and memory stays as it is, but devices are quiesced,
+//! every device interrupt line but the wake-up sources is masked, the APs are parked and the
+//! BSP waits in its deepest C-state until a key is pressed or the RTC alarm goes off.
+//!
+//! Drivers take part by registering a [`SuspendHook`]. Hooks suspend in the order they were
+//! registered and resume in reverse, and a hook that refuses to suspend aborts the suspend,
+//! resuming those that already had.
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+use crate::apic_ptr::APIC_BASE;
+use crate::irq::{self, IrqError, IrqHandle};
+use crate::smp::park::{self, ParkError, ParkState};
+use crate::{cpu, info, interrupts, platform, time, warn};
+
+/// Hooks that can be registered.
+const MAX_HOOKS: usize = 16;
+const KEYBOARD_IRQ: u8 = 1;
+const RTC_IRQ: u8 = 8;
+
+/// A driver's part in suspending and resuming.
+#[derive(Clone, Copy)]
+pub struct SuspendHook {
+    pub name: &'static str,
+    /// Quiesces the device, or says why it can't, which aborts the suspend.
+    pub suspend: fn() -> Result<(), &'static str>,
+    /// Brings the device back, after a suspend or after a later hook refused to suspend.
+    pub resume: fn(),
+}
+
+static HOOKS: Mutex<[Option<SuspendHook>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspendError {
+    /// Only the BSP suspends, as device interrupts wake it.
+    NotBsp,
+    /// Masking lines and parking APs need the local and I/O APICs.
+    NoApic,
+    /// A hook refused to suspend its device.
+    Hook {
+        name: &'static str,
+        reason: &'static str,
+    },
+    /// An AP couldn't be parked.
+    Park(ParkError),
+    /// The RTC's interrupt couldn't be routed, so the alarm couldn't wake the machine.
+    RtcIrq(IrqError),
+}
+
+impl fmt::Display for SuspendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuspendError::NotBsp => write!(f, "only the BSP can suspend"),
+            SuspendError::NoApic => write!(f, "no APIC"),
+            SuspendError::Hook { name, reason } => write!(f, "{} refused: {}", name, reason),
+            SuspendError::Park(e) => write!(f, "parking failed: {:?}", e),
+            SuspendError::RtcIrq(e) => write!(f, "RTC interrupt not routed: {:?}", e),
+        }
+    }
+}
+
+/// What ended a suspend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    Keyboard,
+    Alarm,
+}
+
+/// Set by the RTC interrupt when the alarm goes off.
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+/// The RTC's interrupt, routed the first time a suspend sets an alarm.
+static RTC_HANDLE: Once<Result<IrqHandle, IrqError>> = Once::new();
+
+/// Adds `hook` to those [`suspend`] runs. Panics if there are already [`MAX_HOOKS`].
+pub fn register(hook: SuspendHook) {
+    let mut hooks = HOOKS.lock();
+    let slot = hooks
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many suspend hooks");
+    *slot = Some(hook);
+}
+
+fn rtc_interrupt() -> bool {
+    let alarm = time::ack_rtc_interrupt();
+    if alarm {
+        ALARM_FIRED.store(true, Ordering::Release);
+    }
+    alarm
+}
+
+/// Resumes `hooks`, last first.
+fn resume_hooks(hooks: &[SuspendHook]) {
+    for hook in hooks.iter().rev() {
+        (hook.resume)();
+    }
+}
+
+/// Parks every running AP, and returns which were parked.
+fn park_aps() -> Result<Vec<usize>, ParkError> {
+    let mut parked = Vec::new();
+    for (index, _) in cpu::online().filter(|&(index, _)| index != 0) {
+        if park::state(index) != Some(ParkState::Running) {
+            continue;
+        }
+        if let Err(e) = park::park(index) {
+            unpark_aps(&parked);
+            return Err(e);
+        }
+        parked.push(index);
+    }
+    Ok(parked)
+}
+
+fn unpark_aps(parked: &[usize]) {
+    for &index in parked {
+        if let Err(e) = park::unpark(index) {
+            warn!("suspend: cpu{} not unparked: {:?}", index, e);
+        }
+    }
+}
+
+/// Suspends to idle until a key is pressed or, with `alarm_secs`, the RTC alarm goes off that
+/// many seconds from now, and returns which woke the machine. Timer ticks stop while
+/// suspended; the clocks don't. Call on the BSP, outside interrupt handlers.
+pub fn suspend(alarm_secs: Option<u32>) -> Result<Wake, SuspendError> {
+    if !cpu::is_bsp() {
+        return Err(SuspendError::NotBsp);
+    }
+    if unsafe { APIC_BASE }.is_none() {
+        return Err(SuspendError::NoApic);
+    }
+    if alarm_secs.is_some() {
+        RTC_HANDLE
+            .call_once(|| irq::register_isa(RTC_IRQ, "rtc", rtc_interrupt))
+            .map_err(SuspendError::RtcIrq)?;
+    }
+
+    let hooks: Vec<SuspendHook> = HOOKS.lock().iter().flatten().copied().collect();
+    for (suspended, hook) in hooks.iter().enumerate() {
+        if let Err(reason) = (hook.suspend)() {
+            resume_hooks(&hooks[..suspended]);
+            return Err(SuspendError::Hook {
+                name: hook.name,
+                reason,
+            });
+        }
+    }
+    let parked = park_aps().map_err(|e| {
+        resume_hooks(&hooks);
+        SuspendError::Park(e)
+    })?;
+    info!(
+        "suspend: {} devices quiesced, {} CPUs parked",
+        hooks.len(),
+        parked.len()
+    );
+
+    let platform = platform::get();
+    let wake_sources = [
+        platform.isa_irq(KEYBOARD_IRQ).gsi,
+        platform.isa_irq(RTC_IRQ).gsi,
+    ];
+    let were_enabled = cpu_interrupts::are_enabled();
+    cpu_interrupts::disable();
+    let masks = irq::mask_lines_except(&wake_sources);
+    interrupts::set_apic_timer_masked(true);
+    ALARM_FIRED.store(false, Ordering::Relaxed);
+    if let Some(secs) = alarm_secs {
+        time::set_rtc_alarm(secs.max(1));
+    }
+
+    // Only the wake sources are left to interrupt
+    let delivered = irq::delivered();
+    while irq::delivered() == delivered {
+        cpu::idle::enable_and_idle();
+        cpu_interrupts::disable();
+    }
+    let wake = if ALARM_FIRED.load(Ordering::Acquire) {
+        Wake::Alarm
+    } else {
+        Wake::Keyboard
+    };
+
+    if alarm_secs.is_some() {
+        time::clear_rtc_alarm();
+    }
+    interrupts::set_apic_timer_masked(false);
+    irq::restore_lines(masks);
+    if were_enabled {
+        cpu_interrupts::enable();
+    }
+    unpark_aps(&parked);
+    resume_hooks(&hooks);
+    info!("suspend: woken by {:?}", wake);
+    Ok(wake)
+}
+
+#[test_case]
+fn test_refused_suspend_resumes_earlier_hooks() {
+    use core::sync::atomic::AtomicUsize;
+
+    static RESUMED: AtomicUsize = AtomicUsize::new(0);
+    let hooks = [
+        SuspendHook {
+            name: "quiet",
+            suspend: || Ok(()),
+            resume: || {
+                RESUMED.fetch_add(1, Ordering::Relaxed);
+            },
+        },
+        SuspendHook {
+            name: "busy",
+            suspend: || Err("transfer in flight"),
+            resume: || panic!("resumed a hook that never suspended"),
+        },
+    ];
+    // As `suspend` unwinds when the second hook refuses
+    assert!((hooks[0].suspend)().is_ok());
+    assert_eq!((hooks[1].suspend)(), Err("transfer in flight"));
+    resume_hooks(&hooks[..1]);
+    assert_eq!(RESUMED.load(Ordering::Relaxed), 1);
+}
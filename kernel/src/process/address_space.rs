@@ -0,0 +1,591 @@
+//! Per-process page tables.
+//!
+//! Every address space has its own level 4 table. The level 4 entries in the user range start
+//! out empty; all others are copied from the kernel's table, so kernel mappings are shared and
+//! stay valid whichever address space is active. The kernel must therefore not create new
+//! top-level entries after processes exist, or they won't appear in existing address spaces.
+//!
+//! [`AddressSpace::fork`] shares every user page with the child instead of copying it. Writable
+//! pages become read-only in both, marked with [`COW`], and the first write to one takes a page
+//! fault that [`handle_cow_fault`] resolves by giving the writer a private copy.
+//!
+//! Device memory mapped with [`AddressSpace::map_device`], and kernel pages mapped with
+//! [`AddressSpace::map_kernel_frame`], are marked [`DEVICE`]. Their frames aren't the
+//! process's, so they are never freed or copied, and `fork` shares them as they are.
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use spin::{Mutex, Once};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PageTableIndex, PhysFrame, Size4KiB, Translate,
+    mapper::{MapToError, TranslateError, TranslateResult},
+    page_table::PageTableEntry,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::fault::{self, Site};
+use crate::init::memory_init::{get_offset, get_offset_u64};
+use crate::mem;
+use crate::memory::{self, CountingFrameAllocator, PAGE_SIZE, prune_page_tables, zeroed};
+
+/// Start of the range reserved for user mappings, which runs up to the end of the lower half.
+/// The level 4 entries below it hold the kernel image and the bootloader's mappings.
+pub const USER_START: u64 = 0x0000_4000_0000_0000;
+
+const USER_P4_RANGE: core::ops::Range<usize> = (USER_START >> 39) as usize..256;
+
+/// Software-defined PTE bit marking a page that is shared copy-on-write. Such pages are mapped
+/// read-only even though the process may write to them.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Software-defined PTE bit marking a page whose frame the process doesn't own: device memory,
+/// such as a framebuffer, or a page of the kernel's, such as the vDSO's.
+pub const DEVICE: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Where [`AddressSpace::map_device`] starts looking for room, well above program images and
+/// below the stack.
+pub const MMAP_BASE: u64 = 0x0000_6000_0000_0000;
+const MMAP_END: u64 = 0x0000_7F00_0000_0000;
+
+static KERNEL_PML4: Once<PhysFrame> = Once::new();
+
+/// Frames mapped by more than one address space, with the number of extra references.
+/// Frames not in the map have a single owner.
+static SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, u32>> = Mutex::new(BTreeMap::new());
+
+fn share_frame(frame: PhysFrame) {
+    *SHARED_FRAMES.lock().entry(frame).or_insert(0) += 1;
+}
+
+/// Drops one reference to `frame`. Returns `true` if it was the last one and the frame is free.
+fn release_frame(frame: PhysFrame) -> bool {
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&frame) {
+        Some(1) => {
+            shared.remove(&frame);
+            false
+        }
+        Some(count) => {
+            *count -= 1;
+            false
+        }
+        None => true,
+    }
+}
+
+/// The kernel's own level 4 table, as set up by the bootloader. Recorded the first time an
+/// address space is created, which happens from kernel context.
+pub fn kernel_pml4() -> PhysFrame {
+    *KERNEL_PML4.call_once(|| Cr3::read().0)
+}
+
+/// Switches back to the kernel's page tables.
+pub fn activate_kernel() {
+    let frame = kernel_pml4();
+    if Cr3::read().0 != frame {
+        unsafe { Cr3::write(frame, Cr3Flags::empty()) };
+    }
+}
+
+fn phys_to_ptr<T>(addr: PhysAddr) -> *mut T {
+    memory::phys_to_virt(addr)
+        .expect("page table outside the physical memory mapping")
+        .as_mut_ptr()
+}
+
+/// Looks up `addr` in the active page tables and returns the flags of its mapping.
+pub fn translate_active(addr: VirtAddr) -> Option<PageTableFlags> {
+    let table = unsafe { &mut *phys_to_ptr::<PageTable>(Cr3::read().0.start_address()) };
+    let mapper = unsafe { OffsetPageTable::new(table, get_offset()) };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// Looks up `addr` in the active page tables and returns the physical address it maps to.
+pub fn translate_phys_active(addr: VirtAddr) -> Option<PhysAddr> {
+    let table = unsafe { &mut *phys_to_ptr::<PageTable>(Cr3::read().0.start_address()) };
+    let mapper = unsafe { OffsetPageTable::new(table, get_offset()) };
+    mapper.translate_addr(addr)
+}
+
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    /// Level 3, 2 and 1 tables allocated for user mappings, not counting the level 4 table.
+    table_frames: usize,
+    /// User pages mapped, whether or not their frames are shared, and the most there have been.
+    resident_pages: usize,
+    peak_pages: usize,
+    /// Regions mapped by [`map_device`](Self::map_device), from start to end address.
+    regions: BTreeMap<u64, u64>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mapped and the user range empty.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let kernel = kernel_pml4();
+        let frame = {
+            let mut guard = PAGE_ALLOCATOR.lock();
+            let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+            page_alloc
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?
+        };
+
+        let table = unsafe { &mut *phys_to_ptr::<PageTable>(frame.start_address()) };
+        let kernel_table = unsafe { &*phys_to_ptr::<PageTable>(kernel.start_address()) };
+        for (i, entry) in table.iter_mut().enumerate() {
+            if USER_P4_RANGE.contains(&i) {
+                entry.set_unused();
+            } else {
+                *entry = kernel_table[i].clone();
+            }
+        }
+        Ok(AddressSpace {
+            pml4: frame,
+            table_frames: 0,
+            resident_pages: 0,
+            peak_pages: 0,
+            regions: BTreeMap::new(),
+        })
+    }
+
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+
+    /// Page-table frames in use for user mappings.
+    pub fn table_frames(&self) -> usize {
+        self.table_frames
+    }
+
+    /// User pages mapped, counting pages shared copy-on-write in full.
+    pub fn resident_pages(&self) -> usize {
+        self.resident_pages
+    }
+
+    /// The most user pages that have been mapped at once.
+    pub fn peak_pages(&self) -> usize {
+        self.peak_pages
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        let table = unsafe { &mut *phys_to_ptr::<PageTable>(self.pml4.start_address()) };
+        unsafe { OffsetPageTable::new(table, get_offset()) }
+    }
+
+    /// Maps `num_pages` zeroed pages at `start`, accessible from user mode. Pages already mapped
+    /// keep their contents and get the access `flags` allow as well, since segments of a program
+    /// may share a page.
+    pub fn map_user(
+        &mut self,
+        start: VirtAddr,
+        num_pages: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        let first = Page::containing_address(start);
+        for page in Page::range(first, first + num_pages as u64) {
+            if let TranslateResult::Mapped { flags: mapped, .. } =
+                mapper.translate(page.start_address())
+            {
+                let mut merged = mapped | (flags & PageTableFlags::WRITABLE);
+                if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                if merged != mapped
+                    && let Ok(flush) = unsafe { mapper.update_flags(page, merged) }
+                {
+                    flush.flush();
+                }
+                continue;
+            }
+            if fault::should_fail(Site::MapTo) {
+                return Err(MapToError::FrameAllocationFailed);
+            }
+            let frame = match zeroed::take() {
+                Some(frame) => frame,
+                None => {
+                    let frame = page_alloc
+                        .frame_allocator
+                        .allocate_frame()
+                        .ok_or(MapToError::FrameAllocationFailed)?;
+                    unsafe { mem::zero_pages(phys_to_ptr::<u8>(frame.start_address()), 1) };
+                    frame
+                }
+            };
+            let mut tables = CountingFrameAllocator::new(&mut page_alloc.frame_allocator);
+            let mapped = unsafe { mapper.map_to(page, frame, flags, &mut tables) };
+            self.table_frames += tables.count;
+            match mapped {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    unsafe { page_alloc.frame_allocator.deallocate_frame(frame) };
+                    return Err(e);
+                }
+            }
+            self.resident_pages += 1;
+            self.peak_pages = self.peak_pages.max(self.resident_pages);
+        }
+        Ok(())
+    }
+
+    /// Maps the `num_pages` pages of device memory at `phys` where there is room above
+    /// [`MMAP_BASE`], accessible from user mode, and returns the address.
+    pub fn map_device(
+        &mut self,
+        phys: PhysAddr,
+        num_pages: usize,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
+        let len = num_pages as u64 * PAGE_SIZE;
+        let mut start = MMAP_BASE;
+        for (&used, &end) in &self.regions {
+            if used - start >= len {
+                break;
+            }
+            start = end;
+        }
+        if MMAP_END - start < len {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | DEVICE;
+        let mut mapper = self.mapper();
+        let first = Page::containing_address(VirtAddr::new(start));
+        let mut mapped = 0;
+        let mut result = Ok(());
+        {
+            let mut guard = PAGE_ALLOCATOR.lock();
+            let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+            for page in Page::range(first, first + num_pages as u64) {
+                if fault::should_fail(Site::MapTo) {
+                    result = Err(MapToError::FrameAllocationFailed);
+                    break;
+                }
+                let frame = PhysFrame::containing_address(phys + mapped as u64 * PAGE_SIZE);
+                let mut tables = CountingFrameAllocator::new(&mut page_alloc.frame_allocator);
+                let flush = unsafe { mapper.map_to(page, frame, flags, &mut tables) };
+                self.table_frames += tables.count;
+                match flush {
+                    Ok(flush) => flush.flush(),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                mapped += 1;
+                self.resident_pages += 1;
+                self.peak_pages = self.peak_pages.max(self.resident_pages);
+            }
+        }
+        if let Err(e) = result {
+            self.unmap_user(first.start_address(), mapped);
+            return Err(e);
+        }
+        self.regions.insert(start, start + len);
+        Ok(first.start_address())
+    }
+
+    /// Maps the kernel's frame at `phys` at `addr`, accessible from user mode, e.g. for the
+    /// vDSO. The kernel keeps ownership of the frame.
+    pub fn map_kernel_frame(
+        &mut self,
+        addr: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        if fault::should_fail(Site::MapTo) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | DEVICE;
+        let mut mapper = self.mapper();
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        let mut tables = CountingFrameAllocator::new(&mut page_alloc.frame_allocator);
+        let mapped = unsafe {
+            mapper.map_to(
+                Page::containing_address(addr),
+                PhysFrame::containing_address(phys),
+                flags,
+                &mut tables,
+            )
+        };
+        self.table_frames += tables.count;
+        mapped?.flush();
+        self.resident_pages += 1;
+        self.peak_pages = self.peak_pages.max(self.resident_pages);
+        Ok(())
+    }
+
+    /// Unmaps the region [`map_device`](Self::map_device) put at `start`, if it is `len`
+    /// bytes long. Returns `false` if there is no such region.
+    pub fn unmap_region(&mut self, start: VirtAddr, len: u64) -> bool {
+        let start = start.as_u64();
+        if self.regions.get(&start) != start.checked_add(len.next_multiple_of(PAGE_SIZE)).as_ref() {
+            return false;
+        }
+        self.regions.remove(&start);
+        self.unmap_user(VirtAddr::new(start), len.div_ceil(PAGE_SIZE) as usize);
+        true
+    }
+
+    /// Unmaps `num_pages` pages at `start`, freeing frames no other address space shares, and
+    /// then the page tables left empty. Pages that aren't mapped are skipped.
+    pub fn unmap_user(&mut self, start: VirtAddr, num_pages: usize) {
+        let mut mapper = self.mapper();
+        {
+            let mut guard = PAGE_ALLOCATOR.lock();
+            let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+            let first = Page::containing_address(start);
+            for page in Page::range(first, first + num_pages as u64) {
+                let device = matches!(
+                    mapper.translate(page.start_address()),
+                    TranslateResult::Mapped { flags, .. } if flags.contains(DEVICE)
+                );
+                let Ok((frame, flush)) = mapper.unmap(page) else {
+                    continue;
+                };
+                flush.ignore();
+                self.resident_pages -= 1;
+                if !device && release_frame(frame) {
+                    unsafe { page_alloc.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        }
+        // Pruning flushes the TLB itself if it frees anything
+        let end = start.align_down(PAGE_SIZE) + num_pages as u64 * PAGE_SIZE;
+        if self.prune_page_tables(start..end) == 0 && Cr3::read().0 == self.pml4 {
+            x86_64::instructions::tlb::flush_all();
+        }
+    }
+
+    /// Frees the page tables in the user part of `range` that no longer map anything, and
+    /// returns how many there were.
+    pub fn prune_page_tables(&mut self, range: Range<VirtAddr>) -> usize {
+        let user = VirtAddr::new(USER_START)..VirtAddr::new(1 << 47);
+        let range = range.start.max(user.start)..range.end.min(user.end);
+        let table = unsafe { &mut *phys_to_ptr::<PageTable>(self.pml4.start_address()) };
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        let freed = unsafe {
+            prune_page_tables(
+                table,
+                range,
+                false,
+                get_offset_u64(),
+                &mut page_alloc.frame_allocator,
+                usize::MAX,
+            )
+        };
+        if freed > 0 && Cr3::read().0 == self.pml4 {
+            x86_64::instructions::tlb::flush_all();
+        }
+        self.table_frames -= freed;
+        freed
+    }
+
+    /// Copies `bytes` into this address space at `addr`, which must already be mapped.
+    /// Works whether or not the address space is active.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), TranslateError> {
+        let mapper = self.mapper();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let virt = addr + offset as u64;
+            let phys = mapper
+                .translate_addr(virt)
+                .ok_or(TranslateError::PageNotMapped)?;
+            let in_page = (PAGE_SIZE - virt.as_u64() % PAGE_SIZE) as usize;
+            let len = in_page.min(bytes.len() - offset);
+            unsafe { mem::copy_raw(phys_to_ptr::<u8>(phys), bytes[offset..].as_ptr(), len) };
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Creates a copy of this address space that shares all user pages copy-on-write.
+    pub fn fork(&mut self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let mut child = AddressSpace::new()?;
+        let mut child_mapper = child.mapper();
+        let parent = unsafe { &mut *phys_to_ptr::<PageTable>(self.pml4.start_address()) };
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut result = Ok(());
+        for_each_user_leaf(parent, &mut |page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let Ok(frame) = entry.frame() else {
+                return;
+            };
+            let mut flags = entry.flags();
+            if !flags.contains(DEVICE) {
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COW);
+                    entry.set_flags(flags);
+                }
+                share_frame(frame);
+            }
+            let mut tables = CountingFrameAllocator::new(&mut page_alloc.frame_allocator);
+            result = unsafe {
+                child_mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, &mut tables)
+                    .map(|flush| flush.ignore())
+            };
+            child.table_frames += tables.count;
+            if result.is_ok() {
+                child.resident_pages += 1;
+            }
+        });
+        child.peak_pages = child.resident_pages;
+        child.regions = self.regions.clone();
+        // Writable entries in the parent just became read-only
+        if Cr3::read().0 == self.pml4 {
+            x86_64::instructions::tlb::flush_all();
+        }
+        result.map(|_| child)
+    }
+
+    /// Loads this address space into CR3.
+    pub fn activate(&self) {
+        if Cr3::read().0 != self.pml4 {
+            unsafe { Cr3::write(self.pml4, Cr3Flags::empty()) };
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if Cr3::read().0 == self.pml4 {
+            activate_kernel();
+        }
+        let table = unsafe { &mut *phys_to_ptr::<PageTable>(self.pml4.start_address()) };
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        for i in USER_P4_RANGE {
+            free_entry(&mut table[i], 3, &mut page_alloc.frame_allocator);
+        }
+        unsafe { page_alloc.frame_allocator.deallocate_frame(self.pml4) };
+    }
+}
+
+/// Calls `f` with every mapped 4KiB page in the user range of `pml4`.
+fn for_each_user_leaf(pml4: &mut PageTable, f: &mut impl FnMut(Page, &mut PageTableEntry)) {
+    for i4 in USER_P4_RANGE {
+        let Some(p3) = next_table(&mut pml4[i4]) else {
+            continue;
+        };
+        for i3 in 0..512 {
+            let Some(p2) = next_table(&mut p3[i3]) else {
+                continue;
+            };
+            for i2 in 0..512 {
+                let Some(p1) = next_table(&mut p2[i2]) else {
+                    continue;
+                };
+                for i1 in 0..512 {
+                    if p1[i1].is_unused() {
+                        continue;
+                    }
+                    let page = Page::from_page_table_indices(
+                        PageTableIndex::new(i4 as u16),
+                        PageTableIndex::new(i3 as u16),
+                        PageTableIndex::new(i2 as u16),
+                        PageTableIndex::new(i1 as u16),
+                    );
+                    f(page, &mut p1[i1]);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the table a non-leaf entry points to, if any.
+fn next_table(entry: &mut PageTableEntry) -> Option<&'static mut PageTable> {
+    let frame = entry.frame().ok()?;
+    Some(unsafe { &mut *phys_to_ptr::<PageTable>(frame.start_address()) })
+}
+
+/// Returns the level 1 entry for `addr` in the active address space.
+fn active_leaf_entry(addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+    let pml4 = unsafe { &mut *phys_to_ptr::<PageTable>(Cr3::read().0.start_address()) };
+    let p3 = next_table(&mut pml4[addr.p4_index()])?;
+    let p2 = next_table(&mut p3[addr.p3_index()])?;
+    let p1 = next_table(&mut p2[addr.p2_index()])?;
+    let entry = &mut p1[addr.p1_index()];
+    if entry.is_unused() { None } else { Some(entry) }
+}
+
+/// Resolves a write to a copy-on-write page in the active address space. Returns `false` if
+/// `addr` isn't on such a page, or memory for the copy ran out.
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let Some(entry) = active_leaf_entry(addr) else {
+        return false;
+    };
+    let mut flags = entry.flags();
+    if !flags.contains(COW) {
+        return false;
+    }
+    let Ok(old) = entry.frame() else {
+        return false;
+    };
+    flags.remove(COW);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    if SHARED_FRAMES.lock().contains_key(&old) {
+        let mut guard = PAGE_ALLOCATOR.lock();
+        let page_alloc = guard.as_mut().expect("PAGE_ALLOCATOR not initialized");
+        // Not from the zeroed pool: the copy overwrites all of it
+        let Some(new) = page_alloc.frame_allocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            mem::copy_raw(
+                phys_to_ptr::<u8>(new.start_address()),
+                phys_to_ptr::<u8>(old.start_address()),
+                PAGE_SIZE as usize,
+            );
+        }
+        release_frame(old);
+        entry.set_frame(new, flags);
+    } else {
+        // Every other sharer has already taken its own copy
+        entry.set_flags(flags);
+    }
+    x86_64::instructions::tlb::flush(addr.align_down(PAGE_SIZE));
+    super::scheduler::with_current(|process| process.page_faults += 1);
+    true
+}
+
+/// Frees the frame `entry` points to, along with everything below it. `level` is the level of
+/// the table the entry points to, 0 meaning a data page.
+fn free_entry(
+    entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let Ok(frame) = entry.frame() else {
+        return;
+    };
+    if level > 0 {
+        let table = unsafe { &mut *phys_to_ptr::<PageTable>(frame.start_address()) };
+        for child in table.iter_mut() {
+            free_entry(child, level - 1, frame_allocator);
+        }
+    }
+    if level > 0 || !entry.flags().contains(DEVICE) && release_frame(frame) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    entry.set_unused();
+}
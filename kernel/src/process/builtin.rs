@@ -0,0 +1,212 @@
+//! Small position-independent programs assembled into the kernel image, for exercising the
+//! user-mode path without a file system to load real binaries from.
+use core::arch::global_asm;
+
+use crate::syscall::nr;
+
+global_asm!(
+    ".section .rodata.user_programs, \"a\"",
+    // Prints a greeting and exits with its own PID as the exit code
+    ".global user_hello_start",
+    ".global user_hello_end",
+    "user_hello_start:",
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    lea rsi, [rip + 2f]",
+    "    mov edx, 3f - 2f",
+    "    syscall",
+    "    mov eax, {getpid}",
+    "    syscall",
+    "    mov edi, eax",
+    "    mov eax, {exit}",
+    "    syscall",
+    "    ud2",
+    "2: .ascii \"Hello from ring 3!\\n\"",
+    "3:",
+    "user_hello_end:",
+    // Forks; the child execs `hello` while the parent reports and exits with 0
+    ".global user_forktest_start",
+    ".global user_forktest_end",
+    "user_forktest_start:",
+    "    mov eax, {fork}",
+    "    syscall",
+    "    test rax, rax",
+    "    jz 4f",
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    lea rsi, [rip + 5f]",
+    "    mov edx, 6f - 5f",
+    "    syscall",
+    "    mov eax, {exit}",
+    "    xor edi, edi",
+    "    syscall",
+    "4:  mov eax, {exec}",
+    "    lea rdi, [rip + 7f]",
+    "    mov esi, 8f - 7f",
+    "    syscall",
+    // Only reached if exec failed
+    "    mov eax, {exit}",
+    "    mov edi, 1",
+    "    syscall",
+    "    ud2",
+    "5: .ascii \"forked a child\\n\"",
+    "6:",
+    "7: .ascii \"/bin/hello\"",
+    "8:",
+    "user_forktest_end:",
+    // Runs `hello` with its output redirected into a pipe, copies whatever comes out of the
+    // pipe to the console, then waits for it and exits with its exit code
+    ".global user_pipetest_start",
+    ".global user_pipetest_end",
+    "user_pipetest_start:",
+    // [rsp] holds the pipe descriptors, [rsp + 8] the child's status, [rsp + 16] a buffer
+    "    sub rsp, 80",
+    "    mov eax, {pipe}",
+    "    mov rdi, rsp",
+    "    syscall",
+    "    test rax, rax",
+    "    jnz 9f",
+    "    mov eax, {fork}",
+    "    syscall",
+    "    test rax, rax",
+    "    js 9f",
+    "    jz 4f",
+    "    mov rbx, rax",
+    "    mov eax, {close}",
+    "    mov edi, [rsp + 4]",
+    "    syscall",
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    lea rsi, [rip + 5f]",
+    "    mov edx, 6f - 5f",
+    "    syscall",
+    "2:  mov eax, {read}",
+    "    mov edi, [rsp]",
+    "    lea rsi, [rsp + 16]",
+    "    mov edx, 64",
+    "    syscall",
+    "    test rax, rax",
+    "    jle 3f",
+    "    mov rdx, rax",
+    "    mov eax, {write}",
+    "    mov edi, 1",
+    "    lea rsi, [rsp + 16]",
+    "    syscall",
+    "    jmp 2b",
+    "3:  mov eax, {waitpid}",
+    "    mov rdi, rbx",
+    "    lea rsi, [rsp + 8]",
+    "    syscall",
+    "    mov eax, {exit}",
+    "    mov edi, [rsp + 8]",
+    "    syscall",
+    "    ud2",
+    // Child: make the pipe its standard output and become `hello`
+    "4:  mov eax, {close}",
+    "    mov edi, [rsp]",
+    "    syscall",
+    "    mov eax, {dup2}",
+    "    mov edi, [rsp + 4]",
+    "    mov esi, 1",
+    "    syscall",
+    "    mov eax, {close}",
+    "    mov edi, [rsp + 4]",
+    "    syscall",
+    "    mov eax, {exec}",
+    "    lea rdi, [rip + 7f]",
+    "    mov esi, 8f - 7f",
+    "    syscall",
+    "9:  mov eax, {exit}",
+    "    mov edi, 1",
+    "    syscall",
+    "    ud2",
+    "5: .ascii \"captured: \"",
+    "6:",
+    "7: .ascii \"/bin/hello\"",
+    "8:",
+    "user_pipetest_end:",
+    // Writes through a null pointer, which should end it with SIGSEGV
+    ".global user_segv_start",
+    ".global user_segv_end",
+    "user_segv_start:",
+    "    mov byte ptr [0], 1",
+    "    ud2",
+    "user_segv_end:",
+    // Sets an alarm and spins until SIGALRM ends it
+    ".global user_alarm_start",
+    ".global user_alarm_end",
+    "user_alarm_start:",
+    "    mov eax, {alarm}",
+    "    mov edi, 100",
+    "    syscall",
+    "2:  jmp 2b",
+    "user_alarm_end:",
+    ".previous",
+    write = const nr::WRITE,
+    getpid = const nr::GETPID,
+    exit = const nr::EXIT,
+    fork = const nr::FORK,
+    exec = const nr::EXEC,
+    read = const nr::READ,
+    close = const nr::CLOSE,
+    pipe = const nr::PIPE,
+    waitpid = const nr::WAITPID,
+    dup2 = const nr::DUP2,
+    alarm = const nr::ALARM,
+);
+
+unsafe extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_forktest_start: u8;
+    static user_forktest_end: u8;
+    static user_pipetest_start: u8;
+    static user_pipetest_end: u8;
+    static user_segv_start: u8;
+    static user_segv_end: u8;
+    static user_alarm_start: u8;
+    static user_alarm_end: u8;
+}
+
+unsafe fn between(start: *const u8, end: *const u8) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start, end.offset_from(start) as usize) }
+}
+
+pub fn hello() -> &'static [u8] {
+    unsafe { between(&raw const user_hello_start, &raw const user_hello_end) }
+}
+
+pub fn forktest() -> &'static [u8] {
+    unsafe { between(&raw const user_forktest_start, &raw const user_forktest_end) }
+}
+
+pub fn pipetest() -> &'static [u8] {
+    unsafe { between(&raw const user_pipetest_start, &raw const user_pipetest_end) }
+}
+
+pub fn segv() -> &'static [u8] {
+    unsafe { between(&raw const user_segv_start, &raw const user_segv_end) }
+}
+
+pub fn alarm() -> &'static [u8] {
+    unsafe { between(&raw const user_alarm_start, &raw const user_alarm_end) }
+}
+
+/// A program's name and the function returning its image.
+pub type Program = (&'static str, fn() -> &'static [u8]);
+
+/// Programs that can be started by name, e.g. through `exec`.
+pub const PROGRAMS: &[Program] = &[
+    ("hello", hello),
+    ("forktest", forktest),
+    ("pipetest", pipetest),
+    ("segv", segv),
+    ("alarm", alarm),
+];
+
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .map(|(_, image)| image())
+}
@@ -0,0 +1,242 @@
+//! Loader for static ELF64 executables.
+//!
+//! Only what a statically linked, non-PIE x86_64 program needs is supported: the `PT_LOAD`
+//! segments are mapped at their linked addresses, with the file contents copied in and the rest
+//! of each segment left zeroed. [`parse_executable`] also serves [`crate::kexec`], which loads
+//! kernels linked the same way.
+use alloc::vec::Vec;
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError};
+
+use super::address_space::{AddressSpace, USER_START};
+use crate::memory::PAGE_SIZE;
+use crate::syscall::uaccess::USER_END;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+const PT_LOAD: u32 = 1;
+const PT_TLS: u32 = 7;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+#[derive(Debug)]
+pub enum ElfError {
+    NotElf,
+    /// A valid ELF file, but not a static x86_64 executable.
+    Unsupported,
+    /// A header or segment points past the end of the file.
+    Truncated,
+    /// A segment would land, or the entry point is, outside the user half of the address space,
+    /// or the entry point isn't a canonical address.
+    BadAddress,
+    Map(MapToError<Size4KiB>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ElfInfo {
+    pub entry: u64,
+    /// The `PT_LOAD` segments.
+    pub segments: Vec<Segment>,
+    /// The template for thread-local storage, if the program has any.
+    pub tls: Option<Segment>,
+}
+
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(ELF_MAGIC)
+}
+
+/// The `len` bytes at `offset`, which comes from the file and may be anything.
+fn field(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    bytes.get(offset..end).ok_or(ElfError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let b = field(bytes, offset, 2)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let b = field(bytes, offset, 4)?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let b = field(bytes, offset, 8)?;
+    Ok(u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Validates the headers and collects the loadable segments of a user program, which must all
+/// be in the user half of the address space, along with its entry point.
+pub fn parse(image: &[u8]) -> Result<ElfInfo, ElfError> {
+    let info = parse_executable(image)?;
+    // Returning to a bad entry point would fault in the kernel, on the `iretq`
+    if !(USER_START..USER_END).contains(&info.entry) {
+        return Err(ElfError::BadAddress);
+    }
+    for segment in &info.segments {
+        if segment.vaddr < USER_START
+            || segment
+                .vaddr
+                .checked_add(segment.mem_size)
+                .is_none_or(|end| end > USER_END)
+        {
+            return Err(ElfError::BadAddress);
+        }
+    }
+    Ok(info)
+}
+
+/// Validates the headers and collects the segments, wherever they are linked to run.
+pub fn parse_executable(image: &[u8]) -> Result<ElfInfo, ElfError> {
+    if image.len() < EHDR_SIZE || !is_elf(image) {
+        return Err(ElfError::NotElf);
+    }
+    if image[4] != ELFCLASS64
+        || image[5] != ELFDATA2LSB
+        || read_u16(image, 0x10)? != ET_EXEC
+        || read_u16(image, 0x12)? != EM_X86_64
+    {
+        return Err(ElfError::Unsupported);
+    }
+
+    let entry = read_u64(image, 0x18)?;
+    if VirtAddr::try_new(entry).is_err() {
+        return Err(ElfError::BadAddress);
+    }
+    let phoff = read_u64(image, 0x20)? as usize;
+    let phentsize = read_u16(image, 0x36)? as usize;
+    let phnum = read_u16(image, 0x38)? as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+
+    let mut segments = Vec::new();
+    let mut tls = None;
+    for i in 0..phnum {
+        let ph = i
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .ok_or(ElfError::Truncated)?;
+        let ph = field(image, ph, PHDR_SIZE)?;
+        let kind = read_u32(ph, 0)?;
+        if kind != PT_LOAD && kind != PT_TLS {
+            continue;
+        }
+        let flags = read_u32(ph, 4)?;
+        let segment = Segment {
+            offset: read_u64(ph, 8)?,
+            vaddr: read_u64(ph, 16)?,
+            file_size: read_u64(ph, 32)?,
+            mem_size: read_u64(ph, 40)?,
+            writable: flags & PF_W != 0,
+            executable: flags & PF_X != 0,
+        };
+        let file_end = segment.offset.checked_add(segment.file_size);
+        if file_end.is_none_or(|end| end > image.len() as u64)
+            || segment.file_size > segment.mem_size
+        {
+            return Err(ElfError::Truncated);
+        }
+        if kind == PT_TLS {
+            tls = Some(segment);
+        } else {
+            segments.push(segment);
+        }
+    }
+    Ok(ElfInfo {
+        entry,
+        segments,
+        tls,
+    })
+}
+
+/// Maps and fills the segments of `image` in `space`, returning the entry point.
+pub fn load(space: &mut AddressSpace, image: &[u8]) -> Result<VirtAddr, ElfError> {
+    let info = parse(image)?;
+    for segment in &info.segments {
+        let start = segment.vaddr & !(PAGE_SIZE - 1);
+        let end = (segment.vaddr + segment.mem_size).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mut flags = PageTableFlags::empty();
+        if segment.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        space
+            .map_user(
+                VirtAddr::new(start),
+                ((end - start) / PAGE_SIZE) as usize,
+                flags,
+            )
+            .map_err(ElfError::Map)?;
+
+        let data = &image[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+        space
+            .write(VirtAddr::new(segment.vaddr), data)
+            .map_err(|_| ElfError::BadAddress)?;
+    }
+    Ok(VirtAddr::new(info.entry))
+}
+
+#[test_case]
+fn test_parse_rejects_bad_headers() {
+    assert!(matches!(parse(b"not an elf"), Err(ElfError::NotElf)));
+
+    let mut header = [0u8; EHDR_SIZE];
+    header[..4].copy_from_slice(ELF_MAGIC);
+    header[4] = ELFCLASS64;
+    header[5] = ELFDATA2LSB;
+    header[0x10] = ET_EXEC as u8;
+    header[0x12] = EM_X86_64 as u8;
+    header[0x18..0x20].copy_from_slice(&0x4000_0000_1000u64.to_le_bytes());
+    header[0x36] = PHDR_SIZE as u8;
+    let info = parse(&header).expect("header without segments is valid");
+    assert_eq!(info.entry, 0x4000_0000_1000);
+    assert!(info.segments.is_empty());
+
+    // Entry points that aren't canonical, or are in the kernel half
+    let mut bad_entry = header;
+    bad_entry[0x18..0x20].copy_from_slice(&0x8000_0000_0000_1000u64.to_le_bytes());
+    assert!(matches!(parse(&bad_entry), Err(ElfError::BadAddress)));
+    bad_entry[0x18..0x20].copy_from_slice(&0xFFFF_8000_0000_1000u64.to_le_bytes());
+    assert!(matches!(parse(&bad_entry), Err(ElfError::BadAddress)));
+
+    // One PT_LOAD header pointing past the end of the file
+    header[0x20] = EHDR_SIZE as u8;
+    header[0x38] = 1;
+    let mut image = [0u8; EHDR_SIZE + PHDR_SIZE];
+    image[..EHDR_SIZE].copy_from_slice(&header);
+    image[EHDR_SIZE] = PT_LOAD as u8;
+    image[EHDR_SIZE + 32] = 0xFF;
+    image[EHDR_SIZE + 40] = 0xFF;
+    assert!(matches!(parse(&image), Err(ElfError::Truncated)));
+
+    // Program headers that would wrap around the address space
+    let mut wrapping = image;
+    wrapping[0x20..0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(parse(&wrapping), Err(ElfError::Truncated)));
+    wrapping[0x20..0x28].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+    assert!(matches!(parse(&wrapping), Err(ElfError::Truncated)));
+
+    header[0x12] = 0x28; // ARM
+    assert!(matches!(parse(&header), Err(ElfError::Unsupported)));
+}
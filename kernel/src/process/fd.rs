@@ -0,0 +1,213 @@
+//! Per-process file descriptor tables.
+use alloc::{sync::Arc, vec::Vec};
+
+use spin::Mutex;
+
+use crate::fs::{self, DeviceMemory, FsError, Node};
+
+/// Highest number of descriptors a process may have open at once.
+pub const MAX_FDS: usize = 64;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// Furthest a seek may move the offset. Larger offsets wouldn't fit the signed value `lseek`
+/// returns them in.
+pub const MAX_OFFSET: u64 = i64::MAX as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdError {
+    /// Not an open descriptor, or not open for the requested kind of access.
+    BadFd,
+    TooManyOpen,
+    InvalidArgument,
+    Fs(FsError),
+}
+
+impl From<FsError> for FdError {
+    fn from(e: FsError) -> Self {
+        FdError::Fs(e)
+    }
+}
+
+/// An open file: a node plus the offset and access mode. Shared between descriptors that were
+/// duplicated or inherited across `fork`, so they move the same offset.
+pub struct OpenFile {
+    node: Arc<dyn Node>,
+    offset: Mutex<u64>,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl OpenFile {
+    pub fn new(node: Arc<dyn Node>, flags: u64) -> Result<Self, FdError> {
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => return Err(FdError::InvalidArgument),
+        };
+        Ok(OpenFile {
+            node,
+            offset: Mutex::new(0),
+            readable,
+            writable,
+            append: flags & O_APPEND != 0,
+        })
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FdError> {
+        if !self.readable {
+            return Err(FdError::BadFd);
+        }
+        let mut offset = self.offset.lock();
+        let len = self.node.read_at(*offset, buf)?;
+        if self.node.size().is_some() {
+            *offset += len as u64;
+        }
+        Ok(len)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FdError> {
+        if !self.writable {
+            return Err(FdError::BadFd);
+        }
+        let mut offset = self.offset.lock();
+        if let (true, Some(size)) = (self.append, self.node.size()) {
+            *offset = size;
+        }
+        let len = self.node.write_at(*offset, buf)?;
+        if self.node.size().is_some() {
+            *offset += len as u64;
+        }
+        Ok(len)
+    }
+
+    /// Moves the offset and returns the new one. Streams can't seek.
+    pub fn seek(&self, delta: i64, whence: u64) -> Result<u64, FdError> {
+        let size = self.node.size().ok_or(FdError::Fs(FsError::NotSupported))?;
+        let mut offset = self.offset.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *offset,
+            SEEK_END => size,
+            _ => return Err(FdError::InvalidArgument),
+        };
+        *offset = base
+            .checked_add_signed(delta)
+            .filter(|&new| new <= MAX_OFFSET)
+            .ok_or(FdError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    /// Passes `request` on to the node; see [`Node::ioctl`].
+    pub fn ioctl(&self, request: u64, arg: u64, answer: &mut [u8]) -> Result<usize, FdError> {
+        Ok(self.node.ioctl(request, arg, answer)?)
+    }
+
+    /// The device memory behind the file, checking that it is open for reading, and for
+    /// writing too if the memory will be mapped writable.
+    pub fn device_memory(&self, write: bool) -> Result<DeviceMemory, FdError> {
+        if !self.readable || write && !self.writable {
+            return Err(FdError::BadFd);
+        }
+        self.node
+            .device_memory()
+            .ok_or(FdError::Fs(FsError::NotSupported))
+    }
+}
+
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<Arc<OpenFile>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// A table with standard input and output on the console and standard error on the serial
+    /// port, or an empty one if the device file system isn't mounted.
+    pub fn with_stdio() -> Self {
+        let mut table = FdTable::new();
+        let stdio = [
+            ("/dev/console", O_RDONLY),
+            ("/dev/console", O_WRONLY),
+            ("/dev/serial", O_WRONLY),
+        ];
+        for (path, flags) in stdio {
+            let Ok(node) = fs::open(path, false) else {
+                return FdTable::new();
+            };
+            let file = OpenFile::new(node, flags).expect("valid access mode");
+            let _ = table.insert(Arc::new(file));
+        }
+        table
+    }
+
+    /// Stores `file` in the lowest free descriptor and returns it.
+    pub fn insert(&mut self, file: Arc<OpenFile>) -> Result<usize, FdError> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FDS {
+            return Err(FdError::TooManyOpen);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<OpenFile>, FdError> {
+        self.files.get(fd).cloned().flatten().ok_or(FdError::BadFd)
+    }
+
+    /// Makes descriptor `new` refer to the same open file as `old`, and returns the file `new`
+    /// referred to before, which the caller drops as for [`close`](Self::close).
+    pub fn dup2(&mut self, old: usize, new: usize) -> Result<Option<Arc<OpenFile>>, FdError> {
+        let file = self.get(old)?;
+        if new >= MAX_FDS {
+            return Err(FdError::BadFd);
+        }
+        if new >= self.files.len() {
+            self.files.resize(new + 1, None);
+        }
+        Ok(self.files[new].replace(file))
+    }
+
+    /// Removes descriptor `fd` and returns the file it referred to. Dropping the last reference
+    /// to a pipe end wakes processes waiting on the pipe, so don't drop it under the scheduler
+    /// lock.
+    pub fn close(&mut self, fd: usize) -> Result<Arc<OpenFile>, FdError> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(FdError::BadFd)
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Opens `path` with POSIX-style `flags`.
+pub fn open(path: &str, flags: u64) -> Result<OpenFile, FdError> {
+    let node = fs::open(path, flags & O_CREAT != 0)?;
+    if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+        node.truncate()?;
+    }
+    OpenFile::new(node, flags)
+}
@@ -0,0 +1,153 @@
+//! Futexes: the kernel half of user-space locks, which only enter the kernel to wait while
+//! contended and to wake waiters.
+//!
+//! A futex is keyed by the physical address of its 32-bit word, so processes sharing a page
+//! share its futexes, while private memory, which is copied before the key is taken if it is
+//! copy-on-write, never collides. Waiters are kept in buckets hashed by key, and block with the
+//! key as their wait channel.
+use alloc::vec::Vec;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::address_space::translate_phys_active;
+use super::{Pid, scheduler};
+use crate::trap::TrapFrame;
+
+const BUCKET_COUNT: usize = 64;
+
+struct Waiter {
+    key: PhysAddr,
+    pid: Pid,
+    /// Set by [`wake`]; the waiter removes itself once it sees it.
+    woken: bool,
+}
+
+static BUCKETS: [Mutex<Vec<Waiter>>; BUCKET_COUNT] =
+    [const { Mutex::new(Vec::new()) }; BUCKET_COUNT];
+
+/// What [`wait`] did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The caller was blocked and has been switched away from.
+    Blocked,
+    /// A [`wake`] picked the caller.
+    Woken,
+    /// The word no longer held the expected value.
+    ValueChanged,
+}
+
+/// The key of the futex word at `addr` in the current address space, or `None` if it isn't
+/// mapped.
+pub fn key(addr: VirtAddr) -> Option<PhysAddr> {
+    translate_phys_active(addr)
+}
+
+fn bucket_index(key: PhysAddr) -> usize {
+    // Fibonacci hashing on the word number spreads neighbouring words over the buckets
+    ((key.as_u64() >> 2).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - BUCKET_COUNT.ilog2()))
+        as usize
+}
+
+fn bucket(key: PhysAddr) -> &'static Mutex<Vec<Waiter>> {
+    &BUCKETS[bucket_index(key)]
+}
+
+fn channel(key: PhysAddr) -> usize {
+    key.as_u64() as usize
+}
+
+/// Removes the current process's entry for `key` and returns whether it had been woken.
+fn take_entry(waiters: &mut Vec<Waiter>, key: PhysAddr, pid: Pid) -> Option<bool> {
+    let index = waiters
+        .iter()
+        .position(|waiter| waiter.key == key && waiter.pid == pid)?;
+    Some(waiters.remove(index).woken)
+}
+
+/// Blocks the current process on the futex `key` while `still_expected` says its word holds
+/// the value the caller expects. Like other blocking system calls, the caller runs again from
+/// the start once woken, and then gets [`WaitOutcome::Woken`].
+pub(crate) fn wait(
+    frame: &mut TrapFrame,
+    key: PhysAddr,
+    still_expected: impl FnOnce() -> bool,
+) -> WaitOutcome {
+    let Some(pid) = super::current_pid() else {
+        return WaitOutcome::ValueChanged;
+    };
+    let bucket = bucket(key);
+    // The word is checked under the bucket lock, so a waker that changes it and then wakes
+    // the futex either finds this waiter queued or changed the word before it was read
+    let queued = interrupts::without_interrupts(|| {
+        let mut waiters = bucket.lock();
+        if let Some(woken) = take_entry(&mut waiters, key, pid)
+            && woken
+        {
+            return Err(WaitOutcome::Woken);
+        }
+        if !still_expected() {
+            return Err(WaitOutcome::ValueChanged);
+        }
+        waiters.push(Waiter {
+            key,
+            pid,
+            woken: false,
+        });
+        Ok(())
+    });
+    if let Err(outcome) = queued {
+        return outcome;
+    }
+
+    let woken_already = || {
+        bucket
+            .lock()
+            .iter()
+            .any(|waiter| waiter.key == key && waiter.pid == pid && waiter.woken)
+    };
+    if scheduler::block_current_unless(frame, channel(key), woken_already) {
+        return WaitOutcome::Blocked;
+    }
+    interrupts::without_interrupts(|| take_entry(&mut bucket.lock(), key, pid));
+    WaitOutcome::Woken
+}
+
+/// Wakes up to `count` processes waiting on the futex `key`, oldest first, and returns how
+/// many it woke.
+pub fn wake(key: PhysAddr, count: usize) -> usize {
+    let woken: Vec<Pid> = interrupts::without_interrupts(|| {
+        bucket(key)
+            .lock()
+            .iter_mut()
+            .filter(|waiter| waiter.key == key && !waiter.woken)
+            .take(count)
+            .map(|waiter| {
+                waiter.woken = true;
+                waiter.pid
+            })
+            .collect()
+    });
+    for &pid in &woken {
+        scheduler::wake_process(pid, channel(key));
+    }
+    woken.len()
+}
+
+/// Drops whatever `pid` was waiting on, once it has exited.
+pub(crate) fn forget(pid: Pid) {
+    for bucket in &BUCKETS {
+        interrupts::without_interrupts(|| bucket.lock().retain(|waiter| waiter.pid != pid));
+    }
+}
+
+#[test_case]
+fn test_futex_buckets_spread() {
+    let mut used = [false; BUCKET_COUNT];
+    for word in 0..BUCKET_COUNT as u64 {
+        used[bucket_index(PhysAddr::new(0x20_0000 + word * 4))] = true;
+    }
+    // Neighbouring words of one page mustn't pile up in a few buckets
+    assert!(used.iter().filter(|&&used| used).count() > BUCKET_COUNT / 2);
+}
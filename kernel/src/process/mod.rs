@@ -0,0 +1,334 @@
+//! User processes.
+//!
+//! A process runs a program image in an address space of its own, with a small stack below
+//! [`USER_STACK_TOP`]. Images are either static ELF executables or flat binaries, which are
+//! loaded at [`USER_CODE_BASE`] and entered at their first byte. [`spawn`] creates a process and
+//! queues it with the [`scheduler`], which runs it in ring 3 until it exits.
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::cpu::fpu::{self, FpuState};
+use crate::interrupts::APIC_TIMER_HZ;
+use crate::memory::PAGE_SIZE;
+use crate::trap::TrapFrame;
+use crate::{cpu, gdt};
+use address_space::AddressSpace;
+use fd::FdTable;
+
+pub mod address_space;
+pub mod builtin;
+pub mod elf;
+pub mod fd;
+pub mod futex;
+pub mod scheduler;
+pub mod signal;
+
+pub use scheduler::current_pid;
+
+/// Where flat program images are loaded.
+pub const USER_CODE_BASE: u64 = address_space::USER_START;
+/// Top of the user stack, which grows down from here.
+pub const USER_STACK_TOP: u64 = 0x0000_7FFF_FFFF_F000;
+const USER_STACK_PAGES: usize = 4;
+
+/// RFLAGS for a new process: interrupts enabled, plus the always-set reserved bit 1.
+const USER_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    Map(MapToError<Size4KiB>),
+    Elf(elf::ElfError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ready,
+    Running,
+    Sleeping {
+        until_us: u64,
+    },
+    /// Waiting in a system call until [`scheduler::wake`] is called with `channel`.
+    Blocked {
+        channel: usize,
+    },
+    Exited(i32),
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Sleeping { .. } => "sleeping",
+            State::Blocked { .. } => "blocked",
+            State::Exited(_) => "exited",
+        }
+    }
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: State,
+    /// Index of the CPU whose run queue the process is on. Processes start on the CPU that
+    /// created them and stay there.
+    pub cpu: usize,
+    /// User registers, valid whenever the process is not running.
+    frame: TrapFrame,
+    /// Released as soon as the process exits; only the exit status stays behind until reaped.
+    space: Option<AddressSpace>,
+    pub files: FdTable,
+    slice_left: u32,
+    /// Timer ticks that arrived while the process was running in user mode.
+    pub user_ticks: u64,
+    /// Timer ticks that arrived while the kernel was working on the process's behalf.
+    pub system_ticks: u64,
+    /// Copy-on-write faults resolved by giving the process its own copy of a page.
+    pub page_faults: u64,
+    /// Signals sent while the process was running, one bit per signal number.
+    pending_signals: u64,
+    /// When to send `SIGALRM`, as set by the `alarm` system call.
+    alarm_us: Option<u64>,
+    /// x87, SSE and AVX registers, valid unless they are loaded on the process's CPU (see
+    /// [`fpu`]).
+    fpu: Box<FpuState>,
+}
+
+impl Process {
+    /// What the process has used so far.
+    pub fn usage(&self) -> Rusage {
+        let space = self.space.as_ref();
+        Rusage {
+            user_us: ticks_to_us(self.user_ticks),
+            system_us: ticks_to_us(self.system_ticks),
+            resident_pages: space.map_or(0, |space| space.resident_pages() as u64),
+            peak_pages: space.map_or(0, |space| space.peak_pages() as u64),
+            page_faults: self.page_faults,
+        }
+    }
+}
+
+/// Resources a process has used, as the `getrusage` system call reports them. CPU time is
+/// only as fine as the timer tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct Rusage {
+    pub user_us: u64,
+    pub system_us: u64,
+    /// User pages mapped now, counting pages shared copy-on-write in full.
+    pub resident_pages: u64,
+    /// The most user pages mapped at once since the last `exec`.
+    pub peak_pages: u64,
+    /// Copy-on-write faults resolved with a private copy of the page.
+    pub page_faults: u64,
+}
+
+impl Rusage {
+    /// The fields in order, little-endian.
+    pub fn to_bytes(self) -> [u8; 40] {
+        let fields = [
+            self.user_us,
+            self.system_us,
+            self.resident_pages,
+            self.peak_pages,
+            self.page_faults,
+        ];
+        let mut bytes = [0u8; 40];
+        for (chunk, field) in bytes.chunks_exact_mut(8).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+fn ticks_to_us(ticks: u64) -> u64 {
+    ticks * 1_000_000 / APIC_TIMER_HZ as u64
+}
+
+/// Builds a fresh address space holding `image` and a stack, and returns it with the
+/// registers to start it with.
+fn load(image: &[u8]) -> Result<(AddressSpace, TrapFrame), SpawnError> {
+    let mut space = AddressSpace::new().map_err(SpawnError::Map)?;
+    crate::vdso::map(&mut space).map_err(SpawnError::Map)?;
+    let entry = if elf::is_elf(image) {
+        elf::load(&mut space, image).map_err(SpawnError::Elf)?
+    } else {
+        let image_pages = (image.len() as u64).div_ceil(PAGE_SIZE).max(1) as usize;
+        space
+            .map_user(
+                VirtAddr::new(USER_CODE_BASE),
+                image_pages,
+                PageTableFlags::WRITABLE,
+            )
+            .map_err(SpawnError::Map)?;
+        space
+            .write(VirtAddr::new(USER_CODE_BASE), image)
+            .expect("image pages were just mapped");
+        VirtAddr::new(USER_CODE_BASE)
+    };
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_PAGES as u64 * PAGE_SIZE;
+    space
+        .map_user(
+            VirtAddr::new(stack_bottom),
+            USER_STACK_PAGES,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(SpawnError::Map)?;
+
+    let selectors = gdt::selectors();
+    let frame = TrapFrame {
+        rip: entry.as_u64(),
+        cs: selectors.user_code_selector.0 as u64,
+        rflags: USER_RFLAGS,
+        rsp: USER_STACK_TOP,
+        ss: selectors.user_data_selector.0 as u64,
+        ..TrapFrame::default()
+    };
+    Ok((space, frame))
+}
+
+/// Creates a process running `image` and queues it to be scheduled.
+pub fn spawn(name: &str, image: &[u8]) -> Result<Pid, SpawnError> {
+    spawn_with_files(name, image, FdTable::with_stdio())
+}
+
+/// Like [`spawn`], but the process starts with the descriptors in `files` rather than the
+/// console, e.g. to capture its output.
+pub fn spawn_with_files(name: &str, image: &[u8], files: FdTable) -> Result<Pid, SpawnError> {
+    let (space, frame) = load(image)?;
+    let pid = Pid::new();
+    let fpu = Box::new(FpuState::new());
+    scheduler::add(Process {
+        pid,
+        parent: None,
+        name: String::from(name),
+        state: State::Ready,
+        cpu: cpu::current_index(),
+        frame,
+        space: Some(space),
+        files,
+        slice_left: 0,
+        user_ticks: 0,
+        system_ticks: 0,
+        page_faults: 0,
+        pending_signals: 0,
+        alarm_us: None,
+        fpu,
+    })?;
+    Ok(pid)
+}
+
+/// Duplicates the current process. The child shares the parent's memory copy-on-write and
+/// resumes from the same system call with a return value of 0. Pending signals and alarms are
+/// not inherited.
+pub fn fork(frame: &TrapFrame) -> Result<Pid, SpawnError> {
+    let (space, files, name, parent, fpu) = scheduler::with_current(|process| {
+        let space = process
+            .space
+            .as_mut()
+            .expect("running process has an address space");
+        let mut fpu = process.fpu.clone();
+        fpu::save_if_owner(process.pid.0, &mut fpu);
+        Ok((
+            space.fork()?,
+            process.files.clone(),
+            process.name.clone(),
+            process.pid,
+            fpu,
+        ))
+    })
+    .expect("fork outside of a process")
+    .map_err(SpawnError::Map)?;
+
+    let mut child_frame = *frame;
+    child_frame.rax = 0;
+    let pid = Pid::new();
+    scheduler::add(Process {
+        pid,
+        parent: Some(parent),
+        name,
+        state: State::Ready,
+        cpu: cpu::current_index(),
+        frame: child_frame,
+        space: Some(space),
+        files,
+        slice_left: 0,
+        user_ticks: 0,
+        system_ticks: 0,
+        page_faults: 0,
+        pending_signals: 0,
+        alarm_us: None,
+        fpu,
+    })?;
+    Ok(pid)
+}
+
+/// Replaces the program of the current process with `image`, resetting `frame` to its entry
+/// point. On failure the current program is left untouched.
+pub fn exec(frame: &mut TrapFrame, name: &str, image: &[u8]) -> Result<(), SpawnError> {
+    let (space, new_frame) = load(image)?;
+    space.activate();
+    let old = scheduler::with_current(|process| {
+        process.name = String::from(name);
+        *process.fpu = FpuState::new();
+        fpu::forget(process.pid.0);
+        process.space.replace(space)
+    })
+    .expect("exec outside of a process");
+    drop(old);
+    *frame = new_frame;
+    scheduler::mark_switched();
+    Ok(())
+}
+
+/// Maps the `num_pages` pages of device memory at `phys` into the current process, and returns
+/// where.
+pub fn map_device(
+    phys: PhysAddr,
+    num_pages: usize,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    scheduler::with_current(|process| {
+        process
+            .space
+            .as_mut()
+            .expect("running process has an address space")
+            .map_device(phys, num_pages, flags)
+    })
+    .expect("mmap outside of a process")
+}
+
+/// Unmaps the `len` bytes at `addr` from the current process, which must be a whole region
+/// [`map_device`] returned. Returns `false` if they aren't.
+pub fn unmap_region(addr: VirtAddr, len: u64) -> bool {
+    scheduler::with_current(|process| {
+        process
+            .space
+            .as_mut()
+            .is_some_and(|space| space.unmap_region(addr, len))
+    })
+    .unwrap_or(false)
+}
+
+/// Runs `image` as a new process, along with anything else that is runnable, until it exits.
+pub fn run(name: &str, image: &[u8]) -> Result<scheduler::ExitInfo, SpawnError> {
+    let pid = spawn(name, image)?;
+    scheduler::run();
+    Ok(scheduler::reap(pid).expect("scheduler returned with a live process"))
+}
@@ -0,0 +1,728 @@
+//! Round-robin, preemptive scheduling of user processes.
+//!
+//! Processes have no kernel stacks of their own. Every entry from ring 3 saves the user
+//! registers in a [`TrapFrame`] at the top of the CPU's kernel stack, and switching processes
+//! means storing that frame in the outgoing process and overwriting it with the incoming one's
+//! before returning to user mode. A system call therefore can't block halfway through the
+//! kernel: handlers that wait, like `sleep`, record what they wait for and switch away instead.
+//! Handlers that wait for another process, e.g. to fill a pipe, block on a wait channel and
+//! restart the system call from scratch once it is woken.
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+use core::arch::naked_asm;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::Poll;
+
+use futures_util::task::AtomicWaker;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+
+use super::signal::{self, SignalError};
+use super::{Pid, Process, SpawnError, State, address_space, fd::FdTable};
+use crate::allocator::slab::{SlabBox, SlabCache, SlabStats};
+use crate::cpu::{self, MAX_CPUS, PerCpu, fpu};
+use crate::metrics::{Counter, Gauge};
+use crate::timer::uptime_us;
+use crate::trap::{TrapFrame, pop_gprs};
+use crate::warn;
+
+/// Timer ticks a process may run before it is preempted in favour of another ready one.
+const TIME_SLICE_TICKS: u32 = 3;
+
+/// Length of the `syscall` instruction, for backing up over it to restart a system call.
+const SYSCALL_INSN_LEN: u64 = 2;
+
+/// Process structs, kept together in a cache of their own rather than in the heap.
+static PROCESSES: SlabCache<Process> = SlabCache::new();
+
+struct Scheduler {
+    processes: BTreeMap<Pid, SlabBox<'static, Process>>,
+    /// Ready processes, queued on the CPU they belong to (see [`Process::cpu`]). A CPU only
+    /// dispatches from its own queue, and only its own timer ticks preempt what it runs.
+    ready: [VecDeque<Pid>; MAX_CPUS],
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    processes: BTreeMap::new(),
+    ready: [const { VecDeque::new() }; MAX_CPUS],
+});
+
+static SWITCHES: Counter = Counter::new("sched.switches");
+/// Processes in the process table, including exited ones not yet reaped.
+static PROCESS_COUNT: Gauge = Gauge::new("sched.processes");
+
+/// PID of the process running on each CPU, or 0 when there is none.
+static CURRENT: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// Kernel stack pointer saved by [`enter_user`], for returning once no processes are left.
+static SAVED_RSP: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// Set once [`serve`] runs, which resumes blocked processes when they are woken.
+static SERVING: AtomicBool = AtomicBool::new(false);
+/// Woken by [`wake`] making a process ready, for [`serve`].
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Set when a system call handler replaced the trap frame with another context.
+static SWITCHED: PerCpu<AtomicBool> = PerCpu::new([const { AtomicBool::new(false) }; MAX_CPUS]);
+
+/// Timer ticks each CPU spent running processes, and how often it switched between them.
+struct CpuCounters {
+    user_ticks: AtomicU64,
+    system_ticks: AtomicU64,
+    switches: AtomicU64,
+}
+
+static CPU_COUNTERS: PerCpu<CpuCounters> = PerCpu::new(
+    [const {
+        CpuCounters {
+            user_ticks: AtomicU64::new(0),
+            system_ticks: AtomicU64::new(0),
+            switches: AtomicU64::new(0),
+        }
+    }; MAX_CPUS],
+);
+
+/// What one CPU's scheduler has done since boot, as returned by [`cpu_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuStats {
+    /// Ticks that arrived while a process was running in user mode.
+    pub user_ticks: u64,
+    /// Ticks that arrived while the kernel was working on a process's behalf.
+    pub system_ticks: u64,
+    /// Processes dispatched.
+    pub switches: u64,
+}
+
+/// Scheduling statistics for CPU `index`.
+pub fn cpu_stats(index: usize) -> Option<CpuStats> {
+    CPU_COUNTERS.get_for(index).map(|counters| CpuStats {
+        user_ticks: counters.user_ticks.load(Ordering::Relaxed),
+        system_ticks: counters.system_ticks.load(Ordering::Relaxed),
+        switches: counters.switches.load(Ordering::Relaxed),
+    })
+}
+
+/// How a process ended, returned by [`reap`].
+#[derive(Debug, Clone, Copy)]
+pub struct ExitInfo {
+    pub code: i32,
+    pub user_ticks: u64,
+    pub system_ticks: u64,
+}
+
+/// Queues `process` on its CPU's run queue.
+fn enqueue(ready: &mut [VecDeque<Pid>; MAX_CPUS], process: &Process) {
+    ready[process.cpu].push_back(process.pid);
+}
+
+impl Scheduler {
+    /// Makes sleepers whose deadline has passed ready. Without a clock, sleeps end immediately.
+    fn wake_sleepers(&mut self, now_us: Option<u64>) {
+        for process in self.processes.values_mut() {
+            if let State::Sleeping { until_us } = process.state
+                && now_us.is_none_or(|now| now >= until_us)
+            {
+                process.state = State::Ready;
+                enqueue(&mut self.ready, process);
+            }
+        }
+    }
+
+    /// Returns the processes whose alarm has gone off, clearing it.
+    fn take_alarms(&mut self, now_us: Option<u64>) -> Vec<Pid> {
+        let Some(now) = now_us else {
+            return Vec::new();
+        };
+        let mut expired = Vec::new();
+        for process in self.processes.values_mut() {
+            if process.alarm_us.is_some_and(|at| now >= at) {
+                process.alarm_us = None;
+                expired.push(process.pid);
+            }
+        }
+        expired
+    }
+
+    /// Whether any process can still run without another process waking it first.
+    fn has_runnable(&self) -> bool {
+        self.processes.values().any(|p| match p.state {
+            State::Ready | State::Running | State::Sleeping { .. } => true,
+            State::Blocked { .. } => p.alarm_us.is_some(),
+            State::Exited(_) => false,
+        })
+    }
+
+    fn blocked(&self) -> usize {
+        self.processes
+            .values()
+            .filter(|p| matches!(p.state, State::Blocked { .. }))
+            .count()
+    }
+
+    /// Charges a tick to `pid`, which was interrupted in user mode with the registers in
+    /// `frame`, and preempts it if its time slice is used up and another process is ready on
+    /// this CPU.
+    fn charge_user_tick(&mut self, pid: Pid, frame: &mut TrapFrame) {
+        let has_ready = !self.ready[cpu::current_index()].is_empty();
+        let Some(process) = self.processes.get_mut(&pid) else {
+            return;
+        };
+        process.user_ticks += 1;
+        process.slice_left = process.slice_left.saturating_sub(1);
+        if process.slice_left > 0 {
+            return;
+        }
+        if !has_ready {
+            process.slice_left = TIME_SLICE_TICKS;
+            return;
+        }
+
+        process.frame = *frame;
+        process.state = State::Ready;
+        enqueue(&mut self.ready, process);
+        if let Some(next) = self.dispatch_next() {
+            *frame = next;
+        }
+    }
+
+    /// Makes the next process ready on this CPU current and returns its registers.
+    fn dispatch_next(&mut self) -> Option<TrapFrame> {
+        while let Some(pid) = self.ready[cpu::current_index()].pop_front() {
+            let Some(process) = self.processes.get_mut(&pid) else {
+                continue;
+            };
+            if process.state != State::Ready {
+                continue;
+            }
+            process.state = State::Running;
+            process.slice_left = TIME_SLICE_TICKS;
+            if let Some(space) = &process.space {
+                space.activate();
+            }
+            CURRENT.get().store(pid.0, Ordering::Relaxed);
+            fpu::switch_to(pid.0);
+            SWITCHES.inc();
+            CPU_COUNTERS.get().switches.fetch_add(1, Ordering::Relaxed);
+            crate::stack::check();
+            return Some(process.frame);
+        }
+        None
+    }
+}
+
+enum Next {
+    Run(TrapFrame),
+    Done,
+}
+
+/// Picks the next process to run, idling while every live process is asleep. Processes blocked
+/// on each other with nothing else left to run are left in place. Leaves interrupts disabled.
+fn next_or_idle() -> Next {
+    loop {
+        interrupts::disable();
+        let now = uptime_us();
+        let alarms = {
+            let mut scheduler = SCHEDULER.lock();
+            scheduler.wake_sleepers(now);
+            let alarms = scheduler.take_alarms(now);
+            if alarms.is_empty() {
+                if let Some(frame) = scheduler.dispatch_next() {
+                    return Next::Run(frame);
+                }
+                if !scheduler.has_runnable() {
+                    let blocked = scheduler.blocked();
+                    // Under `serve`, a task such as the terminal may still wake them
+                    if blocked > 0 && !SERVING.load(Ordering::Relaxed) {
+                        warn!(
+                            "scheduler: {} processes blocked with nothing left to wake them",
+                            blocked
+                        );
+                    }
+                    return Next::Done;
+                }
+            }
+            alarms
+        };
+        if alarms.is_empty() {
+            cpu::idle::enable_and_idle();
+        }
+        for pid in alarms {
+            let _ = send_signal(pid, signal::SIGALRM);
+        }
+    }
+}
+
+pub(super) fn add(process: Process) -> Result<(), SpawnError> {
+    let process = PROCESSES
+        .alloc(process)
+        .ok_or(SpawnError::Map(MapToError::FrameAllocationFailed))?;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        enqueue(&mut scheduler.ready, &process);
+        scheduler.processes.insert(process.pid, process);
+        PROCESS_COUNT.inc();
+    });
+    Ok(())
+}
+
+/// Usage of the cache process structs are allocated from.
+pub fn slab_stats() -> SlabStats {
+    PROCESSES.stats()
+}
+
+/// Returns the PID of the process running on this CPU.
+pub fn current_pid() -> Option<Pid> {
+    match CURRENT.get().load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+/// Runs user processes on this CPU until none are left that can make progress, then returns.
+pub fn run() {
+    if let Next::Run(frame) = next_or_idle() {
+        unsafe { enter_user(SAVED_RSP.get().as_ptr(), &frame) };
+    }
+    address_space::activate_kernel();
+}
+
+/// Runs user processes from the executor: whenever [`run`] returns with processes blocked,
+/// waits for one of them to be woken, e.g. by input arriving for a shell, and runs them again.
+pub async fn serve() {
+    SERVING.store(true, Ordering::Relaxed);
+    loop {
+        run();
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            let has_ready = interrupts::without_interrupts(|| {
+                !SCHEDULER.lock().ready[cpu::current_index()].is_empty()
+            });
+            if has_ready {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+    }
+}
+
+/// Removes an exited process from the process table and returns how it ended.
+pub fn reap(pid: Pid) -> Option<ExitInfo> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let State::Exited(code) = scheduler.processes.get(&pid)?.state else {
+            return None;
+        };
+        let process = scheduler.processes.remove(&pid)?;
+        PROCESS_COUNT.dec();
+        Some(ExitInfo {
+            code,
+            user_ticks: process.user_ticks,
+            system_ticks: process.system_ticks,
+        })
+    })
+}
+
+/// Hands this CPU's FPU registers to the current process, saving them into the process they
+/// belonged to first. Runs on the #NM trap its first FPU or SIMD instruction since being
+/// switched to takes, with interrupts disabled.
+pub(crate) fn load_fpu() {
+    let Some(pid) = current_pid() else {
+        return;
+    };
+    let owner = fpu::owner();
+    if owner == pid.0 {
+        return;
+    }
+    let mut scheduler = SCHEDULER.lock();
+    // Processes stay on their CPU, so the owner isn't running anywhere else
+    if let Some(previous) = scheduler.processes.get_mut(&Pid(owner)) {
+        unsafe { previous.fpu.save() };
+    }
+    if let Some(process) = scheduler.processes.get(&pid) {
+        unsafe { process.fpu.restore() };
+    }
+    fpu::set_owner(pid.0);
+}
+
+/// Runs `f` on the process current on this CPU.
+pub(crate) fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let pid = current_pid()?;
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .processes
+            .get_mut(&pid)
+            .map(|process| f(process))
+    })
+}
+
+/// Records that a system call handler rewrote the whole trap frame, so the return to user
+/// mode must restore every register.
+pub(crate) fn mark_switched() {
+    SWITCHED.get().store(true, Ordering::Relaxed);
+}
+
+/// Whether the last system call handler switched contexts. Clears the flag.
+pub(crate) fn take_switched() -> bool {
+    SWITCHED.get().swap(false, Ordering::Relaxed)
+}
+
+/// How the kernel was entered from user mode, which decides how to leave it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Entry {
+    /// Through `syscall`, with GS swapped to the kernel's.
+    Syscall,
+    /// Through an interrupt or exception stub, which returns with `iretq`.
+    Interrupt,
+}
+
+/// Loads the next process into `frame`, or returns to the kernel context that called [`run`]
+/// if there is nothing left to run.
+fn switch_away(frame: &mut TrapFrame, entry: Entry) {
+    match next_or_idle() {
+        Next::Run(next) => {
+            *frame = next;
+            if entry == Entry::Syscall {
+                mark_switched();
+            }
+        }
+        Next::Done => unsafe {
+            address_space::activate_kernel();
+            if entry == Entry::Syscall {
+                // Undo the entry stub's swapgs, since the return path that would do it is
+                // abandoned
+                core::arch::asm!("swapgs");
+            }
+            resume_kernel(SAVED_RSP.get().load(Ordering::Relaxed))
+        },
+    }
+}
+
+fn switch_from_syscall(frame: &mut TrapFrame) {
+    switch_away(frame, Entry::Syscall);
+}
+
+/// Ends the current process with `code` and switches to the next one.
+pub(crate) fn exit_current(frame: &mut TrapFrame, code: i32) {
+    end_current(frame, code, Entry::Syscall);
+}
+
+fn end_current(frame: &mut TrapFrame, code: i32, entry: Entry) {
+    let pid = Pid(CURRENT.get().swap(0, Ordering::Relaxed));
+    terminate(pid, code);
+    switch_away(frame, entry);
+}
+
+/// Marks `pid` as exited with `code`. Its descriptors are closed and its memory freed right
+/// away; the exit status stays until the parent reaps it. Children it leaves behind are handed
+/// to its own parent, or to the kernel if it has none.
+fn terminate(pid: Pid, code: i32) {
+    let exited = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let process = scheduler.processes.get_mut(&pid)?;
+        if let State::Exited(_) = process.state {
+            return None;
+        }
+        process.state = State::Exited(code);
+        process.alarm_us = None;
+        let files = core::mem::replace(&mut process.files, FdTable::new());
+        let space = process.space.take();
+        let parent = process.parent;
+        for child in scheduler.processes.values_mut() {
+            if child.parent == Some(pid) {
+                child.parent = parent;
+            }
+        }
+        Some((files, space, parent))
+    });
+    if let Some((files, space, parent)) = exited {
+        // Closing files may wake processes, which needs the scheduler lock
+        drop(files);
+        // Dropping the address space switches away from it first if it is active
+        drop(space);
+        super::futex::forget(pid);
+        if let Some(parent) = parent {
+            wake(child_channel(parent));
+        }
+    }
+}
+
+/// Sends `signal` to `pid`. A process that isn't running is terminated right away; a running
+/// one when it next enters the kernel, see [`deliver_signals`].
+pub fn send_signal(pid: Pid, signal: u32) -> Result<(), SignalError> {
+    if !signal::is_valid(signal) {
+        return Err(SignalError::InvalidSignal);
+    }
+    let running = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let process = scheduler
+            .processes
+            .get_mut(&pid)
+            .ok_or(SignalError::NoSuchProcess)?;
+        match process.state {
+            State::Exited(_) => Err(SignalError::NoSuchProcess),
+            State::Running => {
+                process.pending_signals |= signal::mask(signal);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    })?;
+    if !running {
+        terminate(pid, signal::exit_code(signal));
+    }
+    Ok(())
+}
+
+/// Acts on signals pending for the current process before it returns to user mode through
+/// `frame`. Every signal terminates, so this switches to the next process if there are any.
+pub(crate) fn deliver_signals(frame: &mut TrapFrame, entry: Entry) {
+    let pending = with_current(|process| core::mem::take(&mut process.pending_signals));
+    if let Some(signal) = pending.and_then(signal::first) {
+        end_current(frame, signal::exit_code(signal), entry);
+    }
+}
+
+/// Terminates the current process with `signal` straight away, e.g. for a fault it can't
+/// recover from. `frame` must come from an interrupt or exception stub.
+pub(crate) fn force_signal(frame: &mut TrapFrame, signal: u32) {
+    end_current(frame, signal::exit_code(signal), Entry::Interrupt);
+}
+
+/// Arranges for `SIGALRM` to be sent to the current process at `at_us`, or cancels the alarm
+/// if `None`. Returns when the previous alarm would have gone off.
+pub(crate) fn set_alarm(at_us: Option<u64>) -> Option<u64> {
+    with_current(|process| core::mem::replace(&mut process.alarm_us, at_us)).flatten()
+}
+
+/// The wait channel a process blocks on while waiting for its children to exit.
+pub(crate) fn child_channel(parent: Pid) -> usize {
+    parent.0 as usize
+}
+
+/// Blocks the current process on `channel` and switches to the next one. Once woken, the
+/// process executes the same system call again with the registers in `frame`.
+pub(crate) fn block_current(frame: &mut TrapFrame, channel: usize) {
+    block_current_unless(frame, channel, || false);
+}
+
+/// Like [`block_current`], unless `woken` returns `true`. It is called under the scheduler
+/// lock, which waking a process also takes, so a wakeup arriving between the caller's last
+/// check and blocking isn't lost. Returns whether the process blocked.
+pub(crate) fn block_current_unless(
+    frame: &mut TrapFrame,
+    channel: usize,
+    woken: impl FnOnce() -> bool,
+) -> bool {
+    let blocked = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if woken() {
+            return false;
+        }
+        let pid = Pid(CURRENT.get().swap(0, Ordering::Relaxed));
+        if let Some(process) = scheduler.processes.get_mut(&pid) {
+            process.frame = *frame;
+            process.frame.rip -= SYSCALL_INSN_LEN;
+            process.state = State::Blocked { channel };
+        }
+        true
+    });
+    if blocked {
+        switch_from_syscall(frame);
+    }
+    blocked
+}
+
+/// Makes every process blocked on `channel` ready. Must not be called with the scheduler lock
+/// held.
+pub fn wake(channel: usize) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Scheduler { processes, ready } = &mut *scheduler;
+        for process in processes.values_mut() {
+            if process.state == (State::Blocked { channel }) {
+                process.state = State::Ready;
+                enqueue(ready, process);
+            }
+        }
+    });
+    WAKER.wake();
+}
+
+/// Makes `pid` ready if it is blocked on `channel`, leaving any others blocked there.
+pub fn wake_process(pid: Pid, channel: usize) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Scheduler { processes, ready } = &mut *scheduler;
+        if let Some(process) = processes.get_mut(&pid)
+            && process.state == (State::Blocked { channel })
+        {
+            process.state = State::Ready;
+            enqueue(ready, process);
+        }
+    });
+    WAKER.wake();
+}
+
+/// What [`reap_child`] found.
+pub(crate) enum ChildStatus {
+    Exited(Pid, ExitInfo),
+    /// Matching children exist, but none has exited yet.
+    Running,
+    NoChildren,
+}
+
+/// Reaps an exited child of `parent`: `pid`, or any child if `None`.
+pub(crate) fn reap_child(parent: Pid, pid: Option<Pid>) -> ChildStatus {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let mut status = ChildStatus::NoChildren;
+        for child in scheduler.processes.values() {
+            if child.parent != Some(parent) || pid.is_some_and(|pid| pid != child.pid) {
+                continue;
+            }
+            if let State::Exited(code) = child.state {
+                status = ChildStatus::Exited(
+                    child.pid,
+                    ExitInfo {
+                        code,
+                        user_ticks: child.user_ticks,
+                        system_ticks: child.system_ticks,
+                    },
+                );
+                break;
+            }
+            status = ChildStatus::Running;
+        }
+        if let ChildStatus::Exited(pid, _) = status {
+            scheduler.processes.remove(&pid);
+            PROCESS_COUNT.dec();
+        }
+        status
+    })
+}
+
+/// Puts the current process to sleep until `until_us` and switches to the next one. The
+/// process resumes with the registers in `frame`, so the caller sets the return value first.
+pub(crate) fn sleep_current(frame: &mut TrapFrame, until_us: u64) {
+    interrupts::without_interrupts(|| {
+        let pid = Pid(CURRENT.get().swap(0, Ordering::Relaxed));
+        if let Some(process) = SCHEDULER.lock().processes.get_mut(&pid) {
+            process.frame = *frame;
+            process.state = State::Sleeping { until_us };
+        }
+    });
+    switch_from_syscall(frame);
+}
+
+/// Called from the timer interrupt. Charges the tick to the current process and, if it was
+/// interrupted in user mode with its time slice used up, switches `frame` to the next ready
+/// process. Expired alarms are raised from here too.
+pub(crate) fn tick(frame: &mut TrapFrame) {
+    let pid = Pid(CURRENT.get().load(Ordering::Relaxed));
+    if pid.0 == 0 {
+        return;
+    }
+    let counters = CPU_COUNTERS.get();
+    if !frame.from_user() {
+        counters.system_ticks.fetch_add(1, Ordering::Relaxed);
+        // The kernel may have been interrupted while holding the lock
+        if let Some(mut scheduler) = SCHEDULER.try_lock()
+            && let Some(process) = scheduler.processes.get_mut(&pid)
+        {
+            process.system_ticks += 1;
+        }
+        return;
+    }
+    counters.user_ticks.fetch_add(1, Ordering::Relaxed);
+
+    let now = uptime_us();
+    let alarms = {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.wake_sleepers(now);
+        let alarms = scheduler.take_alarms(now);
+        scheduler.charge_user_tick(pid, frame);
+        alarms
+    };
+    for pid in alarms {
+        let _ = send_signal(pid, signal::SIGALRM);
+    }
+    deliver_signals(frame, Entry::Interrupt);
+}
+
+/// Saves the callee-saved registers and RFLAGS on the current stack, records the stack pointer
+/// in `*saved_rsp` and returns to user mode through `frame`. "Returns" when [`resume_kernel`]
+/// is called.
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user(saved_rsp: *mut u64, frame: *const TrapFrame) {
+    naked_asm!(
+        "pushfq",
+        // An interrupt taken once RSP points at the frame would overwrite what was saved here
+        "cli",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        pop_gprs!(),
+        "iretq",
+    );
+}
+
+/// Switches back to the stack saved by [`enter_user`] and returns from it.
+#[unsafe(naked)]
+unsafe extern "C" fn resume_kernel(saved_rsp: u64) -> ! {
+    naked_asm!(
+        "mov rsp, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "popfq",
+        "ret",
+    );
+}
+
+/// A snapshot of a process for diagnostics.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: alloc::string::String,
+    pub state: State,
+    pub cpu: usize,
+    pub user_ticks: u64,
+    pub system_ticks: u64,
+    pub resident_pages: usize,
+    pub page_faults: u64,
+}
+
+pub fn processes() -> Vec<ProcessInfo> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .processes
+            .values()
+            .map(|p| ProcessInfo {
+                pid: p.pid,
+                parent: p.parent,
+                name: p.name.clone(),
+                state: p.state,
+                cpu: p.cpu,
+                user_ticks: p.user_ticks,
+                system_ticks: p.system_ticks,
+                resident_pages: p.space.as_ref().map_or(0, |space| space.resident_pages()),
+                page_faults: p.page_faults,
+            })
+            .collect()
+    })
+}
@@ -0,0 +1,49 @@
+//! Signals.
+//!
+//! Only the default disposition exists so far: every signal terminates the process, which then
+//! exits with status 128 plus the signal number, the way shells report it. A signal sent to a
+//! process that isn't running ends it straight away; a running process acts on it the next
+//! time it enters the kernel, through a system call, the timer tick or a fault.
+
+pub const SIGILL: u32 = 4;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGSEGV: u32 = 11;
+pub const SIGALRM: u32 = 14;
+
+/// One past the highest signal number, which must fit the pending-signal bitmask.
+pub const NSIG: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    InvalidSignal,
+    NoSuchProcess,
+}
+
+/// Whether `signal` is a number a signal can have.
+pub fn is_valid(signal: u32) -> bool {
+    (1..NSIG).contains(&signal)
+}
+
+/// The exit status of a process terminated by `signal`.
+pub fn exit_code(signal: u32) -> i32 {
+    128 + signal as i32
+}
+
+/// The bit standing for `signal` in a pending-signal mask.
+pub(super) fn mask(signal: u32) -> u64 {
+    1 << (signal - 1)
+}
+
+/// The lowest-numbered signal in `pending`, which is acted on first.
+pub(super) fn first(pending: u64) -> Option<u32> {
+    (pending != 0).then(|| pending.trailing_zeros() + 1)
+}
+
+#[test_case]
+fn test_signal_masks() {
+    assert_eq!(first(0), None);
+    assert_eq!(first(mask(SIGALRM) | mask(SIGSEGV)), Some(SIGSEGV));
+    assert_eq!(exit_code(SIGKILL), 137);
+    assert!(!is_valid(0) && !is_valid(NSIG));
+}
@@ -0,0 +1,168 @@
+//! A sampling profiler driven by the timer interrupt.
+//!
+//! While profiling is on, every timer tick records the instruction pointer it interrupted into
+//! a buffer for the CPU it arrived on. [`report`] folds the samples from all CPUs into a flat
+//! profile, counting samples per function as resolved through [`crate::ksyms`].
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::cpu::{MAX_CPUS, PerCpu};
+use crate::ksyms::{self, Demangle};
+use crate::trap::TrapFrame;
+use crate::{serial_print, serial_println};
+
+/// Samples each CPU keeps; later ones are counted as dropped.
+const SAMPLES_PER_CPU: usize = 4096;
+
+struct Samples {
+    rips: [AtomicU64; SAMPLES_PER_CPU],
+    len: AtomicUsize,
+    /// Ticks that interrupted user code, which isn't broken down further.
+    user: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Samples {
+    const fn new() -> Self {
+        Samples {
+            rips: [const { AtomicU64::new(0) }; SAMPLES_PER_CPU],
+            len: AtomicUsize::new(0),
+            user: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLES: PerCpu<Samples> = PerCpu::new([const { Samples::new() }; MAX_CPUS]);
+
+/// Discards earlier samples and starts sampling.
+pub fn start() {
+    ENABLED.store(false, Ordering::Relaxed);
+    for index in 0..MAX_CPUS {
+        let Some(samples) = SAMPLES.get_for(index) else {
+            continue;
+        };
+        samples.len.store(0, Ordering::Relaxed);
+        samples.user.store(0, Ordering::Relaxed);
+        samples.dropped.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stops sampling, keeping the samples taken so far for [`report`].
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Records where the timer interrupt in `frame` landed. Called from the timer handler, so the
+/// only writer to a CPU's buffer is that CPU with interrupts off.
+pub(crate) fn sample(frame: &TrapFrame) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+    let samples = SAMPLES.get();
+    if frame.from_user() {
+        samples.user.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let len = samples.len.load(Ordering::Relaxed);
+    match samples.rips.get(len) {
+        Some(slot) => {
+            slot.store(frame.rip, Ordering::Relaxed);
+            samples.len.store(len + 1, Ordering::Release);
+        }
+        None => {
+            samples.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Samples attributed to one function, or to one unresolved address.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// The function's start, or the sampled address if no symbol covers it.
+    pub addr: u64,
+    pub name: Option<&'static str>,
+    pub samples: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Functions by sample count, highest first.
+    pub entries: Vec<Entry>,
+    /// Kernel samples, the sum over `entries`.
+    pub kernel: u64,
+    pub user: u64,
+    pub dropped: u64,
+}
+
+/// Folds the samples taken so far into a flat profile.
+pub fn report() -> Profile {
+    let mut profile = Profile::default();
+    let mut addrs = Vec::new();
+    for index in 0..MAX_CPUS {
+        let Some(samples) = SAMPLES.get_for(index) else {
+            continue;
+        };
+        let len = samples.len.load(Ordering::Acquire);
+        addrs.extend(
+            samples.rips[..len]
+                .iter()
+                .map(|rip| rip.load(Ordering::Relaxed)),
+        );
+        profile.user += samples.user.load(Ordering::Relaxed);
+        profile.dropped += samples.dropped.load(Ordering::Relaxed);
+    }
+    profile.kernel = addrs.len() as u64;
+
+    // Sorted, the samples from one function are next to each other
+    addrs.sort_unstable();
+    for addr in addrs {
+        let symbol = ksyms::resolve(addr);
+        let addr = symbol.map_or(addr, |symbol| symbol.start);
+        match profile.entries.last_mut() {
+            Some(last) if last.addr == addr => last.samples += 1,
+            _ => profile.entries.push(Entry {
+                addr,
+                name: symbol.map(|symbol| symbol.name),
+                samples: 1,
+            }),
+        }
+    }
+    profile.entries.sort_by_key(|e| Reverse(e.samples));
+    profile
+}
+
+/// Prints the `top` functions with the most samples over serial.
+pub fn dump(top: usize) {
+    let profile = report();
+    serial_println!(
+        "Flat profile: {} kernel samples, {} user, {} dropped",
+        profile.kernel,
+        profile.user,
+        profile.dropped
+    );
+    serial_println!("      %  samples  function");
+    for entry in profile.entries.iter().take(top) {
+        let tenths = (entry.samples * 1000)
+            .checked_div(profile.kernel)
+            .unwrap_or(0);
+        serial_print!(
+            "  {:>3}.{} {:>8}  ",
+            tenths / 10,
+            tenths % 10,
+            entry.samples
+        );
+        if let Some(name) = entry.name {
+            serial_println!("{}", Demangle(name));
+        } else {
+            serial_println!("{:#x}", entry.addr);
+        }
+    }
+}
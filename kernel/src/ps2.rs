@@ -0,0 +1,372 @@
+//! Command path to the PS/2 keyboard behind the i8042 controller.
+//!
+//! The keyboard answers every command byte with an ACK (0xFA) or RESEND (0xFE), and those replies
+//! arrive through IRQ 1 like any other scancode. Commands are therefore queued here and advanced
+//! from the keyboard interrupt handler via [`handle_response`], instead of busy-waiting for the
+//! reply with interrupts enabled.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::ioport::{self, IoRegion};
+use crate::irq::{self, IrqError, IrqHandle};
+
+/// The keyboard's line on the ISA bus.
+const ISA_IRQ: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the mouse.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Controller command: pulse the CPU's reset line.
+const CTRL_PULSE_RESET: u8 = 0xFE;
+/// Controller command: put the next data byte in the output buffer as if the keyboard sent it.
+const CTRL_WRITE_KEYBOARD_OUTPUT: u8 = 0xD2;
+const CMD_SET_LEDS: u8 = 0xED;
+const CMD_SET_TYPEMATIC: u8 = 0xF3;
+const RESP_ACK: u8 = 0xFA;
+const RESP_RESEND: u8 = 0xFE;
+
+const MAX_RETRIES: u8 = 3;
+/// Number of timer ticks a command may wait for a reply before it is retried.
+const REPLY_TIMEOUT_TICKS: u64 = 3;
+const QUEUE_LEN: usize = 8;
+
+/// Keyboard LED bitmask, laid out the way the `0xED` command expects it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LedState(u8);
+
+impl LedState {
+    pub const SCROLL_LOCK: LedState = LedState(1 << 0);
+    pub const NUM_LOCK: LedState = LedState(1 << 1);
+    pub const CAPS_LOCK: LedState = LedState(1 << 2);
+
+    pub const fn empty() -> Self {
+        LedState(0)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: LedState) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn toggle(&mut self, other: LedState) {
+        self.0 ^= other.0;
+    }
+}
+
+/// Key repeat configuration: how long a key must be held before it repeats, and how often it
+/// repeats afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    /// Delay before the first repeat. The device supports 250, 500, 750 and 1000ms.
+    pub delay_ms: u16,
+    /// Repeat rate in characters per second, from roughly 2 to 30.
+    pub rate_hz: u8,
+}
+
+impl Typematic {
+    pub const DEFAULT: Typematic = Typematic {
+        delay_ms: 500,
+        rate_hz: 10,
+    };
+
+    /// Encodes the configuration into the `0xF3` parameter byte.
+    ///
+    /// Bits 5-6 select the delay in 250ms steps, and bits 0-4 select the repeat period
+    /// `(8 + A) * 2^B * 4.17ms`, where A is bits 0-2 and B is bits 3-4. The closest
+    /// supported value is picked for both.
+    pub fn encode(&self) -> u8 {
+        let delay = (self.delay_ms.clamp(250, 1000) + 125) / 250 - 1;
+
+        let target_us = 1_000_000 / self.rate_hz.max(1) as u32;
+        let mut best = 0u8;
+        let mut best_diff = u32::MAX;
+        for code in 0..32u8 {
+            let diff = rate_code_period_us(code).abs_diff(target_us);
+            if diff < best_diff {
+                best = code;
+                best_diff = diff;
+            }
+        }
+        ((delay as u8) << 5) | best
+    }
+
+    /// Time between repeats in microseconds.
+    pub fn period_us(&self) -> u64 {
+        1_000_000 / self.rate_hz.max(1) as u64
+    }
+}
+
+fn rate_code_period_us(code: u8) -> u32 {
+    let a = (code & 0x7) as u32;
+    let b = ((code >> 3) & 0x3) as u32;
+    (8 + a) * (1 << b) * 4170
+}
+
+#[derive(Clone, Copy)]
+struct InFlight {
+    bytes: [u8; 2],
+    sent: usize,
+    retries: u8,
+    issued_tick: u64,
+}
+
+struct Controller {
+    queue: [Option<[u8; 2]>; QUEUE_LEN],
+    head: usize,
+    len: usize,
+    in_flight: Option<InFlight>,
+    leds: LedState,
+    typematic: Typematic,
+}
+
+static CONTROLLER: Mutex<Controller> = Mutex::new(Controller {
+    queue: [None; QUEUE_LEN],
+    head: 0,
+    len: 0,
+    in_flight: None,
+    leds: LedState::empty(),
+    typematic: Typematic::DEFAULT,
+});
+
+/// Set once the device has rejected a typematic command, so the keyboard task synthesizes
+/// repeats in software instead.
+static TYPEMATIC_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+impl Controller {
+    fn push(&mut self, bytes: [u8; 2]) -> bool {
+        if self.len == QUEUE_LEN {
+            return false;
+        }
+        let tail = (self.head + self.len) % QUEUE_LEN;
+        self.queue[tail] = Some(bytes);
+        self.len += 1;
+        true
+    }
+
+    fn start_next(&mut self) {
+        while self.in_flight.is_none() && self.len > 0 {
+            let bytes = self.queue[self.head].take();
+            self.head = (self.head + 1) % QUEUE_LEN;
+            self.len -= 1;
+            if let Some(bytes) = bytes {
+                self.in_flight = Some(InFlight {
+                    bytes,
+                    sent: 0,
+                    retries: 0,
+                    issued_tick: crate::timer::ticks(),
+                });
+                write_data(bytes[0]);
+            }
+        }
+    }
+
+    /// Re-sends the current byte of the in-flight command, or gives up on it after
+    /// `MAX_RETRIES` attempts.
+    fn retry(&mut self) {
+        let Some(mut cmd) = self.in_flight else {
+            return;
+        };
+        if cmd.retries >= MAX_RETRIES {
+            if cmd.bytes[0] == CMD_SET_TYPEMATIC {
+                TYPEMATIC_UNSUPPORTED.store(true, Ordering::Relaxed);
+            }
+            self.in_flight = None;
+            self.start_next();
+            return;
+        }
+        cmd.retries += 1;
+        cmd.issued_tick = crate::timer::ticks();
+        self.in_flight = Some(cmd);
+        write_data(cmd.bytes[cmd.sent]);
+    }
+}
+
+/// The controller's data port, and its status port, which takes commands when written.
+struct Ports {
+    data: IoRegion,
+    status: IoRegion,
+}
+
+static PORTS: Once<Ports> = Once::new();
+
+fn ports() -> &'static Ports {
+    PORTS.call_once(|| unsafe {
+        Ports {
+            data: ioport::claim("i8042", DATA_PORT, 1).expect("i8042 data port taken"),
+            status: ioport::claim("i8042", STATUS_PORT, 1).expect("i8042 status port taken"),
+        }
+    })
+}
+
+/// Blocks until the controller's input buffer is empty, then writes `byte` to the device.
+fn write_data(byte: u8) {
+    wait_input_empty();
+    ports().data.write(0, byte);
+}
+
+/// Blocks until the controller's input buffer is empty, then writes `byte` to the controller.
+fn write_controller(byte: u8) {
+    wait_input_empty();
+    ports().status.write(0, byte);
+}
+
+fn wait_input_empty() {
+    for _ in 0..100_000 {
+        if ports().status.read::<u8>(0) & STATUS_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn submit(bytes: [u8; 2]) {
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        if !controller.push(bytes) {
+            crate::serial_println!("WARNING: PS/2 command queue full; dropping {:#x}", bytes[0]);
+            return;
+        }
+        controller.start_next();
+    });
+}
+
+/// Updates the keyboard LEDs.
+pub fn set_leds(leds: LedState) {
+    interrupts::without_interrupts(|| CONTROLLER.lock().leds = leds);
+    submit([CMD_SET_LEDS, leds.bits()]);
+}
+
+/// Returns the LED state last sent to the device.
+pub fn leds() -> LedState {
+    interrupts::without_interrupts(|| CONTROLLER.lock().leds)
+}
+
+/// Configures hardware key repeat. If the device refuses the command, software repeat in the
+/// keyboard task takes over using the same settings.
+pub fn set_typematic(typematic: Typematic) {
+    interrupts::without_interrupts(|| CONTROLLER.lock().typematic = typematic);
+    TYPEMATIC_UNSUPPORTED.store(false, Ordering::Relaxed);
+    submit([CMD_SET_TYPEMATIC, typematic.encode()]);
+}
+
+/// Returns the key repeat settings last requested.
+pub fn typematic() -> Typematic {
+    interrupts::without_interrupts(|| CONTROLLER.lock().typematic)
+}
+
+/// Whether the device rejected hardware key repeat configuration.
+pub fn typematic_unsupported() -> bool {
+    TYPEMATIC_UNSUPPORTED.load(Ordering::Relaxed)
+}
+
+/// Has the controller deliver `scancode` through IRQ 1 as though a key had been pressed, so
+/// the whole interrupt-to-task path can be driven without a keyboard.
+pub fn inject_scancode(scancode: u8) {
+    interrupts::without_interrupts(|| {
+        write_controller(CTRL_WRITE_KEYBOARD_OUTPUT);
+        write_data(scancode);
+    });
+}
+
+/// Resets the machine by pulsing the CPU's reset line from the controller. Returns if the
+/// controller doesn't do it.
+pub fn pulse_reset() {
+    write_controller(CTRL_PULSE_RESET);
+}
+
+/// Reads a byte from the keyboard if one is waiting, without IRQ 1. For when the interrupt path
+/// isn't running, e.g. after a panic. Mouse bytes are read and discarded.
+pub fn poll_scancode() -> Option<u8> {
+    let status: u8 = ports().status.read(0);
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let byte: u8 = ports().data.read(0);
+    (status & STATUS_AUX_DATA == 0).then_some(byte)
+}
+
+/// Routes the keyboard interrupt through the I/O APIC.
+pub fn init_irq() -> Result<IrqHandle, IrqError> {
+    irq::register_isa(ISA_IRQ, "keyboard", handle_interrupt)
+}
+
+/// Reads the byte the controller raised IRQ 1 for and passes it on as a command reply or a
+/// scancode. Returns `false` if the controller had nothing to deliver, so the interrupt came
+/// from another device on the line.
+pub(crate) fn handle_interrupt() -> bool {
+    if ports().status.read::<u8>(0) & STATUS_OUTPUT_FULL == 0 {
+        return false;
+    }
+    let byte: u8 = ports().data.read(0);
+    if !handle_response(byte) {
+        crate::task::keyboard::add_scancode(byte);
+    }
+    true
+}
+
+/// Called for every byte read from the data port.
+///
+/// Returns `true` if the byte was a reply to an outstanding command and must not be treated as
+/// a scancode.
+fn handle_response(byte: u8) -> bool {
+    if byte != RESP_ACK && byte != RESP_RESEND {
+        return false;
+    }
+    let mut controller = CONTROLLER.lock();
+    let Some(mut cmd) = controller.in_flight else {
+        return false;
+    };
+    if byte == RESP_RESEND {
+        controller.retry();
+        return true;
+    }
+
+    cmd.sent += 1;
+    if cmd.sent < cmd.bytes.len() {
+        cmd.retries = 0;
+        cmd.issued_tick = crate::timer::ticks();
+        controller.in_flight = Some(cmd);
+        write_data(cmd.bytes[cmd.sent]);
+    } else {
+        controller.in_flight = None;
+        controller.start_next();
+    }
+    true
+}
+
+/// Called from the timer interrupt to retry commands the device never answered.
+pub(crate) fn tick(now: u64) {
+    let mut controller = CONTROLLER.lock();
+    if let Some(cmd) = controller.in_flight
+        && now.wrapping_sub(cmd.issued_tick) > REPLY_TIMEOUT_TICKS
+    {
+        controller.retry();
+    }
+}
+
+#[test_case]
+fn test_typematic_encoding() {
+    // 30 cps with a 250ms delay is the fastest setting: all bits clear.
+    let fastest = Typematic {
+        delay_ms: 250,
+        rate_hz: 30,
+    };
+    assert_eq!(fastest.encode(), 0x00);
+
+    // 2 cps with a 1s delay is the slowest setting: all bits set.
+    let slowest = Typematic {
+        delay_ms: 1000,
+        rate_hz: 2,
+    };
+    assert_eq!(slowest.encode(), 0x7F);
+
+    assert_eq!(Typematic::DEFAULT.encode() >> 5, 1);
+}
@@ -0,0 +1,305 @@
+//! Paravirtual clocks: KVM's kvmclock and Hyper-V's reference TSC page.
+//!
+//! Both hypervisors keep a structure in guest memory up to date with the scale and offset that
+//! turn the TSC into guest time, so reading the clock is a TSC read and a multiply with no exit
+//! to the hypervisor. Under KVM this is also the clock that keeps the right rate: the emulated
+//! HPET is paced differently than under TCG. [`init`] registers whichever one the hypervisor
+//! offers, and [`crate::timer::uptime_ns`] then prefers it over the HPET.
+//!
+//! Only the boot CPU registers a kvmclock area, so kvmclock is only used when KVM reports the
+//! TSC as stable across CPUs, which makes the boot CPU's area valid for every CPU.
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::Translate;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::cpu::hypervisor::{self, Hypervisor};
+use crate::cpu::rdtsc;
+use crate::warn;
+
+const KVM_LEAF_FEATURES: u32 = 0x4000_0001;
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+const KVM_FEATURE_CLOCKSOURCE_STABLE_BIT: u32 = 1 << 24;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4B56_4D01;
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+const HV_LEAF_FEATURES: u32 = 0x4000_0003;
+const HV_ACCESS_REFERENCE_TSC: u32 = 1 << 9;
+const HV_X64_MSR_GUEST_OS_ID: u32 = 0x4000_0000;
+const HV_X64_MSR_TIME_REF_COUNT: u32 = 0x4000_0020;
+const HV_X64_MSR_REFERENCE_TSC: u32 = 0x4000_0021;
+/// An open-source guest (bit 63) that gives no vendor or version.
+const GUEST_OS_ID: u64 = 1 << 63;
+/// Hyper-V counts time in 100 ns units.
+const HV_NS_PER_UNIT: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Kvmclock,
+    HyperVTscPage,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Kvmclock => "kvmclock",
+            Source::HyperVTscPage => "Hyper-V TSC page",
+        }
+    }
+}
+
+/// KVM's `pvclock_vcpu_time_info`.
+#[repr(C, align(64))]
+struct PvclockTimeInfo {
+    /// Odd while the host is updating the other fields.
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+/// Hyper-V's reference TSC page. Only the start of the page is used.
+#[repr(C, align(4096))]
+struct HvTscPage {
+    /// Changes whenever the host updates the page; 0 while the page can't be used.
+    sequence: u32,
+    reserved: u32,
+    scale: u64,
+    offset: i64,
+    rest: [u8; 4096 - 24],
+}
+
+/// Memory the hypervisor writes to behind the compiler's back.
+struct Shared<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for Shared<T> {}
+
+static KVMCLOCK: Shared<PvclockTimeInfo> = Shared(UnsafeCell::new(PvclockTimeInfo {
+    version: 0,
+    pad0: 0,
+    tsc_timestamp: 0,
+    system_time: 0,
+    tsc_to_system_mul: 0,
+    tsc_shift: 0,
+    flags: 0,
+    pad: [0; 2],
+}));
+
+static TSC_PAGE: Shared<HvTscPage> = Shared(UnsafeCell::new(HvTscPage {
+    sequence: 0,
+    reserved: 0,
+    scale: 0,
+    offset: 0,
+    rest: [0; 4096 - 24],
+}));
+
+static SOURCE: Once<Source> = Once::new();
+/// The clock's reading when uptime was zero, so uptime carries on from the HPET's.
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the paravirtual clock of the hypervisor the kernel runs under, if it has one the
+/// kernel can use. Needs the page allocator, to find the physical address to register.
+pub fn init() -> Option<Source> {
+    if let Some(&source) = SOURCE.get() {
+        return Some(source);
+    }
+    let source = match hypervisor::get()? {
+        Hypervisor::Kvm => init_kvmclock()?,
+        Hypervisor::HyperV => init_hyperv()?,
+        _ => return None,
+    };
+    let uptime = crate::timer::uptime_ns().unwrap_or(0);
+    BASE_NS.store(read(source).wrapping_sub(uptime), Ordering::Relaxed);
+    Some(*SOURCE.call_once(|| source))
+}
+
+pub fn source() -> Option<Source> {
+    SOURCE.get().copied()
+}
+
+/// Nanoseconds since boot on the paravirtual clock, or `None` if there isn't one.
+pub fn uptime_ns() -> Option<u64> {
+    let source = source()?;
+    Some(read(source).wrapping_sub(BASE_NS.load(Ordering::Relaxed)))
+}
+
+/// How far apart two distinct readings can be.
+pub fn resolution_ns() -> Option<u64> {
+    match source()? {
+        Source::Kvmclock => Some(1),
+        Source::HyperVTscPage => Some(HV_NS_PER_UNIT),
+    }
+}
+
+/// The TSC frequency the hypervisor scales by, in kHz.
+pub fn tsc_khz() -> Option<u64> {
+    let khz = match source()? {
+        Source::Kvmclock => {
+            let area = KVMCLOCK.0.get();
+            let (mul, shift) = unsafe {
+                (
+                    ptr::read_volatile(&raw const (*area).tsc_to_system_mul),
+                    ptr::read_volatile(&raw const (*area).tsc_shift),
+                )
+            };
+            let khz = (1_000_000u128 << 32).checked_div(mul as u128)?;
+            if shift < 0 {
+                khz << -shift
+            } else {
+                khz >> shift
+            }
+        }
+        Source::HyperVTscPage => {
+            let scale = unsafe { ptr::read_volatile(&raw const (*TSC_PAGE.0.get()).scale) };
+            (10_000u128 << 64).checked_div(scale as u128)?
+        }
+    };
+    u64::try_from(khz).ok()
+}
+
+fn phys_addr<T>(object: &'static T) -> Option<PhysAddr> {
+    let guard = PAGE_ALLOCATOR.lock();
+    guard
+        .as_ref()?
+        .mapper
+        .translate_addr(VirtAddr::from_ptr(object))
+}
+
+fn init_kvmclock() -> Option<Source> {
+    let [features, ..] = hypervisor::cpuid(KVM_LEAF_FEATURES)?;
+    if features & KVM_FEATURE_CLOCKSOURCE2 == 0 {
+        return None;
+    }
+    if features & KVM_FEATURE_CLOCKSOURCE_STABLE_BIT == 0 {
+        warn!("kvmclock: the host can't say whether the TSC is stable, not using it");
+        return None;
+    }
+    let phys = phys_addr(&KVMCLOCK)?;
+    let mut msr = Msr::new(MSR_KVM_SYSTEM_TIME_NEW);
+    unsafe { msr.write(phys.as_u64() | 1) };
+
+    let flags = unsafe { ptr::read_volatile(&raw const (*KVMCLOCK.0.get()).flags) };
+    if flags & PVCLOCK_TSC_STABLE_BIT == 0 {
+        warn!("kvmclock: the TSC isn't stable across CPUs, not using it");
+        unsafe { msr.write(0) };
+        return None;
+    }
+    Some(Source::Kvmclock)
+}
+
+fn init_hyperv() -> Option<Source> {
+    let [features, ..] = hypervisor::cpuid(HV_LEAF_FEATURES)?;
+    if features & HV_ACCESS_REFERENCE_TSC == 0 {
+        return None;
+    }
+    let phys = phys_addr(&TSC_PAGE)?;
+    unsafe {
+        // Hyper-V ignores the other MSRs until the guest has identified itself
+        Msr::new(HV_X64_MSR_GUEST_OS_ID).write(GUEST_OS_ID);
+        let mut msr = Msr::new(HV_X64_MSR_REFERENCE_TSC);
+        // Bits 1..12 are reserved and must be written back as they were
+        msr.write(msr.read() & 0xFFE | phys.as_u64() | 1);
+    }
+    Some(Source::HyperVTscPage)
+}
+
+fn read(source: Source) -> u64 {
+    match source {
+        Source::Kvmclock => read_kvmclock(),
+        Source::HyperVTscPage => read_hyperv(),
+    }
+}
+
+fn read_kvmclock() -> u64 {
+    let area = KVMCLOCK.0.get();
+    loop {
+        let version = unsafe { ptr::read_volatile(&raw const (*area).version) };
+        if version & 1 != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        fence(Ordering::Acquire);
+        let (tsc_timestamp, system_time, mul, shift) = unsafe {
+            (
+                ptr::read_volatile(&raw const (*area).tsc_timestamp),
+                ptr::read_volatile(&raw const (*area).system_time),
+                ptr::read_volatile(&raw const (*area).tsc_to_system_mul),
+                ptr::read_volatile(&raw const (*area).tsc_shift),
+            )
+        };
+        let tsc = rdtsc();
+        fence(Ordering::Acquire);
+        if unsafe { ptr::read_volatile(&raw const (*area).version) } == version {
+            return pvclock_ns(tsc, tsc_timestamp, system_time, mul, shift);
+        }
+    }
+}
+
+fn read_hyperv() -> u64 {
+    let page = TSC_PAGE.0.get();
+    loop {
+        let sequence = unsafe { ptr::read_volatile(&raw const (*page).sequence) };
+        if sequence == 0 {
+            // The page is invalid, e.g. during migration; the MSR counts on the same timebase
+            let units = unsafe { Msr::new(HV_X64_MSR_TIME_REF_COUNT).read() };
+            return units * HV_NS_PER_UNIT;
+        }
+        fence(Ordering::Acquire);
+        let (scale, offset) = unsafe {
+            (
+                ptr::read_volatile(&raw const (*page).scale),
+                ptr::read_volatile(&raw const (*page).offset),
+            )
+        };
+        let tsc = rdtsc();
+        fence(Ordering::Acquire);
+        if unsafe { ptr::read_volatile(&raw const (*page).sequence) } == sequence {
+            return hyperv_ns(tsc, scale, offset);
+        }
+    }
+}
+
+/// kvmclock's scaling: the TSC ticks since `tsc_timestamp`, shifted by `shift`, times `mul`
+/// as a 32.32 fixed-point number, on top of `system_time`.
+fn pvclock_ns(tsc: u64, tsc_timestamp: u64, system_time: u64, mul: u32, shift: i8) -> u64 {
+    let mut delta = tsc.wrapping_sub(tsc_timestamp);
+    if shift < 0 {
+        delta >>= -shift;
+    } else {
+        delta <<= shift;
+    }
+    system_time.wrapping_add(((delta as u128 * mul as u128) >> 32) as u64)
+}
+
+/// The TSC page's scaling: the TSC times `scale` as a 0.64 fixed-point number, plus `offset`,
+/// in 100 ns units.
+fn hyperv_ns(tsc: u64, scale: u64, offset: i64) -> u64 {
+    let units = ((tsc as u128 * scale as u128) >> 64) as u64;
+    units.wrapping_add_signed(offset) * HV_NS_PER_UNIT
+}
+
+#[test_case]
+fn test_pvclock_scaling() {
+    // A 2 GHz TSC, 0.5 ns per tick: a multiplier of 0.5, or just under 1.0 after halving
+    let ns = pvclock_ns(3_000, 1_000, 500, 1 << 31, 0);
+    assert_eq!(ns, 500 + 1_000);
+    let ns = pvclock_ns(3_000, 1_000, 500, u32::MAX, -1);
+    assert_eq!(ns, 500 + 999);
+}
+
+#[test_case]
+fn test_hyperv_scaling() {
+    // A 1 GHz TSC: a 100 ns unit every 100 ticks
+    let scale = (u64::MAX / 100) + 1;
+    assert_eq!(hyperv_ns(1_000_000, scale, 0), 1_000_000);
+    assert_eq!(hyperv_ns(1_000_000, scale, -10), 999_000);
+}
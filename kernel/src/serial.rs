@@ -1,27 +1,105 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, Once};
 use uart_16550::SerialPort;
 
+use crate::ioport::{self, IoRegion};
+
+const COM1: u16 = 0x3F8;
+const COM1_PORT_COUNT: u16 = 8;
+/// Line status register, and its bit for a received byte.
+const LSR: u16 = 5;
+const LSR_DATA_READY: u8 = 1 << 0;
+/// Line status bits for room to queue another byte, and for every byte having been sent.
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+/// Spins waiting on the line status before giving up on a UART that isn't there.
+const TX_SPINS: usize = 100_000;
+
+static COM1_PORTS: Once<IoRegion> = Once::new();
+
+fn com1() -> &'static IoRegion {
+    COM1_PORTS.call_once(|| {
+        unsafe { ioport::claim("uart", COM1, COM1_PORT_COUNT) }.expect("COM1 ports taken")
+    })
+}
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(com1().base()) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Takes a byte the UART has received, if there is one, without waiting.
+pub fn try_read_byte() -> Option<u8> {
+    let ports = com1();
+    (ports.read::<u8>(LSR) & LSR_DATA_READY != 0).then(|| ports.read(0))
+}
+
+/// Set once a panic has taken the port from whoever held [`SERIAL1`]. Every write bypasses
+/// the lock from then on, as the holder may be the panicking code, or stopped for good.
+static POISONED: AtomicBool = AtomicBool::new(false);
+
+/// Spins until the line status has `bit` set, or for [`TX_SPINS`] at most.
+fn wait_for(ports: &IoRegion, bit: u8) {
+    for _ in 0..TX_SPINS {
+        if ports.read::<u8>(LSR) & bit != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Writes to the UART's registers directly, without [`SERIAL1`]'s lock.
+struct RawPort(&'static IoRegion);
+
+impl Write for RawPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            wait_for(self.0, LSR_THR_EMPTY);
+            self.0.write(0, byte);
+        }
+        Ok(())
+    }
+}
+
+/// Prints `args` whoever holds the serial lock, and waits until the UART has sent all of it,
+/// so it is out before the machine resets or QEMU exits. Only for the panic and double fault
+/// handlers: it marks the lock poisoned and breaks it, and all later output bypasses it.
+pub fn force_unlock_and_print(args: fmt::Arguments) {
+    POISONED.store(true, Ordering::Release);
+    // Whoever held it may still write, interleaved with us, but nobody waits on it again
+    unsafe { SERIAL1.force_unlock() };
+    let ports = com1();
+    let _ = RawPort(ports).write_fmt(args);
+    wait_for(ports, LSR_TX_IDLE);
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("An error occurred while writing to the serial port");
+        write_port(args);
+        crate::virtio::console::mirror(args);
     });
 }
 
+/// Writes to the serial port only, without copying to the virtio console.
+pub(crate) fn write_port(args: ::core::fmt::Arguments) {
+    if POISONED.load(Ordering::Acquire) {
+        let _ = RawPort(com1()).write_fmt(args);
+        return;
+    }
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("An error occurred while writing to the serial port");
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
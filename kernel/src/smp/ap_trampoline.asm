@@ -1,59 +1,78 @@
 ; ap_trampoline.asm
-; Self-contained trampoline designed to be loaded at physical address 0x8000.
-; It reserves data fields that will be patched by the BSP:
-;   cr3val:    4 bytes (offset 0)
-;   kcode:     8 bytes (offset 4) – kernel entry pointer (64-bit)
-;   kstack:    8 bytes (offset 12) – kernel stack pointer for this AP
-;   kgsval:    8 bytes (offset 20) – GS base value
-;   commword:  4 bytes (offset 28) – communication flag; AP sets to 1 when ready
-
-section .rodata
-    cr3val:    dq 0
-    kcode:     dq 0
-    kstack:    dq 0
-    kgsval:    dq 0
-    commword:  dd 0
-
-section .text
-global trampoline
-; Start in 16-bit real mode.
+; Assembled as a flat binary at offset 0 and copied by the BSP to a free page below 1 MiB that
+; it picks from the memory map at boot, so nothing here may assume where it runs. Real mode
+; code only uses offsets from CS, which the SIPI sets to the page. The few absolute addresses
+; protected and long mode need are listed in `relocs`, and the BSP adds the page's physical
+; address to each of them after copying (see smp/trampoline.rs).
+;
+; Fields at the start of the page, patched by the BSP:
+;   cr3val:    8 bytes (offset 8)  - page table root
+;   kcode:     8 bytes (offset 16) - kernel entry pointer (64-bit)
+;   kstack:    8 bytes (offset 24) - kernel stack pointer for this AP
+;   kgsval:    8 bytes (offset 32) - GS base value
+;   commword:  4 bytes (offset 40) - communication flags; AP sets bit 0 when it starts and
+;                                    bit 1 once it has loaded kstack
+;   relocs:    2 bytes (offset 44) - offset of the relocation table
+;   nrelocs:   2 bytes (offset 46) - entries in it
+
 bits 16
+org 0
+
 trampoline:
     jmp real_start
 
-;------------------------------------------------------------------------------ 
-; GDT at physical 0x8000:
+align 8
+cr3val:    dq 0
+kcode:     dq 0
+kstack:    dq 0
+kgsval:    dq 0
+commword:  dd 0
+relocs:    dw reloc_table
+nrelocs:   dw (reloc_table_end - reloc_table) / 2
+
+;------------------------------------------------------------------------------
+; GDT, flat so that linear addresses are physical ones until paging is on:
 ;   0x00: null
-;   0x08: 32‑bit code
-;   0x10: 32‑bit data
-;   0x18: 64‑bit code (L=1)
-;------------------------------------------------------------------------------ 
+;   0x08: 32-bit code
+;   0x10: 32-bit data
+;   0x18: 64-bit code (L=1)
+;------------------------------------------------------------------------------
 align 8
 gdt:
     ; 0x00: null
     dq 0x0000000000000000
 
-    ; 0x08: 32‑bit code @ physical 0x8000
-    ;   limit=0xFFFFF, base=0x8000, access=0x9A, flags=0xC
-    dq 0x00CF9A008000FFFF
+    ; 0x08: 32-bit code, base=0, limit=0xFFFFF, access=0x9A, flags=0xC
+    dq 0x00CF9A000000FFFF
 
-    ; 0x10: 32‑bit data @ physical 0x8000
-    ;   limit=0xFFFFF, base=0x8000, access=0x92, flags=0xC
-    dq 0x00CF920008000FFFF
+    ; 0x10: 32-bit data, base=0, limit=0xFFFFF, access=0x92, flags=0xC
+    dq 0x00CF92000000FFFF
 
-    ; 0x18: 64‑bit code (base is ignored in long mode)
-    ;   limit=0xFFFFF, access=0x9A, L=1, G=1 → flags+limit = 0xAF
+    ; 0x18: 64-bit code (base is ignored in long mode)
+    ;   limit=0xFFFFF, access=0x9A, L=1, G=1 -> flags+limit = 0xAF
     dq 0x00AF9A000000FFFF
 
 gdt_end:
 gdt_ptr:
     dw gdt_end - gdt - 1
-    dd gdt
+gdt_base:
+    dd gdt                      ; relocated
+
+; Offsets of the 32-bit absolute addresses the BSP relocates.
+reloc_table:
+    dw gdt_base
+    dw prot_target
+    dw long_target
+reloc_table_end:
 
 real_start:
+    cli
     ; Set DS = CS.
     mov ax, cs
     mov ds, ax
+    ; The trampoline's physical address, kept in EBX for protected mode
+    movzx ebx, ax
+    shl ebx, 4
     ; Signal readiness: set commword OR 1.
     or dword [commword], 1
     ; Load the minimal GDT.
@@ -62,8 +81,11 @@ real_start:
     mov eax, cr0
     bts eax, 0
     mov cr0, eax
-    ; Far jump into protected mode.
-    jmp 0x08:prot_start
+    ; Far jump into protected mode: 66 EA, then the 32-bit offset and the selector.
+    db 0x66, 0xEA
+prot_target:
+    dd prot_start               ; relocated
+    dw 0x08
 
 ; Protected mode code (32-bit)
 bits 32
@@ -74,7 +96,7 @@ prot_start:
     mov ss, ax
 
     ; Load CR3
-    mov eax, [cr3val]
+    mov eax, [ebx + cr3val]
     mov cr3, eax
 
     ; Enable PAE
@@ -88,13 +110,17 @@ prot_start:
     or   eax, 1
     wrmsr
 
-    ; Enable paging → enter long‑mode
+    ; Enable paging -> enter long mode. The kernel identity maps this page, so execution
+    ; carries on here.
     mov  eax, cr0
     bts  eax, 31
     mov  cr0, eax
 
-    ; Far jump into your 64‑bit code segment (selector 0x18)
-    jmp 0x18:long_start
+    ; Far jump into the 64-bit code segment: EA, then the 32-bit offset and the selector.
+    db 0xEA
+long_target:
+    dd long_start               ; relocated
+    dw 0x18
 
 ; Long mode code (64-bit)
 bits 64
@@ -103,11 +129,13 @@ long_start:
     mov ds, ax
     mov es, ax
     mov ss, ax
-    ; Load the AP stack pointer from the trampoline’s kstack field.
-    mov rax, [kstack]
+    ; Load the AP stack pointer from the trampoline's kstack field.
+    mov rax, [rel kstack]
     mov rsp, rax
+    ; kstack can now be patched for the next AP.
+    lock or dword [rel commword], 2
     ; Jump to the kernel entry point stored in kcode.
-    mov rax, [kcode]
+    mov rax, [rel kcode]
     jmp rax
 
 
@@ -0,0 +1,327 @@
+//! Running a function on another CPU.
+//!
+//! The caller posts a request to the target's mailbox and sends it an IPI, and the target runs
+//! the function from the interrupt handler. [`call_on`] and [`call_all`] return futures that
+//! complete with the results. [`call_on_wait`] and [`call_all_wait`] spin instead and don't
+//! allocate, so they also work with the heap locked. Neither should be called holding a lock
+//! that another CPU could be spinning on with interrupts disabled, as it would never take the
+//! request; the page allocator only shoots down pages with its lock dropped for this reason.
+//!
+//! Functions run in interrupt context with interrupts disabled, so they must not block or take
+//! a lock the interrupted code could be holding.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::ops::Range;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::tlb;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::cpu::{self, MAX_CPUS, PerCpu};
+use crate::interrupts::{self, CALL_VEC};
+use crate::memory::PAGE_SIZE;
+
+/// Requests a CPU can have waiting at once. Posting to a full mailbox waits for it to drain.
+const MAILBOX_SIZE: usize = 8;
+
+/// Above this many pages, [`shootdown`] flushes whole TLBs rather than page by page.
+const SHOOTDOWN_MAX_PAGES: u64 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// No CPU with this index has started.
+    NoSuchCpu(usize),
+    /// IPIs need the local APIC.
+    NoApic,
+}
+
+/// A function posted to another CPU. Whoever posts it keeps it in place until `released`.
+struct Request {
+    /// Runs the function in `data` and stores its result there.
+    run: unsafe fn(*const ()),
+    data: *const (),
+    /// Set once the result is available.
+    done: AtomicBool,
+    /// Set once the target no longer touches the request, so it may be freed.
+    released: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl Request {
+    fn new<F: FnOnce() -> R, R>(slot: *const Slot<F, R>) -> Self {
+        Request {
+            run: run_slot::<F, R>,
+            data: slot as *const (),
+            done: AtomicBool::new(false),
+            released: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    fn run(&self) {
+        unsafe { (self.run)(self.data) };
+        self.done.store(true, Ordering::Release);
+        self.waker.wake();
+        self.released.store(true, Ordering::Release);
+    }
+
+    /// Spins until the target is done with the request, running what is posted to this CPU
+    /// meanwhile so two CPUs calling each other can't deadlock.
+    fn wait(&self) {
+        while !self.released.load(Ordering::Acquire) {
+            run_pending();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// The function of a request and, once it has run, its result.
+struct Slot<F, R> {
+    f: Mutex<Option<F>>,
+    result: Mutex<Option<R>>,
+}
+
+impl<F, R> Slot<F, R> {
+    fn new(f: F) -> Self {
+        Slot {
+            f: Mutex::new(Some(f)),
+            result: Mutex::new(None),
+        }
+    }
+}
+
+unsafe fn run_slot<F: FnOnce() -> R, R>(data: *const ()) {
+    let slot = unsafe { &*(data as *const Slot<F, R>) };
+    if let Some(f) = slot.f.lock().take() {
+        let result = f();
+        *slot.result.lock() = Some(result);
+    }
+}
+
+/// Requests waiting for one CPU.
+struct Mailbox {
+    requests: [*const Request; MAILBOX_SIZE],
+    len: usize,
+}
+
+// The requests are only dereferenced while their posters keep them alive
+unsafe impl Send for Mailbox {}
+
+static MAILBOXES: PerCpu<Mutex<Mailbox>> = PerCpu::new(
+    [const {
+        Mutex::new(Mailbox {
+            requests: [core::ptr::null(); MAILBOX_SIZE],
+            len: 0,
+        })
+    }; MAX_CPUS],
+);
+
+/// Queues `request` for CPU `cpu` and interrupts it. `request` must stay in place until it is
+/// released.
+fn post(cpu: usize, request: &Request) -> Result<(), CallError> {
+    let (_, apic_id) = cpu::online()
+        .find(|&(index, _)| index == cpu)
+        .ok_or(CallError::NoSuchCpu(cpu))?;
+    let mailbox = MAILBOXES.get_for(cpu).ok_or(CallError::NoSuchCpu(cpu))?;
+    loop {
+        let posted = cpu_interrupts::without_interrupts(|| {
+            let mut mailbox = mailbox.lock();
+            if mailbox.len == MAILBOX_SIZE {
+                return false;
+            }
+            let len = mailbox.len;
+            mailbox.requests[len] = request;
+            mailbox.len += 1;
+            true
+        });
+        if posted {
+            break;
+        }
+        run_pending();
+        core::hint::spin_loop();
+    }
+    if !interrupts::send_ipi(apic_id, CALL_VEC) {
+        // Nobody will take it out of the mailbox, so do that here rather than leave it dangling
+        take_pending(cpu, request);
+        return Err(CallError::NoApic);
+    }
+    Ok(())
+}
+
+/// Removes `request` from CPU `cpu`'s mailbox if it is still there.
+fn take_pending(cpu: usize, request: &Request) {
+    let Some(mailbox) = MAILBOXES.get_for(cpu) else {
+        return;
+    };
+    cpu_interrupts::without_interrupts(|| {
+        let mut mailbox = mailbox.lock();
+        let len = mailbox.len;
+        if let Some(index) = mailbox.requests[..len]
+            .iter()
+            .position(|&posted| core::ptr::eq(posted, request))
+        {
+            mailbox.requests.copy_within(index + 1..len, index);
+            mailbox.len -= 1;
+        }
+    });
+}
+
+/// Runs everything posted to the executing CPU.
+fn run_pending() {
+    let (requests, len) = cpu_interrupts::without_interrupts(|| {
+        let mut mailbox = MAILBOXES.get().lock();
+        let len = core::mem::take(&mut mailbox.len);
+        (mailbox.requests, len)
+    });
+    for &request in &requests[..len] {
+        unsafe { &*request }.run();
+    }
+}
+
+/// A call posted with [`call_on`]. Completes with the function's result. Dropping it before
+/// then waits for the function to finish, as the target still needs the request.
+pub struct Completion<R> {
+    inner: Box<Pending<R>>,
+}
+
+struct Pending<R> {
+    slot: Slot<Box<dyn FnOnce() -> R + Send>, R>,
+    request: Request,
+}
+
+impl<R> Completion<R> {
+    /// Spins until the call has finished and returns its result.
+    pub fn wait(self) -> R {
+        self.inner.request.wait();
+        self.take()
+    }
+
+    fn take(&self) -> R {
+        self.inner
+            .slot
+            .result
+            .lock()
+            .take()
+            .expect("call completion taken twice")
+    }
+}
+
+impl<R> Future for Completion<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        let request = &self.inner.request;
+        request.waker.register(cx.waker());
+        if !request.done.load(Ordering::Acquire) {
+            return Poll::Pending;
+        }
+        Poll::Ready(self.take())
+    }
+}
+
+impl<R> Drop for Completion<R> {
+    fn drop(&mut self) {
+        self.inner.request.wait();
+    }
+}
+
+/// Runs `f` on CPU `cpu`, or right away if that is the executing CPU.
+pub fn call_on<R: Send + 'static>(
+    cpu: usize,
+    f: impl FnOnce() -> R + Send + 'static,
+) -> Result<Completion<R>, CallError> {
+    let f: Box<dyn FnOnce() -> R + Send> = Box::new(f);
+    let mut inner = Box::new(Pending {
+        slot: Slot::new(f),
+        request: Request::new::<Box<dyn FnOnce() -> R + Send>, R>(core::ptr::null()),
+    });
+    inner.request.data = &inner.slot as *const _ as *const ();
+    if cpu == cpu::current_index() {
+        inner.request.run();
+    } else if let Err(e) = post(cpu, &inner.request) {
+        // Nothing will run it now, so the completion mustn't wait for it when dropped
+        inner.request.released.store(true, Ordering::Release);
+        return Err(e);
+    }
+    Ok(Completion { inner })
+}
+
+/// Runs `f` on every CPU that has started, this one included, and returns a completion for
+/// each, by CPU index.
+pub fn call_all<R: Send + 'static>(
+    f: impl FnOnce() -> R + Send + Clone + 'static,
+) -> Vec<(usize, Result<Completion<R>, CallError>)> {
+    cpu::online()
+        .map(|(index, _)| (index, call_on(index, f.clone())))
+        .collect()
+}
+
+/// Runs `f` on CPU `cpu` and spins until it returns. Doesn't allocate.
+pub fn call_on_wait<R: Send>(cpu: usize, f: impl FnOnce() -> R + Send) -> Result<R, CallError> {
+    if cpu == cpu::current_index() {
+        return Ok(f());
+    }
+    let slot = Slot::new(f);
+    let request = Request::new(&slot);
+    post(cpu, &request)?;
+    request.wait();
+    Ok(slot
+        .result
+        .lock()
+        .take()
+        .expect("finished call has a result"))
+}
+
+/// Runs `f` on every CPU that has started, this one included, and spins until all of them are
+/// done. Doesn't allocate. CPUs that can't be reached are skipped.
+pub fn call_all_wait(f: impl Fn() + Sync) {
+    let this = cpu::current_index();
+    let slots: [Slot<&(dyn Fn() + Sync), ()>; MAX_CPUS] =
+        core::array::from_fn(|_| Slot::new(&f as _));
+    let requests: [Request; MAX_CPUS] = core::array::from_fn(|index| Request::new(&slots[index]));
+    let mut posted = [false; MAX_CPUS];
+    for (index, _) in cpu::online().filter(|&(index, _)| index != this) {
+        posted[index] = post(index, &requests[index]).is_ok();
+    }
+    f();
+    for (request, _) in requests.iter().zip(posted).filter(|&(_, posted)| posted) {
+        request.wait();
+    }
+}
+
+/// Invalidates the TLB entries for `range` on every CPU, after the kernel has changed or
+/// removed mappings in it. Large ranges flush the whole TLB instead.
+pub fn shootdown(range: Range<VirtAddr>) {
+    let start = range.start.align_down(PAGE_SIZE);
+    let pages = (range.end.align_up(PAGE_SIZE) - start) / PAGE_SIZE;
+    call_all_wait(|| {
+        if pages > SHOOTDOWN_MAX_PAGES {
+            tlb::flush_all();
+            return;
+        }
+        for page in 0..pages {
+            tlb::flush(start + page * PAGE_SIZE);
+        }
+    });
+}
+
+pub(crate) extern "x86-interrupt" fn call_interrupt_handler(_frame: InterruptStackFrame) {
+    interrupts::end_of_interrupt(CALL_VEC);
+    run_pending();
+}
+
+#[test_case]
+fn test_call_on_wait_runs_locally() {
+    assert_eq!(call_on_wait(cpu::current_index(), || 6 * 7), Ok(42));
+    assert_eq!(
+        call_on_wait(MAX_CPUS, || ()),
+        Err(CallError::NoSuchCpu(MAX_CPUS))
+    );
+}
@@ -1 +1,7 @@
+pub mod call;
+pub mod park;
 pub mod trampoline;
+pub mod tsc;
+
+pub use call::{call_all, call_on, shootdown};
+pub use park::{park, unpark};
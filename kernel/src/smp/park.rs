@@ -0,0 +1,140 @@
+//! Taking APs out of service and putting them back, e.g. to keep a core quiet during a
+//! benchmark or to stop the others before dumping state after a panic.
+//!
+//! A parked AP waits in the handler of the IPI that parked it with its local APIC timer
+//! masked, so it takes no ticks and runs nothing until another IPI unparks it. The BSP can't
+//! be parked, as device interrupts and timekeeping depend on it.
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::cpu::{self, MAX_CPUS, PerCpu};
+use crate::interrupts::{self, PARK_VEC};
+use crate::timer;
+
+/// How long [`park`] waits for the AP to confirm it has stopped.
+const PARK_TIMEOUT_US: u64 = 10_000;
+
+const RUNNING: u8 = 0;
+const PARKING: u8 = 1;
+const PARKED: u8 = 2;
+
+static STATE: PerCpu<AtomicU8> = PerCpu::new([const { AtomicU8::new(RUNNING) }; MAX_CPUS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParkState {
+    Running,
+    /// Asked to park, but it hasn't taken the IPI yet.
+    Parking,
+    Parked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParkError {
+    /// No CPU with this index has started.
+    NoSuchCpu(usize),
+    /// The BSP always keeps running.
+    Bsp,
+    /// A CPU can't park itself, as nothing would be left to unpark it.
+    CurrentCpu,
+    /// IPIs need the local APIC.
+    NoApic,
+    AlreadyParked(usize),
+    NotParked(usize),
+    /// The AP didn't stop in time. It still parks once it takes the IPI.
+    Timeout(usize),
+}
+
+/// Parks AP `cpu` and waits for it to stop. Without a clock to time out with, returns as soon
+/// as the IPI is sent.
+pub fn park(cpu: usize) -> Result<(), ParkError> {
+    let (apic_id, state) = target(cpu)?;
+    state
+        .compare_exchange(RUNNING, PARKING, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| ParkError::AlreadyParked(cpu))?;
+    if !interrupts::send_ipi(apic_id, PARK_VEC) {
+        state.store(RUNNING, Ordering::Release);
+        return Err(ParkError::NoApic);
+    }
+
+    let Some(start) = timer::uptime_us() else {
+        return Ok(());
+    };
+    while state.load(Ordering::Acquire) != PARKED {
+        if timer::uptime_us().is_some_and(|now| now - start >= PARK_TIMEOUT_US) {
+            return Err(ParkError::Timeout(cpu));
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Lets AP `cpu` run again, or cancels a [`park`] it hasn't acted on yet.
+pub fn unpark(cpu: usize) -> Result<(), ParkError> {
+    let (apic_id, state) = target(cpu)?;
+    if state.swap(RUNNING, Ordering::AcqRel) == RUNNING {
+        return Err(ParkError::NotParked(cpu));
+    }
+    // Wakes it from idle; the handler sees it is no longer parked and returns
+    if !interrupts::send_ipi(apic_id, PARK_VEC) {
+        return Err(ParkError::NoApic);
+    }
+    Ok(())
+}
+
+/// Whether CPU `cpu` is parked, or `None` if there is no such CPU.
+pub fn state(cpu: usize) -> Option<ParkState> {
+    let state = match STATE.get_for(cpu)?.load(Ordering::Acquire) {
+        RUNNING => ParkState::Running,
+        PARKING => ParkState::Parking,
+        _ => ParkState::Parked,
+    };
+    Some(state)
+}
+
+/// The APIC ID and park state of `cpu`, if it is an AP other than the current CPU.
+fn target(cpu: usize) -> Result<(u32, &'static AtomicU8), ParkError> {
+    let (_, apic_id) = cpu::online()
+        .find(|&(index, _)| index == cpu)
+        .ok_or(ParkError::NoSuchCpu(cpu))?;
+    if cpu == 0 {
+        return Err(ParkError::Bsp);
+    }
+    if cpu == cpu::current_index() {
+        return Err(ParkError::CurrentCpu);
+    }
+    let state = STATE.get_for(cpu).ok_or(ParkError::NoSuchCpu(cpu))?;
+    Ok((apic_id, state))
+}
+
+/// Both parks and unparks: the state decides which. A parked CPU idles in here with interrupts
+/// enabled, which lets the unparking IPI in once this one has been acknowledged.
+pub(crate) extern "x86-interrupt" fn park_interrupt_handler(_frame: InterruptStackFrame) {
+    interrupts::end_of_interrupt(PARK_VEC);
+    let state = STATE.get();
+    if state
+        .compare_exchange(PARKING, PARKED, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return;
+    }
+
+    interrupts::set_apic_timer_masked(true);
+    while state.load(Ordering::Acquire) == PARKED {
+        cpu_interrupts::disable();
+        cpu::idle::enable_and_idle();
+    }
+    cpu_interrupts::disable();
+    interrupts::set_apic_timer_masked(false);
+}
+
+#[test_case]
+fn test_park_rejects_bsp_and_unknown_cpus() {
+    // The BSP registers as CPU 0 on first use
+    assert_eq!(cpu::current_index(), 0);
+    assert_eq!(park(0), Err(ParkError::Bsp));
+    assert_eq!(unpark(0), Err(ParkError::Bsp));
+    assert_eq!(park(MAX_CPUS), Err(ParkError::NoSuchCpu(MAX_CPUS)));
+    assert_eq!(state(0), Some(ParkState::Running));
+}
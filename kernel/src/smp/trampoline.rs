@@ -1,32 +1,104 @@
-pub const TRAMPOLINE_BASE: usize = 0x8000; // physical base of the trampoline
+//! The real-mode code APs start in, and the data the BSP hands them through it.
+//!
+//! The trampoline is assembled at offset 0 and copied to whichever page below 1 MiB the frame
+//! allocator reserved for it (see [`crate::memory::low_memory`]); [`load_ap_trampoline`] then adds that page's address to
+//! the absolute addresses the trampoline lists in its relocation table.
 
-// Offsets within the trampoline's data (from its start at 0x8000)
-pub const CR3VAL_OFFSET: usize = 0; // 4 bytes
-pub const KCODE_OFFSET: usize = 8; // 8 bytes (u64)
-pub const KSTACK_OFFSET: usize = 16; // 8 bytes (u64)
-pub const KGSVAL_OFFSET: usize = 24; // 8 bytes (u64)
-pub const COMMWORD_OFFSET: usize = 32; // 4 bytes
+/// The page the trampoline goes to when it is free. Use [`crate::platform`] for the address
+/// actually in use.
+pub const TRAMPOLINE_BASE: usize = 0x8000;
+/// The SIPI vector can only name pages below 1 MiB.
+pub const TRAMPOLINE_LIMIT: usize = 0x10_0000;
+
+// Offsets within the trampoline's data, from its start
+pub const CR3VAL_OFFSET: usize = 8; // 8 bytes (u64)
+pub const KCODE_OFFSET: usize = 16; // 8 bytes (u64)
+pub const KSTACK_OFFSET: usize = 24; // 8 bytes (u64)
+pub const KGSVAL_OFFSET: usize = 32; // 8 bytes (u64)
+pub const COMMWORD_OFFSET: usize = 40; // 4 bytes
+/// Set in the commword by the AP as soon as it runs the trampoline.
+pub const COMM_ALIVE: u32 = 1 << 0;
+/// Set in the commword by the AP once it has loaded `kstack`, so the field can be patched for
+/// the next AP.
+pub const COMM_ON_STACK: u32 = 1 << 1;
+/// Offset of the relocation table: `u16` offsets of `u32` addresses to add the base to.
+const RELOCS_OFFSET: usize = 44; // 2 bytes
+const NRELOCS_OFFSET: usize = 46; // 2 bytes
 
 use core::arch::asm;
 use core::sync::atomic::Ordering;
 
-use crate::init::hpet::HPET_BASE;
-use crate::init::memory_init::get_offset_u64;
+use crate::config;
+use crate::error::KernelError;
 use crate::init::multicore::{AP_STACK_INDEX, AP_STACKS, NUM_AP_STACKS, ap_startup};
+use crate::memory::phys_to_virt;
 use crate::serial_println;
-use crate::timer::get_current_time_us;
 
 pub static AP_TRAMPOLINE_BIN: &[u8] = include_bytes!(env!("AP_TRAMPOLINE_BIN"));
-/// Loads the AP trampoline code into physical memory at TRAMPOLINE_BASE.
-pub unsafe fn load_ap_trampoline() {
-    let trampoline_size = AP_TRAMPOLINE_BIN.len();
-    let dest = (get_offset_u64() as usize + TRAMPOLINE_BASE) as *mut u8;
-    unsafe { core::ptr::copy_nonoverlapping(AP_TRAMPOLINE_BIN.as_ptr(), dest, trampoline_size) };
+
+const _: () = assert!(
+    AP_TRAMPOLINE_BIN.len() <= 4096,
+    "the AP trampoline must fit in a page"
+);
+
+/// The trampoline page, through the physical memory mapping.
+pub(crate) fn trampoline_ptr() -> *mut u8 {
+    phys_to_virt(crate::platform::get().trampoline)
+        .expect("AP trampoline outside the physical memory mapping")
+        .as_mut_ptr()
 }
 
-/// Patches the trampoline's data fields with values from the BSP.
+/// Loads the AP trampoline code, relocated, into physical memory at the platform's trampoline
+/// page, then reads it back to check that the page really holds it.
+///
+/// # Safety
+/// The platform's trampoline page must be reserved for it in the frame allocator, and no AP
+/// may be running from it.
+pub unsafe fn load_ap_trampoline() -> Result<(), KernelError> {
+    let page = crate::platform::get().trampoline;
+    let mut buffer = [0u8; 4096];
+    let image = &mut buffer[..AP_TRAMPOLINE_BIN.len()];
+    image.copy_from_slice(AP_TRAMPOLINE_BIN);
+    relocate(image, page.as_u64() as u32);
+
+    let dest = trampoline_ptr();
+    unsafe { core::ptr::copy_nonoverlapping(image.as_ptr(), dest, image.len()) };
+    let written = (0..image.len()).map(|i| unsafe { core::ptr::read_volatile(dest.add(i)) });
+    if checksum(written) != checksum(image.iter().copied()) {
+        return Err(KernelError::TrampolineCorrupt(page));
+    }
+    Ok(())
+}
+
+/// FNV-1a, enough to notice a page that doesn't hold what was copied to it.
+fn checksum(bytes: impl Iterator<Item = u8>) -> u32 {
+    bytes.fold(0x811C_9DC5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Adds `base` to every address in the relocation table of the trampoline image `image`.
+/// Entries that point outside the image are skipped.
+fn relocate(image: &mut [u8], base: u32) {
+    let read_u16 = |image: &[u8], at: usize| {
+        image
+            .get(at..at + 2)
+            .map_or(0, |bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    };
+    let table = read_u16(image, RELOCS_OFFSET);
+    for entry in 0..read_u16(image, NRELOCS_OFFSET) {
+        let at = read_u16(image, table + entry * 2);
+        if let Some(field) = image.get_mut(at..at + 4) {
+            let address = u32::from_le_bytes(field.try_into().unwrap());
+            field.copy_from_slice(&address.wrapping_add(base).to_le_bytes());
+        }
+    }
+}
+
+/// Patches the trampoline's data fields shared by every AP with values from the BSP. Each AP
+/// then gets its own stack from [`prepare_ap`].
 pub unsafe fn patch_trampoline() {
-    let tramp_ptr = (get_offset_u64() as usize + TRAMPOLINE_BASE) as *mut u8;
+    let tramp_ptr = trampoline_ptr();
     // Patch CR3 (4 bytes)
     let cr3: u64 = unsafe { read_cr3() };
     unsafe {
@@ -38,30 +110,28 @@ pub unsafe fn patch_trampoline() {
         serial_println!("Patching trampoline: ap_startup = {:#x}", ap_entry);
         *(tramp_ptr.add(KCODE_OFFSET) as *mut u64) = ap_entry;
 
-        //Allocate an AP stack and patch the pointer
-        let ap_stack: u64 = allocate_ap_stack();
-        serial_println!("Patching trampoline: AP stack top = {:#x}", ap_stack);
-        *(tramp_ptr.add(KSTACK_OFFSET) as *mut u64) = ap_stack;
-
         //Patch GS value if needed
         *(tramp_ptr.add(KGSVAL_OFFSET) as *mut u64) = 0;
-
-        //clear commword
-        *(tramp_ptr.add(COMMWORD_OFFSET) as *mut u32) = 0;
     }
 }
 
-/// Wait for the AP to signal readiness by polling the commword.
-pub unsafe fn wait_for_ap(timeout_us: u64) -> bool {
-    let tramp_ptr = (get_offset_u64() as usize + TRAMPOLINE_BASE) as *const u8;
-    let comm_ptr = unsafe { tramp_ptr.add(COMMWORD_OFFSET) } as *const u32;
-    let start = unsafe { get_current_time_us(HPET_BASE) };
-    while unsafe { get_current_time_us(HPET_BASE) } - start < timeout_us {
-        if unsafe { core::ptr::read_volatile(comm_ptr) } == 1 {
-            return true;
-        }
+/// Points the trampoline at the stack whose top is `stack_top` and clears the commword, for
+/// the next AP to start.
+///
+/// # Safety
+/// The trampoline must be loaded, and the previous AP must have reported [`COMM_ON_STACK`],
+/// so no AP still reads the old stack or commword.
+pub unsafe fn prepare_ap(stack_top: u64) {
+    let tramp_ptr = trampoline_ptr();
+    unsafe {
+        core::ptr::write_volatile(tramp_ptr.add(KSTACK_OFFSET) as *mut u64, stack_top);
+        core::ptr::write_volatile(tramp_ptr.add(COMMWORD_OFFSET) as *mut u32, 0);
     }
-    false
+}
+
+/// What the AP being started has reported in the commword so far.
+pub fn commword() -> u32 {
+    unsafe { core::ptr::read_volatile(trampoline_ptr().add(COMMWORD_OFFSET) as *const u32) }
 }
 
 #[inline]
@@ -78,15 +148,34 @@ pub unsafe fn read_cr3() -> u64 {
 }
 
 /// Allocates an AP stack and returns its top address (as a u64).
-/// Each stack is a fixed-size block (32KB), and the top-of-stack is at the end of the array.
-/// Panics if no more stacks are available.
-pub unsafe fn allocate_ap_stack() -> u64 {
+/// Each stack is a fixed-size block, and the top-of-stack is at the end of the array.
+/// Returns `None` if no more stacks are available.
+///
+/// # Safety
+/// Nothing may use the AP stacks except through this function, as each is painted over when
+/// it is handed out, and the stack returned must go to one AP only.
+pub unsafe fn allocate_ap_stack() -> Option<u64> {
     let index = AP_STACK_INDEX.fetch_add(1, Ordering::Relaxed);
     if index >= NUM_AP_STACKS {
-        panic!("Out of AP stacks!");
+        return None;
     }
     let stack = unsafe { &AP_STACKS[index] };
     let stack_ptr = stack.as_ptr() as usize;
-    let stack_size = core::mem::size_of::<[u8; 32768]>();
-    (stack_ptr + stack_size) as u64
+    let stack_size = config::AP_STACK_SIZE;
+    unsafe { crate::stack::paint(stack_ptr as u64..(stack_ptr + stack_size) as u64) };
+    Some((stack_ptr + stack_size) as u64)
+}
+
+#[test_case]
+fn test_relocate() {
+    let mut image = [0u8; 64];
+    image[RELOCS_OFFSET..RELOCS_OFFSET + 2].copy_from_slice(&48u16.to_le_bytes());
+    image[NRELOCS_OFFSET..NRELOCS_OFFSET + 2].copy_from_slice(&2u16.to_le_bytes());
+    image[48..50].copy_from_slice(&56u16.to_le_bytes());
+    // Past the end of the image, so left alone
+    image[50..52].copy_from_slice(&62u16.to_le_bytes());
+    image[56..60].copy_from_slice(&0x10u32.to_le_bytes());
+    relocate(&mut image, 0x8000);
+    assert_eq!(image[56..60], 0x8010u32.to_le_bytes());
+    assert_eq!(image[60..64], [0; 4]);
 }
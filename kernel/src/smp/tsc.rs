@@ -0,0 +1,61 @@
+//! How far each AP's time-stamp counter is from the BSP's.
+//!
+//! Timekeeping reads the TSC on whichever CPU it runs on (see [`crate::pvclock`]), which is
+//! only right if the counters agree. [`measure`] reads each AP's counter with a cross-CPU call
+//! after SMP bring-up and records the difference, so skew shows up in the log and `cpus`.
+use core::sync::atomic::{AtomicI64, Ordering};
+
+use crate::cpu::{self, MAX_CPUS, PerCpu, rdtsc};
+use crate::smp::call::{CallError, call_on_wait};
+use crate::{info, warn};
+
+/// Offsets beyond this many cycles, either way, are reported as skew. A cross-CPU call takes a
+/// few thousand cycles, which bounds how precisely the offset can be measured.
+const SKEW_WARN_CYCLES: u64 = 100_000;
+
+/// Each CPU's TSC minus the BSP's, as last measured.
+static OFFSETS: PerCpu<AtomicI64> = PerCpu::new([const { AtomicI64::new(0) }; MAX_CPUS]);
+
+/// Reads CPU `cpu`'s TSC and returns how far ahead of this CPU's it is. The remote read is
+/// compared with the midpoint of the local reads taken around the call.
+pub fn measure_one(cpu: usize) -> Result<i64, CallError> {
+    let before = rdtsc();
+    let remote = call_on_wait(cpu, rdtsc)?;
+    let after = rdtsc();
+    let local = before + (after - before) / 2;
+    Ok(remote.wrapping_sub(local) as i64)
+}
+
+/// Measures and records the offset of every AP that has started. Call on the BSP.
+pub fn measure() {
+    let mut worst = 0i64;
+    for (index, apic_id) in cpu::online().filter(|&(index, _)| index != 0) {
+        let offset = match measure_one(index) {
+            Ok(offset) => offset,
+            Err(e) => {
+                warn!("cpu{}: TSC not read: {:?}", index, e);
+                continue;
+            }
+        };
+        if let Some(slot) = OFFSETS.get_for(index) {
+            slot.store(offset, Ordering::Relaxed);
+        }
+        if offset.unsigned_abs() > SKEW_WARN_CYCLES {
+            warn!(
+                "cpu{} (apic {}): TSC is {} cycles off the BSP's",
+                index, apic_id, offset
+            );
+        }
+        if offset.unsigned_abs() > worst.unsigned_abs() {
+            worst = offset;
+        }
+    }
+    info!("TSC offsets measured, largest {} cycles", worst);
+}
+
+/// CPU `index`'s TSC offset from the BSP's as of the last [`measure`].
+pub fn offset(index: usize) -> Option<i64> {
+    OFFSETS
+        .get_for(index)
+        .map(|offset| offset.load(Ordering::Relaxed))
+}
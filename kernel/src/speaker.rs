@@ -0,0 +1,101 @@
+//! The PC speaker, driven by PIT channel 2. Useful as a last-resort signal on real hardware
+//! without a visible display or serial console.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::error::KernelError;
+use crate::power::suspend::{self, SuspendHook};
+use crate::{kernel_init, timer};
+
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// System control port B: bit 0 gates channel 2, bit 1 connects it to the speaker.
+const PORT_B: u16 = 0x61;
+const SPEAKER_BITS: u8 = 0b11;
+
+/// Channel 2, lobyte/hibyte access, mode 3 (square wave), binary.
+const CHANNEL2_SQUARE_WAVE: u8 = 0b1011_0110;
+
+/// Whether panics and failed tests beep. On by default with the `panic-beep` feature.
+static ALERTS: AtomicBool = AtomicBool::new(cfg!(feature = "panic-beep"));
+
+/// Starts a tone of `freq_hz` that plays until [`stop`].
+pub fn start(freq_hz: u32) {
+    let divisor = (PIT_FREQUENCY_HZ / freq_hz.max(19)).min(u16::MAX as u32) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(CHANNEL2_SQUARE_WAVE);
+        let mut data = Port::<u8>::new(PIT_CHANNEL2);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value | SPEAKER_BITS);
+    }
+}
+
+pub fn stop() {
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let value = port_b.read();
+        port_b.write(value & !SPEAKER_BITS);
+    }
+}
+
+kernel_init!(Early, "speaker", init_stage);
+
+/// A tone left playing would keep sounding through a suspend.
+fn init_stage() -> Result<(), KernelError> {
+    suspend::register(SuspendHook {
+        name: "speaker",
+        suspend: || {
+            stop();
+            Ok(())
+        },
+        resume: || {},
+    });
+    Ok(())
+}
+
+/// Plays `freq_hz` for `duration_ms`, busy-waiting so it is safe with interrupts disabled, e.g.
+/// while panicking.
+pub fn beep(freq_hz: u32, duration_ms: u64) {
+    start(freq_hz);
+    wait_us(duration_ms * 1000);
+    stop();
+}
+
+pub fn set_alerts(enabled: bool) {
+    ALERTS.store(enabled, Ordering::Relaxed);
+}
+
+pub fn alerts_enabled() -> bool {
+    ALERTS.load(Ordering::Relaxed)
+}
+
+/// Three descending beeps, if alerts are on. Called on panic and on test failure.
+pub fn alert() {
+    if !alerts_enabled() {
+        return;
+    }
+    for freq in [880, 660, 440] {
+        beep(freq, 150);
+        wait_us(50_000);
+    }
+}
+
+/// Spins on the HPET if it is up, or on writes to the POST port (about a microsecond each)
+/// this early in boot.
+fn wait_us(us: u64) {
+    if let Some(start) = timer::uptime_us() {
+        while timer::uptime_us().is_some_and(|now| now - start < us) {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    let mut post: Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        unsafe { post.write(0) };
+    }
+}
@@ -0,0 +1,196 @@
+//! How much of each kernel stack is used.
+//!
+//! Kernel stacks are painted with [`PAINT`] when they are created and [`register`]ed with the
+//! CPU they belong to. Whatever is left of the pattern shows the deepest the stack has ever
+//! been, its high-water mark, at no cost while it runs. The scheduler calls [`check`] on every
+//! context switch, which warns once about each stack that has been more than
+//! `STACK_WARN_PERCENT` full; the threshold is set in `kernel.config`.
+//!
+//! Async tasks run on the stack of the CPU polling them and processes run their kernel side on
+//! the CPU's entry stack, so usage is tracked per CPU rather than per task.
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+
+use crate::cpu::{self, MAX_CPUS};
+use crate::memory::PAGE_SIZE;
+use crate::process::address_space;
+use crate::{config, warn};
+
+/// What an unused stack word holds.
+pub const PAINT: u64 = 0x57AC_D00D_57AC_D00D;
+/// The boot, entry and double fault stacks of every CPU.
+const MAX_STACKS: usize = 3 * MAX_CPUS;
+/// Bytes below the stack pointer [`adopt_current`] leaves alone, for its own frame.
+const LIVE_MARGIN: u64 = 1024;
+
+#[derive(Debug, Clone)]
+struct Stack {
+    name: &'static str,
+    cpu: usize,
+    range: Range<u64>,
+    /// Whether [`check`] has warned about it already.
+    warned: bool,
+}
+
+static STACKS: Mutex<[Option<Stack>; MAX_STACKS]> = Mutex::new([const { None }; MAX_STACKS]);
+
+/// The executing CPU's stack pointer.
+pub fn current_rsp() -> u64 {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    rsp
+}
+
+/// The stack's words, lowest first.
+fn words(range: &Range<u64>) -> impl Iterator<Item = *mut u64> {
+    (range.start.next_multiple_of(8)..range.end.saturating_sub(7))
+        .step_by(8)
+        .map(|addr| addr as *mut u64)
+}
+
+/// Fills `range` with [`PAINT`].
+///
+/// # Safety
+///
+/// `range` must be valid for writes, and nothing may be running on it.
+pub unsafe fn paint(range: Range<u64>) {
+    for word in words(&range) {
+        unsafe { word.write_volatile(PAINT) };
+    }
+}
+
+/// Bytes of `range` that have been used, counting down from the top to the lowest word that
+/// no longer holds [`PAINT`].
+fn high_water_of(range: &Range<u64>) -> usize {
+    let lowest_used = words(range)
+        .find(|&word| unsafe { word.read_volatile() } != PAINT)
+        .map_or(range.end, |word| word as u64);
+    (range.end - lowest_used) as usize
+}
+
+/// Whether more than `percent` of `range` has been used, from the one word at that depth.
+fn used_past(range: &Range<u64>, percent: usize) -> bool {
+    let size = range.end - range.start;
+    let depth = size * percent.min(100) as u64 / 100;
+    let word = (range.end - depth).saturating_sub(8) & !7;
+    word >= range.start && unsafe { (word as *const u64).read_volatile() } != PAINT
+}
+
+/// Records `range` as `cpu`'s `name` stack. It should have been [`paint`]ed before first use.
+pub fn register(name: &'static str, cpu: usize, range: Range<u64>) {
+    let stack = Stack {
+        name,
+        cpu,
+        range,
+        warned: false,
+    };
+    let added = interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks.iter_mut().find(|slot| slot.is_none())?;
+        *slot = Some(stack);
+        Some(())
+    });
+    if added.is_none() {
+        warn!("stack: no room to track cpu{}'s {} stack", cpu, name);
+    }
+}
+
+/// Paints what is unused of the stack the executing CPU runs on and registers it as the CPU's
+/// boot stack. For stacks the kernel didn't create, such as the bootloader's: the stack is
+/// taken to end at the page boundary above the stack pointer and to reach down at most `size`
+/// bytes, stopping short of the guard page below it. Needs the physical memory mapping, to
+/// find the guard page.
+#[inline(never)]
+pub fn adopt_current(size: u64) {
+    let rsp = current_rsp();
+    let top = rsp.next_multiple_of(PAGE_SIZE);
+    let mut bottom = rsp & !(PAGE_SIZE - 1);
+    while top - bottom < size
+        && address_space::translate_active(VirtAddr::new(bottom - PAGE_SIZE)).is_some()
+    {
+        bottom -= PAGE_SIZE;
+    }
+    let range = bottom..top;
+    unsafe { paint(range.start..rsp - LIVE_MARGIN) };
+    register("boot", cpu::current_index(), range);
+}
+
+/// A stack's usage as [`high_water`] reports it.
+#[derive(Debug, Clone, Copy)]
+pub struct StackUsage {
+    pub name: &'static str,
+    pub cpu: usize,
+    pub size: usize,
+    /// The most bytes it has ever had in use.
+    pub high_water: usize,
+}
+
+/// The high-water marks of `cpu`'s stacks.
+pub fn high_water(cpu: usize) -> Vec<StackUsage> {
+    let stacks: Vec<Stack> = interrupts::without_interrupts(|| {
+        STACKS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|stack| stack.cpu == cpu)
+            .cloned()
+            .collect()
+    });
+    stacks
+        .iter()
+        .map(|stack| StackUsage {
+            name: stack.name,
+            cpu: stack.cpu,
+            size: (stack.range.end - stack.range.start) as usize,
+            high_water: high_water_of(&stack.range),
+        })
+        .collect()
+}
+
+/// Warns once about each of this CPU's stacks that has been more than `STACK_WARN_PERCENT`
+/// full. Called on every context switch, so it looks at a single word per stack.
+pub fn check() {
+    let cpu = cpu::current_index();
+    let Some(mut stacks) = STACKS.try_lock() else {
+        return;
+    };
+    for stack in stacks
+        .iter_mut()
+        .flatten()
+        .filter(|stack| stack.cpu == cpu && !stack.warned)
+    {
+        if used_past(&stack.range, config::STACK_WARN_PERCENT) {
+            stack.warned = true;
+            warn!(
+                "stack: cpu{}'s {} stack has been {} of {} bytes deep",
+                cpu,
+                stack.name,
+                high_water_of(&stack.range),
+                stack.range.end - stack.range.start
+            );
+        }
+    }
+}
+
+#[test_case]
+fn test_high_water() {
+    let mut buf = alloc::vec![0u64; 64];
+    let start = buf.as_mut_ptr() as u64;
+    let range = start..start + 64 * 8;
+    unsafe { paint(range.clone()) };
+    assert_eq!(high_water_of(&range), 0);
+    assert!(!used_past(&range, 50));
+
+    // The top quarter used
+    for word in &mut buf[48..] {
+        *word = 0;
+    }
+    assert_eq!(high_water_of(&range), 16 * 8);
+    assert!(used_past(&range, 20));
+    assert!(!used_past(&range, 30));
+}
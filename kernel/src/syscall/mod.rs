@@ -0,0 +1,234 @@
+//! SYSCALL/SYSRET system call entry.
+//!
+//! User code puts the system call number in RAX and up to six arguments in RDI, RSI, RDX, R10,
+//! R8 and R9 (the Linux x86_64 convention, with R10 standing in for RCX because SYSCALL uses RCX
+//! for the return address). The result comes back in RAX: a non-negative value on success, or a
+//! negated [`Errno`] on failure.
+use core::arch::naked_asm;
+
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+
+use crate::cpu::{self, MAX_CPUS};
+use crate::fs::FsError;
+use crate::gdt;
+use crate::process::fd::FdError;
+use crate::process::scheduler;
+use crate::process::signal::SignalError;
+use crate::trap::{TrapFrame, pop_gprs, push_gprs};
+
+pub mod table;
+pub mod uaccess;
+
+/// System call numbers. These are the kernel's user ABI: existing numbers must never change.
+pub mod nr {
+    pub const WRITE: u64 = 0;
+    pub const EXIT: u64 = 1;
+    pub const SLEEP: u64 = 2;
+    pub const GETPID: u64 = 3;
+    pub const FORK: u64 = 4;
+    pub const EXEC: u64 = 5;
+    pub const OPEN: u64 = 6;
+    pub const READ: u64 = 7;
+    pub const CLOSE: u64 = 8;
+    pub const LSEEK: u64 = 9;
+    pub const PIPE: u64 = 10;
+    pub const WAITPID: u64 = 11;
+    pub const DUP2: u64 = 12;
+    pub const KILL: u64 = 13;
+    pub const ALARM: u64 = 14;
+    pub const CLOCK_GETTIME: u64 = 15;
+    pub const CLOCK_GETRES: u64 = 16;
+    pub const FUTEX_WAIT: u64 = 17;
+    pub const FUTEX_WAKE: u64 = 18;
+    pub const GETRUSAGE: u64 = 19;
+    pub const IOCTL: u64 = 20;
+    pub const MMAP: u64 = 21;
+    pub const MUNMAP: u64 = 22;
+
+    /// One past the highest assigned number.
+    pub const COUNT: usize = 23;
+}
+
+/// Error numbers returned (negated) in RAX. Values match Linux so existing tooling decodes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENODEV = 19,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ENOSYS = 38,
+}
+
+impl From<FsError> for Errno {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::InvalidPath => Errno::EINVAL,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::NotSupported => Errno::ESPIPE,
+            FsError::WouldBlock(_) => Errno::EAGAIN,
+            FsError::BrokenPipe => Errno::EPIPE,
+            FsError::InvalidArgument => Errno::EINVAL,
+            FsError::FileTooBig => Errno::EFBIG,
+        }
+    }
+}
+
+impl From<SignalError> for Errno {
+    fn from(e: SignalError) -> Self {
+        match e {
+            SignalError::InvalidSignal => Errno::EINVAL,
+            SignalError::NoSuchProcess => Errno::ESRCH,
+        }
+    }
+}
+
+impl From<FdError> for Errno {
+    fn from(e: FdError) -> Self {
+        match e {
+            FdError::BadFd => Errno::EBADF,
+            FdError::TooManyOpen => Errno::EMFILE,
+            FdError::InvalidArgument => Errno::EINVAL,
+            FdError::Fs(e) => e.into(),
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+/// Encodes a handler result into the value returned in RAX.
+pub fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// Per-CPU data reached through GS after `swapgs` in the entry stub. The field offsets are
+/// hard-coded in the stub.
+#[repr(C)]
+struct CpuLocal {
+    /// Stack to switch to on entry; kept equal to the TSS RSP0.
+    kernel_rsp: u64,
+    /// Scratch slot for the user stack pointer while the frame is built.
+    user_rsp: u64,
+    user_cs: u64,
+    user_ss: u64,
+}
+
+static mut CPU_LOCAL: [CpuLocal; MAX_CPUS] = [const {
+    CpuLocal {
+        kernel_rsp: 0,
+        user_rsp: 0,
+        user_cs: 0,
+        user_ss: 0,
+    }
+}; MAX_CPUS];
+
+fn cpu_local() -> *mut CpuLocal {
+    unsafe { &raw mut CPU_LOCAL[cpu::current_index()] }
+}
+
+/// Enables SYSCALL/SYSRET on the executing CPU. The GDT must already be loaded.
+pub fn init() {
+    let selectors = gdt::selectors();
+    let local = cpu_local();
+    unsafe {
+        (*local).kernel_rsp = gdt::kernel_stack().as_u64();
+        (*local).user_cs = selectors.user_code_selector.0 as u64;
+        (*local).user_ss = selectors.user_data_selector.0 as u64;
+        KernelGsBase::write(VirtAddr::from_ptr(local));
+
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.code_selector,
+            selectors.data_selector,
+        )
+        .expect("GDT layout is incompatible with SYSRET");
+        LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+        // Enter the kernel with interrupts off until the stack has been switched
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Sets the stack system calls run on for the executing CPU. Called alongside
+/// [`gdt::set_kernel_stack`], which does the same for interrupts.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*cpu_local()).kernel_rsp = top.as_u64() };
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "swapgs",
+        "mov gs:[8], rsp",
+        "mov rsp, gs:[0]",
+        // Interrupt-style return frame: ss, rsp, rflags, cs, rip
+        "push qword ptr gs:[24]",
+        "push qword ptr gs:[8]",
+        "push r11",
+        "push qword ptr gs:[16]",
+        "push rcx",
+        push_gprs!(),
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "cli",
+        // Flags from this test survive the pops below
+        "test rax, rax",
+        pop_gprs!(),
+        "jnz 2f",
+        "mov rcx, [rsp]",
+        "mov r11, [rsp + 16]",
+        "mov rsp, [rsp + 24]",
+        "swapgs",
+        "sysretq",
+        // The frame now holds a different context, possibly one that was preempted with live
+        // values in RCX and R11, so it has to be restored in full
+        "2:",
+        "swapgs",
+        "iretq",
+        dispatch = sym syscall_dispatch,
+    );
+}
+
+/// Returns non-zero if the handler switched the frame to another context, which has to be
+/// returned to with `iretq`.
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) -> u64 {
+    let result = table::dispatch(frame.rax, frame);
+    if scheduler::take_switched() {
+        return 1;
+    }
+    frame.rax = encode(result);
+    // Signals that arrived meanwhile, or that the caller sent itself, act before it resumes
+    scheduler::deliver_signals(frame, scheduler::Entry::Syscall);
+    scheduler::take_switched() as u64
+}
+
+/// The argument registers of a system call, in order.
+pub fn args(frame: &TrapFrame) -> [u64; 6] {
+    [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ]
+}
@@ -0,0 +1,401 @@
+//! The system call table, indexed by the numbers in [`super::nr`], and its handlers.
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+use super::uaccess::{check_range, copy_from_user, copy_to_user};
+use super::{Errno, SyscallResult, args, nr};
+use crate::cpu::pat;
+use crate::fs::FsError;
+use crate::memory::PAGE_SIZE;
+use crate::process::fd::{self, FdError, OpenFile};
+use crate::process::futex::{self, WaitOutcome};
+use crate::process::scheduler::{self, ChildStatus};
+use crate::process::{self, Pid};
+use crate::time::{self, Clock};
+use crate::trap::TrapFrame;
+use crate::{fs, timer};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub type Handler = fn(&mut TrapFrame) -> SyscallResult;
+
+static TABLE: [Option<Handler>; nr::COUNT] = {
+    let mut table: [Option<Handler>; nr::COUNT] = [None; nr::COUNT];
+    table[nr::WRITE as usize] = Some(sys_write);
+    table[nr::EXIT as usize] = Some(sys_exit);
+    table[nr::SLEEP as usize] = Some(sys_sleep);
+    table[nr::GETPID as usize] = Some(sys_getpid);
+    table[nr::FORK as usize] = Some(sys_fork);
+    table[nr::EXEC as usize] = Some(sys_exec);
+    table[nr::OPEN as usize] = Some(sys_open);
+    table[nr::READ as usize] = Some(sys_read);
+    table[nr::CLOSE as usize] = Some(sys_close);
+    table[nr::LSEEK as usize] = Some(sys_lseek);
+    table[nr::PIPE as usize] = Some(sys_pipe);
+    table[nr::WAITPID as usize] = Some(sys_waitpid);
+    table[nr::DUP2 as usize] = Some(sys_dup2);
+    table[nr::KILL as usize] = Some(sys_kill);
+    table[nr::ALARM as usize] = Some(sys_alarm);
+    table[nr::CLOCK_GETTIME as usize] = Some(sys_clock_gettime);
+    table[nr::CLOCK_GETRES as usize] = Some(sys_clock_getres);
+    table[nr::FUTEX_WAIT as usize] = Some(sys_futex_wait);
+    table[nr::FUTEX_WAKE as usize] = Some(sys_futex_wake);
+    table[nr::GETRUSAGE as usize] = Some(sys_getrusage);
+    table[nr::IOCTL as usize] = Some(sys_ioctl);
+    table[nr::MMAP as usize] = Some(sys_mmap);
+    table[nr::MUNMAP as usize] = Some(sys_munmap);
+    table
+};
+
+/// Largest number of bytes a single `read` or `write` call transfers; callers loop on short
+/// transfers.
+const MAX_TRANSFER: usize = 4096;
+const MAX_PATH: usize = 256;
+/// Largest answer an `ioctl` request stores.
+const MAX_IOCTL: usize = 64;
+
+/// `prot` bits for [`sys_mmap`].
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+
+/// Runs the handler for system call `number`.
+pub fn dispatch(number: u64, frame: &mut TrapFrame) -> SyscallResult {
+    match TABLE.get(number as usize).copied().flatten() {
+        Some(handler) => handler(frame),
+        None => Err(Errno::ENOSYS),
+    }
+}
+
+/// Copies a path argument in from user memory.
+fn user_path(ptr: u64, len: u64) -> Result<String, Errno> {
+    if len as usize > MAX_PATH {
+        return Err(Errno::EINVAL);
+    }
+    let mut buf = alloc::vec![0u8; len as usize];
+    copy_from_user(&mut buf, ptr)?;
+    String::from_utf8(buf).map_err(|_| Errno::EINVAL)
+}
+
+/// Looks up descriptor `fd` in the current process.
+fn current_file(fd: u64) -> Result<Arc<OpenFile>, Errno> {
+    scheduler::with_current(|process| process.files.get(fd as usize))
+        .ok_or(Errno::ESRCH)?
+        .map_err(Errno::from)
+}
+
+/// Blocks the caller if `result` says the operation would block, so the system call is
+/// restarted once it can make progress.
+fn block_on(frame: &mut TrapFrame, result: Result<usize, fd::FdError>) -> SyscallResult {
+    match result {
+        Ok(len) => Ok(len as u64),
+        Err(fd::FdError::Fs(FsError::WouldBlock(channel))) => {
+            scheduler::block_current(frame, channel);
+            Ok(0)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `write(fd, buf, len)`: waits while a pipe is full.
+fn sys_write(frame: &mut TrapFrame) -> SyscallResult {
+    let [fd, buf, len, ..] = args(frame);
+    let file = current_file(fd)?;
+    let mut bytes = alloc::vec![0u8; (len as usize).min(MAX_TRANSFER)];
+    copy_from_user(&mut bytes, buf)?;
+    let result = file.write(&bytes);
+    block_on(frame, result)
+}
+
+/// `read(fd, buf, len)`: returns 0 at end of file, and waits while a pipe is empty.
+fn sys_read(frame: &mut TrapFrame) -> SyscallResult {
+    let [fd, buf, len, ..] = args(frame);
+    let file = current_file(fd)?;
+    let mut bytes: Vec<u8> = alloc::vec![0u8; (len as usize).min(MAX_TRANSFER)];
+    let read = match file.read(&mut bytes) {
+        Ok(read) => read,
+        result => return block_on(frame, result),
+    };
+    copy_to_user(buf, &bytes[..read])?;
+    Ok(read as u64)
+}
+
+/// `open(path, path_len, flags)`: returns the new descriptor.
+fn sys_open(frame: &mut TrapFrame) -> SyscallResult {
+    let [path, len, flags, ..] = args(frame);
+    let path = user_path(path, len)?;
+    let file = Arc::new(fd::open(&path, flags)?);
+    let fd =
+        scheduler::with_current(|process| process.files.insert(file)).ok_or(Errno::ESRCH)??;
+    Ok(fd as u64)
+}
+
+/// `close(fd)`
+fn sys_close(frame: &mut TrapFrame) -> SyscallResult {
+    let fd = frame.rdi as usize;
+    let file = scheduler::with_current(|process| process.files.close(fd)).ok_or(Errno::ESRCH)??;
+    // Outside the scheduler lock, since closing a pipe end wakes the other side
+    drop(file);
+    Ok(0)
+}
+
+/// `dup2(old, new)`: makes `new` a copy of `old`, closing it first if it was open. Returns
+/// `new`.
+fn sys_dup2(frame: &mut TrapFrame) -> SyscallResult {
+    let [old, new, ..] = args(frame);
+    let replaced =
+        scheduler::with_current(|process| process.files.dup2(old as usize, new as usize))
+            .ok_or(Errno::ESRCH)??;
+    drop(replaced);
+    Ok(new)
+}
+
+/// `pipe(fds)`: stores the read and write descriptors as two `i32`s at `fds`.
+fn sys_pipe(frame: &mut TrapFrame) -> SyscallResult {
+    let fds_ptr = frame.rdi;
+    check_range(fds_ptr, 8, true)?;
+    let (reader, writer) = fs::pipe::pipe();
+    let read_end = Arc::new(OpenFile::new(reader, fd::O_RDONLY)?);
+    let write_end = Arc::new(OpenFile::new(writer, fd::O_WRONLY)?);
+    // The table only gets clones, so nothing is dropped under the scheduler lock on failure
+    let [read_fd, write_fd] = scheduler::with_current(|process| {
+        let read_fd = process.files.insert(read_end.clone())?;
+        match process.files.insert(write_end.clone()) {
+            Ok(write_fd) => Ok([read_fd, write_fd]),
+            Err(e) => {
+                let _ = process.files.close(read_fd);
+                Err(e)
+            }
+        }
+    })
+    .ok_or(Errno::ESRCH)??;
+    let mut fds = [0u8; 8];
+    fds[..4].copy_from_slice(&(read_fd as i32).to_le_bytes());
+    fds[4..].copy_from_slice(&(write_fd as i32).to_le_bytes());
+    copy_to_user(fds_ptr, &fds)?;
+    Ok(0)
+}
+
+/// `waitpid(pid, status)`: waits for child `pid`, or any child if `pid` is -1, to exit and
+/// reaps it. Stores the exit code as an `i32` at `status` unless that is null, and returns the
+/// child's PID.
+fn sys_waitpid(frame: &mut TrapFrame) -> SyscallResult {
+    let [pid, status, ..] = args(frame);
+    let target = match pid as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid(pid as u64)),
+        _ => return Err(Errno::EINVAL),
+    };
+    if status != 0 {
+        check_range(status, 4, true)?;
+    }
+    let parent = process::current_pid().ok_or(Errno::ESRCH)?;
+    match scheduler::reap_child(parent, target) {
+        ChildStatus::Exited(pid, exit) => {
+            if status != 0 {
+                copy_to_user(status, &exit.code.to_le_bytes())?;
+            }
+            Ok(pid.0)
+        }
+        ChildStatus::Running => {
+            scheduler::block_current(frame, scheduler::child_channel(parent));
+            Ok(0)
+        }
+        ChildStatus::NoChildren => Err(Errno::ECHILD),
+    }
+}
+
+/// `lseek(fd, offset, whence)`: returns the new offset.
+fn sys_lseek(frame: &mut TrapFrame) -> SyscallResult {
+    let [fd, offset, whence, ..] = args(frame);
+    Ok(current_file(fd)?.seek(offset as i64, whence)?)
+}
+
+/// `exit(code)`: does not return to the caller.
+fn sys_exit(frame: &mut TrapFrame) -> SyscallResult {
+    scheduler::exit_current(frame, frame.rdi as i32);
+    Ok(0)
+}
+
+/// `sleep(ms)`
+fn sys_sleep(frame: &mut TrapFrame) -> SyscallResult {
+    let us = frame.rdi.checked_mul(1000).ok_or(Errno::EINVAL)?;
+    let now = timer::uptime_us().ok_or(Errno::ENOSYS)?;
+    frame.rax = 0;
+    scheduler::sleep_current(frame, now.saturating_add(us));
+    Ok(0)
+}
+
+/// `getpid()`
+fn sys_getpid(_frame: &mut TrapFrame) -> SyscallResult {
+    process::current_pid().map(|pid| pid.0).ok_or(Errno::ESRCH)
+}
+
+/// `fork()`: returns the child's PID in the parent and 0 in the child.
+fn sys_fork(frame: &mut TrapFrame) -> SyscallResult {
+    let pid = process::fork(frame).map_err(|_| Errno::ENOMEM)?;
+    Ok(pid.0)
+}
+
+/// `exec(path, path_len)`: only returns on failure.
+fn sys_exec(frame: &mut TrapFrame) -> SyscallResult {
+    let [path, len, ..] = args(frame);
+    let path = user_path(path, len)?;
+    let image = fs::read_all(&path)?;
+    let name = path.rsplit('/').next().unwrap_or(&path);
+    process::exec(frame, name, &image).map_err(|e| match e {
+        process::SpawnError::Elf(_) => Errno::ENOEXEC,
+        process::SpawnError::Map(_) => Errno::ENOMEM,
+    })?;
+    Ok(0)
+}
+
+/// `kill(pid, signal)`
+fn sys_kill(frame: &mut TrapFrame) -> SyscallResult {
+    let [pid, signal, ..] = args(frame);
+    scheduler::send_signal(Pid(pid), signal as u32)?;
+    Ok(0)
+}
+
+/// `alarm(ms)`: sends `SIGALRM` to the caller after `ms` milliseconds, replacing any earlier
+/// alarm; 0 cancels it. Returns the milliseconds that were left on the earlier alarm.
+fn sys_alarm(frame: &mut TrapFrame) -> SyscallResult {
+    let us = frame.rdi.checked_mul(1000).ok_or(Errno::EINVAL)?;
+    let now = timer::uptime_us().ok_or(Errno::ENOSYS)?;
+    let at = (us != 0).then(|| now.saturating_add(us));
+    let previous = scheduler::set_alarm(at);
+    Ok(previous.map_or(0, |at| at.saturating_sub(now).div_ceil(1000)))
+}
+
+/// Looks up the clock named by a `clock_gettime`-style clock ID argument.
+fn user_clock(id: u64) -> Result<Clock, Errno> {
+    Clock::from_id(id).ok_or(Errno::EINVAL)
+}
+
+/// `clock_gettime(clock, ts)`: stores the time on `clock` as a `struct timespec` at `ts`.
+fn sys_clock_gettime(frame: &mut TrapFrame) -> SyscallResult {
+    let [clock, ts, ..] = args(frame);
+    let clock = user_clock(clock)?;
+    let now = time::now(clock).ok_or(Errno::ENOSYS)?;
+    copy_to_user(ts, &now.to_bytes())?;
+    Ok(0)
+}
+
+/// `clock_getres(clock, ts)`: stores the resolution of `clock` at `ts` unless that is null.
+fn sys_clock_getres(frame: &mut TrapFrame) -> SyscallResult {
+    let [clock, ts, ..] = args(frame);
+    let clock = user_clock(clock)?;
+    let resolution = time::resolution(clock).ok_or(Errno::ENOSYS)?;
+    if ts != 0 {
+        copy_to_user(ts, &resolution.to_bytes())?;
+    }
+    Ok(0)
+}
+
+/// Looks up the futex for the 32-bit word at `addr`, which must be aligned.
+fn futex_key(addr: u64) -> Result<PhysAddr, Errno> {
+    if !addr.is_multiple_of(4) {
+        return Err(Errno::EINVAL);
+    }
+    // Checked for writing, so a copy-on-write page is copied now rather than after the key
+    // is taken
+    check_range(addr, 4, true)?;
+    futex::key(VirtAddr::new(addr)).ok_or(Errno::EFAULT)
+}
+
+/// `futex_wait(addr, expected)`: waits for a `futex_wake` on the 32-bit word at `addr` if it
+/// holds `expected`, and fails with `EAGAIN` if it doesn't.
+fn sys_futex_wait(frame: &mut TrapFrame) -> SyscallResult {
+    let [addr, expected, ..] = args(frame);
+    let key = futex_key(addr)?;
+    let still_expected = || {
+        let mut word = [0u8; 4];
+        copy_from_user(&mut word, addr).is_ok() && u32::from_le_bytes(word) == expected as u32
+    };
+    match futex::wait(frame, key, still_expected) {
+        WaitOutcome::Blocked | WaitOutcome::Woken => Ok(0),
+        WaitOutcome::ValueChanged => Err(Errno::EAGAIN),
+    }
+}
+
+/// `futex_wake(addr, count)`: wakes up to `count` processes waiting on the word at `addr`.
+/// Returns how many it woke.
+fn sys_futex_wake(frame: &mut TrapFrame) -> SyscallResult {
+    let [addr, count, ..] = args(frame);
+    let key = futex_key(addr)?;
+    Ok(futex::wake(key, count as usize) as u64)
+}
+
+/// `who` for [`sys_getrusage`]: the calling process. Usage of children isn't kept.
+const RUSAGE_SELF: u64 = 0;
+
+/// `getrusage(who, usage)`: stores the calling process's [`process::Rusage`] at `usage`.
+fn sys_getrusage(frame: &mut TrapFrame) -> SyscallResult {
+    let [who, usage, ..] = args(frame);
+    if who != RUSAGE_SELF {
+        return Err(Errno::EINVAL);
+    }
+    let rusage = scheduler::with_current(|process| process.usage()).ok_or(Errno::ESRCH)?;
+    copy_to_user(usage, &rusage.to_bytes())?;
+    Ok(0)
+}
+
+/// `ioctl(fd, request, arg)`: carries out a device-specific request, such as
+/// [`FBIOGET_INFO`](crate::fs::devfs::FBIOGET_INFO). `arg` is either a value or where to
+/// store the answer, depending on the request.
+fn sys_ioctl(frame: &mut TrapFrame) -> SyscallResult {
+    let [fd, request, arg, ..] = args(frame);
+    let mut answer = [0u8; MAX_IOCTL];
+    let len = current_file(fd)?
+        .ioctl(request, arg, &mut answer)
+        .map_err(|e| match e {
+            FdError::Fs(FsError::NotSupported) => Errno::ENOTTY,
+            e => e.into(),
+        })?;
+    copy_to_user(arg, &answer[..len])?;
+    Ok(0)
+}
+
+/// `mmap(len, prot, fd, offset)`: maps `len` bytes of the device memory behind `fd`, from the
+/// page-aligned `offset`, and returns the address. Only devices such as `/dev/fb0` can be
+/// mapped, and the mapping is shared with every other process that maps them. `prot` is
+/// `PROT_READ`, optionally with `PROT_WRITE`.
+fn sys_mmap(frame: &mut TrapFrame) -> SyscallResult {
+    let [len, prot, fd, offset, ..] = args(frame);
+    if len == 0 || offset % PAGE_SIZE != 0 || prot & !PROT_WRITE != PROT_READ {
+        return Err(Errno::EINVAL);
+    }
+    let write = prot & PROT_WRITE != 0;
+    let memory = current_file(fd)?
+        .device_memory(write)
+        .map_err(|e| match e {
+            FdError::Fs(FsError::NotSupported) => Errno::ENODEV,
+            e => e.into(),
+        })?;
+    let end = offset.checked_add(len).ok_or(Errno::EINVAL)?;
+    if end > memory.len.next_multiple_of(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut flags = PageTableFlags::NO_EXECUTE;
+    if write {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    flags |= if memory.write_combining {
+        pat::WRITE_COMBINING
+    } else {
+        PageTableFlags::NO_CACHE
+    };
+    let pages = len.div_ceil(PAGE_SIZE) as usize;
+    let addr =
+        process::map_device(memory.start + offset, pages, flags).map_err(|_| Errno::ENOMEM)?;
+    Ok(addr.as_u64())
+}
+
+/// `munmap(addr, len)`: removes a mapping `mmap` made, which has to be unmapped whole.
+fn sys_munmap(frame: &mut TrapFrame) -> SyscallResult {
+    let [addr, len, ..] = args(frame);
+    let addr = VirtAddr::try_new(addr).map_err(|_| Errno::EINVAL)?;
+    if !process::unmap_region(addr, len) {
+        return Err(Errno::EINVAL);
+    }
+    Ok(0)
+}
@@ -0,0 +1,122 @@
+//! Checked access to user memory from system call handlers.
+//!
+//! Every pointer a process passes in is checked to lie in the user half of the address space
+//! and to be mapped user-accessible (and writable, for output buffers) before it is touched.
+//! The copy itself goes through [`copy_user_raw`], whose accesses are listed in an exception
+//! table: should one fault anyway, e.g. because the mapping changed after the check, the page
+//! fault handler resumes at a fixup that makes the copy fail with `EFAULT` instead of bringing
+//! down the kernel.
+use core::arch::naked_asm;
+
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+use super::Errno;
+use crate::memory::PAGE_SIZE;
+use crate::process::address_space::{COW, handle_cow_fault, translate_active};
+
+/// First address above the user half of the address space.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Checks that `len` bytes at `addr` are mapped for user access.
+pub fn check_range(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len as u64).ok_or(Errno::EFAULT)?;
+    if end > USER_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < end {
+        let flags = translate_active(VirtAddr::new(page))
+            .filter(|flags| flags.contains(required))
+            .ok_or(Errno::EFAULT)?;
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            // Take a private copy now rather than relying on the kernel faulting on the write
+            if !flags.contains(COW) || !handle_cow_fault(VirtAddr::new(page)) {
+                return Err(Errno::EFAULT);
+            }
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes from user address `src`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    check_range(src, dst.len(), false)?;
+    match unsafe { copy_user_raw(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copies `src` to user address `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    check_range(dst, src.len(), true)?;
+    match unsafe { copy_user_raw(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Copies `len` bytes from `src` to `dst` and returns how many were left uncopied because an
+/// access faulted.
+///
+/// # Safety
+/// The kernel side of the copy must be valid; only the user side may fault.
+#[unsafe(naked)]
+unsafe extern "C" fn copy_user_raw(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    naked_asm!(
+        "mov rcx, rdx",
+        // A fault leaves RCX counting the bytes still to go
+        "2: rep movsb",
+        "3: mov rax, rcx",
+        "ret",
+        ".pushsection ex_table, \"a\"",
+        ".balign 8",
+        ".quad 2b, 3b",
+        ".popsection",
+    );
+}
+
+/// An instruction allowed to fault, and where to resume if it does.
+#[repr(C)]
+struct ExceptionEntry {
+    fault: u64,
+    fixup: u64,
+}
+
+unsafe extern "C" {
+    // Defined by the linker around the `ex_table` section
+    static __start_ex_table: ExceptionEntry;
+    static __stop_ex_table: ExceptionEntry;
+}
+
+fn exception_table() -> &'static [ExceptionEntry] {
+    unsafe {
+        let start = &raw const __start_ex_table;
+        let end = &raw const __stop_ex_table;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Returns where to resume if the kernel faulted at `rip` inside a user-memory accessor.
+pub fn fixup(rip: u64) -> Option<u64> {
+    exception_table()
+        .iter()
+        .find(|entry| entry.fault == rip)
+        .map(|entry| entry.fixup)
+}
+
+#[test_case]
+fn test_faulting_copy_is_fixed_up() {
+    let mut buf = [0u8; 8];
+    // Nothing is mapped in the user half outside of a process
+    let src = crate::process::address_space::USER_START as *const u8;
+    let left = unsafe { copy_user_raw(buf.as_mut_ptr(), src, buf.len()) };
+    assert_eq!(left, buf.len());
+}
@@ -1,16 +1,37 @@
+//! A cooperative executor for kernel tasks.
+//!
+//! Wakers push their task's ID onto `task_queue`, and the executor only polls the tasks it
+//! finds there, so a wake-up costs the same however many tasks exist. Each task is queued at
+//! most once at a time, which also bounds the queue by the number of tasks.
 use crate::println;
+use crate::watchdog::Heartbeat;
 
-use super::{Task, TaskId};
+use super::{Task, TaskId, TaskMeta, TaskState};
 use alloc::task::Wake;
 use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::Waker;
 use core::task::{Context, Poll};
 use crossbeam_queue::ArrayQueue;
 
+/// How many tasks an executor can hold.
+pub const MAX_TASKS: usize = 256;
+
+/// Beaten on every pass of [`Executor::run`]. Idling ends at the next timer tick, so only a
+/// task that never returns from `poll` misses it.
+static HEARTBEAT: Heartbeat = Heartbeat::new("executor", 2_000);
+
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
+}
+
+/// A task's waker, with the state shared with it so the executor can tell it the task has
+/// left the queue.
+struct CachedWaker {
+    state: Arc<TaskWaker>,
+    waker: Waker,
 }
 
 impl Executor {
@@ -18,7 +39,7 @@ impl Executor {
         println!("Creating new executor");
         let new = Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
             waker_cache: BTreeMap::new(),
         };
         println!("Done!");
@@ -27,26 +48,63 @@ impl Executor {
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let meta = task.meta.clone();
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
         if self.tasks.insert(task_id, task).is_some() {
             panic!("Task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("Queue full!");
+        let state = Arc::new(TaskWaker {
+            task_id,
+            meta,
+            task_queue: self.task_queue.clone(),
+            queued: AtomicBool::new(false),
+        });
+        state.wake_task();
+        let waker = Waker::from(state.clone());
+        self.waker_cache
+            .insert(task_id, CachedWaker { state, waker });
     }
 
     pub fn run(&mut self) -> ! {
         loop {
+            HEARTBEAT.beat();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Runs tasks until every one of them has finished, idling while they wait. The kernel's
+    /// own tasks never finish, so this is for tests and short-lived executors.
+    pub fn run_to_completion(&mut self) {
+        while !self.tasks.is_empty() {
             self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Runs tasks until `done` returns `true`. It is checked each time the ready tasks have
+    /// been polled, so tasks that never finish can be left behind in the executor.
+    pub fn run_until(&mut self, mut done: impl FnMut() -> bool) {
+        loop {
+            self.run_ready_tasks();
+            if done() {
+                return;
+            }
             self.sleep_if_idle();
         }
     }
 
     fn sleep_if_idle(&self) {
         // Disable interrupts before checking if the task queue is empty. This prevents a race condition if an interrupt were to occur after entering the if statement but before the hlt instruction.
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use crate::cpu::idle::enable_and_idle;
+        use x86_64::instructions::interrupts;
 
         interrupts::disable();
         if self.task_queue.is_empty() {
-            enable_and_hlt();
+            enable_and_idle();
         } else {
             interrupts::enable();
         }
@@ -61,14 +119,13 @@ impl Executor {
         } = self;
 
         while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
+            let (Some(task), Some(cached)) = (tasks.get_mut(&task_id), waker_cache.get(&task_id))
+            else {
+                continue; // task no longer exists
             };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
+            // Cleared before polling, so a wake-up during the poll queues the task again
+            cached.state.queued.store(false, Ordering::Release);
+            let mut context = Context::from_waker(&cached.waker);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     // task done -> remove it and its cached waker
@@ -83,19 +140,18 @@ impl Executor {
 
 struct TaskWaker {
     task_id: TaskId,
+    meta: Arc<TaskMeta>,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    /// Set while the task is in `task_queue`, so waking it again doesn't queue it twice.
+    queued: AtomicBool,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
-    }
-
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            self.meta.set_state(TaskState::Queued);
+            self.task_queue.push(self.task_id).expect("task_queue full");
+        }
     }
 }
 
@@ -0,0 +1,212 @@
+//! Dispatch of decoded key events to any number of consumers.
+//!
+//! The keyboard task is the only reader of the raw scancode queue. It decodes scancodes and
+//! hands the resulting events to [`dispatch`], which routes them to the consumer that currently
+//! holds input focus, plus every consumer that subscribed with [`Route::All`].
+//!
+//! Loss is counted at both stages: [`scancode_stats`] for raw scancodes the keyboard task did
+//! not get to in time, and [`consumers`] for events a consumer's queue had no room for.
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+use spin::RwLock;
+
+pub use super::keyboard::{ScancodeStats, scancode_stats, set_scancode_queue_len};
+
+const CONSUMER_QUEUE_LEN: usize = 64;
+const NO_FOCUS: u64 = u64::MAX;
+
+/// A key press or release, along with its decoded meaning if it has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub key: Option<DecodedKey>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConsumerId(u64);
+
+impl ConsumerId {
+    fn new() -> ConsumerId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ConsumerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Which events a consumer receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Only events that arrive while this consumer holds input focus (consoles, the shell).
+    Focused,
+    /// Every event regardless of focus (monitors, hotkey handlers).
+    All,
+}
+
+struct Consumer {
+    id: ConsumerId,
+    name: &'static str,
+    route: Route,
+    queue: ArrayQueue<InputEvent>,
+    waker: AtomicWaker,
+    dropped: AtomicU64,
+}
+
+static CONSUMERS: RwLock<Vec<Arc<Consumer>>> = RwLock::new(Vec::new());
+static FOCUS: AtomicU64 = AtomicU64::new(NO_FOCUS);
+
+/// Registers a new consumer and returns the stream its events arrive on.
+///
+/// The first [`Route::Focused`] consumer is given focus automatically.
+pub fn subscribe(name: &'static str, route: Route) -> InputStream {
+    let consumer = Arc::new(Consumer {
+        id: ConsumerId::new(),
+        name,
+        route,
+        queue: ArrayQueue::new(CONSUMER_QUEUE_LEN),
+        waker: AtomicWaker::new(),
+        dropped: AtomicU64::new(0),
+    });
+    CONSUMERS.write().push(consumer.clone());
+    if route == Route::Focused {
+        let _ =
+            FOCUS.compare_exchange(NO_FOCUS, consumer.id.0, Ordering::AcqRel, Ordering::Relaxed);
+    }
+    InputStream { consumer }
+}
+
+/// Gives input focus to `id`. Returns `false` if no focusable consumer has that id.
+pub fn set_focus(id: ConsumerId) -> bool {
+    let consumers = CONSUMERS.read();
+    if consumers
+        .iter()
+        .any(|c| c.id == id && c.route == Route::Focused)
+    {
+        FOCUS.store(id.0, Ordering::Release);
+        true
+    } else {
+        false
+    }
+}
+
+/// Gives focus to the `n`th focusable consumer, in subscription order.
+pub fn focus_nth(n: usize) -> bool {
+    let id = CONSUMERS
+        .read()
+        .iter()
+        .filter(|c| c.route == Route::Focused)
+        .nth(n)
+        .map(|c| c.id);
+    match id {
+        Some(id) => set_focus(id),
+        None => false,
+    }
+}
+
+/// Returns the consumer currently holding focus, if any.
+pub fn focused() -> Option<ConsumerId> {
+    match FOCUS.load(Ordering::Acquire) {
+        NO_FOCUS => None,
+        id => Some(ConsumerId(id)),
+    }
+}
+
+/// A snapshot of a registered consumer, for listing in diagnostics.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsumerInfo {
+    pub id: ConsumerId,
+    pub name: &'static str,
+    pub route: Route,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+pub fn consumers() -> Vec<ConsumerInfo> {
+    CONSUMERS
+        .read()
+        .iter()
+        .map(|c| ConsumerInfo {
+            id: c.id,
+            name: c.name,
+            route: c.route,
+            queued: c.queue.len(),
+            dropped: c.dropped.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// Routes a decoded event to the focused consumer and all broadcast consumers.
+pub(crate) fn dispatch(event: InputEvent) {
+    let focus = FOCUS.load(Ordering::Acquire);
+    for consumer in CONSUMERS.read().iter() {
+        let wants = match consumer.route {
+            Route::All => true,
+            Route::Focused => consumer.id.0 == focus,
+        };
+        if !wants {
+            continue;
+        }
+        if consumer.queue.push(event).is_err() {
+            consumer.dropped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            consumer.waker.wake();
+        }
+    }
+}
+
+/// The receiving end of a subscription. Dropping it unsubscribes the consumer and passes focus
+/// on to the next focusable consumer.
+pub struct InputStream {
+    consumer: Arc<Consumer>,
+}
+
+impl InputStream {
+    pub fn id(&self) -> ConsumerId {
+        self.consumer.id
+    }
+
+    /// Requests input focus for this stream.
+    pub fn take_focus(&self) -> bool {
+        set_focus(self.consumer.id)
+    }
+}
+
+impl Stream for InputStream {
+    type Item = InputEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<InputEvent>> {
+        let consumer = &self.consumer;
+        if let Some(event) = consumer.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        consumer.waker.register(cx.waker());
+        match consumer.queue.pop() {
+            Some(event) => {
+                consumer.waker.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for InputStream {
+    fn drop(&mut self) {
+        let mut consumers = CONSUMERS.write();
+        consumers.retain(|c| c.id != self.consumer.id);
+        if FOCUS.load(Ordering::Acquire) == self.consumer.id.0 {
+            let next = consumers
+                .iter()
+                .find(|c| c.route == Route::Focused)
+                .map_or(NO_FOCUS, |c| c.id.0);
+            FOCUS.store(next, Ordering::Release);
+        }
+    }
+}
@@ -1,44 +1,145 @@
-use crate::{print, println};
+use super::input::{self, InputEvent, Route};
+use crate::log::Level;
+use crate::ps2::{self, LedState, Typematic};
+use crate::{log_ratelimited, print};
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1, layouts};
+use spin::Mutex;
+
+/// Scancodes that can wait for the keyboard task unless [`set_scancode_queue_len`] says
+/// otherwise.
+pub const DEFAULT_SCANCODE_QUEUE_LEN: usize = 100;
 
 static WAKER: AtomicWaker = AtomicWaker::new();
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static SCANCODE_QUEUE_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_SCANCODE_QUEUE_LEN);
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Drops the keyboard task has not warned about yet. Warnings are printed from the task rather
+/// than the interrupt handler, one for each run of lost scancodes.
+static UNREPORTED_DROPS: AtomicU64 = AtomicU64::new(0);
 
-pub async fn print_keypresses() {
+/// Sets how many scancodes can wait for the keyboard task. The queue is created when the task
+/// starts, so this returns `false` and changes nothing once it is running.
+pub fn set_scancode_queue_len(len: usize) -> bool {
+    assert!(
+        len > 0,
+        "the scancode queue needs room for at least one scancode"
+    );
+    if SCANCODE_QUEUE.is_initialized() {
+        return false;
+    }
+    SCANCODE_QUEUE_LEN.store(len, Ordering::Relaxed);
+    true
+}
+
+/// Counters for the raw scancode queue, as reported by [`scancode_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScancodeStats {
+    /// Size of the queue, or 0 before the keyboard task has created it.
+    pub capacity: usize,
+    pub queued: usize,
+    /// Scancodes handed over by the interrupt handler, including dropped ones.
+    pub received: u64,
+    /// Scancodes lost because the queue was full or did not exist yet.
+    pub dropped: u64,
+}
+
+pub fn scancode_stats() -> ScancodeStats {
+    let queue = SCANCODE_QUEUE.try_get().ok();
+    ScancodeStats {
+        capacity: queue.map_or(0, |queue| queue.capacity()),
+        queued: queue.map_or(0, |queue| queue.len()),
+        received: RECEIVED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+/// Decodes raw scancodes and hands the resulting key events to the input dispatch layer.
+///
+/// This is the only reader of the scancode queue; everything else that wants keyboard input
+/// subscribes through [`input::subscribe`]. Alt+F1..F12 moves focus between focusable consumers.
+pub async fn dispatch_keypresses() {
     let mut scancodes = ScancodeStream::new();
     let mut keyboard = Keyboard::new(
         ScancodeSet1::new(),
         layouts::Us104Key,
         HandleControl::Ignore,
     );
+    let mut locks = LockKeys::new();
+    let mut alt_held = false;
+
+    ps2::set_leds(locks.leds);
+    ps2::set_typematic(Typematic::DEFAULT);
 
-    // print!("{:?}", key)
     while let Some(scancode) = scancodes.next().await {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => {
-                        match key {
-                            // Handle special keys and don't print them
-                            pc_keyboard::KeyCode::CapsLock => (),
-                            pc_keyboard::KeyCode::LShift => (),
-                            pc_keyboard::KeyCode::RShift => (),
-                            pc_keyboard::KeyCode::LControl => (),
-                            pc_keyboard::KeyCode::RControl => (),
-                            pc_keyboard::KeyCode::LAlt => (),
-                            pc_keyboard::KeyCode::LWin => (),
-                            pc_keyboard::KeyCode::Backspace => (),
-                            _ => print!("{:?}", key),
-                        }
+            let (code, state) = (key_event.code, key_event.state);
+            locks.update(code, state);
+
+            if code == KeyCode::LAlt {
+                alt_held = state != KeyState::Up;
+            }
+            if alt_held
+                && state == KeyState::Down
+                && let Some(n) = function_key_index(code)
+            {
+                input::focus_nth(n);
+                continue;
+            }
+
+            let key = keyboard.process_keyevent(key_event);
+            input::dispatch(InputEvent { code, state, key });
+        }
+    }
+}
+
+fn function_key_index(code: KeyCode) -> Option<usize> {
+    const KEYS: [KeyCode; 12] = [
+        KeyCode::F1,
+        KeyCode::F2,
+        KeyCode::F3,
+        KeyCode::F4,
+        KeyCode::F5,
+        KeyCode::F6,
+        KeyCode::F7,
+        KeyCode::F8,
+        KeyCode::F9,
+        KeyCode::F10,
+        KeyCode::F11,
+        KeyCode::F12,
+    ];
+    KEYS.iter().position(|&k| k == code)
+}
+
+/// Echoes typed characters to the screen while it holds input focus.
+pub async fn print_keypresses() {
+    let mut events = input::subscribe("echo", Route::Focused);
+
+    while let Some(event) = events.next().await {
+        if let Some(key) = event.key {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => {
+                    match key {
+                        // Handle special keys and don't print them
+                        pc_keyboard::KeyCode::CapsLock => (),
+                        pc_keyboard::KeyCode::LShift => (),
+                        pc_keyboard::KeyCode::RShift => (),
+                        pc_keyboard::KeyCode::LControl => (),
+                        pc_keyboard::KeyCode::RControl => (),
+                        pc_keyboard::KeyCode::LAlt => (),
+                        pc_keyboard::KeyCode::LWin => (),
+                        pc_keyboard::KeyCode::Backspace => (),
+                        _ => print!("{:?}", key),
                     }
                 }
             }
@@ -46,30 +147,133 @@ pub async fn print_keypresses() {
     }
 }
 
+/// Tracks the Caps/Num/Scroll lock toggles and mirrors them onto the keyboard LEDs.
+struct LockKeys {
+    leds: LedState,
+    /// Lock keys currently held down, so auto-repeated make codes don't toggle them again.
+    held: LedState,
+}
+
+impl LockKeys {
+    fn new() -> Self {
+        LockKeys {
+            leds: LedState::NUM_LOCK,
+            held: LedState::empty(),
+        }
+    }
+
+    fn update(&mut self, code: KeyCode, state: KeyState) {
+        let led = match code {
+            KeyCode::CapsLock => LedState::CAPS_LOCK,
+            KeyCode::NumpadLock => LedState::NUM_LOCK,
+            KeyCode::ScrollLock => LedState::SCROLL_LOCK,
+            _ => return,
+        };
+        match state {
+            KeyState::Down if !self.held.contains(led) => {
+                self.held.toggle(led);
+                self.leds.toggle(led);
+                ps2::set_leds(self.leds);
+            }
+            KeyState::Up if self.held.contains(led) => self.held.toggle(led),
+            _ => {}
+        }
+    }
+}
+
+/// The key currently held down, used to synthesize repeats when the device can't do it.
+struct HeldKey {
+    code: u8,
+    extended: bool,
+    next_repeat_us: u64,
+}
+
+static HELD_KEY: Mutex<Option<HeldKey>> = Mutex::new(None);
+static EXTENDED_PREFIX: AtomicBool = AtomicBool::new(false);
+
+/// Records make/break codes so `repeat_tick` knows which key is being held.
+fn track_held_key(scancode: u8) {
+    if scancode == 0xE0 {
+        EXTENDED_PREFIX.store(true, Ordering::Relaxed);
+        return;
+    }
+    let extended = EXTENDED_PREFIX.swap(false, Ordering::Relaxed);
+    let mut held = HELD_KEY.lock();
+
+    if scancode & 0x80 != 0 {
+        // Break code: release the key if it's the one being repeated
+        if let Some(ref key) = *held
+            && key.code == scancode & 0x7F
+            && key.extended == extended
+        {
+            *held = None;
+        }
+        return;
+    }
+
+    let is_same =
+        matches!(*held, Some(ref key) if key.code == scancode && key.extended == extended);
+    if !is_same {
+        *held = crate::timer::uptime_us().map(|now| HeldKey {
+            code: scancode,
+            extended,
+            next_repeat_us: now + ps2::typematic().delay_ms as u64 * 1000,
+        });
+    }
+}
+
+/// Called from the timer interrupt. When hardware typematic is unavailable, re-injects the make
+/// code of the held key once its repeat deadline has passed.
+pub(crate) fn repeat_tick() {
+    if !ps2::typematic_unsupported() {
+        return;
+    }
+    let Some(now) = crate::timer::uptime_us() else {
+        return;
+    };
+    let mut held = HELD_KEY.lock();
+    if let Some(ref mut key) = *held
+        && now >= key.next_repeat_us
+    {
+        key.next_repeat_us = now + ps2::typematic().period_us();
+        if key.extended {
+            push_scancode(0xE0);
+        }
+        push_scancode(key.code);
+    }
+}
+
 // Called by keyboard interrupt handler
 // Must not block or allocate.
 
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
+    track_held_key(scancode);
+    push_scancode(scancode);
+}
+
+fn push_scancode(scancode: u8) {
+    RECEIVED.fetch_add(1, Ordering::Relaxed);
+    let pushed = SCANCODE_QUEUE
+        .try_get()
+        .is_ok_and(|queue| queue.push(scancode).is_ok());
+    if !pushed {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        UNREPORTED_DROPS.fetch_add(1, Ordering::Relaxed);
     }
+    // Also wakes the task for a drop, so it gets to report it
+    WAKER.wake();
 }
 
 // Giving the ScancodeStream type a private field to prevent it from being instantiated from anywhere other than the new function.
-pub struct ScancodeStream {
+// Only `dispatch_keypresses` reads raw scancodes; other consumers go through the input layer.
+pub(crate) struct ScancodeStream {
     _private: (),
 }
 
 impl ScancodeStream {
-    pub fn new() -> Self {
+    pub(crate) fn new() -> Self {
         SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_LEN.load(Ordering::Relaxed)))
             .expect("ScancodeStream::new should only be called once");
         ScancodeStream { _private: () }
     }
@@ -83,6 +287,15 @@ impl Stream for ScancodeStream {
             .try_get()
             .expect("Scancode queue not initialized!");
 
+        let dropped = UNREPORTED_DROPS.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log_ratelimited!(
+                Level::Warn,
+                "scancode queue full; dropped {} bytes of keyboard input",
+                dropped
+            );
+        }
+
         // fast path
         if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
@@ -0,0 +1,61 @@
+//! Task-local storage.
+//!
+//! A [`TaskLocal`] is a static cell with a separate value for every task, created from the
+//! cell's initialiser the first time the task uses it and dropped with the task. The values
+//! live in the task itself; while a task is being polled, the CPU polling it points at them.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::any::Any;
+use core::cell::RefCell;
+use core::ptr::{self, null_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::cpu::{MAX_CPUS, PerCpu};
+
+/// A task's values, keyed by the address of the [`TaskLocal`] they belong to.
+pub(super) type Locals = BTreeMap<usize, Box<dyn Any>>;
+
+/// The locals of the task each CPU is polling, or null.
+static CURRENT: PerCpu<AtomicPtr<RefCell<Locals>>> =
+    PerCpu::new([const { AtomicPtr::new(null_mut()) }; MAX_CPUS]);
+
+/// Runs `f` with `locals` as the current task's storage.
+pub(super) fn enter<R>(locals: &RefCell<Locals>, f: impl FnOnce() -> R) -> R {
+    let current = CURRENT.get();
+    let previous = current.swap(ptr::from_ref(locals).cast_mut(), Ordering::Relaxed);
+    let result = f();
+    current.store(previous, Ordering::Relaxed);
+    result
+}
+
+pub struct TaskLocal<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: 'static> TaskLocal<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        TaskLocal { init }
+    }
+
+    /// Runs `f` on the current task's value. Panics outside of a task.
+    ///
+    /// The value is taken out of the task while `f` runs, so using the same cell again from
+    /// inside `f` sees a fresh value rather than this one.
+    pub fn with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> R {
+        self.try_with(f).expect("task-local used outside of a task")
+    }
+
+    /// Like [`TaskLocal::with`], but returns `None` outside of a task.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let locals = unsafe { CURRENT.get().load(Ordering::Relaxed).as_ref()? };
+        let key = ptr::from_ref(self) as usize;
+        let taken = locals.borrow_mut().remove(&key);
+        let mut value = match taken.map(|value| value.downcast::<T>()) {
+            Some(Ok(value)) => value,
+            _ => Box::new((self.init)()),
+        };
+        let result = f(&mut value);
+        locals.borrow_mut().insert(key, value);
+        Some(result)
+    }
+}
@@ -1,27 +1,86 @@
 use alloc::boxed::Box;
-use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::panic::Location;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
+use spin::Mutex;
+
+use crate::cpu::{self, rdtsc};
+
+pub mod channel;
 pub mod executor;
+pub mod input;
 pub mod keyboard;
+pub mod local;
+pub mod monitor;
+pub mod sched;
 pub mod simple_executor;
+pub mod sleep;
+pub mod top;
 
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    meta: Arc<TaskMeta>,
+    locals: RefCell<local::Locals>,
 }
 
 impl Task {
+    /// Creates a task called "task". Prefer [`Task::named`] for anything long-lived.
+    #[track_caller]
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    /// Creates a task that shows up as `name` in [`list`], along with where it was created.
+    #[track_caller]
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        let id = TaskId::new();
+        let meta = Arc::new(TaskMeta {
+            name,
+            spawned_at: Location::caller(),
+            state: AtomicU8::new(TaskState::Queued as u8),
+            cpu: AtomicUsize::new(cpu::current_index()),
+            polls: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+        });
+        REGISTRY.lock().insert(id, meta.clone());
         Task {
-            id: TaskId::new(),
+            id,
             future: Box::pin(future),
+            meta,
+            locals: RefCell::new(BTreeMap::new()),
         }
     }
 
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
-        self.future.as_mut().poll(context)
+        self.meta.set_state(TaskState::Running);
+        self.meta.cpu.store(cpu::current_index(), Ordering::Relaxed);
+        let start = rdtsc();
+        let result = local::enter(&self.locals, || self.future.as_mut().poll(context));
+        self.meta.polls.fetch_add(1, Ordering::Relaxed);
+        self.meta
+            .poll_cycles
+            .fetch_add(rdtsc() - start, Ordering::Relaxed);
+        // Unless it was woken while being polled, it now waits to be
+        let _ = self.meta.state.compare_exchange(
+            TaskState::Running as u8,
+            TaskState::Waiting as u8,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        result
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        REGISTRY.lock().remove(&self.id);
     }
 }
 
@@ -34,3 +93,101 @@ impl TaskId {
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// Woken and waiting for the executor to poll it.
+    Queued,
+    /// Being polled.
+    Running,
+    /// Waiting to be woken.
+    Waiting,
+}
+
+impl TaskState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskState::Queued => "queued",
+            TaskState::Running => "running",
+            TaskState::Waiting => "waiting",
+        }
+    }
+
+    fn from_u8(value: u8) -> TaskState {
+        match value {
+            0 => TaskState::Queued,
+            1 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+/// What is known about a task, shared between the task and [`REGISTRY`].
+struct TaskMeta {
+    name: &'static str,
+    spawned_at: &'static Location<'static>,
+    /// A [`TaskState`].
+    state: AtomicU8,
+    /// Index of the CPU that last polled the task.
+    cpu: AtomicUsize,
+    polls: AtomicU64,
+    /// TSC cycles spent in the task's `poll`.
+    poll_cycles: AtomicU64,
+}
+
+impl TaskMeta {
+    fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+}
+
+/// Every task that exists, whichever executor it's on.
+static REGISTRY: Mutex<BTreeMap<TaskId, Arc<TaskMeta>>> = Mutex::new(BTreeMap::new());
+
+/// A snapshot of one task, as returned by [`list`].
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    pub spawned_at: &'static Location<'static>,
+    pub state: TaskState,
+    pub cpu: usize,
+    pub polls: u64,
+    pub poll_cycles: u64,
+}
+
+impl TaskInfo {
+    fn new(id: TaskId, meta: &TaskMeta) -> TaskInfo {
+        TaskInfo {
+            id: id.0,
+            name: meta.name,
+            spawned_at: meta.spawned_at,
+            state: TaskState::from_u8(meta.state.load(Ordering::Relaxed)),
+            cpu: meta.cpu.load(Ordering::Relaxed),
+            polls: meta.polls.load(Ordering::Relaxed),
+            poll_cycles: meta.poll_cycles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Lists the tasks that exist, in the order they were created.
+pub fn list() -> Vec<TaskInfo> {
+    REGISTRY
+        .lock()
+        .iter()
+        .map(|(&id, meta)| TaskInfo::new(id, meta))
+        .collect()
+}
+
+/// Calls `f` for each task like [`list`], but without allocating, for the panic path. Returns
+/// `false` without calling it if the registry is locked.
+pub fn try_for_each(mut f: impl FnMut(TaskInfo)) -> bool {
+    let Some(registry) = REGISTRY.try_lock() else {
+        return false;
+    };
+    for (&id, meta) in registry.iter() {
+        f(TaskInfo::new(id, meta));
+    }
+    true
+}
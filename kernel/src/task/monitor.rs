@@ -0,0 +1,872 @@
+//! A small interactive kernel monitor, run as an executor task.
+//!
+//! The monitor subscribes to keyboard input like any other focusable consumer, collects a line,
+//! and runs the matching entry from `COMMANDS`. PageUp and PageDown scroll through the console's
+//! scrollback. While `top` is on, the monitor redraws it every [`TOP_INTERVAL_TICKS`] until a
+//! key is pressed.
+use alloc::{string::String, vec::Vec};
+use futures_util::future::{self, Either};
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, layouts};
+use spin::Mutex;
+
+use super::input::{self, Route};
+use super::sleep::sleep_ticks;
+use super::{sched, top};
+use crate::allocator::alloc_info;
+#[cfg(feature = "alloc-sites")]
+use crate::allocator::alloc_sites;
+#[cfg(feature = "heap-compaction")]
+use crate::allocator::compact;
+use crate::allocator::page_allocator::{Fragmentation, PAGE_ALLOCATOR};
+use crate::fs::fat32;
+use crate::memory::{self, Zone};
+use crate::{
+    block, console, cpu, crashdump, fs, interrupts, irq, kexec, metrics, power, print, println,
+    process, profile, ps2, smp, speaker, stack, timer, vbe, virtio, watchdog,
+};
+
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list available commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "cpus",
+        help: "list CPUs with their frequencies",
+        run: cmd_cpus,
+    },
+    Command {
+        name: "tasks",
+        help: "list kernel tasks with their states, poll counts and time spent polling",
+        run: cmd_tasks,
+    },
+    Command {
+        name: "top",
+        help: "show CPU usage per CPU, task and process, refreshed until a key is pressed",
+        run: cmd_top,
+    },
+    Command {
+        name: "idle",
+        help: "show per-CPU idle residency",
+        run: cmd_idle,
+    },
+    Command {
+        name: "mem",
+        help: "show free physical memory in each zone, heap fragmentation and slab cache usage",
+        run: cmd_mem,
+    },
+    #[cfg(feature = "alloc-sites")]
+    Command {
+        name: "allocs",
+        help: "write the N heap allocation sites with the most live bytes to serial",
+        run: cmd_allocs,
+    },
+    #[cfg(feature = "heap-compaction")]
+    Command {
+        name: "compact",
+        help: "move movable heap allocations down into freed address space",
+        run: cmd_compact,
+    },
+    Command {
+        name: "memmap",
+        help: "show the boot memory map, its totals per type and what the kernel reserved",
+        run: cmd_memmap,
+    },
+    Command {
+        name: "stats",
+        help: "show event counters and gauges",
+        run: cmd_stats,
+    },
+    Command {
+        name: "profile",
+        help: "sample where the kernel spends time: profile [start|stop|dump [N]]",
+        run: cmd_profile,
+    },
+    Command {
+        name: "irqs",
+        help: "list device interrupts and their handlers; * marks masked handlers",
+        run: cmd_irqs,
+    },
+    Command {
+        name: "input",
+        help: "show keyboard input queues and how much input each has dropped",
+        run: cmd_input,
+    },
+    Command {
+        name: "ls",
+        help: "list files in all mounted file systems",
+        run: cmd_ls,
+    },
+    Command {
+        name: "fsck",
+        help: "check the FAT32 volume on a block device without changing it: fsck DEVICE",
+        run: cmd_fsck,
+    },
+    Command {
+        name: "dmesg",
+        help: "show the most recent log messages",
+        run: cmd_dmesg,
+    },
+    Command {
+        name: "crashdump",
+        help: "show or clear the crash dump saved on a block device: crashdump DEVICE [clear]",
+        run: cmd_crashdump,
+    },
+    Command {
+        name: "ps",
+        help: "list user processes with their CPU time, resident pages and page faults",
+        run: cmd_ps,
+    },
+    Command {
+        name: "user",
+        help: "run N copies of a built-in user program: user [name] [N]",
+        run: cmd_user,
+    },
+    Command {
+        name: "mode",
+        help: "show or set the display mode: mode [WIDTHxHEIGHT[xBPP]]",
+        run: cmd_mode,
+    },
+    Command {
+        name: "hvc",
+        help: "show or set whether output is copied to the virtio console: hvc [on|off]",
+        run: cmd_hvc,
+    },
+    Command {
+        name: "beep",
+        help: "play a tone, or turn panic beeps on or off: beep [FREQ [MS]] | beep on|off",
+        run: cmd_beep,
+    },
+    Command {
+        name: "shutdown",
+        help: "power off the machine",
+        run: cmd_shutdown,
+    },
+    Command {
+        name: "suspend",
+        help: "idle with devices quiesced until a key or the RTC alarm: suspend [SECS]",
+        run: cmd_suspend,
+    },
+    Command {
+        name: "watchdog",
+        help: "list subsystem heartbeats",
+        run: cmd_watchdog,
+    },
+    Command {
+        name: "stacks",
+        help: "show how deep each CPU's kernel stacks have been",
+        run: cmd_stacks,
+    },
+    Command {
+        name: "jobs",
+        help: "list scheduled maintenance jobs",
+        run: cmd_jobs,
+    },
+    Command {
+        name: "park",
+        help: "take an AP out of service, or list which are: park [CPU]",
+        run: cmd_park,
+    },
+    Command {
+        name: "unpark",
+        help: "put a parked AP back in service: unpark CPU",
+        run: cmd_unpark,
+    },
+    Command {
+        name: "kexec",
+        help: "start another kernel image without a reboot: kexec PATH|FW_CFG_FILE",
+        run: cmd_kexec,
+    },
+    Command {
+        name: "reboot",
+        help: "reset the machine",
+        run: cmd_reboot,
+    },
+];
+
+const PROMPT: &str = "> ";
+
+/// Timer ticks between `top` refreshes: a second.
+const TOP_INTERVAL_TICKS: u64 = interrupts::APIC_TIMER_HZ as u64;
+
+/// What `top` last showed, while it is on.
+static TOP: Mutex<Option<top::Sample>> = Mutex::new(None);
+
+pub async fn run() {
+    let mut events = input::subscribe("monitor", Route::Focused);
+    let mut line = String::new();
+
+    console::echo(PROMPT);
+    loop {
+        let event = if TOP.lock().is_some() {
+            match future::select(events.next(), sleep_ticks(TOP_INTERVAL_TICKS)).await {
+                Either::Left((event, _)) => event,
+                Either::Right(_) => {
+                    refresh_top();
+                    continue;
+                }
+            }
+        } else {
+            events.next().await
+        };
+        let Some(event) = event else {
+            break;
+        };
+        let Some(key) = event.key else {
+            continue;
+        };
+        // The key that stops `top` isn't typed
+        if TOP.lock().take().is_some() {
+            console::echo(PROMPT);
+            continue;
+        }
+        handle_key(&mut line, key);
+    }
+}
+
+/// Runs the monitor without the executor or interrupts, polling the PS/2 keyboard. This is for
+/// after a panic, when nothing else is going to run; commands that need a lock the panicking
+/// code held will hang.
+pub fn run_polled() -> ! {
+    let mut keyboard = Keyboard::new(
+        ScancodeSet1::new(),
+        layouts::Us104Key,
+        HandleControl::Ignore,
+    );
+    let mut line = String::new();
+
+    console::echo(PROMPT);
+    loop {
+        let Some(scancode) = ps2::poll_scancode() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        if let Ok(Some(event)) = keyboard.add_byte(scancode)
+            && let Some(key) = keyboard.process_keyevent(event)
+        {
+            handle_key(&mut line, key);
+        }
+    }
+}
+
+fn handle_key(line: &mut String, key: DecodedKey) {
+    match key {
+        DecodedKey::Unicode('\n') => {
+            console::echo("\n");
+            execute(line);
+            line.clear();
+            console::echo(PROMPT);
+        }
+        DecodedKey::Unicode('\u{8}') => {
+            if line.pop().is_some() {
+                console::echo("\u{8}");
+            }
+        }
+        DecodedKey::Unicode(c) if !c.is_control() => {
+            line.push(c);
+            console::echo(c.encode_utf8(&mut [0; 4]));
+        }
+        DecodedKey::RawKey(KeyCode::PageUp) => {
+            console::scroll_back(console::size().1 / 2);
+        }
+        DecodedKey::RawKey(KeyCode::PageDown) => {
+            console::scroll_forward(console::size().1 / 2);
+        }
+        _ => {}
+    }
+}
+
+/// Runs a single command line.
+pub fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, rest)) = args.split_first() else {
+        return;
+    };
+    match COMMANDS.iter().find(|c| c.name == name) {
+        Some(command) => (command.run)(rest),
+        None => println!("unknown command '{}', try 'help'", name),
+    }
+}
+
+fn cmd_help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("  {:<12} {}", command.name, command.help);
+    }
+}
+
+fn cmd_shutdown(_args: &[&str]) {
+    power::shutdown();
+}
+
+fn cmd_reboot(_args: &[&str]) {
+    power::reboot();
+}
+
+fn cmd_suspend(args: &[&str]) {
+    let alarm_secs = match args.first().map(|secs| secs.parse()) {
+        None => None,
+        Some(Ok(secs)) => Some(secs),
+        Some(Err(_)) => {
+            println!("usage: suspend [SECS]");
+            return;
+        }
+    };
+    match power::suspend::suspend(alarm_secs) {
+        Ok(wake) => println!("resumed, woken by {:?}", wake),
+        Err(e) => println!("suspend failed: {}", e),
+    }
+}
+
+fn cmd_cpus(_args: &[&str]) {
+    let mut brand = [0u8; 48];
+    if let Some(name) = cpu::brand_string(&mut brand) {
+        println!("{}", name);
+    }
+    if let Some(hypervisor) = cpu::hypervisor::get() {
+        println!("running under {}", hypervisor.name());
+    }
+    if let Some(info) = cpu::freq::info() {
+        println!(
+            "base {} MHz, max {:?} MHz, bus {:?} MHz, TSC {:?} MHz",
+            info.base_mhz, info.max_mhz, info.bus_mhz, info.tsc_mhz
+        );
+    }
+    for (index, apic_id) in cpu::online() {
+        match cpu::freq::current_mhz(index) {
+            Some(mhz) => println!("  cpu{} (apic {}): {} MHz", index, apic_id, mhz),
+            None => println!(
+                "  cpu{} (apic {}): current frequency unknown",
+                index, apic_id
+            ),
+        }
+        if let Some(ticks) = timer::cpu_ticks(index) {
+            println!("    {} timer ticks", ticks);
+        }
+        if index != cpu::current_index() {
+            match smp::tsc::measure_one(index) {
+                Ok(offset) => println!("    TSC offset {} cycles", offset),
+                Err(e) => println!("    TSC not read: {:?}", e),
+            }
+        }
+        let Some(events) = interrupts::apic_event_stats(index) else {
+            continue;
+        };
+        if events.errors > 0 {
+            print!("    {} APIC errors:", events.errors);
+            for error in interrupts::esr_errors(events.esr_bits) {
+                print!(" {};", error);
+            }
+            println!();
+        }
+        if events.thermal > 0 {
+            println!("    {} thermal events", events.thermal);
+        }
+    }
+}
+
+fn cmd_tasks(_args: &[&str]) {
+    let tsc_mhz = cpu::freq::info().and_then(|info| info.tsc_mhz);
+    let tasks = super::list();
+    let total: u64 = tasks.iter().map(|task| task.poll_cycles).sum();
+    for task in tasks {
+        let share = (task.poll_cycles * 100).checked_div(total).unwrap_or(0);
+        print!(
+            "  {:>3} {:<12} {:<8} cpu{} {:>8} polls, {:>3}% ",
+            task.id,
+            task.name,
+            task.state.name(),
+            task.cpu,
+            task.polls,
+            share
+        );
+        match tsc_mhz {
+            Some(mhz) => print!("{:>8} us", task.poll_cycles / mhz as u64),
+            None => print!("{:>8} cycles", task.poll_cycles),
+        }
+        println!("  ({})", task.spawned_at);
+    }
+}
+
+/// Shows the totals since boot. The monitor task then keeps redrawing with what changed since
+/// the last refresh, but not after a panic, when nothing runs it.
+fn cmd_top(_args: &[&str]) {
+    let sample = top::Sample::take();
+    console::clear();
+    top::render(&top::Sample::boot(), &sample);
+    println!("(since boot; press a key to stop)");
+    *TOP.lock() = Some(sample);
+}
+
+fn refresh_top() {
+    let sample = top::Sample::take();
+    let mut top = TOP.lock();
+    let Some(last) = top.as_ref() else {
+        return;
+    };
+    console::clear();
+    top::render(last, &sample);
+    println!("(press a key to stop)");
+    *top = Some(sample);
+}
+
+fn cmd_idle(_args: &[&str]) {
+    match cpu::idle::cstate() {
+        Some(cstate) => println!("idle: MWAIT, C{} hint", cstate),
+        None => println!("idle: hlt"),
+    }
+    for (index, apic_id) in cpu::online() {
+        if let Some(stats) = cpu::idle::stats(index) {
+            println!(
+                "  cpu{} (apic {}): {} entries ({} mwait), {} idle cycles",
+                index, apic_id, stats.entries, stats.mwait_entries, stats.idle_cycles
+            );
+        }
+    }
+}
+
+fn cmd_user(args: &[&str]) {
+    let name = args.first().copied().unwrap_or("hello");
+    let Some(image) = process::builtin::find(name) else {
+        println!("no built-in program '{}'", name);
+        return;
+    };
+    let count: usize = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(1);
+    let mut pids = Vec::new();
+    for _ in 0..count {
+        match process::spawn(name, image) {
+            Ok(pid) => pids.push(pid),
+            Err(e) => {
+                println!("failed to start process: {:?}", e);
+                break;
+            }
+        }
+    }
+    process::scheduler::run();
+    // Also collect children the programs forked
+    for info in process::scheduler::processes() {
+        if !pids.contains(&info.pid) {
+            pids.push(info.pid);
+        }
+    }
+    for pid in pids {
+        if let Some(exit) = process::scheduler::reap(pid) {
+            println!(
+                "pid {} exited with code {} ({} user, {} system ticks)",
+                pid.0, exit.code, exit.user_ticks, exit.system_ticks
+            );
+        }
+    }
+}
+
+fn cmd_ps(_args: &[&str]) {
+    let processes = process::scheduler::processes();
+    if processes.is_empty() {
+        println!("no processes");
+        return;
+    }
+    println!("  pid  ppid name         state    cpu   user    sys  pages faults");
+    for info in processes {
+        println!(
+            "  {:>3} {:>5} {:<12} {:<8} {:>3} {:>6} {:>6} {:>6} {:>6}",
+            info.pid.0,
+            info.parent.map_or(0, |parent| parent.0),
+            info.name,
+            info.state.name(),
+            info.cpu,
+            info.user_ticks,
+            info.system_ticks,
+            info.resident_pages,
+            info.page_faults
+        );
+    }
+}
+
+fn cmd_mem(_args: &[&str]) {
+    let guard = PAGE_ALLOCATOR.lock();
+    let Some(page_alloc) = guard.as_ref() else {
+        println!("memory not initialised");
+        return;
+    };
+    let frames = &page_alloc.frame_allocator;
+    println!("{} KiB free", frames.free_frames() * 4);
+    for zone in Zone::ALL {
+        println!("  {}: {} KiB", zone.name(), frames.free_frames_in(zone) * 4);
+    }
+    let fragmentation = page_alloc.fragmentation();
+    drop(guard);
+    let (large, large_pages) = alloc_info::large_alloc_stats();
+    println!("large allocations: {} in {} KiB", large, large_pages * 4);
+    print_fragmentation(&fragmentation);
+    let slab = process::scheduler::slab_stats();
+    println!(
+        "slab {}: {} objects of {} bytes in {} KiB",
+        slab.name,
+        slab.allocated,
+        slab.object_size,
+        slab.slab_bytes / 1024
+    );
+}
+
+fn print_fragmentation(fragmentation: &Fragmentation) {
+    println!(
+        "heap address space: {} KiB free in {} ranges, largest {} KiB, {}% fragmented, {} KiB unused in window",
+        fragmentation.free_bytes / 1024,
+        fragmentation.free_ranges,
+        fragmentation.largest_free / 1024,
+        fragmentation.percent(),
+        fragmentation.unused_window / 1024
+    );
+}
+
+#[cfg(feature = "alloc-sites")]
+fn cmd_allocs(args: &[&str]) {
+    let top = args.first().and_then(|n| n.parse().ok()).unwrap_or(10);
+    alloc_sites::dump(top);
+    println!("allocation sites written to serial");
+}
+
+#[cfg(feature = "heap-compaction")]
+fn cmd_compact(_args: &[&str]) {
+    let Some(compaction) = compact::compact() else {
+        println!("memory not initialised");
+        return;
+    };
+    println!(
+        "moved {} allocations, {} KiB; {} KiB given back to the window",
+        compaction.moved,
+        compaction.moved_bytes / 1024,
+        compaction.trimmed / 1024
+    );
+    print!("before: ");
+    print_fragmentation(&compaction.before);
+    print!("after:  ");
+    print_fragmentation(&compaction.after);
+}
+
+fn cmd_memmap(_args: &[&str]) {
+    match memory::report::Report::current() {
+        Some(report) => print!("{}", report),
+        None => println!("memory not initialised"),
+    }
+}
+
+fn cmd_stats(_args: &[&str]) {
+    for sample in metrics::list() {
+        println!(
+            "  {:<24} {:>12} {}",
+            sample.name,
+            sample.value,
+            sample.kind.name()
+        );
+    }
+    let unlisted = metrics::unlisted();
+    if unlisted > 0 {
+        println!("  ({} more not listed)", unlisted);
+    }
+}
+
+fn cmd_watchdog(_args: &[&str]) {
+    println!(
+        "watchdog: {}",
+        if watchdog::is_running() { "on" } else { "off" }
+    );
+    for status in watchdog::list() {
+        match status.age_ms {
+            Some(age) => println!(
+                "  {:<16} last beat {} ms ago, timeout {} ms",
+                status.name, age, status.timeout_ms
+            ),
+            None => println!("  {:<16} disarmed", status.name),
+        }
+    }
+}
+
+fn cmd_stacks(_args: &[&str]) {
+    for (index, _) in cpu::online() {
+        for usage in stack::high_water(index) {
+            println!(
+                "  cpu{} {:<12} {:>6} of {:>6} bytes ({}%)",
+                index,
+                usage.name,
+                usage.high_water,
+                usage.size,
+                usage.high_water * 100 / usage.size.max(1)
+            );
+        }
+    }
+}
+
+fn cmd_jobs(_args: &[&str]) {
+    for job in sched::list() {
+        let every = match job.period_ticks {
+            Some(period) => alloc::format!("every {} ticks", period),
+            None => String::from("once"),
+        };
+        println!(
+            "  {:<16} {:<20} due in {} ticks, run {} times",
+            job.name, every, job.due_in_ticks, job.runs
+        );
+    }
+}
+
+fn cmd_park(args: &[&str]) {
+    let Some(arg) = args.first() else {
+        for (index, apic_id) in cpu::online() {
+            if let Some(state) = smp::park::state(index) {
+                println!("  cpu{} (apic {}): {:?}", index, apic_id, state);
+            }
+        }
+        return;
+    };
+    let Ok(index) = arg.parse() else {
+        println!("usage: park [CPU]");
+        return;
+    };
+    match smp::park(index) {
+        Ok(()) => println!("cpu{} parked", index),
+        Err(e) => println!("failed to park cpu{}: {:?}", index, e),
+    }
+}
+
+fn cmd_unpark(args: &[&str]) {
+    let Some(Ok(index)) = args.first().map(|arg| arg.parse()) else {
+        println!("usage: unpark CPU");
+        return;
+    };
+    match smp::unpark(index) {
+        Ok(()) => println!("cpu{} running", index),
+        Err(e) => println!("failed to unpark cpu{}: {:?}", index, e),
+    }
+}
+
+fn cmd_profile(args: &[&str]) {
+    match args {
+        [] => println!(
+            "profiling: {}",
+            if profile::is_running() { "on" } else { "off" }
+        ),
+        ["start"] => {
+            profile::start();
+            println!("profiling");
+        }
+        ["stop"] => {
+            profile::stop();
+            let profile = profile::report();
+            println!(
+                "stopped with {} kernel samples, {} user",
+                profile.kernel, profile.user
+            );
+        }
+        ["dump"] | ["dump", _] => {
+            let top = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(20);
+            profile::dump(top);
+            println!("profile written to serial");
+        }
+        _ => println!("usage: profile [start|stop|dump [N]]"),
+    }
+}
+
+fn cmd_irqs(_args: &[&str]) {
+    for irq in irq::list() {
+        print!(
+            "  {:#04x} GSI {:<3} {:>8} taken, {:>6} unclaimed{} ",
+            irq.vector,
+            irq.gsi,
+            irq.count,
+            irq.unclaimed,
+            if irq.line_masked { " (masked)" } else { "" }
+        );
+        for (name, masked) in irq.handlers {
+            print!(" {}{}", name, if masked { "*" } else { "" });
+        }
+        println!();
+    }
+}
+
+fn cmd_input(_args: &[&str]) {
+    let stats = input::scancode_stats();
+    println!(
+        "scancodes: {}/{} queued, {} received, {} dropped",
+        stats.queued, stats.capacity, stats.received, stats.dropped
+    );
+    let focused = input::focused();
+    for consumer in input::consumers() {
+        let marker = if Some(consumer.id) == focused {
+            '*'
+        } else {
+            ' '
+        };
+        println!(
+            " {}{:<12} {:?}: {} queued, {} dropped",
+            marker, consumer.name, consumer.route, consumer.queued, consumer.dropped
+        );
+    }
+}
+
+fn cmd_ls(_args: &[&str]) {
+    for path in fs::list() {
+        println!("  {}", path);
+    }
+}
+
+fn cmd_fsck(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        for device in block::devices() {
+            println!("  {} ({} sectors)", device.name(), device.sector_count());
+        }
+        return;
+    };
+    let Some(device) = block::find(name) else {
+        println!("no block device '{}'", name);
+        return;
+    };
+    match block::wait(fat32::fsck::check(&*device)) {
+        Ok(report) => {
+            println!(
+                "{}: {} files, {} directories, {} clusters used, {} free",
+                name, report.files, report.directories, report.used_clusters, report.free_clusters
+            );
+            for problem in &report.problems {
+                println!("  {:?}", problem);
+            }
+            if report.is_clean() {
+                println!("no problems found");
+            }
+        }
+        Err(e) => println!("{}: {:?}", name, e),
+    }
+}
+
+fn cmd_dmesg(_args: &[&str]) {
+    let shown = crate::log::with_history(|older, newer| {
+        for chunk in older.utf8_chunks().chain(newer.utf8_chunks()) {
+            print!("{}", chunk.valid());
+        }
+    });
+    if shown.is_none() {
+        println!("the log history is busy");
+    }
+}
+
+fn cmd_crashdump(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("usage: crashdump DEVICE [clear]");
+        return;
+    };
+    let Some(device) = block::find(name) else {
+        println!("no block device '{}'", name);
+        return;
+    };
+    if args.get(1) == Some(&"clear") {
+        match block::wait(crashdump::clear(&*device)) {
+            Ok(()) => println!("crash dump on {} cleared", name),
+            Err(e) => println!("{}: {:?}", name, e),
+        }
+        return;
+    }
+    match block::wait(crashdump::load(&*device)) {
+        Ok(Some(dump)) => print!("{}", String::from_utf8_lossy(&dump)),
+        Ok(None) => println!("no crash dump on {}", name),
+        Err(e) => println!("{}: {:?}", name, e),
+    }
+}
+
+fn cmd_kexec(args: &[&str]) {
+    let Some(&source) = args.first() else {
+        println!("usage: kexec PATH|FW_CFG_FILE");
+        return;
+    };
+    let kernel = match kexec::read_image(source).and_then(|image| kexec::load(&image)) {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            println!("kexec: {:?}", e);
+            return;
+        }
+    };
+    println!("{}: entry at {:#x}, starting it", source, kernel.entry());
+    let Err(e) = kexec::execute(kernel);
+    println!("kexec: {:?}", e);
+}
+
+fn cmd_mode(args: &[&str]) {
+    let Some(current) = vbe::current_mode() else {
+        println!("no Bochs-compatible display adapter");
+        return;
+    };
+    let Some(spec) = args.first() else {
+        println!(
+            "{}x{}x{}, {} KiB video memory",
+            current.width,
+            current.height,
+            current.bpp,
+            vbe::video_memory().unwrap_or(0) / 1024
+        );
+        return;
+    };
+    let mut parts = spec.split('x').map(str::parse::<u16>);
+    let mode = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(width)), Some(Ok(height)), bpp, None) => match bpp {
+            None => Some((width, height, current.bpp)),
+            Some(Ok(bpp)) => Some((width, height, bpp)),
+            Some(Err(_)) => None,
+        },
+        _ => None,
+    };
+    let Some((width, height, bpp)) = mode else {
+        println!("usage: mode [WIDTHxHEIGHT[xBPP]]");
+        return;
+    };
+    if let Err(e) = vbe::set_mode(vbe::Mode { width, height, bpp }) {
+        println!("failed to set mode: {:?}", e);
+    }
+}
+
+fn cmd_beep(args: &[&str]) {
+    let toggle = match args {
+        ["on"] => Some(true),
+        ["off"] => Some(false),
+        _ => None,
+    };
+    if let Some(enabled) = toggle {
+        speaker::set_alerts(enabled);
+        println!("panic beeps: {}", if enabled { "on" } else { "off" });
+        return;
+    }
+    let freq = args.first().map_or(Ok(440), |freq| freq.parse::<u32>());
+    let duration = args.get(1).map_or(Ok(200), |ms| ms.parse::<u64>());
+    match (freq, duration) {
+        (Ok(freq), Ok(ms)) if freq > 0 && args.len() <= 2 => speaker::beep(freq, ms),
+        _ => println!("usage: beep [FREQ [MS]] | beep on|off"),
+    }
+}
+
+fn cmd_hvc(args: &[&str]) {
+    use virtio::console;
+    if !console::is_present() {
+        println!("no virtio console");
+        return;
+    }
+    match args.first().copied() {
+        None => {}
+        Some("on") => console::set_mirror(true),
+        Some("off") => console::set_mirror(false),
+        Some(_) => {
+            println!("usage: hvc [on|off]");
+            return;
+        }
+    }
+    let state = if console::mirror_enabled() {
+        "on"
+    } else {
+        "off"
+    };
+    println!("copying output to hvc0: {}", state);
+}
@@ -0,0 +1,252 @@
+//! Jobs that run on a schedule, cron style: [`every`] runs one periodically and [`at`] runs one
+//! once at a wall-clock time.
+//!
+//! Jobs run one after another in the [`run`] task rather than in the timer interrupt, so they
+//! may allocate and take locks, but a job that blocks holds up every other. Periodic jobs are
+//! timed in timer ticks. Wall-clock jobs are timed on [`Clock::Realtime`], which can be stepped,
+//! so the task wakes at least every [`MAX_WAIT_TICKS`] to notice when one has come due early.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::future::{Future, poll_fn};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+use super::sleep::sleep_ticks;
+use crate::interrupts::APIC_TIMER_HZ;
+use crate::time::{self, Clock, Timespec};
+use crate::timer;
+
+/// Longest the task sleeps between looks at the wall clock: a minute.
+pub const MAX_WAIT_TICKS: u64 = 60 * APIC_TIMER_HZ as u64;
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+/// Identifies a job to [`cancel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JobId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum When {
+    /// At this timer tick.
+    Tick(u64),
+    /// At this many nanoseconds on the realtime clock.
+    Realtime(u64),
+}
+
+type JobFn = Box<dyn FnMut() + Send>;
+
+struct Job {
+    id: JobId,
+    name: &'static str,
+    /// When it next runs, or `None` once a one-shot job has run.
+    next: Option<When>,
+    period_ticks: Option<u64>,
+    /// Taken out while the job runs, so it can schedule or cancel jobs itself.
+    run: Option<JobFn>,
+    runs: u64,
+}
+
+static JOBS: Mutex<Vec<Job>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Set and woken when a job is added, so [`run`] doesn't sleep past it.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Whole timer ticks covering `duration`, at least one.
+fn ticks_for(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * APIC_TIMER_HZ as u128).div_ceil(NS_PER_SEC);
+    ticks.clamp(1, u64::MAX as u128) as u64
+}
+
+/// The tick `when` falls on, given the tick and realtime clock now. A wall-clock time is
+/// rechecked after [`MAX_WAIT_TICKS`] without a realtime clock.
+fn due_tick(when: When, now: u64, realtime_ns: Option<u64>) -> u64 {
+    match when {
+        When::Tick(tick) => tick,
+        When::Realtime(target) => match realtime_ns {
+            Some(realtime) if target <= realtime => now,
+            Some(realtime) => now + ticks_for(Duration::from_nanos(target - realtime)),
+            None => now + MAX_WAIT_TICKS,
+        },
+    }
+}
+
+fn realtime_ns() -> Option<u64> {
+    time::now(Clock::Realtime)?.as_nanos()
+}
+
+fn add(name: &'static str, next: When, period_ticks: Option<u64>, run: JobFn) -> JobId {
+    let id = JobId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    JOBS.lock().push(Job {
+        id,
+        name,
+        next: Some(next),
+        period_ticks,
+        run: Some(run),
+        runs: 0,
+    });
+    CHANGED.store(true, Ordering::Release);
+    WAKER.wake();
+    id
+}
+
+/// Runs `job` every `period`, the first time one period from now.
+pub fn every(period: Duration, name: &'static str, job: impl FnMut() + Send + 'static) -> JobId {
+    let period_ticks = ticks_for(period);
+    let next = When::Tick(timer::ticks() + period_ticks);
+    add(name, next, Some(period_ticks), Box::new(job))
+}
+
+/// Runs `job` once when the realtime clock reaches `time`, or straight away if it already has.
+/// Returns `None` if `time` is before the Unix epoch.
+pub fn at(
+    time: Timespec,
+    name: &'static str,
+    job: impl FnOnce() + Send + 'static,
+) -> Option<JobId> {
+    let target = time.as_nanos()?;
+    let mut job = Some(job);
+    let run = move || {
+        if let Some(job) = job.take() {
+            job();
+        }
+    };
+    Some(add(name, When::Realtime(target), None, Box::new(run)))
+}
+
+/// Removes a job so it doesn't run again. Returns `false` if there is no such job, e.g. a
+/// one-shot job that has already run.
+pub fn cancel(id: JobId) -> bool {
+    let mut jobs = JOBS.lock();
+    let before = jobs.len();
+    jobs.retain(|job| job.id != id);
+    jobs.len() != before
+}
+
+/// A scheduled job as [`list`] reports it.
+#[derive(Debug, Clone, Copy)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub name: &'static str,
+    /// Ticks until it next runs, 0 if it is due or running.
+    pub due_in_ticks: u64,
+    pub period_ticks: Option<u64>,
+    pub runs: u64,
+}
+
+pub fn list() -> Vec<JobInfo> {
+    let now = timer::ticks();
+    let realtime = realtime_ns();
+    JOBS.lock()
+        .iter()
+        .filter_map(|job| {
+            Some(JobInfo {
+                id: job.id,
+                name: job.name,
+                due_in_ticks: due_tick(job.next?, now, realtime).saturating_sub(now),
+                period_ticks: job.period_ticks,
+                runs: job.runs,
+            })
+        })
+        .collect()
+}
+
+/// Runs every job due at tick `now`, and returns the tick the next one is due.
+fn run_due(now: u64) -> Option<u64> {
+    let realtime = realtime_ns();
+    let mut due = Vec::new();
+    for job in JOBS.lock().iter_mut() {
+        let Some(next) = job.next else {
+            continue;
+        };
+        if due_tick(next, now, realtime) > now {
+            continue;
+        }
+        let Some(run) = job.run.take() else {
+            continue;
+        };
+        job.next = job.period_ticks.map(|period| When::Tick(now + period));
+        job.runs += 1;
+        due.push((job.id, run));
+    }
+
+    for (id, mut run) in due {
+        run();
+        // Unless the job was cancelled while it ran
+        let mut jobs = JOBS.lock();
+        if let Some(job) = jobs.iter_mut().find(|job| job.id == id) {
+            job.run = Some(run);
+        }
+    }
+
+    let mut jobs = JOBS.lock();
+    jobs.retain(|job| job.next.is_some());
+    jobs.iter()
+        .filter_map(|job| Some(due_tick(job.next?, now, realtime)))
+        .min()
+}
+
+/// Runs jobs as they come due, forever. Spawn it on the executor.
+pub async fn run() {
+    loop {
+        let now = timer::ticks();
+        let wait = run_due(now).map_or(MAX_WAIT_TICKS, |due| {
+            due.saturating_sub(now).clamp(1, MAX_WAIT_TICKS)
+        });
+        let mut sleep = sleep_ticks(wait);
+        // Until the next job is due or another is added
+        poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            if CHANGED.swap(false, Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            Pin::new(&mut sleep).poll(cx)
+        })
+        .await;
+    }
+}
+
+#[test_case]
+fn test_due_tick() {
+    assert_eq!(due_tick(When::Tick(7), 5, None), 7);
+    // Already past on the wall clock
+    assert_eq!(due_tick(When::Realtime(1_000), 5, Some(2_000)), 5);
+    // Two seconds ahead of the wall clock
+    assert_eq!(
+        due_tick(
+            When::Realtime(3 * NS_PER_SEC as u64),
+            5,
+            Some(NS_PER_SEC as u64)
+        ),
+        5 + 2 * APIC_TIMER_HZ as u64
+    );
+    assert_eq!(due_tick(When::Realtime(1_000), 5, None), 5 + MAX_WAIT_TICKS);
+    assert_eq!(ticks_for(Duration::from_nanos(1)), 1);
+}
+
+#[test_case]
+fn test_periodic_job_runs_until_cancelled() {
+    use alloc::sync::Arc;
+
+    let runs = Arc::new(AtomicU64::new(0));
+    let counted = runs.clone();
+    let start = timer::ticks();
+    let id = every(Duration::from_secs(1), "test", move || {
+        counted.fetch_add(1, Ordering::Relaxed);
+    });
+    run_due(start);
+    assert_eq!(runs.load(Ordering::Relaxed), 0);
+    // Leaves room for ticks that passed since `start`
+    run_due(start + 2 * APIC_TIMER_HZ as u64);
+    assert_eq!(runs.load(Ordering::Relaxed), 1);
+    assert!(cancel(id));
+    run_due(start + 10 * MAX_WAIT_TICKS);
+    assert_eq!(runs.load(Ordering::Relaxed), 1);
+    assert!(!cancel(id));
+}
@@ -0,0 +1,94 @@
+//! Futures that finish after a number of timer ticks.
+//!
+//! A sleeping task parks its waker in a fixed table that the timer interrupt scans on every
+//! tick, so waking it never allocates in interrupt context. A sleeper that finds the table full
+//! asks to be polled again instead of waiting for the timer.
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::timer;
+
+/// How many tasks can wait on the timer at once.
+const MAX_SLEEPERS: usize = 64;
+
+struct Sleeper {
+    deadline: u64,
+    /// Taken by the timer interrupt when it wakes the task.
+    waker: Option<Waker>,
+}
+
+static SLEEPERS: Mutex<[Option<Sleeper>; MAX_SLEEPERS]> =
+    Mutex::new([const { None }; MAX_SLEEPERS]);
+
+/// Returns a future that finishes once `ticks` more timer ticks have passed.
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep {
+        deadline: timer::ticks() + ticks,
+        slot: None,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    /// Our entry in `SLEEPERS`, held from the first pending poll until the sleep ends.
+    slot: Option<usize>,
+}
+
+impl Sleep {
+    fn release(&mut self) {
+        if let Some(index) = self.slot.take() {
+            interrupts::without_interrupts(|| SLEEPERS.lock()[index] = None);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if timer::ticks() >= self.deadline {
+            self.release();
+            return Poll::Ready(());
+        }
+
+        let sleeper = Sleeper {
+            deadline: self.deadline,
+            waker: Some(cx.waker().clone()),
+        };
+        let slot = interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            let index = self
+                .slot
+                .or_else(|| sleepers.iter().position(Option::is_none))?;
+            sleepers[index] = Some(sleeper);
+            Some(index)
+        });
+        match slot {
+            Some(index) => self.slot = Some(index),
+            None => cx.waker().wake_by_ref(),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Called from the timer interrupt: wakes every sleeper whose deadline has passed.
+pub(crate) fn wake_expired(now: u64) {
+    let mut sleepers = SLEEPERS.lock();
+    for sleeper in sleepers.iter_mut().flatten() {
+        if sleeper.deadline <= now
+            && let Some(waker) = sleeper.waker.take()
+        {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! The monitor's `top` view: how busy each CPU is, and which tasks and processes keep it busy.
+//!
+//! A [`Sample`] records cumulative counters: idle and poll cycles, each CPU's timer and
+//! scheduler ticks, and the [`metrics`]. [`render`] prints what changed between two samples,
+//! as shares of the time between them.
+use alloc::vec::Vec;
+
+use super::TaskInfo;
+use crate::cpu::{self, rdtsc};
+use crate::metrics::{self, Kind};
+use crate::process::Pid;
+use crate::process::scheduler::{self, CpuStats, ProcessInfo};
+use crate::{println, timer};
+
+/// Counters that moved the most in the interval are listed, up to this many.
+const MAX_COUNTERS: usize = 8;
+
+/// Everything `top` shows, as totals at one point in time.
+pub struct Sample {
+    tsc: u64,
+    cpus: Vec<CpuSample>,
+    tasks: Vec<TaskInfo>,
+    processes: Vec<ProcessInfo>,
+    metrics: Vec<metrics::Sample>,
+}
+
+#[derive(Default)]
+struct CpuSample {
+    index: usize,
+    idle_cycles: u64,
+    ticks: u64,
+    sched: CpuStats,
+}
+
+impl Sample {
+    pub fn take() -> Sample {
+        let cpus = cpu::online()
+            .map(|(index, _)| CpuSample {
+                index,
+                idle_cycles: cpu::idle::stats(index).map_or(0, |stats| stats.idle_cycles),
+                ticks: timer::cpu_ticks(index).unwrap_or(0),
+                sched: scheduler::cpu_stats(index).unwrap_or_default(),
+            })
+            .collect();
+        Sample {
+            tsc: rdtsc(),
+            cpus,
+            tasks: super::list(),
+            processes: scheduler::processes(),
+            metrics: metrics::list(),
+        }
+    }
+
+    /// A sample with every counter at zero, to render totals since boot against.
+    pub fn boot() -> Sample {
+        Sample {
+            tsc: 0,
+            cpus: Vec::new(),
+            tasks: Vec::new(),
+            processes: Vec::new(),
+            metrics: Vec::new(),
+        }
+    }
+
+    fn cpu(&self, index: usize) -> Option<&CpuSample> {
+        self.cpus.iter().find(|cpu| cpu.index == index)
+    }
+
+    fn process(&self, pid: Pid) -> Option<&ProcessInfo> {
+        self.processes.iter().find(|info| info.pid == pid)
+    }
+}
+
+/// `part` as a percentage of `whole`, at most 100.
+fn percent(part: u64, whole: u64) -> u64 {
+    (part * 100).checked_div(whole).unwrap_or(0).min(100)
+}
+
+/// Prints what happened between `before` and `after`.
+pub fn render(before: &Sample, after: &Sample) {
+    let cycles = after.tsc - before.tsc;
+    let none = CpuSample::default();
+
+    println!("  CPU   busy  user   sys  switches");
+    for cpu in &after.cpus {
+        let was = before.cpu(cpu.index).unwrap_or(&none);
+        let idle = cpu.idle_cycles - was.idle_cycles;
+        println!(
+            "  cpu{:<2} {:>3}% {:>5} {:>5} {:>9}",
+            cpu.index,
+            100 - percent(idle, cycles),
+            cpu.sched.user_ticks - was.sched.user_ticks,
+            cpu.sched.system_ticks - was.sched.system_ticks,
+            cpu.sched.switches - was.sched.switches
+        );
+    }
+
+    let mut tasks: Vec<(u64, u64, &TaskInfo)> = after
+        .tasks
+        .iter()
+        .map(|task| {
+            let was = before.tasks.iter().find(|was| was.id == task.id);
+            let cycles = task.poll_cycles - was.map_or(0, |was| was.poll_cycles);
+            let polls = task.polls - was.map_or(0, |was| was.polls);
+            (cycles, polls, task)
+        })
+        .collect();
+    tasks.sort_by_key(|&(cycles, _, _)| core::cmp::Reverse(cycles));
+    println!("  TASK name         state    cpu  share    polls");
+    for (task_cycles, polls, task) in tasks {
+        println!(
+            "  {:>4} {:<12} {:<8} {:>3} {:>5}% {:>8}",
+            task.id,
+            task.name,
+            task.state.name(),
+            task.cpu,
+            percent(task_cycles, cycles),
+            polls
+        );
+    }
+
+    if !after.processes.is_empty() {
+        println!("   PID name         state    cpu  share");
+        for info in &after.processes {
+            let was = before.process(info.pid);
+            let ticks = info.user_ticks + info.system_ticks
+                - was.map_or(0, |was| was.user_ticks + was.system_ticks);
+            let cpu_ticks = after.cpu(info.cpu).map_or(0, |cpu| cpu.ticks)
+                - before.cpu(info.cpu).map_or(0, |cpu| cpu.ticks);
+            println!(
+                "  {:>4} {:<12} {:<8} {:>3} {:>5}%",
+                info.pid.0,
+                info.name,
+                info.state.name(),
+                info.cpu,
+                percent(ticks, cpu_ticks)
+            );
+        }
+    }
+
+    let mut counters: Vec<(u64, &str)> = after
+        .metrics
+        .iter()
+        .filter(|sample| sample.kind == Kind::Counter)
+        .map(|sample| {
+            let was = before
+                .metrics
+                .iter()
+                .find(|was| was.name == sample.name)
+                .map_or(0, |was| was.value);
+            ((sample.value - was) as u64, sample.name)
+        })
+        .filter(|&(delta, _)| delta > 0)
+        .collect();
+    counters.sort_by_key(|&(delta, _)| core::cmp::Reverse(delta));
+    for (delta, name) in counters.into_iter().take(MAX_COUNTERS) {
+        println!("  {:<24} +{}", name, delta);
+    }
+}
+
+#[test_case]
+fn test_percent() {
+    assert_eq!(percent(1, 4), 25);
+    assert_eq!(percent(5, 4), 100);
+    assert_eq!(percent(3, 0), 0);
+}
@@ -0,0 +1,381 @@
+//! Clocks with nanosecond timestamps, shared by kernel code and the `clock_gettime` system call.
+//!
+//! [`Clock::Monotonic`] counts from boot on the HPET, or on the hypervisor's paravirtual clock
+//! (see [`crate::pvclock`]). [`Clock::Realtime`] is the monotonic clock
+//! plus the wall-clock time at boot, which [`init`] reads from the CMOS real-time clock. Without
+//! an RTC the wall clock starts at the Unix epoch, as Linux's does.
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::interrupts;
+
+use crate::cmos::{self, RTC_STATUS_A, RTC_STATUS_B, RTC_STATUS_C};
+use crate::error::KernelError;
+use crate::init::hpet;
+use crate::task::sched;
+use crate::{info, kernel_acpi, kernel_init, timer};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+/// Clock identifiers. The values are the user ABI's and match Linux's `CLOCK_*` constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Clock {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+impl Clock {
+    pub fn from_id(id: u64) -> Option<Self> {
+        match id {
+            0 => Some(Clock::Realtime),
+            1 => Some(Clock::Monotonic),
+            _ => None,
+        }
+    }
+}
+
+/// A point in time on some clock, laid out like C's `struct timespec` on x86_64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[repr(C)]
+pub struct Timespec {
+    pub sec: i64,
+    /// Always below one second.
+    pub nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_nanos(ns: u64) -> Self {
+        Timespec {
+            sec: (ns / NS_PER_SEC) as i64,
+            nsec: (ns % NS_PER_SEC) as i64,
+        }
+    }
+
+    /// Nanoseconds since the clock's zero, or `None` before it or if the fields are out of
+    /// range.
+    pub fn as_nanos(self) -> Option<u64> {
+        if !(0..NS_PER_SEC as i64).contains(&self.nsec) {
+            return None;
+        }
+        u64::try_from(self.sec)
+            .ok()?
+            .checked_mul(NS_PER_SEC)?
+            .checked_add(self.nsec as u64)
+    }
+
+    /// The in-memory layout user programs see.
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.sec.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.nsec.to_le_bytes());
+        bytes
+    }
+}
+
+/// Realtime nanoseconds when the monotonic clock read zero.
+static BOOT_REALTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the wall-clock time at boot from the RTC. Call once a clock source is set up.
+pub fn init() {
+    let Some(secs) = rtc_now() else {
+        return;
+    };
+    set_realtime(Timespec {
+        sec: secs as i64,
+        nsec: 0,
+    });
+}
+
+/// Seconds since the Unix epoch on the CMOS RTC, or `None` if the machine has none.
+fn rtc_now() -> Option<u64> {
+    let fadt = kernel_acpi::registry().and_then(|registry| registry.fadt().ok());
+    if fadt.is_some_and(|fadt| !fadt.has_cmos_rtc) {
+        return None;
+    }
+    read_rtc(fadt.map_or(0, |fadt| fadt.century))
+}
+
+/// How far the realtime clock may drift from the RTC before [`resync`] steps it. The RTC only
+/// counts whole seconds.
+const RESYNC_SLACK_SECS: i64 = 2;
+/// How often the realtime clock is checked against the RTC.
+const RESYNC_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// Steps the realtime clock back to the RTC if the two have drifted apart by more than
+/// [`RESYNC_SLACK_SECS`], and returns by how many seconds, positive if the clock was behind.
+/// There is no network to ask for the time, so the RTC is the only reference.
+pub fn resync() -> Option<i64> {
+    let rtc = rtc_now()? as i64;
+    let drift = rtc - now(Clock::Realtime)?.sec;
+    if drift.abs() <= RESYNC_SLACK_SECS {
+        return Some(0);
+    }
+    set_realtime(Timespec { sec: rtc, nsec: 0 });
+    Some(drift)
+}
+
+kernel_init!(Late, "rtc-resync", init_stage);
+
+fn init_stage() -> Result<(), KernelError> {
+    sched::every(RESYNC_EVERY, "rtc resync", || {
+        if let Some(drift) = resync().filter(|&drift| drift != 0) {
+            info!("time: realtime clock stepped {}s to match the RTC", drift);
+        }
+    });
+    Ok(())
+}
+
+/// Returns the current time on `clock`, or `None` if there is no clock source yet.
+pub fn now(clock: Clock) -> Option<Timespec> {
+    let monotonic = timer::uptime_ns()?;
+    Some(Timespec::from_nanos(match clock {
+        Clock::Monotonic => monotonic,
+        Clock::Realtime => boot_realtime_ns().saturating_add(monotonic),
+    }))
+}
+
+/// Realtime nanoseconds when the monotonic clock read zero.
+pub fn boot_realtime_ns() -> u64 {
+    BOOT_REALTIME_NS.load(Ordering::Relaxed)
+}
+
+/// Returns how far apart consecutive readings of `clock` can be, or `None` if there is no clock
+/// source yet.
+pub fn resolution(_clock: Clock) -> Option<Timespec> {
+    if let Some(ns) = crate::pvclock::resolution_ns() {
+        return Some(Timespec::from_nanos(ns));
+    }
+    let period_fs = hpet::get()?.period_fs();
+    Some(Timespec::from_nanos(period_fs.div_ceil(1_000_000)))
+}
+
+/// Steps the realtime clock to `time`. Monotonic time is unaffected. Returns `false` if `time`
+/// is before the Unix epoch or there is no clock source yet.
+pub fn set_realtime(time: Timespec) -> bool {
+    let (Some(ns), Some(monotonic)) = (time.as_nanos(), timer::uptime_ns()) else {
+        return false;
+    };
+    BOOT_REALTIME_NS.store(ns.saturating_sub(monotonic), Ordering::Relaxed);
+    crate::vdso::update();
+    true
+}
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_ALARM_INTERRUPT: u8 = 0x20;
+const STATUS_C_ALARM: u8 = 0x20;
+const HOUR_PM: u8 = 0x80;
+const SECS_PER_DAY: u32 = 86_400;
+
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS_ALARM: u8 = 0x05;
+
+/// The RTC's date and time registers, in the order read.
+type RtcRegisters = [u8; 7];
+
+fn read_rtc_registers(century: u8) -> RtcRegisters {
+    while cmos::read(RTC_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    let mut registers = [0; 7];
+    for (value, register) in registers
+        .iter_mut()
+        .zip([0x00, 0x02, 0x04, 0x07, 0x08, 0x09])
+    {
+        *value = cmos::read(register);
+    }
+    if century != 0 {
+        registers[6] = cmos::read(century);
+    }
+    registers
+}
+
+/// Reads the RTC as seconds since the Unix epoch. `century` is the FADT's century register, or
+/// 0 to assume the 21st century.
+fn read_rtc(century: u8) -> Option<u64> {
+    let (registers, status_b) = interrupts::without_interrupts(|| {
+        // An update can land between reads, so read until two passes agree
+        let mut registers = read_rtc_registers(century);
+        loop {
+            let again = read_rtc_registers(century);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        (registers, cmos::read(RTC_STATUS_B))
+    });
+    rtc_to_unix(registers, status_b)
+}
+
+/// A register value in the RTC's format, BCD or binary as status register B says.
+fn decode(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+        value
+    } else {
+        (value >> 4) * 10 + (value & 0x0F)
+    }
+}
+
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 {
+        value
+    } else {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+/// An hours register as 0 to 23, whether the RTC keeps a 12 or a 24-hour clock.
+fn decode_hour(hour: u8, status_b: u8) -> u8 {
+    let pm = hour & HOUR_PM != 0;
+    let hour = decode(hour & !HOUR_PM, status_b);
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return hour;
+    }
+    // 12 AM is midnight and 12 PM is noon
+    hour % 12 + if pm { 12 } else { 0 }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return encode(hour, status_b);
+    }
+    let pm = if hour >= 12 { HOUR_PM } else { 0 };
+    let hour = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    encode(hour, status_b) | pm
+}
+
+/// Converts raw RTC registers (seconds, minutes, hours, day, month, year, century) to seconds
+/// since the Unix epoch.
+fn rtc_to_unix(registers: RtcRegisters, status_b: u8) -> Option<u64> {
+    let [sec, min, hour, day, month, year, century] = registers;
+    let decode = |value: u8| decode(value, status_b);
+    let hour = decode_hour(hour, status_b);
+    let century = if century != 0 { decode(century) } else { 20 };
+    let year = century as i64 * 100 + decode(year) as i64;
+    let (month, day) = (decode(month), decode(day));
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 {
+        return None;
+    }
+    let days = days_from_civil(year, month as i64, day as i64);
+    let secs = days * 86_400 + hour as i64 * 3600 + decode(min) as i64 * 60 + decode(sec) as i64;
+    u64::try_from(secs).ok()
+}
+
+/// The alarm registers (seconds, minutes, hours) for `after_secs` past the time of day in
+/// `now`, which holds the seconds, minutes and hours registers.
+fn alarm_registers(now: [u8; 3], status_b: u8, after_secs: u32) -> [u8; 3] {
+    let [sec, min, hour] = now;
+    let now = decode_hour(hour, status_b) as u32 * 3600
+        + decode(min, status_b) as u32 * 60
+        + decode(sec, status_b) as u32;
+    let at = (now + after_secs % SECS_PER_DAY) % SECS_PER_DAY;
+    [
+        encode((at % 60) as u8, status_b),
+        encode((at / 60 % 60) as u8, status_b),
+        encode_hour((at / 3600) as u8, status_b),
+    ]
+}
+
+/// Arms the RTC alarm to raise IRQ 8 `after_secs` seconds from now. The alarm matches the time
+/// of day only, so it can't be set further than a day ahead.
+pub fn set_rtc_alarm(after_secs: u32) {
+    interrupts::without_interrupts(|| {
+        let registers = read_rtc_registers(0);
+        let status_b = cmos::read(RTC_STATUS_B);
+        let alarm = alarm_registers(
+            [registers[0], registers[1], registers[2]],
+            status_b,
+            after_secs,
+        );
+        for (register, value) in [RTC_SECONDS_ALARM, RTC_MINUTES_ALARM, RTC_HOURS_ALARM]
+            .into_iter()
+            .zip(alarm)
+        {
+            cmos::write(register, value);
+        }
+        // Drops an alarm that went off earlier, which would otherwise keep IRQ 8 from firing
+        cmos::read(RTC_STATUS_C);
+        cmos::write(RTC_STATUS_B, status_b | STATUS_B_ALARM_INTERRUPT);
+    });
+}
+
+/// Disarms the RTC alarm.
+pub fn clear_rtc_alarm() {
+    interrupts::without_interrupts(|| {
+        let status_b = cmos::read(RTC_STATUS_B);
+        cmos::write(RTC_STATUS_B, status_b & !STATUS_B_ALARM_INTERRUPT);
+        cmos::read(RTC_STATUS_C);
+    });
+}
+
+/// Acknowledges an RTC interrupt, and returns whether the alarm raised it.
+pub fn ack_rtc_interrupt() -> bool {
+    cmos::read(RTC_STATUS_C) & STATUS_C_ALARM != 0
+}
+
+/// Days from 1970-01-01 to the given proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Counts from March so the leap day ends the year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[test_case]
+fn test_days_from_civil() {
+    assert_eq!(days_from_civil(1970, 1, 1), 0);
+    assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+    assert_eq!(days_from_civil(1969, 12, 31), -1);
+}
+
+#[test_case]
+fn test_rtc_to_unix() {
+    // 2024-02-29 13:05:09 in BCD with a 12-hour clock, so 1 PM
+    let registers = [0x09, 0x05, HOUR_PM | 0x01, 0x29, 0x02, 0x24, 0x20];
+    assert_eq!(rtc_to_unix(registers, 0), Some(1_709_211_909));
+    // The same in binary with a 24-hour clock and no century register
+    let registers = [9, 5, 13, 29, 2, 24, 0];
+    assert_eq!(
+        rtc_to_unix(registers, STATUS_B_BINARY | STATUS_B_24_HOUR),
+        Some(1_709_211_909)
+    );
+    assert_eq!(rtc_to_unix([0, 0, 0, 0, 1, 0, 0], STATUS_B_BINARY), None);
+}
+
+#[test_case]
+fn test_alarm_registers_wrap_at_midnight() {
+    // 11:59:50 PM in BCD with a 12-hour clock, and 15 seconds later is 12:00:05 AM
+    let now = [0x50, 0x59, HOUR_PM | 0x11];
+    assert_eq!(alarm_registers(now, 0, 15), [0x05, 0x00, 0x12]);
+    assert_eq!(alarm_registers(now, 0, 3600 + 10), [0x00, 0x00, 0x01]);
+    let binary = STATUS_B_BINARY | STATUS_B_24_HOUR;
+    assert_eq!(alarm_registers([50, 59, 23], binary, 15), [5, 0, 0]);
+    assert_eq!(alarm_registers([0, 30, 11], binary, 45 * 60), [0, 15, 12]);
+}
+
+#[test_case]
+fn test_timespec_round_trip() {
+    let time = Timespec::from_nanos(3 * NS_PER_SEC + 7);
+    assert_eq!(time, Timespec { sec: 3, nsec: 7 });
+    assert_eq!(time.as_nanos(), Some(3 * NS_PER_SEC + 7));
+    assert_eq!(Timespec { sec: -1, nsec: 0 }.as_nanos(), None);
+    assert_eq!(
+        Timespec {
+            sec: 0,
+            nsec: NS_PER_SEC as i64
+        }
+        .as_nanos(),
+        None
+    );
+}
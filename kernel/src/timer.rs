@@ -1,60 +1,202 @@
-use crate::{init::hpet::get_clock_tick_unit_fallback, println};
-
-/// Delay for the given number of milliseconds using HPET.
-/// Assumes the HPET registers are already mapped at `hpet_base`.
-///
-/// `clock_tick_unit` is given in femtoseconds (fs) per tick.
-pub unsafe fn delay_ms(hpet_base: *const u64, ms: u64) {
-    let clock_tick_unit = unsafe { get_clock_tick_unit_fallback(hpet_base) } as u32;
-    if clock_tick_unit == 0 {
-        panic!("HPET clock tick unit is still zero!");
-    }
-    println!("Using clock tick unit {}", clock_tick_unit);
-    let main_counter_ptr = unsafe { hpet_base.add(0xF0 / 8) };
-    let start = unsafe { core::ptr::read_volatile(main_counter_ptr) };
-
-    // 1 millisecond = 1e12 femtoseconds.
-    let delay_fs = ms * 1_000_000_000_000;
-    // Compute the number of ticks to wait.
-    let ticks_to_wait = delay_fs / clock_tick_unit as u64;
-    let target = start.wrapping_add(ticks_to_wait);
-
-    // Spin until the main counter reaches the target.
-    while unsafe { core::ptr::read_volatile(main_counter_ptr) } < target {
-        core::hint::spin_loop();
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu::{self, MAX_CPUS, PerCpu};
+use crate::init::hpet;
+
+/// Number of local APIC timer interrupts handled by the BSP since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupts handled by each CPU.
+static CPU_TICKS: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// Called by the APIC timer interrupt handler on every tick, on every CPU. The global tick
+/// count and the timeouts driven by it advance on the BSP only, so starting more cores
+/// doesn't make them run faster.
+pub fn on_tick() {
+    CPU_TICKS.get().fetch_add(1, Ordering::Relaxed);
+    if !cpu::is_bsp() {
+        return;
     }
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::ps2::tick(now);
+    crate::cpu::freq::on_tick();
+    crate::vdso::on_tick(now);
+    crate::task::keyboard::repeat_tick();
+    crate::task::sleep::wake_expired(now);
+    crate::watchdog::on_tick(now);
+}
+
+/// Returns the number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts CPU `index` has handled.
+pub fn cpu_ticks(index: usize) -> Option<u64> {
+    CPU_TICKS
+        .get_for(index)
+        .map(|ticks| ticks.load(Ordering::Relaxed))
 }
 
-pub unsafe fn delay_us(hpet_base: *const u64, us: u64) {
-    let clock_tick_unit = unsafe { get_clock_tick_unit_fallback(hpet_base) } as u32;
-    if clock_tick_unit == 0 {
-        panic!("HPET clock tick unit is still zero!");
+/// A free-running counter with a fixed period, e.g. the HPET. Timekeeping is written against
+/// this so it can be tested with a fake clock.
+pub trait Clock {
+    fn counter(&self) -> u64;
+
+    /// Femtoseconds per counter tick.
+    fn period_fs(&self) -> u64;
+
+    /// The counter converted to microseconds.
+    fn now_us(&self) -> u64 {
+        (self.counter() as u128 * self.period_fs() as u128 / FS_PER_US as u128) as u64
     }
-    println!("Using clock tick unit {}", clock_tick_unit);
-    let main_counter_ptr = unsafe { hpet_base.add(0xF0 / 8) };
-    let start = unsafe { core::ptr::read_volatile(main_counter_ptr) };
 
-    // 1 microsecond = 1 billion femtoseconds.
-    let delay_fs = us * 1000000000;
-    // Compute the number of ticks to wait.
-    let ticks_to_wait = delay_fs / clock_tick_unit as u64;
-    let target = start.wrapping_add(ticks_to_wait);
+    /// The counter converted to nanoseconds.
+    fn now_ns(&self) -> u64 {
+        (self.counter() as u128 * self.period_fs() as u128 / FS_PER_NS as u128) as u64
+    }
+}
+
+const FS_PER_US: u64 = 1_000_000_000;
+const FS_PER_NS: u64 = 1_000_000;
+
+/// Returns the time since boot in microseconds, or `None` if there is no clock yet. This is
+/// the HPET, or the hypervisor's paravirtual clock once [`crate::pvclock`] has registered one.
+pub fn uptime_us() -> Option<u64> {
+    uptime_ns().map(|ns| ns / 1000)
+}
+
+/// Like [`uptime_us`], in nanoseconds.
+pub fn uptime_ns() -> Option<u64> {
+    crate::pvclock::uptime_ns().or_else(|| hpet::get().map(|hpet| hpet.now_ns()))
+}
 
-    // Spin until the main counter reaches the target.
-    while unsafe { core::ptr::read_volatile(main_counter_ptr) } < target {
+/// Sleeps for at least `us` microseconds, idling the CPU with interrupts enabled in between
+/// timer ticks. Returns `false` without sleeping if the HPET has not been set up.
+pub fn sleep_us(us: u64) -> bool {
+    let Some(start) = uptime_us() else {
+        return false;
+    };
+    while uptime_us().is_some_and(|now| now - start < us) {
+        crate::cpu::idle::enable_and_idle();
+    }
+    true
+}
+
+/// Spins for at least `us` microseconds on `clock`.
+pub fn spin_us_on(clock: &impl Clock, us: u64) {
+    let ticks = (us as u128 * FS_PER_US as u128).div_ceil(clock.period_fs() as u128) as u64;
+    let start = clock.counter();
+    while clock.counter().wrapping_sub(start) < ticks {
         core::hint::spin_loop();
     }
 }
 
-/// Returns the current time in microseconds using the HPET.
-/// `hpet_base` is a pointer to the mapped HPET registers.
-pub unsafe fn get_current_time_us(hpet_base: *const u64) -> u64 {
-    let clock_tick_unit = unsafe { get_clock_tick_unit_fallback(hpet_base) } as u64;
-    if clock_tick_unit == 0 {
-        panic!("HPET clock tick unit is zero!");
+/// Spins for at least `us` microseconds on the HPET, without enabling interrupts. Returns
+/// `false` without waiting if the HPET has not been set up.
+pub fn delay_us(us: u64) -> bool {
+    match hpet::get() {
+        Some(hpet) => {
+            spin_us_on(hpet, us);
+            true
+        }
+        None => false,
     }
-    let main_counter_ptr = unsafe { hpet_base.add(0xF0 / 8) };
-    let ticks = unsafe { core::ptr::read_volatile(main_counter_ptr) };
-    // 1 microsecond = 1_000_000_000 femtoseconds.
-    (ticks * clock_tick_unit) / 1_000_000_000
+}
+
+/// Like [`delay_us`], in milliseconds.
+pub fn delay_ms(ms: u64) -> bool {
+    delay_us(ms * 1000)
+}
+
+/// A time by which something has to happen, for waits that poll rather than sleep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at_us: u64,
+}
+
+impl Deadline {
+    /// `us` microseconds from now, on [`uptime_us`]. Without a clock it has already passed.
+    pub fn after_us(us: u64) -> Self {
+        Deadline {
+            at_us: uptime_us().map_or(0, |now| now.saturating_add(us)),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        uptime_us().is_none_or(|now| now >= self.at_us)
+    }
+
+    /// Spins until `done` returns `true` or the deadline passes, and returns whether `done`
+    /// did. `done` is always checked at least once.
+    pub fn spin_until(&self, mut done: impl FnMut() -> bool) -> bool {
+        loop {
+            if done() {
+                return true;
+            }
+            if self.expired() {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Advances by `step` ticks every time it is read.
+#[cfg(test)]
+struct FakeClock {
+    now: core::cell::Cell<u64>,
+    step: u64,
+    period_fs: u64,
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn counter(&self) -> u64 {
+        let now = self.now.get();
+        self.now.set(now.wrapping_add(self.step));
+        now
+    }
+
+    fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+}
+
+#[test_case]
+fn test_spin_waits_long_enough() {
+    // 10 MHz, like QEMU's HPET
+    let clock = FakeClock {
+        now: core::cell::Cell::new(u64::MAX - 5),
+        step: 3,
+        period_fs: 100_000_000,
+    };
+    spin_us_on(&clock, 2);
+    // 20 ticks are needed, read 3 at a time across the wrap, plus the final read
+    assert!(clock.now.get().wrapping_sub(u64::MAX - 5) >= 20);
+    assert!(clock.now.get().wrapping_sub(u64::MAX - 5) <= 27);
+}
+
+#[test_case]
+fn test_deadline_checks_before_giving_up() {
+    let passed = Deadline::after_us(0);
+    assert!(passed.expired());
+    assert!(passed.spin_until(|| true));
+    let mut polls = 0;
+    assert!(!passed.spin_until(|| {
+        polls += 1;
+        false
+    }));
+    assert_eq!(polls, 1);
+}
+
+#[test_case]
+fn test_now_us_does_not_overflow() {
+    let clock = FakeClock {
+        now: core::cell::Cell::new(1 << 40),
+        step: 0,
+        period_fs: 100_000_000,
+    };
+    // About 30 hours at 10 MHz
+    assert_eq!(clock.now_us(), (1u64 << 40) / 10);
 }
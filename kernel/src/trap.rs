@@ -0,0 +1,246 @@
+//! Full register state for kernel entries that may switch to a different context.
+//!
+//! The `x86-interrupt` calling convention only exposes the interrupt stack frame. Entries that
+//! need the general-purpose registers as well, such as system calls, the scheduler tick and
+//! the exceptions that are fatal in the kernel, go through assembly stubs that push them into a
+//! [`TrapFrame`] instead. A fatal exception records its frame with [`set_fault_frame`], so the
+//! crash dump and a debugger can show the registers it happened with.
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::cpu::{MAX_CPUS, PerCpu};
+
+/// Saved register state, in the order the entry stubs push it. The tail (`rip` to `ss`) is the
+/// frame the CPU pushes on an interrupt, so `iretq` can return through it directly.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Whether the frame was saved while running in ring 3.
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+/// The registers, four to a line.
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = [
+            [
+                ("rax", self.rax),
+                ("rbx", self.rbx),
+                ("rcx", self.rcx),
+                ("rdx", self.rdx),
+            ],
+            [
+                ("rsi", self.rsi),
+                ("rdi", self.rdi),
+                ("rbp", self.rbp),
+                ("rsp", self.rsp),
+            ],
+            [
+                ("r8", self.r8),
+                ("r9", self.r9),
+                ("r10", self.r10),
+                ("r11", self.r11),
+            ],
+            [
+                ("r12", self.r12),
+                ("r13", self.r13),
+                ("r14", self.r14),
+                ("r15", self.r15),
+            ],
+        ];
+        for row in rows {
+            for (name, value) in row {
+                write!(f, "  {:<3} {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        write!(
+            f,
+            "  rip {:#018x}  rflags {:#x}  cs {:#x}  ss {:#x}",
+            self.rip, self.rflags, self.cs, self.ss
+        )
+    }
+}
+
+/// The frame of the fatal exception each CPU is handling, or null.
+static FAULT_FRAME: PerCpu<AtomicPtr<TrapFrame>> =
+    PerCpu::new([const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS]);
+
+/// Records `frame` as the one this CPU faulted with. Only for handlers that never return, as
+/// the frame has to stay where it is.
+pub(crate) fn set_fault_frame(frame: &TrapFrame) {
+    FAULT_FRAME
+        .get()
+        .store(ptr::from_ref(frame).cast_mut(), Ordering::Release);
+}
+
+/// The registers of the fatal exception this CPU is handling, if it is handling one.
+pub fn fault_frame() -> Option<TrapFrame> {
+    unsafe { FAULT_FRAME.get().load(Ordering::Acquire).as_ref() }.copied()
+}
+
+/// Assembly that pushes the general-purpose registers in [`TrapFrame`] order. The
+/// `without_rax` form pushes all but the first, for stubs that store RAX themselves.
+macro_rules! push_gprs {
+    () => {
+        concat!("push rax\n", $crate::trap::push_gprs!(without_rax))
+    };
+    (without_rax) => {
+        concat!(
+            "push rbx\n",
+            "push rcx\n",
+            "push rdx\n",
+            "push rsi\n",
+            "push rdi\n",
+            "push rbp\n",
+            "push r8\n",
+            "push r9\n",
+            "push r10\n",
+            "push r11\n",
+            "push r12\n",
+            "push r13\n",
+            "push r14\n",
+            "push r15\n",
+        )
+    };
+}
+
+/// Assembly that pops the registers pushed by [`push_gprs`].
+macro_rules! pop_gprs {
+    () => {
+        concat!(
+            "pop r15\n",
+            "pop r14\n",
+            "pop r13\n",
+            "pop r12\n",
+            "pop r11\n",
+            "pop r10\n",
+            "pop r9\n",
+            "pop r8\n",
+            "pop rbp\n",
+            "pop rdi\n",
+            "pop rsi\n",
+            "pop rdx\n",
+            "pop rcx\n",
+            "pop rbx\n",
+            "pop rax\n",
+        )
+    };
+}
+
+/// Call frame information for a stub that has just pushed the registers with [`push_gprs`]. The
+/// stub's caller, as an unwinder sees it, is the interrupted code, and every one of its
+/// registers is in the [`TrapFrame`]. The CFA is where the CPU's part of the frame starts.
+macro_rules! cfi_trap_frame {
+    () => {
+        concat!(
+            ".cfi_def_cfa rsp, 120\n",
+            ".cfi_offset rax, -8\n",
+            ".cfi_offset rbx, -16\n",
+            ".cfi_offset rcx, -24\n",
+            ".cfi_offset rdx, -32\n",
+            ".cfi_offset rsi, -40\n",
+            ".cfi_offset rdi, -48\n",
+            ".cfi_offset rbp, -56\n",
+            ".cfi_offset r8, -64\n",
+            ".cfi_offset r9, -72\n",
+            ".cfi_offset r10, -80\n",
+            ".cfi_offset r11, -88\n",
+            ".cfi_offset r12, -96\n",
+            ".cfi_offset r13, -104\n",
+            ".cfi_offset r14, -112\n",
+            ".cfi_offset r15, -120\n",
+            // DWARF register 16 is the return address, here the interrupted RIP
+            ".cfi_offset 16, 0\n",
+            ".cfi_offset rsp, 24\n",
+        )
+    };
+}
+
+/// Defines an interrupt entry point `$name`. It saves the registers into a [`TrapFrame`], calls
+/// `$handler(&mut TrapFrame)` and returns with `iretq` through whatever the handler left in the
+/// frame. For exceptions that push an error code, add `, error_code`: the handler then takes
+/// the code as a second `u64` argument. The stub is described in `.eh_frame` as a signal frame,
+/// so backtraces unwind through it into the interrupted code.
+macro_rules! trap_stub {
+    ($name:ident => $handler:path, error_code) => {
+        #[unsafe(naked)]
+        pub(crate) unsafe extern "C" fn $name() {
+            core::arch::naked_asm!(
+                ".cfi_startproc simple",
+                ".cfi_signal_frame",
+                // Swap RAX into the error code's slot, which is where the frame keeps it
+                "xchg rax, [rsp]",
+                $crate::trap::push_gprs!(without_rax),
+                $crate::trap::cfi_trap_frame!(),
+                "mov rdi, rsp",
+                "mov rsi, rax",
+                "call {handler}",
+                $crate::trap::pop_gprs!(),
+                "iretq",
+                ".cfi_endproc",
+                handler = sym $handler,
+            );
+        }
+    };
+    ($name:ident => $handler:path) => {
+        #[unsafe(naked)]
+        pub(crate) unsafe extern "C" fn $name() {
+            core::arch::naked_asm!(
+                ".cfi_startproc simple",
+                ".cfi_signal_frame",
+                $crate::trap::push_gprs!(),
+                $crate::trap::cfi_trap_frame!(),
+                "mov rdi, rsp",
+                "call {handler}",
+                $crate::trap::pop_gprs!(),
+                "iretq",
+                ".cfi_endproc",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+pub(crate) use {cfi_trap_frame, pop_gprs, push_gprs, trap_stub};
+
+#[test_case]
+fn test_trap_frame_shows_every_register() {
+    let frame = TrapFrame {
+        rax: 0xA,
+        r15: 0xF15,
+        rip: 0xFFFF_8000_0000_1000,
+        ..TrapFrame::default()
+    };
+    let text = alloc::format!("{}", frame);
+    assert!(text.starts_with("  rax 0x000000000000000a"));
+    assert!(text.contains("  r15 0x0000000000000f15\n"));
+    assert!(text.contains("rip 0xffff800000001000"));
+    assert_eq!(text.lines().count(), 5);
+}
@@ -0,0 +1,311 @@
+//! The terminal behind `/dev/console`: a line discipline between keyboard input and the
+//! processes reading the console.
+//!
+//! In canonical mode, the default, input is collected a line at a time and can be edited
+//! before it is handed over: backspace erases a character, ^W the last word and ^U the whole
+//! line, and ^D hands the line over without a newline, so on an empty line a read returns 0,
+//! end of file. Raw mode hands every byte over as it arrives. Typed characters are echoed to
+//! the screen through [`console::echo`] unless echo is off, so log output doesn't land in the
+//! middle of a line being typed. Processes switch modes with the [`TTY_GET_MODE`] and
+//! [`TTY_SET_MODE`] ioctls.
+//!
+//! Keys reach the terminal while it holds input focus, which Alt+F1..F12 moves between the
+//! terminal, the monitor and the other focusable consumers.
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+
+use futures_util::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::console;
+use crate::fs::FsError;
+use crate::process::scheduler;
+use crate::task::input::{self, Route};
+
+/// Input is collected into lines and can be edited.
+pub const MODE_CANONICAL: u32 = 1 << 0;
+/// Typed characters are echoed.
+pub const MODE_ECHO: u32 = 1 << 1;
+pub const DEFAULT_MODE: u32 = MODE_CANONICAL | MODE_ECHO;
+
+/// `ioctl` request for the mode, answered as a `u32`.
+pub const TTY_GET_MODE: u64 = 0x5401;
+/// `ioctl` request setting the mode to the argument.
+pub const TTY_SET_MODE: u64 = 0x5402;
+
+/// Longest line canonical mode collects; further characters are dropped until it ends.
+const MAX_LINE: usize = 1024;
+/// Input held for readers; more is dropped until they catch up.
+const MAX_READY: usize = 4096;
+
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const DELETE: u8 = 0x7F;
+
+pub struct LineDiscipline {
+    mode: u32,
+    /// The line being edited, in canonical mode.
+    line: Vec<u8>,
+    /// Input waiting to be read, in canonical mode a line per entry. An empty entry is an end
+    /// of file.
+    ready: VecDeque<Vec<u8>>,
+    ready_bytes: usize,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> Self {
+        LineDiscipline {
+            mode: DEFAULT_MODE,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+            ready_bytes: 0,
+        }
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Switches to `mode`. Leaving canonical mode hands over the line being edited as it is.
+    pub fn set_mode(&mut self, mode: u32) {
+        if mode & MODE_CANONICAL == 0 && !self.line.is_empty() {
+            let line = core::mem::take(&mut self.line);
+            self.push_ready(line);
+        }
+        self.mode = mode;
+    }
+
+    /// Takes one byte of input, adding what to echo for it to `echo`.
+    pub fn input(&mut self, byte: u8, echo: &mut Vec<u8>) {
+        let echoing = self.mode & MODE_ECHO != 0;
+        if self.mode & MODE_CANONICAL == 0 {
+            if echoing {
+                echo_byte(byte, echo);
+            }
+            self.push_raw(byte);
+            return;
+        }
+        let mut erased = 0;
+        match byte {
+            BACKSPACE | DELETE => erased = self.erase_char(),
+            CTRL_U => {
+                while !self.line.is_empty() {
+                    erased += self.erase_char();
+                }
+            }
+            CTRL_W => {
+                while self.line.last() == Some(&b' ') {
+                    erased += self.erase_char();
+                }
+                while self.line.last().is_some_and(|&byte| byte != b' ') {
+                    erased += self.erase_char();
+                }
+            }
+            CTRL_D => {
+                let line = core::mem::take(&mut self.line);
+                self.push_ready(line);
+            }
+            b'\n' | b'\r' => {
+                self.line.push(b'\n');
+                if echoing {
+                    echo.push(b'\n');
+                }
+                let line = core::mem::take(&mut self.line);
+                self.push_ready(line);
+            }
+            _ if self.line.len() < MAX_LINE => {
+                self.line.push(byte);
+                if echoing {
+                    echo_byte(byte, echo);
+                }
+            }
+            _ => {}
+        }
+        if echoing {
+            for _ in 0..erased {
+                echo.extend_from_slice(b"\x08 \x08");
+            }
+        }
+    }
+
+    /// Removes the last character of the line, and returns how many columns its echo took.
+    fn erase_char(&mut self) -> usize {
+        // Continuation bytes of a UTF-8 sequence, then the byte that starts it
+        while self.line.last().is_some_and(|&byte| byte & 0xC0 == 0x80) {
+            self.line.pop();
+        }
+        match self.line.pop() {
+            None => 0,
+            Some(byte) if is_control(byte) => 2,
+            Some(_) => 1,
+        }
+    }
+
+    fn push_ready(&mut self, line: Vec<u8>) {
+        if self.ready_bytes + line.len() <= MAX_READY {
+            self.ready_bytes += line.len();
+            self.ready.push_back(line);
+        }
+    }
+
+    fn push_raw(&mut self, byte: u8) {
+        if self.ready_bytes >= MAX_READY {
+            return;
+        }
+        self.ready_bytes += 1;
+        match self.ready.back_mut() {
+            Some(chunk) if !chunk.is_empty() => chunk.push(byte),
+            _ => self.ready.push_back(alloc::vec![byte]),
+        }
+    }
+
+    /// Moves input into `buf`, in canonical mode no more than one line. Returns `None` if
+    /// there is nothing to read yet, and `Some(0)` at an end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let chunk = self.ready.front_mut()?;
+        let len = buf.len().min(chunk.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        chunk.drain(..len);
+        if chunk.is_empty() {
+            self.ready.pop_front();
+        }
+        self.ready_bytes -= len;
+        Some(len)
+    }
+}
+
+impl Default for LineDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Control characters, which are echoed as `^X`.
+fn is_control(byte: u8) -> bool {
+    (byte < 0x20 && byte != b'\t') || byte == DELETE
+}
+
+fn echo_byte(byte: u8, echo: &mut Vec<u8>) {
+    if byte == b'\n' || !is_control(byte) {
+        echo.push(byte);
+    } else {
+        echo.extend_from_slice(&[b'^', byte ^ 0x40]);
+    }
+}
+
+static TTY: Mutex<LineDiscipline> = Mutex::new(LineDiscipline::new());
+
+/// The wait channel of processes waiting for input.
+fn channel() -> usize {
+    &TTY as *const _ as usize
+}
+
+fn with_tty<R>(f: impl FnOnce(&mut LineDiscipline) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut TTY.lock()))
+}
+
+/// Reads input for `/dev/console`. Fails with [`FsError::WouldBlock`] until there is some.
+pub fn read(buf: &mut [u8]) -> Result<usize, FsError> {
+    with_tty(|tty| tty.read(buf)).ok_or(FsError::WouldBlock(channel()))
+}
+
+/// Carries out an `ioctl` on `/dev/console`; see [`crate::fs::Node::ioctl`].
+pub fn ioctl(request: u64, arg: u64, answer: &mut [u8]) -> Result<usize, FsError> {
+    match request {
+        TTY_GET_MODE => {
+            answer[..4].copy_from_slice(&with_tty(|tty| tty.mode()).to_le_bytes());
+            Ok(4)
+        }
+        TTY_SET_MODE => {
+            if arg & !(DEFAULT_MODE as u64) != 0 {
+                return Err(FsError::InvalidArgument);
+            }
+            with_tty(|tty| tty.set_mode(arg as u32));
+            // A line handed over by leaving canonical mode can be read now
+            scheduler::wake(channel());
+            Ok(0)
+        }
+        _ => Err(FsError::NotSupported),
+    }
+}
+
+/// Passes `bytes` through the line discipline as if they were typed, and wakes processes
+/// waiting for input.
+pub fn feed(bytes: &[u8]) {
+    let mut echo = Vec::new();
+    with_tty(|tty| {
+        for &byte in bytes {
+            tty.input(byte, &mut echo);
+        }
+    });
+    if !echo.is_empty() {
+        console::echo(&String::from_utf8_lossy(&echo));
+    }
+    scheduler::wake(channel());
+}
+
+/// Feeds the keys typed while the terminal holds input focus into the line discipline. Ctrl
+/// with a letter gives the matching control character.
+pub async fn run() {
+    let mut events = input::subscribe("tty", Route::Focused);
+    let mut ctrl_held = false;
+    while let Some(event) = events.next().await {
+        if matches!(event.code, KeyCode::LControl | KeyCode::RControl) {
+            ctrl_held = event.state != KeyState::Up;
+            continue;
+        }
+        let Some(DecodedKey::Unicode(c)) = event.key else {
+            continue;
+        };
+        let mut utf8 = [0; 4];
+        match c {
+            'a'..='z' | 'A'..='Z' if ctrl_held => feed(&[c.to_ascii_uppercase() as u8 ^ 0x40]),
+            c => feed(c.encode_utf8(&mut utf8).as_bytes()),
+        }
+    }
+}
+
+#[test_case]
+fn test_canonical_line_editing() {
+    let mut tty = LineDiscipline::new();
+    let mut echo = Vec::new();
+    for &byte in b"lx\x08s -l\x17-a\n" {
+        tty.input(byte, &mut echo);
+    }
+    assert_eq!(echo, b"lx\x08 \x08s -l\x08 \x08\x08 \x08-a\n");
+
+    let mut buf = [0u8; 16];
+    assert_eq!(tty.read(&mut buf), Some(6));
+    assert_eq!(&buf[..6], b"ls -a\n");
+    assert_eq!(tty.read(&mut buf), None);
+
+    // A line is only handed over once it ends, and ^D on an empty one is an end of file
+    for &byte in b"\xC3\xA9\x08no\x04\x04" {
+        tty.input(byte, &mut echo);
+    }
+    assert_eq!(tty.read(&mut buf), Some(2));
+    assert_eq!(&buf[..2], b"no");
+    assert_eq!(tty.read(&mut buf), Some(0));
+    assert_eq!(tty.read(&mut buf), None);
+}
+
+#[test_case]
+fn test_raw_mode_without_echo() {
+    let mut tty = LineDiscipline::new();
+    let mut echo = Vec::new();
+    tty.input(b'a', &mut echo);
+    tty.set_mode(0);
+    for &byte in b"b\x08\x03" {
+        tty.input(byte, &mut echo);
+    }
+    assert_eq!(echo, b"a");
+
+    // The line being edited went over with the switch
+    let mut buf = [0u8; 16];
+    assert_eq!(tty.read(&mut buf), Some(4));
+    assert_eq!(&buf[..4], b"ab\x08\x03");
+    assert_eq!(tty.read(&mut buf), None);
+}
@@ -0,0 +1,174 @@
+//! Mode setting through the Bochs "dispi" display interface, which QEMU's `-vga std` (and
+//! Bochs, VirtualBox's VBoxVGA) implement.
+//!
+//! The interface is a handful of 16-bit registers behind an index/data port pair. Setting a mode
+//! reprograms the resolution and depth in place; the linear framebuffer stays at the same
+//! physical address, so the driver only has to make sure enough of it is mapped and hand the new
+//! geometry to the [`FrameBufferWriter`](crate::framebuffer::FrameBufferWriter), after which
+//! the console redraws its text to fit.
+use bootloader_api::info::PixelFormat;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::console;
+use crate::memory::{self, PhysMapError};
+
+const INDEX_PORT: u16 = 0x01CE;
+const DATA_PORT: u16 = 0x01CF;
+
+const REG_ID: u16 = 0x0;
+const REG_XRES: u16 = 0x1;
+const REG_YRES: u16 = 0x2;
+const REG_BPP: u16 = 0x3;
+const REG_ENABLE: u16 = 0x4;
+const REG_VIRT_WIDTH: u16 = 0x6;
+const REG_X_OFFSET: u16 = 0x8;
+const REG_Y_OFFSET: u16 = 0x9;
+const REG_VIDEO_MEMORY_64K: u16 = 0xA;
+
+const ENABLE_DISPLAY: u16 = 0x01;
+const ENABLE_LFB: u16 = 0x40;
+
+/// Interface versions. Anything older than `ID_MIN` lacks the linear framebuffer; `ID_VRAM`
+/// added the video memory size register.
+const ID_MIN: u16 = 0xB0C2;
+const ID_VRAM: u16 = 0xB0C5;
+const ID_MAX: u16 = 0xB0CF;
+
+/// Video memory assumed when the interface can't report it.
+const DEFAULT_VRAM: usize = 8 * 1024 * 1024;
+
+const MAX_WIDTH: u16 = 4096;
+const MAX_HEIGHT: u16 = 4096;
+
+#[derive(Debug)]
+pub enum VbeError {
+    /// No Bochs-compatible display adapter.
+    NotPresent,
+    /// The framebuffer writer isn't set up, so there's nothing to switch.
+    NoFramebuffer,
+    /// Only 24 and 32 bits per pixel can be drawn by the framebuffer writer.
+    UnsupportedDepth,
+    /// The mode is out of range or doesn't fit in video memory.
+    TooLarge,
+    /// The adapter didn't take the mode.
+    Rejected,
+    Map(PhysMapError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u16,
+}
+
+fn read(reg: u16) -> u16 {
+    unsafe {
+        Port::<u16>::new(INDEX_PORT).write(reg);
+        Port::<u16>::new(DATA_PORT).read()
+    }
+}
+
+fn write(reg: u16, value: u16) {
+    unsafe {
+        Port::<u16>::new(INDEX_PORT).write(reg);
+        Port::<u16>::new(DATA_PORT).write(value);
+    }
+}
+
+/// Returns the interface version if a Bochs-compatible adapter with a linear framebuffer is
+/// present.
+pub fn detect() -> Option<u16> {
+    let id = read(REG_ID);
+    (ID_MIN..=ID_MAX).contains(&id).then_some(id)
+}
+
+/// Bytes of video memory on the adapter.
+pub fn video_memory() -> Option<usize> {
+    let id = detect()?;
+    if id >= ID_VRAM {
+        Some(read(REG_VIDEO_MEMORY_64K) as usize * 64 * 1024)
+    } else {
+        Some(DEFAULT_VRAM)
+    }
+}
+
+/// The mode the adapter is currently in.
+pub fn current_mode() -> Option<Mode> {
+    detect()?;
+    Some(Mode {
+        width: read(REG_XRES),
+        height: read(REG_YRES),
+        bpp: read(REG_BPP),
+    })
+}
+
+/// Switches the display to `mode` and points the framebuffer writer at the new geometry. The
+/// console is redrawn to fit.
+pub fn set_mode(mode: Mode) -> Result<(), VbeError> {
+    detect().ok_or(VbeError::NotPresent)?;
+    if mode.bpp != 24 && mode.bpp != 32 {
+        return Err(VbeError::UnsupportedDepth);
+    }
+    if mode.width == 0 || mode.height == 0 || mode.width > MAX_WIDTH || mode.height > MAX_HEIGHT {
+        return Err(VbeError::TooLarge);
+    }
+    let bytes_per_pixel = mode.bpp as usize / 8;
+    let byte_len = mode.width as usize * mode.height as usize * bytes_per_pixel;
+    if byte_len > video_memory().unwrap_or(DEFAULT_VRAM) {
+        return Err(VbeError::TooLarge);
+    }
+
+    // Hold the writer for the whole switch so nothing draws with the old geometry
+    console::with_framebuffer(|writer| {
+        let phys = framebuffer_phys(writer.buffer_start()).ok_or(VbeError::NoFramebuffer)?;
+        let buffer = map_framebuffer(phys, byte_len)?;
+
+        write(REG_ENABLE, 0);
+        write(REG_XRES, mode.width);
+        write(REG_YRES, mode.height);
+        write(REG_BPP, mode.bpp);
+        write(REG_VIRT_WIDTH, mode.width);
+        write(REG_X_OFFSET, 0);
+        write(REG_Y_OFFSET, 0);
+        write(REG_ENABLE, ENABLE_DISPLAY | ENABLE_LFB);
+
+        let set = Mode {
+            width: read(REG_XRES),
+            height: read(REG_YRES),
+            bpp: read(REG_BPP),
+        };
+        if set != mode {
+            return Err(VbeError::Rejected);
+        }
+
+        let mut info = writer.info();
+        info.byte_len = byte_len;
+        info.width = mode.width as usize;
+        info.height = mode.height as usize;
+        // The adapter stores pixels as little-endian 0x00RRGGBB, i.e. blue first
+        info.pixel_format = PixelFormat::Bgr;
+        info.bytes_per_pixel = bytes_per_pixel;
+        info.stride = mode.width as usize;
+        writer.replace_buffer(buffer, info);
+        Ok(())
+    })
+    .ok_or(VbeError::NoFramebuffer)?
+}
+
+/// Finds the physical address behind the framebuffer mapping the bootloader set up.
+fn framebuffer_phys(start: VirtAddr) -> Option<PhysAddr> {
+    let guard = PAGE_ALLOCATOR.lock();
+    guard.as_ref()?.mapper.translate_addr(start)
+}
+
+/// Returns `len` bytes of the framebuffer at `phys` through the physical memory mapping, mapping
+/// any pages of it the bootloader left out (it maps RAM, not device memory).
+fn map_framebuffer(phys: PhysAddr, len: usize) -> Result<&'static mut [u8], VbeError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let virt = memory::map_physical(phys, len as u64, flags).map_err(VbeError::Map)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr(), len) })
+}
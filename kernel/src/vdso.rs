@@ -0,0 +1,259 @@
+//! The vDSO: a read-only page of timekeeping data the kernel keeps up to date and a page of
+//! code that reads it, mapped into every process so `clock_gettime` needs no system call.
+//!
+//! The data page at [`DATA_ADDR`] holds an anchor, a TSC reading and the monotonic time at it,
+//! and the TSC period. The code at [`CLOCK_GETTIME_ADDR`] scales the TSC ticks since the anchor,
+//! and makes the system call instead while the page isn't valid: before [`init`], or when the
+//! TSC doesn't tick at a constant rate. Like [`crate::pvclock`], it assumes every CPU's TSC
+//! agrees with the BSP's.
+//!
+//! The BSP moves the anchor every [`UPDATE_TICKS`] timer ticks and refines the period from the
+//! interval since the last one. A new anchor never goes behind where the old one extrapolated
+//! to, so readings stay monotonic across updates, at the cost of running ahead of the system
+//! call by the calibration error over one interval.
+use core::arch::{global_asm, x86_64::__cpuid};
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB, mapper::MapToError};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::cpu::{self, rdtsc};
+use crate::interrupts::APIC_TIMER_HZ;
+use crate::memory::PAGE_SIZE;
+use crate::process::address_space::{AddressSpace, translate_phys_active};
+use crate::syscall::nr;
+use crate::time::{self, Clock};
+
+/// Where the data page is mapped, just above the range `mmap` uses.
+pub const DATA_ADDR: u64 = 0x0000_7F00_0000_0000;
+/// Where the code page is mapped.
+pub const CODE_ADDR: u64 = DATA_ADDR + PAGE_SIZE;
+/// `clock_gettime(clock, ts)` with the C calling convention, returning 0 or a negated errno
+/// like the system call.
+pub const CLOCK_GETTIME_ADDR: u64 = CODE_ADDR;
+
+/// Timer ticks between updates of the anchor.
+pub const UPDATE_TICKS: u64 = APIC_TIMER_HZ as u64;
+
+const CPUID_EXT_POWER: u32 = 0x8000_0007;
+const CPUID_EXT_POWER_EDX_INVARIANT_TSC: u32 = 1 << 8;
+const NS_PER_SEC: u64 = 1_000_000_000;
+/// Shortest interval the period is refined over; updates closer together only move the anchor.
+const MIN_CALIBRATION_NS: u64 = NS_PER_SEC / 2;
+
+/// The data page. User code reads `seq`, then the fields, then `seq` again, and starts over
+/// if it was odd or has changed.
+#[repr(C, align(4096))]
+struct TimePage {
+    /// Odd while the kernel is updating the page.
+    seq: AtomicU32,
+    /// Nonzero once the rest of the page can be used.
+    valid: AtomicU32,
+    tsc_base: AtomicU64,
+    /// Monotonic nanoseconds at `tsc_base`.
+    ns_base: AtomicU64,
+    /// Nanoseconds per TSC tick, as a 32.32 fixed-point number.
+    mult: AtomicU64,
+    /// Realtime nanoseconds when the monotonic clock read zero.
+    realtime_offset: AtomicU64,
+}
+
+static TIME_PAGE: TimePage = TimePage {
+    seq: AtomicU32::new(0),
+    valid: AtomicU32::new(0),
+    tsc_base: AtomicU64::new(0),
+    ns_base: AtomicU64::new(0),
+    mult: AtomicU64::new(0),
+    realtime_offset: AtomicU64::new(0),
+};
+
+/// The code page. It holds nothing but the stub, since user code can read all of it.
+#[repr(C, align(4096))]
+struct CodePage(UnsafeCell<[u8; PAGE_SIZE as usize]>);
+
+unsafe impl Sync for CodePage {}
+
+static CODE_PAGE: CodePage = CodePage(UnsafeCell::new([0; PAGE_SIZE as usize]));
+/// Set once the stub has been copied into [`CODE_PAGE`].
+static CODE_READY: Once<()> = Once::new();
+
+global_asm!(
+    ".section .rodata.vdso, \"a\"",
+    ".global vdso_clock_gettime_start",
+    ".global vdso_clock_gettime_end",
+    "vdso_clock_gettime_start:",
+    "    mov r8, {data}",
+    "    cmp rdi, {monotonic}",
+    "    je 1f",
+    "    cmp rdi, {realtime}",
+    "    jne 9f",
+    "1:  mov r9d, [r8 + {seq}]",
+    "    test r9d, 1",
+    "    jnz 1b",
+    "    cmp dword ptr [r8 + {valid}], 0",
+    "    je 9f",
+    // The TSC may not be read before the sequence number
+    "    lfence",
+    "    rdtsc",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    sub rax, [r8 + {tsc_base}]",
+    "    mul qword ptr [r8 + {mult}]",
+    "    shrd rax, rdx, 32",
+    "    add rax, [r8 + {ns_base}]",
+    "    cmp rdi, {realtime}",
+    "    jne 2f",
+    "    add rax, [r8 + {realtime_offset}]",
+    "2:  cmp r9d, [r8 + {seq}]",
+    "    jne 1b",
+    "    xor edx, edx",
+    "    mov rcx, {ns_per_sec}",
+    "    div rcx",
+    "    mov [rsi], rax",
+    "    mov [rsi + 8], rdx",
+    "    xor eax, eax",
+    "    ret",
+    "9:  mov eax, {clock_gettime}",
+    "    syscall",
+    "    ret",
+    "vdso_clock_gettime_end:",
+    data = const DATA_ADDR,
+    monotonic = const Clock::Monotonic as u64,
+    realtime = const Clock::Realtime as u64,
+    seq = const offset_of!(TimePage, seq),
+    valid = const offset_of!(TimePage, valid),
+    tsc_base = const offset_of!(TimePage, tsc_base),
+    mult = const offset_of!(TimePage, mult),
+    ns_base = const offset_of!(TimePage, ns_base),
+    realtime_offset = const offset_of!(TimePage, realtime_offset),
+    ns_per_sec = const NS_PER_SEC,
+    clock_gettime = const nr::CLOCK_GETTIME,
+);
+
+unsafe extern "C" {
+    static vdso_clock_gettime_start: u8;
+    static vdso_clock_gettime_end: u8;
+}
+
+/// The last clock reading an update anchored to, for refining the period.
+struct Calibration {
+    tsc: u64,
+    ns: u64,
+}
+
+static CALIBRATION: Mutex<Option<Calibration>> = Mutex::new(None);
+
+/// Nanoseconds in `ticks` TSC ticks at `mult` nanoseconds per tick, as the stub computes them.
+fn scale(ticks: u64, mult: u64) -> u64 {
+    ((ticks as u128 * mult as u128) >> 32) as u64
+}
+
+/// Fills in the data page if the TSC can be used for time. Needs a clock source and the TSC
+/// frequency, so call it after [`cpu::freq::init`].
+pub fn init() {
+    let invariant = __cpuid(0x8000_0000).eax >= CPUID_EXT_POWER
+        && __cpuid(CPUID_EXT_POWER).edx & CPUID_EXT_POWER_EDX_INVARIANT_TSC != 0;
+    // kvmclock is only used when the host says the TSC is stable
+    if !invariant && crate::pvclock::source().is_none() {
+        return;
+    }
+    let Some(mhz) = cpu::freq::info().and_then(|info| info.tsc_mhz) else {
+        return;
+    };
+    if mhz == 0 {
+        return;
+    }
+    TIME_PAGE
+        .mult
+        .store((1000u64 << 32) / mhz as u64, Ordering::Relaxed);
+    update();
+}
+
+/// Moves the anchor to the current time. Called on the BSP's timer ticks.
+pub fn on_tick(now: u64) {
+    if now.is_multiple_of(UPDATE_TICKS) {
+        update();
+    }
+}
+
+/// Anchors the data page to a fresh clock reading and the current realtime offset, and makes
+/// it valid. Does nothing unless [`init`] found the TSC usable.
+pub fn update() {
+    interrupts::without_interrupts(|| {
+        let mut calibration = CALIBRATION.lock();
+        let mut mult = TIME_PAGE.mult.load(Ordering::Relaxed);
+        if mult == 0 {
+            return;
+        }
+        let tsc = rdtsc();
+        let Some(ns) = crate::timer::uptime_ns() else {
+            return;
+        };
+
+        let mut ns_base = ns;
+        match calibration.as_ref() {
+            Some(last) => {
+                let ticks = tsc.wrapping_sub(last.tsc);
+                if ns.saturating_sub(last.ns) >= MIN_CALIBRATION_NS && ticks > 0 {
+                    mult = ((((ns - last.ns) as u128) << 32) / ticks as u128) as u64;
+                    *calibration = Some(Calibration { tsc, ns });
+                }
+                let old_tsc = TIME_PAGE.tsc_base.load(Ordering::Relaxed);
+                let old_ns = TIME_PAGE.ns_base.load(Ordering::Relaxed);
+                let old_mult = TIME_PAGE.mult.load(Ordering::Relaxed);
+                ns_base = ns_base.max(old_ns + scale(tsc.wrapping_sub(old_tsc), old_mult));
+            }
+            None => *calibration = Some(Calibration { tsc, ns }),
+        }
+
+        TIME_PAGE.seq.fetch_add(1, Ordering::AcqRel);
+        TIME_PAGE.tsc_base.store(tsc, Ordering::Relaxed);
+        TIME_PAGE.ns_base.store(ns_base, Ordering::Relaxed);
+        TIME_PAGE.mult.store(mult, Ordering::Relaxed);
+        TIME_PAGE
+            .realtime_offset
+            .store(time::boot_realtime_ns(), Ordering::Relaxed);
+        TIME_PAGE.valid.store(1, Ordering::Relaxed);
+        TIME_PAGE.seq.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// Whether the data page is valid, so the stub reads the time without a system call.
+pub fn is_active() -> bool {
+    TIME_PAGE.valid.load(Ordering::Relaxed) != 0
+}
+
+/// Maps the data page read-only and the code page executable into `space`, where every
+/// process finds them.
+pub fn map(space: &mut AddressSpace) -> Result<(), MapToError<Size4KiB>> {
+    CODE_READY.call_once(|| unsafe {
+        let start = &raw const vdso_clock_gettime_start;
+        let len = (&raw const vdso_clock_gettime_end).offset_from(start) as usize;
+        core::ptr::copy_nonoverlapping(start, CODE_PAGE.0.get().cast::<u8>(), len);
+    });
+    let pages = [
+        (DATA_ADDR, phys_addr(&TIME_PAGE), PageTableFlags::NO_EXECUTE),
+        (CODE_ADDR, phys_addr(&CODE_PAGE), PageTableFlags::empty()),
+    ];
+    for (addr, phys, flags) in pages {
+        space.map_kernel_frame(VirtAddr::new(addr), phys, flags)?;
+    }
+    Ok(())
+}
+
+fn phys_addr<T>(page: &'static T) -> PhysAddr {
+    translate_phys_active(VirtAddr::from_ptr(page)).expect("kernel statics are mapped")
+}
+
+#[test_case]
+fn test_scale() {
+    // A 2 GHz TSC: half a nanosecond per tick
+    let mult = (1000u64 << 32) / 2000;
+    assert_eq!(scale(3_000, mult), 1_500);
+    // 10 minutes of ticks between updates doesn't overflow
+    assert_eq!(scale(1_200 * NS_PER_SEC, mult), 600 * NS_PER_SEC);
+}
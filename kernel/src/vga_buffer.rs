@@ -1,33 +1,7 @@
-use core::fmt;
-use lazy_static::lazy_static;
-use spin::Mutex;
+//! The VGA text buffer as a [`Console`] backend, for machines that booted in text mode.
 use volatile::Volatile;
 
-use crate::println;
-
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-
-// Defines a color palette for the VGA text mode
-pub enum Color {
-    Black = 0,
-    Blue = 1,
-    Green = 2,
-    Cyan = 3,
-    Red = 4,
-    Magenta = 5,
-    Brown = 6,
-    LightGray = 7,
-    DarkGray = 8,
-    LightBlue = 9,
-    LightGreen = 10,
-    LightCyan = 11,
-    LightRed = 12,
-    Pink = 13,
-    Yellow = 14,
-    White = 15,
-}
+use crate::console::{Cell, Color, Console};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -49,6 +23,9 @@ struct ScreenChar {
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+/// Physical address of the text-mode buffer.
+pub const BUFFER_PHYS_ADDR: u64 = 0xb8000;
+
 #[repr(transparent)]
 struct Buffer {
     // The buffer is a 2D array of ScreenChar structs. ScreenChar must be wrapped in a Volatile type to prevent the compiler from optimizing away writes to the VGA buffer.
@@ -56,61 +33,26 @@ struct Buffer {
 }
 
 pub struct Writer {
-    column_position: usize,
-    color_code: ColorCode,
     buffer: &'static mut Buffer,
 }
 
 impl Writer {
-    // Writes a byte to the screen
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
-
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
-            }
-        }
-    }
-
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
-            }
-        }
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
-    }
-
-    // Writes a string to the screen by writing each byte of the string
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // Prints a ■ character for any byte that is not in the printable ASCII range
-                _ => self.write_byte(0xfe),
-            }
+    /// Creates a writer for the text buffer mapped at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be where [`BUFFER_PHYS_ADDR`] is mapped, and nothing else may write there.
+    pub unsafe fn new(addr: u64) -> Self {
+        Writer {
+            buffer: unsafe { &mut *(addr as *mut Buffer) },
         }
     }
 
-    // Clears the screen by overwriting all characters with a space character
-    fn clear_row(&mut self, row: usize) {
+    // Clears a row by overwriting all characters with a space character
+    fn clear_row(&mut self, row: usize, bg: Color) {
         let blank = ScreenChar {
             ascii_character: b' ',
-            color_code: self.color_code,
+            color_code: ColorCode::new(bg, bg),
         };
         for col in 0..BUFFER_WIDTH {
             self.buffer.chars[row][col].write(blank);
@@ -118,60 +60,49 @@ impl Writer {
     }
 }
 
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
-        Ok(())
+impl Console for Writer {
+    fn size(&self) -> (usize, usize) {
+        (BUFFER_WIDTH, BUFFER_HEIGHT)
     }
-}
 
-lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
-}
+    fn draw(&mut self, col: usize, row: usize, cell: Cell) {
+        let ascii_character = match cell.ch {
+            '\0' => b' ',
+            c @ ' '..='~' => c as u8,
+            // Prints a ■ character for anything that is not in the printable ASCII range
+            _ => 0xfe,
+        };
+        self.buffer.chars[row][col].write(ScreenChar {
+            ascii_character,
+            color_code: ColorCode::new(cell.fg, cell.bg),
+        });
+    }
+
+    fn scroll_up(&mut self, bg: Color) {
+        for row in 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.buffer.chars[row][col].read();
+                self.buffer.chars[row - 1][col].write(character);
+            }
+        }
+        self.clear_row(BUFFER_HEIGHT - 1, bg);
+    }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    fn clear(&mut self, bg: Color) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row, bg);
+        }
+    }
 }
 
 #[test_case]
 fn test_println_simple() {
-    println!("test_println_simple output");
+    crate::println!("test_println_simple output");
 }
 
 #[test_case]
 fn test_println_many() {
     for _ in 0..200 {
-        println!("test_println_many output");
+        crate::println!("test_println_many output");
     }
 }
-
-#[test_case]
-fn test_println_output() {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-    /*
-        Writer must explicitly locked while testing to avoid a race condition.
-        The writeln macro allows for printing to a writer that is already locked.
-        Additionally, we have to disable any interrupts for the duration of the test. If we don't, the test might be interrupted while the writer is locked.
-        However, the interrupt handler can be running before the test. So, we print an empty line before the test string to ensure that the test string is printed on a new line and the test is accurate.
-        Note - the race condition is not dangerous, since it is not a data race and does not cause undefined behavior. It only causes the test to fail.
-    */
-    let s = "Some test string that we can fit on one line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed!");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
-}
@@ -0,0 +1,175 @@
+//! virtio-console: a character channel to the host, e.g. QEMU's
+//! `-device virtio-serial-pci -device virtconsole,chardev=...`.
+//!
+//! Only port 0 is used (multiport is not negotiated), so the device has exactly one receive and
+//! one transmit queue. Once initialised the channel mirrors the serial log and the console, so
+//! the host can capture both without the overhead of an emulated UART.
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use super::{Transport, VENDOR_ID, VirtQueue, VirtioError};
+#[cfg(driver = "virtio_console")]
+use crate::{error::KernelError, kernel_init};
+use crate::{info, pci};
+
+/// The transitional (legacy-capable) device ID.
+const DEVICE_ID: u16 = 0x1003;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+const BUF_SIZE: usize = 256;
+
+struct VirtioConsole {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// Bytes received but not read yet.
+    pending: VecDeque<u8>,
+}
+
+static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+/// Whether log and console output is copied to the channel.
+static MIRROR: AtomicBool = AtomicBool::new(true);
+
+#[cfg(driver = "virtio_console")]
+kernel_init!(Early, "virtio-console", init_stage);
+
+/// [`init`] as an initcall, where a machine without the device isn't a failure.
+#[cfg(driver = "virtio_console")]
+fn init_stage() -> Result<(), KernelError> {
+    match init() {
+        Ok(()) | Err(VirtioError::NotPresent) => Ok(()),
+        Err(e) => Err(KernelError::VirtioConsole(e)),
+    }
+}
+
+/// Finds and sets up the device. Must run after memory initialisation.
+pub fn init() -> Result<(), VirtioError> {
+    let device = pci::find(VENDOR_ID, DEVICE_ID).ok_or(VirtioError::NotPresent)?;
+    let transport = Transport::new(&device)?;
+    transport.begin_init();
+    transport.set_features(0);
+    let queues = transport
+        .setup_queue(RX_QUEUE, BUF_SIZE)
+        .and_then(|rx| Ok((rx, transport.setup_queue(TX_QUEUE, BUF_SIZE)?)));
+    let (mut rx, tx) = match queues {
+        Ok(queues) => queues,
+        Err(e) => {
+            transport.fail();
+            return Err(e);
+        }
+    };
+    while let Some(id) = rx.alloc() {
+        rx.submit(id, BUF_SIZE, true);
+    }
+    transport.finish_init();
+    transport.notify(&rx);
+
+    let base = transport.base();
+    *CONSOLE.lock() = Some(VirtioConsole {
+        transport,
+        rx,
+        tx,
+        pending: VecDeque::new(),
+    });
+    info!("virtio-console: port 0 at I/O {:#x}", base);
+    Ok(())
+}
+
+/// Resets the device and forgets it, so it stops using the memory of its queues, e.g. before
+/// handing the machine to another kernel.
+pub fn shutdown() {
+    if let Some(console) = CONSOLE.lock().take() {
+        console.transport.reset();
+    }
+}
+
+pub fn is_present() -> bool {
+    CONSOLE.lock().is_some()
+}
+
+/// Sends `bytes` to the host. Does nothing if there is no device.
+pub fn write(bytes: &[u8]) {
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        console.write(bytes);
+    }
+}
+
+/// Copies whatever the host has sent into `buf` without waiting, returning how much that was.
+pub fn read(buf: &mut [u8]) -> usize {
+    match CONSOLE.lock().as_mut() {
+        Some(console) => console.read(buf),
+        None => 0,
+    }
+}
+
+/// Turns copying the log and console output to the channel on or off.
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+pub fn mirror_enabled() -> bool {
+    MIRROR.load(Ordering::Relaxed)
+}
+
+/// Copies output printed elsewhere to the channel. Output is dropped rather than waited for if
+/// the channel is busy, e.g. when printing from inside the driver or from an interrupt that
+/// arrived while it was writing.
+pub fn mirror(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if !mirror_enabled() {
+        return;
+    }
+    if let Some(mut guard) = CONSOLE.try_lock()
+        && let Some(console) = guard.as_mut()
+    {
+        let _ = console.write_fmt(args);
+    }
+}
+
+impl VirtioConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(BUF_SIZE) {
+            let id = loop {
+                while let Some((done, _)) = self.tx.pop_used() {
+                    self.tx.release(done);
+                }
+                if let Some(id) = self.tx.alloc() {
+                    break id;
+                }
+                core::hint::spin_loop();
+            };
+            self.tx.buffer(id)[..chunk.len()].copy_from_slice(chunk);
+            self.tx.submit(id, chunk.len(), false);
+            self.transport.notify(&self.tx);
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut refilled = false;
+        while let Some((id, len)) = self.rx.pop_used() {
+            let len = len.min(BUF_SIZE);
+            self.pending.extend(&self.rx.buffer(id)[..len]);
+            self.rx.submit(id, BUF_SIZE, true);
+            refilled = true;
+        }
+        if refilled {
+            self.transport.notify(&self.rx);
+        }
+        let len = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *dst = src;
+        }
+        len
+    }
+}
+
+impl fmt::Write for VirtioConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Virtio devices over the legacy (0.9.5) PCI transport, which QEMU offers on transitional
+//! devices. Queues are split virtqueues in physically contiguous memory and are polled rather
+//! than interrupt driven.
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{Ordering, fence};
+
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::allocator::page_allocator::PAGE_ALLOCATOR;
+use crate::mem;
+use crate::memory::phys_range_to_virt;
+use crate::pci::{Bar, PciDevice};
+
+pub mod console;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 0x80;
+
+/// The legacy transport requires the used ring to start on its own page.
+const QUEUE_ALIGN: usize = 4096;
+const PAGE_SIZE: usize = 4096;
+
+const DESC_F_WRITE: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// No matching device on the PCI bus.
+    NotPresent,
+    /// BAR 0 is not an I/O port range, so this is not a legacy or transitional device.
+    NoIoBar,
+    /// The device does not implement the requested queue.
+    NoQueue,
+    OutOfMemory,
+}
+
+/// Register access to a device through its legacy I/O BAR.
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Enables the device on the bus and resets it.
+    pub fn new(device: &PciDevice) -> Result<Self, VirtioError> {
+        let Some(Bar::Io(base)) = device.bar(0) else {
+            return Err(VirtioError::NoIoBar);
+        };
+        device.enable(true);
+        let transport = Transport { base };
+        transport.reset();
+        Ok(transport)
+    }
+
+    /// Returns the device to its initial state, in which it no longer uses its queues.
+    pub fn reset(&self) {
+        self.write_u8(REG_STATUS, 0);
+    }
+
+    /// Acknowledges the device and returns the features it offers.
+    pub fn begin_init(&self) -> u32 {
+        self.write_u8(REG_STATUS, STATUS_ACKNOWLEDGE);
+        self.write_u8(REG_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        self.read_u32(REG_DEVICE_FEATURES)
+    }
+
+    pub fn set_features(&self, features: u32) {
+        self.write_u32(REG_GUEST_FEATURES, features);
+    }
+
+    /// Allocates queue `index` with a `buf_size`-byte buffer per descriptor and hands it to the
+    /// device.
+    pub fn setup_queue(&self, index: u16, buf_size: usize) -> Result<VirtQueue, VirtioError> {
+        self.write_u16(REG_QUEUE_SELECT, index);
+        let size = self.read_u16(REG_QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::NoQueue);
+        }
+        let queue = VirtQueue::new(index, size, buf_size)?;
+        self.write_u32(REG_QUEUE_PFN, (queue.phys / PAGE_SIZE as u64) as u32);
+        Ok(queue)
+    }
+
+    pub fn finish_init(&self) {
+        let status = self.read_u8(REG_STATUS);
+        self.write_u8(REG_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        let status = self.read_u8(REG_STATUS);
+        self.write_u8(REG_STATUS, status | STATUS_FAILED);
+    }
+
+    pub fn notify(&self, queue: &VirtQueue) {
+        self.write_u16(REG_QUEUE_NOTIFY, queue.index);
+    }
+
+    /// Reads byte `offset` of the device-specific configuration that follows the common
+    /// registers.
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        self.read_u8(0x14 + offset)
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    fn read_u8(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+
+    fn write_u8(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) }
+    }
+
+    fn read_u16(&self, reg: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.base + reg).read() }
+    }
+
+    fn write_u16(&self, reg: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.base + reg).write(value) }
+    }
+
+    fn read_u32(&self, reg: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.base + reg).read() }
+    }
+
+    fn write_u32(&self, reg: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.base + reg).write(value) }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A split virtqueue where every descriptor owns a fixed buffer, so requests never chain.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    /// Physical address of the ring memory, given to the device.
+    phys: u64,
+    desc: *mut Descriptor,
+    /// `flags`, `idx`, then `size` ring entries.
+    avail: *mut u16,
+    /// `flags`, `idx`, then `size` (id, len) pairs.
+    used: *mut u16,
+    buffers: *mut u8,
+    buffers_phys: u64,
+    buf_size: usize,
+    free: Vec<u16>,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+// The rings are only touched through `&mut self`, under whatever lock owns the queue
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    fn new(index: u16, size: u16, buf_size: usize) -> Result<Self, VirtioError> {
+        let n = size as usize;
+        let driver_area = align_up(16 * n + 6 + 2 * n, QUEUE_ALIGN);
+        let device_area = align_up(6 + 8 * n, QUEUE_ALIGN);
+        let (phys, ring) = alloc_dma(driver_area + device_area)?;
+        let (buffers_phys, buffers) = alloc_dma(n * buf_size)?;
+        Ok(VirtQueue {
+            index,
+            size,
+            phys,
+            desc: ring.cast(),
+            avail: unsafe { ring.add(16 * n) }.cast(),
+            used: unsafe { ring.add(driver_area) }.cast(),
+            buffers,
+            buffers_phys,
+            buf_size,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// Takes a free descriptor, if the device has given enough back.
+    pub fn alloc(&mut self) -> Option<u16> {
+        self.free.pop()
+    }
+
+    pub fn release(&mut self, id: u16) {
+        self.free.push(id);
+    }
+
+    /// The buffer that belongs to descriptor `id`.
+    pub fn buffer(&mut self, id: u16) -> &mut [u8] {
+        assert!(id < self.size);
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.buffers.add(id as usize * self.buf_size),
+                self.buf_size,
+            )
+        }
+    }
+
+    /// Makes the first `len` bytes of descriptor `id`'s buffer available to the device, for it
+    /// to write into if `device_writes` is set and to read otherwise. The device only looks once
+    /// notified.
+    pub fn submit(&mut self, id: u16, len: usize, device_writes: bool) {
+        assert!(len <= self.buf_size);
+        let slot = (self.avail_idx % self.size) as usize;
+        unsafe {
+            ptr::write_volatile(
+                self.desc.add(id as usize),
+                Descriptor {
+                    addr: self.buffers_phys + (id as usize * self.buf_size) as u64,
+                    len: len as u32,
+                    flags: if device_writes { DESC_F_WRITE } else { 0 },
+                    next: 0,
+                },
+            );
+            ptr::write_volatile(self.avail.add(2 + slot), id);
+            // The entry has to be visible before the index that publishes it
+            fence(Ordering::SeqCst);
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            ptr::write_volatile(self.avail.add(1), self.avail_idx);
+        }
+    }
+
+    /// Returns the next descriptor the device has finished with, and how many bytes it wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used_idx = unsafe { ptr::read_volatile(self.used.add(1)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used % self.size) as usize;
+        let (id, len) = unsafe {
+            let elem = self.used.add(2).cast::<u32>().add(2 * slot);
+            (ptr::read_volatile(elem), ptr::read_volatile(elem.add(1)))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        Some((id as u16, len as usize))
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Allocates zeroed, physically contiguous memory for the device to access, returning its
+/// physical address and where it is mapped. It is never freed.
+fn alloc_dma(len: usize) -> Result<(u64, *mut u8), VirtioError> {
+    let pages = align_up(len, PAGE_SIZE) / PAGE_SIZE;
+    let frame: PhysFrame = PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|allocator| allocator.frame_allocator.allocate_contiguous(pages))
+        .ok_or(VirtioError::OutOfMemory)?;
+    let phys = frame.start_address().as_u64();
+    let virt = phys_range_to_virt(frame.start_address(), (pages * PAGE_SIZE) as u64)
+        .expect("DMA memory outside the physical memory mapping")
+        .as_mut_ptr::<u8>();
+    unsafe { mem::zero_pages(virt, pages) };
+    Ok((phys, virt))
+}
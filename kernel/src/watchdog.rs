@@ -0,0 +1,215 @@
+//! A software watchdog for subsystems that are supposed to keep making progress.
+//!
+//! A subsystem declares a `static` [`Heartbeat`] with a timeout and calls [`Heartbeat::beat`]
+//! from its main loop. Once [`start`] has been called, the timer interrupt checks every
+//! heartbeat that has beaten at least once and panics, naming the subsystem and listing how
+//! long ago every heartbeat last beat, as soon as one misses its deadline. A wedged subsystem
+//! then fails loudly, which with `panic=exit` also ends a test run, instead of hanging.
+//!
+//! The check runs in the timer interrupt, so a CPU stuck with interrupts disabled goes
+//! unnoticed.
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::KernelError;
+use crate::power::suspend::{self, SuspendHook};
+use crate::{kernel_init, timer};
+
+/// Heartbeats the watchdog can watch; later ones are ignored.
+const MAX_HEARTBEATS: usize = 16;
+/// Timer ticks between checks.
+const CHECK_EVERY_TICKS: u64 = 16;
+const NS_PER_MS: u64 = 1_000_000;
+
+pub struct Heartbeat {
+    name: &'static str,
+    timeout_ms: u64,
+    /// Uptime at the last beat, in nanoseconds, or 0 while disarmed.
+    last_ns: AtomicU64,
+    registered: AtomicBool,
+}
+
+impl Heartbeat {
+    pub const fn new(name: &'static str, timeout_ms: u64) -> Self {
+        Heartbeat {
+            name,
+            timeout_ms,
+            last_ns: AtomicU64::new(0),
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    /// Records progress, and arms the heartbeat if it wasn't.
+    pub fn beat(&'static self) {
+        let Some(now) = timer::uptime_ns() else {
+            return;
+        };
+        self.last_ns.store(now.max(1), Ordering::Relaxed);
+        self.register();
+    }
+
+    /// Stops watching until the next beat, for a subsystem that stops on purpose.
+    pub fn disarm(&self) {
+        self.last_ns.store(0, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the last beat, or `None` while disarmed.
+    fn age_ms(&self, now_ns: u64) -> Option<u64> {
+        match self.last_ns.load(Ordering::Relaxed) {
+            0 => None,
+            last => Some(now_ns.saturating_sub(last) / NS_PER_MS),
+        }
+    }
+
+    fn overdue(&self, now_ns: u64) -> bool {
+        self.age_ms(now_ns).is_some_and(|age| age > self.timeout_ms)
+    }
+
+    fn register(&'static self) {
+        if self.registered.load(Ordering::Relaxed) || self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let index = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+        if let Some(slot) = REGISTRY.get(index) {
+            slot.store(ptr::from_ref(self).cast_mut(), Ordering::Release);
+        }
+    }
+}
+
+static REGISTRY: [AtomicPtr<Heartbeat>; MAX_HEARTBEATS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HEARTBEATS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn heartbeats() -> impl Iterator<Item = &'static Heartbeat> {
+    REGISTRY
+        .iter()
+        // A slot is claimed before its pointer is stored, so it may still be null
+        .filter_map(|slot| unsafe { slot.load(Ordering::Acquire).as_ref() })
+}
+
+/// Starts checking heartbeats from the timer interrupt.
+pub fn start() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub fn is_running() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether the watchdog was running when the machine suspended.
+static RUNNING_BEFORE_SUSPEND: AtomicBool = AtomicBool::new(false);
+
+kernel_init!(Early, "watchdog", init_stage);
+
+fn init_stage() -> Result<(), KernelError> {
+    suspend::register(SuspendHook {
+        name: "watchdog",
+        suspend: suspend_watchdog,
+        resume: resume_watchdog,
+    });
+    Ok(())
+}
+
+fn suspend_watchdog() -> Result<(), &'static str> {
+    RUNNING_BEFORE_SUSPEND.store(is_running(), Ordering::Relaxed);
+    stop();
+    Ok(())
+}
+
+/// Nothing beat while suspended, so every heartbeat waits for its next beat before it is
+/// watched again.
+fn resume_watchdog() {
+    for heartbeat in heartbeats() {
+        heartbeat.disarm();
+    }
+    if RUNNING_BEFORE_SUSPEND.load(Ordering::Relaxed) {
+        start();
+    }
+}
+
+/// The panic message: the stalled subsystem, then every heartbeat.
+struct Report {
+    stalled: &'static Heartbeat,
+    now_ns: u64,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "watchdog: {} made no progress within {} ms",
+            self.stalled.name, self.stalled.timeout_ms
+        )?;
+        for heartbeat in heartbeats() {
+            match heartbeat.age_ms(self.now_ns) {
+                Some(age) => writeln!(
+                    f,
+                    "  {}: last beat {} ms ago, timeout {} ms",
+                    heartbeat.name, age, heartbeat.timeout_ms
+                )?,
+                None => writeln!(f, "  {}: disarmed", heartbeat.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first heartbeat past its deadline at `now_ns`.
+fn find_stalled(now_ns: u64) -> Option<&'static Heartbeat> {
+    heartbeats().find(|heartbeat| heartbeat.overdue(now_ns))
+}
+
+/// Checks the heartbeats every few ticks. Called from the timer interrupt.
+pub(crate) fn on_tick(tick: u64) {
+    if !ENABLED.load(Ordering::Relaxed) || !tick.is_multiple_of(CHECK_EVERY_TICKS) {
+        return;
+    }
+    let Some(now_ns) = timer::uptime_ns() else {
+        return;
+    };
+    if let Some(stalled) = find_stalled(now_ns) {
+        // One report is enough; the next tick shouldn't panic inside the panic
+        ENABLED.store(false, Ordering::Relaxed);
+        panic!("{}", Report { stalled, now_ns });
+    }
+}
+
+/// One heartbeat's state when [`list`] was called.
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub name: &'static str,
+    pub timeout_ms: u64,
+    /// Milliseconds since the last beat, or `None` while disarmed.
+    pub age_ms: Option<u64>,
+}
+
+pub fn list() -> Vec<Status> {
+    let now_ns = timer::uptime_ns().unwrap_or(0);
+    heartbeats()
+        .map(|heartbeat| Status {
+            name: heartbeat.name,
+            timeout_ms: heartbeat.timeout_ms,
+            age_ms: heartbeat.age_ms(now_ns),
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_overdue_only_when_armed() {
+    static TEST_HEARTBEAT: Heartbeat = Heartbeat::new("test", 100);
+    assert!(!TEST_HEARTBEAT.overdue(u64::MAX));
+    TEST_HEARTBEAT.last_ns.store(NS_PER_MS, Ordering::Relaxed);
+    TEST_HEARTBEAT.register();
+    assert!(!TEST_HEARTBEAT.overdue(101 * NS_PER_MS));
+    assert!(TEST_HEARTBEAT.overdue(102 * NS_PER_MS));
+    assert!(find_stalled(102 * NS_PER_MS).is_some_and(|h| ptr::eq(h, &TEST_HEARTBEAT)));
+    TEST_HEARTBEAT.disarm();
+    assert!(find_stalled(u64::MAX).is_none_or(|h| !ptr::eq(h, &TEST_HEARTBEAT)));
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rust_kernel::block::{self, BlockDevice, RamDisk, SECTOR_SIZE, conformance};
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::init_pic_mode;
+use rust_kernel::task::Task;
+use rust_kernel::task::executor::Executor;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    // An idle executor waits for an interrupt, which the PIT's timer provides
+    init_pic_mode();
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn block_devices_pass_the_conformance_checks() {
+    let finished = Arc::new(AtomicBool::new(false));
+    let done = finished.clone();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let ram = RamDisk::new("ram-conformance", 64);
+        assert_eq!(conformance::check(&ram).await, Ok(()));
+        // Whatever disks the drivers found; test images only attach scratch disks
+        for device in block::devices() {
+            let result = conformance::check(&*device).await;
+            assert_eq!(result, Ok(()), "{} failed", device.name());
+        }
+        done.store(true, Ordering::Relaxed);
+    }));
+    executor.run_to_completion();
+    assert!(finished.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn ram_disk_loses_unflushed_writes() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let ram = RamDisk::new("ram-power", 4);
+        let mut buf = [0; SECTOR_SIZE];
+        ram.write(0, &[1; SECTOR_SIZE]).await.unwrap();
+        ram.flush().await.unwrap();
+        ram.write(0, &[2; SECTOR_SIZE]).await.unwrap();
+        ram.write(1, &[2; SECTOR_SIZE]).await.unwrap();
+        assert_eq!(ram.dirty_sectors(), 2);
+        ram.lose_power();
+        ram.read(0, &mut buf).await.unwrap();
+        assert_eq!(buf, [1; SECTOR_SIZE]);
+        ram.read(1, &mut buf).await.unwrap();
+        assert_eq!(buf, [0; SECTOR_SIZE]);
+    }));
+    executor.run_to_completion();
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::block::RamDisk;
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::init_pic_mode;
+use rust_kernel::task::Task;
+use rust_kernel::task::executor::Executor;
+use rust_kernel::{backtrace, crashdump, info};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    // An idle executor waits for an interrupt, which the PIT's timer provides
+    init_pic_mode();
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn backtraces_walk_up_the_stack() {
+    let mut frames = 0;
+    backtrace::walk(|_| frames += 1);
+    // At least this function and the test runner that called it
+    assert!(
+        (2..=backtrace::MAX_FRAMES).contains(&frames),
+        "{} frames",
+        frames
+    );
+}
+
+#[test_case]
+fn crash_dumps_survive_a_power_loss() {
+    info!("before the crash");
+    let mut dump = String::new();
+    crashdump::compose(&mut dump, &"test panic").unwrap();
+    for section in [
+        "test panic",
+        "Registers:",
+        "Backtrace:",
+        "  #0 ",
+        "Tasks:",
+        "Log:",
+    ] {
+        assert!(dump.contains(section), "no '{}' in the dump", section);
+    }
+    assert!(dump.contains("before the crash"));
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let ram = RamDisk::new("ram-crashdump", 128);
+        assert_eq!(crashdump::load(&ram).await, Ok(None));
+        crashdump::save(&ram, dump.as_bytes()).await.unwrap();
+        ram.lose_power();
+        assert_eq!(crashdump::load(&ram).await, Ok(Some(dump.into_bytes())));
+        crashdump::clear(&ram).await.unwrap();
+        assert_eq!(crashdump::load(&ram).await, Ok(None));
+    }));
+    executor.run_to_completion();
+}
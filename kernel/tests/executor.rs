@@ -0,0 +1,355 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::StreamExt;
+use pc_keyboard::DecodedKey;
+use rust_kernel::error::KernelError;
+use rust_kernel::init::initcall::{self, Initcall, Stage};
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::init_pic_mode;
+use rust_kernel::task::channel::channel;
+use rust_kernel::task::executor::{Executor, MAX_TASKS};
+use rust_kernel::task::input::{self, Route};
+use rust_kernel::task::local::TaskLocal;
+use rust_kernel::task::sleep::sleep_ticks;
+use rust_kernel::task::{self, Task, TaskState, keyboard};
+use rust_kernel::{console, cpu, println, ps2, smp, timer};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    // The PIT drives the timer and the keyboard interrupt arrives through the PIC
+    init_pic_mode();
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+/// Returns `Pending` once, waking itself first, so the task goes back through the queue.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+#[test_case]
+fn many_tasks_run_to_completion() {
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..MAX_TASKS {
+        let finished = finished.clone();
+        executor.spawn(Task::new(async move {
+            for _ in 0..4 {
+                yield_now().await;
+            }
+            finished.fetch_add(1, Ordering::Relaxed);
+        }));
+    }
+    executor.run_to_completion();
+    assert_eq!(finished.load(Ordering::Relaxed), MAX_TASKS);
+}
+
+/// Counts its polls, waking itself many times on the first one.
+struct WakeManyTimes {
+    polls: Arc<AtomicUsize>,
+}
+
+impl Future for WakeManyTimes {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.polls.fetch_add(1, Ordering::Relaxed) > 0 {
+            return Poll::Ready(());
+        }
+        for _ in 0..2 * MAX_TASKS {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn repeated_wakes_queue_a_task_once() {
+    let polls = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WakeManyTimes {
+        polls: polls.clone(),
+    }));
+    executor.run_to_completion();
+    assert_eq!(polls.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn sleep_waits_for_timer_ticks() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = timer::ticks();
+        sleep_ticks(3).await;
+        assert!(timer::ticks() - start >= 3);
+    }));
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn sleepers_wake_in_deadline_order() {
+    let (sender, mut receiver) = channel();
+    let mut executor = Executor::new();
+    for ticks in [6, 2, 4] {
+        let sender = sender.clone();
+        executor.spawn(Task::new(async move {
+            sleep_ticks(ticks).await;
+            sender.send(ticks).unwrap();
+        }));
+    }
+    drop(sender);
+    executor.spawn(Task::new(async move {
+        let mut order = Vec::new();
+        while let Some(ticks) = receiver.recv().await {
+            order.push(ticks);
+        }
+        assert_eq!(order, [2, 4, 6]);
+    }));
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn task_states_follow_the_executor() {
+    let (sender, mut receiver) = channel::<()>();
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("state-receiver", async move {
+        receiver.recv().await;
+    }));
+    executor.spawn(Task::named("state-observer", async move {
+        let state = |name| {
+            task::list()
+                .into_iter()
+                .find(|info| info.name == name)
+                .map(|info| info.state)
+        };
+        assert_eq!(state("state-observer"), Some(TaskState::Running));
+        // Polled first, and waiting for the channel since
+        assert_eq!(state("state-receiver"), Some(TaskState::Waiting));
+        sender.send(()).unwrap();
+        assert_eq!(state("state-receiver"), Some(TaskState::Queued));
+    }));
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn channel_delivers_in_order_and_closes() {
+    let (sender, mut receiver) = channel();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let mut expected = 0;
+        while let Some(value) = receiver.recv().await {
+            assert_eq!(value, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 100);
+    }));
+    executor.spawn(Task::new(async move {
+        for value in 0..100 {
+            sender.send(value).unwrap();
+            if value % 10 == 0 {
+                yield_now().await;
+            }
+        }
+    }));
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn send_fails_after_receiver_drops() {
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(1), Err(1));
+}
+
+static COUNTER: TaskLocal<usize> = TaskLocal::new(|| 0);
+
+#[test_case]
+fn task_locals_are_per_task() {
+    let mut executor = Executor::new();
+    for n in 1..=3 {
+        executor.spawn(Task::new(async move {
+            for _ in 0..n {
+                COUNTER.with(|count| *count += 1);
+                yield_now().await;
+            }
+            assert_eq!(COUNTER.with(|count| *count), n);
+        }));
+    }
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn keyboard_interrupt_wakes_subscriber() {
+    // 'a' pressed, in scancode set 1
+    const SCANCODE_A: u8 = 0x1E;
+
+    let received = Arc::new(AtomicBool::new(false));
+    let mut executor = Executor::new();
+    // Spawned first, so the scancode queue exists before anything is injected
+    executor.spawn(Task::named("keyboard", keyboard::dispatch_keypresses()));
+    let mut events = input::subscribe("test", Route::All);
+    let done = received.clone();
+    executor.spawn(Task::new(async move {
+        ps2::inject_scancode(SCANCODE_A);
+        let event = events.next().await.expect("input stream ended");
+        assert_eq!(event.key, Some(DecodedKey::Unicode('a')));
+        done.store(true, Ordering::Relaxed);
+    }));
+    // The keyboard task never finishes
+    executor.run_until(|| received.load(Ordering::Relaxed));
+}
+
+/// Injects `scancode` and waits for the keyboard interrupt to hand it over.
+fn inject_and_wait(scancode: u8) {
+    let received = input::scancode_stats().received;
+    ps2::inject_scancode(scancode);
+    let start = timer::ticks();
+    while input::scancode_stats().received == received {
+        assert!(
+            timer::ticks() - start < 100,
+            "keyboard interrupt never arrived"
+        );
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn scancode_overflow_is_counted() {
+    // 'a' released, which the keyboard task would ignore anyway
+    const SCANCODE_A_UP: u8 = 0x9E;
+
+    // Nothing reads the queue any more, so everything past its free space is lost
+    let before = input::scancode_stats();
+    let free = before.capacity - before.queued;
+    for _ in 0..free + 10 {
+        inject_and_wait(SCANCODE_A_UP);
+    }
+    let after = input::scancode_stats();
+    assert_eq!(after.queued, after.capacity);
+    assert_eq!(after.received - before.received, free as u64 + 10);
+    assert_eq!(after.dropped - before.dropped, 10);
+    assert!(!input::set_scancode_queue_len(16));
+}
+
+#[test_case]
+fn println_is_drawn_by_the_render_task() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::named("console", console::render::run()));
+    executor.run_until(console::render::is_deferred);
+
+    println!("\ndrawn by the render task");
+    assert!(console::render::queued() > 0);
+    executor.run_until(|| console::render::queued() == 0);
+    assert!(console::capture().contains("drawn by the render task"));
+
+    console::render::stop();
+}
+
+fn no_op() -> Result<(), KernelError> {
+    Ok(())
+}
+
+#[test_case]
+fn initcalls_run_after_their_dependencies() {
+    static INITCALLS: [Initcall; 4] = [
+        Initcall {
+            name: "a",
+            stage: Stage::Early,
+            after: &["c"],
+            run: no_op,
+        },
+        Initcall {
+            name: "b",
+            stage: Stage::Early,
+            after: &[],
+            run: no_op,
+        },
+        Initcall {
+            name: "c",
+            stage: Stage::Early,
+            after: &["b", "d"],
+            run: no_op,
+        },
+        Initcall {
+            name: "d",
+            stage: Stage::Device,
+            after: &[],
+            run: no_op,
+        },
+    ];
+    let names = |stage| {
+        initcall::ordered(&INITCALLS, stage)
+            .iter()
+            .map(|initcall| initcall.name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(Stage::Early), ["b", "c", "a"]);
+    assert_eq!(names(Stage::Device), ["d"]);
+    assert!(names(Stage::Late).is_empty());
+}
+
+#[test_case]
+fn cross_cpu_calls_complete_as_futures() {
+    let this = cpu::current_index();
+    let finished = Arc::new(AtomicBool::new(false));
+    let done = finished.clone();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let call = smp::call_on(this, move || this + 1).expect("current CPU is online");
+        assert_eq!(call.await, this + 1);
+        for (index, call) in smp::call_all(cpu::current_index) {
+            assert_eq!(call.expect("CPU is online").await, index);
+        }
+        done.store(true, Ordering::Relaxed);
+    }));
+    executor.run_to_completion();
+    assert!(finished.load(Ordering::Relaxed));
+}
@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::block::{RamDisk, SECTOR_SIZE};
+use rust_kernel::fs::fat32::fsck::{self, Problem};
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::init_pic_mode;
+use rust_kernel::task::Task;
+use rust_kernel::task::executor::Executor;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    // An idle executor waits for an interrupt, which the PIT's timer provides
+    init_pic_mode();
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+/// Sectors of the FAT32 image [`fat32_image`] builds: 32 reserved, two one-sector FATs and 126
+/// one-sector clusters.
+const FAT_IMAGE_SECTORS: usize = 160;
+
+/// A FAT32 volume holding `/HELLO.TXT` (700 bytes, clusters 3 and 4) and `/DIR/A.BIN` (10
+/// bytes, cluster 6), with the root directory in cluster 2 and `/DIR` in cluster 5. `fat` gives
+/// the FAT entries to write from cluster 0 up, into both copies.
+fn fat32_image(fat: &[u32]) -> Vec<u8> {
+    let mut image = alloc::vec![0u8; FAT_IMAGE_SECTORS * SECTOR_SIZE];
+    let boot = &mut image[..SECTOR_SIZE];
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1; // sectors per cluster
+    boot[14..16].copy_from_slice(&32u16.to_le_bytes()); // reserved sectors
+    boot[16] = 2; // FATs
+    boot[32..36].copy_from_slice(&(FAT_IMAGE_SECTORS as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&1u32.to_le_bytes()); // sectors per FAT
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes()); // root cluster
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+    for copy in 0..2 {
+        let start = (32 + copy) * SECTOR_SIZE;
+        for (i, entry) in fat.iter().enumerate() {
+            image[start + i * 4..start + i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+    let mut entry =
+        |cluster: usize, index: usize, name: &[u8; 11], attributes: u8, first: u16, size: u32| {
+            let at = (34 + cluster - 2) * SECTOR_SIZE + index * 32;
+            image[at..at + 11].copy_from_slice(name);
+            image[at + 11] = attributes;
+            image[at + 26..at + 28].copy_from_slice(&first.to_le_bytes());
+            image[at + 28..at + 32].copy_from_slice(&size.to_le_bytes());
+        };
+    entry(2, 0, b"HELLO   TXT", 0, 3, 700);
+    entry(2, 1, b"DIR        ", 0x10, 5, 0);
+    entry(5, 0, b".          ", 0x10, 5, 0);
+    entry(5, 1, b"..         ", 0x10, 0, 0);
+    entry(5, 2, b"A       BIN", 0, 6, 10);
+    image
+}
+
+/// FAT entries for [`fat32_image`]'s files: the two reserved entries, then clusters 2 to 6.
+const CLEAN_FAT: [u32; 7] = [
+    0x0FFF_FFF8,
+    0x0FFF_FFFF,
+    0x0FFF_FFFF,
+    4,
+    0x0FFF_FFFF,
+    0x0FFF_FFFF,
+    0x0FFF_FFFF,
+];
+
+#[test_case]
+fn fsck_passes_a_clean_volume() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let disk = RamDisk::with_contents("fat-clean", fat32_image(&CLEAN_FAT));
+        let report = fsck::check(&disk).await.expect("volume is FAT32");
+        assert_eq!(report.problems, []);
+        assert_eq!((report.files, report.directories), (2, 2));
+        assert_eq!(report.used_clusters, 5);
+        assert_eq!(report.free_clusters, 126 - 5);
+    }));
+    executor.run_to_completion();
+}
+
+#[test_case]
+fn fsck_finds_cross_links_and_lost_clusters() {
+    let mut fat = Vec::from(CLEAN_FAT);
+    // A.BIN runs on into HELLO.TXT's last cluster, and cluster 9 is allocated to nothing. /DIR
+    // is checked first, so A.BIN claims cluster 4 before HELLO.TXT reaches it
+    fat[6] = 4;
+    fat.extend([0, 0, 0x0FFF_FFFF]);
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        let disk = RamDisk::with_contents("fat-broken", fat32_image(&fat));
+        let report = fsck::check(&disk).await.expect("volume is FAT32");
+        assert!(report.problems.contains(&Problem::CrossLinked {
+            cluster: 4,
+            first: "/DIR/A.BIN".into(),
+            second: "/HELLO.TXT".into(),
+        }));
+        assert!(
+            report
+                .problems
+                .contains(&Problem::LostClusters { first: 9, count: 1 })
+        );
+    }));
+    executor.run_to_completion();
+}
@@ -0,0 +1,198 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::page_allocator::PAGE_ALLOCATOR;
+use rust_kernel::fault::{self, Fault, Site};
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::disable_pic;
+use rust_kernel::process::address_space::{AddressSpace, USER_START};
+use rust_kernel::process::elf::ElfError;
+use rust_kernel::process::fd::FdTable;
+use rust_kernel::process::scheduler;
+use rust_kernel::process::{self, SpawnError};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, Size4KiB};
+
+/// Small user programs from `kernel/user`, assembled and linked by the build script.
+macro_rules! user_program {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/", $name, ".elf"))
+    };
+}
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    // No timer: processes run until they exit, and nothing else allocates behind a test's back
+    disable_pic();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+/// Free frames plus the page-table frames the page allocator holds. A failed allocation may
+/// leave page tables behind for the next one, but nothing else.
+fn frames_accounted() -> usize {
+    let guard = PAGE_ALLOCATOR.lock();
+    let page_alloc = guard.as_ref().expect("PAGE_ALLOCATOR not initialized");
+    page_alloc.frame_allocator.free_frames() + page_alloc.table_frames()
+}
+
+fn alloc_pages(num_pages: usize) -> Result<usize, MapToError<Size4KiB>> {
+    PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("PAGE_ALLOCATOR not initialized")
+        .alloc(
+            num_pages,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        )
+}
+
+fn dealloc_pages(addr: usize, num_pages: usize) {
+    PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("PAGE_ALLOCATOR not initialized")
+        .dealloc(addr, num_pages)
+        .expect("dealloc failed");
+}
+
+#[test_case]
+fn test_frame_allocation_failure() {
+    let injected = fault::injected(Site::FrameAlloc);
+    let before = frames_accounted();
+    fault::set(Site::FrameAlloc, Fault::Nth(1));
+    assert!(matches!(
+        alloc_pages(1),
+        Err(MapToError::FrameAllocationFailed)
+    ));
+    assert_eq!(fault::injected(Site::FrameAlloc) - injected, 1);
+    assert_eq!(frames_accounted(), before);
+
+    // Only the one call failed
+    let addr = alloc_pages(1).expect("allocation after the fault failed");
+    dealloc_pages(addr, 1);
+    fault::clear();
+}
+
+#[test_case]
+fn test_failed_allocation_unwinds() {
+    let before = frames_accounted();
+    fault::set(Site::MapTo, Fault::Nth(3));
+    assert!(matches!(
+        alloc_pages(4),
+        Err(MapToError::FrameAllocationFailed)
+    ));
+    // The two pages mapped before the failure were given back
+    assert_eq!(frames_accounted(), before);
+    fault::clear();
+}
+
+#[test_case]
+fn test_random_failures() {
+    const ATTEMPTS: usize = 64;
+    fault::seed(1);
+    let injected = fault::injected(Site::FrameAlloc);
+    let before = frames_accounted();
+    fault::set(Site::FrameAlloc, Fault::OneIn(4));
+    let mut allocated = Vec::with_capacity(ATTEMPTS);
+    let mut failures = 0;
+    for _ in 0..ATTEMPTS {
+        match alloc_pages(1) {
+            Ok(addr) => allocated.push(addr),
+            Err(_) => failures += 1,
+        }
+    }
+    fault::clear();
+
+    assert!(failures > 0 && failures < ATTEMPTS);
+    // Each failed allocation stopped at the first frame it couldn't get
+    assert_eq!(
+        fault::injected(Site::FrameAlloc) - injected,
+        failures as u64
+    );
+    for addr in allocated {
+        dealloc_pages(addr, 1);
+    }
+    assert_eq!(frames_accounted(), before);
+}
+
+#[test_case]
+fn test_user_mapping_failure() {
+    let mut space = AddressSpace::new().expect("no address space");
+    fault::set(Site::FrameAlloc, Fault::Nth(2));
+    let result = space.map_user(VirtAddr::new(USER_START), 4, PageTableFlags::WRITABLE);
+    fault::clear();
+    assert!(matches!(result, Err(MapToError::FrameAllocationFailed)));
+
+    // The address space is still usable
+    space
+        .map_user(VirtAddr::new(USER_START), 4, PageTableFlags::WRITABLE)
+        .expect("mapping after the fault failed");
+}
+
+#[test_case]
+fn test_spawn_failure() {
+    let image = user_program!("exit_code");
+    let spawn = || process::spawn_with_files("exit_code", image, FdTable::new());
+
+    // The first two mappings are the vDSO's, so this fails while loading the program. The
+    // first attempt may leave the heap with more pages; the second has to give back everything.
+    for attempt in 0..2 {
+        let before = frames_accounted();
+        fault::set(Site::MapTo, Fault::Nth(3));
+        assert!(matches!(
+            spawn(),
+            Err(SpawnError::Map(_) | SpawnError::Elf(ElfError::Map(_)))
+        ));
+        fault::clear();
+        if attempt > 0 {
+            assert_eq!(frames_accounted(), before);
+        }
+    }
+
+    let pid = spawn().expect("spawn after the fault failed");
+    scheduler::run();
+    assert_eq!(scheduler::reap(pid).expect("program did not exit").code, 42);
+}
+
+#[test_case]
+fn test_heap_failure() {
+    let mut small: Vec<u8> = Vec::new();
+    let mut large: Vec<u8> = Vec::new();
+    fault::set(Site::Heap, Fault::Nth(1));
+    assert!(small.try_reserve(16).is_err());
+    fault::set(Site::Heap, Fault::Nth(1));
+    assert!(large.try_reserve(64 * 1024).is_err());
+    fault::clear();
+
+    assert!(small.try_reserve(16).is_ok());
+    assert!(large.try_reserve(64 * 1024).is_ok());
+    assert_eq!(*Box::new(7), 7);
+}
@@ -12,6 +12,7 @@ use bootloader_api::info::Optional;
 use bootloader_api::{BootInfo, entry_point};
 use core::panic::PanicInfo;
 use rust_kernel::allocator::page_allocator::PAGE_ALLOCATOR;
+use rust_kernel::allocator::slab::{SlabBox, SlabCache};
 use rust_kernel::allocator::{self, page_allocator::init_page_allocator};
 
 entry_point!(main);
@@ -28,9 +29,10 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
     // };
     // init_page_allocator(mapper, test_allocator);
     if let Optional::Some(physical_offset) = boot_info.physical_memory_offset {
+        rust_kernel::init::memory_init::init_offset(VirtAddr::new(physical_offset));
+        memory::phys::init(&boot_info.memory_regions);
         let mapper = unsafe { memory::init(VirtAddr::new(physical_offset)) };
-        let test_allocator =
-            unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, physical_offset) };
+        let test_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions) };
         init_page_allocator(mapper, test_allocator);
     } else {
         panic!("Physical memory offset not provided by bootloader");
@@ -87,3 +89,46 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn slab_cache_reuses_slots() {
+    struct Object {
+        id: u64,
+        _payload: [u8; 200],
+    }
+    static CACHE: SlabCache<Object> = SlabCache::new();
+
+    let objects: Vec<_> = (0..100)
+        .map(|id| {
+            CACHE
+                .alloc(Object {
+                    id,
+                    _payload: [0; 200],
+                })
+                .expect("slab allocation failed")
+        })
+        .collect();
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(object.id, id as u64);
+    }
+    let stats = CACHE.stats();
+    assert_eq!(stats.allocated, 100);
+    assert!(stats.slab_bytes >= 100 * stats.object_size);
+
+    let addresses: Vec<_> = objects.iter().map(SlabBox::as_ptr).collect();
+    drop(objects);
+    assert_eq!(CACHE.stats().allocated, 0);
+
+    // Freed slots are reused before any new slab is made
+    let slabs = CACHE.stats().slabs;
+    for _ in 0..100 {
+        let object = CACHE
+            .alloc(Object {
+                id: 0,
+                _payload: [0; 200],
+            })
+            .expect("slab allocation failed");
+        assert!(addresses.contains(&SlabBox::as_ptr(&object)));
+    }
+    assert_eq!(CACHE.stats().slabs, slabs);
+}
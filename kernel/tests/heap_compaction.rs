@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::compact::{self, Movable};
+use rust_kernel::allocator::page_allocator::PAGE_ALLOCATOR;
+use rust_kernel::init::memory_init;
+use rust_kernel::memory::PAGE_SIZE;
+use x86_64::structures::paging::PageTableFlags;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_compaction_moves_down_and_keeps_contents() {
+    static MOVABLE: Movable = Movable::new();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let hole = PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .unwrap()
+        .alloc(2, flags)
+        .unwrap();
+    MOVABLE.alloc(2).unwrap();
+    MOVABLE.with(|bytes| bytes[PAGE_SIZE as usize + 7] = 0x5A);
+    PAGE_ALLOCATOR
+        .lock()
+        .as_mut()
+        .unwrap()
+        .dealloc(hole, 2)
+        .unwrap();
+
+    let before = MOVABLE.addr().unwrap();
+    compact::compact().unwrap();
+    let after = MOVABLE.addr().unwrap();
+    // The hole is free, so if it was below, there is somewhere lower to go
+    if hole < before {
+        assert!(after < before);
+    }
+    assert!(after <= before);
+    assert_eq!(
+        MOVABLE.with(|bytes| bytes[PAGE_SIZE as usize + 7]),
+        Some(0x5A)
+    );
+    MOVABLE.free();
+    assert_eq!(MOVABLE.addr(), None);
+}
@@ -22,9 +22,10 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
 
     rust_kernel::init_gdt_idt();
     if let Optional::Some(physical_offset) = boot_info.physical_memory_offset {
+        rust_kernel::init::memory_init::init_offset(VirtAddr::new(physical_offset));
+        memory::phys::init(&boot_info.memory_regions);
         let mapper = unsafe { memory::init(VirtAddr::new(physical_offset)) };
-        let test_allocator =
-            unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, physical_offset) };
+        let test_allocator = unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions) };
         init_page_allocator(mapper, test_allocator);
     } else {
         panic!("Physical memory offset not provided by bootloader");
@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{Layout, alloc, dealloc};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::sanitizer::{self, Kind, QUARANTINE_LEN};
+use rust_kernel::init::memory_init;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+const LAYOUT: Layout = match Layout::from_size_align(24, 8) {
+    Ok(layout) => layout,
+    Err(_) => panic!("bad layout"),
+};
+
+fn counts() -> [u64; Kind::ALL.len()] {
+    Kind::ALL.map(sanitizer::violations)
+}
+
+/// Runs `f` and returns how many violations of each kind it caused.
+fn violations_in(f: impl FnOnce()) -> [u64; Kind::ALL.len()] {
+    let before = counts();
+    f();
+    let after = counts();
+    core::array::from_fn(|i| after[i] - before[i])
+}
+
+fn only(kind: Kind) -> [u64; Kind::ALL.len()] {
+    let mut expected = [0; Kind::ALL.len()];
+    expected[kind as usize] = 1;
+    expected
+}
+
+#[test_case]
+fn test_clean_allocations() {
+    let found = violations_in(|| {
+        let mut values: Vec<u64> = Vec::new();
+        for i in 0..100 {
+            values.push(i);
+        }
+        let boxed = Box::new([7u8; 100]);
+        assert_eq!(values.iter().sum::<u64>(), 4950);
+        assert_eq!(boxed[99], 7);
+    });
+    assert_eq!(found, [0; Kind::ALL.len()]);
+}
+
+#[test_case]
+fn test_overflow() {
+    let found = violations_in(|| unsafe {
+        let ptr = alloc(LAYOUT);
+        ptr.add(LAYOUT.size()).write_volatile(0x41);
+        dealloc(ptr, LAYOUT);
+    });
+    assert_eq!(found, only(Kind::Overflow));
+}
+
+#[test_case]
+fn test_underflow() {
+    // The block is left out of the heap afterwards
+    let found = violations_in(|| unsafe {
+        let ptr = alloc(LAYOUT);
+        ptr.sub(1).write_volatile(0x41);
+        dealloc(ptr, LAYOUT);
+    });
+    assert_eq!(found, only(Kind::Underflow));
+}
+
+#[test_case]
+fn test_double_free() {
+    let found = violations_in(|| unsafe {
+        let ptr = alloc(LAYOUT);
+        dealloc(ptr, LAYOUT);
+        dealloc(ptr, LAYOUT);
+    });
+    assert_eq!(found, only(Kind::DoubleFree));
+}
+
+#[test_case]
+fn test_freed_block_not_reused() {
+    unsafe {
+        let first = alloc(LAYOUT);
+        dealloc(first, LAYOUT);
+        let second = alloc(LAYOUT);
+        assert_ne!(first, second);
+        dealloc(second, LAYOUT);
+    }
+}
+
+#[test_case]
+fn test_use_after_free() {
+    let found = violations_in(|| unsafe {
+        let ptr = alloc(LAYOUT);
+        dealloc(ptr, LAYOUT);
+        ptr.add(8).write_volatile(0x41);
+        // Push it out of quarantine
+        for _ in 0..QUARANTINE_LEN {
+            dealloc(alloc(LAYOUT), LAYOUT);
+        }
+    });
+    assert_eq!(found, only(Kind::UseAfterFree));
+}
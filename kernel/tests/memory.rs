@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::page_allocator::{self, KernelPageAllocator, PAGE_ALLOCATOR};
+use rust_kernel::init::memory_init;
+use rust_kernel::interrupts::PHYSICAL_MEMORY_OFFSET;
+use rust_kernel::memory::PAGE_SIZE;
+use rust_kernel::memory::phys::{self, PhysMapError};
+use rust_kernel::memory::{vma, zeroed};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_BYTES: usize = PAGE_SIZE as usize;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_phys_to_virt_checks_the_mapping() {
+    let offset = PHYSICAL_MEMORY_OFFSET.get().unwrap().as_u64();
+    assert_eq!(
+        phys::phys_to_virt(PhysAddr::new(0x1000)).unwrap(),
+        VirtAddr::new(offset + 0x1000)
+    );
+    // Far above the test machine's RAM
+    let high = PhysAddr::new(0x7F_FFFF_F000);
+    assert!(matches!(
+        phys::phys_range_to_virt(high - PAGE_SIZE, 2 * PAGE_SIZE),
+        Err(PhysMapError::NotMapped(addr)) if addr == high - PAGE_SIZE
+    ));
+    assert!(matches!(
+        phys::phys_range_to_virt(PhysAddr::new(0x1000), u64::MAX),
+        Err(PhysMapError::BadRange)
+    ));
+}
+
+/// Runs `f` with the page allocator pointed at a window of `pages` pages of its own, so what
+/// it hands out doesn't depend on what the kernel allocated before. `f` gets the start of the
+/// window and must free what it allocates. Nothing else in this image allocates pages
+/// meanwhile.
+fn with_test_window(pages: usize, f: impl FnOnce(usize)) {
+    let len = pages * PAGE_BYTES;
+    let window = vma::reserve("page allocator test", len as u64).expect("no room for test window");
+    let start = window.start.as_u64() as usize;
+    page_allocator::flush_retired();
+    let saved = with_page_alloc(|page_alloc| page_alloc.set_window(start, start + len));
+    f(start);
+    page_allocator::flush_retired();
+    with_page_alloc(|page_alloc| page_alloc.restore_window(saved));
+    vma::release(window.start);
+}
+
+/// Runs `f` with [`PAGE_ALLOCATOR`] locked, which has to be dropped again before
+/// [`page_allocator::flush_retired`] can free what was deallocated.
+fn with_page_alloc<R>(f: impl FnOnce(&mut KernelPageAllocator) -> R) -> R {
+    let mut guard = PAGE_ALLOCATOR.lock();
+    f(guard.as_mut().expect("page allocator not initialized"))
+}
+
+#[test_case]
+fn test_consecutive_allocations_are_adjacent() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_test_window(16, |start| {
+        with_page_alloc(|page_alloc| {
+            let a = page_alloc.alloc(2, flags).unwrap();
+            let b = page_alloc.alloc(3, flags).unwrap();
+            let c = page_alloc.alloc(1, flags).unwrap();
+            assert_eq!(a, start);
+            assert_eq!(b, a + 2 * PAGE_BYTES);
+            assert_eq!(c, b + 3 * PAGE_BYTES);
+            // Only 10 pages are left
+            assert!(page_alloc.alloc(11, flags).is_err());
+            page_alloc.dealloc(a, 2).unwrap();
+            page_alloc.dealloc(b, 3).unwrap();
+            page_alloc.dealloc(c, 1).unwrap();
+        });
+    });
+}
+
+#[test_case]
+fn test_dealloc_then_alloc_reuses_space() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_test_window(8, |start| {
+        let b = with_page_alloc(|page_alloc| {
+            let a = page_alloc.alloc(4, flags).unwrap();
+            let b = page_alloc.alloc(1, flags).unwrap();
+            page_alloc.dealloc(a, 4).unwrap();
+            // Still mapped until the flush
+            assert_eq!(page_alloc.free_bytes(), 0);
+            b
+        });
+        assert_eq!(page_allocator::flush_retired(), 4);
+
+        with_page_alloc(|page_alloc| {
+            assert_eq!(page_alloc.free_bytes(), 4 * PAGE_BYTES);
+            // Served from the freed range, front first, before the rest of the window
+            let c = page_alloc.alloc(2, flags).unwrap();
+            let d = page_alloc.alloc(2, flags).unwrap();
+            assert_eq!((c, d), (start, start + 2 * PAGE_BYTES));
+            assert_eq!(page_alloc.free_bytes(), 0);
+            let e = page_alloc.alloc(1, flags).unwrap();
+            assert_eq!(e, b + PAGE_BYTES);
+            page_alloc.dealloc(e, 1).unwrap();
+            page_alloc.dealloc(c, 2).unwrap();
+            page_alloc.dealloc(d, 2).unwrap();
+        });
+        page_allocator::flush_retired();
+
+        // Neighbouring ranges join up, so a larger allocation fits where they were
+        with_page_alloc(|page_alloc| {
+            let f = page_alloc.alloc(4, flags).unwrap();
+            assert_eq!(f, start);
+            page_alloc.dealloc(f, 4).unwrap();
+            page_alloc.dealloc(b, 1).unwrap();
+        });
+    });
+}
+
+#[test_case]
+fn test_pooled_frames_are_zero() {
+    assert!(zeroed::refill_batch());
+    let taken = zeroed::take().unwrap();
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            phys::phys_to_virt(taken.start_address())
+                .unwrap()
+                .as_ptr::<u8>(),
+            PAGE_BYTES,
+        )
+    };
+    assert!(bytes.iter().all(|&b| b == 0));
+    let guard = PAGE_ALLOCATOR.lock();
+    let page_alloc = guard.as_ref().unwrap();
+    page_alloc.frame_allocator.deallocate(taken);
+    while let Some(frame) = zeroed::take() {
+        page_alloc.frame_allocator.deallocate(frame);
+    }
+}
@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use rust_kernel::allocator::page_allocator::PAGE_ALLOCATOR;
+use rust_kernel::apic_ptr::APIC_BASE;
+use rust_kernel::cpu::{self, MAX_CPUS};
+use rust_kernel::init::hpet::init_hpet;
+use rust_kernel::init::multicore::{init_smp, init_stack_top, remap_trampoline_uncacheable};
+use rust_kernel::init::{self, memory_init};
+use rust_kernel::{metrics, platform, println, smp};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    start_aps(boot_info);
+    println!("{} CPUs online", cpu::online().count());
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+/// Starts the other CPUs the way the kernel does at boot: they need the APIC for IPIs and the
+/// HPET to time the INIT/SIPI sequence.
+fn start_aps(boot_info: &BootInfo) {
+    let (_tables, registry) = init::acpi::init_acpi(boot_info).expect("ACPI tables not found");
+    let platform_info = registry.platform();
+    platform::init(Some(platform_info));
+    init::apic::init_apic(platform_info).expect("APIC initialization failed");
+    init_hpet(registry.hpet().expect("no HPET")).expect("HPET initialization failed");
+    interrupts::enable();
+
+    let processor_info = platform_info
+        .processor_info
+        .as_ref()
+        .expect("no ACPI processor list");
+    let apic_base = unsafe { APIC_BASE }.expect("APIC not mapped");
+    unsafe {
+        remap_trampoline_uncacheable().expect("trampoline not mapped");
+        init_stack_top();
+        init_smp(apic_base.as_ptr(), processor_info).expect("APs not started");
+    }
+}
+
+/// Allocation sizes covering every block list and the page allocator fallback above 2 KiB.
+const SIZES: &[usize] = &[
+    1, 8, 13, 64, 100, 256, 700, 1024, 2048, 2049, 4096, 5000, 12288,
+];
+/// Allocations each CPU replaces per test.
+const ROUNDS: usize = 400;
+/// Allocations each CPU keeps alive at once.
+const LIVE: usize = 16;
+const PAGE_SIZE: usize = 4096;
+
+/// Runs `f` with the index of the executing CPU on every online CPU at once. The APs run it
+/// from the call IPI handler, so they take interrupts while it runs: freeing pages sends TLB
+/// shootdowns that every CPU has to answer.
+fn on_every_cpu(f: impl Fn(usize) + Sync) {
+    smp::call::call_all_wait(|| {
+        let enabled = interrupts::are_enabled();
+        interrupts::enable();
+        f(cpu::current_index());
+        if !enabled {
+            interrupts::disable();
+        }
+    });
+}
+
+/// A xorshift generator, so each CPU picks its own sequence of sizes and slots.
+struct Rng(u64);
+
+impl Rng {
+    fn new(cpu: usize) -> Self {
+        Rng(0x9E37_79B9_7F4A_7C15 ^ (cpu as u64 + 1))
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+/// The byte at `index` of an allocation tagged `tag`.
+fn pattern(tag: usize, index: usize) -> u8 {
+    (tag ^ (tag >> 8) ^ index.wrapping_mul(31) ^ (index >> 8)) as u8
+}
+
+fn filled(tag: usize, size: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; size];
+    for (index, byte) in buf.iter_mut().enumerate() {
+        *byte = pattern(tag, index);
+    }
+    buf
+}
+
+fn intact(tag: usize, buf: &[u8]) -> bool {
+    buf.iter()
+        .enumerate()
+        .all(|(index, &byte)| byte == pattern(tag, index))
+}
+
+/// Pages the heap hands back to the page allocator when `size` bytes are freed.
+fn pages_returned(size: usize) -> usize {
+    if size > 2048 {
+        size.div_ceil(PAGE_SIZE)
+    } else {
+        0
+    }
+}
+
+fn counter(name: &str) -> i64 {
+    metrics::list()
+        .into_iter()
+        .find(|sample| sample.name == name)
+        .map_or(0, |sample| sample.value)
+}
+
+/// Checks that every online CPU did all its rounds and nothing was corrupted.
+fn check_counts(rounds: &[AtomicUsize], corrupted: &AtomicUsize) {
+    assert_eq!(corrupted.load(Ordering::Relaxed), 0);
+    for (index, _) in cpu::online() {
+        assert_eq!(
+            rounds[index].load(Ordering::Relaxed),
+            ROUNDS,
+            "cpu{}",
+            index
+        );
+    }
+}
+
+#[test_case]
+fn test_global_allocator_mixed_sizes() {
+    let rounds: [AtomicUsize; MAX_CPUS] = core::array::from_fn(|_| AtomicUsize::new(0));
+    let corrupted = AtomicUsize::new(0);
+    let returned = AtomicUsize::new(0);
+    let unmapped_before = counter("alloc.pages_unmapped");
+
+    on_every_cpu(|cpu| {
+        let mut rng = Rng::new(cpu);
+        let mut live: Vec<(usize, Vec<u8>)> = Vec::with_capacity(LIVE);
+        let free = |tag: usize, buf: Vec<u8>| {
+            if !intact(tag, &buf) {
+                corrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            returned.fetch_add(pages_returned(buf.capacity()), Ordering::Relaxed);
+        };
+        for round in 0..ROUNDS {
+            // Tags differ between CPUs, so a block handed to two of them at once shows up
+            let tag = cpu * ROUNDS + round;
+            let buf = filled(tag, SIZES[rng.below(SIZES.len())]);
+            if live.len() < LIVE {
+                live.push((tag, buf));
+            } else {
+                let (old_tag, old) = core::mem::replace(&mut live[rng.below(LIVE)], (tag, buf));
+                free(old_tag, old);
+            }
+            rounds[cpu].fetch_add(1, Ordering::Relaxed);
+        }
+        for (tag, buf) in live.drain(..) {
+            free(tag, buf);
+        }
+    });
+
+    check_counts(&rounds, &corrupted);
+    // Every large allocation went back to the page allocator
+    assert_eq!(
+        counter("alloc.pages_unmapped") - unmapped_before,
+        returned.load(Ordering::Relaxed) as i64
+    );
+}
+
+#[test_case]
+fn test_page_allocator_concurrent() {
+    const MAX_PAGES: usize = 4;
+    const HELD: usize = 4;
+    let rounds: [AtomicUsize; MAX_CPUS] = core::array::from_fn(|_| AtomicUsize::new(0));
+    let corrupted = AtomicUsize::new(0);
+    let freed = AtomicUsize::new(0);
+    let mapped_before = counter("alloc.pages_mapped");
+    let unmapped_before = counter("alloc.pages_unmapped");
+
+    on_every_cpu(|cpu| {
+        let mut rng = Rng::new(cpu);
+        let mut held: [Option<(usize, usize)>; HELD] = [None; HELD];
+        let release = |(addr, pages): (usize, usize)| {
+            let words =
+                unsafe { core::slice::from_raw_parts(addr as *const u64, pages * PAGE_SIZE / 8) };
+            if words.iter().any(|&word| word != (addr as u64 ^ cpu as u64)) {
+                corrupted.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut guard = PAGE_ALLOCATOR.lock();
+            let allocator = guard.as_mut().expect("page allocator not initialized");
+            allocator.dealloc(addr, pages).expect("dealloc failed");
+            freed.fetch_add(pages, Ordering::Relaxed);
+        };
+        for _ in 0..ROUNDS {
+            let pages = 1 + rng.below(MAX_PAGES);
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            let addr = PAGE_ALLOCATOR
+                .lock()
+                .as_mut()
+                .expect("page allocator not initialized")
+                .alloc(pages, flags)
+                .expect("out of memory");
+            let words =
+                unsafe { core::slice::from_raw_parts_mut(addr as *mut u64, pages * PAGE_SIZE / 8) };
+            words.fill(addr as u64 ^ cpu as u64);
+            if let Some(old) = held[rng.below(HELD)].replace((addr, pages)) {
+                release(old);
+            }
+            rounds[cpu].fetch_add(1, Ordering::Relaxed);
+        }
+        for slot in &mut held {
+            if let Some(old) = slot.take() {
+                release(old);
+            }
+        }
+    });
+
+    check_counts(&rounds, &corrupted);
+    let freed = freed.load(Ordering::Relaxed) as i64;
+    assert_eq!(counter("alloc.pages_unmapped") - unmapped_before, freed);
+    assert_eq!(counter("alloc.pages_mapped") - mapped_before, freed);
+}
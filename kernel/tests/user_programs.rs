@@ -0,0 +1,240 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::page_allocator::PAGE_ALLOCATOR;
+use rust_kernel::fs::{self, Node};
+use rust_kernel::init::{graphics, memory_init};
+use rust_kernel::interrupts::disable_pic;
+use rust_kernel::process::address_space::{self, AddressSpace, USER_START};
+use rust_kernel::process::fd::{self, FdTable, OpenFile};
+use rust_kernel::process::scheduler::{self, ExitInfo};
+use rust_kernel::process::{self, signal};
+use rust_kernel::tty;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+/// Small user programs from `kernel/user`, assembled and linked by the build script.
+macro_rules! user_program {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("USER_PROGRAMS_DIR"), "/", $name, ".elf"))
+    };
+}
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    // No timer: processes run until they exit or block, and nothing else may interrupt them
+    disable_pic();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+    // For `/dev/fb0`; without a framebuffer the test drawing on it checks it isn't there
+    let _ = graphics::init_framebuffer(boot_info);
+    fs::init();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+/// Runs `image` to completion with its standard output and error going into a pipe, and
+/// returns how it exited along with what it wrote. The output must fit in the pipe, since
+/// nothing drains it while the program runs.
+fn run_captured(name: &str, image: &[u8]) -> (ExitInfo, Vec<u8>) {
+    run_captured_from("/dev/null", name, image)
+}
+
+/// Like [`run_captured`], with standard input read from the file at `stdin`.
+fn run_captured_from(stdin: &str, name: &str, image: &[u8]) -> (ExitInfo, Vec<u8>) {
+    let (reader, writer) = fs::pipe::pipe();
+    let output = Arc::new(OpenFile::new(writer, fd::O_WRONLY).unwrap());
+    let mut files = FdTable::new();
+    files
+        .insert(Arc::new(fd::open(stdin, fd::O_RDONLY).unwrap()))
+        .unwrap();
+    files.insert(output.clone()).unwrap();
+    files.insert(output).unwrap();
+
+    let pid = process::spawn_with_files(name, image, files).expect("failed to start program");
+    scheduler::run();
+    let exit = scheduler::reap(pid).expect("program did not exit");
+
+    let mut captured = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        match reader.read_at(0, &mut buf) {
+            Ok(0) => break,
+            Ok(len) => captured.extend_from_slice(&buf[..len]),
+            Err(e) => panic!("program left its output pipe open: {:?}", e),
+        }
+    }
+    (exit, captured)
+}
+
+#[test_case]
+fn test_exit_code() {
+    let (exit, output) = run_captured("exit_code", user_program!("exit_code"));
+    assert_eq!(exit.code, 42);
+    assert!(output.is_empty());
+}
+
+#[test_case]
+fn test_write_to_stdout() {
+    let (exit, output) = run_captured("hello", user_program!("hello"));
+    assert_eq!(exit.code, 0);
+    assert_eq!(output, b"hello from user mode\n");
+}
+
+#[test_case]
+fn test_fork_and_wait() {
+    let (exit, _) = run_captured("fork_wait", user_program!("fork_wait"));
+    assert_eq!(exit.code, 8);
+}
+
+#[test_case]
+fn test_pipe_round_trip() {
+    let (exit, output) = run_captured("pipe", user_program!("pipe"));
+    assert_eq!(exit.code, 0);
+    assert_eq!(output, b"through the pipe\n");
+}
+
+#[test_case]
+fn test_bad_pointer_is_efault() {
+    let (exit, output) = run_captured("bad_pointer", user_program!("bad_pointer"));
+    assert_eq!(exit.code, 14);
+    assert!(output.is_empty());
+}
+
+#[test_case]
+fn test_unknown_clock_is_einval() {
+    let (exit, _) = run_captured("bad_clock", user_program!("bad_clock"));
+    assert_eq!(exit.code, 22);
+}
+
+#[test_case]
+fn test_segfault_kills_process() {
+    let (exit, _) = run_captured("segfault", user_program!("segfault"));
+    assert_eq!(exit.code, signal::exit_code(signal::SIGSEGV));
+}
+
+#[test_case]
+fn test_unmap_prunes_page_tables() {
+    let mut space = AddressSpace::new().expect("no memory for an address space");
+    let free_frames = || {
+        let guard = PAGE_ALLOCATOR.lock();
+        guard.as_ref().unwrap().frame_allocator.free_frames()
+    };
+    let before = free_frames();
+
+    // 4 MiB starting 1 MiB before a 1 GiB boundary needs a level 3 table, two level 2 tables
+    // and three level 1 tables
+    let start = VirtAddr::new(USER_START + (1 << 30) - (1 << 20));
+    let flags = PageTableFlags::WRITABLE;
+    space.map_user(start, 1024, flags).expect("map failed");
+    assert_eq!(space.table_frames(), 1 + 2 + 3);
+
+    // Unmapping the first megabyte only empties the level 1 table and level 2 table below it
+    space.unmap_user(start, 256);
+    assert_eq!(space.table_frames(), 1 + 1 + 2);
+
+    space.unmap_user(start + (1u64 << 20), 768);
+    assert_eq!(space.table_frames(), 0);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn test_shared_page_gets_both_segments_access() {
+    let mut space = AddressSpace::new().expect("no memory for an address space");
+    let start = VirtAddr::new(USER_START);
+    // Read-only text, then writable data on the same page
+    space
+        .map_user(start, 1, PageTableFlags::empty())
+        .expect("map failed");
+    space
+        .map_user(
+            start,
+            1,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("map failed");
+    assert_eq!(space.resident_pages(), 1);
+
+    space.activate();
+    let flags = address_space::translate_active(start);
+    address_space::activate_kernel();
+    let flags = flags.expect("page not mapped");
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    space.unmap_user(start, 1);
+}
+
+#[test_case]
+fn test_futex_checks_the_word() {
+    let (exit, _) = run_captured("futex", user_program!("futex"));
+    assert_eq!(exit.code, 0);
+}
+
+#[test_case]
+fn test_getrusage_counts_cow_faults() {
+    let (exit, _) = run_captured("rusage", user_program!("rusage"));
+    assert_eq!(exit.code, 0);
+}
+
+#[test_case]
+fn test_draw_on_mapped_framebuffer() {
+    let (exit, _) = run_captured("fb", user_program!("fb"));
+    let Ok(fb) = fs::open("/dev/fb0", false) else {
+        assert_eq!(exit.code, 2);
+        return;
+    };
+    assert_eq!(exit.code, 0);
+    // The console leaves the top line alone
+    let mut top = [0u8; 16];
+    assert_eq!(fb.read_at(0, &mut top), Ok(16));
+    assert_eq!(top, [0xFF; 16]);
+}
+
+#[test_case]
+fn test_console_reads_edited_lines() {
+    tty::feed(b"lz\x08s\n");
+    let (exit, output) = run_captured("tty", user_program!("tty"));
+    assert_eq!(exit.code, tty::DEFAULT_MODE as i32);
+    assert_eq!(output, b"ls\n");
+}
+
+#[test_case]
+fn test_vdso_clock_agrees_with_syscall() {
+    let (exit, output) = run_captured("vdso", user_program!("vdso"));
+    assert_eq!(exit.code, 0);
+    assert!(output.is_empty());
+}
+
+#[test_case]
+fn test_shell_runs_builtins_and_programs() {
+    tty::feed(b"echo hi there\nnope\nhello\nexit 3\n");
+    let (exit, output) = run_captured_from("/dev/console", "sh", user_program!("sh"));
+    assert_eq!(exit.code, 3);
+    assert_eq!(
+        output,
+        b"$ hi there\n$ sh: nope: not found\n$ Hello from ring 3!\n$ "
+    );
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader_api::config::{BootloaderConfig, Mapping};
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use rust_kernel::fs::{self, FileSystem, FsError, Node, devfs, pipe, ramfs};
+use rust_kernel::init::memory_init;
+use rust_kernel::process::fd::{self, OpenFile};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    rust_kernel::init_gdt_idt();
+    memory_init::init_memory(boot_info).expect("memory initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_mount_resolution() {
+    let root = ramfs::RamFs::new();
+    root.insert("etc/motd", b"hi", false);
+    fs::mount("/", Arc::new(root));
+    fs::mount("/dev", Arc::new(devfs::DevFs::new()));
+
+    assert_eq!(fs::read_all("/etc/motd").as_deref(), Ok(&b"hi"[..]));
+    assert!(fs::open("/dev/null", false).is_ok());
+    assert!(matches!(
+        fs::open("/dev/motd", false),
+        Err(FsError::NotFound)
+    ));
+    assert!(matches!(
+        fs::open("relative", false),
+        Err(FsError::InvalidPath)
+    ));
+    assert!(matches!(
+        fs::open("/etc//motd", false),
+        Err(FsError::InvalidPath)
+    ));
+
+    let node = fs::open("/tmp/new", true).unwrap();
+    assert_eq!(node.write_at(0, b"abc"), Ok(3));
+    assert_eq!(fs::read_all("/tmp/new").as_deref(), Ok(&b"abc"[..]));
+}
+
+#[test_case]
+fn test_pipe_end_of_file() {
+    let (reader, writer) = pipe::pipe();
+    let mut buf = [0u8; 8];
+    assert!(matches!(
+        reader.read_at(0, &mut buf),
+        Err(FsError::WouldBlock(_))
+    ));
+    assert_eq!(writer.write_at(0, b"abc"), Ok(3));
+    assert_eq!(reader.read_at(0, &mut buf), Ok(3));
+    assert_eq!(&buf[..3], b"abc");
+    drop(writer);
+    assert_eq!(reader.read_at(0, &mut buf), Ok(0));
+}
+
+#[test_case]
+fn test_file_size_is_capped() {
+    let fs = ramfs::RamFs::new();
+    fs.insert("big", b"", false);
+    let node = fs.lookup("big").unwrap();
+    assert_eq!(
+        node.write_at(u64::MAX - 1, b"abc"),
+        Err(FsError::FileTooBig)
+    );
+    assert_eq!(
+        node.write_at(ramfs::MAX_FILE_SIZE, b"a"),
+        Err(FsError::FileTooBig)
+    );
+    assert_eq!(node.write_at(ramfs::MAX_FILE_SIZE - 1, b"a"), Ok(1));
+    assert_eq!(node.size(), Some(ramfs::MAX_FILE_SIZE));
+
+    let file = OpenFile::new(node, fd::O_RDWR).unwrap();
+    assert_eq!(file.seek(i64::MAX, fd::SEEK_SET), Ok(fd::MAX_OFFSET));
+    assert_eq!(
+        file.seek(1, fd::SEEK_CUR),
+        Err(fd::FdError::InvalidArgument)
+    );
+    assert_eq!(
+        file.seek(-1, fd::SEEK_SET),
+        Err(fd::FdError::InvalidArgument)
+    );
+    assert_eq!(file.write(b"x"), Err(fd::FdError::Fs(FsError::FileTooBig)));
+}
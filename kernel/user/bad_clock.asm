@@ -0,0 +1,16 @@
+; Asks for the time on a clock that doesn't exist and exits with the error number it gets back.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 16
+    mov eax, 15             ; clock_gettime(7, &ts)
+    mov edi, 7
+    mov rsi, rsp
+    syscall
+    neg rax
+    mov edi, eax
+    mov eax, 1              ; exit
+    syscall
+    ud2
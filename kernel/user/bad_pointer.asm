@@ -0,0 +1,16 @@
+; Passes an unmapped buffer to write and exits with the error number it gets back.
+bits 64
+global _start
+
+section .text
+_start:
+    mov eax, 0              ; write
+    mov edi, 1
+    mov esi, 0x10
+    mov edx, 5
+    syscall
+    neg rax
+    mov edi, eax
+    mov eax, 1              ; exit
+    syscall
+    ud2
@@ -0,0 +1,10 @@
+; Exits with a fixed code.
+bits 64
+global _start
+
+section .text
+_start:
+    mov eax, 1              ; exit
+    mov edi, 42
+    syscall
+    ud2
@@ -0,0 +1,56 @@
+; Maps /dev/fb0, paints its top line white and unmaps it again. Exits with 0, or with the error
+; number of the first call that fails.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 32             ; the FBIOGET_INFO answer
+    mov eax, 6              ; open("/dev/fb0", O_RDWR)
+    lea rdi, [rel path]
+    mov esi, path_len
+    mov edx, 2
+    syscall
+    test rax, rax
+    js .fail
+    mov rbx, rax
+    mov eax, 20             ; ioctl(fd, FBIOGET_INFO, rsp)
+    mov rdi, rbx
+    mov esi, 0x4600
+    mov rdx, rsp
+    syscall
+    test rax, rax
+    jnz .fail
+    mov eax, 21             ; mmap(len, PROT_READ | PROT_WRITE, fd, 0)
+    mov rdi, [rsp + 24]
+    mov esi, 3
+    mov rdx, rbx
+    xor r10d, r10d
+    syscall
+    test rax, rax
+    js .fail
+    mov r12, rax
+    mov rdi, rax            ; width * bytes_per_pixel bytes of 0xFF
+    mov ecx, [rsp]
+    imul ecx, [rsp + 12]
+    mov al, 0xFF
+    rep stosb
+    mov eax, 22             ; munmap(addr, len)
+    mov rdi, r12
+    mov rsi, [rsp + 24]
+    syscall
+    test rax, rax
+    jnz .fail
+    mov eax, 1              ; exit
+    xor edi, edi
+    syscall
+.fail:
+    neg rax
+    mov edi, eax
+    mov eax, 1              ; exit
+    syscall
+    ud2
+
+section .rodata
+path: db "/dev/fb0"
+path_len equ $ - path
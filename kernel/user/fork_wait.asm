@@ -0,0 +1,31 @@
+; Forks a child that exits with 7, waits for it and exits with its status plus one.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 16
+    mov eax, 4              ; fork
+    syscall
+    test rax, rax
+    js .fail
+    jz .child
+    mov rdi, rax
+    mov eax, 11             ; waitpid(child, &status)
+    mov rsi, rsp
+    syscall
+    test rax, rax
+    js .fail
+    mov edi, [rsp]
+    inc edi
+    mov eax, 1              ; exit
+    syscall
+.child:
+    mov eax, 1              ; exit
+    mov edi, 7
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
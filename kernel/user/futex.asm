@@ -0,0 +1,36 @@
+; Checks the futex calls that can't block: waiting on a word that holds something else fails
+; with EAGAIN, waking a word nobody waits on wakes no one, and a misaligned word is EINVAL.
+; Exits with 0, or with the number of the first check that failed.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 16
+    mov dword [rsp], 5
+    mov eax, 17             ; futex_wait(&word, 6)
+    mov rdi, rsp
+    mov esi, 6
+    syscall
+    cmp rax, -11            ; EAGAIN
+    mov edi, 1
+    jne .exit
+    mov eax, 18             ; futex_wake(&word, 1)
+    mov rdi, rsp
+    mov esi, 1
+    syscall
+    test rax, rax
+    mov edi, 2
+    jnz .exit
+    mov eax, 17             ; futex_wait(&word + 1, 5)
+    lea rdi, [rsp + 1]
+    mov esi, 5
+    syscall
+    cmp rax, -22            ; EINVAL
+    mov edi, 3
+    jne .exit
+    xor edi, edi
+.exit:
+    mov eax, 1              ; exit
+    syscall
+    ud2
@@ -0,0 +1,19 @@
+; Writes a line to standard output.
+bits 64
+global _start
+
+section .text
+_start:
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rel message]
+    mov edx, message_len
+    syscall
+    mov eax, 1              ; exit
+    xor edi, edi
+    syscall
+    ud2
+
+section .rodata
+message: db "hello from user mode", 10
+message_len equ $ - message
@@ -0,0 +1,51 @@
+; The first process: puts /dev/console on standard input, output and error, then execs the
+; shell. Exits with 127 if it can't.
+bits 64
+global _start
+
+section .text
+_start:
+    mov eax, 6              ; open("/dev/console", O_RDWR)
+    lea rdi, [rel console]
+    mov esi, console_len
+    mov edx, 2
+    syscall
+    test rax, rax
+    js .fail
+    mov rbx, rax
+    xor r12d, r12d
+.dup:
+    mov eax, 12             ; dup2(console, r12)
+    mov rdi, rbx
+    mov rsi, r12
+    syscall
+    test rax, rax
+    js .fail
+    inc r12d
+    cmp r12d, 3
+    jne .dup
+    mov eax, 8              ; close
+    mov rdi, rbx
+    syscall
+    mov eax, 5              ; exec("/bin/sh")
+    lea rdi, [rel shell]
+    mov esi, shell_len
+    syscall
+    mov eax, 0              ; write
+    mov edi, 2
+    lea rsi, [rel message]
+    mov edx, message_len
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 127
+    syscall
+    ud2
+
+section .rodata
+console: db "/dev/console"
+console_len equ $ - console
+shell: db "/bin/sh"
+shell_len equ $ - shell
+message: db "init: can't run /bin/sh", 10
+message_len equ $ - message
@@ -0,0 +1,43 @@
+; Sends a message through a pipe to itself and copies it to standard output.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 80             ; [rsp] descriptors, [rsp + 16] buffer
+    mov eax, 10             ; pipe
+    mov rdi, rsp
+    syscall
+    test rax, rax
+    jnz .fail
+    mov eax, 0              ; write
+    mov edi, [rsp + 4]
+    lea rsi, [rel message]
+    mov edx, message_len
+    syscall
+    cmp rax, message_len
+    jne .fail
+    mov eax, 7              ; read
+    mov edi, [rsp]
+    lea rsi, [rsp + 16]
+    mov edx, 64
+    syscall
+    test rax, rax
+    jle .fail
+    mov rdx, rax
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rsp + 16]
+    syscall
+    mov eax, 1              ; exit
+    xor edi, edi
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
+
+section .rodata
+message: db "through the pipe", 10
+message_len equ $ - message
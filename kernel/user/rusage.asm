@@ -0,0 +1,53 @@
+; Forks a child that writes to its copy-on-write stack and checks what getrusage reports: at
+; least one page fault, some resident pages, and EINVAL for anything but the caller. The parent
+; exits with the child's status: 0, or the number of the first check that failed.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 48
+    mov eax, 4              ; fork
+    syscall
+    test rax, rax
+    js .fail
+    jz .child
+    mov rdi, rax
+    mov eax, 11             ; waitpid(child, &status)
+    mov rsi, rsp
+    syscall
+    test rax, rax
+    js .fail
+    mov edi, [rsp]
+    jmp .exit
+.child:
+    mov qword [rsp], 1      ; the stack is shared with the parent until this write
+    mov eax, 19             ; getrusage(RUSAGE_SELF, &usage)
+    xor edi, edi
+    mov rsi, rsp
+    syscall
+    test rax, rax
+    mov edi, 1
+    jnz .exit
+    cmp qword [rsp + 32], 0 ; page_faults
+    mov edi, 2
+    je .exit
+    cmp qword [rsp + 16], 0 ; resident_pages
+    mov edi, 3
+    je .exit
+    mov eax, 19             ; getrusage(1, &usage)
+    mov edi, 1
+    mov rsi, rsp
+    syscall
+    cmp rax, -22            ; EINVAL
+    mov edi, 4
+    jne .exit
+    xor edi, edi
+.exit:
+    mov eax, 1              ; exit
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
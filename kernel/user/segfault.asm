@@ -0,0 +1,8 @@
+; Writes through a null pointer.
+bits 64
+global _start
+
+section .text
+_start:
+    mov byte [0], 1
+    ud2
@@ -0,0 +1,201 @@
+; A small shell. Reads commands a line at a time from standard input: `echo`, `help` and
+; `exit` are built in, and any other word runs the program of that name from /bin and waits
+; for it. Programs take no arguments. Exits with 0 at end of input.
+bits 64
+global _start
+
+LINE_MAX equ 256
+NAME_MAX equ 64
+
+section .text
+_start:
+    sub rsp, 512            ; [rsp] status, [rsp + 16] path, [rsp + 128] line
+.prompt:
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rel prompt]
+    mov edx, prompt_len
+    syscall
+    mov eax, 7              ; read
+    xor edi, edi
+    lea rsi, [rsp + 128]
+    mov edx, LINE_MAX
+    syscall
+    test rax, rax
+    js .fail
+    jz .eof
+    lea r12, [rsp + 128]    ; r12: start of the command, r13: end of the line
+    lea r13, [r12 + rax]
+    cmp byte [r13 - 1], 10
+    jne .skip_spaces
+    dec r13
+.skip_spaces:
+    cmp r12, r13
+    je .prompt
+    cmp byte [r12], ' '
+    jne .find_end
+    inc r12
+    jmp .skip_spaces
+.find_end:
+    mov r14, r12            ; r14: end of the command
+.next_char:
+    cmp r14, r13
+    je .dispatch
+    cmp byte [r14], ' '
+    je .dispatch
+    inc r14
+    jmp .next_char
+.dispatch:
+    lea rsi, [rel echo]
+    mov ecx, echo_len
+    call is_command
+    je .echo
+    lea rsi, [rel help]
+    mov ecx, help_len
+    call is_command
+    je .help
+    lea rsi, [rel exit]
+    mov ecx, exit_len
+    call is_command
+    je .exit
+    mov rcx, r14
+    sub rcx, r12
+    cmp rcx, NAME_MAX
+    ja .too_long
+    lea rdi, [rsp + 16]     ; path = "/bin/" + command
+    mov dword [rdi], '/bin'
+    mov byte [rdi + 4], '/'
+    add rdi, 5
+    mov rsi, r12
+    rep movsb
+    mov eax, 4              ; fork
+    syscall
+    test rax, rax
+    js .fail
+    jz .child
+    mov rdi, rax
+    mov eax, 11             ; waitpid(child, &status)
+    mov rsi, rsp
+    syscall
+    jmp .prompt
+.child:
+    mov eax, 5              ; exec(path)
+    lea rdi, [rsp + 16]
+    mov rsi, r14
+    sub rsi, r12
+    add rsi, 5
+    syscall
+    call not_found
+    mov eax, 1              ; exit
+    mov edi, 127
+    syscall
+.too_long:
+    call not_found
+    jmp .prompt
+.echo:
+    cmp r14, r13
+    je .echo_newline
+    lea rsi, [r14 + 1]      ; the rest of the line, after the space
+    mov rdx, r13
+    sub rdx, rsi
+    mov eax, 0              ; write
+    mov edi, 1
+    syscall
+.echo_newline:
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rel newline]
+    mov edx, 1
+    syscall
+    jmp .prompt
+.help:
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rel usage]
+    mov edx, usage_len
+    syscall
+    jmp .prompt
+.exit:
+    xor edi, edi            ; the code, in decimal after the command
+    mov rsi, r14
+.exit_space:
+    cmp rsi, r13
+    je .exit_now
+    cmp byte [rsi], ' '
+    jne .exit_digit
+    inc rsi
+    jmp .exit_space
+.exit_digit:
+    cmp rsi, r13
+    je .exit_now
+    movzx eax, byte [rsi]
+    sub eax, '0'
+    cmp eax, 9
+    ja .exit_now
+    imul edi, edi, 10
+    add edi, eax
+    inc rsi
+    jmp .exit_digit
+.exit_now:
+    mov eax, 1              ; exit
+    syscall
+.eof:
+    mov eax, 1              ; exit
+    xor edi, edi
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
+
+; Sets ZF if the command from r12 to r14 is the rcx bytes at rsi.
+is_command:
+    mov rax, r14
+    sub rax, r12
+    cmp rax, rcx
+    jne .done
+    mov rdi, r12
+    repe cmpsb
+.done:
+    ret
+
+; Writes "sh: <command>: not found" to standard error.
+not_found:
+    mov eax, 0              ; write
+    mov edi, 2
+    lea rsi, [rel not_found_prefix]
+    mov edx, not_found_prefix_len
+    syscall
+    mov eax, 0              ; write
+    mov edi, 2
+    mov rsi, r12
+    mov rdx, r14
+    sub rdx, r12
+    syscall
+    mov eax, 0              ; write
+    mov edi, 2
+    lea rsi, [rel not_found_suffix]
+    mov edx, not_found_suffix_len
+    syscall
+    ret
+
+section .rodata
+prompt: db "$ "
+prompt_len equ $ - prompt
+newline: db 10
+echo: db "echo"
+echo_len equ $ - echo
+help: db "help"
+help_len equ $ - help
+exit: db "exit"
+exit_len equ $ - exit
+usage: db "echo WORDS  print WORDS", 10
+       db "exit [N]    leave the shell with exit code N", 10
+       db "help        show this list", 10
+       db "NAME        run /bin/NAME", 10
+usage_len equ $ - usage
+not_found_prefix: db "sh: "
+not_found_prefix_len equ $ - not_found_prefix
+not_found_suffix: db ": not found", 10
+not_found_suffix_len equ $ - not_found_suffix
@@ -0,0 +1,47 @@
+; Reads a line from /dev/console, copies it to standard output and exits with the terminal's
+; mode.
+bits 64
+global _start
+
+section .text
+_start:
+    sub rsp, 80             ; [rsp] mode, [rsp + 16] line
+    mov eax, 6              ; open("/dev/console", O_RDONLY)
+    lea rdi, [rel path]
+    mov esi, path_len
+    xor edx, edx
+    syscall
+    test rax, rax
+    js .fail
+    mov rbx, rax
+    mov eax, 7              ; read
+    mov rdi, rbx
+    lea rsi, [rsp + 16]
+    mov edx, 64
+    syscall
+    test rax, rax
+    jle .fail
+    mov rdx, rax
+    mov eax, 0              ; write
+    mov edi, 1
+    lea rsi, [rsp + 16]
+    syscall
+    mov eax, 20             ; ioctl(fd, TTY_GET_MODE, rsp)
+    mov rdi, rbx
+    mov esi, 0x5401
+    mov rdx, rsp
+    syscall
+    test rax, rax
+    jnz .fail
+    mov eax, 1              ; exit
+    mov edi, [rsp]
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
+
+section .rodata
+path: db "/dev/console"
+path_len equ $ - path
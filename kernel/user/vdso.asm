@@ -0,0 +1,81 @@
+; Reads the monotonic clock through the vDSO and with the system call, in this process and in
+; a forked child. Exits with 1 if they don't succeed or fail alike, 2 if the vDSO's clock went
+; backwards, and 0 otherwise.
+bits 64
+global _start
+
+VDSO_CLOCK_GETTIME equ 0x7F0000001000
+CLOCK_MONOTONIC equ 1
+
+section .text
+_start:
+    sub rsp, 16             ; [rsp] the child's status
+    mov eax, 4              ; fork
+    syscall
+    test rax, rax
+    js .fail
+    mov rbx, rax
+    call check
+    test rbx, rbx
+    jz .exit
+    test eax, eax
+    jnz .exit
+    mov rdi, rbx
+    mov eax, 11             ; waitpid(child, &status)
+    mov rsi, rsp
+    syscall
+    test rax, rax
+    js .fail
+    mov eax, [rsp]
+.exit:
+    mov edi, eax
+    mov eax, 1              ; exit
+    syscall
+.fail:
+    mov eax, 1              ; exit
+    mov edi, 255
+    syscall
+    ud2
+
+; Returns the exit code for this process's readings in eax.
+check:
+    push rbx
+    sub rsp, 48             ; [rsp] first reading, [rsp + 16] second, [rsp + 32] system call's
+    mov edi, CLOCK_MONOTONIC
+    mov rsi, rsp
+    mov rax, VDSO_CLOCK_GETTIME
+    call rax
+    mov rbx, rax
+    mov eax, 15             ; clock_gettime(CLOCK_MONOTONIC, ts)
+    mov edi, CLOCK_MONOTONIC
+    lea rsi, [rsp + 32]
+    syscall
+    cmp rax, rbx
+    jne .mismatch
+    mov edi, CLOCK_MONOTONIC
+    lea rsi, [rsp + 16]
+    mov rax, VDSO_CLOCK_GETTIME
+    call rax
+    cmp rax, rbx
+    jne .mismatch
+    test rbx, rbx
+    jnz .ok                 ; both failed, e.g. without a clock source
+    mov rax, [rsp + 16]
+    cmp rax, [rsp]
+    jl .backwards
+    jg .ok
+    mov rax, [rsp + 24]
+    cmp rax, [rsp + 8]
+    jl .backwards
+.ok:
+    xor eax, eax
+    jmp .done
+.mismatch:
+    mov eax, 1
+    jmp .done
+.backwards:
+    mov eax, 2
+.done:
+    add rsp, 48
+    pop rbx
+    ret
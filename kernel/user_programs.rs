@@ -0,0 +1,49 @@
+//! Builds the user programs in `user/`. Shared by the kernel's build script, which hands them to
+//! the integration tests, and the disk image build, which packs them into the initrd.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where user programs are linked; matches `process::address_space::USER_START`.
+const USER_IMAGE_BASE: &str = "0x400000000000";
+
+/// Assembles and links each `*.asm` in `src_dir` into a static ELF executable `<name>.elf` in
+/// `out_dir`, and returns the name and path of each executable.
+pub fn build(src_dir: &Path, out_dir: &Path) -> Vec<(String, PathBuf)> {
+    fs::create_dir_all(out_dir).expect("Failed to create user program directory");
+
+    let mut programs = Vec::new();
+    for entry in fs::read_dir(src_dir).expect("failed to read user program sources") {
+        let src = entry.expect("failed to read user program sources").path();
+        if src.extension().is_none_or(|ext| ext != "asm") {
+            continue;
+        }
+        let name = src.file_stem().unwrap().to_str().unwrap();
+        let obj = out_dir.join(format!("{name}.o"));
+        let elf = out_dir.join(format!("{name}.elf"));
+
+        let status = Command::new("nasm")
+            .args(["-f", "elf64"])
+            .arg(&src)
+            .arg("-o")
+            .arg(&obj)
+            .status()
+            .expect("failed to run nasm");
+        assert!(status.success(), "nasm failed on {}", src.display());
+
+        let status = Command::new("ld.lld")
+            .args(["-static", "-e", "_start", "-z", "max-page-size=4096"])
+            .arg(format!("--image-base={USER_IMAGE_BASE}"))
+            .arg(&obj)
+            .arg("-o")
+            .arg(&elf)
+            .status()
+            .expect("failed to run ld.lld");
+        assert!(status.success(), "ld.lld failed on {}", obj.display());
+
+        programs.push((String::from(name), elf));
+    }
+    programs.sort();
+    programs
+}
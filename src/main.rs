@@ -1,17 +1,66 @@
+mod matrix;
+mod options;
+mod test;
+
+use options::{Firmware, Options};
+use test::{Outcome, TestOptions};
+
 fn main() {
     // read env variables set in build.rs
-    //let uefi_path = env!("UEFI_PATH");
+    let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
 
-    // let uefi = true;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mode = match args.first().map(String::as_str) {
+        Some(mode @ ("test" | "matrix")) => {
+            let mode = String::from(mode);
+            args.remove(0);
+            mode
+        }
+        _ => String::from("run"),
+    };
+    let test_mode = mode == "test";
+    let (test_options, args) = if mode == "run" {
+        (TestOptions::default(), args)
+    } else {
+        TestOptions::extract(args).unwrap_or_else(|e| usage_error(&e))
+    };
+
+    if mode == "matrix" {
+        let (configs, args) = matrix::extract(args).unwrap_or_else(|e| usage_error(&e));
+        let options = Options::parse(args).unwrap_or_else(|e| usage_error(&e));
+        let code = matrix::run(&options, &test_options, &configs, bios_path, uefi_path);
+        std::process::exit(code);
+    }
+
+    let options = Options::parse(args).unwrap_or_else(|e| usage_error(&e));
+    let image = match (&test_options.image, options.firmware) {
+        (Some(image), _) => image.as_str(),
+        (None, Firmware::Bios) => bios_path,
+        (None, Firmware::Uefi) => uefi_path,
+    };
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    cmd.arg("-drive")
-        .arg(format!("format=raw,file={bios_path}"));
-    // pass additional args to QEMU, e.g.:
-    cmd.args(["-serial", "stdio", "-smp", "4", "-cpu", "Skylake-Client"]);
+    if test_mode {
+        let outcome = test::run(options, &test_options, image, false);
+        match outcome {
+            Outcome::Passed => println!("Tests passed"),
+            Outcome::Failed => println!("Tests failed"),
+            Outcome::NoResult(code) => println!("QEMU exited without a result ({code:?})"),
+            Outcome::TimedOut => println!("Timed out after {:?}", test_options.timeout),
+        }
+        println!("Serial log: {}", test_options.log.display());
+        std::process::exit(outcome.exit_code());
+    }
+
+    let mut cmd = options.command(image);
     println!("Running QEMU with command: {:?}", cmd);
 
     let mut child = cmd.spawn().expect("Failed to launch QEMU");
-    child.wait().unwrap();
+    let status = child.wait().unwrap();
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{message}");
+    std::process::exit(2);
 }
@@ -0,0 +1,209 @@
+//! `cargo run -- matrix`: boots the kernel in test mode under a range of machine
+//! configurations and reports which ones pass, since some bugs only show up with particular
+//! CPU counts, CPU models, firmware or timers.
+//!
+//! ```text
+//! cargo run -- matrix [--config NAME]... [--timeout SECS] [--log DIR] [QEMU OPTIONS]
+//! ```
+//!
+//! `--config` limits the run to the named configurations; `--log` is the directory the serial
+//! log of each run is written to. Other options apply to every configuration.
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use crate::options::{Firmware, Options};
+use crate::test::{self, Outcome, TestOptions};
+
+pub struct Config {
+    pub name: &'static str,
+    pub firmware: Firmware,
+    pub smp: u32,
+    pub cpu: &'static str,
+    pub hpet: bool,
+    /// Extra kernel command line flags.
+    pub cmdline: &'static [&'static str],
+}
+
+pub const CONFIGS: &[Config] = &[
+    Config {
+        name: "bios-1cpu",
+        firmware: Firmware::Bios,
+        smp: 1,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "bios-2cpu",
+        firmware: Firmware::Bios,
+        smp: 2,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "bios-4cpu",
+        firmware: Firmware::Bios,
+        smp: 4,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "bios-4cpu-nohpet",
+        firmware: Firmware::Bios,
+        smp: 4,
+        cpu: "Skylake-Client",
+        hpet: false,
+        cmdline: &[],
+    },
+    Config {
+        name: "bios-2cpu-pic",
+        firmware: Firmware::Bios,
+        smp: 2,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &["nolapic"],
+    },
+    Config {
+        name: "bios-4cpu-qemu64",
+        firmware: Firmware::Bios,
+        smp: 4,
+        cpu: "qemu64",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "bios-2cpu-max",
+        firmware: Firmware::Bios,
+        smp: 2,
+        cpu: "max",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "uefi-1cpu",
+        firmware: Firmware::Uefi,
+        smp: 1,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &[],
+    },
+    Config {
+        name: "uefi-4cpu",
+        firmware: Firmware::Uefi,
+        smp: 4,
+        cpu: "Skylake-Client",
+        hpet: true,
+        cmdline: &[],
+    },
+];
+
+/// Takes the matrix-specific flags out of `args`, returning the configurations to run.
+pub fn extract(args: Vec<String>) -> Result<(Vec<&'static Config>, Vec<String>), String> {
+    let mut names = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => names.push(args.next().ok_or("--config needs a value")?),
+            "--" => {
+                rest.push(arg);
+                rest.extend(args);
+                break;
+            }
+            _ => rest.push(arg),
+        }
+    }
+    if names.is_empty() {
+        return Ok((CONFIGS.iter().collect(), rest));
+    }
+    let configs = names
+        .iter()
+        .map(|name| {
+            CONFIGS
+                .iter()
+                .find(|config| config.name == name)
+                .ok_or(format!("unknown configuration `{name}`"))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((configs, rest))
+}
+
+/// Runs every configuration in turn and prints a summary. Returns the runner's exit code:
+/// success only if every configuration passed.
+pub fn run(
+    base: &Options,
+    test: &TestOptions,
+    configs: &[&Config],
+    bios_image: &str,
+    uefi_image: &str,
+) -> i32 {
+    // `--log` names a directory here rather than a file
+    let log_dir = if test.log == TestOptions::default().log {
+        PathBuf::from("test-matrix")
+    } else {
+        test.log.clone()
+    };
+    std::fs::create_dir_all(&log_dir)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {e}", log_dir.display()));
+
+    let mut results = Vec::new();
+    for config in configs {
+        let mut options = base.clone();
+        options.firmware = config.firmware;
+        options.smp = config.smp;
+        options.cpu = String::from(config.cpu);
+        options
+            .cmdline
+            .extend(config.cmdline.iter().map(|flag| String::from(*flag)));
+        if !config.hpet {
+            options
+                .qemu_args
+                .extend(["-machine".into(), "hpet=off".into()]);
+        }
+        let image = match (&test.image, config.firmware) {
+            (Some(image), _) => image.as_str(),
+            (None, Firmware::Bios) => bios_image,
+            (None, Firmware::Uefi) => uefi_image,
+        };
+        let run_test = TestOptions {
+            log: log_dir.join(format!("{}.log", config.name)),
+            ..test.clone()
+        };
+
+        print!("{:<20} ", config.name);
+        let _ = std::io::stdout().flush();
+        let started = Instant::now();
+        let outcome = test::run(options, &run_test, image, true);
+        println!(
+            "{:<12} {:>6.1}s",
+            describe(outcome),
+            started.elapsed().as_secs_f64()
+        );
+        results.push(outcome);
+    }
+
+    let passed = results.iter().filter(|&&o| o == Outcome::Passed).count();
+    println!(
+        "{passed}/{} configurations passed; logs in {}",
+        results.len(),
+        log_dir.display()
+    );
+    if passed == results.len() {
+        test::EXIT_PASSED
+    } else {
+        test::EXIT_FAILED
+    }
+}
+
+fn describe(outcome: Outcome) -> String {
+    match outcome {
+        Outcome::Passed => String::from("passed"),
+        Outcome::Failed => String::from("FAILED"),
+        Outcome::NoResult(Some(code)) => format!("EXITED {code}"),
+        Outcome::NoResult(None) => String::from("KILLED"),
+        Outcome::TimedOut => String::from("TIMED OUT"),
+    }
+}
@@ -0,0 +1,166 @@
+//! QEMU settings for the runner, taken from the environment and then overridden by flags.
+//!
+//! ```text
+//! cargo run -- [--uefi|--bios] [-m SIZE] [--smp N] [--cpu MODEL] [--kvm] [--nographic]
+//!              [--gdb] [--device SPEC]... [--qemu-arg ARG]... [-- KERNEL CMDLINE...]
+//! ```
+//!
+//! The same settings can be given as `QEMU_MEMORY`, `QEMU_SMP`, `QEMU_CPU`, `QEMU_KVM`,
+//! `QEMU_NOGRAPHIC`, `QEMU_GDB`, `QEMU_UEFI` (the flags take `1`) and `QEMU_DEVICES`
+//! (separated by `;`, since device specs contain commas). `OVMF_PATH` points at the UEFI
+//! firmware.
+use std::process::Command;
+
+const DEFAULT_OVMF: &str = "/usr/share/ovmf/OVMF.fd";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firmware {
+    Bios,
+    Uefi,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub firmware: Firmware,
+    pub memory: String,
+    pub smp: u32,
+    pub cpu: String,
+    pub kvm: bool,
+    pub nographic: bool,
+    /// Listen for GDB on port 1234 and wait for it before running.
+    pub gdb: bool,
+    pub devices: Vec<String>,
+    /// Passed to QEMU as they are.
+    pub qemu_args: Vec<String>,
+    /// Handed to the kernel through fw_cfg.
+    pub cmdline: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            firmware: Firmware::Bios,
+            memory: String::from("128M"),
+            smp: 4,
+            cpu: String::from("Skylake-Client"),
+            kvm: false,
+            nographic: false,
+            gdb: false,
+            devices: Vec::new(),
+            qemu_args: Vec::new(),
+            cmdline: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Reads the environment, then applies `args` (without the program name) on top.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options::from_env()?;
+        options.apply_args(args)?;
+        Ok(options)
+    }
+
+    fn from_env() -> Result<Options, String> {
+        let mut options = Options::default();
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let flag = |name: &str| var(name).is_some_and(|v| v != "0");
+        if let Some(memory) = var("QEMU_MEMORY") {
+            options.memory = memory;
+        }
+        if let Some(smp) = var("QEMU_SMP") {
+            options.smp = parse_smp(&smp)?;
+        }
+        if let Some(cpu) = var("QEMU_CPU") {
+            options.cpu = cpu;
+        }
+        if let Some(devices) = var("QEMU_DEVICES") {
+            options.devices = devices.split(';').map(String::from).collect();
+        }
+        options.kvm = flag("QEMU_KVM");
+        options.nographic = flag("QEMU_NOGRAPHIC");
+        options.gdb = flag("QEMU_GDB");
+        if flag("QEMU_UEFI") {
+            options.firmware = Firmware::Uefi;
+        }
+        Ok(options)
+    }
+
+    /// Applies flags, returning an error for unknown ones or missing values.
+    pub fn apply_args(&mut self, args: impl IntoIterator<Item = String>) -> Result<(), String> {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| args.next().ok_or(format!("{flag} needs a value"));
+            match arg.as_str() {
+                "--uefi" => self.firmware = Firmware::Uefi,
+                "--bios" => self.firmware = Firmware::Bios,
+                "-m" | "--memory" => self.memory = value(&arg)?,
+                "--smp" => self.smp = parse_smp(&value(&arg)?)?,
+                "--cpu" => self.cpu = value(&arg)?,
+                "--kvm" => self.kvm = true,
+                "--nographic" => self.nographic = true,
+                "--gdb" => self.gdb = true,
+                "--device" => self.devices.push(value(&arg)?),
+                "--qemu-arg" => self.qemu_args.push(value(&arg)?),
+                "--" => {
+                    self.cmdline.extend(args);
+                    break;
+                }
+                _ => return Err(format!("unknown option `{arg}`")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the QEMU invocation for `image`, which has to match `self.firmware`.
+    pub fn command(&self, image: &str) -> Command {
+        let mut cmd = Command::new("qemu-system-x86_64");
+        cmd.arg("-drive").arg(format!("format=raw,file={image}"));
+        if self.firmware == Firmware::Uefi {
+            let ovmf = std::env::var("OVMF_PATH").unwrap_or_else(|_| String::from(DEFAULT_OVMF));
+            cmd.arg("-bios").arg(ovmf);
+        }
+        cmd.args(["-serial", "stdio"]);
+        cmd.arg("-m").arg(&self.memory);
+        cmd.arg("-smp").arg(self.smp.to_string());
+        cmd.arg("-cpu").arg(&self.cpu);
+        if self.kvm {
+            cmd.arg("-enable-kvm");
+        }
+        if self.nographic {
+            // Serial already goes to stdio, so only the display has to go
+            cmd.args(["-display", "none"]);
+        }
+        if self.gdb {
+            cmd.args(["-s", "-S"]);
+        }
+        for device in &self.devices {
+            cmd.arg("-device").arg(device);
+        }
+        // a virtio console whose output (a copy of the log and console) lands in hvc0.log
+        cmd.args([
+            "-device",
+            "virtio-serial-pci",
+            "-chardev",
+            "file,id=hvc0,path=hvc0.log",
+            "-device",
+            "virtconsole,chardev=hvc0",
+        ]);
+        // the kernel command line, read back through fw_cfg
+        if !self.cmdline.is_empty() {
+            cmd.arg("-fw_cfg").arg(format!(
+                "name=opt/rust-kernel/cmdline,string={}",
+                self.cmdline.join(" ").replace(',', ",,")
+            ));
+        }
+        cmd.args(&self.qemu_args);
+        cmd
+    }
+}
+
+fn parse_smp(value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid CPU count `{value}`")),
+    }
+}
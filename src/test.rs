@@ -0,0 +1,157 @@
+//! `cargo run -- test`: boots the kernel headless with the `isa-debug-exit` device, copies
+//! serial output to the terminal and a log file, and turns the outcome into an exit code.
+//!
+//! ```text
+//! cargo run -- test [--timeout SECS] [--log PATH] [--image PATH] [QEMU OPTIONS] [-- CMDLINE]
+//! ```
+//!
+//! Without `--image` the runner's own kernel is booted with `test` on its command line, which
+//! makes it exit as soon as it has finished initialising. `--image` boots another disk image,
+//! e.g. one built from a test binary, which is expected to exit through the device itself.
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::options::Options;
+
+/// What QEMU exits with for the kernel's `QemuExitCode::Success` and `Failed`: the value
+/// written to the device, shifted left, with the low bit set.
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_FAILED: i32 = (0x11 << 1) | 1;
+
+pub const EXIT_PASSED: i32 = 0;
+pub const EXIT_FAILED: i32 = 1;
+/// QEMU stopped without the kernel reporting a result, e.g. on a triple fault.
+pub const EXIT_NO_RESULT: i32 = 2;
+/// Matches `timeout(1)`.
+pub const EXIT_TIMED_OUT: i32 = 124;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct TestOptions {
+    pub timeout: Duration,
+    pub log: PathBuf,
+    pub image: Option<String>,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        TestOptions {
+            timeout: Duration::from_secs(300),
+            log: PathBuf::from("test-serial.log"),
+            image: None,
+        }
+    }
+}
+
+impl TestOptions {
+    /// Takes the test-specific flags out of `args`, leaving the rest for [`Options`].
+    pub fn extract(args: Vec<String>) -> Result<(TestOptions, Vec<String>), String> {
+        let mut test = TestOptions::default();
+        if let Ok(secs) = std::env::var("QEMU_TIMEOUT") {
+            test.timeout = parse_timeout(&secs)?;
+        }
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| args.next().ok_or(format!("{flag} needs a value"));
+            match arg.as_str() {
+                "--timeout" => test.timeout = parse_timeout(&value(&arg)?)?,
+                "--log" => test.log = PathBuf::from(value(&arg)?),
+                "--image" => test.image = Some(value(&arg)?),
+                "--" => {
+                    rest.push(arg);
+                    rest.extend(args);
+                    break;
+                }
+                _ => rest.push(arg),
+            }
+        }
+        Ok((test, rest))
+    }
+}
+
+/// The result of one boot, with the exit code the runner reports for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    NoResult(Option<i32>),
+    TimedOut,
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Passed => EXIT_PASSED,
+            Outcome::Failed => EXIT_FAILED,
+            Outcome::NoResult(_) => EXIT_NO_RESULT,
+            Outcome::TimedOut => EXIT_TIMED_OUT,
+        }
+    }
+}
+
+/// Boots `image` once in test mode. Serial output is echoed to stdout unless `quiet` is set,
+/// and always written to `test.log`.
+pub fn run(mut options: Options, test: &TestOptions, image: &str, quiet: bool) -> Outcome {
+    options.nographic = true;
+    if test.image.is_none() {
+        options.cmdline.insert(0, String::from("test"));
+    }
+    let mut cmd = options.command(image);
+    cmd.args([
+        "-device",
+        "isa-debug-exit,iobase=0xf4,iosize=0x04",
+        "-no-reboot",
+    ]);
+    cmd.stdout(Stdio::piped());
+    if !quiet {
+        println!("Running QEMU with command: {:?}", cmd);
+    }
+
+    let mut log = File::create(&test.log)
+        .unwrap_or_else(|e| panic!("Failed to create {}: {e}", test.log.display()));
+    let mut child = cmd.spawn().expect("Failed to launch QEMU");
+    let stdout = child.stdout.take().unwrap();
+    let echo = std::thread::spawn(move || {
+        for line in BufReader::new(stdout).split(b'\n') {
+            let Ok(mut line) = line else { break };
+            line.push(b'\n');
+            if !quiet {
+                let _ = std::io::stdout().write_all(&line);
+            }
+            let _ = log.write_all(&line);
+        }
+    });
+
+    let deadline = Instant::now() + test.timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("Failed to wait for QEMU") {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    let _ = echo.join();
+
+    match status.map(|s| s.code()) {
+        None => Outcome::TimedOut,
+        Some(Some(QEMU_SUCCESS)) => Outcome::Passed,
+        Some(Some(QEMU_FAILED)) => Outcome::Failed,
+        Some(code) => Outcome::NoResult(code),
+    }
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    match value.parse() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("invalid timeout `{value}`")),
+    }
+}